@@ -0,0 +1,91 @@
+// Benchmarks of the passes of the compiler
+//
+// Each generated program is parsed and compiled with Criterion and the passes
+// that have grown slow before are timed on their own: parsing, rename, lambda
+// lifting and code generation. The passes are timed from the trace of a whole
+// compile (see `emit::traced`), so they run just like in a build.
+//
+//     $ cargo bench --bench passes                     # Every program
+//     $ cargo bench --bench passes -- deep             # Only the deep lets
+//     $ cargo bench --bench passes -- --sample-size 50
+//
+// Criterion keeps the results in target/criterion and reports how much each
+// benchmark changed since the last run, along with its own statistics.
+//
+// The prelude isn't compiled along with the programs, so that only the shape
+// of the program changes from one benchmark to the next.
+//
+// `cargo test --all-targets` runs every benchmark once, to make sure the
+// programs still compile. Criterion is started here rather than with
+// `criterion_main!`, to run on a deep stack, see `Cargo.toml`.
+extern crate criterion;
+extern crate inc;
+
+use criterion::Criterion;
+use inc::{compiler::emit, host, lang::Passes, parser::parse};
+use std::time::Duration;
+
+/// Passes timed from the trace of the compiler, along with parsing
+const PASSES: [&str; 3] = ["renamed", "lifted", "codegen"];
+
+// The passes recurse as deep as the programs are nested, like in the binary
+fn main() {
+    host::deep(|| {
+        let mut c = Criterion::default().sample_size(20).configure_from_args();
+        passes(&mut c);
+        c.final_summary();
+    })
+}
+
+fn passes(c: &mut Criterion) {
+    for (name, source) in programs() {
+        let mut group = c.benchmark_group(name);
+
+        group.bench_function("parse", |b| b.iter(|| parse(&source).unwrap()));
+
+        for pass in PASSES.iter() {
+            group.bench_function(*pass, |b| {
+                b.iter_custom(|iters| (0..iters).map(|_| time(&source, pass)).sum())
+            });
+        }
+
+        group.finish();
+    }
+}
+
+/// Time a pass in a whole compile of a program
+fn time(source: &str, pass: &str) -> Duration {
+    let mut time = Duration::default();
+
+    emit::traced(parse(source).unwrap(), Passes::default(), &mut |traced| {
+        if traced.name == pass {
+            time = traced.time
+        }
+    })
+    .unwrap();
+
+    time
+}
+
+/// Programs of the shapes that stress the passes the most
+fn programs() -> Vec<(&'static str, String)> {
+    let n = 500;
+
+    // (let ((x0 0)) (let ((x1 (+ x0 1))) … x499))
+    let mut deep = format!("x{}", n - 1);
+    for i in (0..n).rev() {
+        let value = if i == 0 { String::from("0") } else { format!("(+ x{} 1)", i - 1) };
+        deep = format!("(let ((x{} {})) {})", i, value, deep);
+    }
+
+    // (list 0 1 2 … 4999) and a vector of strings
+    let numbers: Vec<String> = (0..10 * n).map(|i| i.to_string()).collect();
+    let strings: Vec<String> = (0..n).map(|i| format!("\"s{}\"", i)).collect();
+    let wide = format!("(list {}) (vector {})", numbers.join(" "), strings.join(" "));
+
+    // Functions with a lambda each to lift, called once
+    let lambda = |i| format!("(define (f{0} x) (let ((g (lambda (y) (+ y {0})))) (g x)))", i);
+    let lambdas: String = (0..n).map(|i| format!("{}\n(f{} 1)\n", lambda(i), i)).collect();
+
+    vec![("deep-lets", deep), ("wide-lists", wide), ("many-lambdas", lambdas)]
+}
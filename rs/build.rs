@@ -0,0 +1,53 @@
+//! Identity of the build, see `src/cache.rs`
+//!
+//! Compiled files are cached across builds of a program, but a compiler built
+//! from different sources may compile them differently even when its version
+//! didn't change. The identity is a hash of every source file of the crate,
+//! exported to the compiler as `INC_BUILD`.
+
+use std::{env, fs, io, path::Path};
+
+/// FNV-1a, stable across toolchains unlike the hashers of std
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Hash the path relative to `root` and the contents of every file under
+/// `dir`, in a fixed order
+fn visit(root: &Path, dir: &Path, hash: &mut Fnv) -> io::Result<()> {
+    let mut entries =
+        fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            visit(root, &path, hash)?;
+        } else {
+            hash.write(path.strip_prefix(root).unwrap().to_string_lossy().as_bytes());
+            hash.write(&fs::read(&path)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let root = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let root = Path::new(&root);
+    let mut hash = Fnv(0xcbf2_9ce4_8422_2325);
+
+    hash.write(&fs::read(root.join("Cargo.toml"))?);
+    visit(root, &root.join("src"), &mut hash)?;
+
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rustc-env=INC_BUILD={:016x}", hash.0);
+
+    Ok(())
+}
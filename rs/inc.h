@@ -7,6 +7,14 @@
 
 #define CHAR 2
 
+/**
+ * Returned by the functions of the runtime for ports when they fail, for the
+ * generated code to raise the error with [rt_io_error](io::rt_io_error)
+ *
+ * This is never an object; it would be a vector right below address 0.
+ */
+#define FAILED -1
+
 #define FALSE ((0 << SHIFT) | BOOL)
 
 #define MASK 7
@@ -36,40 +44,649 @@ typedef struct {
   int64_t _0;
 } Object;
 
+/**
+ * Free space in the heap after an allocation, returned in RAX & RDX
+ */
+typedef struct {
+  int64_t *pointer;
+  int64_t *limit;
+} Space;
+
+/**
+ * An unreachable object and its finalizer, returned in RAX & RDX
+ */
+typedef struct {
+  Object obj;
+  int64_t address;
+} Finalizer;
+
+/**
+ * Where a suspended thread continues
+ *
+ * The generated code switches to a thread by loading the base and the limit
+ * of its stack into R14 and R15 and jumping to `address` with `frame` as
+ * both RBP and RSP.
+ */
+typedef struct {
+  int64_t frame;
+  int64_t address;
+  int64_t base;
+  int64_t limit;
+} Context;
+
+/**
+ * Where to continue after a raise, returned in RAX & RDX
+ *
+ * A guard continues at `address` with the stack frame `frame`, while a
+ * procedure handler at `address` is called from the frame of the raise,
+ * denoted by a null `frame`. A frame of [UNWIND] denotes an after thunk of
+ * `dynamic-wind` to be called before asking for the target again with
+ * [rt_unwind].
+ */
+typedef struct {
+  int64_t frame;
+  int64_t address;
+} Target;
+
+/**
+ * A diagnostic for C, in a list of them
+ *
+ * The span is in bytes from the start of `file`, with both ends -1 if it
+ * isn't known, and so is `file` if it is null.
+ */
+typedef struct inc_diag {
+  /**
+   * `error` or `warning`
+   */
+  char *severity;
+  /**
+   * Stable code of the kind of error, like `E0001`
+   */
+  char *code;
+  char *message;
+  char *file;
+  int64_t start;
+  int64_t end;
+  /**
+   * The next diagnostic, null after the last one
+   */
+  struct inc_diag *next;
+} inc_diag;
+
 Object car(Object val);
 
+/**
+ * Upper bound on the bytes needed for the result of an arithmetic primitive
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+ */
+int64_t rt_arithmetic_size(int64_t primitive, Object a, Object b);
+
+/**
+ * Apply an arithmetic primitive or a conversion to numbers
+ *
+ * There must be room for [rt_arithmetic_size] bytes at the heap pointer.
+ * Conversions ignore the second operand.
+ */
+Object rt_arithmetic(int64_t primitive, Object a, Object b);
+
+/**
+ * Compare numbers with a comparison primitive
+ *
+ * Every comparison with NaN is false.
+ */
+Object rt_compare(int64_t primitive, Object a, Object b);
+
 Object cdr(Object val);
 
+/**
+ * Load a shared library, making its functions available to foreign
+ * procedures
+ */
+Object rt_load_shared_object(Object path);
+
+/**
+ * Call the C function `name` with `count` arguments starting at `args` and
+ * going down the stack
+ *
+ * `signature` is the encoded types of the function, see [signature]. There
+ * must be room for [RESULT] bytes at the heap pointer.
+ */
+Object rt_foreign_call(Object name, int64_t signature, const int64_t *args, int64_t count);
+
+/**
+ * Make space for an object of `size` bytes, collecting garbage if required
+ *
+ * `top` is the last live stack slot of the frame at `rbp`, `base` is the frame
+ * of `init` and `pointer` is the current heap pointer. See module
+ * documentation for details. Aborts if there isn't enough free memory even
+ * after a collection.
+ */
+Space gc_collect(int64_t size, int64_t *top, int64_t *rbp, int64_t *base, int64_t *pointer);
+
+/**
+ * Number of collections so far
+ *
+ * Objects may have moved if the epoch changed, which invalidates anything
+ * derived from their addresses like the hashes of an identity hash table.
+ */
+Object gc_epoch(void);
+
+/**
+ * Create a guardian and return its ID
+ */
+Object gc_guardian(void);
+
+/**
+ * Hand `obj` back from the guardian `id` once it is unreachable
+ */
+Object gc_guard(Object id, Object obj);
+
+/**
+ * The next unreachable object of the guardian `id`, or `#f` if there is none
+ */
+Object gc_collected(Object id);
+
+/**
+ * Call the function at `address` with `obj` once the object is unreachable
+ */
+Object gc_register_finalizer(Object obj, int64_t address);
+
+/**
+ * The next unreachable object with a finalizer, or a 0 address if there is
+ * none
+ */
+Finalizer gc_finalizer(void);
+
+/**
+ * Run a major collection and return the empty nursery
+ *
+ * Takes the same arguments as [gc_collect], except for the size.
+ */
+Space gc_force(int64_t *top, int64_t *rbp, int64_t *base, int64_t *pointer);
+
+/**
+ * The counter at index `k` of [STATS] given the current heap pointer
+ */
+Object gc_stat(Object k, int64_t *pointer);
+
+/**
+ * Create a fresh heap for the current thread and return the nursery
+ *
+ * The initial size of each half of the old generation, the growth factor and
+ * the hard limit in words default to the environment if 0. See [Policy].
+ */
+Space gc_init(uintptr_t size, uintptr_t growth, uintptr_t limit);
+
+/**
+ * The nursery of the current thread, creating the heap if there is none yet
+ *
+ * Entry points called from C pick up the heap where the last call left it,
+ * see [header](crate::header).
+ */
+Space gc_space(void);
+
+/**
+ * [sync] for generated code
+ *
+ * # Safety
+ *
+ * `pointer` must be the heap pointer of the current thread.
+ */
+void gc_sync(int64_t *pointer);
+
+/**
+ * Record a slot updated by a mutation if it points into the nursery
+ */
+void gc_remember(int64_t *slot);
+
+/**
+ * Set the hard limit of each half of the old generation to `words`
+ *
+ * Returns the previous limit.
+ */
+Object heap_limit(Object words);
+
 void print(Object val, bool nested);
 
+/**
+ * Raise the error of the last operation on a port that failed
+ */
+Target rt_io_error(void);
+
+/**
+ * Write a newline to a port
+ */
+Object rt_newline(Object port);
+
+/**
+ * The text written to a string output port so far, as a new string
+ */
+Object rt_get_output_string(Object port);
+
+/**
+ * Open a port to read the characters of a string
+ *
+ * String ports have negative descriptors that don't refer to any file;
+ * the string is copied into the input buffer of the port right away.
+ */
+Object rt_open_input_string(Object data);
+
+/**
+ * Open a port that accumulates the text written to it in a string
+ */
+Object rt_open_output_string(void);
+
 /**
  * Open a file for reading return the immediate encoded file descriptor
- * Fails if file doesn't exist already
+ * Raises an error if the file can't be opened, like when it doesn't exist
+ *
+ * The file stays open till the port is closed with `rt_close_port`.
  */
 Object rt_open_read(Object fname);
 
 /**
  * Open a file for writing and return the immediate encoded file descriptor
  * Creates file if it doesn't exist already
+ *
+ * The file stays open till the port is closed with `rt_close_port`.
  */
 Object rt_open_write(Object fname);
 
+/**
+ * Close the file of a port
+ *
+ * Closing a standard port does nothing, the process owns them.
+ */
+Object rt_close_port(Object port);
+
+/**
+ * The object raised or passed to a continuation most recently
+ */
+Object rt_condition(void);
+
+/**
+ * Write the human readable representation of an object to a port
+ */
+Object rt_display(Object val, Object port);
+
+/**
+ * The end of file object returned by `read`
+ */
+Object rt_eof_object(void);
+
+/**
+ * Compare two objects with [equal], by identity unless `deep` is true
+ */
+Object rt_equal(Object a, Object b, Object deep);
+
+/**
+ * Compile an expression in an environment and return the address of the code
+ *
+ * Returns 0 if the expression can't be compiled, see [rt_eval_error].
+ */
+int64_t rt_eval(Object expr, Object env);
+
+/**
+ * Raise the error of the last `eval` that failed to compile
+ */
+Target rt_eval_error(void);
+
+/**
+ * Raise an error to the next handler when a handler returns from `raise`
+ */
+Target rt_handler_returned(void);
+
+/**
+ * Return the next character from a port without consuming it
+ */
+Object rt_peek_char(Object port);
+
+/**
+ * Remove the escape point after `call/cc` returns normally
+ */
+void rt_pop_escape(void);
+
+/**
+ * Remove the current exception handler
+ */
+void rt_pop_handler(void);
+
+/**
+ * Remove the after thunk of a `dynamic-wind` before calling it
+ */
+void rt_pop_wind(void);
+
+/**
+ * Push a new escape point and return its id
+ */
+Object rt_push_escape(int64_t frame, int64_t address);
+
+/**
+ * Push the after thunk of a `dynamic-wind`
+ */
+void rt_push_wind(int64_t after);
+
+/**
+ * Install a guard with the frame and the address of its clauses
+ */
+void rt_push_guard(int64_t frame, int64_t address);
+
+/**
+ * Install a procedure as the current exception handler
+ */
+void rt_push_handler(int64_t address);
+
+/**
+ * Raise an object to the current handler
+ */
+Target rt_raise(Object obj);
+
+/**
+ * A non negative fixnum hash of an object, see [hash]
+ */
+Object rt_hash(Object val, Object deep);
+
+/**
+ * Raise an attempt to modify a string literal
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+ */
+Target rt_immutable_error(int64_t primitive, Object val);
+
+/**
+ * Raise an index out of the range of a vector
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED) and
+ * `val` is the object being indexed, or `()` for an invalid length or
+ * character code.
+ */
+Target rt_range_error(int64_t primitive, Object index, Object val);
+
+/**
+ * Raise a division by zero in `primitive`
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+ */
+Target rt_division_error(int64_t primitive);
+
 /**
  * Read string from a port object
  */
 Object rt_read(Object port);
 
+/**
+ * Read the next character from a port, defaults to stdin if the port is
+ * `()`
+ */
+Object rt_read_char(Object port);
+
+/**
+ * Read the next datum from a port, defaults to stdin if the port is `()`
+ */
+Object rt_read_datum(Object port);
+
+/**
+ * Put the handlers back in effect after a procedure handler returns
+ */
+void rt_resume(void);
+
+/**
+ * Lowest address the scheme stack may grow to on the current thread
+ *
+ * A small margin is left at the end of the stack to report the overflow.
+ */
+const void *rt_stack_limit(void);
+
+/**
+ * Raise a stack overflow
+ */
+Target rt_stack_overflow(void);
+
 Object rt_standard_error_port(void);
 
 Object rt_standard_input_port(void);
 
 Object rt_standard_output_port(void);
 
+/**
+ * Return `val` from the escape point of the continuation `k`
+ */
+Target rt_throw(Object k, Object val);
+
+/**
+ * Set every character of a new string to `c` and return the string
+ */
+Object rt_string_fill(Object val, Object c);
+
+/**
+ * The character at index `k` of a string; the caller checks the bounds
+ */
+Object rt_string_ref(Object val, Object k);
+
+/**
+ * Replace the character at index `k` of a mutable string; the caller checks
+ * the bounds
+ */
+Object rt_string_set(Object val, Object k, Object c);
+
+/**
+ * Raise a primitive applied to a value of the wrong type
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED) and
+ * `expected` is the tag of the expected type.
+ */
+Target rt_type_error(int64_t primitive, int64_t expected, Object val);
+
+/**
+ * Continue the transfer of control after an after thunk of `dynamic-wind`
+ */
+Target rt_unwind(void);
+
 /**
  * Write a string object to a port
  */
 Object rt_write(Object data, Object port);
 
+/**
+ * Write a character to a port
+ */
+Object rt_write_char(Object c, Object port);
+
+/**
+ * Write the external representation of an object to a port
+ */
+Object rt_write_datum(Object val, Object port);
+
+/**
+ * Write the characters of a string to a port
+ */
+Object rt_write_string(Object data, Object port);
+
+/**
+ * Bytes needed for the list returned by [rt_command_line]
+ */
+int64_t rt_command_line_size(void);
+
+/**
+ * The arguments of the program as a list of strings
+ *
+ * There must be room for [rt_command_line_size] bytes at the heap pointer.
+ */
+Object rt_command_line(void);
+
+/**
+ * Bytes needed for the value of an environment variable, see
+ * [rt_get_environment_variable]
+ */
+int64_t rt_environment_size(Object name);
+
+/**
+ * The value of an environment variable as a string, or `#f` if it isn't set
+ *
+ * There must be room for [rt_environment_size] bytes at the heap pointer.
+ */
+Object rt_get_environment_variable(Object name);
+
+/**
+ * Run a process for a primitive and wait for it to finish
+ *
+ * `primitive` is an index into [CHECKED](crate::primitives::CHECKED). Returns
+ * the exit status or the size of the output for `process-output`, or -1 if
+ * the process couldn't be started, see [rt_process_error].
+ */
+int64_t rt_process_run(int64_t primitive, Object prog, Object args);
+
+/**
+ * The output of the last `process-output` as a string
+ *
+ * There must be room for the size returned by [rt_process_run] at the heap
+ * pointer.
+ */
+Object rt_process_output(void);
+
+/**
+ * Raise the error of the last process that failed to start
+ */
+Target rt_process_error(void);
+
+/**
+ * Microseconds since an arbitrary point fixed for the life of the process
+ */
+Object current_jiffy(void);
+
+/**
+ * Number of jiffies in a second, see [current_jiffy]
+ */
+Object jiffies_per_second(void);
+
+/**
+ * Report the time and allocation of an expression, see `(time expr)`
+ */
+Object rt_time(Object expr, Object elapsed, Object allocated);
+
+/**
+ * Create a thread starting at `address` for a thread object
+ *
+ * The thread is ready to run, but the current thread continues for now.
+ */
+Object rt_spawn(Object thread, int64_t address);
+
+/**
+ * Suspend the current thread and return the context of the next one
+ *
+ * The current thread continues at `address` with the frame `frame`, whose
+ * last live slot is `top`. It waits on the key `wait` unless it is `()`.
+ * A thread that yields continues right away if there is no other thread to
+ * run, while a waiting one gets null, see [rt_deadlock].
+ */
+const Context *rt_switch(int64_t frame,
+                         int64_t top,
+                         int64_t address,
+                         int64_t base,
+                         int64_t limit,
+                         Object wait);
+
+/**
+ * Finish the current thread and return the context of the next one
+ *
+ * Wakes every thread waiting on the thread. Returns null if there is no
+ * thread to run.
+ */
+const Context *rt_exit(void);
+
+/**
+ * Raise a deadlock when no thread can run
+ */
+Target rt_deadlock(void);
+
+/**
+ * A fresh key for a thread or a channel
+ */
+Object rt_new_key(void);
+
+/**
+ * Make every thread waiting on `key` ready to run
+ */
+Object rt_wake(Object key);
+
+/**
+ * Are both the objects strings with the same characters?
+ */
+int64_t string_eq(int64_t a, int64_t b);
+
 Object string_length(int64_t val);
 
 int64_t symbol_eq(int64_t a, int64_t b);
+
+/**
+ * Start the symbol table of a program with the `count` symbols in the binary
+ * starting at `first`
+ *
+ * Symbols of a previous program on the same thread are forgotten.
+ */
+void rt_register_symbols(Object first, int64_t count);
+
+/**
+ * The symbol named by a string
+ */
+Object rt_string_to_symbol(Object name);
+
+/**
+ * A copy of the name of a symbol as a string
+ *
+ * There must be room for the string at the heap pointer.
+ */
+Object rt_symbol_to_string(Object sym);
+
+/**
+ * The `main` of a compiled program
+ *
+ * # Safety
+ *
+ * Must be called only as the entry point of an executable, with valid
+ * arguments.
+ */
+int inc_main(int argc, const char *const *argv);
+
+/**
+ * Compile a program along with the prelude to asm, see
+ * [compile_to_asm](cli::compile_to_asm)
+ *
+ * # Safety
+ *
+ * `source` must be a valid C string, and `diags` null or valid to write to.
+ */
+char *inc_compile(const char *source, inc_diag **diags);
+
+/**
+ * Evaluate a program and return its value as it would be printed, see
+ * [eval](builder::eval)
+ *
+ * Anything the program prints goes to stdout as usual.
+ *
+ * # Safety
+ *
+ * `source` must be a valid C string, and `diags` null or valid to write to.
+ */
+char *inc_eval(const char *source, inc_diag **diags);
+
+/**
+ * Free a string returned by the library
+ *
+ * # Safety
+ *
+ * `s` must be null or a string returned by the library, not freed before.
+ */
+void inc_string_free(char *s);
+
+/**
+ * Free a list of diagnostics returned by the library, along with their
+ * strings
+ *
+ * # Safety
+ *
+ * `diag` must be null or the first of a list returned by the library, not
+ * freed before.
+ */
+void inc_diag_free(inc_diag *diag);
@@ -0,0 +1,615 @@
+//! A tiny x86-64 assembler.
+//!
+//! [Encoder] turns the very same [Ins](crate::x86::Ins) values the text
+//! emitter prints into machine code bytes, so that code can be executed or
+//! written into an object file without shelling out to an external assembler.
+//!
+//! Only the handful of instructions and addressing modes the compiler actually
+//! emits are supported and everything else is an error. The encoding always
+//! picks a fixed size form for jumps and calls (a 32 bit displacement) which
+//! keeps the whole thing single pass; labels are resolved at the end once all
+//! the offsets are known. References to labels that are not defined in the
+//! same unit (runtime functions like `print` for example) are returned as
+//! [Relocation]s for the linker or JIT to patch.
+//!
+//! # Reference Reading
+//!
+//! 1. [Intel® 64 and IA-32 Architectures Software Developer Manuals](https://software.intel.com/content/www/us/en/develop/articles/intel-sdm.html),
+//!    volume 2 has the details of every instruction.
+//! 1. [x86-64 Instruction Encoding](https://wiki.osdev.org/X86-64_Instruction_Encoding)
+//!    is a much friendlier introduction to REX prefixes, ModR/M and SIB bytes.
+//! 1. [Online x86 / x64 Assembler and Disassembler](https://defuse.ca/online-x86-assembler.htm)
+//!    is handy to check the output by hand.
+//!
+//! `objdump` can disassemble the raw bytes as well
+//!
+//! ```bash
+//! $ objdump -D -b binary -m i386:x86-64 -M intel code.bin
+//! ```
+use crate::{
+    core::Error,
+    x86::{Condition, Directive, Ins, Reference, Register, Relative, ASM},
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// Encoded machine code along with its symbols
+#[derive(Debug, Default, Clone)]
+pub struct Object {
+    /// Machine code and data, in the same order as the input
+    pub code: Vec<u8>,
+    /// Offsets of all the labels defined in the code
+    pub symbols: HashMap<String, usize>,
+    /// Labels exported with `.globl`
+    pub globals: Vec<String>,
+    /// References to symbols that are not defined in this unit
+    pub relocations: Vec<Relocation>,
+}
+
+/// A 32 bit PC relative reference to an external symbol
+///
+/// The value to be written at `offset` is `S + addend - P`, where `S` is the
+/// address of the symbol and `P` the address of the field itself. This is the
+/// same as an ELF `R_X86_64_PC32` relocation.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub offset: usize,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+/// Encode instructions one at a time and collect the result with `finish`
+#[derive(Default)]
+pub struct Encoder {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    globals: Vec<String>,
+    fixups: Vec<Relocation>,
+}
+
+/// Encode a whole program
+pub fn encode(asm: &ASM) -> Result<Object, Error<'static>> {
+    let mut encoder = Encoder::new();
+
+    for ins in &asm.0 {
+        encoder.encode(ins)?;
+    }
+
+    Ok(encoder.finish())
+}
+
+/// An operand after register allocation; either a register or memory
+enum Operand {
+    Reg(u8),
+    Mem { base: u8, disp: i32 },
+}
+
+/// Opcodes for the arithmetic family as `(r/m, r)`, `(r, r/m)` and the
+/// `/digit` extension for the immediate forms.
+#[derive(Copy, Clone)]
+struct Alu(u8, u8, u8);
+
+const ADD: Alu = Alu(0x01, 0x03, 0);
+const OR: Alu = Alu(0x09, 0x0B, 1);
+const AND: Alu = Alu(0x21, 0x23, 4);
+const SUB: Alu = Alu(0x29, 0x2B, 5);
+const CMP: Alu = Alu(0x39, 0x3B, 7);
+
+impl Encoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Encode a single instruction
+    pub fn encode(&mut self, ins: &Ins) -> Result<(), Error<'static>> {
+        match ins {
+            Ins::Add(r, v) => self.alu(ADD, r, v, ins),
+            Ins::And(r, v) => self.alu(AND, r, v, ins),
+            Ins::Cmp(r, v) => self.alu(CMP, r, v, ins),
+            Ins::Or(r, v) => self.alu(OR, r, v, ins),
+            Ins::Sub(r, v) => self.alu(SUB, r, v, ins),
+
+            Ins::Call(l) => {
+                self.code.push(0xE8);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Jmp(l) => {
+                self.code.push(0xE9);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::CallIndirect(r) if !byte(*r) => {
+                self.unary(0xFF, 2, &Operand::Reg(reg(*r)));
+                Ok(())
+            }
+
+            Ins::JmpIndirect(r) if !byte(*r) => {
+                self.unary(0xFF, 4, &Operand::Reg(reg(*r)));
+                Ok(())
+            }
+
+            Ins::Jb(l) => {
+                self.code.extend(&[0x0F, 0x82]);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Je(l) => {
+                self.code.extend(&[0x0F, 0x84]);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Jle(l) => {
+                self.code.extend(&[0x0F, 0x8E]);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Jne(l) => {
+                self.code.extend(&[0x0F, 0x85]);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Jo(l) => {
+                self.code.extend(&[0x0F, 0x80]);
+                self.rel32(l, -4);
+                Ok(())
+            }
+
+            Ins::Cqo => {
+                self.code.extend(&[0x48, 0x99]);
+                Ok(())
+            }
+
+            Ins::Idiv(r) if !byte(*r) => {
+                self.unary(0xF7, 7, &Operand::Reg(reg(*r)));
+                Ok(())
+            }
+
+            Ins::Imul(v) => match operand(v) {
+                Some(v) => {
+                    self.unary(0xF7, 5, &v);
+                    Ok(())
+                }
+                None => unsupported(ins),
+            },
+
+            Ins::Lea(r, label, offset) => {
+                // ModR/M with mod = 00 and r/m = 101 is RIP relative
+                self.rex(true, reg(*r), 0);
+                self.code.push(0x8D);
+                self.code.push(((reg(*r) & 7) << 3) | 0b101);
+                self.rel32(label, offset - 4);
+                Ok(())
+            }
+
+            Ins::Mov(to, from) => self.mov(to, from, ins),
+
+            Ins::Movzx(to, Register::AL) => {
+                self.rex(true, reg(*to), 0);
+                self.code.extend(&[0x0F, 0xB6]);
+                self.modrm(reg(*to), &Operand::Reg(0));
+                Ok(())
+            }
+
+            Ins::Set(c, Register::AL) => {
+                self.code.extend(&[0x0F, 0x90 | condition(*c)]);
+                self.modrm(0, &Operand::Reg(0));
+                Ok(())
+            }
+
+            Ins::Push(Reference::Register(r)) if !byte(*r) => {
+                self.rex(false, 0, reg(*r));
+                self.code.push(0x50 + (reg(*r) & 7));
+                Ok(())
+            }
+
+            Ins::Pop(Reference::Register(r)) if !byte(*r) => {
+                self.rex(false, 0, reg(*r));
+                self.code.push(0x58 + (reg(*r) & 7));
+                Ok(())
+            }
+
+            Ins::Ret => {
+                self.code.push(0xC3);
+                Ok(())
+            }
+
+            Ins::Sal(r, v) => self.shift(4, r, v, ins),
+            Ins::Sar(r, v) => self.shift(7, r, v, ins),
+
+            Ins::Label(l) => {
+                if self.labels.insert(l.clone(), self.code.len()).is_some() {
+                    return Err(Error::Compilation(format!("Label `{}` defined twice", l)));
+                }
+                Ok(())
+            }
+
+            Ins::Directive(d) => {
+                match d {
+                    Directive::Global(name) => self.globals.push(name.clone()),
+                    Directive::Align(n) => {
+                        while self.code.len() & ((1 << n) - 1) != 0 {
+                            self.code.push(0)
+                        }
+                    }
+                    Directive::Quad(n) => self.code.extend(&n.to_le_bytes()),
+                    Directive::Asciz(s) => {
+                        self.code.extend(s.as_bytes());
+                        self.code.push(0)
+                    }
+                    // Everything ends up in one block of code that can't be
+                    // written to, see [jit](crate::jit)
+                    Directive::Data => return unsupported(ins),
+                    Directive::Text
+                    | Directive::IntelSyntax
+                    | Directive::Function(_)
+                    | Directive::Ident(_) => {}
+                }
+                Ok(())
+            }
+
+            Ins::Comment(_) | Ins::Blank => Ok(()),
+
+            _ => unsupported(ins),
+        }
+    }
+
+    /// Resolve all local labels and return the encoded object
+    pub fn finish(mut self) -> Object {
+        let mut relocations = vec![];
+
+        for fixup in self.fixups {
+            match self.labels.get(&fixup.symbol) {
+                Some(target) => {
+                    let value = *target as i64 + fixup.addend - fixup.offset as i64;
+                    let bytes = (value as i32).to_le_bytes();
+                    self.code[fixup.offset..fixup.offset + 4].copy_from_slice(&bytes);
+                }
+                None => relocations.push(fixup),
+            }
+        }
+
+        Object { code: self.code, symbols: self.labels, globals: self.globals, relocations }
+    }
+
+    // Arithmetic and logic instructions share the same set of encodings
+    fn alu(
+        &mut self,
+        op: Alu,
+        r: &Reference,
+        v: &Reference,
+        ins: &Ins,
+    ) -> Result<(), Error<'static>> {
+        match (r, v) {
+            // The only byte sized arithmetic emitted is tagging booleans
+            (Reference::Register(Register::AL), Reference::Const(c)) => match i8::try_from(*c) {
+                Ok(c) => {
+                    self.code.push(0x80);
+                    self.modrm(op.2, &Operand::Reg(0));
+                    self.code.push(c as u8);
+                    Ok(())
+                }
+                Err(_) => unsupported(ins),
+            },
+
+            (Reference::Register(a), _) | (_, Reference::Register(a)) if byte(*a) => {
+                unsupported(ins)
+            }
+
+            (_, Reference::Const(c)) => {
+                let rm = operand(r).unwrap();
+                if let Ok(c) = i8::try_from(*c) {
+                    self.unary(0x83, op.2, &rm);
+                    self.code.push(c as u8);
+                    Ok(())
+                } else if let Ok(c) = i32::try_from(*c) {
+                    self.unary(0x81, op.2, &rm);
+                    self.code.extend(&c.to_le_bytes());
+                    Ok(())
+                } else {
+                    unsupported(ins)
+                }
+            }
+
+            (_, Reference::Register(src)) => {
+                let rm = operand(r).unwrap();
+                self.rex_rm(reg(*src), &rm);
+                self.code.push(op.0);
+                self.modrm(reg(*src), &rm);
+                Ok(())
+            }
+
+            (Reference::Register(dst), Reference::Relative(_)) => {
+                let rm = operand(v).unwrap();
+                self.rex_rm(reg(*dst), &rm);
+                self.code.push(op.1);
+                self.modrm(reg(*dst), &rm);
+                Ok(())
+            }
+
+            _ => unsupported(ins),
+        }
+    }
+
+    fn mov(&mut self, to: &Reference, from: &Reference, ins: &Ins) -> Result<(), Error<'static>> {
+        match (to, from) {
+            (Reference::Register(a), _) | (_, Reference::Register(a)) if byte(*a) => {
+                unsupported(ins)
+            }
+
+            (Reference::Const(_), _) | (Reference::Relative(_), Reference::Relative(_)) => {
+                unsupported(ins)
+            }
+
+            (_, Reference::Register(src)) => {
+                let rm = operand(to).unwrap();
+                self.rex_rm(reg(*src), &rm);
+                self.code.push(0x89);
+                self.modrm(reg(*src), &rm);
+                Ok(())
+            }
+
+            (Reference::Register(dst), Reference::Relative(_)) => {
+                let rm = operand(from).unwrap();
+                self.rex_rm(reg(*dst), &rm);
+                self.code.push(0x8B);
+                self.modrm(reg(*dst), &rm);
+                Ok(())
+            }
+
+            (_, Reference::Const(c)) => match (to, i32::try_from(*c)) {
+                // Sign extended 32 bit immediate, works for registers and memory
+                (_, Ok(c)) => {
+                    self.unary(0xC7, 0, &operand(to).unwrap());
+                    self.code.extend(&c.to_le_bytes());
+                    Ok(())
+                }
+
+                // `movabs` is the only instruction that takes a 64 bit immediate
+                (Reference::Register(r), Err(_)) => {
+                    self.rex(true, 0, reg(*r));
+                    self.code.push(0xB8 + (reg(*r) & 7));
+                    self.code.extend(&c.to_le_bytes());
+                    Ok(())
+                }
+
+                _ => unsupported(ins),
+            },
+        }
+    }
+
+    fn shift(
+        &mut self,
+        ext: u8,
+        r: &Reference,
+        v: &Reference,
+        ins: &Ins,
+    ) -> Result<(), Error<'static>> {
+        let n = match v {
+            Reference::Const(n) if *n >= 0 && *n < 64 => *n as u8,
+            _ => return unsupported(ins),
+        };
+
+        match r {
+            Reference::Register(Register::AL) => {
+                self.code.push(0xC0);
+                self.modrm(ext, &Operand::Reg(0));
+            }
+            _ => match operand(r) {
+                Some(rm) => self.unary(0xC1, ext, &rm),
+                None => return unsupported(ins),
+            },
+        }
+
+        self.code.push(n);
+        Ok(())
+    }
+
+    /// Instructions with a single 64 bit `r/m` operand and an opcode extension
+    fn unary(&mut self, opcode: u8, ext: u8, rm: &Operand) {
+        self.rex_rm(0, rm);
+        self.code.push(opcode);
+        self.modrm(ext, rm);
+    }
+
+    /// REX prefix; required for 64 bit operands and the extended registers
+    fn rex(&mut self, wide: bool, reg: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (base >> 3);
+
+        if rex != 0x40 {
+            self.code.push(rex)
+        }
+    }
+
+    fn rex_rm(&mut self, reg: u8, rm: &Operand) {
+        match rm {
+            Operand::Reg(r) => self.rex(true, reg, *r),
+            Operand::Mem { base, .. } => self.rex(true, reg, *base),
+        }
+    }
+
+    /// ModR/M byte, followed by SIB and displacement for memory operands
+    fn modrm(&mut self, reg: u8, rm: &Operand) {
+        let reg = (reg & 7) << 3;
+
+        match rm {
+            Operand::Reg(r) => self.code.push(0b11_000_000 | reg | (r & 7)),
+            Operand::Mem { base, disp } => {
+                // RBP and R13 can't be encoded without a displacement, since
+                // that bit pattern means RIP relative.
+                let (mode, size) = if *disp == 0 && base & 7 != 5 {
+                    (0b00, 0)
+                } else if i8::try_from(*disp).is_ok() {
+                    (0b01, 1)
+                } else {
+                    (0b10, 4)
+                };
+
+                self.code.push(mode << 6 | reg | (base & 7));
+
+                // RSP and R12 as the base needs a SIB byte with no index
+                if base & 7 == 4 {
+                    self.code.push(0x24);
+                }
+
+                self.code.extend(&disp.to_le_bytes()[..size]);
+            }
+        }
+    }
+
+    /// A 32 bit PC relative reference to a label, resolved in `finish`
+    fn rel32(&mut self, label: &str, addend: i64) {
+        self.fixups.push(Relocation { offset: self.code.len(), symbol: label.to_string(), addend });
+        self.code.extend(&[0, 0, 0, 0]);
+    }
+}
+
+/// Register numbers as used in the encoding, extended registers take 4 bits
+const fn reg(r: Register) -> u8 {
+    match r {
+        Register::RAX | Register::AL => 0,
+        Register::RCX => 1,
+        Register::RDX => 2,
+        Register::RBX => 3,
+        Register::RSP => 4,
+        Register::RBP => 5,
+        Register::RSI => 6,
+        Register::RDI => 7,
+        Register::R8 => 8,
+        Register::R9 => 9,
+        Register::R10 => 10,
+        Register::R11 => 11,
+        Register::R12 => 12,
+        Register::R13 => 13,
+        Register::R14 => 14,
+        Register::R15 => 15,
+    }
+}
+
+const fn byte(r: Register) -> bool {
+    matches!(r, Register::AL)
+}
+
+fn operand(r: &Reference) -> Option<Operand> {
+    match r {
+        Reference::Register(r) if byte(*r) => None,
+        Reference::Register(r) => Some(Operand::Reg(reg(*r))),
+        Reference::Relative(Relative { register, offset }) => {
+            i32::try_from(*offset).ok().map(|disp| Operand::Mem { base: reg(*register), disp })
+        }
+        Reference::Const(_) => None,
+    }
+}
+
+/// Condition codes as used in `Jcc`, `SETcc` and `CMOVcc`
+const fn condition(c: Condition) -> u8 {
+    match c {
+        Condition::E => 0x4,
+        Condition::NE => 0x5,
+        Condition::L => 0xC,
+        Condition::GE => 0xD,
+        Condition::LE => 0xE,
+        Condition::G => 0xF,
+    }
+}
+
+fn unsupported(ins: &Ins) -> Result<(), Error<'static>> {
+    Err(Error::Compilation(format!("Cannot encode instruction `{}`", ins)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{self, Register::*};
+    use pretty_assertions::assert_eq;
+
+    fn bytes(ins: Ins) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.encode(&ins).unwrap();
+        e.finish().code
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(bytes(x86::mov(RBP.into(), RSP.into())), [0x48, 0x89, 0xE5]);
+        assert_eq!(bytes(x86::mov(R12.into(), RDI.into())), [0x49, 0x89, 0xFC]);
+        assert_eq!(bytes(x86::push(RBP.into())), [0x55]);
+        assert_eq!(bytes(x86::pop(R12.into())), [0x41, 0x5C]);
+        assert_eq!(bytes(x86::idiv(RCX)), [0x48, 0xF7, 0xF9]);
+        assert_eq!(bytes(x86::cqo()), [0x48, 0x99]);
+        assert_eq!(bytes(x86::ret()), [0xC3]);
+        assert_eq!(bytes(x86::call_indirect(RDX)), [0x48, 0xFF, 0xD2]);
+        assert_eq!(bytes(x86::jmp_indirect(R11)), [0x49, 0xFF, 0xE3]);
+    }
+
+    #[test]
+    fn immediates() {
+        assert_eq!(bytes(x86::mov(RAX.into(), 8.into())), [0x48, 0xC7, 0xC0, 8, 0, 0, 0]);
+        assert_eq!(bytes(x86::add(RAX.into(), 8.into())), [0x48, 0x83, 0xC0, 8]);
+        assert_eq!(bytes(x86::sub(RSP.into(), 1024.into())), [0x48, 0x81, 0xEC, 0, 4, 0, 0]);
+        assert_eq!(bytes(x86::sar(RAX.into(), 3.into())), [0x48, 0xC1, 0xF8, 3]);
+
+        assert_eq!(
+            bytes(x86::mov(RAX.into(), (1 << 40).into())),
+            [0x48, 0xB8, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        // RBP needs an explicit displacement and R12 needs a SIB byte
+        assert_eq!(bytes(x86::mov(RAX.into(), (RBP - 8).into())), [0x48, 0x8B, 0x45, 0xF8]);
+        assert_eq!(bytes(x86::mov((R12 + 0).into(), RAX.into())), [0x49, 0x89, 0x04, 0x24]);
+        assert_eq!(
+            bytes(x86::mov((R12 + 8).into(), 3.into())),
+            [0x49, 0xC7, 0x44, 0x24, 0x08, 3, 0, 0, 0]
+        );
+        assert_eq!(bytes(x86::imul((RBP - 16).into())), [0x48, 0xF7, 0x6D, 0xF0]);
+        assert_eq!(bytes(x86::cmp((RBP - 8).into(), RAX.into())), [0x48, 0x39, 0x45, 0xF8]);
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(bytes(x86::set(Condition::L, AL)), [0x0F, 0x9C, 0xC0]);
+        assert_eq!(bytes(x86::movzx(RAX, AL)), [0x48, 0x0F, 0xB6, 0xC0]);
+        assert_eq!(bytes(x86::sal(AL.into(), 3.into())), [0xC0, 0xE0, 3]);
+        assert_eq!(bytes(x86::or(AL.into(), 1.into())), [0x80, 0xC8, 1]);
+    }
+
+    #[test]
+    fn labels() {
+        let asm = x86::label("top")
+            + x86::jmp("end")
+            + x86::je("top")
+            + x86::jb("end")
+            + x86::jne("end")
+            + x86::jo("top")
+            + x86::call("print")
+            + x86::label("end");
+
+        let obj = encode(&asm).unwrap();
+
+        assert_eq!(
+            obj.code,
+            [
+                0xE9, 29, 0, 0, 0, 0x0F, 0x84, 0xF5, 0xFF, 0xFF, 0xFF, 0x0F, 0x82, 17, 0, 0, 0,
+                0x0F, 0x85, 11, 0, 0, 0, 0x0F, 0x80, 0xE3, 0xFF, 0xFF, 0xFF, 0xE8, 0, 0, 0, 0
+            ]
+        );
+        assert_eq!(
+            obj.relocations,
+            [Relocation { offset: 30, symbol: "print".into(), addend: -4 }]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Encoder::new().encode(&x86::mov((RBP - 8).into(), (RBP - 16).into())).is_err());
+        assert!(Encoder::new().encode(&x86::mov((RBP - 8).into(), (1 << 40).into())).is_err());
+    }
+}
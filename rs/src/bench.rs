@@ -0,0 +1,160 @@
+//! Benchmark compiled programs
+//!
+//! `inc bench fib.scm` builds the program once and runs the executable a few
+//! times, reporting the wall time of the runs and what the collector did. The
+//! time includes starting the process, which is the same for every program and
+//! small next to any benchmark worth running.
+//!
+//! The heap counters come from the runtime, which prints them when the program
+//! is done if `INC_STATS` is set, see [gc::report]. Allocation doesn't depend
+//! on the machine, so it is often a better measure of an optimization than the
+//! time.
+//!
+//! `--compare` builds the program again with another set of passes and shows
+//! both, which is how the optimization passes are evaluated.
+//!
+//! ```text
+//! $ inc bench --runs 20 --compare=-O0 fib.scm
+//! fold, anf, tco  min 10.512ms  median 10.804ms  stddev 0.214ms  1024 bytes  0 collections
+//! none            min 12.039ms  median 12.327ms  stddev 0.305ms  1024 bytes  0 collections
+//! ```
+use crate::{
+    cli,
+    core::{Config, Error},
+    gc,
+};
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// Measurements of one run of a program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub elapsed: Duration,
+    /// Bytes allocated in the heap
+    pub allocated: usize,
+    pub collections: usize,
+}
+
+/// Measurements of several runs of a program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub runs: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub stddev: Duration,
+    /// Median of the bytes allocated by each run
+    pub allocated: usize,
+    /// Median of the collections of each run
+    pub collections: usize,
+}
+
+impl Summary {
+    /// Summarize a non empty set of samples
+    pub fn new(samples: &[Sample]) -> Self {
+        fn median<T: Ord + Copy>(mut values: Vec<T>) -> T {
+            values.sort_unstable();
+            values[values.len() / 2]
+        }
+
+        let secs: Vec<f64> = samples.iter().map(|s| s.elapsed.as_secs_f64()).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+
+        Summary {
+            runs: samples.len(),
+            min: samples.iter().map(|s| s.elapsed).min().unwrap_or_default(),
+            median: median(samples.iter().map(|s| s.elapsed).collect()),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            allocated: median(samples.iter().map(|s| s.allocated).collect()),
+            collections: median(samples.iter().map(|s| s.collections).collect()),
+        }
+    }
+}
+
+/// Build the program in `config` and run it `runs` times
+///
+/// The executable is written to `config.output` and removed once it is done.
+pub fn run(config: &Config, runs: usize) -> Result<Summary, Error<'_>> {
+    cli::run(config, cli::Action::Build)?;
+
+    let samples = (0..runs.max(1)).map(|_| sample(config)).collect::<Result<Vec<_>, _>>();
+
+    for path in &[config.output.clone(), config.asm()] {
+        fs::remove_file(path).ok();
+    }
+
+    Ok(Summary::new(&samples?))
+}
+
+/// Run the executable once
+fn sample(config: &Config) -> Result<Sample, Error<'static>> {
+    let path = PathBuf::from(&config.output).canonicalize()?;
+
+    let start = Instant::now();
+    let exe = Command::new(path)
+        .env("INC_STATS", "1")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+    let elapsed = start.elapsed();
+
+    let stderr = String::from_utf8_lossy(&exe.stderr);
+
+    if !exe.status.success() {
+        return Err(Error::Runtime(format!(
+            "Benchmark failed with code: `{:?}`\n{}",
+            exe.status.code(),
+            stderr.trim()
+        )));
+    }
+
+    let counter = |name: &str| counter(&stderr, name).unwrap_or_default();
+
+    Ok(Sample { elapsed, allocated: counter("allocated"), collections: counter("collections") })
+}
+
+/// A counter printed by [gc::report] in the output of a program
+fn counter(stderr: &str, name: &str) -> Option<usize> {
+    let line = stderr.lines().rev().find_map(|line| line.strip_prefix(gc::STATS_PREFIX))?;
+
+    line.split_whitespace().find_map(|pair| match pair.split_once('=') {
+        Some((k, v)) if k == name => v.parse().ok(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let sample = |ms, allocated| Sample {
+            elapsed: Duration::from_millis(ms),
+            allocated,
+            collections: 0,
+        };
+
+        let summary = Summary::new(&[sample(30, 16), sample(10, 16), sample(20, 32)]);
+
+        assert_eq!(summary.runs, 3);
+        assert_eq!(summary.min, Duration::from_millis(10));
+        assert_eq!(summary.median, Duration::from_millis(20));
+        assert_eq!(summary.allocated, 16);
+        assert_eq!(summary.stddev.as_micros(), 8164);
+    }
+
+    #[test]
+    fn counters() {
+        let stderr = "hello\ninc-stats: collections=3 minor=3 allocated=4096\n";
+
+        assert_eq!(counter(stderr, "allocated"), Some(4096));
+        assert_eq!(counter(stderr, "collections"), Some(3));
+        assert_eq!(counter(stderr, "pause"), None);
+        assert_eq!(counter("", "allocated"), None);
+    }
+}
@@ -0,0 +1,480 @@
+//! Arbitrary precision integers
+//!
+//! Fixnums are 61 bit integers and the result of `+`, `-` or `*` doesn't
+//! always fit. Generated code checks the overflow flag after the fast fixnum
+//! operation and falls back to the runtime, which promotes the operands to
+//! bignums and works out the exact result. Results small enough to be a fixnum
+//! are always demoted back, so a number has exactly one representation and a
+//! bignum is never zero.
+//!
+//! Integer literals too large for a fixnum are read as bignums, and allocated
+//! on the heap like flonum literals every time they are evaluated.
+//!
+//! A bignum is a string object as far as the collector is concerned, with the
+//! [BIGNUM] bit set in the length prefix. The bytes are the sign followed by
+//! the magnitude in base 2^32, least significant digit first.
+//!
+//! ```txt
+//!  ---------------------------------------
+//! | BIGNUM or 9 | 1 | 0x00000000 | 0x1   |  => -(2^32)
+//!  ---------------------------------------
+//! ```
+//!
+//! Mixed with flonums, bignums are converted to the nearest double; see
+//! [numbers](crate::numbers) for the runtime entry points of the tower.
+use crate::{
+    compiler::state::State,
+    gc,
+    immediate::{self, *},
+    rt::{self, Object},
+    tags::{tag, untag},
+    x86::{self, Reference, Register::*, ASM, WORDSIZE},
+};
+use std::{cmp::Ordering, fmt};
+
+/// Marks the length of a string object that holds a bignum
+pub const BIGNUM: i64 = 1 << 61;
+
+/// An integer of any size, as a sign and magnitude
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Big {
+    negative: bool,
+    // Base 2^32 digits, least significant first and without leading zeros
+    digits: Vec<u32>,
+}
+
+impl Big {
+    /// A normalized integer; zero is never negative
+    fn new(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+
+        Big { negative: negative && !digits.is_empty(), digits }
+    }
+
+    /// The value of a fixnum or a bignum object, `None` for anything else
+    pub fn decode(val: Object) -> Option<Self> {
+        if tag(val.0) == NUM {
+            return Some(Self::from(untag(val.0)));
+        }
+
+        if !is(val.0) {
+            return None;
+        }
+
+        let bytes = bytes(val.0);
+        let digits =
+            bytes[1..].chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+
+        Some(Self::new(bytes[0] == 1, digits))
+    }
+
+    /// A fixnum if the value fits in one, a new bignum on the heap otherwise
+    ///
+    /// There must be room for [Big::size] bytes at the heap pointer.
+    pub fn encode(&self) -> Object {
+        if let Some(n) = self.fixnum() {
+            return Object::immediate(n);
+        }
+
+        let len = 1 + 4 * self.digits.len();
+        let r12 = rt::heap();
+        rt::allocate(self.size());
+
+        unsafe {
+            std::ptr::write(r12 as *mut i64, len as i64 | BIGNUM);
+
+            let data = (r12 + WORDSIZE as usize) as *mut u8;
+            std::ptr::write(data, self.negative as u8);
+            for (i, d) in self.digits.iter().enumerate() {
+                std::ptr::copy(d.to_le_bytes().as_ptr(), data.add(1 + 4 * i), 4);
+            }
+        }
+
+        Object::new(r12 as i64 | STR)
+    }
+
+    /// Bytes taken by the heap object of the number, including the prefix
+    // Same as a string with a byte for the sign and 4 for each digit
+    pub fn size(&self) -> usize {
+        bytes_for(self.digits.len())
+    }
+
+    /// Upper bound on the [size](Big::size) of the result of an arithmetic
+    /// primitive
+    pub fn bound(primitive: &str, a: &Self, b: &Self) -> usize {
+        bytes_for(match primitive {
+            "*" => a.digits.len() + b.digits.len(),
+            _ => a.digits.len().max(b.digits.len()) + 1,
+        })
+    }
+
+    /// Apply an arithmetic primitive, the divisor of `/` and `%` can't be zero
+    ///
+    /// Division truncates towards zero, just like with fixnums.
+    pub fn apply(primitive: &str, a: &Self, b: &Self) -> Self {
+        match primitive {
+            "+" | "inc" => a.add(b),
+            "-" | "dec" => a.sub(b),
+            "*" => a.mul(b),
+            "/" => a.div_rem(b).0,
+            "%" => a.div_rem(b).1,
+            p => unreachable!("`{}` is not an arithmetic primitive", p),
+        }
+    }
+
+    /// The integer written in decimal, with an optional sign
+    ///
+    /// The digits are read nine at a time, the most a digit of the magnitude
+    /// can take in one step.
+    pub fn parse(s: &str) -> Self {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+
+        let n = digits.as_bytes().chunks(9).fold(Self::from(0), |n, chunk| {
+            let scale = 10i64.pow(chunk.len() as u32);
+            let chunk = chunk.iter().fold(0, |acc, d| acc * 10 + i64::from(d - b'0'));
+            n.mul(&Self::from(scale)).add(&Self::from(chunk))
+        });
+
+        Self::new(negative, n.digits)
+    }
+
+    /// The nearest double, which loses precision beyond 53 bits
+    pub fn to_f64(&self) -> f64 {
+        let m = self.digits.iter().rev().fold(0.0, |acc, d| acc * 4_294_967_296.0 + f64::from(*d));
+        if self.negative {
+            -m
+        } else {
+            m
+        }
+    }
+
+    /// The exact value of a double, `None` unless it is a finite integer
+    pub fn from_f64(f: f64) -> Option<Self> {
+        if !f.is_finite() || f.fract() != 0.0 {
+            return None;
+        }
+
+        if f.abs() < 9_223_372_036_854_775_808.0 {
+            return Some(Self::from(f as i64));
+        }
+
+        // Large doubles are a 53 bit mantissa shifted left by the exponent
+        let bits = f.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+
+        let mut n = Self::from(mantissa as i64);
+        for _ in 0..exponent {
+            n = n.add(&n);
+        }
+
+        Some(Self::new(f < 0.0, n.digits))
+    }
+
+    /// The value as an `i64` if it fits in a fixnum
+    pub fn fixnum(&self) -> Option<i64> {
+        let magnitude = match self.digits.as_slice() {
+            [] => 0,
+            [a] => u64::from(*a),
+            [a, b] => u64::from(*a) | (u64::from(*b) << 32),
+            _ => return None,
+        };
+
+        let limit = 1u64 << (63 - SHIFT);
+
+        if self.negative && magnitude <= limit {
+            Some((magnitude as i64).wrapping_neg())
+        } else if !self.negative && magnitude < limit {
+            Some(magnitude as i64)
+        } else {
+            None
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return Self::new(self.negative, add(&self.digits, &other.digits));
+        }
+
+        match compare(&self.digits, &other.digits) {
+            Ordering::Less => Self::new(other.negative, sub(&other.digits, &self.digits)),
+            _ => Self::new(self.negative, sub(&self.digits, &other.digits)),
+        }
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        self.add(&Self::new(!other.negative, other.digits.clone()))
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let mut digits = vec![0u32; self.digits.len() + other.digits.len()];
+
+        for (i, a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+
+            for (j, b) in other.digits.iter().enumerate() {
+                let t = u64::from(*a) * u64::from(*b) + u64::from(digits[i + j]) + carry;
+                digits[i + j] = t as u32;
+                carry = t >> 32;
+            }
+
+            digits[i + other.digits.len()] = carry as u32;
+        }
+
+        Self::new(self.negative != other.negative, digits)
+    }
+
+    /// Truncated division, the remainder has the sign of the dividend
+    // Plain binary long division, one bit of the quotient at a time
+    fn div_rem(&self, other: &Self) -> (Self, Self) {
+        let divisor = Self::new(false, other.digits.clone());
+        let mut quotient = vec![0u32; self.digits.len()];
+        let mut rem = Self::from(0);
+
+        for i in (0..32 * self.digits.len()).rev() {
+            let bit = (self.digits[i / 32] >> (i % 32)) & 1;
+            rem = rem.add(&rem).add(&Self::from(i64::from(bit)));
+
+            if rem >= divisor {
+                rem = rem.sub(&divisor);
+                quotient[i / 32] |= 1 << (i % 32);
+            }
+        }
+
+        (Self::new(self.negative != other.negative, quotient), Self::new(self.negative, rem.digits))
+    }
+
+    /// Divide the magnitude by a single digit in place and return the remainder
+    fn divide(digits: &mut [u32], d: u32) -> u32 {
+        let mut rem = 0u64;
+
+        for digit in digits.iter_mut().rev() {
+            let t = (rem << 32) | u64::from(*digit);
+            *digit = (t / u64::from(d)) as u32;
+            rem = t % u64::from(d);
+        }
+
+        rem as u32
+    }
+}
+
+impl From<i64> for Big {
+    fn from(n: i64) -> Self {
+        let m = n.unsigned_abs();
+        Self::new(n < 0, vec![m as u32, (m >> 32) as u32])
+    }
+}
+
+impl Ord for Big {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare(&self.digits, &other.digits),
+            (true, true) => compare(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for Big {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Big {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Peel off 9 decimal digits at a time, least significant first
+        let mut digits = self.digits.clone();
+        let mut chunks = vec![];
+
+        while !digits.is_empty() {
+            chunks.push(Self::divide(&mut digits, 1_000_000_000));
+            while digits.last() == Some(&0) {
+                digits.pop();
+            }
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, rest)) => {
+                write!(f, "{}", first)?;
+                rest.iter().rev().try_for_each(|c| write!(f, "{:09}", c))
+            }
+        }
+    }
+}
+
+/// Allocate a bignum literal on the heap and move the reference to RAX
+///
+/// The object is laid out exactly like [Big::encode] does at run time, and
+/// written a word at a time.
+pub fn eval(s: &mut State, n: &Big) -> ASM {
+    if let Some(n) = n.fixnum() {
+        return x86::mov(RAX.into(), immediate::n(n).into()).into();
+    }
+
+    let size = n.size();
+    let mut bytes = vec![0u8; size];
+
+    bytes[..8].copy_from_slice(&((1 + 4 * n.digits.len()) as i64 | BIGNUM).to_le_bytes());
+    bytes[8] = n.negative as u8;
+    for (i, d) in n.digits.iter().enumerate() {
+        bytes[9 + 4 * i..13 + 4 * i].copy_from_slice(&d.to_le_bytes());
+    }
+
+    let mut asm = gc::alloc(s, Reference::Const(size as i64));
+
+    for (i, word) in bytes.chunks(8).enumerate() {
+        let word = word.iter().rev().fold(0, |acc, b| (acc << 8) | i64::from(*b));
+        asm += x86::mov(R11.into(), word.into());
+        asm += x86::mov(Reference::from(R12 + (i as i64 * WORDSIZE)), R11.into());
+    }
+
+    asm + x86::mov(RAX.into(), R12.into())
+        + x86::add(R12.into(), Reference::from(size as i64))
+        + x86::or(RAX.into(), STR.into())
+}
+
+/// Is the object a bignum?
+pub const fn is(val: i64) -> bool {
+    tag(val) == STR && unsafe { *((val - STR) as *const i64) } >> BIGNUM.trailing_zeros() == 1
+}
+
+/// Sign and digits of a bignum object
+fn bytes<'a>(val: i64) -> &'a [u8] {
+    unsafe {
+        let len = *((val - STR) as *const i64) & !BIGNUM;
+        std::slice::from_raw_parts((val - STR + WORDSIZE) as *const u8, len as usize)
+    }
+}
+
+/// Bytes taken by a bignum with `n` digits, see [Big::size]
+const fn bytes_for(n: usize) -> usize {
+    (WORDSIZE as usize + 1 + 4 * n + 1 + 7) & !7
+}
+
+/// Compare magnitudes
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+
+    for i in 0..a.len().max(b.len()) {
+        let t = u64::from(*a.get(i).unwrap_or(&0)) + u64::from(*b.get(i).unwrap_or(&0)) + carry;
+        digits.push(t as u32);
+        carry = t >> 32;
+    }
+
+    digits.push(carry as u32);
+    digits
+}
+
+/// Subtract magnitudes, `a` must not be smaller than `b`
+fn sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+
+    for (i, x) in a.iter().enumerate() {
+        let mut t = i64::from(*x) - i64::from(*b.get(i).unwrap_or(&0)) - borrow;
+        borrow = (t < 0) as i64;
+        if t < 0 {
+            t += 1 << 32;
+        }
+        digits.push(t as u32);
+    }
+
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(n: i64) -> Big {
+        Big::from(n)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(big(7).add(&big(-9)), big(-2));
+        assert_eq!(big(-7).sub(&big(-9)), big(2));
+        assert_eq!(big(1 << 40).mul(&big(-3)), big(-3 << 40));
+        assert_eq!(big(5).sub(&big(5)), big(0));
+        assert!(!big(5).sub(&big(5)).negative);
+    }
+
+    #[test]
+    fn division() {
+        let n = big(1 << 40).mul(&big(1 << 40));
+
+        assert_eq!(n.div_rem(&big(1 << 30)), (big(1 << 50), big(0)));
+        assert_eq!(n.add(&big(7)).div_rem(&big(-(1 << 40))), (big(-1 << 40), big(7)));
+        assert_eq!(big(-7).div_rem(&big(2)), (big(-3), big(-1)));
+    }
+
+    #[test]
+    fn doubles() {
+        let n = big(1 << 60).mul(&big(-(1 << 10)));
+
+        assert_eq!(n.to_f64(), -(2f64.powi(70)));
+        assert_eq!(Big::from_f64(-(2f64.powi(70))), Some(n));
+        assert_eq!(Big::from_f64(-3.0), Some(big(-3)));
+        assert_eq!(big(7).to_f64(), 7.0);
+        assert_eq!(Big::from_f64(1.5), None);
+        assert_eq!(Big::from_f64(f64::NAN), None);
+        assert_eq!(Big::from_f64(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn fixnums() {
+        let limit = 1 << 60;
+
+        assert_eq!(big(limit - 1).fixnum(), Some(limit - 1));
+        assert_eq!(big(-limit).fixnum(), Some(-limit));
+        assert_eq!(big(limit).fixnum(), None);
+        assert_eq!(big(limit).mul(&big(limit)).fixnum(), None);
+    }
+
+    #[test]
+    fn order() {
+        assert!(big(-1 << 50) < big(3));
+        assert!(big(1 << 50).mul(&big(1 << 50)) > big(1 << 62));
+        assert!(big(-1 << 50).mul(&big(1 << 50)) < big(-1 << 62));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Big::parse("0"), big(0));
+        assert_eq!(Big::parse("-42"), big(-42));
+        assert_eq!(Big::parse("+9223372036854775807"), big(i64::MAX));
+        assert_eq!(Big::parse("1000000000"), big(1_000_000_000));
+
+        let n = "-1329227995784915872903807060280344576";
+        assert_eq!(Big::parse(n), big(1 << 60).mul(&big(1 << 60)).mul(&big(-1)));
+        assert_eq!(Big::parse(n).to_string(), n);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(big(0).to_string(), "0");
+        assert_eq!(big(-42).to_string(), "-42");
+        assert_eq!(big(i64::MAX).to_string(), "9223372036854775807");
+
+        let n = big(1 << 60).mul(&big(1 << 60)).mul(&big(-1));
+        assert_eq!(n.to_string(), "-1329227995784915872903807060280344576");
+    }
+}
@@ -0,0 +1,302 @@
+//! Compile programs from other Rust programs
+//!
+//! The [Compiler] is the whole compiler behind a builder, for programs that
+//! embed inc instead of running the binary. A program is given as a string,
+//! compiled with the options set on the builder into an [Artifact], which can
+//! be run in memory or built into an executable.
+//!
+//! ```
+//! use inc::Compiler;
+//!
+//! let artifact = Compiler::new()
+//!     .source("(define (twice x) (* x 2)) (twice 21)")
+//!     .opt_level(1)
+//!     .compile()
+//!     .unwrap();
+//!
+//! assert!(artifact.asm().contains("twice"));
+//! assert_eq!(artifact.run().unwrap(), "42");
+//! ```
+//!
+//! Errors in the program are returned as [Diagnostic]s, like the binary shows
+//! them, with the source named `<input>`.
+//!
+//! Programs that only need the value of some Scheme, like when it is used as
+//! an extension language, can [eval] it instead and get back a [Value].
+//!
+//! ```
+//! use inc::Value;
+//!
+//! assert_eq!(inc::eval("(+ 1 2)").unwrap(), Value::Int(3));
+//! assert_eq!(inc::eval("(cons 1 (cons 2 ()))").unwrap().to_string(), "(1 2)");
+//! ```
+
+use crate::{
+    cli::{self, Driver},
+    compiler::{self, emit},
+    core::{Config, Error, Expr, Literal::*, Syntax, Target},
+    diagnostic::Diagnostic,
+    host,
+    interp::{self, Outcome},
+    lang,
+    lang::Passes,
+    parser,
+    value::Value,
+    x86::{self, ASM},
+};
+#[cfg(feature = "native")]
+use crate::{jit, repl};
+use std::fs;
+
+/// Name of the program in diagnostics
+const INPUT: &str = "<input>";
+
+/// A builder for compiling a program, see the [module](self) docs
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    program: String,
+    load: Vec<String>,
+    prelude: Option<bool>,
+    level: Option<u8>,
+    passes: Option<Passes>,
+    target: Option<String>,
+}
+
+/// A compiled program
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    asm: ASM,
+    target: Target,
+}
+
+impl Compiler {
+    /// A compiler for an empty program, with all the defaults of the binary
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The program to compile
+    pub fn source<S: Into<String>>(mut self, source: S) -> Self {
+        self.program = source.into();
+        self
+    }
+
+    /// Load a file before the program, like `--load`
+    pub fn load<S: Into<String>>(mut self, path: S) -> Self {
+        self.load.push(path.into());
+        self
+    }
+
+    /// Compile the program with the prelude or without it, with it by default
+    pub const fn prelude(mut self, prelude: bool) -> Self {
+        self.prelude = Some(prelude);
+        self
+    }
+
+    /// Optimization level from 0 to 2, like `-O`, see [Passes::level]
+    pub const fn opt_level(mut self, level: u8) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Run exactly these passes, overriding the optimization level
+    pub const fn passes(mut self, passes: Passes) -> Self {
+        self.passes = Some(passes);
+        self
+    }
+
+    /// Platform to compile for by its triple, the host by default
+    pub fn target<S: Into<String>>(mut self, triple: S) -> Self {
+        self.target = Some(triple.into());
+        self
+    }
+
+    /// The options as a [Config] for the [Driver]
+    ///
+    /// An unknown level or target is an error, reported like one in the
+    /// program would be.
+    fn config(&self) -> Result<Config, Error<'static>> {
+        let passes = match (self.passes, self.level) {
+            (Some(passes), _) => passes,
+            (None, Some(level)) => Passes::level(&level.to_string()).map_err(Error::Compilation)?,
+            (None, None) => Passes::default(),
+        };
+
+        let target = match &self.target {
+            Some(triple) => Target::parse(triple).map_err(Error::Compilation)?,
+            None => Target::host(),
+        };
+
+        Ok(Config {
+            program: self.program.clone(),
+            load: self.load.clone(),
+            prelude: self.prelude.unwrap_or(true),
+            passes,
+            target,
+            ..Config::default()
+        })
+    }
+
+    /// Compile the program, with every error as a [Diagnostic]
+    ///
+    /// This never panics for invalid programs, see
+    /// [compile_to_asm](crate::cli::compile_to_asm). The compiler runs
+    /// [deep](host::deep), like the command line.
+    pub fn compile(&self) -> Result<Artifact, Vec<Diagnostic>> {
+        let sources = [(INPUT, self.program.as_str())];
+        let config = self.config().map_err(|e| Diagnostic::all(&e, &sources))?;
+
+        host::deep(|| match Driver::new(&config).compile() {
+            Ok(asm) => Ok(Artifact { asm, target: config.target.clone() }),
+            Err(e) => Err(Diagnostic::all(&e, &[(INPUT, config.program.as_str())])),
+        })
+    }
+}
+
+impl Artifact {
+    /// The generated code of the program
+    pub fn asm(&self) -> String {
+        self.asm.to_string()
+    }
+
+    /// Run the program in memory and return its value, like `run --jit`
+    #[cfg(feature = "native")]
+    pub fn run(&self) -> Result<String, Error<'static>> {
+        jit::load(&self.asm).map(|image| image.run(|val| val.to_string()))
+    }
+
+    /// Build an executable at `output`, like `build -o`
+    ///
+    /// The asm is written next to it, at `output.s`.
+    pub fn build(&self, output: &str) -> Result<(), Error<'static>> {
+        let target = self.target.clone();
+        let config = Config { output: output.to_string(), target, ..Config::default() };
+        let asm = x86::ident(&self.target) + self.asm.clone();
+
+        fs::write(config.asm(), asm.to_string()).map_err(|e| Error::Internal {
+            message: format!("Failed to write {}", config.asm()),
+            e: Some(e),
+        })?;
+
+        cli::build(&config)
+    }
+}
+
+/// Evaluate some Scheme in this process and return its value
+///
+/// The source is evaluated along with the prelude by the
+/// [interpreter](crate::interp), or compiled and run with the [jit] if it needs
+/// anything the interpreter doesn't support, like the [repl] does. Anything
+/// printed goes to stdout as usual. An error raised and not caught is returned
+/// as [Error::Runtime] instead of ending the process.
+///
+/// The value is that of the last expression, or `()` without any.
+pub fn eval(source: &str) -> Result<Value, Error<'_>> {
+    let prog = parser::parse(source)?;
+    let prog = lang::load(prog).map_err(Error::compilation)?;
+    let all = compiler::prelude().into_iter().chain(prog.clone()).collect();
+
+    match interp::eval(all, Passes::default()) {
+        Ok(Outcome { output, value }) => {
+            print!("{}", output);
+            value.map(Value::new).ok_or(Error::Exit { status: 0, message: output })
+        }
+        #[cfg(feature = "native")]
+        Err(Error::Internal { .. }) => jit(prog),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compile a loaded program along with the prelude and run it with the [jit],
+/// returning the value of the last expression like [eval]
+#[cfg(feature = "native")]
+pub(crate) fn jit(prog: Vec<Syntax>) -> Result<Value, Error<'static>> {
+    // Definitions stay at the top level and the rest is guarded
+    let (mut prog, exprs): (Vec<Syntax>, Vec<Syntax>) =
+        prog.into_iter().partition(|e| matches!(e, Expr::Define { .. }));
+    let value = !exprs.is_empty();
+    if value {
+        prog.push(repl::guard(exprs));
+    }
+
+    let prog = compiler::prelude().into_iter().chain(prog).collect();
+    let asm = emit::compile(prog).map_err(Error::compilation)?;
+    let image = jit::load(&asm)?;
+
+    image.run(|val| {
+        let val = if value { val.deref() } else { Expr::Literal(Nil) };
+
+        match repl::raised(&val) {
+            Some(message) => Err(Error::Runtime(format!("Exception: {}", message))),
+            None => Ok(Value::new(val)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::codes;
+
+    #[test]
+    fn compile() {
+        let compiler = Compiler::new().source("(define (f x) (+ x 1)) (f 41)");
+
+        assert_eq!(compiler.compile().unwrap().run().unwrap(), "42");
+        assert_eq!(compiler.clone().opt_level(0).compile().unwrap().run().unwrap(), "42");
+        assert_eq!(compiler.clone().prelude(false).compile().unwrap().run().unwrap(), "42");
+
+        let errors = compiler.clone().opt_level(3).compile().unwrap_err();
+        assert_eq!(errors[0].code, codes::COMPILE);
+        assert!(errors[0].message.contains("Unknown optimization level `3`"));
+
+        let errors = compiler.target("aarch64-linux").compile().unwrap_err();
+        assert!(errors[0].message.contains("Unsupported target aarch64"));
+
+        let errors = Compiler::new().source("1").load("/nonexistent.scm").compile().unwrap_err();
+        assert_eq!(errors[0].code, codes::COMPILE);
+        assert!(errors[0].message.contains("/nonexistent.scm"));
+
+        let errors = Compiler::new().source("(1").compile().unwrap_err();
+        assert_eq!(errors[0].code, codes::PARSE);
+        assert_eq!(errors[0].file.as_deref(), Some(INPUT));
+    }
+
+    #[test]
+    fn eval() {
+        let list = super::eval("(define (f x) (cons x (cons \"b\" ()))) (f #\\a)").unwrap();
+        assert_eq!(list.as_list(), Some(vec![&Value::Char('a'), &Value::Str(String::from("b"))]));
+        assert_eq!(list.to_string(), r#"(#\a "b")"#);
+
+        assert_eq!(super::eval("(cons 1 2)").unwrap().as_list(), None);
+        assert_eq!(super::eval("(vector 1.5 #t)").unwrap().to_string(), "[1.5 #t]");
+        assert_eq!(super::eval("(define (f) 1)").unwrap(), Value::Nil);
+
+        // Ports need the runtime, so this one is compiled
+        let port = "(let ((p (open-output-string))) (write 42 p) (get-output-string p))";
+        assert_eq!(super::eval(port).unwrap(), Value::Str(String::from("42")));
+
+        let e = super::eval("(car 1)").unwrap_err();
+        assert!(matches!(&e, Error::Runtime(e) if e == "Exception: car: expected pair, got 1"));
+
+        let e = super::eval("(open-output-string) (raise \"oops\")").unwrap_err();
+        assert!(matches!(&e, Error::Runtime(e) if e == "Exception: oops"), "{:?}", e);
+
+        assert!(matches!(super::eval("(f 1)"), Err(Error::Compilation(_))));
+        assert!(matches!(super::eval("(1"), Err(Error::Parser(_))));
+    }
+
+    #[test]
+    fn build() {
+        let output = std::env::temp_dir().join(format!("inc-builder-{}", std::process::id()));
+        let output = output.display().to_string();
+        let artifact = Compiler::new().source("(* 6 7)").compile().unwrap();
+
+        artifact.build(&output).unwrap();
+        let out = std::process::Command::new(&output).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "42");
+
+        fs::remove_file(format!("{}.s", output)).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+}
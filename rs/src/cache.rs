@@ -0,0 +1,112 @@
+//! Content addressed cache of compiled files
+//!
+//! Building a program compiles every [unit](crate::core::Unit) and the program
+//! itself, but most of them don't change between two builds. Each artifact is
+//! stored under `.inc-cache` next to the output, named after a hash of
+//! everything that goes into it: the source after libraries and loads are
+//! resolved, the version and the build of the compiler, every flag changing
+//! the generated code (the passes picked by the optimization level, profiling
+//! and coverage) and the target. The collector is picked when the program runs, see
+//! [gc](crate::gc), so it isn't part of the key. A build finding its artifact
+//! in the cache copies it instead of compiling the file again.
+//!
+//! Nothing is ever evicted, `rm -r .inc-cache` is always safe.
+
+use crate::{
+    core::{Config, Syntax},
+    hash::{hex, sha256},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Name of the cache directory
+pub const DIR: &str = ".inc-cache";
+
+/// The cache for the artifacts of a build
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The cache next to the output of a config
+    pub fn new(config: &Config) -> Self {
+        let output = Path::new(&config.output);
+        let parent = output.parent().unwrap_or_else(|| Path::new(""));
+
+        Cache { dir: parent.join(DIR) }
+    }
+
+    /// A key for a compiled file, unique to its source and the compiler
+    ///
+    /// The name is part of the key since it ends up in the generated labels.
+    /// The compiler is identified by its version and a hash of the sources it
+    /// was built from, see `build.rs`, so that a rebuilt compiler never picks
+    /// up the artifacts of another one. The key is a [sha256] of all of it,
+    /// which unlike the hashers of std is the same with every toolchain.
+    pub fn key(config: &Config, name: &str, prog: &[Syntax]) -> String {
+        let parts = [
+            env!("CARGO_PKG_VERSION").to_string(),
+            env!("INC_BUILD").to_string(),
+            format!("{:?}", config.passes),
+            format!("{} {} {}", config.profile, config.coverage, config.prelude),
+            config.target.to_string(),
+            name.to_string(),
+            format!("{:?}", prog),
+        ];
+
+        // Every part is prefixed with its length, so that moving text from one
+        // to the next changes the key
+        let mut bytes = vec![];
+        for part in &parts {
+            bytes.extend(&(part.len() as u64).to_le_bytes());
+            bytes.extend(part.as_bytes());
+        }
+
+        hex(&sha256(&bytes))
+    }
+
+    fn path(&self, key: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, ext))
+    }
+
+    /// Copy a cached artifact to `target`, if there is one
+    pub fn restore(&self, key: &str, ext: &str, target: &str) -> bool {
+        fs::copy(self.path(key, ext), target).is_ok()
+    }
+
+    /// Remember an artifact for later builds
+    ///
+    /// A cache that can't be written only makes the next build slower, so
+    /// errors are ignored.
+    pub fn store(&self, key: &str, ext: &str, source: &str) {
+        fs::create_dir_all(&self.dir).and_then(|_| fs::copy(source, self.path(key, ext))).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn key() {
+        let config = Config::default();
+        let key = |name, prog| Cache::key(&config, name, &parser::parse(prog).unwrap());
+
+        assert_eq!(key("a", "(+ 1 2)"), key("a", "(+  1   2)"));
+        assert_ne!(key("a", "(+ 1 2)"), key("a", "(+ 1 3)"));
+        assert_ne!(key("a", "(+ 1 2)"), key("b", "(+ 1 2)"));
+
+        let mut other = Config::default();
+        other.passes.tco = !other.passes.tco;
+        let prog = parser::parse("(+ 1 2)").unwrap();
+
+        assert_ne!(Cache::key(&config, "a", &prog), Cache::key(&other, "a", &prog));
+
+        let profiled = Config { profile: true, ..Config::default() };
+
+        assert_ne!(Cache::key(&config, "a", &prog), Cache::key(&profiled, "a", &prog));
+    }
+}
@@ -0,0 +1,284 @@
+//! Rust functions called from Scheme
+//!
+//! Programs embedding inc can extend the language with functions of their
+//! own. A [Callback] registered under a name before a program is compiled is
+//! called like any other function by the program, with its arguments as
+//! [Value]s, and its result is the value of the call:
+//!
+//! ```
+//! use inc::{callbacks, core::Error, Value};
+//! use std::convert::TryFrom;
+//!
+//! fn add(args: &[Value]) -> Result<Value, Error<'static>> {
+//!     let mut sum = 0;
+//!     for arg in args {
+//!         sum += i64::try_from(arg)?;
+//!     }
+//!     Ok(Value::from(sum))
+//! }
+//!
+//! callbacks::register("host-add", add);
+//! assert_eq!(inc::eval("(host-add 1 2 3)").unwrap(), Value::Int(6));
+//! ```
+//!
+//! An error returned by the callback is raised in the program like the errors
+//! of the runtime, so it can be caught with `guard`, with the message of the
+//! error as the object raised. A callback hides any function of the same name,
+//! but not the primitives.
+//!
+//! The compiled code calls into the runtime with the index of the callback in
+//! the registry, which looks it up when the call is made. Callbacks live in the
+//! process that registered them, so they work for programs run in memory, with
+//! [eval](crate::eval) or an [Artifact](crate::Artifact), but an executable
+//! built from a program calling one raises an error instead. The interpreter
+//! doesn't call them either, so [eval](crate::eval) always compiles programs
+//! that do.
+//!
+//! The arguments are copied out of the heap before the call and the result is
+//! allocated with the size asked for first, just like the results of the
+//! [process](crate::process) primitives. A callback must not evaluate Scheme
+//! itself, since the program calling it is still running on the same heap.
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::{Core, Error},
+    exceptions::{self, Target},
+    ffi, gc,
+    immediate::*,
+    numbers::Number,
+    rt::{self, Object},
+    symbols,
+    tags::immediate,
+    value::Value,
+    x86::{self, Reference, Register::*, ASM, WORDSIZE},
+};
+use std::{cell::RefCell, sync::Mutex};
+
+/// A function callable from Scheme
+pub type Callback = fn(&[Value]) -> Result<Value, Error<'static>>;
+
+/// Every callback registered in the process, by name
+///
+/// Entries are never removed, so the index of a name compiled into a program
+/// stays valid while it runs.
+static REGISTRY: Mutex<Vec<(String, Callback)>> = Mutex::new(vec![]);
+
+thread_local! {
+    /// The value of the last callback, waiting to be allocated, see
+    /// [rt_callback_result]
+    static RESULT: RefCell<Value> = const { RefCell::new(Value::Nil) };
+
+    /// The error of the last callback that failed, see [rt_callback_error]
+    static ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Register a callback under a name, replacing any registered before
+///
+/// Programs compiled from now on call the callback for the name, and so do the
+/// ones already compiled if it replaces another.
+pub fn register<S: Into<String>>(name: S, f: Callback) {
+    let name = name.into();
+    let mut registry = REGISTRY.lock().unwrap();
+
+    match registry.iter_mut().find(|(n, _)| *n == name) {
+        Some(entry) => entry.1 = f,
+        None => registry.push((name, f)),
+    }
+}
+
+/// Index of the callback registered under a name, if there is one
+pub fn find(name: &str) -> Option<usize> {
+    REGISTRY.lock().unwrap().iter().position(|(n, _)| n == name)
+}
+
+/// Emit code for a call to the callback at `index` in the registry
+///
+/// The arguments are evaluated into consecutive stack slots, like the
+/// arguments of a [lambda](crate::lambda::call), and the runtime is given the
+/// address of the first one and their count.
+pub fn call(s: &mut State, index: usize, args: &[Core]) -> ASM {
+    let ok = s.gen_label("callback");
+    let first = s.si;
+    let mut asm = ASM::default();
+
+    for arg in args {
+        asm += eval(s, arg);
+        asm += x86::save(RAX.into(), s.alloc());
+    }
+
+    asm += x86::mov(RDI.into(), Reference::Const(index as i64));
+    asm += x86::mov(RSI.into(), Reference::Const(args.len() as i64));
+    asm += x86::mov(RDX.into(), RBP.into());
+    asm += x86::add(RDX.into(), Reference::Const(first));
+    asm += ffi::runtime(s, "rt_callback");
+    asm += x86::cmp(RAX.into(), Reference::Const(-1));
+    asm += x86::jne(&ok);
+    asm += ffi::runtime(s, "rt_callback_error");
+    asm += exceptions::fail(s);
+    asm += x86::label(&ok);
+
+    // The result is waiting in the runtime, RAX is its size
+    let size = s.alloc();
+    asm += x86::save(RAX.into(), size);
+    asm += gc::alloc(s, Reference::from(RBP + size));
+    asm += ffi::runtime(s, "rt_callback_result");
+
+    s.dealloc(args.len() as i64 + 1);
+    asm
+}
+
+/// Call the callback at `index` with `count` arguments, the first one at
+/// `args` and the rest in the words below it
+///
+/// Returns the size of the result on the heap, or -1 if the callback failed.
+///
+/// # Safety
+///
+/// Must be called only from generated code, with `count` valid stack slots
+/// at `args`.
+#[no_mangle]
+pub unsafe extern "C" fn rt_callback(index: usize, count: i64, args: *const i64) -> i64 {
+    let (name, f) = match REGISTRY.lock().unwrap().get(index) {
+        Some((name, f)) => (name.clone(), *f),
+        None => return fail(format!("callback #{} isn't registered in this process", index)),
+    };
+
+    let args: Vec<Value> = (0..count as isize)
+        .map(|i| Value::new(Object::new(*args.offset(-i)).deref()))
+        .collect();
+
+    match f(&args) {
+        Ok(val) => match size(&val) {
+            Some(size) => {
+                RESULT.with(|result| *result.borrow_mut() = val);
+                size as i64
+            }
+            None => fail(format!("{}: can't return {}", name, val)),
+        },
+        Err(e) => fail(format!("{}: {}", name, message(e))),
+    }
+}
+
+/// Allocate the result of the last callback
+#[no_mangle]
+pub extern "C" fn rt_callback_result() -> Object {
+    RESULT.with(|result| encode(&result.replace(Value::Nil)))
+}
+
+/// Raise the error of the last callback that failed
+#[no_mangle]
+pub extern "C" fn rt_callback_error() -> Target {
+    ERROR.with(|e| exceptions::error(&e.borrow()))
+}
+
+/// Keep the error for [rt_callback_error] and return the failure sentinel
+fn fail(message: String) -> i64 {
+    ERROR.with(|e| *e.borrow_mut() = message);
+    -1
+}
+
+/// The message of an error, without the prefix a runtime error is shown with
+fn message(e: Error) -> String {
+    match e {
+        Error::Runtime(message) => match message.strip_prefix("Exception: ") {
+            Some(message) => message.to_string(),
+            None => message,
+        },
+        Error::Compilation(message) => message,
+        Error::Internal { message, .. } => message,
+        e => e.to_string(),
+    }
+}
+
+/// Bytes taken by a value on the heap, or nothing if it can't be allocated
+///
+/// Procedures are only names on the Rust side, and there is nothing in the
+/// heap they could be.
+fn size(val: &Value) -> Option<usize> {
+    let word = WORDSIZE as usize;
+
+    Some(match val {
+        Value::Nil | Value::Bool(_) | Value::Char(_) | Value::Symbol(_) => 0,
+        Value::Int(n) => Number::Exact((*n).into()).size(),
+        Value::Big(n) => n.size(),
+        Value::Float(f) => Number::Inexact(*f).size(),
+        Value::Str(s) => (word + s.len() + 1 + word - 1) / word * word,
+        Value::Pair(car, cdr) => 2 * word + size(car)? + size(cdr)?,
+        Value::Vector(values) => {
+            (values.len() + 1) * word + values.iter().map(size).sum::<Option<usize>>()?
+        }
+        Value::Procedure(_) => return None,
+    })
+}
+
+/// Allocate a value on the heap, with room for it made already
+fn encode(val: &Value) -> Object {
+    match val {
+        Value::Nil => Object::new(NIL),
+        Value::Bool(true) => Object::new(TRUE),
+        Value::Bool(false) => Object::new(FALSE),
+        Value::Char(c) => Object::new(immediate(i64::from(*c as u8), CHAR)),
+        Value::Symbol(s) => symbols::intern(s),
+        Value::Int(n) => Number::Exact((*n).into()).encode(),
+        Value::Big(n) => n.encode(),
+        Value::Float(f) => Number::Inexact(*f).encode(),
+        Value::Str(s) => rt::string(s.as_bytes()),
+        Value::Pair(car, cdr) => {
+            let car = encode(car);
+            rt::cons(car, encode(cdr))
+        }
+        Value::Vector(values) => rt::vector(&values.iter().map(encode).collect::<Vec<_>>()),
+        Value::Procedure(_) => unreachable!("Procedures have no size"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn sum(args: &[Value]) -> Result<Value, Error<'static>> {
+        args.iter().map(i64::try_from).sum::<Result<i64, _>>().map(Value::from)
+    }
+
+    fn echo(args: &[Value]) -> Result<Value, Error<'static>> {
+        Ok(Value::list(vec![Value::symbol("args"), Value::list(args.to_vec()), 2.5.into()]))
+    }
+
+    fn procedure(_: &[Value]) -> Result<Value, Error<'static>> {
+        Ok(Value::Procedure(String::from("f")))
+    }
+
+    #[test]
+    fn call() {
+        register("test-sum", sum);
+        register("test-echo", echo);
+
+        assert_eq!(crate::eval("(test-sum)").unwrap(), Value::Int(0));
+        assert_eq!(crate::eval("(+ 1 (test-sum 1 2 (test-sum 3 4)))").unwrap(), Value::Int(11));
+
+        let prog = "(define (f x) (test-sum x x)) (let ((y (f 21))) (cons y (f y)))";
+        assert_eq!(crate::eval(prog).unwrap().to_string(), "(42 . 84)");
+
+        let val = crate::eval(r#"(test-echo "a" (cons 1 2) (vector #\b 1.5) 'c)"#).unwrap();
+        assert_eq!(val.to_string(), r#"('args ("a" (1 . 2) [#\b 1.5] 'c) 2.5)"#);
+        assert!(find("test-sum").is_some());
+        assert_eq!(find("test-nothing"), None);
+    }
+
+    #[test]
+    fn errors() {
+        register("test-fails", sum);
+        register("test-procedure", procedure);
+
+        let e = crate::eval("(test-fails 1 \"2\")").unwrap_err();
+        let message = r#"Exception: test-fails: expected fixnum, got "2""#;
+        assert!(matches!(&e, Error::Runtime(e) if e == message), "{:?}", e);
+
+        let caught = "(guard (e ((string? e) (string-length e))) (test-fails #t))";
+        assert_eq!(crate::eval(caught).unwrap(), Value::Int(35));
+
+        let e = crate::eval("(test-procedure)").unwrap_err();
+        let message = "Exception: test-procedure: can't return #<procedure f>";
+        assert!(matches!(&e, Error::Runtime(e) if e == message), "{:?}", e);
+    }
+}
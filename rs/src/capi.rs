@@ -0,0 +1,266 @@
+//! The compiler for programs written in C and other languages
+//!
+//! With the `capi` feature, the shared library `libinc.so` built along with
+//! the crate exports functions to compile and evaluate Scheme, declared in the
+//! header `inc.h` with the rest of the runtime:
+//!
+//! ```c
+//! #include <stdio.h>
+//! #include "inc.h"
+//!
+//! int main(void) {
+//!     inc_diag *diags = NULL;
+//!     char *code = inc_compile("(define (twice x) (* x 2)) (twice 21)", &diags);
+//!
+//!     if (code == NULL) {
+//!         for (inc_diag *d = diags; d != NULL; d = d->next)
+//!             fprintf(stderr, "error[%s]: %s\n", d->code, d->message);
+//!         inc_diag_free(diags);
+//!         return 1;
+//!     }
+//!
+//!     puts(code);
+//!     inc_string_free(code);
+//! }
+//! ```
+//!
+//! Strings returned belong to the caller, who frees them with
+//! [inc_string_free], and lists of diagnostics with [inc_diag_free]. A function
+//! that fails returns null and points its `diags` argument at the
+//! [diagnostics](Diagnostic) of the errors, in the order they were found.
+//! Nothing panics across the boundary; a bug in the compiler is reported as an
+//! internal error instead.
+use crate::{
+    builder, cli,
+    core::Error,
+    diagnostic::{Diagnostic, Severity},
+};
+use std::{
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic, ptr,
+};
+
+/// Name of the program in diagnostics
+const INPUT: &str = "<input>";
+
+/// A diagnostic for C, in a list of them
+///
+/// The span is in bytes from the start of `file`, with both ends -1 if it
+/// isn't known, and so is `file` if it is null.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct inc_diag {
+    /// `error` or `warning`
+    pub severity: *mut c_char,
+    /// Stable code of the kind of error, like `E0001`
+    pub code: *mut c_char,
+    pub message: *mut c_char,
+    pub file: *mut c_char,
+    pub start: i64,
+    pub end: i64,
+    /// The next diagnostic, null after the last one
+    pub next: *mut inc_diag,
+}
+
+/// Compile a program along with the prelude to asm, see
+/// [compile_to_asm](cli::compile_to_asm)
+///
+/// # Safety
+///
+/// `source` must be a valid C string, and `diags` null or valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn inc_compile(
+    source: *const c_char,
+    diags: *mut *mut inc_diag,
+) -> *mut c_char {
+    call(source, diags, cli::compile_to_asm)
+}
+
+/// Evaluate a program and return its value as it would be printed, see
+/// [eval](builder::eval)
+///
+/// Anything the program prints goes to stdout as usual.
+///
+/// # Safety
+///
+/// `source` must be a valid C string, and `diags` null or valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn inc_eval(source: *const c_char, diags: *mut *mut inc_diag) -> *mut c_char {
+    call(source, diags, |source| {
+        builder::eval(source)
+            .map(|val| val.to_string())
+            .map_err(|e| Diagnostic::all(&e, &[(INPUT, source)]))
+    })
+}
+
+/// Free a string returned by the library
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by the library, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn inc_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s))
+    }
+}
+
+/// Free a list of diagnostics returned by the library, along with their
+/// strings
+///
+/// # Safety
+///
+/// `diag` must be null or the first of a list returned by the library, not
+/// freed before.
+#[no_mangle]
+pub unsafe extern "C" fn inc_diag_free(mut diag: *mut inc_diag) {
+    while !diag.is_null() {
+        let d = Box::from_raw(diag);
+        for s in &[d.severity, d.code, d.message, d.file] {
+            inc_string_free(*s);
+        }
+        diag = d.next;
+    }
+}
+
+/// Run `f` on a C string, turning its result into a string or diagnostics for
+/// C
+unsafe fn call<F>(source: *const c_char, diags: *mut *mut inc_diag, f: F) -> *mut c_char
+where
+    F: FnOnce(&str) -> Result<String, Vec<Diagnostic>> + panic::UnwindSafe,
+{
+    if !diags.is_null() {
+        *diags = ptr::null_mut();
+    }
+
+    let source = match CStr::from_ptr(source).to_str() {
+        Ok(source) => source,
+        Err(_) => return fail(&[internal("The source isn't valid UTF-8")], diags),
+    };
+
+    let result = panic::catch_unwind(|| f(source)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| payload.downcast_ref::<&str>().copied())
+            .unwrap_or("The compiler crashed");
+        Err(vec![internal(message)])
+    });
+
+    match result {
+        Ok(out) => string(&out),
+        Err(errors) => fail(&errors, diags),
+    }
+}
+
+/// Point `diags` at a list of diagnostics and return null
+unsafe fn fail(errors: &[Diagnostic], diags: *mut *mut inc_diag) -> *mut c_char {
+    if !diags.is_null() {
+        *diags = errors.iter().rev().fold(ptr::null_mut(), |next, d| list(d, next));
+    }
+
+    ptr::null_mut()
+}
+
+/// A diagnostic at the head of a list
+fn list(d: &Diagnostic, next: *mut inc_diag) -> *mut inc_diag {
+    let severity = match d.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let (start, end) = d.span.as_ref().map_or((-1, -1), |s| (s.start as i64, s.end as i64));
+
+    Box::into_raw(Box::new(inc_diag {
+        severity: string(severity),
+        code: string(d.code),
+        message: string(&d.message),
+        file: d.file.as_deref().map_or(ptr::null_mut(), string),
+        start,
+        end,
+        next,
+    }))
+}
+
+fn internal(message: &str) -> Diagnostic {
+    Diagnostic::new(&Error::Internal { message: message.to_string(), e: None }, &[])
+}
+
+/// A string for C, without any NUL bytes in it
+fn string(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::codes;
+
+    /// The diagnostics of a list as (code, message, start) and free it
+    unsafe fn collect(mut diag: *mut inc_diag) -> Vec<(String, String, i64)> {
+        let head = diag;
+        let mut out = vec![];
+        while !diag.is_null() {
+            let d = &*diag;
+            let s = |p: *mut c_char| CStr::from_ptr(p).to_string_lossy().into_owned();
+            out.push((s(d.code), s(d.message), d.start));
+            diag = d.next;
+        }
+        inc_diag_free(head);
+        out
+    }
+
+    #[test]
+    fn compile() {
+        unsafe {
+            let mut diags = ptr::null_mut();
+
+            let source = CString::new("(define (twice x) (* x 2)) (twice 21)").unwrap();
+            let asm = inc_compile(source.as_ptr(), &mut diags);
+            assert!(CStr::from_ptr(asm).to_str().unwrap().contains("init"));
+            assert!(diags.is_null());
+            inc_string_free(asm);
+
+            let source = CString::new("(twice 21").unwrap();
+            assert!(inc_compile(source.as_ptr(), &mut diags).is_null());
+            let errors = collect(diags);
+            assert_eq!(errors.len(), 1);
+            assert_eq!((errors[0].0.as_str(), errors[0].2), (codes::PARSE, 9));
+
+            // Without diagnostics asked for, failing is enough
+            assert!(inc_compile(source.as_ptr(), ptr::null_mut()).is_null());
+        }
+    }
+
+    #[test]
+    fn eval() {
+        unsafe {
+            let mut diags = ptr::null_mut();
+
+            let source = CString::new("(define (f x) (cons x x)) (f 1)").unwrap();
+            let val = inc_eval(source.as_ptr(), &mut diags);
+            assert_eq!(CStr::from_ptr(val).to_str().unwrap(), "(1 . 1)");
+            inc_string_free(val);
+
+            let source = CString::new("(g 1)").unwrap();
+            assert!(inc_eval(source.as_ptr(), &mut diags).is_null());
+            let errors = collect(diags);
+            assert_eq!(errors[0].0, codes::COMPILE);
+            assert_eq!(errors[0].2, -1);
+        }
+    }
+
+    #[test]
+    fn header() {
+        let header = include_str!("../inc.h");
+
+        for declaration in &[
+            "char *inc_compile(const char *source, inc_diag **diags);",
+            "char *inc_eval(const char *source, inc_diag **diags);",
+            "void inc_string_free(char *s);",
+            "void inc_diag_free(inc_diag *diag);",
+        ] {
+            assert!(header.contains(declaration), "inc.h doesn't declare {}", declaration);
+        }
+    }
+}
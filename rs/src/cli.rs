@@ -1,79 +1,801 @@
 //! Command line interface for inc
+//!
+//! The `inc` binary is a thin layer over the [Driver], which does the work of
+//! each subcommand:
+//!
+//! ```text
+//! inc build [-o FILE] [-S] [FILE]…  Build an executable, or only the asm
+//! inc run [--jit|--interp] [FILE]…  Build and run a program, or interpret it
+//! inc repl [--load FILE]            Evaluate expressions as they are typed, see [repl]
+//! inc check [FILE]                  Report errors without building anything
+//! inc expand [FILE]                 Print the program with syntax expanded
+//! inc FILE [ARG]…                   Run a program as a script, see [Driver::script]
+//! ```
+//!
+//! `build` and `run` take several files, the last of which is the program and
+//! the rest [Unit]s compiled to objects of their own and linked together. A
+//! [library](crate::library) defined in one of them can be imported by the
+//! files after it. Files that didn't change since the last build are reused
+//! from the [cache](crate::cache) unless `--no-cache` is given. `--watch` runs
+//! them again every time a file changes, see [wait].
+//!
+//! `--emit STAGE` stops any of these after one of the [Stage]s of the compiler
+//! and prints what it produced, or writes it to `-o` for `obj` and `bin`.
+//! `--trace-passes` shows the program after every one of them instead, see
+//! [Driver::trace], and `--time-passes` how long each one took, see
+//! [Driver::time_passes], along with the [Metrics] `--verbose` reports on its
+//! own. `--coverage` builds the program with counters of the expressions it
+//! evaluates, see [coverage], and `--profile` with counters of the calls to
+//! its functions, see [profile](crate::profile).
+//!
+//! Programs are read from the standard input without a file. The binary parses
+//! the command line into a [Config] and an [Action] and nothing else, so the
+//! same workflows are available to other tools through the library.
 
 use crate::{
-    compiler::emit,
-    core::{Config, Error, Syntax},
-    parser::parse,
+    cache::Cache,
+    compiler::{self, emit, state::State, Fault},
+    core::{Config, Error, Expr, Ident, Syntax, Timings, Trace, Unit},
+    coverage,
+    diagnostic::Diagnostic,
+    header,
+    host::Clock,
+    interp,
+    lang::{self, Program},
+    library::{Export, Libraries},
+    metrics::Metrics,
+    parser, resolve, semantic,
+    sourcemap::Mapper,
+    x86::{self, Output, Writer, ASM},
+    Compiler,
 };
+#[cfg(feature = "native")]
+use crate::{jit, repl};
 
-use std::{fs::File, io::Write, path::PathBuf, process::Command};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Columns the output of [Driver::expand] is laid out in
+pub const WIDTH: usize = 80;
 
 #[derive(Copy, Clone)]
 pub enum Action {
     Parse,
     GenASM,
+    Build,
     Run,
+    Jit,
+    Interp,
+    Repl,
+    Check,
+    Expand,
+    Emit(Stage),
 }
 
-pub fn run(config: &Config, action: Action) -> Result<Option<String>, Error> {
-    let prelude = parse(include_str!("prelude.ss"))?;
-    let prog = parse(&config.program)?;
-    let prog = prelude.into_iter().chain(prog.into_iter()).collect();
+/// Intermediate representations of a program, in the order they are produced
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    /// The source as it is read, one token a line
+    Tokens,
+    /// The tokens of the source and what they mean, see [semantic]
+    SemanticTokens,
+    /// Expressions as they are parsed
+    Ast,
+    /// Derived syntax expanded and names made unique
+    Renamed,
+    /// All lambdas lifted to the top level
+    Lifted,
+    /// The program after all enabled passes, as it is given to code generation
+    Ir,
+    /// Generated assembly
+    Asm,
+    /// A C header declaring the procedures exported by libraries, see
+    /// [header]
+    Header,
+    /// Where the functions in the generated assembly came from, see
+    /// [sourcemap]
+    Map,
+    /// An object file assembled from the generated code, which is piped
+    /// straight into the assembler
+    Obj,
+    /// The executable, as `build` would
+    Bin,
+}
+
+impl Stage {
+    pub const NAMES: [&'static str; 11] = [
+        "tokens",
+        "tokens-semantic",
+        "ast",
+        "renamed",
+        "lifted",
+        "ir",
+        "asm",
+        "header",
+        "map",
+        "obj",
+        "bin",
+    ];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "tokens" => Ok(Stage::Tokens),
+            "tokens-semantic" => Ok(Stage::SemanticTokens),
+            "ast" => Ok(Stage::Ast),
+            "renamed" => Ok(Stage::Renamed),
+            "lifted" => Ok(Stage::Lifted),
+            "ir" => Ok(Stage::Ir),
+            "asm" => Ok(Stage::Asm),
+            "header" => Ok(Stage::Header),
+            "map" => Ok(Stage::Map),
+            "obj" => Ok(Stage::Obj),
+            "bin" => Ok(Stage::Bin),
+            _ => {
+                Err(format!("Unknown stage `{}`, expected one of {}", name, Self::NAMES.join("|")))
+            }
+        }
+    }
+}
+
+/// Run an action for a program, see [Driver::run]
+pub fn run(config: &Config, action: Action) -> Result<Option<String>, Error<'_>> {
+    Driver::new(config).run(action)
+}
+
+/// Compile a program to asm, with every error as a [Diagnostic]
+///
+/// This is the whole compiler short of the assembler behind a single call,
+/// which returns an error for any input instead of panicking and is what a
+/// fuzzer needs. Forms nested deeper than [MAX_DEPTH](parser::MAX_DEPTH) are
+/// rejected by the parser so that the passes can't run out of stack. The
+/// program is compiled with the prelude and files it loads are read as usual.
+pub fn compile_to_asm(source: &str) -> Result<String, Vec<Diagnostic>> {
+    Compiler::new().source(source).compile().map(|artifact| artifact.asm())
+}
+
+/// The compiler driver behind the command line
+///
+/// Each action returns the output meant for the user, if there is one, and
+/// leaves printing it to the caller.
+pub struct Driver<'a> {
+    config: &'a Config,
+}
+
+/// A pass with the time it took and the number of nodes it left behind
+type Measurement = (String, Duration, usize);
+
+impl<'a> Driver<'a> {
+    pub const fn new(config: &'a Config) -> Self {
+        Driver { config }
+    }
+
+    pub fn run(&self, action: Action) -> Result<Option<String>, Error<'a>> {
+        let config = self.config;
+
+        if let Some(trace) = &config.trace {
+            self.trace(trace)?;
+        }
+
+        if let Some(timings) = config.timings {
+            eprintln!("{}", self.time_passes(timings)?);
+        } else if config.verbose {
+            eprintln!("{}", self.measure()?.1);
+        }
+
+        match action {
+            Action::Parse => {
+                for e in self.program()? {
+                    println!("{:?}", e);
+                }
+
+                Ok(None)
+            }
+            Action::GenASM => {
+                let (prog, exports) = self.checked()?;
+                gen(config, prog, &exports)?;
+                Ok(None)
+            }
+            Action::Build => {
+                self.gen()?;
+                self.units()?;
+                build(config)?;
+                Ok(None)
+            }
+            #[cfg(feature = "native")]
+            Action::Run => {
+                self.gen()?;
+                self.units()?;
+                build(config)?;
+                exec(config)
+            }
+            #[cfg(feature = "native")]
+            Action::Jit => {
+                let image = jit::load(&self.compile()?)?;
+                Ok(Some(image.run(|val| val.to_string())))
+            }
+            Action::Interp => {
+                if !config.units.is_empty() {
+                    return Err(Error::Internal {
+                        message: String::from("The interpreter doesn't support units"),
+                        e: None,
+                    });
+                }
+
+                interp::run(self.program()?, config.passes).map(Some)
+            }
+            #[cfg(feature = "native")]
+            Action::Repl => {
+                repl::run(&config.load)?;
+                Ok(None)
+            }
+            #[cfg(feature = "native")]
+            Action::Check => self.check().map(|_| None),
+            // Running anything needs the operating system
+            #[cfg(not(feature = "native"))]
+            Action::Run | Action::Jit | Action::Repl | Action::Check => Err(Error::Internal {
+                message: String::from("Running programs needs the native feature"),
+                e: None,
+            }),
+            Action::Expand => self.expand().map(Some),
+            Action::Emit(stage) => self.emit(stage),
+        }
+    }
+
+    /// Stop after a stage of the compiler and return what it produced
+    ///
+    /// Stages up to `ir` show only the program itself, without the prelude.
+    /// `asm`, `obj` and `bin` are written to the configured output.
+    pub fn emit(&self, stage: Stage) -> Result<Option<String>, Error<'a>> {
+        let config = self.config;
+        let passes = config.passes;
+
+        match stage {
+            Stage::Tokens => Ok(Some(parser::tokens(&config.program)?.join("\n"))),
+            Stage::SemanticTokens => Ok(Some(semantic::render(&config.program))),
+            Stage::Ast => self.staged(Ok),
+            Stage::Renamed => self.staged(lang::renamed),
+            Stage::Lifted => {
+                self.staged(|prog| Ok(lang::lifted(&Ident::empty(), lang::renamed(prog)?)))
+            }
+            Stage::Ir => self.staged(|prog| {
+                let mut s = State::new();
+                s.passes = passes;
+                lang::analyze(&mut s, prog)
+            }),
+            Stage::Asm => {
+                let (prog, exports) = self.checked()?;
+                gen(config, prog, &exports)?;
+                Ok(None)
+            }
+            Stage::Header => self.header().map(Some),
+            Stage::Map => {
+                let (prog, exports) = self.checked()?;
+                let mapper = Mapper::new(&config.program);
+                let map = stream(config, prog, &exports, mapper)?.map_err(|e| Error::Internal {
+                    message: String::from("Failed to map the generated code"),
+                    e: Some(e),
+                })?;
+
+                Ok(Some(map.finish().to_string()))
+            }
+            Stage::Obj => {
+                let (prog, exports) = self.checked()?;
+                pipe(config, prog, &exports)?;
+                Ok(None)
+            }
+            Stage::Bin => {
+                let (prog, exports) = self.checked()?;
+                gen(config, prog, &exports)?;
+                build(config)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Build the program and run it as a script with some arguments, returning
+    /// its exit code
+    ///
+    /// Unlike [Action::Run], the program shares the standard streams of inc
+    /// and gets `name` as its own, so that `(command-line)` is the script and
+    /// its arguments. The executable is thrown away once it exits. A program
+    /// killed by a signal exits with 128 plus the number of the signal, like in
+    /// the shell.
+    #[cfg(feature = "native")]
+    pub fn script(&self, name: &str, args: &[String]) -> Result<i32, Error<'a>> {
+        use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+        let config = self.config;
+        let status = self.gen().and_then(|_| self.units()).and_then(|_| build(config)).and_then(
+            |_| Ok(Command::new(&config.output).arg0(name).args(args).status()?),
+        );
+
+        for path in &[config.output.clone(), config.asm()] {
+            fs::remove_file(path).ok();
+        }
+
+        let status = status?;
+        Ok(status.code().or_else(|| status.signal().map(|n| 128 + n)).unwrap_or(1))
+    }
+
+    /// Compile the program without building or running it
+    ///
+    /// References to names nothing defines are [resolved](resolve) first, and
+    /// the code is then loaded with the JIT but never run, which catches
+    /// anything else the linker would. `inc lsp` checks files the same way.
+    #[cfg(feature = "native")]
+    pub fn check(&self) -> Result<(), Error<'a>> {
+        jit::load(&self.compile()?).map(|_| ())
+    }
+
+    /// Compile the program with the prelude to asm, ignoring any units
+    ///
+    /// Every reference in the program is [resolved](resolve) before it is
+    /// compiled, see [Driver::checked].
+    pub fn compile(&self) -> Result<ASM, Error<'a>> {
+        let (prog, _) = self.checked()?;
+        let passes = self.config.passes;
+
+        emit::compile_with(prog, passes).map_err(Error::compilation)
+    }
+
+    /// The program as written, after the files to load first
+    fn source(&self) -> Result<Vec<Syntax>, Error<'a>> {
+        Ok(self.loads().into_iter().chain(parser::parse(&self.config.program)?).collect())
+    }
+
+    /// A `load` form for each of the files to load before the program
+    fn loads(&self) -> Vec<Syntax> {
+        let load = |path: &String| {
+            Expr::List(vec![Expr::Identifier("load".into()), Expr::string(path.as_str())])
+        };
+
+        self.config.load.iter().map(load).collect()
+    }
+
+    /// The program with the prelude, after the initializers of all units
+    fn program(&self) -> Result<Vec<Syntax>, Error<'a>> {
+        self.resolved().map(|(prog, _)| prog)
+    }
+
+    /// The [program](Driver::program) along with the exports of every library
+    /// defined in the units and the program
+    ///
+    /// The program is [instrumented](coverage::instrument) first if its
+    /// coverage is asked for.
+    fn resolved(&self) -> Result<(Vec<Syntax>, Vec<Export>), Error<'a>> {
+        let init = self.config.units.iter().map(|unit| {
+            Expr::List(vec![Expr::name(emit::initializer(&unit.name))])
+        });
+
+        let (mut libraries, _) = self.libraries()?;
+        let mut source = self.source()?;
+
+        if self.config.coverage {
+            let prog = source.split_off(self.config.load.len());
+            source.extend(coverage::instrument(prog, &self.config.program));
+        }
+
+        let prog = lang::load(source)
+            .and_then(|prog| libraries.resolve(prog))
+            .map_err(Error::compilation)?;
+
+        let prelude = if self.config.prelude { compiler::prelude() } else { vec![] };
+        let prog = prelude.into_iter().chain(init).chain(prog).collect();
+
+        Ok((prog, libraries.exports()))
+    }
+
+    /// The [resolved](Driver::resolved) program, once every reference in it
+    /// and in the units is [resolved](resolve)
+    ///
+    /// Everything that generates code from the program goes through here, so
+    /// that building or running a program reports the same errors as `inc
+    /// check` before the assembler or the linker sees any code. The program
+    /// and the units are linked together, so each can refer to anything the
+    /// others define and to the initializers of the units.
+    fn checked(&self) -> Result<(Vec<Syntax>, Vec<Export>), Error<'a>> {
+        let (_, units) = self.libraries()?;
+        let (prog, exports) = self.resolved()?;
+
+        let mut globals = resolve::Globals::default();
+        prog.iter().chain(units.iter().flatten()).for_each(|e| resolve::defined(e, &mut globals));
+
+        let init = self.config.units.iter().map(|unit| emit::initializer(&unit.name));
+        let external: Vec<String> = globals.into_iter().map(String::from).chain(init).collect();
+
+        resolve::check(&prog, &external).map_err(Error::compilation)?;
+
+        for unit in &units {
+            resolve::check(unit, &external).map_err(Error::compilation)?;
+        }
+
+        Ok((prog, exports))
+    }
+
+    /// The C header declaring the procedures exported by libraries defined in
+    /// the units and the program, see [header]
+    pub fn header(&self) -> Result<String, Error<'a>> {
+        let passes = self.config.passes;
+        let (_, units) = self.libraries()?;
+        let (prog, exports) = self.resolved()?;
+
+        let mut entries = vec![];
+
+        for prog in units.into_iter().chain(std::iter::once(prog)) {
+            let mut s = State::new();
+            s.passes = passes;
+            let prog = lang::analyze(&mut s, prog).map_err(Error::compilation)?;
+            entries.extend(header::entries(&exports, &prog));
+        }
+
+        Ok(header::render(&entries))
+    }
+
+    /// Compile each unit into an object file next to the output
+    ///
+    /// Libraries defined in a unit can be imported by the units after it and
+    /// the program. Units that didn't change since the last build are copied
+    /// from the [cache](crate::cache) instead. The asm of a unit is written to
+    /// its file as it is generated, like the program in [gen].
+    pub fn units(&self) -> Result<(), Error<'a>> {
+        let config = self.config;
+        let cache = Cache::new(config);
+        let (libraries, units) = self.libraries()?;
+        let exports = libraries.exports();
+
+        for (unit, prog) in config.units.iter().zip(units) {
+            let key = Cache::key(config, &unit.name, &prog);
+            let obj = config.unit_obj(unit);
 
-    match action {
-        Action::Parse => {
-            for e in prog {
-                println!("{:?}", e);
+            if config.cache && cache.restore(&key, "o", &obj) {
+                continue;
             }
 
-            Ok(None)
+            let path = config.unit_asm(unit);
+            let mut writer = Writer::new(create(&path)?);
+            writer.emit(x86::ident(&config.target));
+
+            emit::unit(&unit.name, prog, config.passes, &exports, &mut writer)
+                .map_err(Error::compilation)?;
+
+            writer.finish().map_err(|e| Error::Internal {
+                message: format!("Failed to write to {}", path),
+                e: Some(e),
+            })?;
+
+            assemble(&path, &obj)?;
+
+            if config.cache {
+                cache.store(&key, "o", &obj);
+            }
         }
-        Action::GenASM => {
-            gen(config, prog)?;
-            Ok(None)
+
+        Ok(())
+    }
+
+    /// Generate the asm of the program, unless it is in the cache already
+    ///
+    /// The units are [checked](Driver::checked) along with the program, so
+    /// this comes before [Driver::units].
+    fn gen(&self) -> Result<(), Error<'a>> {
+        let config = self.config;
+        let cache = Cache::new(config);
+        let (prog, exports) = self.checked()?;
+        let key = Cache::key(config, "", &prog);
+
+        if config.cache && cache.restore(&key, "s", &config.asm()) {
+            return Ok(());
         }
-        Action::Run => {
-            gen(config, prog)?;
-            build(&config)?;
-            exec(&config)
+
+        gen(config, prog, &exports)?;
+
+        if config.cache {
+            cache.store(&key, "s", &config.asm());
+        }
+
+        Ok(())
+    }
+
+    /// Show the program after each pass of the compiler
+    ///
+    /// The program is shown as it is parsed and then after every pass of
+    /// [analysis](lang::traced), without the prelude. Each pass is headed by
+    /// its name on stdout, or written to `NN-pass.scm` in a directory.
+    pub fn trace(&self, trace: &Trace) -> Result<(), Error<'a>> {
+        let config = self.config;
+        let prog = self.source()?;
+        let parsed = prog.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n");
+
+        let mut passes = vec![(String::from("parsed"), parsed)];
+        let mut s = State::new();
+        s.passes = config.passes;
+
+        lang::traced(&mut s, prog, &mut |pass| {
+            passes.push((pass.name.to_string(), pass.program.show()))
+        });
+
+        match trace {
+            Trace::Stdout => {
+                for (pass, prog) in passes {
+                    println!(";; {}\n{}\n", pass, prog)
+                }
+            }
+            Trace::Dir(dir) => {
+                fs::create_dir_all(dir)?;
+
+                for (i, (pass, prog)) in passes.iter().enumerate() {
+                    let path = PathBuf::from(dir).join(format!("{:02}-{}.scm", i, pass));
+                    write(&path.to_string_lossy(), &format!("{}\n", prog))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compile the program and report how long each pass took
+    ///
+    /// The program is compiled along with the prelude like for a build, and
+    /// timed from parsing through every pass of [analysis](lang::traced) to
+    /// code generation. Each pass is shown with the size of the program it
+    /// produced, in nodes of the syntax tree or in instructions for the
+    /// generated code, as a table or as JSON. The [Metrics] of the whole
+    /// compilation follow the passes.
+    pub fn time_passes(&self, timings: Timings) -> Result<String, Error<'a>> {
+        let (times, metrics) = self.measure()?;
+        let total: Duration = times.iter().map(|(_, time, _)| *time).sum();
+        let ms = |time: &Duration| time.as_secs_f64() * 1000.0;
+
+        match timings {
+            Timings::Table => {
+                let mut table = format!("{:10}  {:>10}  {:>8}\n", "pass", "time", "nodes");
+
+                for (pass, time, nodes) in &times {
+                    table += &format!("{:10}  {:>8.3}ms  {:>8}\n", pass, ms(time), nodes);
+                }
+
+                Ok(table + &format!("{:10}  {:>8.3}ms\n{}", "total", ms(&total), metrics))
+            }
+            Timings::Json => {
+                let passes: Vec<String> = times
+                    .iter()
+                    .map(|(pass, time, nodes)| {
+                        format!(r#"{{"pass":"{}","ms":{:.3},"nodes":{}}}"#, pass, ms(time), nodes)
+                    })
+                    .collect();
+
+                Ok(format!(
+                    r#"{{"passes":[{}],"ms":{:.3},"metrics":{}}}"#,
+                    passes.join(","),
+                    ms(&total),
+                    metrics.json()
+                ))
+            }
+        }
+    }
+
+    /// Compile the program, timing every pass and measuring all of them
+    fn measure(&self) -> Result<(Vec<Measurement>, Metrics), Error<'a>> {
+        let passes = self.config.passes;
+        let clock = Clock::start();
+        let prog = self.program()?;
+        let parsed = (String::from("parsed"), clock.elapsed(), prog.nodes());
+        let mut metrics = Metrics { nodes: parsed.2, ..Metrics::default() };
+
+        let mut times = vec![parsed];
+        emit::traced(prog, passes, &mut |pass| {
+            metrics.pass(pass);
+            times.push((pass.name.to_string(), pass.time, pass.program.nodes()))
+        })
+        .map_err(Error::compilation)?;
+
+        Ok((times, metrics.finish()))
+    }
+
+    /// Files read at compile time besides the program and the units
+    ///
+    /// These are the files loaded before the program and everything loaded or
+    /// included by the sources, see [dependencies](lang::dependencies).
+    pub fn dependencies(&self) -> Vec<String> {
+        let config = self.config;
+        let sources = config.units.iter().map(|unit| &unit.program).chain(Some(&config.program));
+        let mut files = lang::dependencies(&self.loads());
+
+        for source in sources {
+            files.extend(lang::dependencies(&parser::parse(source).unwrap_or_default()))
+        }
+
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// All the units with their libraries resolved in order
+    fn libraries(&self) -> Result<(Libraries, Vec<Vec<Syntax>>), Error<'a>> {
+        let mut libraries = Libraries::new();
+        let mut units = vec![];
+
+        for unit in &self.config.units {
+            let prog = parser::parse(&unit.program)?;
+            let prog = lang::load(prog)
+                .and_then(|prog| libraries.resolve(prog))
+                .map_err(Error::compilation)?;
+
+            units.push(prog);
         }
+
+        Ok((libraries, units))
+    }
+
+    /// Run the front end of the compiler up to a stage, one expression a line
+    fn staged<T: fmt::Display>(
+        &self,
+        f: impl FnOnce(Vec<Syntax>) -> Result<Vec<T>, Vec<Fault>>,
+    ) -> Result<Option<String>, Error<'a>> {
+        let prog = self.source()?;
+        let prog = f(prog).map_err(Error::compilation)?;
+
+        Ok(Some(prog.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")))
+    }
+
+    /// The program with all derived syntax expanded, pretty printed
+    ///
+    /// Files are loaded and libraries resolved first, but nothing is renamed, so
+    /// this is the program in the core language as written: literals, variables,
+    /// applications, `if`, `let`, `λ` and `define`, along with the primitives
+    /// derived forms like `guard` expand to. The prelude is left out.
+    pub fn expand(&self) -> Result<String, Error<'a>> {
+        let prog = self.source()?;
+        let expanded = lang::load(prog)
+            .and_then(|prog| Libraries::new().resolve(prog))
+            .and_then(|prog| prog.into_iter().map(lang::expand).collect::<Result<Vec<_>, _>>())
+            .map_err(Error::compilation)?;
+
+        Ok(expanded.iter().map(|e| e.pretty(WIDTH)).collect::<Vec<_>>().join("\n"))
     }
 }
 
-pub fn gen<'a>(config: &'a Config, prog: Vec<Syntax>) -> Result<(), Error<'a>> {
-    let mut handler = File::create(&config.asm()).or_else(|e| {
-        Err(Error::Internal { message: format!("Failed to create {}", &config.asm()), e: Some(e) })
+/// Generate the asm of a program into its file, as it is generated
+pub fn gen<'a>(config: &'a Config, prog: Vec<Syntax>, exports: &[Export]) -> Result<(), Error<'a>> {
+    let path = config.asm();
+    let file = create(&path)?;
+
+    stream(config, prog, exports, file)?.map_err(|e| Error::Internal {
+        message: format!("Failed to write to {}", path),
+        e: Some(e),
     })?;
 
-    handler.write_all(emit::program(prog).as_bytes()).or_else(|e| {
-        Err(Error::Internal {
-            message: format!("Failed to write to {}", &config.asm()),
-            e: Some(e),
-        })
+    Ok(())
+}
+
+/// Generate the asm of a program straight into `out`, see [Writer]
+///
+/// Errors in the program are returned first, and then any error writing the
+/// code out.
+fn stream<'a, W: io::Write>(
+    config: &'a Config,
+    prog: Vec<Syntax>,
+    exports: &[Export],
+    out: W,
+) -> Result<io::Result<W>, Error<'a>> {
+    let mut writer = Writer::new(out);
+    writer.emit(x86::ident(&config.target));
+
+    emit::streaming(prog, config.passes, exports, config.profile, &mut writer, &mut |_| {})
+        .map_err(Error::compilation)?;
+
+    Ok(writer.finish())
+}
+
+/// Create a file to write generated code to
+fn create<'a>(path: &str) -> Result<File, Error<'a>> {
+    File::create(path)
+        .map_err(|e| Error::Internal { message: format!("Failed to create {}", path), e: Some(e) })
+}
+
+/// Write generated code to a file
+fn write<'a>(path: &str, asm: &str) -> Result<(), Error<'a>> {
+    let mut handler = create(path)?;
+
+    handler.write_all(asm.as_bytes()).map_err(|e| Error::Internal {
+        message: format!("Failed to write to {}", path),
+        e: Some(e),
     })?;
 
     Ok(())
 }
 
+/// Block until one of the files is created, modified or removed
+///
+/// Files are polled a few times a second, which is plenty for a person editing
+/// them and needs nothing from the platform.
+pub fn wait(paths: &[String]) {
+    let stamp = || -> Vec<Option<SystemTime>> {
+        paths.iter().map(|path| fs::metadata(path).and_then(|m| m.modified()).ok()).collect()
+    };
+
+    let before = stamp();
+
+    while stamp() == before {
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Name of a unit for a file, which is the file name without the extension
+///
+/// Characters other than letters and digits are replaced with `_` to keep the
+/// name usable in a label.
+pub fn unit(path: &str, program: String) -> Unit {
+    let stem = PathBuf::from(path).file_stem().map(|s| s.to_string_lossy().into_owned());
+    let name = stem
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    Unit { name, program }
+}
+
+/// Linker flags to use the [start](crate::start) of the runtime as `main`
+#[cfg(target_os = "linux")]
+const ENTRY: [&str; 2] = ["-Wl,--undefined=inc_main", "-Wl,--defsym=main=inc_main"];
+
+#[cfg(target_os = "macos")]
+const ENTRY: [&str; 2] = ["-Wl,-u,_inc_main", "-Wl,-alias,_inc_main,_main"];
+
 /// Build the generated ASM with clang into executable binary
-pub fn build(config: &Config) -> Result<(), Error> {
-    let exe = Command::new("gcc")
+///
+/// The runtime is linked statically, so the executable doesn't depend on
+/// anything in the target folder. Objects of the units are linked in as well,
+/// see [Driver::units]. The C compiler and the runtime depend on the
+/// [target](Target::runtime).
+pub fn build<'a>(config: &Config) -> Result<(), Error<'a>> {
+    let runtime = config.target.runtime();
+    if !Path::new(&runtime).exists() {
+        return Err(Error::Internal {
+            message: format!(
+                "No runtime for {} at {}, build it with `cargo build{}`",
+                config.target,
+                runtime,
+                if config.target.musl() {
+                    format!(" --target {}", config.target)
+                } else {
+                    String::new()
+                }
+            ),
+            e: None,
+        });
+    }
+
+    let exe = Command::new(config.target.cc())
         .arg("-m64")
         .arg("-g3")
         .arg("-ggdb3")
         .arg("-fomit-frame-pointer")
         .arg("-fno-asynchronous-unwind-tables")
-        .arg("-L./target/debug")
         .arg("-O0")
-        .arg("runtime.c")
-        .arg(&config.asm())
-        .arg("-linc")
+        .arg("-rdynamic")
+        .args(ENTRY)
+        .arg(config.asm())
+        .args(config.units.iter().map(|unit| config.unit_obj(unit)))
+        .arg(&runtime)
         .arg("-ldl")
         .arg("-lpthread")
+        .arg("-lm")
         .arg("-o")
         .arg(&config.output)
         .output()
-        .expect("Failed to execute C compiler");
+        .map_err(|e| Error::Internal {
+            message: format!("Failed to execute C compiler `{}`", config.target.cc()),
+            e: Some(e),
+        })?;
 
     if exe.status.success() {
         Ok(())
@@ -88,11 +810,82 @@ pub fn build(config: &Config) -> Result<(), Error> {
     }
 }
 
+/// Assemble generated ASM into an object file, without linking
+pub fn assemble<'a>(asm: &str, obj: &str) -> Result<(), Error<'a>> {
+    let exe = Command::new("gcc")
+        .arg("-m64")
+        .arg("-c")
+        .arg(asm)
+        .arg("-o")
+        .arg(obj)
+        .output()
+        .expect("Failed to execute C compiler");
+
+    if exe.status.success() {
+        Ok(())
+    } else {
+        Err(Error::Internal {
+            message: format!(
+                "Failed to assemble generated machine code. \n{}",
+                String::from_utf8_lossy(&exe.stderr)
+            ),
+            e: None,
+        })
+    }
+}
+
+/// Generate the asm of a program straight into the assembler, which writes
+/// the object file to the output without the asm ever hitting the disk
+pub fn pipe<'a>(config: &'a Config, prog: Vec<Syntax>, exports: &[Export]) -> Result<(), Error<'a>> {
+    let mut exe = Command::new("gcc")
+        .arg("-m64")
+        .arg("-c")
+        .args(["-x", "assembler", "-"])
+        .arg("-o")
+        .arg(&config.output)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute C compiler");
+
+    // Errors are read as they come, the assembler would block on a full pipe
+    // otherwise and never read the rest of the code
+    let mut stderr = exe.stderr.take().expect("Failed to read the assembler");
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        stderr.read_to_string(&mut errors).map(|_| errors)
+    });
+
+    // The assembler stops reading once stdin is dropped
+    let stdin = exe.stdin.take().expect("Failed to write to the assembler");
+    let written = stream(config, prog, exports, stdin).map(|stdin| stdin.map(drop));
+
+    let status = exe.wait()?;
+    let errors = errors.join().expect("Failed to read the assembler")?;
+
+    // The assembler may have made an object of the code before the error
+    if written.is_err() {
+        fs::remove_file(&config.output).ok();
+    }
+
+    written?.map_err(|e| Error::Internal {
+        message: String::from("Failed to write to the assembler"),
+        e: Some(e),
+    })?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Internal {
+            message: format!("Failed to assemble generated machine code. \n{}", errors),
+            e: None,
+        })
+    }
+}
+
 /// Run the generated binary and return output
-// Cargo automatically sets the LD_LIBRARY_PATH, which is really convenient here
-// because the generated binary is dynamically linked to an artifact in the
-// target folder.
-pub fn exec(config: &Config) -> Result<Option<String>, Error> {
+#[cfg(feature = "native")]
+pub fn exec(config: &Config) -> Result<Option<String>, Error<'_>> {
     use std::os::unix::process::ExitStatusExt;
 
     let path = PathBuf::from(&config.output).canonicalize()?;
@@ -109,6 +902,16 @@ pub fn exec(config: &Config) -> Result<Option<String>, Error> {
             String::from_utf8_lossy(&exe.stdout).trim().to_string()
                 + String::from_utf8_lossy(&exe.stderr).trim(),
         ))
+    } else if let Some(status) = exe.status.code() {
+        // The program reported the error itself and the status tells what it
+        // was, see exceptions::Status
+        let message: Vec<String> = [&exe.stdout, &exe.stderr]
+            .iter()
+            .map(|out| String::from_utf8_lossy(out).trim().to_string())
+            .filter(|out| !out.is_empty())
+            .collect();
+
+        Err(Error::Exit { status, message: message.join("\n") })
     } else if exe.status.signal() == Some(libc::SIGABRT) {
         // SIGABRT is #defined as 6 in /usr/include/asm/signal.h
         Err(Error::Runtime(
@@ -118,9 +921,225 @@ pub fn exec(config: &Config) -> Result<Option<String>, Error> {
         ))
     } else {
         Err(Error::Runtime(format!(
-            "Child process failed with code: `{:?}` & signal: {:?}",
+            "Child process failed with code: `{:?}` & signal: {:?}\n{}",
             exe.status.code(),
-            exe.status.signal()
+            exe.status.signal(),
+            String::from_utf8_lossy(&exe.stderr).trim()
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Target;
+
+    #[test]
+    fn compile() {
+        assert!(compile_to_asm("(define (twice x) (* x 2)) (twice 21)").unwrap().contains("init"));
+
+        let errors = compile_to_asm("(car 1 2)\n(load \"/nonexistent.scm\")").unwrap_err();
+        assert_eq!(errors[0].code, crate::diagnostic::codes::COMPILE);
+        assert!(errors[0].message.contains("/nonexistent.scm"));
+
+        let errors = compile_to_asm(&"(".repeat(100_000)).unwrap_err();
+        assert_eq!(errors[0].code, crate::diagnostic::codes::PARSE);
+        assert_eq!(errors[0].span.as_ref().map(|span| span.start), Some(parser::MAX_DEPTH));
+    }
+
+    #[test]
+    fn time_passes() {
+        let program = String::from("(define (f x) (+ (* x 2) (* x 3))) (f (+ 1 2))");
+        let config = Config { program, prelude: false, ..Default::default() };
+        let driver = Driver::new(&config);
+
+        let table = driver.time_passes(Timings::Table).unwrap();
+        let rows: Vec<&str> = table.lines().map(|l| l.split_whitespace().next().unwrap()).collect();
+        assert_eq!(
+            rows,
+            ["pass", "parsed", "expanded", "renamed", "lifted", "inlined", "folded", "anf", "tco"]
+                .iter()
+                .chain(&["codegen", "total", "nodes", "instructions", "tree-bytes", "strings"])
+                .chain(&["symbols", "peak-rss"])
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        assert!(table.lines().nth(1).unwrap().ends_with(" 18"));
+
+        let json = driver.time_passes(Timings::Json).unwrap();
+        assert!(json.starts_with(r#"{"passes":[{"pass":"parsed","ms":"#));
+        assert!(json.contains(r#""pass":"folded","ms":"#) && json.contains(r#","nodes":15}"#));
+        assert!(json.contains(r#""metrics":{"nodes":"#) && json.ends_with("}}"));
+    }
+
+    #[test]
+    fn expand() {
+        let program = String::from("(guard (e (#t 1)) 2)");
+        let config = Config { program, ..Default::default() };
+
+        assert!(Driver::new(&config).expand().unwrap().contains("%guard"));
+
+        let program = "(define (f x) (guard (e ((string? e) (display e) (string-length e)))
+                                         (car x) (vector x x)))";
+        let config = Config { program: program.to_string(), ..Default::default() };
+        let expected = "\
+(define f
+  (λ (x)
+    (let ((e #f))
+      (%guard
+        e
+        (let () (car x) (vector x x))
+        (if (string? e) (let () (display e) (string-length e)) (raise e))))))";
+
+        assert_eq!(Driver::new(&config).expand().unwrap(), expected);
+        assert!(Driver::new(&config).check().is_ok());
+
+        let config = Config { program: String::from("(f 1)"), ..Default::default() };
+        assert!(Driver::new(&config).check().is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let count = |program: &str| {
+            let config = Config { program: program.to_string(), ..Default::default() };
+
+            match Driver::new(&config).check() {
+                Err(Error::Errors(errors)) => errors.len(),
+                Err(_) => 1,
+                Ok(()) => 0,
+            }
+        };
+
+        // Errors in functions, top level expressions and syntax
+        assert_eq!(count("(define (f x) (+ y 1)) (+ 1 z) (guard (e (1)))"), 3);
+
+        // Every undefined reference at once
+        assert_eq!(count("(define (f x) (g x)) (h 1) (f 2)"), 2);
+    }
+
+    #[test]
+    fn checked() {
+        let dir = std::env::temp_dir().join(format!("inc-checked-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let output = dir.join("prog").to_string_lossy().into_owned();
+        let config = |program: &str, units| Config {
+            program: program.to_string(),
+            output: output.clone(),
+            units,
+            ..Default::default()
+        };
+
+        // Nothing is generated for the assembler with an undefined reference
+        let undefined = config("(define (f x) (g x)) (f 1)", vec![]);
+        match Driver::new(&undefined).emit(Stage::Asm) {
+            Err(Error::Located { message, .. }) => {
+                assert_eq!(message, "Undefined reference to `g`")
+            }
+            other => panic!("Expected an undefined reference, got {:?}", other),
+        }
+        assert!(!Path::new(&undefined.asm()).exists());
+
+        // Functions of the units are defined for the program
+        let linked = config("(g 1)", vec![unit("lib.scm", String::from("(define (g x) x)"))]);
+        assert!(Driver::new(&linked).emit(Stage::Asm).is_ok());
+        assert!(Path::new(&linked.asm()).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn emit() {
+        let program = String::from("(let ((f (lambda (x) (* x 2)))) (f 21))");
+        let config = Config { program, ..Default::default() };
+        let emit = |stage| Driver::new(&config).emit(stage).unwrap().unwrap();
+
+        assert_eq!(emit(Stage::Tokens).lines().count(), 23);
+        assert!(emit(Stage::SemanticTokens).starts_with("1:2 keyword let\n1:8 bound f"));
+        assert_eq!(emit(Stage::Ast).lines().count(), 1);
+        assert!(!emit(Stage::Renamed).contains("lambda (x)"));
+        assert!(emit(Stage::Lifted).lines().count() > 1);
+        assert!(!emit(Stage::Ir).is_empty());
+        assert!(emit(Stage::Header).contains("#define INC_FIXNUM(n)"));
+        assert!(emit(Stage::Map).starts_with(r#"{"version":1,"functions":[{"name":"init""#));
+
+        assert_eq!(Stage::parse("ir"), Ok(Stage::Ir));
+        assert!(Stage::parse("llvm").is_err());
+        assert!(Stage::NAMES.iter().all(|name| Stage::parse(name).is_ok()));
+    }
+
+    #[test]
+    fn trace() {
+        let dir = std::env::temp_dir().join(format!("inc-trace-{}", std::process::id()));
+        let program = String::from("(let ((f (lambda (x) (* x 2)))) (f 21))");
+        let config = Config { program, ..Default::default() };
+
+        Driver::new(&config).trace(&Trace::Dir(dir.to_string_lossy().into_owned())).unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+
+        assert_eq!(files[0], "00-parsed.scm");
+        assert_eq!(files[3], "03-lifted.scm");
+        assert_eq!(files.last().unwrap(), "07-tco.scm");
+
+        let lifted = fs::read_to_string(dir.join("03-lifted.scm")).unwrap();
+        assert!(lifted.starts_with("(define"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn target() {
+        let host = Target::host();
+
+        assert_eq!(Target::parse(&host.to_string()), Ok(host.clone()));
+        for triple in ["aarch64-unknown-linux-gnu", "aarch64-apple-darwin"] {
+            let e = Target::parse(triple).unwrap_err();
+            assert!(e.starts_with("Unsupported target"), "{}", e);
+        }
+        assert_eq!(
+            Target::parse("wasm32-wasi").unwrap_err(),
+            "Unsupported target wasm32-unknown-wasi, inc only generates x86-64 for now"
+        );
+        assert!(Target::parse("x86_64").is_err());
+
+        let other = if host.os == "linux" { "x86_64-apple-darwin" } else { "x86_64-linux" };
+        assert!(Target::parse(other).unwrap_err().contains("only targets the host"));
+
+        if host.os == "linux" {
+            let musl = Target::parse("x86_64-unknown-linux-musl").unwrap();
+            assert_eq!(musl.cc(), "musl-gcc");
+            assert_eq!(musl.runtime(), "./target/x86_64-unknown-linux-musl/debug/libinc.a");
+            assert_eq!(host.cc(), "gcc");
+            assert_eq!(host.runtime(), "./target/debug/libinc.a");
+
+            let android = Target::parse("x86_64-unknown-linux-android").unwrap_err();
+            assert!(android.contains("Unknown environment `android`"));
+        }
+    }
+
+    #[test]
+    fn wait() {
+        let path = std::env::temp_dir().join(format!("inc-wait-{}.scm", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        fs::write(&path, "1").unwrap();
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::write(&path, "2").unwrap();
+            })
+        };
+
+        super::wait(std::slice::from_ref(&path));
+        writer.join().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,22 @@
 //! Entry point for the Inc compiler
 
+use crate::{
+    core::{Core, Error, Name, Syntax},
+    parser,
+};
+use std::fmt;
+
 /// State for the code generator
 pub mod state {
     use crate::core::Ident;
+    use crate::hash::{self, Map};
+    use crate::lang::Passes;
+    use crate::library::Export;
     use crate::x86::{Reference, ASM, WORDSIZE};
-    use std::collections::HashMap;
+    use std::{mem, sync::Arc};
+
+    /// Marks the labels generated by a fork until it is [joined](State::join)
+    const FORKED: &str = "%";
 
     /// Shared state for the whole compiler
     ///
@@ -12,37 +24,176 @@ pub mod state {
     /// to allocate on stack. Defaults to `-word size`
     ///
     /// `li` is label index, a counter used to generate unique labels. See
-    /// `gen_label`
+    /// `gen_label`. Labels from a forked state are marked with `ns` until the
+    /// fork is [joined](State::join) and they are numbered after the others.
     ///
     /// `symbols` and `strings` are all strings known at compile time, so that
-    /// they can be allocated in the binary instead of heap.
+    /// they can be allocated in the binary instead of heap. Like the other
+    /// tables of the state they are a [Map](crate::hash::Map), made with room
+    /// for the literals of the program once they are counted. The tables are
+    /// read only by the time code is generated, and forks share them.
+    ///
+    /// `runtime` is set for code compiled while the program is running, which
+    /// refers to symbols interned in the runtime directly; see
+    /// [symbols](crate::symbols).
+    ///
+    /// `passes` are the optional passes run over the program, see
+    /// [Passes](crate::lang::Passes).
+    ///
+    /// `errors` are the errors found so far, which are reported together once
+    /// the program has been checked as far as it can be, see
+    /// [attempt](State::attempt).
+    ///
+    /// `unit` is the name of the unit being compiled when a program is built
+    /// from several files. A unit refers to its symbols through slots its
+    /// initializer fills in, since only the program has a symbol table; see
+    /// [unit](super::emit::unit).
+    ///
+    /// `exports` are the names exported by libraries, which get an entry point
+    /// callable from C if they name a procedure defined in the program; see
+    /// [header](crate::header).
+    ///
+    /// `profile` numbers the functions that count their calls, and is empty
+    /// unless the program is [profiled](crate::profile).
+    #[derive(Clone)]
     pub struct State {
         pub si: i64,
         pub asm: ASM,
         li: u64,
-        pub strings: HashMap<String, usize>,
-        pub symbols: HashMap<String, usize>,
+        ns: String,
+        pub strings: Arc<Map<String, usize>>,
+        pub symbols: Arc<Map<String, usize>>,
+        pub runtime: bool,
+        pub passes: Passes,
+        pub unit: Option<String>,
+        pub exports: Arc<Vec<Export>>,
+        pub profile: Arc<Map<String, usize>>,
+        pub errors: Vec<super::Fault>,
         env: Env,
     }
 
+    impl Default for State {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl State {
         pub fn new() -> Self {
             State {
                 si: -WORDSIZE,
                 asm: Default::default(),
                 li: 0,
-                strings: HashMap::new(),
-                symbols: HashMap::new(),
+                ns: String::new(),
+                strings: Arc::new(hash::map(0)),
+                symbols: Arc::new(hash::map(0)),
+                runtime: false,
+                passes: Passes::default(),
+                unit: None,
+                exports: Arc::new(vec![]),
+                profile: Arc::new(hash::map(0)),
+                errors: vec![],
                 env: Default::default(),
             }
         }
 
+        /// Fork an independent copy of the state for code generation on
+        /// another thread, sharing the tables of the parent
+        pub fn fork(&self) -> Self {
+            State {
+                si: self.si,
+                asm: Default::default(),
+                li: 0,
+                ns: String::from(FORKED),
+                strings: Arc::clone(&self.strings),
+                symbols: Arc::clone(&self.symbols),
+                runtime: self.runtime,
+                passes: self.passes,
+                unit: self.unit.clone(),
+                exports: Arc::clone(&self.exports),
+                profile: Arc::clone(&self.profile),
+                errors: vec![],
+                env: self.env.clone(),
+            }
+        }
+
+        /// Take the code generated by a fork that made `labels` labels
+        ///
+        /// Its labels are numbered after the ones generated here so far, as if
+        /// the code had been generated here. Joining forks in a fixed order
+        /// makes the same labels however the work was spread over threads.
+        pub fn join(&mut self, labels: u64, asm: ASM) -> ASM {
+            let base = self.li;
+            self.li += labels;
+
+            let marker = format!("_{}", FORKED);
+            asm.relabel(|label| match label.rsplit_once(&marker) {
+                Some((prefix, n)) => match n.parse::<u64>() {
+                    Ok(n) => format!("{}_{}", prefix, base + n),
+                    Err(_) => label,
+                },
+                None => label,
+            })
+        }
+
+        /// Number of labels generated so far
+        pub const fn labels(&self) -> u64 {
+            self.li
+        }
+
+        /// Run a step of the compiler that may fail and carry on without it
+        ///
+        /// The errors of a failed step are kept and [checked](State::check)
+        /// along with the rest later. Steps are whole top level expressions,
+        /// so that one bad expression doesn't hide the errors in the others.
+        pub fn attempt<T>(
+            &mut self,
+            f: impl FnOnce(&mut Self) -> Result<T, Vec<super::Fault>>,
+        ) -> Option<T> {
+            match f(self) {
+                Ok(result) => Some(result),
+                Err(errors) => {
+                    self.errors.extend(errors);
+                    None
+                }
+            }
+        }
+
+        /// Keep an error found generating code and carry on without the code
+        ///
+        /// Nothing generated is used once there is an error, so the rest of
+        /// the program is still generated only to find the errors in it.
+        pub fn fail<T: Default>(&mut self, message: String) -> T {
+            self.errors.push(message.into());
+            T::default()
+        }
+
+        /// All the errors found so far, if there are any
+        ///
+        /// Errors are reported once, even if inlining made the same mistake
+        /// show up in several places.
+        pub fn check(&mut self) -> Result<(), Vec<super::Fault>> {
+            let mut errors: Vec<super::Fault> = vec![];
+
+            for e in mem::take(&mut self.errors) {
+                if !errors.contains(&e) {
+                    errors.push(e)
+                }
+            }
+
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        }
+
         pub fn enter(&mut self) {
             self.env.enter();
         }
 
         pub fn leave(&mut self) {
-            let unwind = self.env.0.first().expect("unexpected empty env").len() as i64 * WORDSIZE;
+            let unwind = self.env.len() as i64 * WORDSIZE;
             self.si += unwind;
             self.env.leave()
         }
@@ -81,50 +232,76 @@ pub mod state {
         /// Generate a unique label for jump targets.
         pub fn gen_label(&mut self, prefix: &str) -> String {
             self.li += 1;
-            format!("{}_{}", prefix, self.li)
+            format!("{}_{}{}", prefix, self.ns, self.li)
         }
     }
-    // Environment is an *ordered* list of bindings.
-    struct Env(Vec<HashMap<Ident, Reference>>);
+    // Environment is an *ordered* list of scopes, innermost last.
+    //
+    // Every name maps to all its bindings in scope, innermost last, so that
+    // looking one up doesn't get slower the deeper the scopes are nested.
+    #[derive(Clone)]
+    struct Env {
+        bound: Map<Ident, Vec<Reference>>,
+        scopes: Vec<Vec<Ident>>,
+    }
 
     impl Default for Env {
         fn default() -> Self {
-            Env(vec![HashMap::new()])
+            Env { bound: hash::map(0), scopes: vec![vec![]] }
         }
     }
 
     impl Env {
         pub fn enter(&mut self) {
-            self.0.insert(0, HashMap::new());
+            self.scopes.push(vec![]);
         }
 
         pub fn leave(&mut self) {
-            self.0.remove(0);
+            for i in self.scopes.pop().unwrap_or_default() {
+                if let Some(refs) = self.bound.get_mut(&i) {
+                    refs.pop();
+                    if refs.is_empty() {
+                        self.bound.remove(&i);
+                    }
+                }
+            }
+        }
+
+        /// Number of names bound in the innermost scope
+        pub fn len(&self) -> usize {
+            self.scopes.last().map_or(0, Vec::len)
         }
 
         pub fn set(&mut self, i: Ident, r: Reference) {
-            self.0.first_mut().map(|binding| binding.insert(i, r));
+            let scope = match self.scopes.last_mut() {
+                Some(scope) => scope,
+                None => return,
+            };
+            let refs = self.bound.entry(i.clone()).or_default();
+
+            if scope.contains(&i) {
+                refs.pop();
+            } else {
+                scope.push(i);
+            }
+            refs.push(r);
         }
 
         pub fn get(&self, i: &Ident) -> Option<&Reference> {
-            for bindings in &self.0 {
-                if let Some(t) = bindings.get(i) {
-                    return Some(t);
-                }
-            }
-            None
+            self.bound.get(i).and_then(|refs| refs.last())
         }
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use crate::x86::Ins;
         use pretty_assertions::assert_eq;
 
         #[test]
         fn t() {
             let mut e: Env = Default::default();
-            assert_eq!(e.0.len(), 1);
+            assert_eq!(e.scopes.len(), 1);
 
             // default global scope
             e.set(Ident::new("x"), Reference::from(-8));
@@ -135,7 +312,7 @@ pub mod state {
             assert_eq!(e.get(&Ident::new("x")), Some(&Reference::from(-16)));
 
             e.enter();
-            assert_eq!(e.0.len(), 2);
+            assert_eq!(e.scopes.len(), 2);
             // read variables from parent scope
             assert_eq!(e.get(&Ident::new("x")), Some(&Reference::from(-16)));
 
@@ -146,10 +323,34 @@ pub mod state {
 
             e.leave();
 
-            assert_eq!(e.0.len(), 1);
+            assert_eq!(e.scopes.len(), 1);
             assert_eq!(e.get(&Ident::new("y")), None);
             assert_eq!(e.get(&Ident::new("x")), Some(&Reference::from(-16)));
         }
+
+        #[test]
+        fn fork() {
+            let mut s = State::new();
+            Arc::make_mut(&mut s.strings).insert(String::from("hello"), 0);
+            assert_eq!(s.gen_label("exit"), "exit_1");
+
+            let mut f = s.fork();
+            assert!(Arc::ptr_eq(&s.strings, &f.strings));
+
+            let (l1, l2) = (f.gen_label("exit"), f.gen_label("else"));
+            let asm = ASM::from(vec![Ins::Jmp(l1.clone()), Ins::Label(l2), Ins::Label(l1)]);
+            let asm = s.join(f.labels(), asm);
+
+            assert_eq!(
+                asm.0,
+                vec![
+                    Ins::Jmp(String::from("exit_2")),
+                    Ins::Label(String::from("else_3")),
+                    Ins::Label(String::from("exit_2"))
+                ]
+            );
+            assert_eq!(s.gen_label("exit"), "exit_4");
+        }
     }
 }
 
@@ -161,11 +362,15 @@ pub mod state {
 /// anything generic goes into `x86` module.
 pub mod emit {
     use crate::{
-        compiler::state::State,
-        core::{Core, Expr::*, Ident, Literal::*, Syntax},
-        x86::{self, Ins, Reference, Register::*, Relative, ASM},
+        compiler::{state::State, Fault},
+        core::{Closure, Core, Expr::*, Ident, Literal::*, Syntax},
+        lang::{Pass, Passes},
+        library::Export,
+        x86::{self, Ins, Output, Reference, Register::*, Relative, ASM},
+        host::Clock,
         *,
     };
+    use std::sync::Arc;
 
     /// Clear (mask) all except the least significant 3 tag bits
     pub fn mask() -> Ins {
@@ -181,7 +386,7 @@ pub mod emit {
     /// keep track of the amount of space allocated inside the let expression
     /// and free it afterwards.
     pub fn vars(s: &mut State, vars: &[(Ident, Core)], body: &[Core]) -> ASM {
-        let mut asm = ASM(vec![]);
+        let mut asm = ASM::default();
 
         s.enter();
 
@@ -196,7 +401,7 @@ pub mod emit {
         }
 
         for b in body {
-            asm += eval(s, &b);
+            asm += eval(s, b);
         }
 
         s.leave();
@@ -232,19 +437,22 @@ pub mod emit {
     // TODO: eval should dispatch based on first atom alone, not necessarily
     // care about arity here. `let` and other variadic syntax forms won't fit
     // into any specific branch here.
-
     #[allow(clippy::redundant_pattern)]
     pub fn eval(s: &mut State, prog: &Core) -> ASM {
         match prog {
-            Identifier(i) => match s.get(&i) {
+            Identifier(i) => match s.get(i) {
                 Some(index) => x86::mov(RAX.into(), index.clone()).into(),
-                None => panic!("Undefined variable {}", i),
+                None => s.fail(format!("Undefined variable {}", i)),
             },
 
             // Find the symbol index and return and reference in RAX
-            Literal(Str(data)) => strings::eval(&s, &data),
+            Literal(Str(data)) => strings::eval(s, data),
 
-            Literal(Symbol(data)) => symbols::eval(&s, &data),
+            Literal(Float(f)) => numbers::eval(s, *f),
+
+            Literal(Bignum(n)) => bignum::eval(s, n),
+
+            Literal(Symbol(data)) => symbols::eval(s, data),
 
             Let { bindings, body } => vars(s, bindings, body),
 
@@ -252,45 +460,292 @@ pub mod emit {
 
             List(list) => match list.as_slice() {
                 [Identifier(name), args @ ..] => {
-                    if let Some(x) = primitives::call(s, &name, args) {
+                    // Local variables can't refer to functions yet, the only
+                    // values that can be called are continuations
+                    if s.get(name).is_some() {
+                        continuations::throw(s, name, args)
+                    } else if let Some(x) = primitives::call(s, name, args) {
                         x
-                    } else if rt::defined(&name) {
-                        ffi::call(s, name, &args)
+                    } else if rt::defined(name) {
+                        ffi::call(s, name, args)
+                    } else if let Some(index) = name.free().and_then(callbacks::find) {
+                        callbacks::call(s, index, args)
                     } else {
-                        lambda::call(s, &name, &args)
+                        lambda::call(s, name, args)
                     }
                 }
-                _ => panic!("Unknown expression: `{}`", prog),
+                _ => s.fail(format!("Unknown expression: `{}`", prog)),
             },
 
-            Lambda(_) => ASM(vec![]),
+            Lambda(_) => ASM::default(),
 
-            Define { .. } => ASM(vec![]),
+            Define { .. } => ASM::default(),
 
-            _ => match immediate::to(&prog) {
+            _ => match immediate::to(prog) {
                 Some(c) => x86::mov(RAX.into(), c.into()).into(),
-                None => panic!("Unknown expression: `{}`", prog),
+                None => s.fail(format!("Unknown expression: `{}`", prog)),
             },
         }
     }
 
     /// Top level interface to the emit module
-    pub fn program(prog: Vec<Syntax>) -> String {
+    pub fn program(prog: Vec<Syntax>) -> Result<String, Vec<Fault>> {
+        compile(prog).map(|asm| asm.to_string())
+    }
+
+    /// Compile a whole program into instructions, see `program`
+    pub fn compile(prog: Vec<Syntax>) -> Result<ASM, Vec<Fault>> {
+        compile_with(prog, Passes::default())
+    }
+
+    /// Compile a whole program running only some of the optional passes
+    pub fn compile_with(prog: Vec<Syntax>, passes: Passes) -> Result<ASM, Vec<Fault>> {
+        traced(prog, passes, &mut |_| {})
+    }
+
+    /// Compile a whole program and show every pass to `trace`
+    ///
+    /// The passes of [analysis](lang::traced) are followed by code generation
+    /// as `codegen`, with the generated code as the program.
+    pub fn traced(
+        prog: Vec<Syntax>,
+        passes: Passes,
+        trace: &mut dyn FnMut(&Pass),
+    ) -> Result<ASM, Vec<Fault>> {
+        exporting(prog, passes, &[], false, trace)
+    }
+
+    /// Compile a whole program like [traced], with the exports of libraries
+    /// resolved before it
+    ///
+    /// Exported procedures defined in the program get entry points callable
+    /// from C, see [header]. A program [instrumented](coverage::instrument) for
+    /// coverage gets the table of its counters, and with `profile` every
+    /// function counts its calls, see [profile].
+    pub fn exporting(
+        prog: Vec<Syntax>,
+        passes: Passes,
+        exports: &[Export],
+        profile: bool,
+        trace: &mut dyn FnMut(&Pass),
+    ) -> Result<ASM, Vec<Fault>> {
+        let mut gen = ASM::default();
+        streaming(prog, passes, exports, profile, &mut gen, trace)?;
+        Ok(gen)
+    }
+
+    /// Compile a whole program like [exporting], handing the code to `out` as
+    /// it is generated
+    ///
+    /// Code is generated a top level expression at a time, so that with a
+    /// [Writer](x86::Writer) it goes straight to a file or the assembler.
+    pub fn streaming(
+        prog: Vec<Syntax>,
+        passes: Passes,
+        exports: &[Export],
+        profile: bool,
+        out: &mut impl Output,
+        trace: &mut dyn FnMut(&Pass),
+    ) -> Result<(), Vec<Fault>> {
         let mut s = State::new();
+        s.passes = passes;
+        s.exports = Arc::new(exports.to_vec());
 
-        let prog = lang::analyze(&mut s, prog);
+        let counters = coverage::counters(&prog);
+        let prog = lang::traced(&mut s, prog, trace);
+        let clock = Clock::start();
+        metrics::interned(s.strings.len(), s.symbols.len());
 
-        let mut gen = x86::prelude() + x86::func(&x86::init()) + x86::enter() + x86::init_heap();
+        if profile {
+            s.profile = Arc::new(profile::functions(&prog));
+        }
+
+        out.emit(x86::prelude() + x86::func(&x86::init()) + x86::enter() + x86::init_heap());
+        out.emit(symbols::register(&s));
 
         for b in &prog {
-            gen += eval(&mut s, &b);
+            out.emit(eval(&mut s, b));
         }
 
-        gen += x86::leave();
-        gen += strings::inline(&s);
-        gen += symbols::inline(&s);
-        gen += lambda::emit(&mut s, &prog);
+        out.emit(x86::leave());
+        out.emit(strings::inline(&s));
+        out.emit(symbols::inline(&s));
+        out.emit(lambda::emit(&mut s, &prog));
+        out.emit(entries(&mut s, &prog));
+        out.emit(exceptions::dispatch());
+        out.emit(gc::finalize());
+        out.emit(coverage::table(&counters));
+        out.emit(profile::table(&s));
+
+        trace(&Pass { name: "codegen", time: clock.elapsed(), program: &*out });
+
+        s.check()
+    }
+
+    /// Compile a unit of a program built from several files
+    ///
+    /// A unit has no `init` of its own. All its top level functions are global
+    /// and the top level expressions are moved into a function named by
+    /// [initializer], which the program calls before anything else. Functions
+    /// the unit doesn't define, like the ones in the prelude or in other units,
+    /// are resolved by the linker.
+    ///
+    /// The initializer registers the symbols of the unit before anything else,
+    /// so that they are the same objects as the symbols of the program and the
+    /// other units; see [symbols](crate::symbols).
+    ///
+    /// Like [exporting], procedures exported by libraries get entry points.
+    /// The code is handed to `out` as it is generated, like with [streaming].
+    pub fn unit(
+        name: &str,
+        prog: Vec<Syntax>,
+        passes: Passes,
+        exports: &[Export],
+        out: &mut impl Output,
+    ) -> Result<(), Vec<Fault>> {
+        let mut s = State::new();
+        s.passes = passes;
+        s.unit = Some(name.to_string());
+        s.exports = Arc::new(exports.to_vec());
+
+        let (mut prog, body): (Vec<Core>, Vec<Core>) = lang::traced(&mut s, prog, &mut |_| {})
+            .into_iter()
+            .partition(|e| matches!(e, Define { .. }));
+
+        prog.push(Define {
+            name: Ident::new(initializer(name)),
+            val: Box::new(Lambda(Closure {
+                formals: vec![],
+                free: vec![],
+                body: vec![Let { bindings: vec![], body: registered(body) }],
+                tail: false,
+            })),
+        });
+
+        out.emit(x86::prelude());
+        out.emit(strings::inline(&s));
+        out.emit(symbols::inline(&s));
+        out.emit(lambda::emit(&mut s, &prog));
+        out.emit(entries(&mut s, &prog));
+        out.emit(exceptions::dispatch());
+        out.emit(gc::finalize());
+
+        s.check()
+    }
+
+    /// The body of the initializer of a unit, after registering its symbols
+    fn registered(body: Vec<Core>) -> Vec<Core> {
+        let register = List(vec![Ident::expr(symbols::REGISTER)]);
+        std::iter::once(register).chain(body).collect()
+    }
+
+    /// Entry points for the exported procedures defined in a program
+    fn entries(s: &mut State, prog: &[Core]) -> ASM {
+        let entries = header::entries(&s.exports, prog);
+        header::emit(s, &entries)
+    }
+
+    /// Name of the function running the top level expressions of a unit
+    pub fn initializer(unit: &str) -> String {
+        format!("%init-{}", unit)
+    }
+}
+
+/// Parse a program along with the scheme prelude
+pub fn parse(program: &str) -> Result<Vec<Syntax>, Error<'_>> {
+    let prog = parser::parse(program)?;
+
+    Ok(prelude().into_iter().chain(prog).collect())
+}
 
-        gen.to_string()
+/// The scheme prelude, which comes before every program
+///
+/// The prelude implements the library procedures that are simpler to write in
+/// Scheme than in the runtime, like `length`, `append`, `reverse`, `list?` and
+/// `assoc`. Programs compiled without it can't use any of them.
+pub fn prelude() -> Vec<Syntax> {
+    parser::parse(include_str!("prelude.ss")).expect("Failed to parse the prelude")
+}
+
+/// Fail with an error in the program that doesn't point at any name
+///
+/// See [invalid](crate::lang::invalid) for one that does.
+pub fn fail<T>(message: String) -> Result<T, Vec<Fault>> {
+    Err(vec![message.into()])
+}
+
+/// The results of a few steps, or the errors of every step that failed
+pub fn all<T>(
+    results: impl IntoIterator<Item = Result<T, Vec<Fault>>>,
+) -> Result<Vec<T>, Vec<Fault>> {
+    let mut values = vec![];
+    let mut errors = vec![];
+
+    for result in results {
+        match result {
+            Ok(value) => values.push(value),
+            Err(e) => errors.extend(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Errors in a program as one message, one error a line
+pub fn messages(faults: Vec<Fault>) -> String {
+    faults.iter().map(|e| e.message.as_str()).collect::<Vec<_>>().join("\n")
+}
+
+/// An error in a program
+///
+/// An error about a name, like one bound twice, points at where it was read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub message: String,
+    pub name: Option<Name>,
+}
+
+impl From<String> for Fault {
+    fn from(message: String) -> Self {
+        Fault { message, name: None }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Compile and run scheme programs without leaving the process
+///
+/// ```
+/// use inc::{compiler::Compiler, core::{Expr::*, Literal::*}};
+///
+/// let val = Compiler::new().run("(+ 1 2)").unwrap();
+/// assert_eq!(val, Literal(Number(3)));
+/// ```
+#[derive(Default)]
+pub struct Compiler;
+
+impl Compiler {
+    pub const fn new() -> Self {
+        Compiler
+    }
+
+    /// Compile a program with the JIT, run it and return the resulting value
+    ///
+    /// The program shares the process with the caller, so a call to `exit`
+    /// exits the caller as well.
+    #[cfg(feature = "native")]
+    pub fn run<'a>(&self, program: &'a str) -> Result<Core, Error<'a>> {
+        let asm = emit::compile(parse(program)?).map_err(Error::compilation)?;
+        let image = crate::jit::load(&asm)?;
+
+        Ok(image.run(|val| val.deref()))
     }
 }
@@ -0,0 +1,174 @@
+//! Completion of names for the repl and editors
+//!
+//! [complete] finds the names starting with the one being typed at an offset
+//! of some source, along with what each of them is:
+//!
+//! - Names bound around the offset by lambdas, lets and definitions in a body,
+//!   found like [semantic tokens](crate::semantic) find them.
+//! - Functions and variables defined at the top level of the source, and the
+//!   ones given by the caller, like the definitions of a repl
+//!   [Session](crate::repl::Session) or the other files open in an editor.
+//! - Functions of the [prelude](crate::compiler::prelude), the compiler and the
+//!   runtime.
+//! - Special forms and derived syntax, only at the head of a list.
+//!
+//! ```
+//! use inc::{complete, semantic::Kind};
+//!
+//! let source = "(define (twice x) (* x 2)) (let ((total 1)) (t";
+//! let (span, names) = complete::complete(source, source.len(), &[]);
+//!
+//! assert_eq!(&source[span], "t");
+//! assert_eq!(names[0].name, "total");
+//! assert_eq!(names[0].kind, Kind::Bound);
+//! assert!(names.iter().any(|c| c.name == "twice" && c.kind == Kind::Free));
+//! ```
+use crate::{
+    compiler,
+    core::Expr,
+    lsp, primitives, rt,
+    semantic::{self, Kind, KEYWORDS, MACROS},
+};
+use std::ops::Range;
+
+/// A name that completes the one being typed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub name: String,
+    /// [Bound](Kind::Bound), [Free](Kind::Free) for the names defined at the
+    /// top level, [Primitive](Kind::Primitive) for the prelude and the rest
+    /// built in, [Keyword](Kind::Keyword) or [Macro](Kind::Macro)
+    pub kind: Kind,
+}
+
+/// Characters that end a name
+const DELIMITERS: &[char] = &['(', ')', '[', ']', '\'', '`', ',', '"', ';'];
+
+/// The span of the name being typed at an offset and the names completing it
+///
+/// Candidates are ordered by their kind, in the order of the list in the module
+/// docs, and then by name. A name is given once, as the first kind it is found
+/// to be. Nothing completes a number, a string or a character.
+pub fn complete(source: &str, offset: usize, globals: &[String]) -> (Range<usize>, Vec<Candidate>) {
+    let before = &source[..offset];
+    let start = before
+        .rfind(|c: char| c.is_whitespace() || DELIMITERS.contains(&c))
+        .map_or(0, |i| i + before[i..].chars().next().map_or(0, char::len_utf8));
+    let span = start..offset;
+    let prefix = &source[span.clone()];
+
+    if prefix.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+        return (span, vec![]);
+    }
+
+    let mut bound = semantic::bound_at(source, offset);
+    bound.reverse();
+
+    let defined = lsp::definitions(&lsp::read(source)).into_iter().map(|d| d.name);
+    let defined: Vec<String> = defined.chain(globals.iter().cloned()).collect();
+
+    let head = source[..start].trim_end().ends_with(['(', '[']);
+
+    let groups: Vec<(Kind, Vec<String>)> = vec![
+        (Kind::Bound, bound),
+        (Kind::Free, sorted(defined)),
+        (Kind::Primitive, sorted(builtins())),
+        (Kind::Keyword, if head { sorted(KEYWORDS.iter().map(|k| k.to_string())) } else { vec![] }),
+        (Kind::Macro, if head { sorted(MACROS.iter().map(|k| k.to_string())) } else { vec![] }),
+    ];
+
+    let mut candidates: Vec<Candidate> = vec![];
+
+    for (kind, names) in groups {
+        for name in names {
+            if name.starts_with(prefix) && !candidates.iter().any(|c| c.name == name) {
+                candidates.push(Candidate { name, kind });
+            }
+        }
+    }
+
+    (span, candidates)
+}
+
+/// The functions of the prelude, the compiler and the runtime
+fn builtins() -> Vec<String> {
+    let prelude = compiler::prelude().into_iter().filter_map(|e| match e {
+        Expr::Define { name, .. } if !name.starts_with('%') => Some(String::from(name)),
+        _ => None,
+    });
+    let runtime = rt::FUNCTIONS.iter().filter(|name| !name.starts_with("rt-"));
+
+    prelude
+        .chain(primitives::names().into_iter().map(String::from))
+        .chain(runtime.map(|name| name.to_string()))
+        .collect()
+}
+
+fn sorted(names: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut names: Vec<String> = names.into_iter().collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(source: &str, globals: &[&str]) -> Vec<(String, Kind)> {
+        let globals: Vec<String> = globals.iter().map(|g| g.to_string()).collect();
+        let (_, candidates) = complete(source, source.len(), &globals);
+        candidates.into_iter().map(|c| (c.name, c.kind)).collect()
+    }
+
+    #[test]
+    fn scopes() {
+        let source = "(define (fold f acc l) (let ((first (car l))) (f";
+        let found = names(source, &[]);
+
+        assert_eq!(found[0], (String::from("first"), Kind::Bound));
+        assert_eq!(found[1], (String::from("f"), Kind::Bound));
+        assert!(found.contains(&(String::from("fold"), Kind::Free)));
+
+        // Names of a let aren't bound in its values
+        let found = names("(let ((value 1) (other (v", &[]);
+        assert!(!found.iter().any(|(name, _)| name == "value"));
+
+        // A bound name shadows a global one of the same name
+        let found = names("(define (f car) (ca", &[]);
+        assert_eq!(found[0], (String::from("car"), Kind::Bound));
+        assert_eq!(found.iter().filter(|(name, _)| name == "car").count(), 1);
+    }
+
+    #[test]
+    fn globals() {
+        let found = names("(tw", &["twice", "thrice"]);
+        assert_eq!(found, vec![(String::from("twice"), Kind::Free)]);
+
+        let found = names("(vector-le", &[]);
+        assert_eq!(found, vec![(String::from("vector-length"), Kind::Primitive)]);
+
+        // The prelude is built in too, and its helpers are left out
+        assert!(names("(appe", &[]).contains(&(String::from("append"), Kind::Primitive)));
+        assert!(names("(%", &[]).iter().all(|(name, _)| name == "%"));
+    }
+
+    #[test]
+    fn keywords() {
+        assert!(names("(le", &[]).contains(&(String::from("let*"), Kind::Keyword)));
+        assert!(names("(gu", &[]).contains(&(String::from("guard"), Kind::Macro)));
+        assert!(!names("(f le", &[]).iter().any(|(_, kind)| *kind == Kind::Keyword));
+    }
+
+    #[test]
+    fn spans() {
+        let source = "(display \"hello\") (string-ap 1)";
+        let (span, found) = complete(source, 28, &[]);
+
+        assert_eq!(&source[span], "string-ap");
+        assert_eq!(found[0].name, "string-append");
+
+        assert_eq!(complete("(+ 12", 5, &[]).1, vec![]);
+        assert_eq!(complete("", 0, &[]).0, 0..0);
+    }
+}
@@ -0,0 +1,185 @@
+//! Escaping continuations
+//!
+//! `(call/cc f)` calls the function `f` with the continuation of the call, an
+//! object `k` that returns from `call/cc` when called like a function with a
+//! value `(k v)`. This makes non-local exits like returning early from a deep
+//! recursion straightforward.
+//!
+//! ```scheme
+//! (let ((f (lambda (k) (+ 1 (k 41)))))
+//!   (+ 1 (call/cc f)))
+//! ```
+//!
+//! Only escaping (one shot, upward) continuations are supported for now; a
+//! continuation can be invoked only during the dynamic extent of the call to
+//! `f`, which is enough for early exits, loops with `break` and similar
+//! patterns. Invoking it later raises an error instead of reentering the
+//! function.
+//!
+//! `call/cc` pushes an escape point into the dynamic environment of the
+//! runtime, the same one that holds the exception handlers. Invoking the
+//! continuation discards every entry above the escape point and unwinds the
+//! scheme stack to its frame, exactly like raising to a guard does. See
+//! [exceptions](crate::exceptions) for details.
+//!
+//! A continuation is a vector `['continuation id]` where the `id` identifies
+//! the escape point, much like ports. Functions aren't first class values yet,
+//! so `f` must be the name of a function or a lambda, which is lifted out and
+//! passed by the name it gets.
+//!
+//! `(dynamic-wind before thunk after)` calls `thunk` between `before` and
+//! `after`, and makes sure `after` is called even if control leaves `thunk`
+//! by invoking a continuation or raising to a guard. The after thunks on the
+//! way are called one at a time, innermost first and outside of their own
+//! `dynamic-wind`. Since continuations can't be reentered, `before` is called
+//! exactly once.
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::{Core, Expr, Ident, Literal::*},
+    exceptions::{self, Entry, Target},
+    ffi, lambda,
+    rt::Object,
+    x86::{self, Reference, Register::*, Relative, ASM},
+};
+
+/// Emit code for `(call/cc f)`
+pub fn call(s: &mut State, f: &Ident) -> ASM {
+    lambda::function(s, "call/cc", f);
+
+    let resume = s.gen_label("resume");
+    let done = s.gen_label("done");
+
+    let mut asm = x86::lea(RSI, &resume, 0)
+        + x86::mov(RDI.into(), RBP.into())
+        + ffi::runtime(s, "rt_push_escape");
+
+    // Bind the id of the escape point and then the continuation object to
+    // local variables, so that they can be passed along like any other value.
+    s.enter();
+
+    let id = Ident::new("%id");
+    asm += x86::save(RAX.into(), s.si);
+    s.set(id.clone(), Relative { register: RBP, offset: s.si }.into());
+    asm += lambda::call(s, &Ident::new("%continuation"), &[Expr::Identifier(id)]);
+
+    let k = Ident::new("%k");
+    asm += x86::save(RAX.into(), s.si);
+    s.set(k.clone(), Relative { register: RBP, offset: s.si }.into());
+    asm += lambda::call(s, f, &[Expr::Identifier(k)]);
+
+    s.leave();
+
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += ffi::runtime(s, "rt_pop_escape");
+    asm += x86::load(RAX, slot);
+    s.dealloc(1);
+
+    asm + x86::jmp(&done)
+        + x86::label(&resume)
+        + ffi::runtime(s, "rt_condition")
+        + x86::label(&done)
+}
+
+/// Emit code to invoke the continuation in the local variable `k`
+pub fn throw(s: &mut State, k: &Ident, args: &[Core]) -> ASM {
+    let val = match args {
+        [] => Expr::Literal(Nil),
+        [val] => val.clone(),
+        _ => return s.fail(format!("continuation {} called with {} arguments", k, args.len())),
+    };
+
+    let mut asm = eval(s, &val);
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += eval(s, &Expr::Identifier(k.clone()));
+    asm += x86::mov(RDI.into(), RAX.into());
+    asm += x86::mov(RSI.into(), Reference::from(Relative { register: RBP, offset: slot }));
+    s.dealloc(1);
+
+    asm + ffi::runtime(s, "rt_throw") + exceptions::fail(s)
+}
+
+/// Emit code for `(dynamic-wind before thunk after)`
+pub fn wind(s: &mut State, before: &Ident, thunk: &Ident, after: &Ident) -> ASM {
+    for f in [before, thunk, after] {
+        lambda::function(s, "dynamic-wind", f);
+    }
+
+    let mut asm = lambda::call(s, before, &[])
+        + x86::lea(RDI, &after.mangle(), 0)
+        + ffi::runtime(s, "rt_push_wind")
+        + lambda::call(s, thunk, &[]);
+
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += ffi::runtime(s, "rt_pop_wind");
+    asm += lambda::call(s, after, &[]);
+    asm += x86::load(RAX, slot);
+    s.dealloc(1);
+
+    asm
+}
+
+/// Push the after thunk of a `dynamic-wind`
+#[no_mangle]
+pub extern "C" fn rt_push_wind(after: i64) {
+    exceptions::dynamic(|d| d.stack.push(Entry::Wind(after)))
+}
+
+/// Remove the after thunk of a `dynamic-wind` before calling it
+#[no_mangle]
+pub extern "C" fn rt_pop_wind() {
+    exceptions::dynamic(|d| d.stack.pop());
+}
+
+/// Push a new escape point and return its id
+#[no_mangle]
+pub extern "C" fn rt_push_escape(frame: i64, address: i64) -> Object {
+    exceptions::dynamic(|d| {
+        d.escapes += 1;
+        d.stack.push(Entry::Escape { frame, address, id: d.escapes });
+        Object::immediate(d.escapes)
+    })
+}
+
+/// Remove the escape point after `call/cc` returns normally
+#[no_mangle]
+pub extern "C" fn rt_pop_escape() {
+    exceptions::dynamic(|d| d.stack.pop());
+}
+
+/// Return `val` from the escape point of the continuation `k`
+#[no_mangle]
+pub extern "C" fn rt_throw(k: Object, val: Object) -> Target {
+    let id = match k.deref() {
+        Expr::Vector(v) => match v.as_slice() {
+            [Expr::Literal(Symbol(tag)), Expr::Literal(Number(id))] if tag == "continuation" => {
+                Some(*id)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let id = match id {
+        Some(id) => id,
+        None => return exceptions::error(&format!("attempt to apply non-procedure {}", k)),
+    };
+
+    let target = exceptions::dynamic(|d| {
+        let i =
+            d.stack.iter().rposition(|e| matches!(e, Entry::Escape { id: i, .. } if *i == id))?;
+
+        match d.stack[i] {
+            Entry::Escape { frame, address, .. } => {
+                d.value = val;
+                Some(d.transfer(i, Target { frame, address }))
+            }
+            _ => None,
+        }
+    });
+
+    target
+        .unwrap_or_else(|| exceptions::error("continuation invoked outside of its dynamic extent"))
+}
@@ -1,6 +1,7 @@
 //! Core types shared by most of the program
+use crate::{bignum::Big, compiler::Fault, lang::Passes, numbers};
 use colored::Colorize;
-use std::{clone::Clone, fmt};
+use std::{clone::Clone, fmt, hash, ops::Range, sync::Arc};
 
 /// Parameterized Abstract Syntax Tree
 #[derive(Debug, PartialEq, Clone)]
@@ -19,7 +20,7 @@ pub enum Expr<T: Clone> {
 /// Syntax representation of Scheme
 ///
 /// Parser returns a `Syntax` as one of the first steps.
-pub type Syntax = Expr<String>;
+pub type Syntax = Expr<Name>;
 
 /// Intermediate AST
 pub type Core = Expr<Ident>;
@@ -34,6 +35,10 @@ pub enum Literal {
     Nil,
     // 61b number with a 3bit tag
     Number(i64),
+    // Integers too large for a fixnum, only ever built at runtime
+    Bignum(Big),
+    // Boxed IEEE 754 double
+    Float(f64),
     // #t & #f
     Boolean(bool),
     // A unicode char encoded in UTF-8 can take upto 4 bytes and won't fit in a
@@ -45,12 +50,151 @@ pub enum Literal {
     Symbol(String),
 }
 
+/// A name in the [Syntax] of a program
+///
+/// Names compare like the text they are. The parser keeps where it read each
+/// one by address, like a parse error does with the input it gave up at, so
+/// that an error about a name can point at it in whichever source it is in.
+#[derive(Clone, Default)]
+pub struct Name {
+    text: String,
+    /// Address and length of the name in the source it was read from
+    at: Option<(usize, usize)>,
+}
+
+impl Name {
+    /// A name read from a slice of the source
+    pub fn read(text: &str) -> Self {
+        Self { text: text.to_string(), at: Some((text.as_ptr() as usize, text.len())) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The same name spelled differently, where this one was read
+    pub fn rename(&self, text: &str) -> Self {
+        Self { text: text.to_string(), at: self.at }
+    }
+
+    /// Byte offsets of the name in `source`, if it was read from there
+    ///
+    /// ```
+    /// use inc::core::Name;
+    ///
+    /// let source = "(let ((x 1)) x)";
+    /// assert_eq!(Name::read(&source[7..8]).span(source), Some(7..8));
+    /// assert_eq!(Name::read(&source[7..8]).span("x"), None);
+    /// assert_eq!(Name::from("x").span(source), None);
+    /// ```
+    pub fn span(&self, source: &str) -> Option<Range<usize>> {
+        let (at, len) = self.at?;
+        let start = source.as_ptr() as usize;
+
+        if at >= start && at + len <= start + source.len() {
+            Some(at - start..at - start + len)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::ops::Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl std::borrow::Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        &self.text
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Name {}
+
+impl hash::Hash for Name {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.text.hash(state)
+    }
+}
+
+impl PartialEq<str> for Name {
+    fn eq(&self, other: &str) -> bool {
+        self.text == other
+    }
+}
+
+impl PartialEq<&str> for Name {
+    fn eq(&self, other: &&str) -> bool {
+        self.text == *other
+    }
+}
+
+impl From<&str> for Name {
+    fn from(text: &str) -> Self {
+        Self { text: text.to_string(), at: None }
+    }
+}
+
+impl From<String> for Name {
+    fn from(text: String) -> Self {
+        Self { text, at: None }
+    }
+}
+
+impl From<&Name> for String {
+    fn from(name: &Name) -> Self {
+        name.text.clone()
+    }
+}
+
+impl From<Name> for String {
+    fn from(name: Name) -> Self {
+        name.text
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 /// Identifiers with metadata and namespaces
+///
+/// Parts of the name are shared by all the identifiers extending the same one,
+/// cloning an identifier copies only the list of them.
 pub struct Ident {
-    name: Vec<String>,
+    name: Vec<Arc<str>>,
+    /// Is this a top level definition named like a primitive, which it shadows?
+    shadows: bool,
 }
 
+/// Prefix of the labels of functions, see [Ident::mangle]
+const MANGLED: &str = "scm:";
+
 /// Closures are code blocks with their environment captured
 #[derive(Clone, Debug, PartialEq)]
 pub struct Closure<T: Clone> {
@@ -63,11 +207,10 @@ pub struct Closure<T: Clone> {
 
 impl<T: Clone> Expr<T> {
     /// Checks if an expression is in [A-Normal Form](https://en.wikipedia.org/wiki/A-normal_form)
-    pub fn anf(&self) -> bool {
-        match self {
-            Expr::Literal(..) => true,
-            _ => false,
-        }
+    ///
+    /// Variables are atomic too, which keeps the function of `apply` a name.
+    pub const fn anf(&self) -> bool {
+        matches!(self, Expr::Literal(..) | Expr::Identifier(..))
     }
 
     pub fn symbol<S: Into<String>>(name: S) -> Self {
@@ -77,21 +220,62 @@ impl<T: Clone> Expr<T> {
     pub fn string<S: Into<String>>(name: S) -> Self {
         Expr::Literal(Literal::Str(name.into()))
     }
+
+    /// The name and code of a function definition, `(define (f x) ...)`
+    pub fn function(&self) -> Option<(&T, &Closure<T>)> {
+        match self {
+            Expr::Define { name, val } => match val.as_ref() {
+                Expr::Lambda(code) => Some((name, code)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Size of the expression, counting every literal, variable and form
+    pub fn nodes(&self) -> usize {
+        let all = |exprs: &[Expr<T>]| exprs.iter().map(Self::nodes).sum::<usize>();
+
+        1 + match self {
+            Expr::Literal(..) | Expr::Identifier(..) => 0,
+            Expr::List(list) | Expr::Vector(list) => all(list),
+            Expr::Cond { pred, then, alt } => {
+                pred.nodes() + then.nodes() + alt.as_ref().map_or(0, |alt| alt.nodes())
+            }
+            Expr::Let { bindings, body } => {
+                bindings.iter().map(|(_, val)| val.nodes()).sum::<usize>() + all(body)
+            }
+            Expr::Define { val, .. } => val.nodes(),
+            Expr::Lambda(Closure { body, .. }) => all(body),
+        }
+    }
 }
 
-impl Expr<String> {
-    pub fn name<S: Into<String>>(name: S) -> Self {
+impl Expr<Name> {
+    pub fn name<S: Into<Name>>(name: S) -> Self {
         Self::Identifier(name.into())
     }
 }
 
 impl Ident {
     pub const fn empty() -> Self {
-        Self { name: vec![] }
+        Self { name: vec![], shadows: false }
     }
 
     pub fn new<S: Into<String>>(name: S) -> Self {
-        Self { name: name.into().split("::").map(|s| s.into()).collect::<Vec<_>>() }
+        Self { name: name.into().split("::").map(Arc::from).collect::<Vec<_>>(), shadows: false }
+    }
+
+    /// A top level definition that shadows the primitive or the function of
+    /// the runtime of the same name, see [free](Ident::free)
+    ///
+    /// ```
+    /// # use inc::core::Ident;
+    /// assert_eq!(Ident::shadowing("car").free(), None);
+    /// assert_eq!(Ident::shadowing("car").to_string(), "car");
+    /// ```
+    pub fn shadowing<S: Into<String>>(name: S) -> Self {
+        Self { shadows: true, ..Self::new(name) }
     }
 
     /// Create a new identifier extending an existing environment
@@ -101,31 +285,73 @@ impl Ident {
     /// let base = Ident::new("top");
     /// assert_eq!(Ident::new("top::fn"), base.extend("fn"))
     /// ```
-    pub fn extend<S: Into<String>>(&self, s: S) -> Self {
-        let mut name = self.name.clone();
-        name.push(s.into());
-        Self { name }
+    pub fn extend<S: AsRef<str>>(&self, s: S) -> Self {
+        let mut name = Vec::with_capacity(self.name.len() + 1);
+        name.extend_from_slice(&self.name);
+        name.push(Arc::from(s.as_ref()));
+        Self { name, shadows: false }
     }
 
     pub fn expr<S: Into<String>>(name: S) -> Expr<Ident> {
         Expr::Identifier(Ident::new(name))
     }
 
+    /// The name of a free identifier, which refers to a primitive, the runtime
+    /// or a top level definition
+    ///
+    /// Every name the program binds locally is renamed into the scope binding
+    /// it, so only free names are a single part. A binding named like a
+    /// primitive shadows it and calls of it aren't compiled as the primitive,
+    /// top level definitions included; those are marked as [shadowing].
+    ///
+    /// [shadowing]: Ident::shadowing
+    ///
+    /// ```
+    /// # use inc::core::Ident;
+    /// assert_eq!(Ident::new("car").free(), Some("car"));
+    /// assert_eq!(Ident::new("{let 0}::car").free(), None);
+    /// ```
+    pub fn free(&self) -> Option<&str> {
+        match self.name.as_slice() {
+            [name] if !self.shadows => Some(name),
+            _ => None,
+        }
+    }
+
     /// Short names
     pub fn short(&self) -> String {
         self.name.last().unwrap().to_string()
     }
 
-    /// Mangled names for code generation
-    /// TODO: This is obviously wrong
+    /// The label of a function in the generated code
+    ///
+    /// Labels of functions are global, and no C identifier has the prefix they
+    /// start with. A definition can't clash with a function exported by the
+    /// runtime or the C library this way, or with labels of the compiler.
+    ///
+    /// ```
+    /// # use inc::core::Ident;
+    /// assert_eq!(Ident::new("f::{closure 0}").mangle(), "scm:f {closure 0}");
+    /// ```
     pub fn mangle(&self) -> String {
-        self.name.last().unwrap().to_string()
+        format!("{}{}", MANGLED, self)
+    }
+
+    /// The name of the function with a [mangled](Ident::mangle) label
+    ///
+    /// ```
+    /// # use inc::core::Ident;
+    /// assert_eq!(Ident::unmangle("scm:f {closure 0}"), Some("f {closure 0}"));
+    /// assert_eq!(Ident::unmangle("init"), None);
+    /// ```
+    pub fn unmangle(label: &str) -> Option<&str> {
+        label.strip_prefix(MANGLED)
     }
 }
 
 impl fmt::Display for Ident {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut result = self.name.iter().fold(String::new(), |s, arg| s + &arg + " ");
+        let mut result = self.name.iter().fold(String::new(), |s, arg| s + arg + " ");
         result.pop();
 
         write!(f, "{}", result)
@@ -136,6 +362,8 @@ impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Number(n) => write!(f, "{}", n),
+            Self::Bignum(n) => write!(f, "{}", n),
+            Self::Float(n) => write!(f, "{}", numbers::format(*n)),
             Self::Boolean(t) => write!(f, "{}", if *t { "#t" } else { "#f" }),
             Self::Nil => write!(f, "()"),
             Self::Char(c) => {
@@ -149,12 +377,36 @@ impl fmt::Display for Literal {
                 write!(f, "{}", &p)
             }
 
-            Self::Str(s) => write!(f, "\"{}\"", s),
+            Self::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    escape(f, c)?;
+                }
+                write!(f, "\"")
+            }
             Self::Symbol(i) => write!(f, "'{}", i),
         }
     }
 }
 
+/// Write a character of a string the way the parser reads it back
+///
+/// The quote, the backslash and control characters are escaped, which is all
+/// `write` does differently from `display` for strings.
+fn escape(f: &mut fmt::Formatter, c: char) -> fmt::Result {
+    match c {
+        '"' => write!(f, "\\\""),
+        '\\' => write!(f, "\\\\"),
+        '\u{7}' => write!(f, "\\a"),
+        '\u{8}' => write!(f, "\\b"),
+        '\t' => write!(f, "\\t"),
+        '\n' => write!(f, "\\n"),
+        '\r' => write!(f, "\\r"),
+        c if c.is_ascii_control() => write!(f, "\\x{:x};", c as u32),
+        c => write!(f, "{}", c),
+    }
+}
+
 impl<T: Clone + fmt::Display> fmt::Display for Expr<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -192,11 +444,10 @@ impl<T: Clone + fmt::Display> fmt::Display for Expr<T> {
                 Some(t) => write!(f, "(if {} {} {})", pred, then, t),
             },
             Expr::Let { bindings, body } => {
-                write!(f, "(let (")?;
-                bindings.iter().for_each(|(a, b)| write!(f, "({} {})", a, b).unwrap());
-                write!(f, ") ")?;
-                body.iter().for_each(|b| write!(f, "{}", b).unwrap());
-                write!(f, ")")
+                let bindings: Vec<String> =
+                    bindings.iter().map(|(a, b)| format!("({} {})", a, b)).collect();
+
+                write!(f, "(let ({}) {})", bindings.join(" "), join(body))
             }
             Expr::Lambda(Closure { formals, body, tail, .. }) => {
                 if *tail {
@@ -205,21 +456,128 @@ impl<T: Clone + fmt::Display> fmt::Display for Expr<T> {
                     write!(f, "(λ (")?;
                 }
 
-                formals.iter().for_each(|arg| write!(f, "{}", arg).unwrap());
-                write!(f, ") ")?;
-                body.iter().for_each(|b| write!(f, "{}", b).unwrap());
-                write!(f, ")")
+                write!(f, "{}) {})", join(formals), join(body))
             }
             Expr::Define { name, val } => write!(f, "(define {} {})", name, val),
         }
     }
 }
 
+/// Items separated by spaces
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+impl<T: Clone + fmt::Display> Expr<T> {
+    /// The expression laid out over several lines to fit in `width` columns
+    ///
+    /// Expressions that fit on the rest of the line are printed like
+    /// [Display] does, the rest are broken up with the head of a form on the
+    /// first line and everything else indented below it by two spaces.
+    ///
+    /// ```
+    /// # use inc::parser::parse;
+    /// let e = parse("(define (twice x) (let ((y (* x 2))) (display y) y))").unwrap().remove(0);
+    ///
+    /// let expected = "\
+    /// (define twice
+    ///   (λ (x)
+    ///     (let ((y (* x 2)))
+    ///       (display y)
+    ///       y)))";
+    ///
+    /// assert_eq!(e.pretty(30), expected);
+    /// ```
+    pub fn pretty(&self, width: usize) -> String {
+        let mut out = String::new();
+        self.layout(&mut out, 0, width);
+        out
+    }
+
+    fn layout(&self, out: &mut String, indent: usize, width: usize) {
+        let flat = self.to_string();
+
+        if indent + flat.chars().count() <= width {
+            out.push_str(&flat);
+            return;
+        }
+
+        let line = |out: &mut String, indent: usize| {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+        };
+
+        match self {
+            Expr::List(list) if !list.is_empty() => {
+                out.push('(');
+                list[0].layout(out, indent + 1, width);
+                for e in &list[1..] {
+                    line(out, indent + 2);
+                    e.layout(out, indent + 2, width);
+                }
+                out.push(')');
+            }
+            Expr::Vector(list) if !list.is_empty() => {
+                out.push('[');
+                for (i, e) in list.iter().enumerate() {
+                    if i > 0 {
+                        line(out, indent + 1);
+                    }
+                    e.layout(out, indent + 1, width);
+                }
+                out.push(']');
+            }
+            Expr::Cond { pred, then, alt } => {
+                out.push_str("(if ");
+                pred.layout(out, indent + 4, width);
+                for e in std::iter::once(then).chain(alt) {
+                    line(out, indent + 4);
+                    e.layout(out, indent + 4, width);
+                }
+                out.push(')');
+            }
+            Expr::Let { bindings, body } => {
+                out.push_str("(let (");
+                for (i, (name, val)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        line(out, indent + 6);
+                    }
+                    let name = format!("({} ", name);
+                    out.push_str(&name);
+                    val.layout(out, indent + 6 + name.chars().count(), width);
+                    out.push(')');
+                }
+                out.push(')');
+                for e in body {
+                    line(out, indent + 2);
+                    e.layout(out, indent + 2, width);
+                }
+                out.push(')');
+            }
+            Expr::Lambda(Closure { formals, body, tail, .. }) => {
+                let head = if *tail { "^λ^" } else { "λ" };
+                out.push_str(&format!("({} ({})", head, join(formals)));
+                for e in body {
+                    line(out, indent + 2);
+                    e.layout(out, indent + 2, width);
+                }
+                out.push(')');
+            }
+            Expr::Define { name, val } => {
+                out.push_str(&format!("(define {}", name));
+                line(out, indent + 2);
+                val.layout(out, indent + 2, width);
+                out.push(')');
+            }
+            _ => out.push_str(&flat),
+        }
+    }
+}
+
 /// Idiomatic type conversions from the primitive types to Expr
 ///
 /// https://doc.rust-lang.org/rust-by-example/conversion/from_into.html
 /// https://ricardomartins.cc/2016/08/03/convenient_and_idiomatic_conversions_in_rust
-
 impl<T: Clone> From<i64> for Expr<T> {
     fn from(i: i64) -> Self {
         Self::Literal(Literal::Number(i))
@@ -244,6 +602,186 @@ pub struct Config {
     pub program: String,
     /// Name of the generated asm and executable, stdout otherwise
     pub output: String,
+    /// Optional passes of the compiler to run
+    pub passes: Passes,
+    /// Files compiled separately and linked with the program, in order
+    pub units: Vec<Unit>,
+    /// Files loaded before the program, see [load](crate::lang::load)
+    pub load: Vec<String>,
+    /// Compile the [prelude](crate::compiler::prelude) along with the program
+    pub prelude: bool,
+    /// Reuse files compiled by earlier builds, see [cache](crate::cache)
+    pub cache: bool,
+    /// Show the program after every pass of the compiler, see [Trace]
+    pub trace: Option<Trace>,
+    /// Report the time taken by every pass of the compiler, see [Timings]
+    pub timings: Option<Timings>,
+    /// Report how much work the compiler did, see [metrics](crate::metrics)
+    pub verbose: bool,
+    /// Count how many times every expression of the program is evaluated, see
+    /// [coverage](crate::coverage)
+    pub coverage: bool,
+    /// Count how many times every function of the program is called, see
+    /// [profile](crate::profile)
+    pub profile: bool,
+    /// Platform the program is compiled for
+    pub target: Target,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            program: String::new(),
+            output: String::new(),
+            passes: Passes::default(),
+            units: vec![],
+            load: vec![],
+            prelude: true,
+            cache: true,
+            trace: None,
+            timings: None,
+            verbose: false,
+            coverage: false,
+            profile: false,
+            target: Target::host(),
+        }
+    }
+}
+
+/// Where the program is shown after each pass, see [Driver::trace](crate::cli::Driver::trace)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trace {
+    Stdout,
+    /// A file for each pass in the directory, numbered in the order they run
+    Dir(String),
+}
+
+/// How the time taken by each pass is reported, see
+/// [Driver::time_passes](crate::cli::Driver::time_passes)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timings {
+    Table,
+    Json,
+}
+
+/// A platform to compile for, named by a triple like `x86_64-unknown-linux-gnu`
+///
+/// There is only an x86-64 backend and the details of the platform, like the
+/// names of symbols and the system calls, are picked when inc itself is built.
+/// The operating system is then always the one of the host, but on linux the
+/// C library can be musl instead of glibc: `x86_64-unknown-linux-musl` has
+/// the same code linked with `musl-gcc` against a [runtime](Target::runtime)
+/// built for musl. Other triples are parsed to explain why they can't be used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub arch: String,
+    pub vendor: String,
+    pub os: String,
+    pub env: Option<String>,
+}
+
+/// Triple of the platform inc was built for
+#[cfg(target_os = "linux")]
+const HOST: &str = "x86_64-unknown-linux-gnu";
+
+#[cfg(target_os = "macos")]
+const HOST: &str = "x86_64-apple-darwin";
+
+impl Target {
+    /// The platform inc was built for
+    pub fn host() -> Self {
+        Self::triple(HOST).unwrap()
+    }
+
+    /// Parse a target triple, if inc can compile for it
+    pub fn parse(triple: &str) -> Result<Self, String> {
+        let target = Self::triple(triple)?;
+
+        if target.arch != "x86_64" {
+            Err(format!("Unsupported target {}, inc only generates x86-64 for now", target))
+        } else if target.os != Self::host().os {
+            Err(format!("Can't compile for {} on {}, inc only targets the host", target, HOST))
+        } else {
+            match &target.env {
+                Some(env) if target.os != "linux" || !["gnu", "musl"].contains(&env.as_str()) => {
+                    Err(format!(
+                        "Unknown environment `{}` in {}, expected gnu or musl",
+                        env, target
+                    ))
+                }
+                _ => Ok(target),
+            }
+        }
+    }
+
+    /// The C compiler linking programs for the target
+    pub fn cc(&self) -> &'static str {
+        if self.musl() {
+            "musl-gcc"
+        } else {
+            "gcc"
+        }
+    }
+
+    /// The runtime programs for the target are linked with
+    ///
+    /// Cargo builds the runtime for the host into `target/debug`, and for
+    /// musl into a directory named after the triple with `cargo build
+    /// --target x86_64-unknown-linux-musl`.
+    pub fn runtime(&self) -> String {
+        if self.musl() {
+            format!("./target/{}/debug/libinc.a", self)
+        } else {
+            String::from("./target/debug/libinc.a")
+        }
+    }
+
+    /// Is the C library of the target musl?
+    pub fn musl(&self) -> bool {
+        self.env.as_deref() == Some("musl")
+    }
+
+    /// Split a triple into its parts, the vendor can be left out
+    fn triple(triple: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = triple.split('-').collect();
+
+        let (arch, vendor, os, env) = match parts.as_slice() {
+            [arch, os] => (arch, &"unknown", os, None),
+            [arch, vendor, os] => (arch, vendor, os, None),
+            [arch, vendor, os, env] => (arch, vendor, os, Some(env)),
+            _ => return Err(format!("Invalid target `{}`, expected one like {}", triple, HOST)),
+        };
+
+        Ok(Target {
+            arch: arch.to_string(),
+            vendor: vendor.to_string(),
+            os: if *os == "macos" { "darwin" } else { os }.to_string(),
+            env: env.map(|env| env.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.arch, self.vendor, self.os)?;
+
+        match &self.env {
+            Some(env) => write!(f, "-{}", env),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A file of a program compiled to an object of its own
+///
+/// A unit exports all its top level functions to the others and the top level
+/// expressions run before the program, see [unit](crate::compiler::emit::unit).
+#[derive(Debug, Clone, Default)]
+pub struct Unit {
+    /// Name of the unit, unique within a program and safe to use in a label
+    pub name: String,
+    /// Source of the unit
+    pub program: String,
 }
 
 impl Config {
@@ -255,6 +793,16 @@ impl Config {
             format!("{}.s", self.output)
         }
     }
+
+    /// Name of the generated asm for a unit
+    pub fn unit_asm(&self, unit: &Unit) -> String {
+        format!("{}.{}.s", self.output, unit.name)
+    }
+
+    /// Name of the object file of a unit
+    pub fn unit_obj(&self, unit: &Unit) -> String {
+        format!("{}.{}.o", self.output, unit.name)
+    }
 }
 
 /// Custom error type for all of inc
@@ -269,8 +817,31 @@ pub enum Error<'a> {
     Internal { message: String, e: Option<std::io::Error> },
     // Runtime errors in scheme like an undefined variable
     Runtime(String),
+    // A program stopped by an error with its exit status, along with all it
+    // printed. See [Status](crate::exceptions::Status).
+    Exit { status: i32, message: String },
     // Compilation errors in Scheme like missing functions and type errors
     Compilation(String),
+    // Compilation errors about a name, which point at where it was read
+    Located { message: String, name: Name },
+    // Several errors found together, in the order they were found
+    Errors(Vec<Error<'a>>),
+}
+
+impl<'a> Error<'a> {
+    /// A compilation error for each [fault](crate::compiler::Fault) in a program
+    pub fn compilation(mut faults: Vec<Fault>) -> Self {
+        let error = |fault: Fault| match fault.name {
+            Some(name) => Error::Located { message: fault.message, name },
+            None => Error::Compilation(fault.message),
+        };
+
+        if faults.len() == 1 {
+            error(faults.remove(0))
+        } else {
+            Error::Errors(faults.into_iter().map(error).collect())
+        }
+    }
 }
 
 // Implement std::convert::From for Error; from io::Error
@@ -296,10 +867,21 @@ impl<'a> fmt::Display for Error<'a> {
                 writeln!(f, "{}", "Runtime error!".red().bold())?;
                 writeln!(f, "{}", e)
             }
-            Self::Compilation(e) => {
+            Self::Exit { status, message } => {
+                writeln!(f, "{}", format!("Exited with status {}", status).red().bold())?;
+                writeln!(f, "{}", message)
+            }
+            Self::Compilation(e) | Self::Located { message: e, .. } => {
                 writeln!(f, "{}\n", "Failed to compile program".red().bold())?;
                 writeln!(f, "{:?}", e)
             }
+            Self::Errors(errors) => {
+                for e in errors {
+                    writeln!(f, "{}", e)?;
+                }
+
+                writeln!(f, "{}", format!("{} errors", errors.len()).red().bold())
+            }
         }
     }
 }
@@ -0,0 +1,524 @@
+//! Which parts of a program its runs evaluate, and how often
+//!
+//! `inc build --coverage` [instruments](instrument) the program with a counter
+//! for every top level expression, every expression in the body of a function
+//! or a `let`, and both branches of every `if`. Each counter is a word in the
+//! data section of the executable, bumped right before the expression it
+//! counts is evaluated, next to the span of the expression in the source.
+//!
+//! An executable with counters writes them out when it exits, as JSON:
+//!
+//! ```json
+//! {"version":1,"counters":[[0,26,1],[13,25,3],...]}
+//! ```
+//!
+//! Each counter is the start and the end of the expression in bytes from the
+//! start of the program, followed by the times it was evaluated. They are
+//! written to the path in `INC_COVERAGE`, or next to the executable with a
+//! `.cov` extension. `inc cov report` shows the source with the counts, see
+//! [Coverage::report].
+//!
+//! Only the program itself is instrumented, not the prelude, the files it
+//! loads or the units linked with it. The counters live in memory that the
+//! [jit](crate::jit) can't write to, so programs with them have to be built.
+use crate::{
+    core::{Expr, Literal, Syntax},
+    ffi,
+    json::Json,
+    lsp::{self, Form},
+    x86::{self, Directive, Ins, Register::*, Relative, ASM, WORDSIZE},
+};
+use std::{collections::BTreeMap, fmt, ops::Range};
+
+/// The primitive bumping a counter, `(%coverage index start end)`
+pub const HIT: &str = "%coverage";
+
+/// Label of the counters in the data section, see [table]
+pub const LABEL: &str = "inc_coverage";
+
+/// Version of the counters written by a program, bumped whenever they change
+pub const VERSION: i64 = 1;
+
+/// Forms with syntax of their own, which aren't instrumented inside
+const SPECIAL: [&str; 7] =
+    ["begin", "define-library", "foreign-procedure", "guard", "import", "load", "time"];
+
+/// The counters written by a run of a program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    pub counters: Vec<Counter>,
+}
+
+/// An expression of the program and the times it was evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counter {
+    pub span: Range<usize>,
+    pub hits: u64,
+}
+
+/// Add the counters to a program parsed from `source`
+///
+/// Counters are numbered in the order the expressions appear in the source.
+/// Forms the parser read differently than [lsp::read], like vectors, are left
+/// as they are.
+pub fn instrument(prog: Vec<Syntax>, source: &str) -> Vec<Syntax> {
+    let forms = lsp::read(source);
+
+    if forms.len() != prog.len() {
+        return prog;
+    }
+
+    let mut counters = Instrument { next: 0 };
+    prog.into_iter().zip(&forms).flat_map(|(expr, form)| counters.top(expr, form)).collect()
+}
+
+/// The spans of the counters in a program, by index
+pub fn counters(prog: &[Syntax]) -> Vec<Range<usize>> {
+    fn walk(expr: &Syntax, out: &mut BTreeMap<usize, Range<usize>>) {
+        let all = |exprs: &[Syntax], out: &mut BTreeMap<usize, Range<usize>>| {
+            exprs.iter().for_each(|e| walk(e, out))
+        };
+
+        match expr {
+            Expr::List(list) => match list.as_slice() {
+                [Expr::Identifier(hit), index, start, end] if hit == HIT => {
+                    if let (Some(index), Some(start), Some(end)) =
+                        (number(index), number(start), number(end))
+                    {
+                        out.insert(index, start..end);
+                    }
+                }
+                list => all(list, out),
+            },
+            Expr::Vector(list) => all(list, out),
+            Expr::Cond { pred, then, alt } => {
+                walk(pred, out);
+                walk(then, out);
+                alt.iter().for_each(|alt| walk(alt, out));
+            }
+            Expr::Let { bindings, body } => {
+                bindings.iter().for_each(|(_, val)| walk(val, out));
+                all(body, out);
+            }
+            Expr::Define { val, .. } => walk(val, out),
+            Expr::Lambda(code) => all(&code.body, out),
+            Expr::Literal(_) | Expr::Identifier(_) => {}
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    prog.iter().for_each(|e| walk(e, &mut out));
+    out.into_values().collect()
+}
+
+/// Bump a counter
+///
+/// RAX is left pointing at the counter, which is aligned and reads as a
+/// fixnum if the value is ever used.
+pub fn hit(index: i64) -> ASM {
+    let offset = WORDSIZE * (1 + 3 * index + 2);
+
+    x86::lea(RAX, &ffi::symbol(LABEL), offset)
+        + x86::add(Relative { register: RAX, offset: 0 }.into(), 1.into())
+}
+
+/// The counters of a program in the data section
+///
+/// The table is the number of counters followed by three words for each, the
+/// start and the end of the span and the count. It is exported for the
+/// runtime to find, see [install].
+pub fn table(counters: &[Range<usize>]) -> ASM {
+    if counters.is_empty() {
+        return ASM::default();
+    }
+
+    let label = ffi::symbol(LABEL);
+    let mut asm = Ins::Blank
+        + Ins::Directive(Directive::Data)
+        + Ins::Directive(Directive::Align(3))
+        + Ins::Directive(Directive::Global(label.clone()))
+        + x86::label(&label)
+        + Ins::Directive(Directive::Quad(counters.len() as i64));
+
+    for span in counters {
+        asm += Ins::Directive(Directive::Quad(span.start as i64));
+        asm += Ins::Directive(Directive::Quad(span.end as i64));
+        asm += Ins::Directive(Directive::Quad(0));
+    }
+
+    asm + Ins::Directive(Directive::Text)
+}
+
+/// Write the counters of the program out when it exits, if it has any
+///
+/// # Safety
+///
+/// Must be called once, from the [start](crate::start) of a program.
+#[cfg(feature = "native")]
+pub unsafe fn install() {
+    let name = std::ffi::CString::new(LABEL).unwrap();
+    let table = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+
+    if !table.is_null() {
+        TABLE.store(table as usize, std::sync::atomic::Ordering::SeqCst);
+        libc::atexit(dump);
+    }
+}
+
+/// Address of the counters of the running program
+#[cfg(feature = "native")]
+static TABLE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(feature = "native")]
+extern "C" fn dump() {
+    let table = TABLE.load(std::sync::atomic::Ordering::SeqCst) as *const i64;
+
+    let coverage = unsafe {
+        let words = std::slice::from_raw_parts(table.add(1), *table as usize * 3);
+        let counters = words.chunks(3).map(|counter| Counter {
+            span: counter[0] as usize..counter[1] as usize,
+            hits: counter[2] as u64,
+        });
+
+        Coverage { counters: counters.collect() }
+    };
+
+    let path = std::env::var("INC_COVERAGE").ok().or_else(|| {
+        let exe = std::env::current_exe().ok()?;
+        Some(format!("{}.cov", exe.display()))
+    });
+
+    if let Some(path) = path {
+        if let Err(e) = std::fs::write(&path, coverage.to_string()) {
+            eprintln!("Failed to write coverage to {}: {}", path, e);
+        }
+    }
+}
+
+impl Coverage {
+    /// Read the counters written by a program
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json = Json::parse(text)?;
+
+        match json.get("version").and_then(Json::as_i64) {
+            Some(VERSION) => {}
+            _ => return Err(format!("Expected coverage of version {}", VERSION)),
+        }
+
+        let counters = json.get("counters").and_then(Json::as_array).unwrap_or_default();
+
+        counters
+            .iter()
+            .map(|counter| {
+                let numbers: Option<Vec<i64>> =
+                    counter.as_array().and_then(|n| n.iter().map(Json::as_i64).collect());
+
+                match numbers.as_deref() {
+                    Some([start, end, hits]) => {
+                        Ok(Counter { span: *start as usize..*end as usize, hits: *hits as u64 })
+                    }
+                    _ => Err(format!("Invalid counter: {}", counter)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(|counters| Coverage { counters })
+    }
+
+    /// The source annotated with the counts, followed by a summary
+    ///
+    /// Each line shows the count of the first expression starting on it, and
+    /// lines without any are left blank. The expressions never evaluated are
+    /// listed after the summary, leaving out the ones inside another.
+    pub fn report(&self, source: &str) -> String {
+        let mut counters: Vec<&Counter> = self.counters.iter().collect();
+        counters.sort_by_key(|c| c.span.start);
+
+        let mut missed: Vec<&Range<usize>> = vec![];
+        for c in counters.iter().filter(|c| c.hits == 0) {
+            if missed.last().map_or(true, |last| c.span.start >= last.end) {
+                missed.push(&c.span)
+            }
+        }
+
+        let mut out = String::new();
+        let mut counters = counters.into_iter().peekable();
+        let mut offset = 0;
+
+        for line in source.lines() {
+            let end = offset + line.len();
+            let mut count = None;
+
+            while let Some(counter) = counters.peek() {
+                if counter.span.start > end {
+                    break;
+                }
+                count = count.or(Some(counter.hits));
+                counters.next();
+            }
+
+            let count = count.map_or(String::new(), |n| n.to_string());
+            out += &format!("{:>8} | {}\n", count, line);
+            offset = end + 1;
+        }
+
+        let run = self.counters.iter().filter(|c| c.hits > 0).count();
+        let total = self.counters.len();
+        let percent = if total == 0 { 100.0 } else { run as f64 * 100.0 / total as f64 };
+
+        out += &format!("\n{} of {} expressions evaluated ({:.1}%)", run, total, percent);
+
+        for span in missed {
+            let before = source.get(..span.start).unwrap_or_default();
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |n| n + 1) + 1;
+            let text = source.get(span.clone()).and_then(|s| s.lines().next()).unwrap_or_default();
+
+            out += &format!("\n{:>8} | {}:{} {}", 0, line, column, text);
+        }
+
+        out
+    }
+}
+
+/// Compact JSON, see the module docs
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counters = self
+            .counters
+            .iter()
+            .map(|c| {
+                Json::from(vec![c.span.start.into(), c.span.end.into(), (c.hits as i64).into()])
+            })
+            .collect::<Vec<_>>();
+
+        let json = Json::object(vec![("version", VERSION.into()), ("counters", counters.into())]);
+        write!(f, "{}", json)
+    }
+}
+
+/// Numbers the counters as they are added
+struct Instrument {
+    next: i64,
+}
+
+impl Instrument {
+    /// A new counter for an expression
+    fn hit(&mut self, form: &Form) -> Syntax {
+        let span = form.span();
+        let args = [self.next, span.start as i64, span.end as i64];
+        self.next += 1;
+
+        let args = args.iter().map(|n| Expr::Literal(Literal::Number(*n)));
+        Expr::List(std::iter::once(Expr::Identifier(HIT.into())).chain(args).collect())
+    }
+
+    /// A top level form, counted unless it defines a function or a library
+    fn top(&mut self, expr: Syntax, form: &Form) -> Vec<Syntax> {
+        match (expr, form) {
+            (Expr::List(list), Form::List(items, _)) if special(&list) => match list.first() {
+                Some(Expr::Identifier(head)) if head == "define-library" => {
+                    vec![Expr::List(self.library(list, items))]
+                }
+                _ => vec![Expr::List(list)],
+            },
+            (expr, form) if expr.function().is_some() => vec![self.expr(expr, form)],
+            (expr, form) => vec![self.hit(form), self.expr(expr, form)],
+        }
+    }
+
+    /// The `begin` declarations of a library, like the top level
+    fn library(&mut self, list: Vec<Syntax>, items: &[Form]) -> Vec<Syntax> {
+        if list.len() != items.len() {
+            return list;
+        }
+
+        list.into_iter()
+            .zip(items)
+            .map(|(decl, form)| match (decl, form) {
+                (Expr::List(body), Form::List(forms, _))
+                    if head(&body) == Some("begin") && body.len() == forms.len() =>
+                {
+                    let mut out = vec![body[0].clone()];
+                    for (expr, form) in body.into_iter().zip(forms).skip(1) {
+                        out.extend(self.top(expr, form));
+                    }
+                    Expr::List(out)
+                }
+                (decl, _) => decl,
+            })
+            .collect()
+    }
+
+    /// The expressions in an expression, but not the expression itself
+    fn expr(&mut self, expr: Syntax, form: &Form) -> Syntax {
+        let items = match form {
+            Form::List(items, _) => items,
+            Form::Atom(..) => return expr,
+        };
+
+        match expr {
+            Expr::Define { name, val } if items.len() >= 3 => match (*val, &items[1]) {
+                // (define (f x) body ...)
+                (Expr::Lambda(mut code), Form::List(..)) => {
+                    code.body = self.body(code.body, &items[2..]);
+                    Expr::Define { name, val: Box::new(Expr::Lambda(code)) }
+                }
+                (val, _) => Expr::Define { name, val: Box::new(self.expr(val, &items[2])) },
+            },
+
+            Expr::Lambda(mut code) if items.len() >= 3 => {
+                code.body = self.body(code.body, &items[2..]);
+                Expr::Lambda(code)
+            }
+
+            Expr::Let { bindings, body } if items.len() >= 3 => {
+                let bindings = match &items[1] {
+                    Form::List(forms, _) if forms.len() == bindings.len() => bindings
+                        .into_iter()
+                        .zip(forms)
+                        .map(|(binding, form)| match form {
+                            Form::List(pair, _) if pair.len() == 2 => {
+                                (binding.0, self.expr(binding.1, &pair[1]))
+                            }
+                            _ => binding,
+                        })
+                        .collect(),
+                    _ => bindings,
+                };
+
+                Expr::Let { bindings, body: self.body(body, &items[2..]) }
+            }
+
+            Expr::Cond { pred, then, alt } if items.len() == 3 + alt.is_some() as usize => {
+                Expr::Cond {
+                    pred: Box::new(self.expr(*pred, &items[1])),
+                    then: Box::new(self.branch(*then, &items[2])),
+                    alt: alt.map(|alt| Box::new(self.branch(*alt, &items[3]))),
+                }
+            }
+
+            Expr::List(list) if list.len() == items.len() && !special(&list) => Expr::List(
+                list.into_iter().zip(items).map(|(expr, form)| self.expr(expr, form)).collect(),
+            ),
+
+            expr => expr,
+        }
+    }
+
+    /// A body with every expression in it counted
+    fn body(&mut self, body: Vec<Syntax>, forms: &[Form]) -> Vec<Syntax> {
+        if body.len() != forms.len() {
+            return body;
+        }
+
+        body.into_iter()
+            .zip(forms)
+            .flat_map(|(expr, form)| vec![self.hit(form), self.expr(expr, form)])
+            .collect()
+    }
+
+    /// A branch of a conditional, counted
+    fn branch(&mut self, expr: Syntax, form: &Form) -> Syntax {
+        Expr::Let { bindings: vec![], body: vec![self.hit(form), self.expr(expr, form)] }
+    }
+}
+
+/// The value of a number literal
+const fn number(expr: &Syntax) -> Option<usize> {
+    match expr {
+        Expr::Literal(Literal::Number(n)) => Some(*n as usize),
+        _ => None,
+    }
+}
+
+/// The name at the head of a list
+fn head(list: &[Syntax]) -> Option<&str> {
+    match list.first() {
+        Some(Expr::Identifier(head)) => Some(head),
+        _ => None,
+    }
+}
+
+/// Is the list a form with syntax of its own?
+fn special(list: &[Syntax]) -> bool {
+    head(list).map_or(false, |head| SPECIAL.contains(&head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    const SOURCE: &str = "(define (f x)
+  (if x
+      1
+      2))
+
+(f #t)";
+
+    fn instrumented(source: &str) -> Vec<Syntax> {
+        instrument(parser::parse(source).unwrap(), source)
+    }
+
+    #[test]
+    fn counted() {
+        let prog = instrumented(SOURCE);
+        let spans: Vec<&str> = counters(&prog).into_iter().map(|span| &SOURCE[span]).collect();
+
+        assert_eq!(spans, vec!["(if x\n      1\n      2)", "1", "2", "(f #t)"]);
+        assert_eq!(prog[1].to_string(), "(%coverage 3 41 47)");
+    }
+
+    #[test]
+    fn libraries() {
+        let source = "(define-library (lib)
+                        (export g)
+                        (begin (define (g) (display 1)) (g)))
+                      (load \"x.ss\")";
+
+        let spans: Vec<&str> =
+            counters(&instrumented(source)).into_iter().map(|span| &source[span]).collect();
+
+        assert_eq!(spans, vec!["(display 1)", "(g)"]);
+    }
+
+    #[test]
+    fn hits() {
+        let asm = hit(2).to_string();
+
+        assert!(asm.contains(&format!("lea rax, [rip + 72 + \"{}\"]", ffi::symbol(LABEL))));
+        assert!(asm.contains("add qword ptr [rax], 1"));
+        assert_eq!(table(&[]).0, vec![]);
+    }
+
+    #[test]
+    fn report() {
+        let coverage = Coverage {
+            counters: vec![
+                Counter { span: 16..38, hits: 3 },
+                Counter { span: 28..29, hits: 3 },
+                Counter { span: 36..37, hits: 0 },
+                Counter { span: 41..47, hits: 1 },
+            ],
+        };
+
+        let report = coverage.report(SOURCE);
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(lines[0], "         | (define (f x)");
+        assert_eq!(lines[1], "       3 |   (if x");
+        assert_eq!(lines[3], "       0 |       2))");
+        assert_eq!(lines[5], "       1 | (f #t)");
+        assert!(report.ends_with("3 of 4 expressions evaluated (75.0%)\n       0 | 4:7 2"));
+    }
+
+    #[test]
+    fn json() {
+        let coverage = Coverage { counters: vec![Counter { span: 3..8, hits: 2 }] };
+
+        assert_eq!(coverage.to_string(), r#"{"version":1,"counters":[[3,8,2]]}"#);
+        assert_eq!(Coverage::parse(&coverage.to_string()), Ok(coverage));
+        assert!(Coverage::parse(r#"{"version":0,"counters":[]}"#).is_err());
+        assert!(Coverage::parse(r#"{"version":1,"counters":[[1,2]]}"#).is_err());
+    }
+}
@@ -0,0 +1,720 @@
+//! A debugger for editors
+//!
+//! `inc dap` speaks the [Debug Adapter Protocol] on stdin and stdout. It
+//! launches a program, stops it at breakpoints set by line, steps through it
+//! and shows the arguments of the functions it is in, decoded from the tagged
+//! words on the stack like any other [Value].
+//!
+//! The compiler doesn't emit any debug info, so the program is
+//! [instrumented](instrument) instead: the body of every top level function
+//! starts with a call to a hook and ends with another, and each top level
+//! expression is preceded by one. The hooks are [callbacks] into the debugger,
+//! which waits right there while the program is stopped. The program is
+//! compiled and run in memory with the [jit], on a thread of its own.
+//!
+//! Everything happens at the granularity of the hooks. A breakpoint on any
+//! line of a function stops when the function is called, and one in a top
+//! level expression before it is evaluated. Stepping over runs until the next
+//! hook in the same function or one of its callers, stepping in until the next
+//! hook and stepping out until the function returns. The hook at the end of a
+//! function takes the place of its tail call, so every call has a frame of its
+//! own while debugging.
+//!
+//! Anything the program writes to stdout is sent to the editor as output,
+//! since stdout carries the protocol. There can be only one program debugged
+//! in a process at a time.
+//!
+//! [Debug Adapter Protocol]: https://microsoft.github.io/debug-adapter-protocol
+
+use crate::{
+    builder, callbacks, compiler,
+    core::{Error, Expr, Literal, Name, Syntax},
+    json::Json,
+    lang, lsp, parser,
+    value::Value,
+};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+    os::unix::io::FromRawFd,
+    sync::{
+        mpsc::{self, Sender},
+        Condvar, Mutex,
+    },
+    thread,
+};
+
+/// Name of the hook called when a function is entered or before a top level
+/// expression
+pub const ENTER: &str = "%debug-enter";
+
+/// Name of the hook called when a function returns
+pub const LEAVE: &str = "%debug-leave";
+
+/// Name of the bottom frame, where the top level expressions run
+const TOP: &str = "<top level>";
+
+/// Id of the only thread of a program
+const THREAD: i64 = 1;
+
+/// A function the program is in, or the top level
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub name: String,
+    /// Line of the function or the expression, starting from 1
+    pub line: usize,
+    /// The arguments of the function by name
+    pub variables: Vec<(String, Value)>,
+}
+
+/// How the program goes on after a hook
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Run {
+    /// Till a breakpoint
+    Continue,
+    /// Stop at the next hook, for stepping in or pausing
+    Stop(&'static str),
+    /// Stop at the next hook with at most this many frames
+    Over(usize),
+    /// Stop at the next hook with fewer than this many frames
+    Out(usize),
+}
+
+/// The state shared by the program and the server
+struct Session {
+    breakpoints: Vec<usize>,
+    frames: Vec<Frame>,
+    run: Run,
+    stopped: bool,
+    terminated: bool,
+    events: Option<Sender<Json>>,
+}
+
+static SESSION: Mutex<Session> = Mutex::new(Session {
+    breakpoints: vec![],
+    frames: vec![],
+    run: Run::Continue,
+    stopped: false,
+    terminated: false,
+    events: None,
+});
+
+/// Signalled when a stopped program may go on
+static RESUMED: Condvar = Condvar::new();
+
+/// Add the hooks to a program, with the lines the top level forms start at
+///
+/// The lines are in the same order as the forms, see [lines].
+pub fn instrument(prog: Vec<Syntax>, lines: &[usize]) -> Vec<Syntax> {
+    let call = |name: &str, args: Vec<Syntax>| {
+        Expr::List(std::iter::once(Expr::Identifier(name.into())).chain(args).collect())
+    };
+
+    prog.into_iter()
+        .zip(lines.iter().copied().chain(std::iter::repeat(0)))
+        .flat_map(|(form, line)| match form {
+            Expr::Define { name, val } => {
+                let mut closure = match *val {
+                    Expr::Lambda(closure) => closure,
+                    val => return vec![Expr::Define { name, val: Box::new(val) }],
+                };
+
+                let mut args = vec![
+                    Expr::Literal(Literal::Symbol(String::from(&name))),
+                    Expr::Literal(Literal::Number(line as i64)),
+                    Expr::Literal(Literal::Str(closure.formals.join(" "))),
+                ];
+                args.extend(closure.formals.iter().cloned().map(Expr::Identifier));
+
+                let body = match closure.body.len() {
+                    1 => closure.body.pop().unwrap(),
+                    _ => Expr::Let { bindings: vec![], body: closure.body },
+                };
+                let result = Name::from("%debug-result");
+
+                closure.body = vec![
+                    call(ENTER, args),
+                    Expr::Let {
+                        bindings: vec![(result.clone(), body)],
+                        body: vec![call(LEAVE, vec![]), Expr::Identifier(result)],
+                    },
+                ];
+
+                vec![Expr::Define { name, val: Box::new(Expr::Lambda(closure)) }]
+            }
+            expr => {
+                let args = vec![
+                    Expr::Literal(Literal::Boolean(false)),
+                    Expr::Literal(Literal::Number(line as i64)),
+                    Expr::Literal(Literal::Str(String::new())),
+                ];
+
+                vec![call(ENTER, args), expr]
+            }
+        })
+        .collect()
+}
+
+/// Lines of the top level forms of a source and the lines they span
+pub fn lines(source: &str) -> Vec<Range<usize>> {
+    let line = |offset: usize| source[..offset].matches('\n').count() + 1;
+
+    lsp::read(source)
+        .iter()
+        .map(|form| {
+            let span = form.span();
+            line(span.start)..line(span.end) + 1
+        })
+        .collect()
+}
+
+/// The hook called when a function is entered or before a top level
+/// expression, with the name of the function or `#f`, the line, the names of
+/// the arguments and their values
+fn enter(args: &[Value]) -> Result<Value, Error<'static>> {
+    let (name, line, names, values) = match args {
+        [name, line, names, values @ ..] => (name, line, names, values),
+        _ => return Err(Error::Runtime(String::from("Expected a function and a line"))),
+    };
+    let line = i64::try_from(line)? as usize;
+
+    let mut session = SESSION.lock().unwrap();
+
+    if session.frames.is_empty() {
+        session.frames.push(Frame { name: TOP.to_string(), line, variables: vec![] });
+    }
+
+    match name {
+        Value::Bool(false) => session.frames[0].line = line,
+        name => {
+            let name = String::try_from(name)?;
+            let names = String::try_from(names)?;
+            let variables = names.split_whitespace().map(String::from).zip(values.iter().cloned());
+            session.frames.push(Frame { name, line, variables: variables.collect() });
+        }
+    }
+
+    let depth = session.frames.len();
+    let reason = match session.run {
+        Run::Stop(reason) => Some(reason),
+        Run::Over(frames) if depth <= frames => Some("step"),
+        Run::Out(frames) if depth < frames => Some("step"),
+        _ if session.breakpoints.contains(&line) => Some("breakpoint"),
+        _ => None,
+    };
+
+    if let Some(reason) = reason {
+        session.stopped = true;
+        session.run = Run::Continue;
+
+        let body = Json::object(vec![("reason", reason.into()), ("threadId", THREAD.into())]);
+        if let Some(events) = &session.events {
+            events.send(event("stopped", body)).ok();
+        }
+
+        while session.stopped && !session.terminated {
+            session = RESUMED.wait(session).unwrap();
+        }
+    }
+
+    if session.terminated {
+        return Err(Error::Runtime(String::from("terminated by the debugger")));
+    }
+
+    Ok(Value::Nil)
+}
+
+/// The hook called when a function returns
+fn leave(_: &[Value]) -> Result<Value, Error<'static>> {
+    let mut session = SESSION.lock().unwrap();
+
+    if session.frames.len() > 1 {
+        session.frames.pop();
+    }
+
+    // Stepping out of a function stops right after it returns
+    if let Run::Out(frames) = session.run {
+        if session.frames.len() < frames {
+            session.run = Run::Stop("step");
+        }
+    }
+
+    Ok(Value::Nil)
+}
+
+/// A debugging session with an editor
+///
+/// Responses and events are sent to a channel as they are ready, without the
+/// `seq` numbers, which are added as they are written out; see [serve].
+pub struct Server {
+    messages: Sender<Json>,
+    path: String,
+    source: String,
+    program: Option<Vec<Syntax>>,
+}
+
+impl Server {
+    pub fn new(messages: Sender<Json>) -> Self {
+        callbacks::register(ENTER, enter);
+        callbacks::register(LEAVE, leave);
+
+        let mut session = SESSION.lock().unwrap();
+        *session = Session {
+            breakpoints: vec![],
+            frames: vec![],
+            run: Run::Continue,
+            stopped: false,
+            terminated: false,
+            events: Some(messages.clone()),
+        };
+
+        Server { messages, path: String::new(), source: String::new(), program: None }
+    }
+
+    /// Answer a request from the editor
+    ///
+    /// Returns false once the editor disconnects.
+    pub fn handle(&mut self, request: &Json) -> bool {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default();
+        let args = request.get("arguments").unwrap_or(&Json::Null);
+
+        let body = match command {
+            "initialize" => {
+                self.respond(request, Ok(capabilities()));
+                self.messages.send(event("initialized", Json::Null)).ok();
+                return true;
+            }
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.breakpoints(args)),
+            "configurationDone" => {
+                self.start();
+                Ok(Json::Null)
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", THREAD.into()), ("name", "main".into())]);
+                Ok(Json::object(vec![("threads", vec![thread].into())]))
+            }
+            "stackTrace" => Ok(self.stack()),
+            "scopes" => {
+                let frame = args.get("frameId").and_then(Json::as_i64).unwrap_or_default();
+                let scope = Json::object(vec![
+                    ("name", "Arguments".into()),
+                    ("variablesReference", frame.into()),
+                    ("expensive", false.into()),
+                ]);
+                Ok(Json::object(vec![("scopes", vec![scope].into())]))
+            }
+            "variables" => {
+                let frame = args.get("variablesReference").and_then(Json::as_i64);
+                Ok(variables(frame.unwrap_or_default() as usize))
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let run = match command {
+                    "continue" => Run::Continue,
+                    "next" => Run::Over(depth()),
+                    "stepIn" => Run::Stop("step"),
+                    _ => Run::Out(depth()),
+                };
+
+                // The editor is answered before the program goes on, which
+                // may stop it again right away
+                self.respond(request, Ok(Json::object(vec![("allThreadsContinued", true.into())])));
+                resume(run);
+                return true;
+            }
+            "pause" => {
+                SESSION.lock().unwrap().run = Run::Stop("pause");
+                Ok(Json::Null)
+            }
+            "disconnect" | "terminate" => {
+                let mut session = SESSION.lock().unwrap();
+                session.terminated = true;
+                RESUMED.notify_all();
+                drop(session);
+
+                self.respond(request, Ok(Json::Null));
+                return command == "terminate";
+            }
+            command => Err(format!("Unknown command `{}`", command)),
+        };
+
+        self.respond(request, body);
+        true
+    }
+
+    /// Read and instrument the program, which starts once the editor is done
+    /// setting breakpoints
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("program").and_then(Json::as_str);
+        let path = path.ok_or_else(|| String::from("Expected the path of the `program`"))?;
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+        let starts: Vec<usize> = lines(&source).into_iter().map(|lines| lines.start).collect();
+        let prog = parser::parse(&source).map_err(|e| e.to_string())?;
+        let prog = lang::load(instrument(prog, &starts)).map_err(compiler::messages)?;
+
+        if args.get("stopOnEntry").and_then(Json::as_bool) == Some(true) {
+            SESSION.lock().unwrap().run = Run::Stop("entry");
+        }
+
+        self.path = path.to_string();
+        self.source = source;
+        self.program = Some(prog);
+        Ok(Json::Null)
+    }
+
+    /// Set the breakpoints of the program, each on the first line of the
+    /// form it is in
+    fn breakpoints(&mut self, args: &Json) -> Json {
+        let forms = lines(&self.source);
+        let requested = args.get("breakpoints").and_then(Json::as_array).unwrap_or_default();
+
+        let lines: Vec<Option<usize>> = requested
+            .iter()
+            .map(|bp| {
+                let line = bp.get("line").and_then(Json::as_i64)? as usize;
+                forms.iter().find(|lines| lines.contains(&line)).map(|lines| lines.start)
+            })
+            .collect();
+
+        SESSION.lock().unwrap().breakpoints = lines.iter().flatten().copied().collect();
+
+        let breakpoints: Vec<Json> = lines
+            .iter()
+            .map(|line| match line {
+                Some(line) => Json::object(vec![("verified", true.into()), ("line", (*line).into())]),
+                None => Json::object(vec![("verified", false.into())]),
+            })
+            .collect();
+
+        Json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    /// Run the program on a thread of its own, till it ends
+    fn start(&mut self) {
+        let prog = match self.program.take() {
+            Some(prog) => prog,
+            None => return,
+        };
+        let messages = self.messages.clone();
+
+        thread::spawn(move || {
+            let (category, output, status) = match builder::jit(prog) {
+                Ok(value) => ("stdout", format!("{}\n", value), 0),
+                Err(e) => ("stderr", format!("{}\n", e), 1),
+            };
+
+            let output = Json::object(vec![("category", category.into()), ("output", output.into())]);
+            messages.send(event("output", output)).ok();
+            messages.send(event("exited", Json::object(vec![("exitCode", status.into())]))).ok();
+            messages.send(event("terminated", Json::Null)).ok();
+        });
+    }
+
+    /// The frames of the stopped program, innermost first
+    fn stack(&self) -> Json {
+        let session = SESSION.lock().unwrap();
+        let frames = if session.stopped { &session.frames[..] } else { &[] };
+
+        let source = Json::object(vec![
+            ("name", self.path.rsplit('/').next().unwrap_or_default().into()),
+            ("path", self.path.as_str().into()),
+        ]);
+
+        let frames: Vec<Json> = frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                Json::object(vec![
+                    ("id", (i + 1).into()),
+                    ("name", frame.name.as_str().into()),
+                    ("source", source.clone()),
+                    ("line", frame.line.into()),
+                    ("column", 1.into()),
+                ])
+            })
+            .collect();
+
+        Json::object(vec![("stackFrames", frames.into())])
+    }
+
+    fn respond(&self, request: &Json, body: Result<Json, String>) {
+        let mut response = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+
+        match body {
+            Ok(body) => {
+                response.push(("success", true.into()));
+                response.push(("body", body));
+            }
+            Err(message) => {
+                response.push(("success", false.into()));
+                response.push(("message", message.into()));
+            }
+        }
+
+        self.messages.send(Json::object(response)).ok();
+    }
+}
+
+/// Debug an editor on stdin and stdout until it disconnects
+///
+/// The output of the program is read from a pipe taking the place of stdout
+/// and sent as events, on the real stdout like everything else.
+pub fn serve() -> i32 {
+    let (protocol, output) = match redirect() {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("inc dap: {}", e);
+            return 1;
+        }
+    };
+
+    let (messages, received) = mpsc::channel();
+    let mut server = Server::new(messages.clone());
+
+    thread::spawn(move || {
+        let mut output = output;
+        let mut buffer = [0; 4096];
+
+        while let Ok(n @ 1..) = output.read(&mut buffer) {
+            let text = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let body = Json::object(vec![("category", "stdout".into()), ("output", text.into())]);
+            messages.send(event("output", body)).ok();
+        }
+    });
+
+    let writer = thread::spawn(move || {
+        let mut protocol = protocol;
+
+        for (seq, message) in received.iter().enumerate() {
+            let message = match message {
+                Json::Object(mut pairs) => {
+                    pairs.insert(0, (String::from("seq"), (seq + 1).into()));
+                    Json::Object(pairs)
+                }
+                message => message,
+            };
+
+            if lsp::write_message(&mut protocol, &message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    let status = loop {
+        match lsp::read_message(&mut input) {
+            Ok(Some(message)) => match Json::parse(&message) {
+                Ok(request) if server.handle(&request) => {}
+                Ok(_) => break 0,
+                Err(e) => eprintln!("inc dap: {}", e),
+            },
+            Ok(None) => break 0,
+            Err(e) => {
+                eprintln!("inc dap: {}", e);
+                break 1;
+            }
+        }
+    };
+
+    // Let the last responses out before the process ends
+    drop(server);
+    SESSION.lock().unwrap().events = None;
+    writer.join().ok();
+    status
+}
+
+/// Move stdout out of the way of the program, returning a file for the
+/// protocol and the reading end of a pipe for what the program writes
+fn redirect() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+
+    unsafe {
+        let protocol = libc::dup(libc::STDOUT_FILENO);
+        if protocol < 0 || libc::pipe(fds.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(fds[1], libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::close(fds[1]);
+
+        Ok((File::from_raw_fd(protocol), File::from_raw_fd(fds[0])))
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+fn event(name: &str, body: Json) -> Json {
+    Json::object(vec![("type", "event".into()), ("event", name.into()), ("body", body)])
+}
+
+/// Number of frames of the program
+fn depth() -> usize {
+    SESSION.lock().unwrap().frames.len()
+}
+
+/// Let a stopped program go on
+fn resume(run: Run) {
+    let mut session = SESSION.lock().unwrap();
+    session.run = run;
+    session.stopped = false;
+    RESUMED.notify_all();
+}
+
+/// The arguments in a frame, by its id
+fn variables(frame: usize) -> Json {
+    let session = SESSION.lock().unwrap();
+    let variables = match frame.checked_sub(1).and_then(|i| session.frames.get(i)) {
+        Some(frame) if session.stopped => &frame.variables[..],
+        _ => &[],
+    };
+
+    let variables: Vec<Json> = variables
+        .iter()
+        .map(|(name, value)| {
+            Json::object(vec![
+                ("name", name.as_str().into()),
+                ("value", value.to_string().into()),
+                ("variablesReference", 0.into()),
+            ])
+        })
+        .collect();
+
+    Json::object(vec![("variables", variables.into())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc::Receiver, time::Duration};
+
+    const SOURCE: &str = "(define (add a b)
+  (+ a b))
+
+(define (twice x) (add x x))
+
+(twice 21)
+(add 1 2)";
+
+    #[test]
+    fn hooks() {
+        let prog = instrument(parser::parse(SOURCE).unwrap(), &[1, 4, 6, 7]);
+
+        assert_eq!(prog.len(), 6);
+        let add = prog[0].to_string();
+        assert!(add.contains("(%debug-enter 'add 1 \"a b\" a b)"), "{}", add);
+        assert!(add.contains("(%debug-leave)"), "{}", add);
+        assert_eq!(prog[2].to_string(), "(%debug-enter #f 6 \"\")");
+        assert_eq!(prog[3].to_string(), "(twice 21)");
+
+        let lines: Vec<Range<usize>> = lines(SOURCE);
+        assert_eq!(lines, [1..3, 4..5, 6..7, 7..8]);
+    }
+
+    /// The next message that isn't output of the program
+    fn next(messages: &Receiver<Json>) -> Json {
+        loop {
+            let message = messages.recv_timeout(Duration::from_secs(30)).unwrap();
+            if message.get("event").and_then(Json::as_str) != Some("output") {
+                return message;
+            }
+        }
+    }
+
+    fn request(command: &str, arguments: Json) -> Json {
+        Json::object(vec![
+            ("seq", 1.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])
+    }
+
+    #[test]
+    fn session() {
+        let path = std::env::temp_dir().join(format!("inc-dap-{}.ss", std::process::id()));
+        std::fs::write(&path, SOURCE).unwrap();
+
+        let (sender, messages) = mpsc::channel();
+        let mut server = Server::new(sender);
+        let body = |message: Json| message.get("body").cloned().unwrap();
+
+        server.handle(&request("initialize", Json::Null));
+        assert_eq!(next(&messages).get("success"), Some(&Json::Bool(true)));
+        assert_eq!(next(&messages).get("event").and_then(Json::as_str), Some("initialized"));
+
+        let program = path.display().to_string();
+        server.handle(&request("launch", Json::object(vec![("program", program.into())])));
+        assert_eq!(next(&messages).get("success"), Some(&Json::Bool(true)));
+
+        // Breakpoints move to the first line of their form
+        let lines = vec![Json::object(vec![("line", 2.into())]), Json::object(vec![("line", 3.into())])];
+        server.handle(&request("setBreakpoints", Json::object(vec![("breakpoints", lines.into())])));
+        let breakpoints = body(next(&messages)).get("breakpoints").cloned().unwrap();
+        assert_eq!(breakpoints.to_string(), r#"[{"verified":true,"line":1},{"verified":false}]"#);
+
+        server.handle(&request("configurationDone", Json::Null));
+        next(&messages);
+
+        let stopped = next(&messages);
+        assert_eq!(stopped.get("event").and_then(Json::as_str), Some("stopped"));
+        assert_eq!(body(stopped).get("reason").and_then(Json::as_str), Some("breakpoint"));
+
+        server.handle(&request("stackTrace", Json::Null));
+        let frames = body(next(&messages)).get("stackFrames").cloned().unwrap();
+        let names: Vec<&str> = frames
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| frame.get("name").and_then(Json::as_str).unwrap())
+            .collect();
+        assert_eq!(names, ["add", "twice", TOP]);
+
+        server.handle(&request("variables", Json::object(vec![("variablesReference", 3.into())])));
+        let variables = body(next(&messages)).get("variables").cloned().unwrap();
+        let values: Vec<String> = variables
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| format!("{}={}", v.get("name").unwrap(), v.get("value").unwrap()))
+            .collect();
+        assert_eq!(values, [r#""a"="21""#, r#""b"="21""#]);
+
+        // Out of add and twice, back at the top level
+        server.handle(&request("next", Json::Null));
+        next(&messages);
+        let stopped = next(&messages);
+        assert_eq!(body(stopped).get("reason").and_then(Json::as_str), Some("step"));
+        assert_eq!(SESSION.lock().unwrap().frames.len(), 1);
+        assert_eq!(SESSION.lock().unwrap().frames[0].line, 7);
+
+        server.handle(&request("continue", Json::Null));
+        next(&messages);
+        assert_eq!(next(&messages).get("event").and_then(Json::as_str), Some("stopped"));
+        server.handle(&request("continue", Json::Null));
+        next(&messages);
+
+        let exited = next(&messages);
+        assert_eq!(exited.get("event").and_then(Json::as_str), Some("exited"));
+        assert_eq!(body(exited).get("exitCode"), Some(&Json::from(0)));
+        assert!(server.handle(&request("terminate", Json::Null)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! Errors as they are reported to the user
+//!
+//! A [Diagnostic] is an [Error] with everything needed to show it: a severity,
+//! a stable code, the message, the file and span it points to if it is known,
+//! and notes. Parse errors and errors about a name know where they happened,
+//! the rest only know what went wrong for now.
+//!
+//! ```text
+//! error[E0001]: Failed to parse program
+//!  --> twice.scm:2:10
+//!   |
+//! 2 | (twice 21
+//!   |          ^
+//!   = note: the program ended before a `(` was closed
+//! ```
+//!
+//! Tools read diagnostics as [JSON](Diagnostic::json) instead, one object a
+//! line.
+
+use crate::{core::Error, exceptions::Status, json, parser};
+use colored::Colorize;
+use std::ops::Range;
+
+/// How bad a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A diagnostic about a program, ready to be rendered
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable code of the kind of error, like `E0001`
+    pub code: &'static str,
+    pub message: String,
+    /// Name of the file the span is in
+    pub file: Option<String>,
+    /// Byte offsets of the offending source in the file
+    pub span: Option<Range<usize>>,
+    pub notes: Vec<String>,
+}
+
+/// Codes of each kind of error, which never change meaning
+pub mod codes {
+    /// The source isn't valid Scheme syntax
+    pub const PARSE: &str = "E0001";
+    /// The program is valid syntax, but can't be compiled
+    pub const COMPILE: &str = "E0002";
+    /// The program failed while it ran
+    pub const RUNTIME: &str = "E0003";
+    /// Something went wrong in the compiler or the tools it runs
+    pub const INTERNAL: &str = "E0004";
+}
+
+impl Diagnostic {
+    /// A diagnostic for each of the errors, in the order they were found
+    pub fn all(error: &Error, sources: &[(&str, &str)]) -> Vec<Self> {
+        match error {
+            Error::Errors(errors) => errors.iter().flat_map(|e| Self::all(e, sources)).collect(),
+            e => vec![Self::new(e, sources)],
+        }
+    }
+
+    /// Describe an error, finding its span in one of the named sources
+    ///
+    /// Parse errors point into the source that failed to parse, which is found
+    /// by address so that errors in any of the files of a program are shown
+    /// in the right one. Several errors are described by the first one, see
+    /// [all](Diagnostic::all) for the rest.
+    pub fn new(error: &Error, sources: &[(&str, &str)]) -> Self {
+        let diagnostic = |code, message: &str, notes| Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+            file: None,
+            span: None,
+            notes,
+        };
+
+        match error {
+            Error::Parser(e) => {
+                let (rest, note) = match e {
+                    nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _))
+                        if rest.trim().is_empty() =>
+                    {
+                        (Some(*rest), String::from("the program ended before a `(` was closed"))
+                    }
+                    nom::Err::Failure((rest, nom::error::ErrorKind::TooLarge)) => (
+                        Some(*rest),
+                        format!("forms can't be nested more than {} deep", parser::max_depth()),
+                    ),
+                    nom::Err::Error((rest, kind)) | nom::Err::Failure((rest, kind)) => {
+                        (Some(*rest), format!("the parser gave up at {:?}", kind))
+                    }
+                    nom::Err::Incomplete(needed) => (None, format!("incomplete, {:?}", needed)),
+                };
+
+                let mut d = diagnostic(codes::PARSE, "Failed to parse program", vec![note]);
+
+                if let Some(rest) = rest {
+                    for (name, source) in sources {
+                        if let Some(start) = offset(source, rest) {
+                            d.file = Some(name.to_string());
+                            d.span = Some(start..start + token(&source[start..]));
+                        }
+                    }
+                }
+
+                d
+            }
+            Error::Compilation(e) => diagnostic(codes::COMPILE, e, vec![]),
+            Error::Located { message, name } => {
+                let mut d = diagnostic(codes::COMPILE, message, vec![]);
+
+                for (file, source) in sources {
+                    if let Some(span) = name.span(source) {
+                        d.file = Some(file.to_string());
+                        d.span = Some(span);
+                    }
+                }
+
+                d
+            }
+            Error::Errors(errors) => match errors.first() {
+                Some(e) => Self::new(e, sources),
+                None => diagnostic(codes::COMPILE, "failed to compile", vec![]),
+            },
+            Error::Runtime(e) => diagnostic(codes::RUNTIME, e, vec![]),
+            Error::Exit { status, message } => {
+                let note = format!("the program exited with status {}", status);
+                let note = match Status::from_code(*status) {
+                    Some(s) => format!("{}, {}", note, s.describe()),
+                    None => note,
+                };
+
+                diagnostic(codes::RUNTIME, message, vec![note])
+            }
+            Error::Internal { message, e } => diagnostic(
+                codes::INTERNAL,
+                message.trim(),
+                e.iter().map(|e| e.to_string()).collect(),
+            ),
+        }
+    }
+
+    /// Render with the offending line of the source and the span underlined
+    ///
+    /// `source` is the contents of the file of the diagnostic, without which
+    /// only the message and notes are shown.
+    pub fn render(&self, source: Option<&str>, color: bool) -> String {
+        let paint = |s: &str, style: fn(&str) -> colored::ColoredString| {
+            if color {
+                style(s).to_string()
+            } else {
+                s.to_string()
+            }
+        };
+
+        let (label, style): (_, fn(&str) -> colored::ColoredString) = match self.severity {
+            Severity::Error => ("error", |s| s.red().bold()),
+            Severity::Warning => ("warning", |s| s.yellow().bold()),
+        };
+
+        let mut out = format!(
+            "{}{}\n",
+            paint(&format!("{}[{}]", label, self.code), style),
+            paint(&format!(": {}", self.message), |s| s.bold())
+        );
+
+        let snippet = match (&self.file, &self.span, source) {
+            (Some(file), Some(span), Some(source)) if span.start <= source.len() => {
+                Some((file, span, source))
+            }
+            _ => None,
+        };
+
+        let gutter = match snippet {
+            Some((file, span, source)) => {
+                let (line, column) = position(source, span.start);
+                let text = source.lines().nth(line).unwrap_or("");
+                let number = (line + 1).to_string();
+                let pad = " ".repeat(number.len());
+                let width = span.len().min(text.len().saturating_sub(column)).max(1);
+
+                let location = format!("{}:{}:{}", file, line + 1, column + 1);
+
+                out += &format!("{}{} {}\n", pad, paint("-->", blue), location);
+                out += &format!("{} {}\n", pad, paint("|", blue));
+                out += &format!("{} {} {}\n", paint(&number, blue), paint("|", blue), text);
+                out += &format!(
+                    "{} {} {}{}\n",
+                    pad,
+                    paint("|", blue),
+                    " ".repeat(column),
+                    paint(&"^".repeat(width), style)
+                );
+                pad
+            }
+            None => String::new(),
+        };
+
+        for note in &self.notes {
+            for (i, line) in note.trim().lines().enumerate() {
+                if i == 0 {
+                    out += &format!("{} {} {}\n", gutter, paint("= note:", |s| s.bold()), line);
+                } else {
+                    out += &format!("{}         {}\n", gutter, line);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic as a single line JSON object
+    ///
+    /// ```json
+    /// {"severity":"error","code":"E0001","message":"Failed to parse program",
+    ///  "file":"twice.scm","span":{"start":36,"end":37,"line":2,"column":10},
+    ///  "notes":["the program ended before a `(` was closed"]}
+    /// ```
+    ///
+    /// Lines and columns start from 1 and are only known with the `source` of
+    /// the file, while `file` and `span` are `null` for errors without them.
+    pub fn json(&self, source: Option<&str>) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        let file = self.file.as_deref().map_or_else(|| String::from("null"), json::string);
+
+        let span = match &self.span {
+            Some(span) => {
+                let position = source
+                    .filter(|source| span.start <= source.len())
+                    .map(|source| position(source, span.start));
+
+                match position {
+                    Some((line, column)) => format!(
+                        r#"{{"start":{},"end":{},"line":{},"column":{}}}"#,
+                        span.start,
+                        span.end,
+                        line + 1,
+                        column + 1
+                    ),
+                    None => format!(r#"{{"start":{},"end":{}}}"#, span.start, span.end),
+                }
+            }
+            None => String::from("null"),
+        };
+
+        let notes: Vec<String> = self.notes.iter().map(|note| json::string(note.trim())).collect();
+
+        format!(
+            r#"{{"severity":"{}","code":"{}","message":{},"file":{},"span":{},"notes":[{}]}}"#,
+            severity,
+            self.code,
+            json::string(&self.message),
+            file,
+            span,
+            notes.join(",")
+        )
+    }
+}
+
+fn blue(s: &str) -> colored::ColoredString {
+    s.blue().bold()
+}
+
+/// Offset of a slice of the source, if it is one
+fn offset(source: &str, rest: &str) -> Option<usize> {
+    let start = source.as_ptr() as usize;
+    let at = rest.as_ptr() as usize;
+
+    if at >= start && at + rest.len() <= start + source.len() {
+        Some(at - start)
+    } else {
+        None
+    }
+}
+
+/// Length of the token at the start of the source, at least 1
+fn token(source: &str) -> usize {
+    let end = source.find(|c: char| c.is_whitespace() || c == ')').unwrap_or(source.len());
+    end.max(1)
+}
+
+/// Zero based line and column of an offset
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count();
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1);
+
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Name;
+
+    #[test]
+    fn snippet() {
+        let source = "(define (twice x) (* x 2))\n(twice 21";
+        let error = parser::parse(source).unwrap_err();
+        let d = Diagnostic::new(&error, &[("twice.scm", source)]);
+
+        assert_eq!(d.code, codes::PARSE);
+        assert_eq!(d.file.as_deref(), Some("twice.scm"));
+
+        let out = d.render(Some(source), false);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "error[E0001]: Failed to parse program");
+        assert!(lines[1].starts_with(" --> twice.scm:2:"));
+        assert!(lines[3].starts_with("2 | (twice 21"));
+        assert!(lines[4].contains('^'));
+        assert_eq!(lines[5], "  = note: the program ended before a `(` was closed");
+    }
+
+    #[test]
+    fn unknown() {
+        let error = Error::Compilation(String::from("Unknown function f"));
+        let d = Diagnostic::new(&error, &[]);
+
+        assert_eq!(d.render(None, false), "error[E0002]: Unknown function f\n");
+        assert_eq!(position("ab\ncd", 4), (1, 1));
+    }
+
+    #[test]
+    fn duplicate() {
+        let source = "(define (f x)\n  (let ((y 1) (y x)) y))";
+        let message = String::from("Duplicate binding `y` in let");
+        let error = Error::Located { message, name: Name::read(&source[29..30]) };
+        let d = Diagnostic::new(&error, &[("prelude.scm", "(define (g) 1)"), ("f.scm", source)]);
+
+        assert_eq!(d.file.as_deref(), Some("f.scm"));
+        assert_eq!(d.span, Some(29..30));
+        assert!(d.render(Some(source), false).contains(" --> f.scm:2:16"));
+    }
+
+    #[test]
+    fn json() {
+        let source = "(+ 1 2) )";
+        let error = parser::parse(source).unwrap_err();
+        let d = Diagnostic::new(&error, &[("a.scm", source)]);
+
+        assert_eq!(
+            d.json(Some(source)),
+            concat!(
+                r#"{"severity":"error","code":"E0001","message":"Failed to parse program","#,
+                r#""file":"a.scm","span":{"start":8,"end":9,"line":1,"column":9},"#,
+                r#""notes":["the parser gave up at Char"]}"#
+            )
+        );
+
+        let d = Diagnostic::new(&Error::Runtime(String::from("car: \"x\"\n\u{1}")), &[]);
+        assert!(d.json(None).contains(r#""message":"car: \"x\"\n\u0001","file":null,"span":null"#));
+    }
+}
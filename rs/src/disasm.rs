@@ -0,0 +1,209 @@
+//! Disassemble compiled programs
+//!
+//! `inc disasm inc` shows the machine code of the functions in an executable or
+//! object built by the compiler, under their Scheme names. The disassembly is
+//! done by `objdump`, this only picks out the parts that came from the program
+//! and lays them out for reading:
+//!
+//! ```text
+//! $ inc disasm twice twice.scm
+//! twice:
+//! ; (define (twice x) (* x 2))
+//!     20d27f  mov rax,QWORD PTR [rsp-0x8]
+//!   stack_f90_1:
+//!     20d2a4  ...
+//! ```
+//!
+//! Every function is a global symbol named like the Scheme function, which
+//! starts a new section of the output, while the local labels of the code
+//! generator show up inside the function they belong to. All the code of a
+//! program is emitted in one piece, starting with [init](x86::init) and ending
+//! with the [finalizer](gc::finalize) routine, so everything else in an
+//! executable is the runtime and is left out. Literals kept in the text section
+//! aren't code and are left out too.
+//!
+//! With the source of the program, every function defined at the top level is
+//! preceded by its definition, while the functions of the prelude are left out.
+//! Lambdas are shown under the names they are lifted to. With a
+//! [source map](crate::sourcemap) of the program, every function is preceded by
+//! the line it was defined at and the lines of the asm it was generated from,
+//! which works for lambdas and the functions of libraries as well.
+use crate::{
+    compiler,
+    core::{Error, Expr, Ident},
+    gc, parser,
+    sourcemap::SourceMap,
+    strings, symbols, x86,
+};
+use std::{collections::HashMap, process::Command};
+
+/// A symbol in the disassembly and the instructions following it
+struct Block {
+    name: String,
+    /// Address and text of every instruction
+    code: Vec<(String, String)>,
+}
+
+/// Disassemble an executable or object, along with the source of the program
+/// and its source map
+pub fn disassemble(
+    path: &str,
+    source: Option<&str>,
+    map: Option<&SourceMap>,
+) -> Result<String, Error<'static>> {
+    let globals = globals(&objdump(&["-t", path])?);
+    let blocks = blocks(&objdump(&["-d", "--no-show-raw-insn", "-M", "intel", path])?);
+
+    let forms: HashMap<String, &str> = match source {
+        Some(source) => parser::forms(source)
+            .map_err(|e| Error::Compilation(e.to_string()))?
+            .into_iter()
+            .filter_map(|(e, text)| match e {
+                Expr::Define { name, .. } => Some((String::from(name), text)),
+                _ => None,
+            })
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let prelude: Vec<String> = compiler::prelude()
+        .into_iter()
+        .filter_map(|e| match e {
+            Expr::Define { name, .. } => Some(String::from(name)),
+            _ => None,
+        })
+        .collect();
+
+    // The object of a unit has no `init` and nothing but generated code
+    let init = x86::init();
+    let start = blocks.iter().position(|b| b.name == init).unwrap_or(0);
+    let end = blocks.iter().rposition(|b| b.name.starts_with(gc::FINALIZE)).unwrap_or(blocks.len());
+
+    let mut out = String::new();
+    let mut skip = false;
+
+    for block in blocks.iter().take(end + 1).skip(start) {
+        if block.name.starts_with(strings::LABEL) || block.name.starts_with(symbols::LABEL) {
+            continue;
+        }
+
+        if globals.contains(&block.name) {
+            // Lifted lambdas are named after the function they are defined in
+            let name = Ident::unmangle(&block.name).unwrap_or(&block.name);
+            let function = name.split(' ').next().unwrap_or_default();
+            skip = source.is_some() && prelude.iter().any(|name| name == function);
+
+            if skip {
+                continue;
+            }
+
+            out.push_str(&format!("\n{}:\n", block.name));
+
+            if let Some(f) = map.and_then(|map| map.function(name)) {
+                let line = f.line.map_or(String::new(), |line| format!("line {}, ", line));
+                out.push_str(&format!("; {}asm {}-{}\n", line, f.lines.start, f.lines.end - 1));
+            }
+
+            if let Some(text) = forms.get(name) {
+                for line in text.lines() {
+                    out.push_str(&format!("; {}\n", line));
+                }
+            }
+        } else if skip {
+            continue;
+        } else {
+            out.push_str(&format!("  {}:\n", block.name));
+        }
+
+        for (address, ins) in &block.code {
+            out.push_str(&format!("    {}  {}\n", address, ins));
+        }
+    }
+
+    Ok(out.trim().to_string())
+}
+
+/// Run `objdump` and return what it printed
+fn objdump(args: &[&str]) -> Result<String, Error<'static>> {
+    let exe = Command::new("objdump").args(args).output().map_err(|e| Error::Internal {
+        message: String::from("Failed to run objdump"),
+        e: Some(e),
+    })?;
+
+    if exe.status.success() {
+        Ok(String::from_utf8_lossy(&exe.stdout).into_owned())
+    } else {
+        Err(Error::Internal {
+            message: format!("objdump failed\n{}", String::from_utf8_lossy(&exe.stderr).trim()),
+            e: None,
+        })
+    }
+}
+
+/// Names of the global symbols in the symbol table printed by `objdump -t`
+///
+/// A line is the address, seven columns of flags, the section, the size and
+/// the name, which can have spaces in it.
+fn globals(table: &str) -> Vec<String> {
+    table
+        .lines()
+        .filter(|line| line.get(17..18) == Some("g"))
+        .filter_map(|line| line.split_once('\t'))
+        .filter_map(|(_, rest)| rest.trim_start().split_once(char::is_whitespace))
+        .map(|(_size, name)| name.trim_start().to_string())
+        .collect()
+}
+
+/// Split a disassembly printed by `objdump -d` into its symbols
+///
+/// A symbol is a line like `000000000020d350 <init>:` followed by instructions
+/// like `  20d350:<tab>push   rbp`.
+fn blocks(disassembly: &str) -> Vec<Block> {
+    let mut blocks: Vec<Block> = vec![];
+
+    for line in disassembly.lines() {
+        if let Some(name) = line.split_once(" <").and_then(|(_, rest)| rest.strip_suffix(">:")) {
+            blocks.push(Block { name: name.to_string(), code: vec![] });
+        } else if let Some((address, ins)) = line.split_once(":\t") {
+            let block = match blocks.last_mut() {
+                Some(block) => block,
+                None => continue,
+            };
+            let ins = ins.split_whitespace().collect::<Vec<_>>().join(" ");
+            block.code.push((address.trim().to_string(), ins));
+        }
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let table = "\
+0000000000000000 l    df *ABS*\t0000000000000000              p.s
+000000000020d350 g     F .text\t0000000000000000              init
+000000000020d31c g     F .text\t0000000000000000              {let 0} f
+000000000020d3c8 l       .text\t0000000000000000              inc_str_0";
+
+        assert_eq!(globals(table), vec!["init", "{let 0} f"]);
+
+        let disassembly = "
+Disassembly of section .text:
+
+000000000020d350 <init>:
+  20d350:\tpush   rbp
+  20d351:\tmov    rbp,rsp
+
+000000000020d31c <{let 0} f>:
+  20d31c:\tret";
+
+        let blocks = blocks(disassembly);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].code[1], (String::from("20d351"), String::from("mov rbp,rsp")));
+        assert_eq!(blocks[1].name, "{let 0} f");
+    }
+}
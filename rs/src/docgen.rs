@@ -0,0 +1,344 @@
+//! Documentation extracted from doc comments
+//!
+//! A definition is documented by the comments right above it that start with
+//! `;;;` or `;;>`, like this:
+//!
+//! ```scheme
+//! ;;; Double a number
+//! ;;;
+//! ;;; Works for any number, however large.
+//! (define (double x) (* x 2))
+//! ```
+//!
+//! [extract] reads them into [Library]s, one for every `define-library` in a
+//! file with the definitions it exports and one for the top level of the file
+//! if it defines anything. `inc doc` renders them as [markdown] or [html],
+//! listing the functions with their arguments and docs.
+//!
+//! Comments are thrown away by the [parser](crate::parser), so the source is
+//! read by the [formatter](crate::fmt) instead, which keeps them.
+
+use crate::fmt::{self, Node};
+
+/// The definitions of a library, or of the top level of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Library {
+    pub name: String,
+    pub items: Vec<Item>,
+}
+
+/// A documented definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub name: String,
+    /// Names of the arguments of a function, nothing for a variable
+    pub formals: Option<Vec<String>>,
+    /// Whether the function takes any number of arguments after the formals
+    pub rest: bool,
+    /// The doc comment without its markers, empty if there is none
+    pub doc: String,
+}
+
+/// The libraries of a file, with the top level named after the file
+pub fn extract(file: &str, source: &str) -> Result<Vec<Library>, String> {
+    let nodes = fmt::read(source)?;
+    let mut libraries = vec![];
+
+    for node in &nodes {
+        if let Some((name, items)) = library(node) {
+            libraries.push(Library { name, items });
+        }
+    }
+
+    let items = items(&nodes);
+    if !items.is_empty() {
+        libraries.insert(0, Library { name: file.to_string(), items });
+    }
+
+    Ok(libraries)
+}
+
+impl Item {
+    /// How the function is called, like `(f x . rest)`, or the name of a
+    /// variable
+    pub fn signature(&self) -> String {
+        match &self.formals {
+            Some(formals) => {
+                let mut signature: Vec<&str> = std::iter::once(self.name.as_str())
+                    .chain(formals.iter().map(String::as_str))
+                    .collect();
+                if self.rest {
+                    signature.push(". rest");
+                }
+                format!("({})", signature.join(" "))
+            }
+            None => self.name.clone(),
+        }
+    }
+
+    /// How many arguments the function takes, in words
+    pub fn arity(&self) -> String {
+        let formals = match &self.formals {
+            Some(formals) => formals,
+            None => return String::from("variable"),
+        };
+
+        let plural = if formals.len() == 1 { "" } else { "s" };
+        let least = if self.rest { "at least " } else { "" };
+        format!("takes {}{} argument{}", least, formals.len(), plural)
+    }
+}
+
+/// Render libraries as markdown, a section for each
+pub fn markdown(libraries: &[Library]) -> String {
+    let mut out = String::new();
+
+    for library in libraries {
+        out.push_str(&format!("# {}\n", library.name));
+
+        for item in &library.items {
+            out.push_str(&format!("\n## `{}`\n\n{}\n", item.signature(), item.arity()));
+            if !item.doc.is_empty() {
+                out.push_str(&format!("\n{}\n", item.doc));
+            }
+        }
+
+        out.push('\n');
+    }
+
+    out.trim_end().to_string()
+}
+
+/// Render libraries as a standalone HTML page
+pub fn html(libraries: &[Library]) -> String {
+    let mut out =
+        String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"></head>\n<body>\n");
+
+    for library in libraries {
+        out.push_str(&format!("<h1>{}</h1>\n", escape(&library.name)));
+
+        for item in &library.items {
+            out.push_str(&format!(
+                "<h2><code>{}</code></h2>\n<p><em>{}</em></p>\n",
+                escape(&item.signature()),
+                item.arity()
+            ));
+            for paragraph in item.doc.split("\n\n").filter(|p| !p.is_empty()) {
+                out.push_str(&format!("<p>{}</p>\n", escape(paragraph)));
+            }
+        }
+    }
+
+    out.push_str("</body>\n</html>");
+    out
+}
+
+/// The name and exported items of a `define-library`
+fn library(node: &Node) -> Option<(String, Vec<Item>)> {
+    let items = match node {
+        Node::List { items, .. } if head(items) == Some("define-library") => items,
+        _ => return None,
+    };
+
+    let mut forms = items.iter().filter(form);
+    let name = forms.nth(1)?.flat()?;
+
+    let mut exports = vec![];
+    let mut defined = vec![];
+
+    for form in forms {
+        let list = match form {
+            Node::List { items, .. } => items,
+            _ => continue,
+        };
+
+        match head(list) {
+            Some("export") => {
+                for spec in list.iter().skip(1) {
+                    match spec {
+                        Node::Atom(name) => exports.push((name.clone(), name.clone())),
+                        Node::List { items, .. } if head(items) == Some("rename") => {
+                            let names: Vec<&str> = items.iter().filter_map(atom).collect();
+                            if let [_, internal, external] = names[..] {
+                                exports.push((internal.to_string(), external.to_string()));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Some("begin") => defined.extend(self::items(&list[1..])),
+            _ => {}
+        }
+    }
+
+    let items = exports
+        .into_iter()
+        .filter_map(|(internal, external)| {
+            let item = defined.iter().find(|item| item.name == internal)?;
+            Some(Item { name: external, ..item.clone() })
+        })
+        .collect();
+
+    Some((name, items))
+}
+
+/// The definitions among some nodes, with the doc comments right above them
+fn items(nodes: &[Node]) -> Vec<Item> {
+    let mut items = vec![];
+    let mut doc: Vec<&str> = vec![];
+
+    for node in nodes {
+        match node {
+            Node::Comment { text, trailing: false } => {
+                match text.strip_prefix(";;;").or_else(|| text.strip_prefix(";;>")) {
+                    Some(line) => doc.push(line.strip_prefix(' ').unwrap_or(line)),
+                    None => doc.clear(),
+                }
+            }
+            Node::Comment { trailing: true, .. } => {}
+            Node::List { items: list, .. } if head(list) == Some("begin") => {
+                items.extend(self::items(&list[1..]));
+                doc.clear();
+            }
+            node => {
+                if let Some(item) = define(node, doc.join("\n").trim()) {
+                    items.push(item);
+                }
+                doc.clear();
+            }
+        }
+    }
+
+    items
+}
+
+/// The item of a `define` form
+fn define(node: &Node, doc: &str) -> Option<Item> {
+    let mut forms = match node {
+        Node::List { items, .. } if head(items) == Some("define") => items.iter().filter(form),
+        _ => return None,
+    };
+
+    let item = |name: &str, formals: Option<Vec<&str>>| {
+        let (formals, rest) = match formals {
+            Some(names) => {
+                let dot = names.iter().position(|name| *name == ".");
+                let formals = &names[..dot.unwrap_or(names.len())];
+                (Some(formals.iter().map(|name| name.to_string()).collect()), dot.is_some())
+            }
+            None => (None, false),
+        };
+
+        Item { name: name.to_string(), formals, rest, doc: doc.to_string() }
+    };
+
+    match forms.nth(1)? {
+        // (define (f x ...) body ...), where the formals are the rest of the list
+        Node::List { items, .. } => {
+            let mut names = items.iter().filter_map(atom);
+            let name = names.next()?;
+            Some(item(name, Some(names.collect())))
+        }
+        Node::Atom(name) => match forms.next() {
+            Some(Node::List { items, .. }) if matches!(head(items), Some("lambda" | "λ")) => {
+                match items.iter().filter(form).nth(1) {
+                    Some(Node::List { items, .. }) => {
+                        Some(item(name, Some(items.iter().filter_map(atom).collect())))
+                    }
+                    Some(Node::Atom(rest)) => Some(item(name, Some(vec![".", rest]))),
+                    _ => Some(item(name, None)),
+                }
+            }
+            _ => Some(item(name, None)),
+        },
+        _ => None,
+    }
+}
+
+/// Whether a node is a form, rather than a comment or a blank line
+const fn form(node: &&Node) -> bool {
+    matches!(node, Node::Atom(_) | Node::List { .. })
+}
+
+/// The first form of a list, if it is a name
+fn head(items: &[Node]) -> Option<&str> {
+    items.iter().find(form).and_then(atom)
+}
+
+fn atom(node: &Node) -> Option<&str> {
+    match node {
+        Node::Atom(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Escape text for HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SOURCE: &str = "\
+(define-library (math arith)
+  (export double (rename triple thrice))
+  (begin
+    ;;; Double a number
+    ;;;
+    ;;; Works for any number.
+    (define (double x) (* x 2))
+    ;;> Three times a number
+    (define triple (lambda (x) (+ (double x) x)))
+    ;;; Not exported
+    (define (helper) 1)))
+
+; Not a doc comment
+(define (sum . xs) (fold + 0 xs))
+;;; The answer
+(define answer 42)
+";
+
+    #[test]
+    fn extract() {
+        let libraries = super::extract("main.ss", SOURCE).unwrap();
+        let names: Vec<&str> = libraries.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["main.ss", "(math arith)"]);
+
+        let arith = &libraries[1].items;
+        assert_eq!(arith.len(), 2);
+        assert_eq!(arith[0].signature(), "(double x)");
+        assert_eq!(arith[0].doc, "Double a number\n\nWorks for any number.");
+        assert_eq!(arith[1].signature(), "(thrice x)");
+        assert_eq!(arith[1].arity(), "takes 1 argument");
+        assert_eq!(arith[1].doc, "Three times a number");
+
+        let main = &libraries[0].items;
+        assert_eq!(main[0].signature(), "(sum . rest)");
+        assert_eq!(main[0].arity(), "takes at least 0 arguments");
+        assert_eq!(main[0].doc, "");
+        assert_eq!(
+            (main[1].signature().as_str(), main[1].arity().as_str()),
+            ("answer", "variable")
+        );
+    }
+
+    #[test]
+    fn render() {
+        let libraries = super::extract("main.ss", SOURCE).unwrap();
+
+        let markdown = markdown(&libraries[1..]);
+        assert!(markdown.starts_with("# (math arith)\n\n## `(double x)`\n\ntakes 1 argument\n\n"));
+        assert!(markdown.ends_with("## `(thrice x)`\n\ntakes 1 argument\n\nThree times a number"));
+
+        let html = html(&libraries[..1]);
+        assert!(html.contains(
+            "<h2><code>(sum . rest)</code></h2>\n<p><em>takes at least 0 arguments</em></p>"
+        ));
+        assert!(html.ends_with("<p>The answer</p>\n</body>\n</html>"));
+        assert_eq!(escape("(< a \"b\")"), "(&lt; a &quot;b&quot;)");
+    }
+}
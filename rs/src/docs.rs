@@ -28,9 +28,9 @@ Code here is expressive, powerful and easy to maintain thanks to Rust.
 ⚠ Anything that can be done here should be written in Rust and this approach is
 documented only for completeness sake.
 
-Most of the C runtime was ported to Rust, but there is tiny bit of C left
-including a thin `main` function and a signal handler for managing segmentation
-faults. This will also be migrated over at some point.
+The whole runtime is Rust now, including the `main` of compiled programs and the
+signal handler for segmentation faults; see [start](crate::start). No C is left
+apart from the header `inc.h` describing the runtime.
 
 There will always be an indirect dependency on C for system calls, but it can be
 conveniently abstracted away behind a few rust helper functions.
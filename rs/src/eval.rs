@@ -0,0 +1,249 @@
+//! Evaluate data as code at run time
+//!
+//! `(eval expr env)` compiles the datum `expr` with the compiler linked into
+//! the runtime, loads it next to the program with the [jit] and calls it on
+//! the same stack and heap, just like any other function.
+//!
+//! ```scheme
+//! (let ((env (interaction-environment)))
+//!   (eval (read (open-input-string "(define (sq x) (* x x))")) env)
+//!   (eval (read (open-input-string "(sq 12)")) env))
+//! ```
+//!
+//! An environment holds the functions defined by the expressions evaluated in
+//! it so far, starting with the [prelude](crate::compiler::parse), which is
+//! compiled along with the first expression. The environment object itself is
+//! a vector like ports, `#(environment id)`, backed by a table in the runtime
+//! mapping the names of the functions to their addresses. Code loaded by a
+//! later `eval` calls them through the table.
+//!
+//! Like the rest of the language, only functions can be defined at the top
+//! level and functions aren't values, so the expression has no access to the
+//! functions of the program calling `eval`.
+//!
+//! An expression that fails to compile raises an error from `eval`, just like
+//! the errors raised by the runtime.
+use crate::{
+    compiler::state::State,
+    core::{Core, Ident},
+    exceptions, ffi,
+    x86::{self, Reference::*, Register::*, ASM, WORDSIZE},
+};
+#[cfg(feature = "native")]
+use crate::{
+    compiler::{self, emit},
+    core::{Error, Expr::*, Literal::*, Syntax},
+    exceptions::Target,
+    gc,
+    immediate::*,
+    jit::{self, Image},
+    lambda, lang, parser,
+    rt::{car, cdr, Object},
+    strings, symbols,
+    tags::tag,
+};
+#[cfg(feature = "native")]
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write},
+};
+
+/// Label of the function compiled for an expression
+#[cfg(feature = "native")]
+const ENTRY: &str = "inc_eval";
+
+/// Functions defined in an environment and the code they live in
+#[cfg(feature = "native")]
+#[derive(Default)]
+struct Environment {
+    functions: HashMap<String, usize>,
+    images: Vec<Image>,
+}
+
+#[cfg(feature = "native")]
+thread_local! {
+    /// All environments of the current thread, by id
+    static ENVIRONMENTS: RefCell<HashMap<i64, Environment>> = RefCell::new(HashMap::new());
+
+    /// The error of the last failed `eval`, see [rt_eval_error]
+    static ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Emit code for `(eval expr env)`
+///
+/// The function compiled by the runtime is called with the stack extended past
+/// the live slots, exactly like [lambda::call] with no arguments.
+pub fn call(s: &mut State, expr: &Core, env: &Core) -> ASM {
+    let ok = s.gen_label("eval");
+    let locals = -(s.si + WORDSIZE);
+
+    ffi::call(s, &Ident::new("rt-eval"), &[expr.clone(), env.clone()])
+        + x86::cmp(RAX.into(), Const(0))
+        + x86::jne(&ok)
+        + ffi::runtime(s, "rt_eval_error")
+        + exceptions::fail(s)
+        + x86::label(&ok)
+        + x86::sub(RSP.into(), Const(locals))
+        + x86::call_indirect(RAX)
+        + x86::add(RSP.into(), Const(locals))
+}
+
+/// Compile an expression in an environment and return the address of the code
+///
+/// Returns 0 if the expression can't be compiled, see [rt_eval_error].
+#[cfg(feature = "native")]
+#[no_mangle]
+pub extern "C" fn rt_eval(expr: Object, env: Object) -> i64 {
+    match compile(expr, env) {
+        Ok(address) => address as i64,
+        Err(e) => {
+            ERROR.with(|error| *error.borrow_mut() = format!("eval: {}", e));
+            0
+        }
+    }
+}
+
+/// Raise the error of the last `eval` that failed to compile
+#[cfg(feature = "native")]
+#[no_mangle]
+pub extern "C" fn rt_eval_error() -> Target {
+    ERROR.with(|error| exceptions::error(&error.borrow()))
+}
+
+/// Forget all environments of the current thread along with their code
+#[cfg(feature = "native")]
+pub fn reset() {
+    ENVIRONMENTS.with(|envs| envs.borrow_mut().clear())
+}
+
+#[cfg(feature = "native")]
+fn compile(expr: Object, env: Object) -> Result<usize, String> {
+    let id = match env.deref() {
+        Vector(v) => match v.as_slice() {
+            [Literal(Symbol(tag)), Literal(Number(id))] if tag == "environment" => Some(*id),
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| format!("expected an environment, got {}", env))?;
+
+    let mut text = String::new();
+    source(&mut text, expr).unwrap();
+
+    ENVIRONMENTS.with(|envs| {
+        let mut envs = envs.borrow_mut();
+        let env = envs.entry(id).or_default();
+
+        let parse = if env.images.is_empty() { compiler::parse } else { parser::parse };
+        let prog = parse(&text).map_err(|_| format!("invalid expression {}", text))?;
+
+        let (asm, names) = generate(prog)?;
+
+        let functions = &env.functions;
+        let lookup = |symbol: &str| functions.get(symbol).copied();
+        let image = jit::link(&asm, ENTRY, lookup).map_err(|e| match e {
+            Error::Compilation(message) => message,
+            e => e.to_string(),
+        })?;
+
+        for name in names {
+            if let Some(address) = image.address(&name) {
+                env.functions.insert(name, address);
+            }
+        }
+
+        let address = image.entry();
+        env.images.push(image);
+        Ok(address)
+    })
+}
+
+/// Generate code for a program as a function without arguments
+///
+/// Returns the names of all the functions defined in the program as well. See
+/// [messages](compiler::messages) for errors.
+#[cfg(feature = "native")]
+fn generate(prog: Vec<Syntax>) -> Result<(ASM, Vec<String>), String> {
+    let mut s = State::new();
+    s.runtime = true;
+    let prog = lang::analyze(&mut s, prog).map_err(compiler::messages)?;
+
+    let names = prog
+        .iter()
+        .filter_map(|expr| match expr {
+            Define { name, .. } => Some(name.mangle()),
+            _ => None,
+        })
+        .collect();
+
+    let mut asm = x86::prelude()
+        + x86::func(ENTRY)
+        + x86::enter()
+        + lambda::guard(&mut s)
+        + x86::mov(RAX.into(), NIL.into());
+
+    for expr in &prog {
+        asm += emit::eval(&mut s, expr);
+    }
+
+    asm += x86::leave();
+    asm += strings::inline(&s);
+    asm += symbols::inline(&s);
+    asm += lambda::emit(&mut s, &prog);
+    asm += exceptions::dispatch();
+    asm += gc::finalize();
+
+    s.check().map_err(compiler::messages)?;
+
+    Ok((asm, names))
+}
+
+/// Write a datum as source code, the inverse of [parser::parse]
+///
+/// Symbols are written as identifiers and `(quote x)` as `'x`.
+#[cfg(feature = "native")]
+fn source(f: &mut String, val: Object) -> fmt::Result {
+    match tag(val.0) {
+        SYM => write!(f, "{}", name(val)),
+
+        PAIR => {
+            let quoted = cdr(val);
+            if tag(car(val).0) == SYM
+                && name(car(val)) == "quote"
+                && tag(quoted.0) == PAIR
+                && cdr(quoted).0 == NIL
+                && tag(car(quoted).0) == SYM
+            {
+                return write!(f, "'{}", name(car(quoted)));
+            }
+
+            write!(f, "(")?;
+            source(f, car(val))?;
+
+            let mut rest = cdr(val);
+            while tag(rest.0) == PAIR {
+                write!(f, " ")?;
+                source(f, car(rest))?;
+                rest = cdr(rest);
+            }
+
+            if rest.0 != NIL {
+                write!(f, " . ")?;
+                source(f, rest)?;
+            }
+
+            write!(f, ")")
+        }
+
+        _ => write!(f, "{}", val),
+    }
+}
+
+#[cfg(feature = "native")]
+fn name(symbol: Object) -> String {
+    match symbol.deref() {
+        Literal(Symbol(name)) => name,
+        e => unreachable!("Expected a symbol, got {}", e),
+    }
+}
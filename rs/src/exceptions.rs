@@ -0,0 +1,500 @@
+//! Exceptions
+//!
+//! `(raise obj)` hands `obj` to the current exception handler, installed with
+//! either `guard` or `with-exception-handler`. A raise with no handler at all
+//! prints the object and exits, just like the type errors and stack overflows
+//! reported by the runtime; those are raised as strings and can be caught too.
+//!
+//! ```scheme
+//! (guard (e ((string? e) 0)
+//!           ((symbol? e) 1))
+//!   (car 'oops))
+//! ```
+//!
+//! The handlers live on a stack in the runtime. `guard` pushes the frame of the
+//! function that installed it along with the address of the code for its
+//! clauses; raising to a guard unwinds the scheme stack by resetting RBP and
+//! RSP to that frame and jumping to the address, much like `longjmp`. The
+//! clauses are evaluated with the frame exactly like it was when the guard was
+//! entered, so all the local variables are intact. `guard` itself is expanded
+//! into the primitive `%guard` by [lang](crate::lang).
+//!
+//! A procedure installed with `with-exception-handler` is called on top of the
+//! stack of the raise instead, with the handler itself removed for the
+//! duration of the call. Returning from it resumes a `raise-continuable`,
+//! while a returning handler is an error for `raise`. Since functions aren't
+//! first class values yet, both the handler and the thunk must be the names of
+//! functions.
+//!
+//! Runtime functions that raise return a [Target] in RAX & RDX and the code
+//! emitted by [fail] transfers control to it; the runtime has no way to unwind
+//! the scheme stack by itself.
+//!
+//! A program stopped by an error exits with a [Status] telling what went wrong,
+//! after printing the error to stderr. The status of an uncaught error raised
+//! by the runtime depends on the kind of the error, so that type errors and
+//! stack overflows can be told apart from the objects raised by the program.
+//! Errors that can't be raised at all, like running out of memory, exit with a
+//! status of their own. A crash is killed by a signal instead.
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::{Core, Expr, Ident, Literal},
+    ffi,
+    immediate::STR,
+    lambda,
+    rt::Object,
+    x86::{self, Reference::*, Register::*, ASM, WORDSIZE},
+};
+use std::cell::RefCell;
+
+/// Label of the shared routine that transfers control to a handler
+pub const DISPATCH: &str = "inc_dispatch";
+
+/// The frame of a [Target] denoting an after thunk of `dynamic-wind`
+const UNWIND: i64 = 1;
+
+/// Emit code for `(raise obj)`
+pub fn raise(s: &mut State, obj: &Core) -> ASM {
+    eval(s, obj) + x86::mov(RDI.into(), RAX.into()) + ffi::runtime(s, "rt_raise") + fail(s)
+}
+
+/// Emit code for `(raise-continuable obj)`
+///
+/// Evaluates to the value returned by the handler.
+pub fn raise_continuable(s: &mut State, obj: &Core) -> ASM {
+    let mut asm = eval(s, obj) + x86::mov(RDI.into(), RAX.into()) + ffi::runtime(s, "rt_raise");
+
+    asm += x86::save(Const(0), s.si);
+    asm += ffi::routine(s, DISPATCH);
+
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += ffi::runtime(s, "rt_resume");
+    asm += x86::load(RAX, slot);
+    s.dealloc(1);
+
+    asm
+}
+
+/// Emit code for `(with-exception-handler handler thunk)`
+pub fn with(s: &mut State, handler: &Ident, thunk: &Ident) -> ASM {
+    for f in [handler, thunk] {
+        lambda::function(s, "with-exception-handler", f);
+    }
+
+    let mut asm = x86::lea(RDI, &handler.mangle(), 0)
+        + ffi::runtime(s, "rt_push_handler")
+        + lambda::call(s, thunk, &[]);
+
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += ffi::runtime(s, "rt_pop_handler");
+    asm += x86::load(RAX, slot);
+    s.dealloc(1);
+
+    asm
+}
+
+/// Emit code for `(%guard var body handler)`
+///
+/// `body` is evaluated with a guard installed. If anything is raised from it,
+/// the raised object is stored in the local variable `var` and `handler` is
+/// evaluated instead. See module documentation.
+pub fn guard(s: &mut State, var: &Ident, body: &Core, handler: &Core) -> ASM {
+    let catch = s.gen_label("catch");
+    let done = s.gen_label("done");
+
+    let var = s.get(var).unwrap_or_else(|| panic!("Undefined variable {}", var)).clone();
+
+    let mut asm = x86::lea(RSI, &catch, 0)
+        + x86::mov(RDI.into(), RBP.into())
+        + ffi::runtime(s, "rt_push_guard")
+        + eval(s, body);
+
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+    asm += ffi::runtime(s, "rt_pop_handler");
+    asm += x86::load(RAX, slot);
+    s.dealloc(1);
+
+    asm + x86::jmp(&done)
+        + x86::label(&catch)
+        + ffi::runtime(s, "rt_condition")
+        + x86::mov(var, RAX.into())
+        + eval(s, handler)
+        + x86::label(&done)
+}
+
+/// Transfer control to the [Target] returned by a raise in the runtime
+///
+/// A guard never returns here. If the handler is a procedure and it returns,
+/// that is an error in itself and is raised to the next handler in turn.
+pub fn fail(s: &mut State) -> ASM {
+    let again = s.gen_label("raise");
+
+    // The call to the handler could leave a word of padding in the live frame
+    // to align the stack; make sure the garbage collector never sees a stale
+    // value there.
+    x86::label(&again)
+        + x86::save(Const(0), s.si)
+        + ffi::routine(s, DISPATCH)
+        + ffi::runtime(s, "rt_handler_returned")
+        + x86::jmp(&again)
+}
+
+/// The routine to transfer control to a [Target] in RAX & RDX
+///
+/// Jumps to a guard or calls a procedure handler with the raised object and
+/// returns its value. The after thunks of `dynamic-wind` on the way to a guard
+/// or an escape point are called first, one at a time. The routine is called
+/// with an aligned stack below the live frame of the caller with
+/// [ffi::routine], and the handler is called exactly like any other function
+/// with a single argument. See [lambda](crate::lambda) for details.
+pub fn dispatch() -> ASM {
+    let call = format!("{}_call", DISPATCH);
+    let unwind = format!("{}_unwind", DISPATCH);
+
+    x86::label(DISPATCH)
+        + x86::cmp(RAX.into(), Const(0))
+        + x86::je(&call)
+        + x86::cmp(RAX.into(), Const(UNWIND))
+        + x86::je(&unwind)
+        + x86::mov(RBP.into(), RAX.into())
+        + x86::mov(RSP.into(), RAX.into())
+        + x86::jmp_indirect(RDX)
+        + x86::label(&call)
+        + x86::enter()
+        + x86::save(RDX.into(), -WORDSIZE)
+        + x86::sub(RSP.into(), Const(2 * WORDSIZE))
+        + x86::call(&ffi::symbol("rt_condition"))
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::save(RAX.into(), -4 * WORDSIZE)
+        + x86::load(RDX, -WORDSIZE)
+        + x86::sub(RSP.into(), Const(WORDSIZE))
+        + x86::call_indirect(RDX)
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::leave()
+        + x86::label(&unwind)
+        + x86::enter()
+        + x86::call_indirect(RDX)
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::call(&ffi::symbol("rt_unwind"))
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::pop(RBP.into())
+        + x86::jmp(DISPATCH)
+}
+
+/// Where to continue after a raise, returned in RAX & RDX
+///
+/// A guard continues at `address` with the stack frame `frame`, while a
+/// procedure handler at `address` is called from the frame of the raise,
+/// denoted by a null `frame`. A frame of [UNWIND] denotes an after thunk of
+/// `dynamic-wind` to be called before asking for the target again with
+/// [rt_unwind].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub frame: i64,
+    pub address: i64,
+}
+
+/// Exit status of a program stopped by an error
+///
+/// The driver forwards the status of a program it runs, see
+/// [exec](crate::cli::exec). 2 is left out, which is what inc itself exits with
+/// for a mistake in the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// An object raised with no handler, or any other error
+    Raised = 1,
+    /// A primitive applied to a value of the wrong type
+    Type = 3,
+    /// The heap is full, or over its limit
+    Memory = 4,
+    /// Too many nested calls for the stack
+    Stack = 5,
+}
+
+impl Status {
+    pub const fn from_code(code: i32) -> Option<Self> {
+        match code {
+            1 => Some(Status::Raised),
+            3 => Some(Status::Type),
+            4 => Some(Status::Memory),
+            5 => Some(Status::Stack),
+            _ => None,
+        }
+    }
+
+    /// What went wrong, as shown by the driver
+    pub const fn describe(self) -> &'static str {
+        match self {
+            Status::Raised => "an uncaught exception",
+            Status::Type => "a type error",
+            Status::Memory => "out of memory",
+            Status::Stack => "a stack overflow",
+        }
+    }
+
+    /// End the program with the status
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+/// An entry in the dynamic environment of a thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Entry {
+    /// A guard with its frame and the address of its clauses
+    Guard { frame: i64, address: i64 },
+    /// A procedure installed with `with-exception-handler`
+    Procedure(i64),
+    /// A procedure handler is running, which puts every handler from the
+    /// entry at this index upwards out of effect
+    Handling(usize),
+    /// An escape point of `call/cc`, see [continuations](crate::continuations)
+    Escape { frame: i64, address: i64, id: i64 },
+    /// The after thunk of a `dynamic-wind`
+    Wind(i64),
+}
+
+/// The dynamic environment of a thread
+///
+/// Entries are pushed and popped in a strict stack discipline by the generated
+/// code, and control transfers to a guard or an escape point discard every
+/// entry above it. `value` is the object passed along with the most recent
+/// transfer and `pending` is a transfer waiting for after thunks to return.
+/// `errors` are the errors raised by the runtime with a [Status] other than
+/// [Status::Raised], by address.
+pub(crate) struct Dynamic {
+    pub stack: Vec<Entry>,
+    pub value: Object,
+    pub escapes: i64,
+    pending: Option<(usize, Target, Object)>,
+    errors: Vec<(i64, Status)>,
+}
+
+impl Dynamic {
+    pub(crate) const fn new() -> Self {
+        Dynamic {
+            stack: Vec::new(),
+            value: Object(0),
+            escapes: 0,
+            pending: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Transfer control to `target`, discarding the entry at `index` and
+    /// everything above it
+    ///
+    /// The after thunk of the innermost `dynamic-wind` on the way is returned
+    /// instead if there is one, and the transfer continues with [rt_unwind].
+    pub fn transfer(&mut self, index: usize, target: Target) -> Target {
+        let wind = (index..self.stack.len()).rev().find_map(|i| match self.stack[i] {
+            Entry::Wind(after) => Some((i, after)),
+            _ => None,
+        });
+
+        match wind {
+            Some((i, after)) => {
+                self.stack.truncate(i);
+                self.pending = Some((index, target, self.value));
+                Target { frame: UNWIND, address: after }
+            }
+            None => {
+                self.stack.truncate(index);
+                self.pending = None;
+                target
+            }
+        }
+    }
+
+    /// Continue the pending transfer after an after thunk returns
+    fn unwind(&mut self) -> Target {
+        let (index, target, value) = self.pending.take().expect("no pending transfer");
+        self.value = value;
+        self.transfer(index, target)
+    }
+
+    fn raise(&mut self, obj: Object) -> Target {
+        self.value = obj;
+
+        let mut i = self.stack.len();
+
+        while i > 0 {
+            i -= 1;
+
+            match self.stack[i] {
+                Entry::Guard { frame, address } => {
+                    return self.transfer(i, Target { frame, address });
+                }
+
+                Entry::Procedure(address) => {
+                    self.stack.push(Entry::Handling(i));
+                    return Target { frame: 0, address };
+                }
+
+                Entry::Handling(index) => i = index,
+
+                Entry::Escape { .. } | Entry::Wind(_) => {}
+            }
+        }
+
+        let status = self.errors.iter().find(|(address, _)| *address == obj.0);
+        uncaught(obj, status.map_or(Status::Raised, |(_, status)| *status))
+    }
+}
+
+thread_local! {
+    static DYNAMIC: RefCell<Dynamic> = const { RefCell::new(Dynamic::new()) };
+}
+
+/// Run `f` with the dynamic environment of the current thread
+pub(crate) fn dynamic<T>(f: impl FnOnce(&mut Dynamic) -> T) -> T {
+    DYNAMIC.with(|d| f(&mut d.borrow_mut()))
+}
+
+/// Clear the dynamic environment of the current thread, before running a
+/// program
+pub fn reset() {
+    dynamic(|d| *d = Dynamic::new())
+}
+
+/// Raise `obj` to the current handler
+pub fn raise_object(obj: Object) -> Target {
+    dynamic(|d| d.raise(obj))
+}
+
+/// Raise an error message as a string
+///
+/// The string lives outside the scheme heap and is never collected, which is
+/// fine for the rare error.
+pub fn error(message: &str) -> Target {
+    failure(Status::Raised, message)
+}
+
+/// Raise an error message, which exits with `status` if it isn't handled
+pub fn failure(status: Status, message: &str) -> Target {
+    let words = 1 + (message.len() + 8) / 8;
+    let mut data = vec![0i64; words].into_boxed_slice();
+
+    data[0] = message.len() as i64;
+    unsafe {
+        std::ptr::copy(message.as_ptr(), data[1..].as_mut_ptr() as *mut u8, message.len());
+    }
+
+    let obj = Object(Box::leak(data).as_ptr() as i64 | STR);
+
+    if status != Status::Raised {
+        dynamic(|d| d.errors.push((obj.0, status)));
+    }
+
+    raise_object(obj)
+}
+
+/// Report an object raised with no handler installed and exit
+fn uncaught(obj: Object, status: Status) -> ! {
+    match obj.deref() {
+        Expr::Literal(Literal::Str(message)) => eprintln!("Exception: {}", message),
+        _ => eprintln!("Exception: {}", obj),
+    }
+
+    status.exit()
+}
+
+/// Install a guard with the frame and the address of its clauses
+#[no_mangle]
+pub extern "C" fn rt_push_guard(frame: i64, address: i64) {
+    dynamic(|d| d.stack.push(Entry::Guard { frame, address }))
+}
+
+/// Install a procedure as the current exception handler
+#[no_mangle]
+pub extern "C" fn rt_push_handler(address: i64) {
+    dynamic(|d| d.stack.push(Entry::Procedure(address)))
+}
+
+/// Remove the current exception handler
+#[no_mangle]
+pub extern "C" fn rt_pop_handler() {
+    dynamic(|d| d.stack.pop());
+}
+
+/// Raise an object to the current handler
+#[no_mangle]
+pub extern "C" fn rt_raise(obj: Object) -> Target {
+    raise_object(obj)
+}
+
+/// Put the handlers back in effect after a procedure handler returns
+#[no_mangle]
+pub extern "C" fn rt_resume() {
+    dynamic(|d| d.stack.pop());
+}
+
+/// Continue the transfer of control after an after thunk of `dynamic-wind`
+#[no_mangle]
+pub extern "C" fn rt_unwind() -> Target {
+    dynamic(|d| d.unwind())
+}
+
+/// Raise an error to the next handler when a handler returns from `raise`
+#[no_mangle]
+pub extern "C" fn rt_handler_returned() -> Target {
+    error("handler returned from non-continuable raise")
+}
+
+/// The object raised or passed to a continuation most recently
+#[no_mangle]
+pub extern "C" fn rt_condition() -> Object {
+    dynamic(|d| d.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn handlers() {
+        let mut d = Dynamic::new();
+
+        d.stack.push(Entry::Guard { frame: 16, address: 1 });
+        d.stack.push(Entry::Procedure(2));
+        d.stack.push(Entry::Escape { frame: 32, address: 4, id: 0 });
+        d.stack.push(Entry::Procedure(3));
+
+        // Handlers are out of effect while they run
+        assert_eq!(d.raise(Object(8)), Target { frame: 0, address: 3 });
+        assert_eq!(d.raise(Object(8)), Target { frame: 0, address: 2 });
+        assert_eq!(d.stack.len(), 6);
+
+        // Escaping to the guard discards everything installed after it
+        assert_eq!(d.raise(Object(16)), Target { frame: 16, address: 1 });
+        assert_eq!(d.stack.len(), 0);
+        assert_eq!(d.value.0, 16);
+    }
+
+    #[test]
+    fn unwind() {
+        let mut d = Dynamic::new();
+
+        d.stack.push(Entry::Guard { frame: 16, address: 1 });
+        d.stack.push(Entry::Wind(2));
+        d.stack.push(Entry::Procedure(3));
+        d.stack.push(Entry::Wind(4));
+
+        // After thunks run innermost first, outside their own extent
+        assert_eq!(d.raise(Object(8)), Target { frame: 0, address: 3 });
+        assert_eq!(d.raise(Object(16)), Target { frame: UNWIND, address: 4 });
+        assert_eq!(d.stack.len(), 3);
+
+        // The value is restored even if an after thunk transfers another one
+        d.value = Object(24);
+        assert_eq!(d.unwind(), Target { frame: UNWIND, address: 2 });
+        assert_eq!(d.unwind(), Target { frame: 16, address: 1 });
+        assert_eq!(d.stack.len(), 0);
+        assert_eq!(d.value.0, 16);
+    }
+}
@@ -11,32 +11,91 @@
 //! subject.
 //!
 //! [guide]: https://blog.packagecloud.io/eng/2016/04/05/the-definitive-guide-to-linux-system-calls
+//!
+//! C functions can be bound at run time as well, without touching the
+//! compiler. `(load-shared-object "libm.so.6")` loads a library and
+//! `(foreign-procedure "cos" (double) double)` evaluates to a procedure
+//! calling `cos` with the arguments converted to the C [TYPES].
+//!
+//! ```scheme
+//! (load-shared-object "libm.so.6")
+//! (define cos (foreign-procedure "cos" (double) double))
+//! (cos 0.0)
+//! ```
+//!
+//! [lang](crate::lang) expands `foreign-procedure` into a lambda applying the
+//! primitive `%foreign-call` to the name, the encoded [signature] and the
+//! arguments. The function is looked up with `dlsym` and called by
+//! [rt_foreign_call] with all the integer arguments in general purpose
+//! registers and the floating point ones in vector registers, just like the
+//! System V ABI expects; a single function type with 6 of each works for any
+//! signature. Variadic functions like `printf` aren't supported.
 
 use crate::{
+    bignum::Big,
     compiler::{emit::eval, state::State},
-    core::Core,
-    core::Ident,
-    immediate,
+    core::{Core, Expr, Ident, Literal},
+    exceptions::Status,
+    gc, immediate,
+    immediate::*,
+    numbers::Number,
+    rt::{self, Object},
+    strings,
+    tags::{self, tag, untag},
     x86::{self, Reference::*, Register::*, ASM, WORDSIZE},
 };
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+};
+
+/// C types understood by foreign procedures, by code
+///
+/// `void` is valid only for the result and `string`, a pointer to the NUL
+/// terminated characters, only for the arguments.
+pub const TYPES: [&str; 8] =
+    ["void", "int", "long", "double", "float", "boolean", "char", "string"];
+
+thread_local! {
+    /// Handles of the libraries loaded with `load-shared-object`, in order
+    static LIBRARIES: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+}
+
+/// Room reserved for the result of a foreign procedure, enough for a flonum
+/// or a bignum of 64 bits
+const RESULT: i64 = 3 * WORDSIZE;
 
 /// Call a foreign function defined in Rust/C
 pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
-    let mut asm = ASM(vec![]);
+    let mut asm = ASM::default();
 
     if args.len() > 6 {
-        panic!("foreign function {} called with more than 6 arguments: {:?}", &name, args)
+        return s.fail(format!("foreign function {} called with more than 6 arguments", name));
     }
 
-    for (i, arg) in args.iter().enumerate() {
-        let target = x86::SYS_V[i];
+    // Evaluate all arguments into the stack first and then load them into the
+    // registers; evaluating an argument could clobber the registers or trigger
+    // a collection that moves the previous ones.
+    let mut values = vec![];
 
-        asm += match immediate::to(arg) {
-            Some(c) => x86::mov(Register(target), Const(c)).into(),
-            None => eval(s, &arg) + x86::mov(Register(target), Register(RAX)),
+    for arg in args {
+        match immediate::to(arg) {
+            Some(c) => values.push(Const(c)),
+            None => {
+                asm += eval(s, arg);
+                let slot = s.alloc();
+                asm += x86::save(Register(RAX), slot);
+                values.push(Relative(RBP + slot));
+            }
         }
     }
 
+    for (i, value) in values.iter().enumerate() {
+        asm += x86::mov(Register(x86::SYS_V[i]), value.clone());
+    }
+
+    s.dealloc(values.iter().filter(|v| matches!(v, Relative(_))).count() as i64);
+
     // Translate scheme names into runtime names
     // 1. On macos, function names must be prefixed an underscore like _init
     // 2. Replace =? into _eq (symbol=? -> symbol_eq)
@@ -50,16 +109,238 @@ pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
         format!("_{}", name.replace("-", "_").replace("=?", "_eq"))
     }
 
-    let name = rename(&name.mangle());
+    // The frame of a scheme function isn't necessarily aligned to 16 bytes as
+    // expected by the calling convention, so move the stack out of the way of
+    // the live slots and align it just like `runtime`. See docs in
+    // `lambda:call` for details on how this works.
+    asm + routine(s, &rename(&name.short()))
+}
 
-    // See docs in `lambda:call` for details on how this works.
-    if s.si != -WORDSIZE {
-        asm += x86::sub(RSP.into(), Const(-s.si));
-        asm += x86::call(&name);
-        asm += x86::add(RSP.into(), Const(-s.si));
+/// Call a function in the runtime from generated code, outside of any
+/// expression
+///
+/// Unlike [call], this doesn't assume anything about RSP and preserves every
+/// live slot above `s.si`; the stack is moved out of the way and aligned as
+/// expected by the calling convention.
+pub fn runtime(s: &State, name: &str) -> ASM {
+    routine(s, &symbol(name))
+}
+
+/// Call a routine emitted by the compiler itself, just like [runtime]
+pub fn routine(s: &State, label: &str) -> ASM {
+    x86::mov(Register(R11), Register(RBP))
+        + x86::add(Register(R11), Const(s.si + WORDSIZE))
+        + x86::mov(Register(RSP), Register(R11))
+        + x86::and(Register(RSP), Const(-16))
+        + x86::call(label)
+        + x86::mov(Register(RSP), Register(RBP))
+}
+
+/// Name of a runtime function as seen by the linker
+///
+/// On macos, function names must be prefixed an underscore like _init.
+pub fn symbol(name: &str) -> String {
+    if cfg!(target_os = "macos") {
+        format!("_{}", name)
     } else {
-        asm += x86::call(&name)
+        name.to_string()
+    }
+}
+
+/// Encode the types of a foreign procedure into a fixnum
+///
+/// The code of the result is in the lowest 4 bits followed by the codes of the
+/// arguments in order, 4 bits each.
+pub fn signature(args: &[String], result: &str) -> Result<i64, String> {
+    let code = |name: &str| match TYPES.iter().position(|t| *t == name) {
+        Some(code) => Ok(code as i64),
+        None => Err(format!("foreign-procedure: invalid type `{}`", name)),
+    };
+
+    let doubles = args.iter().filter(|t| *t == "double" || *t == "float").count();
+
+    if args.iter().any(|t| t == "void") || result == "string" {
+        return Err(format!(
+            "foreign-procedure: invalid signature ({}) {}",
+            args.join(" "),
+            result
+        ));
+    }
+
+    if args.len() - doubles > 6 || doubles > 8 {
+        return Err(format!(
+            "foreign-procedure: too many arguments ({}) {}",
+            args.join(" "),
+            result
+        ));
     }
 
+    args.iter()
+        .enumerate()
+        .try_fold(code(result)?, |sig, (i, t)| Ok(sig | code(t)? << (4 * (i + 1))))
+}
+
+/// Emit code for `(%foreign-call name signature args...)`
+///
+/// The arguments are evaluated into consecutive stack slots and the runtime
+/// reads them from there, after making room for the result on the heap.
+pub fn foreign(s: &mut State, name: &str, signature: i64, args: &[Core]) -> ASM {
+    let mut asm = ASM::default();
+    let first = s.si;
+
+    for arg in args {
+        asm += eval(s, arg);
+        asm += x86::save(Register(RAX), s.alloc());
+    }
+
+    asm += gc::alloc(s, Const(RESULT));
+    asm += strings::eval(s, name);
+    asm += x86::mov(Register(RDI), Register(RAX));
+    asm += x86::mov(Register(RSI), Const(signature));
+    asm += x86::mov(Register(RDX), Register(RBP));
+    asm += x86::add(Register(RDX), Const(first));
+    asm += x86::mov(Register(RCX), Const(args.len() as i64));
+    asm += runtime(s, "rt_foreign_call");
+
+    s.dealloc(args.len() as i64);
     asm
 }
+
+/// Load a shared library, making its functions available to foreign
+/// procedures
+#[cfg(feature = "native")]
+#[no_mangle]
+pub extern "C" fn rt_load_shared_object(path: Object) -> Object {
+    let path = match path.deref() {
+        Expr::Literal(Literal::Str(path)) => path,
+        _ => error("load-shared-object", &format!("expected string, got {}", path)),
+    };
+
+    let name = CString::new(path.as_str()).unwrap();
+    let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) };
+
+    if handle.is_null() {
+        let reason = unsafe { CStr::from_ptr(libc::dlerror()) };
+        error("load-shared-object", &reason.to_string_lossy())
+    }
+
+    LIBRARIES.with(|libraries| libraries.borrow_mut().push(handle as usize));
+
+    Object::new(NIL)
+}
+
+/// Call the C function `name` with `count` arguments starting at `args` and
+/// going down the stack
+///
+/// `signature` is the encoded types of the function, see [signature]. There
+/// must be room for [RESULT] bytes at the heap pointer.
+///
+/// # Safety
+///
+/// Must be called only from generated code, with `count` valid stack slots
+/// at `args`. The C function must match the signature.
+#[cfg(feature = "native")]
+#[no_mangle]
+pub unsafe extern "C" fn rt_foreign_call(
+    name: Object,
+    signature: i64,
+    args: *const i64,
+    count: i64,
+) -> Object {
+    let name = CStr::from_ptr((name.0 - STR + WORDSIZE) as *const libc::c_char);
+    let name = name.to_string_lossy();
+    let symbol = CString::new(name.as_ref()).unwrap();
+    let address = lookup(&symbol);
+
+    if address.is_null() {
+        error(&name, "no such foreign procedure")
+    }
+
+    let mut ints = [0i64; 6];
+    let mut doubles = [0f64; 8];
+    let (mut i, mut d) = (0, 0);
+
+    for k in 0..count {
+        let val = Object::new(*args.offset(-k as isize));
+        let t = TYPES[(signature >> (4 * (k + 1)) & 15) as usize];
+        let expected = || -> ! { error(&name, &format!("expected {}, got {}", t, val)) };
+
+        match t {
+            "double" | "float" => {
+                let x = Number::decode(val).map(|n| n.inexact()).unwrap_or_else(|| expected());
+
+                // A float is passed in the low half of the vector register
+                doubles[d] =
+                    if t == "float" { f64::from_bits((x as f32).to_bits() as u64) } else { x };
+                d += 1;
+            }
+            _ => {
+                ints[i] = match t {
+                    "int" | "long" if tag(val.0) == NUM => untag(val.0),
+                    "boolean" => (val.0 != FALSE) as i64,
+                    "char" if tag(val.0) == CHAR => untag(val.0),
+                    "string" if rt::is_string(val.0) => val.0 - STR + WORDSIZE,
+                    _ => expected(),
+                };
+                i += 1;
+            }
+        }
+    }
+
+    match TYPES[(signature & 15) as usize] {
+        "double" => Number::Inexact(invoke(address, ints, doubles)).encode(),
+        "float" => {
+            let x: f64 = invoke(address, ints, doubles);
+            Number::Inexact(f32::from_bits(x.to_bits() as u32) as f64).encode()
+        }
+        result => {
+            let x: i64 = invoke(address, ints, doubles);
+
+            match result {
+                "void" => Object::new(NIL),
+                "int" => Object::immediate(x as i32 as i64),
+                "long" => Big::from(x).encode(),
+                "boolean" => Object::new(if x as u8 != 0 { TRUE } else { FALSE }),
+                "char" => Object::new(tags::immediate(x as u8 as i64, CHAR)),
+                t => unreachable!("Invalid result type {}", t),
+            }
+        }
+    }
+}
+
+/// Find a C function in the loaded libraries, then anywhere in the process
+///
+/// The functions of a compiled program are visible to `dlsym` as well and
+/// shadow C functions of the same name, unless they come from a library loaded
+/// with `load-shared-object`.
+#[cfg(feature = "native")]
+fn lookup(symbol: &CStr) -> *mut libc::c_void {
+    LIBRARIES.with(|libraries| {
+        libraries
+            .borrow()
+            .iter()
+            .map(|handle| unsafe { libc::dlsym(*handle as *mut libc::c_void, symbol.as_ptr()) })
+            .find(|address| !address.is_null())
+            .unwrap_or_else(|| unsafe { libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()) })
+    })
+}
+
+/// Call a C function with 6 integer and 8 floating point arguments
+///
+/// The function may take any subset of them, extra registers are ignored.
+#[cfg(feature = "native")]
+unsafe fn invoke<T>(address: *mut libc::c_void, i: [i64; 6], d: [f64; 8]) -> T {
+    type Function<T> =
+        extern "C" fn(i64, i64, i64, i64, i64, i64, f64, f64, f64, f64, f64, f64, f64, f64) -> T;
+
+    let f: Function<T> = std::mem::transmute(address);
+    f(i[0], i[1], i[2], i[3], i[4], i[5], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7])
+}
+
+/// Report an error in a foreign procedure and exit
+// Foreign functions can't transfer control to a handler, so these errors
+// can't be caught
+fn error(name: &str, message: &str) -> ! {
+    eprintln!("Exception: {}: {}", name, message);
+    Status::Raised.exit()
+}
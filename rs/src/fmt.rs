@@ -0,0 +1,473 @@
+//! A formatter for Scheme source
+//!
+//! `inc fmt` lays out a program the same way every time, whatever shape it
+//! was in, with the usual rules for Lisp:
+//!
+//! - A form that fits in the rest of the line stays on it, except for the
+//!   definition of a function, which always starts its body on the next line.
+//! - Forms with a body like `define`, `lambda` and `let` keep their name,
+//!   formals or bindings on the first line and indent the body by two columns.
+//!   So does `cond`, with each clause on a line of its own.
+//! - Any other list lines up its arguments under the first one, or its items
+//!   under the first one if that is a list or the list is quoted data.
+//! - Comments stay where they were, on a line of their own or at the end of
+//!   one, and so do blank lines between forms, squashed into one.
+//!
+//! ```
+//! let source = "(define (f x) (let ((y (* x 2)))\n\n\n (+ y 1)))";
+//! let formatted = "(define (f x)\n  (let ((y (* x 2))) (+ y 1)))\n";
+//! assert_eq!(inc::fmt::format(source).unwrap(), formatted);
+//! ```
+//!
+//! The [parser](crate::parser) throws comments and layout away, so the
+//! formatter [reads](read) the source into [Node]s of its own, which keep
+//! everything but the whitespace. A file that doesn't read, with a list left
+//! open or an unterminated string, isn't formatted at all.
+
+use crate::cli::WIDTH;
+
+/// A part of the source, as the formatter sees it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// A symbol, number, string, character or boolean, with any quotes before
+    /// it
+    Atom(String),
+    /// A list with what opens it, like `(`, `'(` or `#(`, and what closes it
+    List { open: String, items: Vec<Node>, close: char },
+    /// A comment, and whether it ends a line with code on it
+    Comment { text: String, trailing: bool },
+    /// Blank lines between two forms
+    Blank,
+}
+
+/// Forms with a body, and how many forms before the body stay on the first
+/// line
+const BODIES: [(&str, usize); 15] = [
+    ("define", 1),
+    ("define-syntax", 1),
+    ("lambda", 1),
+    ("let", 1),
+    ("let*", 1),
+    ("letrec", 1),
+    ("letrec*", 1),
+    ("when", 1),
+    ("unless", 1),
+    ("case", 1),
+    ("guard", 1),
+    ("do", 2),
+    ("syntax-rules", 1),
+    ("define-library", 1),
+    ("cond", 0),
+];
+
+/// Format a program, or report why it can't be read
+pub fn format(source: &str) -> Result<String, String> {
+    let nodes = read(source)?;
+    let mut out = String::new();
+    let mut blank = false;
+
+    for node in &nodes {
+        match node {
+            Node::Blank => blank = !out.is_empty(),
+            Node::Comment { text, trailing: true } => {
+                out.push(' ');
+                out.push_str(text);
+            }
+            node => {
+                if !out.is_empty() {
+                    out.push('\n');
+                    if blank {
+                        out.push('\n');
+                    }
+                }
+                blank = false;
+                write(node, &mut out);
+            }
+        }
+    }
+
+    if !out.is_empty() {
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Read the source into nodes
+///
+/// A `#!` line at the start is kept as a comment, for scripts.
+pub fn read(source: &str) -> Result<Vec<Node>, String> {
+    let bytes = source.as_bytes();
+    let delimiter = |b: u8| b.is_ascii_whitespace() || b"()[]\";".contains(&b);
+    let line = |i: usize| source[..i].matches('\n').count() + 1;
+
+    // The items of the lists still open, with what opened them and where
+    let mut open: Vec<(String, Vec<Node>, usize)> = vec![(String::new(), vec![], 0)];
+    let mut prefix = String::new();
+    let mut newlines = 0;
+    let mut i = 0;
+
+    if source.starts_with("#!") {
+        i = source.find('\n').unwrap_or(source.len());
+        let text = source[..i].trim_end().to_string();
+        open[0].1.push(Node::Comment { text, trailing: false });
+    }
+
+    while i < bytes.len() {
+        let start = i;
+
+        if bytes[i].is_ascii_whitespace() {
+            newlines += usize::from(bytes[i] == b'\n');
+            i += 1;
+            continue;
+        }
+
+        let items = &mut open.last_mut().unwrap().1;
+        if newlines > 1 && !items.is_empty() {
+            items.push(Node::Blank);
+        }
+        let trailing = newlines == 0 && !items.is_empty();
+        newlines = 0;
+
+        match bytes[i] {
+            b';' => {
+                i = source[i..].find('\n').map_or(bytes.len(), |n| i + n);
+                let text = source[start..i].trim_end().to_string();
+                items.push(Node::Comment { text, trailing });
+            }
+            b'\'' | b'`' => {
+                prefix.push(bytes[i] as char);
+                i += 1;
+            }
+            b',' => {
+                i += if source[i..].starts_with(",@") { 2 } else { 1 };
+                prefix.push_str(&source[start..i]);
+            }
+            b'(' | b'[' => {
+                i += 1;
+                open.push((prefix.clone() + &source[start..i], vec![], start));
+                prefix.clear();
+            }
+            b'#' if source[i..].starts_with("#(") => {
+                i += 2;
+                open.push((prefix.clone() + "#(", vec![], start));
+                prefix.clear();
+            }
+            b')' | b']' => {
+                if open.len() == 1 {
+                    return Err(format!("Unexpected `{}` on line {}", bytes[i] as char, line(i)));
+                }
+                let close = bytes[i] as char;
+                i += 1;
+                let (open_with, items, _) = open.pop().unwrap();
+                open.last_mut().unwrap().1.push(Node::List { open: open_with, items, close });
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                if i >= bytes.len() {
+                    return Err(format!("Unterminated string on line {}", line(start)));
+                }
+                i += 1;
+                items.push(Node::Atom(prefix.clone() + &source[start..i]));
+                prefix.clear();
+            }
+            _ => {
+                // A character like #\( is a single token, delimiter included
+                if source[i..].starts_with("#\\") && i + 2 < bytes.len() {
+                    i += 3;
+                }
+                while i < bytes.len() && !delimiter(bytes[i]) {
+                    i += 1;
+                }
+                while !source.is_char_boundary(i) {
+                    i += 1;
+                }
+                items.push(Node::Atom(prefix.clone() + &source[start..i]));
+                prefix.clear();
+            }
+        }
+    }
+
+    if let Some((_, _, start)) = open.get(1) {
+        return Err(format!("Unclosed list from line {}", line(*start)));
+    }
+    if !prefix.is_empty() {
+        return Err(format!("Expected a form after `{}`", prefix));
+    }
+
+    Ok(open.pop().unwrap().1)
+}
+
+impl Node {
+    /// The node on a single line, if it can be
+    pub(crate) fn flat(&self) -> Option<String> {
+        match self {
+            Node::Atom(text) => Some(text.clone()),
+            Node::List { open, items, close } => {
+                let mut forms = vec![];
+                for item in items {
+                    match item {
+                        Node::Comment { .. } => return None,
+                        Node::Blank => {}
+                        form => forms.push(form.flat()?),
+                    }
+                }
+                Some(format!("{}{}{}", open, forms.join(" "), close))
+            }
+            Node::Comment { .. } | Node::Blank => None,
+        }
+    }
+
+    const fn is_form(&self) -> bool {
+        matches!(self, Node::Atom(_) | Node::List { .. })
+    }
+}
+
+/// Write a form at the end of `out`, in the column it is left at
+fn write(node: &Node, out: &mut String) {
+    let (open, items, close) = match node {
+        Node::List { open, items, close } => (open, items, close),
+        Node::Atom(text) => return out.push_str(text),
+        Node::Comment { text, .. } => return out.push_str(text),
+        Node::Blank => return,
+    };
+
+    let col = column(out);
+    let forms: Vec<&Node> = items.iter().filter(|item| item.is_form()).collect();
+    let head = match forms.first() {
+        Some(Node::Atom(name)) if open == "(" || open == "[" => Some(name.as_str()),
+        _ => None,
+    };
+
+    // A function definition always breaks before its body
+    let definition = head == Some("define") && matches!(forms.get(1), Some(Node::List { .. }));
+
+    if let Some(flat) = node.flat() {
+        if !definition && !flat.contains('\n') && col + flat.chars().count() <= WIDTH {
+            return out.push_str(&flat);
+        }
+    }
+
+    let inner = col + open.chars().count();
+
+    // How many forms go on the first line, and where the rest are indented
+    let (first, indent) = match head {
+        Some(name) => match BODIES.iter().find(|(n, _)| *n == name) {
+            // A named let has a name before the bindings
+            Some(_) if name == "let" && matches!(forms.get(1), Some(Node::Atom(_))) => {
+                (3, inner + 1)
+            }
+            Some((_, n)) => (n + 1, inner + 1),
+            None => (2, inner + name.chars().count() + 1),
+        },
+        None => (1, inner),
+    };
+
+    out.push_str(open);
+
+    let mut count = 0;
+    let mut blank = false;
+    let mut newline = false;
+
+    for item in items {
+        match item {
+            Node::Blank => blank = true,
+            Node::Comment { text, trailing: true } if count > 0 => {
+                out.push(' ');
+                out.push_str(text);
+                newline = true;
+            }
+            Node::Comment { text, .. } => {
+                if count > 0 || newline {
+                    line(out, if count > 0 { indent } else { inner }, blank);
+                }
+                blank = false;
+                out.push_str(text);
+                newline = true;
+            }
+            form => {
+                if count == 0 {
+                    if newline {
+                        line(out, inner, false);
+                    }
+                } else if count < first && !newline {
+                    out.push(' ');
+                } else {
+                    line(out, indent, blank);
+                }
+                write(form, out);
+                count += 1;
+                blank = false;
+                newline = false;
+            }
+        }
+    }
+
+    // The list can't close on the line of a comment
+    if newline {
+        line(out, if count > 0 { indent } else { inner }, false);
+    }
+    out.push(*close);
+}
+
+/// Start a new line at `indent`, after a blank one if asked for
+fn line(out: &mut String, indent: usize, blank: bool) {
+    if blank {
+        out.push('\n');
+    }
+    out.push('\n');
+    out.push_str(&" ".repeat(indent));
+}
+
+/// The column the end of `out` is at
+fn column(out: &str) -> usize {
+    out.rsplit('\n').next().unwrap_or_default().chars().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use pretty_assertions::assert_eq;
+
+    fn check(source: &str, expected: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted, "Formatting isn't stable");
+    }
+
+    #[test]
+    fn nodes() {
+        let nodes = read("'(a ,@b) ; c\n\n\n#(1 #\\) \"d)\")").unwrap();
+
+        assert_eq!(
+            nodes,
+            vec![
+                Node::List {
+                    open: String::from("'("),
+                    items: vec![Node::Atom(String::from("a")), Node::Atom(String::from(",@b"))],
+                    close: ')',
+                },
+                Node::Comment { text: String::from("; c"), trailing: true },
+                Node::Blank,
+                Node::List {
+                    open: String::from("#("),
+                    items: vec![
+                        Node::Atom(String::from("1")),
+                        Node::Atom(String::from("#\\)")),
+                        Node::Atom(String::from("\"d)\""))
+                    ],
+                    close: ')',
+                },
+            ]
+        );
+
+        assert_eq!(read("(a (b)").unwrap_err(), "Unclosed list from line 1");
+        assert_eq!(read("(a)\n)").unwrap_err(), "Unexpected `)` on line 2");
+        assert_eq!(read("\n\"abc").unwrap_err(), "Unterminated string on line 2");
+        assert_eq!(read("(a) '").unwrap_err(), "Expected a form after `'`");
+    }
+
+    #[test]
+    fn layout() {
+        check("  (+   1\n 2)", "(+ 1 2)\n");
+        check("(define (twice x) (* x 2))", "(define (twice x)\n  (* x 2))\n");
+        check("(define answer    42)", "(define answer 42)\n");
+        check("", "");
+
+        let long = "(define (f x) (let ((first-value (compute x 1)) (second-value (compute x 2))) \
+                    (+ first-value second-value)))";
+        let expected = "\
+(define (f x)
+  (let ((first-value (compute x 1)) (second-value (compute x 2)))
+    (+ first-value second-value)))
+";
+        check(long, expected);
+
+        let long = "(let loop ((i 0) (accumulated-values (quote ())) (remaining-values input-list) (n 1)) \
+                    (if (null? remaining-values) (reverse accumulated-values) \
+                    (loop (+ i 1) (cons (car remaining-values) accumulated-values) (cdr remaining-values))))";
+        let expected = "\
+(let loop ((i 0)
+           (accumulated-values (quote ()))
+           (remaining-values input-list)
+           (n 1))
+  (if (null? remaining-values)
+      (reverse accumulated-values)
+      (loop (+ i 1)
+            (cons (car remaining-values) accumulated-values)
+            (cdr remaining-values))))
+";
+        check(long, expected);
+
+        let cond = "(define (sign x) (cond ((> x 0) 'positive) ((< x 0) 'negative) (else 'zero)))";
+        let expected = "\
+(define (sign x)
+  (cond ((> x 0) 'positive) ((< x 0) 'negative) (else 'zero)))
+";
+        check(cond, expected);
+
+        let data = "'(alpha-value beta-value gamma-value delta-value epsilon-value zeta-value eta-value)";
+        let expected = "\
+'(alpha-value
+  beta-value
+  gamma-value
+  delta-value
+  epsilon-value
+  zeta-value
+  eta-value)
+";
+        check(data, expected);
+    }
+
+    #[test]
+    fn comments() {
+        let source = "\
+#!/usr/bin/env inc
+; The answer
+
+
+(define (f x) ; f
+  ; twice
+  (* x 2)
+
+  ; ignored
+  x)
+(f 21) ; 42
+";
+        let expected = "\
+#!/usr/bin/env inc
+; The answer
+
+(define (f x) ; f
+  ; twice
+  (* x 2)
+
+  ; ignored
+  x)
+(f 21) ; 42
+";
+        check(source, expected);
+
+        check("(a ; b\n)", "(a ; b\n   )\n");
+        check("(; a\n b)", "(; a\n b)\n");
+    }
+
+    #[test]
+    fn programs() {
+        let sources = [
+            include_str!("prelude.ss"),
+            "(define (f x)\n(guard (e (#t (display \"caught\") 'error))\n(raise 'oops)))",
+        ];
+
+        for source in sources.iter() {
+            let formatted = format(source).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted);
+            assert_eq!(parser::parse(&formatted).unwrap(), parser::parse(source).unwrap());
+            assert!(formatted.lines().all(|line| line == line.trim_end()));
+        }
+    }
+}
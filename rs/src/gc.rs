@@ -0,0 +1,1103 @@
+//! Garbage collection
+//!
+//! Inc uses a generational copying collector. Most objects die young, so new
+//! objects are allocated in a small nursery and the few that survive a
+//! collection are promoted to a larger old generation. Collecting the nursery
+//! alone (a *minor* collection) is cheap since it only touches the live young
+//! objects.
+//!
+//! The old generation is managed with a two space copying collector as
+//! described by C. J. Cheney in [A Nonrecursive List Compacting
+//! Algorithm][cheney]. When the old generation runs out of space, every object
+//! reachable from the stack is copied into the other half and the roles of the
+//! two are swapped (a *major* collection). Garbage is never touched, so the cost
+//! of a collection is proportional to the live data alone.
+//! The first word of a copied object is overwritten with its new address, so
+//! later references to it are forwarded there without any other bookkeeping.
+//!
+//! # Allocation
+//!
+//! Allocation is inline and cheap; objects are allocated by bumping the heap
+//! pointer in R12, the end of the nursery is kept in R13 and the slow path
+//! calls [gc_collect] only if the object wouldn't fit.
+//!
+//! ```asm
+//!     mov r11, r12
+//!     add r11, 16
+//!     cmp r11, r13
+//!     jle alloc_1
+//!     ...               ; Spill stack frame and call gc_collect
+//! alloc_1:
+//!     mov [r12], rax    ; Heap has room for 16 bytes now
+//! ```
+//!
+//! Objects larger than the nursery are allocated in the old generation
+//! directly.
+//!
+//! # Write barrier
+//!
+//! A minor collection doesn't look at the old generation, so it would miss
+//! young objects referenced only from old ones. Objects are initialized right
+//! after allocation and can only point to older objects, except when mutated
+//! with `set-car!`, `set-cdr!` or `vector-set!`. Code generated for these
+//! primitives calls
+//! [gc_remember] with the address of the updated slot, which is recorded in a
+//! *remembered set* if it points from the old generation into the nursery.
+//! Minor collections treat the remembered set as additional roots.
+//!
+//! # Stack maps
+//!
+//! The collector must know exactly which stack slots hold scheme values. The
+//! code generator maintains a simple invariant that serves as a stack map for
+//! every frame: all the slots from `RBP - 8` down to the stack index `si` hold
+//! valid scheme values at any point where a collection could happen. Values
+//! are always written to a slot before the slot is counted as allocated and
+//! nothing is kept in registers across an allocation.
+//!
+//! The live part of the innermost frame is passed to the collector explicitly.
+//! For every other frame, the live slots end right above the return address of
+//! the frame it called, so walking the chain of saved base pointers up to the
+//! frame of `init` (saved in R14) finds every root.
+//!
+//! ```text
+//!      ...
+//!      [rbp + 16]   <- Last live slot of caller
+//!      [rbp + 8]    Return address
+//!      [rbp]        Saved RBP of the caller
+//!      [rbp - 8]    First live slot
+//!      ...
+//!      [rbp + si + 8]  Last live slot, passed in as `top`
+//! ```
+//!
+//! # Heap size
+//!
+//! The old generation starts with [HEAP_SIZE] words in each half and grows by
+//! a constant factor whenever it is more than half full after a major
+//! collection, up to a hard limit. Programs that need more than the limit
+//! exit with a clear "Out of memory" error and [Status::Memory]. All three are configurable when
+//! a program starts, see [Policy], and the limit can be changed at run time
+//! with `(heap-limit n)`.
+//!
+//! # Weak references
+//!
+//! `(make-weak-box obj)` holds on to an object without keeping it alive. A weak
+//! box is just a vector `#(weak-box obj)` like ports, except that the collector
+//! skips the value when copying the box and updates it at the very end instead,
+//! to the new address if something else kept the object alive or to `#f` if
+//! not. `(weak-box-value box)` reads it back.
+//!
+//! A guardian is told about objects with `(guardian-register! g obj)` and
+//! hands them back with `(guardian-next g)` once they are no longer reachable
+//! otherwise, so that a program can release any resources held by them. The
+//! runtime keeps a table of all the guardians of the heap, see [Guardian]. The
+//! collector saves the unreachable objects from being reclaimed and queues
+//! them in the guardian, where they stay alive until they are fetched.
+//! `guardian-next` evaluates to `#f` when the queue is empty.
+//!
+//! ```scheme
+//! (let ((g (make-guardian))
+//!       (x (guardian-register! g (open-input-file "data.txt")))
+//!       (y (gc)))
+//!   (close-port (guardian-next g)))
+//! ```
+//!
+//! A minor collection doesn't look at the old generation, so objects already
+//! promoted are reported at the next major collection. Old weak boxes keep
+//! young objects alive until then as well.
+//!
+//! # Finalizers
+//!
+//! `(register-finalizer obj f)` calls the function `f` with `obj` once the
+//! object is unreachable, to close a port or free memory allocated by a C
+//! library for example. Finalizers are kept in a guardian of their own along
+//! with the address of the function. The collector can't run any code itself,
+//! so the allocation that triggered the collection calls them when it is
+//! done, one at a time, and then tries to allocate again; `(gc)` runs them
+//! right away as well. Every allocation is a safe point as far as the
+//! generated code is concerned; nothing is kept in registers across one. An
+//! error raised by a finalizer is raised from the allocation.
+//!
+//! # Statistics
+//!
+//! `(gc)` runs a major collection right away, `(heap-used)` evaluates to the
+//! bytes taken by objects in the heap, live or not, and `(gc-stats)` to an
+//! association list of the counters in [STATS]. Sizes are in bytes and pause
+//! times in microseconds. Setting `GC_LOG` in the environment when a program
+//! starts prints a line for every collection to stderr, and `INC_STATS` the
+//! counters once the program is done, which is what `inc bench` reads.
+//!
+//! ```text
+//! gc: minor collection 3, 1360 of 8192 bytes in use, 21us
+//! ```
+//!
+//! # Conservative mode
+//!
+//! Precise stack maps rely on every part of the code generator following the
+//! rules above. As a fallback, setting `INC_GC=conservative` in the environment
+//! when a program starts switches to scanning the stack conservatively; every
+//! word between the innermost live slot and the frame of `init` that looks like
+//! a reference to an object in the heap is treated as a root. Return addresses
+//! and saved base pointers never point into the heap, so the frame layout is
+//! not needed at all.
+//!
+//! [cheney]: https://dl.acm.org/doi/10.1145/362790.362798
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::{Core, Ident},
+    exceptions::Status,
+    ffi,
+    immediate::*,
+    lambda,
+    numbers::BOXED,
+    rt::Object,
+    symbols, threads,
+    tags::{self, tag, untag},
+    x86::{self, Reference, Register::*, ASM, WORDSIZE},
+};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    env,
+    ops::Range,
+    ptr,
+    time::{Duration, Instant},
+};
+
+/// Initial size of each half of the old generation in words
+pub const HEAP_SIZE: usize = 1024;
+
+/// Factor the old generation grows by when it is running out of space
+pub const HEAP_GROWTH: usize = 2;
+
+/// Default hard limit of each half of the old generation in words, 128MB
+pub const HEAP_LIMIT: usize = 1 << 24;
+
+/// Size of the nursery in words
+pub const NURSERY_SIZE: usize = 256;
+
+/// Marks the first word of a copied object, which holds its new value then
+///
+/// Headers of vectors and strings are never negative and neither are the
+/// references in the car of a pair, so the sign bit is free.
+const FORWARDED: i64 = i64::MIN;
+
+/// Counters reported by `(gc-stats)` in order, see [gc_stat]
+pub const STATS: [&str; 8] =
+    ["collections", "minor", "major", "allocated", "used", "size", "pause", "max-pause"];
+
+/// Free space in the heap after an allocation, returned in RAX & RDX
+#[repr(C)]
+pub struct Space {
+    pub pointer: *mut i64,
+    pub limit: *mut i64,
+}
+
+/// How the collector finds roots in the stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Walk the frames using the layout described in the module docs
+    Precise,
+    /// Treat every word that looks like a heap reference as a root
+    Conservative,
+}
+
+impl Mode {
+    /// Mode selected with the `INC_GC` environment variable
+    pub fn from_env() -> Self {
+        match env::var("INC_GC").as_deref() {
+            Ok("conservative") => Mode::Conservative,
+            _ => Mode::Precise,
+        }
+    }
+}
+
+/// Sizing of the old generation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// Initial size of each half in words
+    pub size: usize,
+    /// Factor to grow by
+    pub growth: usize,
+    /// Hard limit of each half in words
+    pub limit: usize,
+}
+
+impl Policy {
+    /// Use explicit settings where given (non zero) or fall back to the
+    /// `INC_HEAP_SIZE`, `INC_HEAP_GROWTH` and `INC_HEAP_LIMIT` environment
+    /// variables and finally the defaults.
+    pub fn new(size: usize, growth: usize, limit: usize) -> Self {
+        fn setting(value: usize, var: &str, default: usize) -> usize {
+            match value {
+                0 => env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default),
+                _ => value,
+            }
+        }
+
+        let limit = setting(limit, "INC_HEAP_LIMIT", HEAP_LIMIT);
+
+        Policy {
+            size: setting(size, "INC_HEAP_SIZE", HEAP_SIZE).min(limit),
+            growth: setting(growth, "INC_HEAP_GROWTH", HEAP_GROWTH).max(1),
+            limit,
+        }
+    }
+}
+
+/// Label of the routine running pending finalizers, see [finalize]
+pub const FINALIZE: &str = "inc_finalize";
+
+/// Objects registered with a guardian, each with the address of a finalizer
+///
+/// The first guardian of the heap holds the objects with finalizers, the
+/// address is 0 for the rest.
+#[derive(Default)]
+struct Guardian {
+    // Objects to hand back once they are unreachable, held weakly
+    registered: Vec<(i64, i64)>,
+    // Unreachable objects not yet fetched with `guardian-next` or finalized
+    collected: VecDeque<(i64, i64)>,
+}
+
+/// An unreachable object and its finalizer, returned in RAX & RDX
+#[repr(C)]
+pub struct Finalizer {
+    pub obj: Object,
+    pub address: i64,
+}
+
+/// Work done by the collector so far
+#[derive(Default)]
+struct Stats {
+    minor: usize,
+    major: usize,
+    // Bytes allocated before the last collection or in the old generation
+    allocated: usize,
+    pause: Duration,
+    max: Duration,
+}
+
+struct Heap {
+    mode: Mode,
+    // Print every collection to stderr
+    log: bool,
+    policy: Policy,
+    nursery: Vec<i64>,
+    // Words used in the nursery, saved when allocating a large object
+    young: usize,
+    from: Vec<i64>,
+    to: Vec<i64>,
+    // Words used in the old generation
+    old: usize,
+    // Slots in the old generation that may point into the nursery
+    remembered: Vec<*mut i64>,
+    // Current end of the heap, same as R13
+    limit: usize,
+    // Number of collections so far
+    epoch: usize,
+    stats: Stats,
+    // Guardians by ID, see `gc_guardian`
+    guardians: Vec<Guardian>,
+}
+
+thread_local! {
+    // Every thread gets its own heap so that programs can run concurrently
+    // with the JIT.
+    static HEAP: RefCell<Option<Heap>> = const { RefCell::new(None) };
+}
+
+/// Emit code to ensure there is space for `size` bytes at R12
+///
+/// The size is either a constant or a stack slot. Registers are not preserved
+/// when the collector runs; all live values must be in stack slots above
+/// `s.si`. Pending finalizers run right after a collection, see [finalize].
+pub fn alloc(s: &mut State, size: Reference) -> ASM {
+    let (retry, ok) = (s.gen_label("retry"), s.gen_label("alloc"));
+
+    x86::label(&retry)
+        + x86::mov(R11.into(), R12.into())
+        + x86::add(R11.into(), size.clone())
+        + x86::cmp(R11.into(), R13.into())
+        + x86::jle(&ok)
+        + x86::mov(RDI.into(), size)
+        + x86::mov(RSI.into(), RBP.into())
+        + x86::add(RSI.into(), (s.si + WORDSIZE).into())
+        + x86::mov(RDX.into(), RBP.into())
+        + x86::mov(RCX.into(), R14.into())
+        + x86::mov(R8.into(), R12.into())
+        + ffi::runtime(s, "gc_collect")
+        + x86::mov(R12.into(), RAX.into())
+        + x86::mov(R13.into(), RDX.into())
+        + x86::save(Reference::Const(0), s.si)
+        + ffi::routine(s, FINALIZE)
+        + x86::jmp(&retry)
+        + x86::label(&ok)
+}
+
+/// The routine calling every pending finalizer, emitted once per program
+///
+/// Each finalizer is called exactly like a procedure handler by
+/// [dispatch](crate::exceptions::dispatch), with the stack aligned below the
+/// live frame of the allocation.
+pub fn finalize() -> ASM {
+    let (next, done) = (format!("{}_next", FINALIZE), format!("{}_done", FINALIZE));
+
+    x86::label(FINALIZE)
+        + x86::enter()
+        + x86::label(&next)
+        + x86::call(&ffi::symbol("gc_finalizer"))
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::cmp(RDX.into(), Reference::Const(0))
+        + x86::je(&done)
+        + x86::save(Reference::Const(0), -WORDSIZE)
+        + x86::save(RAX.into(), -4 * WORDSIZE)
+        + x86::sub(RSP.into(), Reference::Const(WORDSIZE))
+        + x86::call_indirect(RDX)
+        + x86::mov(RSP.into(), RBP.into())
+        + x86::jmp(&next)
+        + x86::label(&done)
+        + x86::leave()
+}
+
+/// Emit code for `(register-finalizer obj f)`
+pub fn register(s: &mut State, obj: &Core, f: &Ident) -> ASM {
+    lambda::function(s, "register-finalizer", f);
+
+    eval(s, obj)
+        + x86::mov(RDI.into(), RAX.into())
+        + x86::lea(RSI, &f.mangle(), 0)
+        + ffi::runtime(s, "gc_register_finalizer")
+}
+
+/// Emit code for `(gc)`, a major collection right away followed by any
+/// pending finalizers
+pub fn collect(s: &mut State) -> ASM {
+    x86::mov(RDI.into(), RBP.into())
+        + x86::add(RDI.into(), (s.si + WORDSIZE).into())
+        + x86::mov(RSI.into(), RBP.into())
+        + x86::mov(RDX.into(), R14.into())
+        + x86::mov(RCX.into(), R12.into())
+        + ffi::runtime(s, "gc_force")
+        + x86::mov(R12.into(), RAX.into())
+        + x86::mov(R13.into(), RDX.into())
+        + x86::save(Reference::Const(0), s.si)
+        + ffi::routine(s, FINALIZE)
+        + x86::mov(RAX.into(), NIL.into())
+}
+
+/// Emit code for `(%gc-stat k)`, the counter at index `k` of [STATS]
+pub fn stat(s: &mut State, k: &Core) -> ASM {
+    eval(s, k)
+        + x86::mov(RDI.into(), RAX.into())
+        + x86::mov(RSI.into(), R12.into())
+        + ffi::runtime(s, "gc_stat")
+}
+
+/// Emit the write barrier for a slot updated with a new value, address in RDI
+pub fn barrier(s: &mut State) -> ASM {
+    ffi::runtime(s, "gc_remember")
+}
+
+/// Create a fresh heap for the current thread and return the nursery
+///
+/// The initial size of each half of the old generation, the growth factor and
+/// the hard limit in words default to the environment if 0. See [Policy].
+#[no_mangle]
+pub extern "C" fn gc_init(size: usize, growth: usize, limit: usize) -> Space {
+    let policy = Policy::new(size, growth, limit);
+
+    let mut heap = Heap {
+        mode: Mode::from_env(),
+        log: env::var_os("GC_LOG").is_some(),
+        policy,
+        nursery: vec![0; NURSERY_SIZE],
+        young: 0,
+        from: vec![0; policy.size],
+        to: vec![0; policy.size],
+        old: 0,
+        remembered: vec![],
+        limit: 0,
+        epoch: 0,
+        stats: Stats::default(),
+        guardians: vec![Guardian::default()],
+    };
+
+    let space = heap.nursery();
+
+    HEAP.with(|h| *h.borrow_mut() = Some(heap));
+    space
+}
+
+/// The nursery of the current thread, creating the heap if there is none yet
+///
+/// Entry points called from C pick up the heap where the last call left it,
+/// see [header](crate::header).
+#[no_mangle]
+pub extern "C" fn gc_space() -> Space {
+    let space = HEAP.with(|h| h.borrow_mut().as_mut().map(Heap::nursery));
+    space.unwrap_or_else(|| gc_init(0, 0, 0))
+}
+
+/// [sync] for generated code
+///
+/// # Safety
+///
+/// `pointer` must be the heap pointer of the current thread.
+#[no_mangle]
+pub unsafe extern "C" fn gc_sync(pointer: *mut i64) {
+    sync(pointer)
+}
+
+/// End of the current allocation space, if there is one
+pub fn limit() -> Option<usize> {
+    HEAP.with(|h| h.borrow().as_ref().map(|heap| heap.limit))
+}
+
+/// Make space for an object of `size` bytes, collecting garbage if required
+///
+/// `top` is the last live stack slot of the frame at `rbp`, `base` is the frame
+/// of `init` and `pointer` is the current heap pointer. See module
+/// documentation for details. Aborts if there isn't enough free memory even
+/// after a collection.
+///
+/// # Safety
+///
+/// Must be called only from generated code with a valid chain of frames.
+#[no_mangle]
+pub unsafe extern "C" fn gc_collect(
+    size: i64,
+    top: *mut i64,
+    rbp: *mut i64,
+    base: *mut i64,
+    pointer: *mut i64,
+) -> Space {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+        let words = (size as usize + 7) / 8;
+
+        heap.sync(pointer);
+
+        if words > heap.nursery.len() {
+            if heap.old + words > heap.from.len() {
+                heap.collect(top, rbp, base, words, true);
+            }
+
+            if heap.old + words > heap.from.len() {
+                out_of_memory()
+            }
+
+            // The object is initialized after this, possibly with references
+            // to the nursery.
+            let start = heap.from.as_mut_ptr().add(heap.old);
+            heap.remembered.extend((0..words).map(|i| start.add(i)));
+            heap.old += words;
+            heap.limit = start.add(words) as usize;
+            heap.stats.allocated += words * WORDSIZE as usize;
+
+            return Space { pointer: start, limit: start.add(words) };
+        }
+
+        if heap.young + words > heap.nursery.len() {
+            // Promote everything in the worst case
+            let major = heap.old + heap.young > heap.from.len();
+            heap.collect(top, rbp, base, words, major);
+        }
+
+        heap.nursery()
+    })
+}
+
+/// Run a major collection and return the empty nursery
+///
+/// Takes the same arguments as [gc_collect], except for the size.
+///
+/// # Safety
+///
+/// Must be called only from generated code with a valid chain of frames.
+#[no_mangle]
+pub unsafe extern "C" fn gc_force(
+    top: *mut i64,
+    rbp: *mut i64,
+    base: *mut i64,
+    pointer: *mut i64,
+) -> Space {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+
+        heap.sync(pointer);
+        heap.collect(top, rbp, base, 0, true);
+        heap.nursery()
+    })
+}
+
+/// The counter at index `k` of [STATS] given the current heap pointer
+///
+/// # Safety
+///
+/// `pointer` must be the heap pointer of the current thread.
+#[no_mangle]
+pub unsafe extern "C" fn gc_stat(k: Object, pointer: *mut i64) -> Object {
+    sync(pointer);
+
+    let value = HEAP.with(|h| {
+        let h = h.borrow();
+        h.as_ref().expect("Heap is not initialized").counter(STATS[untag(k.0) as usize])
+    });
+
+    Object::immediate(value as i64)
+}
+
+/// Tell the heap of the current thread where the allocation pointer is
+///
+/// The generated code keeps the pointer in R12 and the runtime only sees it
+/// when it is passed along, which is enough for the counters to be exact.
+///
+/// # Safety
+///
+/// `pointer` must be the heap pointer of the current thread.
+pub unsafe fn sync(pointer: *mut i64) {
+    HEAP.with(|h| {
+        if let Some(heap) = h.borrow_mut().as_mut() {
+            heap.sync(pointer);
+        }
+    })
+}
+
+/// Every counter in [STATS] for the heap of the current thread, if it has one
+pub fn counters() -> Option<Vec<(&'static str, usize)>> {
+    HEAP.with(|h| {
+        h.borrow().as_ref().map(|heap| STATS.iter().map(|k| (*k, heap.counter(k))).collect())
+    })
+}
+
+/// Start of the line printed by [report]
+pub const STATS_PREFIX: &str = "inc-stats:";
+
+/// Print the counters to stderr if `INC_STATS` is set, once a program is done
+pub fn report() {
+    if env::var_os("INC_STATS").is_none() {
+        return;
+    }
+
+    if let Some(counters) = counters() {
+        let counters: Vec<String> =
+            counters.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        eprintln!("{} {}", STATS_PREFIX, counters.join(" "));
+    }
+}
+
+/// Set the hard limit of each half of the old generation to `words`
+///
+/// Returns the previous limit.
+#[no_mangle]
+pub extern "C" fn heap_limit(words: Object) -> Object {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+        let previous = heap.policy.limit;
+
+        heap.policy.limit = untag(words.0) as usize;
+        Object::immediate(previous as i64)
+    })
+}
+
+/// Number of collections so far
+///
+/// Objects may have moved if the epoch changed, which invalidates anything
+/// derived from their addresses like the hashes of an identity hash table.
+#[no_mangle]
+pub extern "C" fn gc_epoch() -> Object {
+    HEAP.with(|h| {
+        let h = h.borrow();
+        let heap = h.as_ref().expect("Heap is not initialized");
+
+        Object::immediate(heap.epoch as i64)
+    })
+}
+
+/// Create a guardian and return its ID
+#[no_mangle]
+pub extern "C" fn gc_guardian() -> Object {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+
+        heap.guardians.push(Guardian::default());
+        Object::immediate(heap.guardians.len() as i64 - 1)
+    })
+}
+
+/// Hand `obj` back from the guardian `id` once it is unreachable
+#[no_mangle]
+pub extern "C" fn gc_guard(id: Object, obj: Object) -> Object {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+
+        heap.guardians[untag(id.0) as usize].registered.push((obj.0, 0));
+        Object::new(NIL)
+    })
+}
+
+/// The next unreachable object of the guardian `id`, or `#f` if there is none
+#[no_mangle]
+pub extern "C" fn gc_collected(id: Object) -> Object {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+        let guardian = &mut heap.guardians[untag(id.0) as usize];
+
+        Object::new(guardian.collected.pop_front().map_or(FALSE, |(obj, _)| obj))
+    })
+}
+
+/// Call the function at `address` with `obj` once the object is unreachable
+#[no_mangle]
+pub extern "C" fn gc_register_finalizer(obj: Object, address: i64) -> Object {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+
+        heap.guardians[0].registered.push((obj.0, address));
+        Object::new(NIL)
+    })
+}
+
+/// The next unreachable object with a finalizer, or a 0 address if there is
+/// none
+#[no_mangle]
+pub extern "C" fn gc_finalizer() -> Finalizer {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+        let (obj, address) = heap.guardians[0].collected.pop_front().unwrap_or((NIL, 0));
+
+        Finalizer { obj: Object::new(obj), address }
+    })
+}
+
+/// Record a slot updated by a mutation if it points into the nursery
+///
+/// # Safety
+///
+/// `slot` must be a valid pointer to a word in the heap.
+#[no_mangle]
+pub unsafe extern "C" fn gc_remember(slot: *mut i64) {
+    HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        let heap = h.as_mut().expect("Heap is not initialized");
+        let young = range(&mut heap.nursery);
+
+        let val = *slot;
+        let target = tags::address(val);
+
+        if !young.contains(&slot)
+            && tags::is_heap(val)
+            && young.contains(&target)
+        {
+            heap.remembered.push(slot);
+        }
+    })
+}
+
+impl Heap {
+    /// Value of one of the [STATS], see [gc_stat]
+    fn counter(&self, name: &str) -> usize {
+        let stats = &self.stats;
+        let bytes = |words: usize| words * WORDSIZE as usize;
+
+        match name {
+            "collections" => stats.minor + stats.major,
+            "minor" => stats.minor,
+            "major" => stats.major,
+            "allocated" => stats.allocated + bytes(self.young),
+            "used" => bytes(self.old + self.young),
+            "size" => bytes(self.nursery.len() + 2 * self.from.len()),
+            "pause" => stats.pause.as_micros() as usize,
+            _ => stats.max.as_micros() as usize,
+        }
+    }
+
+    /// Record the words used in the nursery given the heap pointer
+    ///
+    /// The heap pointer is somewhere in the old generation after allocating a
+    /// large object, the nursery is unchanged then.
+    unsafe fn sync(&mut self, pointer: *mut i64) {
+        let young = range(&mut self.nursery);
+
+        if young.start <= pointer && pointer <= young.end {
+            self.young = pointer.offset_from(young.start) as usize;
+        }
+    }
+
+    /// Run a minor or major collection, keeping track of the time it took
+    unsafe fn collect(
+        &mut self,
+        top: *mut i64,
+        rbp: *mut i64,
+        base: *mut i64,
+        words: usize,
+        major: bool,
+    ) {
+        let start = Instant::now();
+
+        if major {
+            self.major(top, rbp, base, words);
+            self.stats.major += 1;
+        } else {
+            self.minor(top, rbp, base);
+            self.stats.minor += 1;
+        }
+
+        let pause = start.elapsed();
+        self.stats.pause += pause;
+        self.stats.max = self.stats.max.max(pause);
+
+        if self.log {
+            eprintln!(
+                "gc: {} collection {}, {} of {} bytes in use, {}us",
+                if major { "major" } else { "minor" },
+                self.stats.minor + self.stats.major,
+                self.old * WORDSIZE as usize,
+                (self.nursery.len() + self.from.len()) * WORDSIZE as usize,
+                pause.as_micros()
+            );
+        }
+    }
+
+    /// Free space in the nursery
+    fn nursery(&mut self) -> Space {
+        let range = range(&mut self.nursery);
+        let pointer = unsafe { range.start.add(self.young) };
+
+        self.limit = range.end as usize;
+        Space { pointer, limit: range.end }
+    }
+
+    /// Promote all live objects in the nursery to the old generation
+    unsafe fn minor(&mut self, top: *mut i64, rbp: *mut i64, base: *mut i64) {
+        let mut gc =
+            Collector::new(self.mode, vec![range(&mut self.nursery)], &mut self.from, self.old);
+
+        gc.roots(top, rbp, base);
+        gc.threads();
+
+        for slot in &self.remembered {
+            **slot = gc.forward(**slot);
+        }
+
+        gc.scan();
+        gc.weak(&mut self.guardians);
+
+        self.old = gc.free;
+        self.reset();
+    }
+
+    /// Collect the old generation and grow it if required to fit `words` more
+    unsafe fn major(&mut self, top: *mut i64, rbp: *mut i64, base: *mut i64, words: usize) {
+        // Everything in use could be live
+        let used = self.old + self.young;
+
+        if used > self.to.len() {
+            self.to = vec![0; self.grown(used)];
+        }
+
+        self.copy(top, rbp, base);
+
+        // Collections would be too frequent with little free space left
+        let needed = self.old + words;
+
+        if needed * 2 > self.from.len() && self.from.len() < self.policy.limit {
+            self.to = vec![0; self.grown(needed)];
+            self.copy(top, rbp, base);
+        }
+
+        if self.to.len() != self.from.len() {
+            self.to = vec![0; self.from.len()];
+        }
+    }
+
+    /// Size of each half after growing to fit at least `words`
+    fn grown(&self, words: usize) -> usize {
+        (self.from.len() * self.policy.growth).max(words).min(self.policy.limit)
+    }
+
+    /// Copy all live objects into the other half of the old generation
+    unsafe fn copy(&mut self, top: *mut i64, rbp: *mut i64, base: *mut i64) {
+        let spaces = vec![range(&mut self.from), range(&mut self.nursery)];
+        let mut gc = Collector::new(self.mode, spaces, &mut self.to, 0);
+
+        gc.roots(top, rbp, base);
+        gc.threads();
+        gc.scan();
+        gc.weak(&mut self.guardians);
+
+        self.old = gc.free;
+        self.from.iter_mut().for_each(|w| *w = 0);
+        std::mem::swap(&mut self.from, &mut self.to);
+        self.reset();
+    }
+
+    // Leave the nursery zeroed for the next cycle, `make-string` relies on
+    // fresh memory being zeroed.
+    fn reset(&mut self) {
+        self.stats.allocated += self.young * WORDSIZE as usize;
+        self.nursery.iter_mut().for_each(|w| *w = 0);
+        self.young = 0;
+        self.remembered.clear();
+        self.epoch += 1;
+    }
+}
+
+struct Collector {
+    mode: Mode,
+    from: Vec<Range<*mut i64>>,
+    to: *mut i64,
+    size: usize,
+    // Words used in the destination
+    free: usize,
+    // Copied objects in order, the ones before `scanned` are done
+    queue: Vec<i64>,
+    scanned: usize,
+    // The symbol `weak-box` and the values of the weak boxes copied so far
+    tag: i64,
+    boxes: Vec<*mut i64>,
+}
+
+impl Collector {
+    /// Copy objects in `from` into `to`, starting at `free`
+    fn new(mode: Mode, from: Vec<Range<*mut i64>>, to: &mut Vec<i64>, free: usize) -> Self {
+        Collector {
+            mode,
+            from,
+            to: to.as_mut_ptr(),
+            size: to.len(),
+            free,
+            queue: vec![],
+            scanned: 0,
+            tag: symbols::intern("weak-box").0,
+            boxes: vec![],
+        }
+    }
+
+    /// Update every root in the stack from `top` up to `base`
+    unsafe fn roots(&mut self, top: *mut i64, rbp: *mut i64, base: *mut i64) {
+        match self.mode {
+            Mode::Precise => self.frames(top, rbp, base),
+            Mode::Conservative => self.ambiguous(top, base),
+        }
+    }
+
+    /// Update every root in the stacks of the suspended threads
+    unsafe fn threads(&mut self) {
+        for (top, rbp, base) in threads::stacks() {
+            self.roots(top, rbp, base);
+        }
+    }
+
+    /// Update every live slot of every frame from `rbp` up to `base`
+    unsafe fn frames(&mut self, mut top: *mut i64, mut rbp: *mut i64, base: *mut i64) {
+        loop {
+            while top < rbp {
+                *top = self.forward(*top);
+                top = top.add(1);
+            }
+
+            if rbp == base {
+                break;
+            }
+
+            top = rbp.add(2);
+            rbp = *rbp as *mut i64;
+        }
+    }
+
+    /// Update every word from `top` up to `base` that could be a reference
+    unsafe fn ambiguous(&mut self, mut top: *mut i64, base: *mut i64) {
+        while top < base {
+            if self.plausible(*top) {
+                *top = self.forward(*top);
+            }
+
+            top = top.add(1);
+        }
+    }
+
+    /// Check if `val` could be a reference to an object in the heap
+    ///
+    /// A stale word on the stack could point anywhere in the heap; make sure
+    /// copying the object wouldn't read past the end of its space at least.
+    unsafe fn plausible(&self, val: i64) -> bool {
+        let addr = tags::address(val);
+
+        let space = match self.from.iter().find(|space| space.contains(&addr)) {
+            Some(space) => space,
+            None => return false,
+        };
+
+        let available = space.end.offset_from(addr) as i64;
+
+        if forwarded(*addr).is_some() {
+            return true;
+        }
+
+        match tag(val) {
+            PAIR => available >= 2,
+            VEC => *addr >= 0 && *addr < available,
+            STR => *addr >= 0 && ((*addr & !BOXED) + 1 + 7) / 8 < available,
+            _ => false,
+        }
+    }
+
+    /// Update references in copied objects, breadth first
+    fn scan(&mut self) {
+        while self.scanned < self.queue.len() {
+            let val = self.queue[self.scanned];
+            let (tag, addr) = (tag(val), tags::address(val));
+
+            let fields = match tag {
+                PAIR => 0..2,
+                // The value of a weak box is left for `weak`
+                VEC if unsafe { *addr == 2 && *addr.add(1) == self.tag } => {
+                    self.boxes.push(unsafe { addr.add(2) });
+                    0..0
+                }
+                VEC => 1..1 + unsafe { *addr } as usize,
+                _ => 0..0,
+            };
+
+            for field in fields {
+                unsafe {
+                    let p = addr.add(field);
+                    *p = self.forward(*p);
+                }
+            }
+
+            self.scanned += 1;
+        }
+    }
+
+    /// Update guardians and weak boxes once every live object is copied
+    ///
+    /// Objects queued in a guardian are live and objects registered with one
+    /// are saved if they are unreachable, along with everything they refer to.
+    /// Weak boxes are updated last, so they keep the saved objects as well.
+    unsafe fn weak(&mut self, guardians: &mut [Guardian]) {
+        for guardian in guardians.iter_mut() {
+            for (obj, _) in guardian.collected.iter_mut() {
+                *obj = self.forward(*obj);
+            }
+        }
+
+        self.scan();
+
+        for guardian in guardians.iter_mut() {
+            let registered = std::mem::take(&mut guardian.registered);
+
+            for (obj, address) in registered {
+                match self.live(obj) {
+                    Some(obj) => guardian.registered.push((obj, address)),
+                    None => guardian.collected.push_back((self.forward(obj), address)),
+                }
+            }
+        }
+
+        self.scan();
+
+        for value in &self.boxes {
+            **value = self.live(**value).unwrap_or(FALSE);
+        }
+    }
+
+    /// The new value of `val` if it survived the collection so far
+    fn live(&self, val: i64) -> Option<i64> {
+        let addr = tags::address(val);
+
+        if !tags::is_heap(val)
+            || !self.from.iter().any(|space| space.contains(&addr))
+        {
+            return Some(val);
+        }
+
+        forwarded(unsafe { *addr })
+    }
+
+    /// Copy the object referenced by `val` if required and return the new value
+    fn forward(&mut self, val: i64) -> i64 {
+        let tag = tag(val);
+
+        if !matches!(tag, PAIR | VEC | STR) {
+            return val;
+        }
+
+        let addr = tags::address(val);
+
+        // Static data like string literals live outside the heap and old
+        // objects stay where they are in a minor collection.
+        if !self.from.iter().any(|space| space.contains(&addr)) {
+            return val;
+        }
+
+        if let Some(new) = forwarded(unsafe { *addr }) {
+            return new;
+        }
+
+        let words = match tag {
+            PAIR => 2,
+            VEC => 1 + unsafe { *addr } as usize,
+            // Length prefixed and NUL terminated, boxed numbers included
+            _ => 1 + (unsafe { *addr & !BOXED } as usize + 1 + 7) / 8,
+        };
+
+        if self.free + words > self.size {
+            out_of_memory()
+        }
+
+        let new = unsafe {
+            let dest = self.to.add(self.free);
+            ptr::copy_nonoverlapping(addr, dest, words);
+            dest as i64 | tag
+        };
+
+        self.free += words;
+        unsafe { *addr = FORWARDED | new };
+        self.queue.push(new);
+        new
+    }
+}
+
+/// New value of an object given its first word, if it has been copied
+const fn forwarded(word: i64) -> Option<i64> {
+    if word < 0 && tags::is_heap(word) {
+        Some(word & !FORWARDED)
+    } else {
+        None
+    }
+}
+
+/// Address range of a space
+fn range(space: &mut Vec<i64>) -> Range<*mut i64> {
+    let start = space.as_mut_ptr();
+    start..unsafe { start.add(space.len()) }
+}
+
+fn out_of_memory() -> ! {
+    eprintln!("Out of memory");
+    Status::Memory.exit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let policy = Policy::new(4096, 3, 8192);
+        assert_eq!(policy, Policy { size: 4096, growth: 3, limit: 8192 });
+
+        // Initial size is always within the limit
+        assert_eq!(Policy::new(4096, 2, 1024).size, 1024);
+    }
+}
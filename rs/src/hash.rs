@@ -0,0 +1,165 @@
+//! Hash tables of the compiler and the symbol table of the runtime
+//!
+//! The keys are names and literals of programs, which don't need SipHash, the
+//! default of the standard library, to defend against collisions made on
+//! purpose. Hashing them with it shows up in profiles of large programs, so
+//! these tables are a [Map] with [Fx] instead, the hasher of rustc, which mixes
+//! in a word of the key at a time.
+//!
+//! The tables of the compiler are made with [map] and a hint of how many
+//! entries they'll get, counted from the program before filling them.
+//!
+//! Hashes that must come out the same in every process and with every build of
+//! the compiler, like the keys of the [cache](crate::cache) or the signatures
+//! of Jupyter messages, use [sha256] instead, from the `sha2` crate.
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    hash::{BuildHasherDefault, Hasher},
+};
+
+/// A hash map keyed by names, see the [module](self) docs
+pub type Map<K, V> = HashMap<K, V, Fast>;
+
+/// Builds the hasher of a [Map]
+pub type Fast = BuildHasherDefault<Fx>;
+
+/// The Fx hasher of Firefox and rustc, fast and not meant for untrusted keys
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fx {
+    hash: u64,
+}
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl Fx {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for Fx {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+
+        for word in &mut words {
+            self.add(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+
+        for byte in words.remainder() {
+            self.add(u64::from(*byte));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(u64::from(i));
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(u64::from(i));
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// An empty map with room for `capacity` entries
+pub fn map<K, V>(capacity: usize) -> Map<K, V> {
+    Map::with_capacity_and_hasher(capacity, Fast::default())
+}
+
+/// Lowercase hexadecimal digits of `bytes`
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256, see FIPS 180-4
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::{BuildHasher, Hash};
+
+    fn hash(key: impl Hash) -> u64 {
+        let mut hasher = Fast::default().build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(hash("inc::twice"), hash(String::from("inc::twice")));
+        assert_ne!(hash("inc::twice"), hash("inc::twicf"));
+        assert_ne!(hash("ab"), hash("ba"));
+        assert_ne!(hash(("a", "b")), hash(("ab", "")));
+
+        let mut names: Map<String, usize> = map(100);
+        assert!(names.capacity() >= 100);
+
+        for i in 0..1000 {
+            names.insert(format!("name{}", i), i);
+        }
+
+        assert_eq!(names.len(), 1000);
+        assert!((0..1000).all(|i| names[&format!("name{}", i)] == i));
+    }
+
+    // The examples of FIPS 180-4 from NIST, the last two of which take two
+    // blocks, and a million bytes
+    #[test]
+    fn digests() {
+        let digests: [(&[u8], &str); 4] = [
+            (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+            ),
+        ];
+
+        for (data, digest) in digests.iter() {
+            assert_eq!(hex(&sha256(data)), *digest);
+        }
+
+        assert_eq!(
+            hex(&sha256(&[b'a'; 1_000_000])),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    // The padding takes a block of its own from 56 bytes on, see FIPS 180-4
+    #[test]
+    fn boundaries() {
+        let digests = [
+            (55, "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318"),
+            (56, "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a"),
+            (63, "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34"),
+            (64, "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb"),
+            (65, "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0"),
+            (119, "31eba51c313a5c08226adf18d4a359cfdfd8d2e816b13f4af952f7ea6584dcfb"),
+            (120, "2f3d335432c70b580af0e8e1b3674a7c020d683aa5f73aaaedfdc55af904c21c"),
+            (128, "6836cf13bac400e9105071cd6af47084dfacad4e5e302c94bfed24e013afb73e"),
+        ];
+
+        for (n, digest) in digests.iter() {
+            assert_eq!(hex(&sha256(&vec![b'a'; *n])), *digest, "{} bytes", n);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Calling procedures exported by libraries from C
+//!
+//! Every procedure exported by a [library](crate::library) gets an entry point
+//! callable from C, named after the library and the name it is exported as.
+//! `(math arith)` exporting `thrice` is called as `inc_math_arith_thrice`.
+//! `inc --emit header` prints the header declaring them, along with macros to
+//! make and take apart the values they are called with:
+//!
+//! ```c
+//! #include <stdio.h>
+//! #include "arith.h"
+//!
+//! int main(void) {
+//!     intptr_t n = inc_math_arith_thrice(INC_FIXNUM(14));
+//!     printf("%ld\n", (long)INC_FIXNUM_VALUE(n));
+//! }
+//! ```
+//!
+//! Values are tagged words as described in [tags](crate::tags), passed and
+//! returned as `intptr_t`. The object with the entry points, from `inc --emit
+//! obj` or a unit, is linked with the runtime in `libinc.a` like an executable
+//! is, but without `inc_main`.
+//!
+//! An entry point saves the registers C expects to be preserved, sets up the
+//! heap of the current thread, creating it the first time, and calls the
+//! procedure like any other call from Scheme. The top level expressions of the
+//! program never run, so only procedures that don't depend on them can be
+//! called. Objects in the heap, like the pairs returned, stay valid only until
+//! the next call since the collector may move them, and an error not caught by
+//! the procedure ends the process after reporting it. Procedures with more
+//! parameters than C passes in registers don't get an entry point.
+use crate::{
+    compiler::state::State,
+    core::{Closure, Core, Expr::*, Ident},
+    ffi,
+    immediate::{self, CHAR, FALSE, MASK, NIL, PAIR, SHIFT, TRUE},
+    lambda,
+    library::Export,
+    symbols,
+    x86::{self, Register, Register::*, Relative, ASM},
+};
+use std::collections::HashMap;
+
+/// Registers the arguments are passed in by C, in order
+const ARGS: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+
+/// Registers C expects to be preserved, besides RBP
+const SAVED: [Register; 5] = [RBX, R12, R13, R14, R15];
+
+/// Keywords of C that can't name a parameter
+const KEYWORDS: [&str; 34] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while",
+];
+
+/// A procedure exported by a library, callable from C
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub export: Export,
+    /// Names of the parameters in the definition
+    pub params: Vec<String>,
+}
+
+impl Entry {
+    /// Name of the entry point in C, like `inc_math_arith_thrice`
+    pub fn symbol(&self) -> String {
+        let library = self.export.library.trim_start_matches('(').trim_end_matches(')');
+        let parts: Vec<String> = library.split(' ').map(mangle).collect();
+
+        format!("inc_{}_{}", parts.join("_"), mangle(&self.export.name))
+    }
+
+    /// The prototype of the entry point
+    pub fn prototype(&self) -> String {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|param| {
+                let name = mangle(param);
+                if KEYWORDS.contains(&name.as_str()) {
+                    format!("intptr_t {}_", name)
+                } else {
+                    format!("intptr_t {}", name)
+                }
+            })
+            .collect();
+
+        let params = if params.is_empty() { String::from("void") } else { params.join(", ") };
+        format!("intptr_t {}({});", self.symbol(), params)
+    }
+}
+
+/// The exported procedures defined in a program, in the order of `exports`
+///
+/// Exports naming anything other than a procedure defined in the program, like
+/// a procedure defined in another unit, are left out.
+pub fn entries(exports: &[Export], prog: &[Core]) -> Vec<Entry> {
+    let procedures: HashMap<String, &Closure<Ident>> = prog
+        .iter()
+        .filter_map(|expr| expr.function())
+        .map(|(name, code)| (name.to_string(), code))
+        .collect();
+
+    exports
+        .iter()
+        .filter_map(|export| {
+            let code = procedures.get(&export.target)?;
+
+            if code.formals.len() > ARGS.len() {
+                return None;
+            }
+
+            let params = code.formals.iter().map(Ident::short).collect();
+            Some(Entry { export: export.clone(), params })
+        })
+        .collect()
+}
+
+/// Emit the entry points of the exported procedures
+pub fn emit(s: &mut State, entries: &[Entry]) -> ASM {
+    let mut asm = ASM::default();
+
+    for entry in entries {
+        asm += emit1(s, entry);
+    }
+
+    asm
+}
+
+/// Emit the entry point of a procedure
+///
+/// The arguments are spilled into the frame of the entry point, which is the
+/// base frame for the collector just like the one of `init`, and then passed on
+/// to the procedure.
+fn emit1(s: &mut State, entry: &Entry) -> ASM {
+    let target = Ident::new(entry.export.target.as_str());
+    let mut asm = x86::func(&ffi::symbol(&entry.symbol()));
+
+    for r in &SAVED {
+        asm += x86::push((*r).into());
+    }
+    asm += x86::enter();
+
+    s.enter();
+
+    let mut args = vec![];
+    for (param, r) in entry.params.iter().zip(&ARGS) {
+        let offset = s.si;
+        let param = target.extend(param.as_str());
+
+        asm += x86::save((*r).into(), offset);
+        s.set(param.clone(), Relative { register: RBP, offset }.into());
+        args.push(Identifier(param));
+    }
+
+    asm += ffi::runtime(s, "gc_space");
+    asm += x86::mov(R12.into(), RAX.into());
+    asm += x86::mov(R13.into(), RDX.into());
+    asm += ffi::runtime(s, "rt_stack_limit");
+    asm += x86::mov(R15.into(), RAX.into());
+    asm += x86::mov(R14.into(), RBP.into());
+    asm += symbols::register(s);
+
+    asm += lambda::call(s, &target, &args);
+
+    // The heap has to know how much of it is used before the next call
+    let val = s.alloc();
+    asm += x86::save(RAX.into(), val);
+    asm += x86::mov(RDI.into(), R12.into());
+    asm += ffi::runtime(s, "gc_sync");
+    asm += x86::load(RAX, val);
+    s.dealloc(1);
+
+    s.leave();
+
+    asm += x86::mov(RSP.into(), RBP.into());
+    asm += x86::pop(RBP.into());
+    for r in SAVED.iter().rev() {
+        asm += x86::pop((*r).into());
+    }
+
+    asm + x86::ret()
+}
+
+/// The C header declaring the entry points, see the module docs
+pub fn render(entries: &[Entry]) -> String {
+    let mut out = String::from(
+        "/* Generated by inc, do not edit */\n\
+         \n\
+         #ifndef INC_EXPORTS_H\n\
+         #define INC_EXPORTS_H\n\
+         \n\
+         #include <stdint.h>\n\
+         \n",
+    );
+
+    let macros: [(&str, String); 14] = [
+        ("INC_TAG(v)", format!("((v) & {})", MASK)),
+        ("INC_FIXNUM(n)", format!("((intptr_t)(n) * {})", 1 << SHIFT)),
+        ("INC_FIXNUM_VALUE(v)", format!("((intptr_t)(v) >> {})", SHIFT)),
+        ("INC_IS_FIXNUM(v)", format!("(INC_TAG(v) == {})", immediate::NUM)),
+        ("INC_CHAR(c)", format!("(((intptr_t)(c) << {}) | {})", SHIFT, CHAR)),
+        ("INC_CHAR_VALUE(v)", format!("((char)((v) >> {}))", SHIFT)),
+        ("INC_IS_CHAR(v)", format!("(INC_TAG(v) == {})", CHAR)),
+        ("INC_TRUE", format!("((intptr_t){})", TRUE)),
+        ("INC_FALSE", format!("((intptr_t){})", FALSE)),
+        ("INC_BOOL(b)", String::from("((b) ? INC_TRUE : INC_FALSE)")),
+        ("INC_NIL", format!("((intptr_t){})", NIL)),
+        ("INC_IS_PAIR(v)", format!("(INC_TAG(v) == {})", PAIR)),
+        ("INC_CAR(v)", format!("(((intptr_t *)((v) - {}))[0])", PAIR)),
+        ("INC_CDR(v)", format!("(((intptr_t *)((v) - {}))[1])", PAIR)),
+    ];
+
+    for (name, value) in &macros {
+        out += &format!("#define {} {}\n", name, value);
+    }
+
+    for entry in entries {
+        out += &format!("\n/* {} {} */\n", entry.export.library, entry.export.name);
+        out += &entry.prototype();
+        out += "\n";
+    }
+
+    out + "\n#endif /* INC_EXPORTS_H */\n"
+}
+
+/// A Scheme name as a C identifier, like `list->vector` as `list_to_vector`
+fn mangle(name: &str) -> String {
+    let mut out = String::new();
+
+    for (i, c) in name.replace("->", "_to_").chars().enumerate() {
+        match c {
+            '?' => out += "_p",
+            '!' => out += "_x",
+            c if c.is_ascii_alphanumeric() && !(i == 0 && c.is_ascii_digit()) => out.push(c),
+            _ => out.push('_'),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::emit, lang, parser};
+
+    const LIB: &str = "(define-library (math arith)
+                         (export double (rename triple thrice) pair-up? (rename pi pi))
+                         (begin
+                           (define pi 3)
+                           (define (double x) (* x 2))
+                           (define (pair-up? char) (pair? char))
+                           (define (triple x) (+ (double x) x))))";
+
+    fn entries(prog: &str) -> Vec<Entry> {
+        let mut s = State::new();
+        let prog = lang::analyze(&mut s, parser::parse(prog).unwrap()).unwrap();
+        super::entries(&s.exports, &prog)
+    }
+
+    #[test]
+    fn prototypes() {
+        let prototypes: Vec<String> = entries(LIB).iter().map(Entry::prototype).collect();
+
+        assert_eq!(
+            prototypes,
+            vec![
+                "intptr_t inc_math_arith_double(intptr_t x);",
+                "intptr_t inc_math_arith_pair_up_p(intptr_t char_);",
+                "intptr_t inc_math_arith_thrice(intptr_t x);",
+            ]
+        );
+    }
+
+    #[test]
+    fn header() {
+        let header = render(&entries(LIB));
+
+        assert!(header.contains("#define INC_FIXNUM(n) ((intptr_t)(n) * 8)"));
+        assert!(header.contains("#define INC_NIL ((intptr_t)4)"));
+        assert!(header.contains("/* (math arith) thrice */\nintptr_t inc_math_arith_thrice("));
+        assert!(header.ends_with("#endif /* INC_EXPORTS_H */\n"));
+
+        // Without libraries, there is nothing but the macros
+        assert!(!render(&entries("(define (f x) x)")).contains("inc_"));
+    }
+
+    #[test]
+    fn compile() {
+        let asm = emit::compile(parser::parse(LIB).unwrap()).unwrap().to_string();
+
+        assert!(asm.contains("inc_math_arith_thrice"));
+        assert!(!asm.contains("inc_math_arith_pi"));
+    }
+
+    #[test]
+    fn mangled() {
+        assert_eq!(mangle("list->vector"), "list_to_vector");
+        assert_eq!(mangle("set-car!"), "set_car_x");
+        assert_eq!(mangle("1+"), "__");
+    }
+}
@@ -0,0 +1,295 @@
+//! What the compiler needs from the system it runs on
+//!
+//! Programs read other files at compile time with `load` and the `include`s of
+//! libraries. The compiler asks the current [Host] for them instead of the file
+//! system, so that it works where there is none, like in a browser on
+//! wasm32-unknown-unknown. [Disk] reads them from the file system and is the
+//! default, [Memory] serves them from a map.
+//!
+//! Passes are timed with a [Clock], which reads the time of the system only
+//! with the `native` feature.
+//!
+//! Every pass walks the program recursively, a few frames of the stack for
+//! every level of nesting, so the compiler runs [deep] on threads with a
+//! [STACK] big enough for programs nested tens of thousands of levels deep.
+//! Passes over independent parts of the program run in [parallel] on a pool of
+//! such threads, started once and shared by every pass.
+//!
+//! ```
+//! use inc::host::{self, Memory};
+//!
+//! let mut files = Memory::default();
+//! files.insert("twice.ss", "(define (twice x) (* x 2))");
+//! host::set(files);
+//!
+//! assert_eq!(host::read("twice.ss").unwrap(), "(define (twice x) (* x 2))");
+//! assert!(host::read("missing.ss").is_err());
+//! # host::set(host::Disk);
+//! ```
+
+#[cfg(feature = "native")]
+use std::time::Instant;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs, io, panic,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// Size of the stack of the threads the compiler runs on
+pub const STACK: usize = 1 << 30;
+
+/// Where the files read at compile time come from
+pub trait Host: Send {
+    /// The contents of the file at a path
+    fn read(&self, path: &str) -> io::Result<String>;
+}
+
+/// The file system, relative to the working directory
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Disk;
+
+/// Files in memory, by path
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    files: HashMap<String, String>,
+}
+
+/// Time since the clock started, for timing the compiler
+///
+/// Time stands still without the `native` feature, since there may be no clock
+/// to read at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    #[cfg(feature = "native")]
+    start: Instant,
+}
+
+/// Work for a thread of the [Pool]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads with a [STACK] taking turns at a queue of jobs, see [parallel]
+struct Pool {
+    /// The queue, unless no thread could be spawned to take from it
+    jobs: Option<mpsc::Sender<Job>>,
+}
+
+/// The host of the process, the [Disk] unless [set] to another
+static HOST: Mutex<Option<Box<dyn Host>>> = Mutex::new(None);
+
+/// The pool of the process, started the first time there is work for it
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+thread_local! {
+    /// Is this thread running on a [STACK] already?
+    static DEEP: Cell<bool> = const { Cell::new(false) };
+
+    /// Is this thread one of the [Pool]?
+    static WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Host for Disk {
+    fn read(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+impl Memory {
+    /// Add a file, replacing any at the same path
+    pub fn insert<P: Into<String>, S: Into<String>>(&mut self, path: P, source: S) {
+        self.files.insert(path.into(), source.into());
+    }
+}
+
+impl Host for Memory {
+    fn read(&self, path: &str) -> io::Result<String> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No file at {}", path)))
+    }
+}
+
+impl Clock {
+    #[cfg(feature = "native")]
+    pub fn start() -> Self {
+        Clock { start: Instant::now() }
+    }
+
+    #[cfg(not(feature = "native"))]
+    pub const fn start() -> Self {
+        Clock {}
+    }
+
+    #[cfg(feature = "native")]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    #[cfg(not(feature = "native"))]
+    pub const fn elapsed(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+/// Read files from another host from now on
+pub fn set<H: Host + 'static>(host: H) {
+    *HOST.lock().unwrap() = Some(Box::new(host));
+}
+
+/// Read a file from the current host
+pub fn read(path: &str) -> io::Result<String> {
+    match HOST.lock().unwrap().as_ref() {
+        Some(host) => host.read(path),
+        None => Disk.read(path),
+    }
+}
+
+impl Pool {
+    /// Spawn a thread for every core, or as many of them as the system allows
+    fn start() -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut spawned = 0;
+
+        for _ in 0..threads() {
+            let rx = Arc::clone(&rx);
+            let worker = move || {
+                DEEP.with(|deep| deep.set(true));
+                WORKER.with(|worker| worker.set(true));
+
+                // The lock is released before the job runs, for the other
+                // threads to take the next ones
+                loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                }
+            };
+
+            if thread::Builder::new().stack_size(STACK).spawn(worker).is_ok() {
+                spawned += 1;
+            }
+        }
+
+        Self { jobs: if spawned > 0 { Some(tx) } else { None } }
+    }
+}
+
+/// Number of threads to split work in [parallel] between, one for every core
+pub fn threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Run jobs on the pool of threads with a [STACK] and wait for all of them
+///
+/// The results come back in the order of the jobs, each with the payload of
+/// the panic if the job panicked. The jobs run one after the other on this
+/// thread instead without the `native` feature, when no thread of the pool
+/// could be spawned, or from a job of the pool itself, which could otherwise
+/// wait for threads that are all waiting for it.
+pub fn parallel<T: Send + 'static>(
+    jobs: Vec<Box<dyn FnOnce() -> T + Send>>,
+) -> Vec<thread::Result<T>> {
+    let run =
+        |job: Box<dyn FnOnce() -> T + Send>| panic::catch_unwind(panic::AssertUnwindSafe(job));
+
+    if cfg!(not(feature = "native")) || WORKER.with(Cell::get) {
+        return jobs.into_iter().map(run).collect();
+    }
+
+    let queue = {
+        let mut pool = POOL.lock().unwrap_or_else(PoisonError::into_inner);
+        match &pool.get_or_insert_with(Pool::start).jobs {
+            Some(queue) => queue.clone(),
+            None => return jobs.into_iter().map(run).collect(),
+        }
+    };
+
+    let (tx, rx) = mpsc::channel();
+
+    for (i, job) in jobs.into_iter().enumerate() {
+        let tx = tx.clone();
+        let job: Job = Box::new(move || drop(tx.send((i, run(job)))));
+
+        // The threads of the pool never exit, but if they did the job would
+        // come back here
+        if let Err(mpsc::SendError(job)) = queue.send(job) {
+            job()
+        }
+    }
+
+    drop(tx);
+
+    let mut results: Vec<(usize, thread::Result<T>)> = rx.iter().collect();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Is this thread running on a [STACK]?
+///
+/// Without the `native` feature no thread ever is.
+pub fn is_deep() -> bool {
+    cfg!(feature = "native") && DEEP.with(Cell::get)
+}
+
+/// Run `f` on a [STACK], on another thread unless this one has it already
+///
+/// `f` can borrow from the caller, since the thread is scoped to the call.
+/// Panics are raised again on this thread, along with their payload. Without
+/// the `native` feature there may be no threads to spawn, and `f` runs on the
+/// stack of the caller, as it does when the thread can't be spawned.
+pub fn deep<'a, T: Send + 'a>(f: impl FnOnce() -> T + Send + 'a) -> T {
+    if cfg!(not(feature = "native")) || is_deep() {
+        return f();
+    }
+
+    // The thread takes `f` only once it runs, a thread that failed to spawn
+    // leaves it for this one
+    let mut f = Some(f);
+
+    let result = thread::scope(|scope| {
+        let f = &mut f;
+        let deep = move || {
+            DEEP.with(|deep| deep.set(true));
+            f.take().map(|f| f())
+        };
+
+        let thread = thread::Builder::new().stack_size(STACK).spawn_scoped(scope, deep).ok()?;
+        thread.join().unwrap_or_else(|e| panic::resume_unwind(e))
+    });
+
+    match (result, f) {
+        (Some(result), _) => result,
+        (None, Some(f)) => f(),
+        (None, None) => unreachable!("The thread ran without a result"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Job<T> = Box<dyn FnOnce() -> T + Send>;
+
+    fn jobs(n: usize) -> Vec<Job<usize>> {
+        (0..n).map(|i| Box::new(move || i * 2) as Job<usize>).collect()
+    }
+
+    #[test]
+    fn pool() {
+        let doubled: Vec<usize> = parallel(jobs(100)).into_iter().map(Result::unwrap).collect();
+        assert_eq!(doubled, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+
+        // Jobs of the pool run jobs of their own on their own thread
+        let nested = parallel(vec![Box::new(|| parallel(jobs(10)).len()) as Job<usize>]);
+        assert_eq!(nested.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [10]);
+
+        let failed = parallel(vec![Box::new(|| panic!("Failed")) as Job<()>]);
+        assert!(failed[0].is_err());
+    }
+}
@@ -9,29 +9,22 @@
 //! 61bit numerics instead of native 64 and some overhead for operations like
 //! multiplication & division.
 //!
-//! See the paper for details. See tests for examples.
+//! The tags themselves are defined in [tags](crate::tags) along with the rest
+//! of the encoding. See the paper for details. See tests for examples.
 
-use crate::core::{Core, Expr::*, Literal::*};
+use crate::{
+    core::{Core, Expr::*, Literal::*},
+    tags,
+};
 
-pub const NUM: i64 = 0;
-pub const BOOL: i64 = 1;
-pub const CHAR: i64 = 2;
-pub const PAIR: i64 = 3;
-pub const NIL: i64 = 4;
-pub const STR: i64 = 5;
-pub const SYM: i64 = 6;
-pub const VEC: i64 = 7;
-
-pub const SHIFT: i64 = 3;
-pub const MASK: i64 = 0b0000_0111;
-
-pub const FALSE: i64 = (0 << SHIFT) | BOOL;
-pub const TRUE: i64 = (1 << SHIFT) | BOOL;
+pub use crate::tags::{
+    name, BOOL, CHAR, FALSE, MASK, NIL, NUM, PAIR, SHIFT, STR, SYM, TRUE, VEC,
+};
 
 /// Immediate representation of an expression.
 pub fn to(prog: &Core) -> Option<i64> {
     match prog {
-        Literal(Number(i)) => Some((i << SHIFT) | NUM),
+        Literal(Number(i)) => Some(tags::immediate(*i, NUM)),
         Literal(Boolean(true)) => Some(TRUE),
         Literal(Boolean(false)) => Some(FALSE),
         // An ASCII char is a single byte, so most of these shifts should be
@@ -39,7 +32,7 @@ pub fn to(prog: &Core) -> Option<i64> {
         Literal(Char(c)) => {
             // Expand u8 to i64 before shifting right, this will easily
             // overflow and give bogus results otherwise. Unit testing FTW!
-            Some((i64::from(*c) << SHIFT) | CHAR)
+            Some(tags::immediate(i64::from(*c), CHAR))
         }
         Literal(Nil) => Some(NIL),
         _ => None,
@@ -49,7 +42,7 @@ pub fn to(prog: &Core) -> Option<i64> {
 // Immediate representation of numbers is required so often a helper is
 // useful.
 pub const fn n(i: i64) -> i64 {
-    (i << SHIFT) | NUM
+    tags::immediate(i, NUM)
 }
 
 #[cfg(test)]
@@ -67,4 +60,11 @@ mod tests {
     fn chars() {
         assert_eq!(to(&Literal(Char(b'A'))), Some((65 << SHIFT) + CHAR))
     }
+
+    #[test]
+    fn names() {
+        assert_eq!(name(to(&Core::from(42)).unwrap() & MASK), "number");
+        assert_eq!(name(to(&Literal(Nil)).unwrap() & MASK), "null");
+        assert_eq!(name(PAIR), "pair");
+    }
 }
@@ -0,0 +1,1454 @@
+//! A tree walking interpreter for the core language
+//!
+//! The interpreter evaluates a program after [analysis](lang::analyze), the
+//! same [Core] the code generator sees, with an environment of plain values.
+//! It doesn't need an assembler, a linker or even the runtime, which makes it
+//! useful in a few places:
+//!
+//! - The [repl](crate::repl) evaluates most input with it and uses the
+//!   [jit](crate::jit) only for the rest, which starts up a lot faster.
+//! - Constant expressions are [folded](fold) at compile time by evaluating
+//!   them here.
+//! - It is the reference the compiled code is compared to by the differential
+//!   tests in `tests/differential.rs`.
+//!
+//! ```
+//! use inc::{compiler, interp, lang::Passes};
+//!
+//! let prog = compiler::parse("(display \"hi \") (string-append \"a\" \"b\")").unwrap();
+//!
+//! assert_eq!(interp::run(prog, Passes::default()).unwrap(), "hi \"ab\"");
+//! ```
+//!
+//! Being the reference for the compiler, the interpreter does what the
+//! generated code does, quirks included, and errors are reported the same way.
+//! Some errors the compiler finds only while generating code, like a variable
+//! that isn't bound or a call to a function that doesn't exist, so they are
+//! looked for before the program runs, see [check]. Values print exactly like
+//! they do in a compiled program.
+//!
+//! Anything with effects beyond the output, like ports, processes and threads,
+//! isn't interpreted and neither is behavior the compiler leaves undefined,
+//! like calling a function with the wrong number of arguments. These are
+//! reported as an [internal error](Error::Internal) before or while the program
+//! runs, which tells the caller to compile the program instead. Continuations
+//! escape just like they do in the compiler, running the after thunks of
+//! `dynamic-wind` on the way out, and a tail call doesn't grow the stack of the
+//! interpreter whether or not `tco` ran. Primitives other than `call/cc`,
+//! `apply`, `map` and `dynamic-wind` that take a function, like
+//! `with-exception-handler`, aren't interpreted.
+use crate::{
+    bignum::Big,
+    callbacks,
+    compiler::state::State,
+    core::{Closure, Core, Error, Expr::*, Ident, Literal, Literal::*, Syntax},
+    lambda,
+    lang::{self, Passes},
+    numbers::Number::{self, *},
+    primitives, rt, tags,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    rc::Rc,
+    thread,
+};
+
+/// Deepest nesting of function calls the interpreter follows
+const MAX_DEPTH: usize = 10_000;
+
+/// Size of the stack of the thread running a program, enough for [MAX_DEPTH]
+const STACK: usize = 512 << 20;
+
+/// Primitives without effects, which are evaluated at compile time by [fold]
+const PURE: &[&str] = &[
+    "%",
+    "*",
+    "+",
+    "-",
+    "/",
+    "<",
+    "<=",
+    "=",
+    ">",
+    ">=",
+    "boolean?",
+    "char->integer",
+    "char<=?",
+    "char<?",
+    "char=?",
+    "char>=?",
+    "char>?",
+    "char?",
+    "dec",
+    "exact->inexact",
+    "fixnum?",
+    "flonum?",
+    "inc",
+    "integer->char",
+    "not",
+    "null?",
+    "number?",
+    "pair?",
+    "string?",
+    "symbol?",
+    "vector?",
+    "zero?",
+];
+
+/// What running a program printed and the value it returned
+///
+/// There is no value if the program called `(exit 0)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: String,
+    pub value: Option<Core>,
+}
+
+/// Evaluate a program and describe the outcome like a compiled program would
+///
+/// The result is everything the program printed followed by its value, just
+/// like the output of [exec](crate::cli::exec).
+pub fn run(prog: Vec<Syntax>, passes: Passes) -> Result<String, Error<'static>> {
+    let outcome = eval(prog, passes)?;
+    let mut out = outcome.output;
+
+    if let Some(value) = outcome.value {
+        write(&mut out, &value, false);
+    }
+
+    Ok(out.trim().to_string())
+}
+
+/// Analyze and evaluate a program, see [run]
+///
+/// The program is evaluated as is, so it must include the
+/// [prelude](compiler::prelude) if it needs it. Programs run on a thread of
+/// their own with a large stack.
+pub fn eval(prog: Vec<Syntax>, passes: Passes) -> Result<Outcome, Error<'static>> {
+    let mut s = State::new();
+    s.passes = passes;
+    let prog = lang::analyze(&mut s, prog).map_err(Error::compilation)?;
+
+    check(&prog)?;
+
+    // Without the operating system there may be no threads to spawn, and the
+    // program runs on the stack of the caller
+    #[cfg(not(feature = "native"))]
+    return Interpreter::new(&prog).run(&prog);
+
+    #[cfg(feature = "native")]
+    thread::Builder::new()
+        .stack_size(STACK)
+        .spawn(move || Interpreter::new(&prog).run(&prog))?
+        .join()
+        .unwrap_or_else(|_| Err(unsupported("a program that crashed the interpreter")))
+}
+
+/// Fold primitives applied to constants into their values
+///
+/// Only primitives without effects are folded, and only if they succeed; an
+/// error is left for the program to raise at run time. Conditionals with a
+/// constant test are replaced by the branch taken.
+pub fn fold(expr: Core) -> Core {
+    match expr {
+        List(list) => {
+            let list: Vec<Core> = list.into_iter().map(fold).collect();
+            let pure = |name: &Ident| name.free().map_or(false, |n| PURE.contains(&n));
+
+            match list.as_slice() {
+                [Identifier(name), args @ ..] if pure(name) => {
+                    constant(name, args).unwrap_or(List(list))
+                }
+                _ => List(list),
+            }
+        }
+
+        Cond { pred, then, alt } => match fold(*pred) {
+            Literal(Boolean(false)) => alt.map_or(Literal(Nil), |alt| fold(*alt)),
+            Literal(_) => fold(*then),
+            pred => Cond {
+                pred: Box::new(pred),
+                then: Box::new(fold(*then)),
+                alt: alt.map(|e| Box::new(fold(*e))),
+            },
+        },
+
+        Let { bindings, body } => Let {
+            bindings: bindings.into_iter().map(|(name, val)| (name, fold(val))).collect(),
+            body: body.into_iter().map(fold).collect(),
+        },
+
+        Define { name, val } => match *val {
+            Lambda(code) => Define {
+                name,
+                val: Box::new(Lambda(Closure {
+                    body: code.body.into_iter().map(fold).collect(),
+                    ..code
+                })),
+            },
+            val => Define { name, val: Box::new(val) },
+        },
+
+        Vector(list) => Vector(list.into_iter().map(fold).collect()),
+
+        e => e,
+    }
+}
+
+/// The value of a pure primitive applied to constants, if it can be a literal
+fn constant(name: &Ident, args: &[Core]) -> Option<Core> {
+    let mut interp = Interpreter::default();
+
+    let args = args
+        .iter()
+        .map(|arg| match arg {
+            Literal(l) => interp.literal(l).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<Value>>>()?;
+
+    match interp.primitive(&name.short(), &args)?.ok()? {
+        Value::Number(Exact(n)) => n.fixnum().map(|n| Literal(Number(n))),
+        Value::Number(Inexact(f)) => Some(Literal(Float(f))),
+        Value::Boolean(b) => Some(Literal(Boolean(b))),
+        Value::Char(c) => Some(Literal(Char(c))),
+        _ => None,
+    }
+}
+
+/// Report a program the interpreter can't run, see the [module](self) docs
+fn unsupported(what: &str) -> Error<'static> {
+    Error::Internal { message: format!("The interpreter doesn't support {}", what), e: None }
+}
+
+/// Find the errors the compiler would find while generating code
+///
+/// These are references to variables that aren't bound, calls to functions
+/// that aren't defined, which fail to link, and expressions that can't be
+/// compiled at all. Calls of primitives with the wrong number of arguments end
+/// up calling whatever the linker finds, so they aren't interpreted.
+///
+/// Like the compiler, only the first error is reported. Undefined functions
+/// are found by the linker after the code is generated, so any other error
+/// comes first.
+fn check(prog: &[Core]) -> Result<(), Error<'static>> {
+    struct Checker<'a> {
+        functions: HashSet<&'a Ident>,
+        error: Option<String>,
+        undefined: Option<String>,
+        unsupported: Option<String>,
+    }
+
+    impl<'a> Checker<'a> {
+        fn error(&mut self, e: String) {
+            self.error.get_or_insert(e);
+        }
+
+        fn function(&mut self, scope: &[&Ident], f: &Ident) {
+            if scope.contains(&f) {
+                self.unsupported = Some(format!("the variable {} called as a function", f))
+            } else if !self.functions.contains(f) && primitives::variadic(f).is_none() {
+                self.undefined.get_or_insert(format!("Undefined function {}", f));
+            }
+        }
+
+        fn expr(&mut self, scope: &mut Vec<&'a Ident>, expr: &'a Core) {
+            match expr {
+                Literal(_) => {}
+
+                Identifier(i) => {
+                    if !scope.contains(&i) {
+                        self.error(format!("Undefined variable {}", i))
+                    }
+                }
+
+                Let { bindings, body } => {
+                    let mark = scope.len();
+
+                    for (name, val) in bindings {
+                        self.expr(scope, val);
+                        scope.push(name);
+                    }
+
+                    for e in body {
+                        self.expr(scope, e);
+                    }
+
+                    scope.truncate(mark);
+                }
+
+                Cond { pred, then, alt } => {
+                    self.expr(scope, pred);
+                    self.expr(scope, then);
+                    if let Some(alt) = alt {
+                        self.expr(scope, alt);
+                    }
+                }
+
+                List(list) => match list.as_slice() {
+                    [Identifier(name), args @ ..] => self.call(scope, name, args),
+                    _ => self.error(format!("Unknown expression: `{}`", expr)),
+                },
+
+                Vector(_) => self.error(format!("Unknown expression: `{}`", expr)),
+
+                Lambda(_) | Define { .. } => {
+                    self.unsupported = Some(format!("the nested definition `{}`", expr))
+                }
+            }
+        }
+
+        fn call(&mut self, scope: &mut Vec<&'a Ident>, name: &'a Ident, args: &'a [Core]) {
+            // Functions named by primitives aren't variable references
+            let mut values: Vec<&Core> = args.iter().collect();
+
+            if scope.contains(&name) {
+                if args.len() > 1 {
+                    let e = format!("continuation {} called with {} arguments", name, args.len());
+                    self.error(e)
+                }
+            } else if primitives::defined(name, args) {
+                match (name.short().as_str(), args) {
+                    ("%guard", _) => {}
+                    (primitive, args) => {
+                        for (i, arg) in args.iter().enumerate() {
+                            if let Identifier(f) = arg {
+                                if takes_function(primitive, i) {
+                                    if scope.contains(&f) {
+                                        self.error(lambda::expected(primitive, f))
+                                    }
+                                    self.function(scope, f);
+                                    values.retain(|v| !std::ptr::eq(*v, arg));
+                                }
+                            }
+                        }
+                    }
+                }
+            } else if rt::defined(name) {
+                // Calls of functions are checked like any other
+            } else if name.free().and_then(callbacks::find).is_some() {
+                self.unsupported = Some(format!("the callback {}", name))
+            } else if self.functions.contains(name) {
+            } else if (0..=4).any(|n| primitives::defined(name, &vec![Literal(Nil); n])) {
+                self.unsupported = Some(format!("`{}` with {} arguments", name, args.len()))
+            } else {
+                self.undefined.get_or_insert(format!("Undefined function {}", name));
+            }
+
+            for arg in values {
+                self.expr(scope, arg);
+            }
+        }
+    }
+
+    let functions = prog.iter().filter_map(|expr| expr.function()).map(|(name, _)| name).collect();
+
+    let mut checker =
+        Checker { functions, error: None, undefined: None, unsupported: None };
+
+    for expr in prog {
+        match (expr, expr.function()) {
+            (_, Some((_, code))) => {
+                let mut scope: Vec<&Ident> = code.formals.iter().collect();
+                for e in &code.body {
+                    checker.expr(&mut scope, e);
+                }
+            }
+            (Define { .. }, None) => {}
+            (e, None) => checker.expr(&mut vec![], e),
+        }
+    }
+
+    if let Some(e) = checker.error.or(checker.undefined) {
+        return Err(Error::Compilation(e));
+    }
+
+    match checker.unsupported {
+        Some(what) => Err(unsupported(&what)),
+        None => Ok(()),
+    }
+}
+
+/// Is argument `i` of a primitive the name of a function?
+fn takes_function(primitive: &str, i: usize) -> bool {
+    match primitive {
+        "apply" | "call/cc" | "call-with-current-continuation" | "map" | "spawn" => i == 0,
+        "dynamic-wind" | "with-exception-handler" => true,
+        "register-finalizer" => i == 1,
+        _ => false,
+    }
+}
+
+/// A string, which can't be changed if it is a literal
+#[derive(Debug)]
+struct Str {
+    bytes: RefCell<Vec<u8>>,
+    literal: bool,
+}
+
+/// Values of the interpreter
+///
+/// Pairs, vectors and strings are shared and mutable like objects in the heap
+/// and compared by identity with `eq?`.
+#[derive(Debug, Clone)]
+enum Value {
+    Nil,
+    Boolean(bool),
+    Char(u8),
+    Number(Number),
+    Str(Rc<Str>),
+    Symbol(String),
+    Pair(Rc<RefCell<(Value, Value)>>),
+    Vector(Rc<RefCell<Vec<Value>>>),
+}
+
+impl Value {
+    fn string(s: &str) -> Self {
+        Value::Str(Rc::new(Str { bytes: RefCell::new(s.as_bytes().to_vec()), literal: false }))
+    }
+
+    fn fixnum(n: i64) -> Self {
+        Value::Number(Exact(Big::from(n)))
+    }
+
+    fn pair(car: Value, cdr: Value) -> Self {
+        Value::Pair(Rc::new(RefCell::new((car, cdr))))
+    }
+
+    const fn truthy(&self) -> bool {
+        !matches!(self, Value::Boolean(false))
+    }
+
+    /// The value as an expression, exactly like [deref](rt::Object::deref)
+    ///
+    /// Pairs are lists of two elements and strings end at the first NUL, since
+    /// they are read as C strings. The compiled program crashes with objects
+    /// that contain themselves, so they aren't supported.
+    fn core(&self) -> Eval<Core> {
+        self.acyclic(&mut HashSet::new())
+    }
+
+    /// The value as an expression, given the objects it is nested in
+    fn acyclic(&self, parents: &mut HashSet<usize>) -> Eval<Core> {
+        let address = match self {
+            Value::Pair(p) => Rc::as_ptr(p) as usize,
+            Value::Vector(v) => Rc::as_ptr(v) as usize,
+            _ => 0,
+        };
+
+        if address != 0 && !parents.insert(address) {
+            return Err(Unwind::Unsupported(String::from("printing a cyclic object")));
+        }
+
+        let core = match self {
+            Value::Nil => Literal(Nil),
+            Value::Boolean(b) => Literal(Boolean(*b)),
+            Value::Char(c) => Literal(Char(*c)),
+            Value::Number(Exact(n)) => Literal(n.fixnum().map_or(Bignum(n.clone()), Number)),
+            Value::Number(Inexact(f)) => Literal(Float(*f)),
+            Value::Str(s) => {
+                let bytes = s.bytes.borrow();
+                let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                Core::string(String::from_utf8_lossy(&bytes[..end]))
+            }
+            Value::Symbol(s) => Core::symbol(s.as_str()),
+            Value::Pair(p) => {
+                let p = p.borrow();
+                List(vec![p.0.acyclic(parents)?, p.1.acyclic(parents)?])
+            }
+            Value::Vector(v) => {
+                Vector(v.borrow().iter().map(|e| e.acyclic(parents)).collect::<Eval<_>>()?)
+            }
+        };
+
+        parents.remove(&address);
+        Ok(core)
+    }
+
+    /// The value written like [write], for error messages
+    fn written(&self) -> Eval<String> {
+        let mut out = String::new();
+        write(&mut out, &self.core()?, false);
+        Ok(out)
+    }
+}
+
+/// Write a value like [print](rt::print), given as [Value::core]
+pub(crate) fn write(f: &mut String, val: &Core, nested: bool) {
+    match val {
+        List(pair) => {
+            if !nested {
+                f.push('(');
+            }
+
+            write(f, &pair[0], false);
+
+            match &pair[1] {
+                Literal(Nil) => {}
+                rest @ List(_) => {
+                    f.push(' ');
+                    write(f, rest, true);
+                }
+                rest => {
+                    f.push_str(" . ");
+                    write(f, rest, false);
+                }
+            }
+
+            if !nested {
+                f.push(')');
+            }
+        }
+        val => write!(f, "{}", val).unwrap(),
+    }
+}
+
+/// Write a value like `write`, given as [Value::core]
+///
+/// Unlike [write], symbols are bare and vectors are `#(...)`, so that `read`
+/// gets the same datum back.
+fn datum(f: &mut String, val: &Core) {
+    match val {
+        Literal(Symbol(s)) => f.push_str(s),
+
+        List(pair) => {
+            f.push('(');
+            datum(f, &pair[0]);
+
+            let mut rest = &pair[1];
+            while let List(pair) = rest {
+                f.push(' ');
+                datum(f, &pair[0]);
+                rest = &pair[1];
+            }
+
+            if *rest != Literal(Nil) {
+                f.push_str(" . ");
+                datum(f, rest);
+            }
+
+            f.push(')');
+        }
+
+        Vector(v) => {
+            f.push_str("#(");
+
+            for (i, val) in v.iter().enumerate() {
+                if i > 0 {
+                    f.push(' ');
+                }
+                datum(f, val);
+            }
+
+            f.push(')');
+        }
+
+        val => write!(f, "{}", val).unwrap(),
+    }
+}
+
+/// Display a value like `display`, given as [Value::core]
+fn display(f: &mut String, val: &Core) {
+    match val {
+        Literal(Str(s)) | Literal(Symbol(s)) => f.push_str(s),
+
+        Literal(Char(c)) => f.push(*c as char),
+
+        List(pair) => {
+            f.push('(');
+            display(f, &pair[0]);
+
+            let mut rest = &pair[1];
+            while let List(pair) = rest {
+                f.push(' ');
+                display(f, &pair[0]);
+                rest = &pair[1];
+            }
+
+            if *rest != Literal(Nil) {
+                f.push_str(" . ");
+                display(f, rest);
+            }
+
+            f.push(')');
+        }
+
+        Vector(v) => {
+            f.push('[');
+
+            for (i, val) in v.iter().enumerate() {
+                if i > 0 {
+                    f.push(' ');
+                }
+                display(f, val);
+            }
+
+            f.push(']');
+        }
+
+        val => write(f, val, false),
+    }
+}
+
+/// Ways of leaving the evaluation of an expression other than with a value
+enum Unwind {
+    /// An object raised and not handled yet
+    Raise(Value),
+    /// A continuation invoked with a value, by the id of its escape point
+    Throw(i64, Value),
+    /// The program called `exit` with a status
+    Exit(i64),
+    /// An error that ends the program, like dividing by zero
+    Fatal(String),
+    /// Something the interpreter doesn't do
+    Unsupported(String),
+}
+
+type Eval<T> = Result<T, Unwind>;
+
+/// Local variables in the order they are bound, innermost last
+type Env = Vec<(Ident, Value)>;
+
+/// The result of an expression in tail position
+enum Next {
+    Value(Value),
+    /// A call of a function that is yet to be made
+    Call(Ident, Vec<Value>),
+}
+
+#[derive(Default)]
+struct Interpreter {
+    functions: HashMap<Ident, Rc<Closure<Ident>>>,
+    /// String literals, which are the same object everywhere like in the binary
+    literals: HashMap<String, Rc<Str>>,
+    /// Ids of the continuations that can be invoked
+    escapes: Vec<i64>,
+    /// Number of `guard`s around the expression, without which a raised object
+    /// ends the program right away
+    guards: usize,
+    next: i64,
+    depth: usize,
+    output: String,
+}
+
+impl Interpreter {
+    fn new(prog: &[Core]) -> Self {
+        let functions = prog
+            .iter()
+            .filter_map(|expr| expr.function())
+            .map(|(name, code)| (name.clone(), Rc::new(code.clone())))
+            .collect();
+
+        Interpreter { functions, ..Interpreter::default() }
+    }
+
+    /// Evaluate the expressions of a program in order
+    fn run(mut self, prog: &[Core]) -> Result<Outcome, Error<'static>> {
+        let mut value = None;
+
+        for expr in prog {
+            if let Define { .. } = expr {
+                continue;
+            }
+
+            match self.eval(&mut vec![], expr) {
+                Ok(val) => value = Some(val),
+                Err(e) => return self.unwound(e),
+            }
+        }
+
+        // The value of a program without expressions is whatever is in RAX
+        match value {
+            Some(value) => match value.core() {
+                Ok(value) => Ok(Outcome { output: self.output, value: Some(value) }),
+                Err(e) => self.unwound(e),
+            },
+            None => Err(unsupported("a program without expressions")),
+        }
+    }
+
+    /// The outcome of a program that didn't return normally
+    fn unwound(self, e: Unwind) -> Result<Outcome, Error<'static>> {
+        match e {
+            Unwind::Exit(0) => Ok(Outcome { output: self.output, value: None }),
+            Unwind::Exit(status) => Err(Error::Runtime(format!("Exited with status {}", status))),
+            Unwind::Raise(Value::Str(s)) => Err(Error::Runtime(format!(
+                "Exception: {}",
+                String::from_utf8_lossy(&s.bytes.borrow())
+            ))),
+            Unwind::Raise(obj) => match obj.written() {
+                Ok(obj) => Err(Error::Runtime(format!("Exception: {}", obj))),
+                Err(e) => self.unwound(e),
+            },
+            Unwind::Fatal(e) => Err(Error::Runtime(e)),
+            Unwind::Throw(..) => Err(unsupported("a continuation invoked at the top level")),
+            Unwind::Unsupported(what) => Err(unsupported(&what)),
+        }
+    }
+
+    fn eval(&mut self, env: &mut Env, expr: &Core) -> Eval<Value> {
+        match self.tail(env, expr)? {
+            Next::Value(val) => Ok(val),
+            Next::Call(f, args) => self.apply(f, args),
+        }
+    }
+
+    /// Evaluate an expression in tail position, leaving a call to the caller
+    fn tail(&mut self, env: &mut Env, expr: &Core) -> Eval<Next> {
+        let val = match expr {
+            Literal(l) => self.literal(l)?,
+
+            Identifier(i) => lookup(env, i)?,
+
+            Let { bindings, body } => {
+                let mark = env.len();
+
+                for (name, val) in bindings {
+                    let val = self.eval(env, val)?;
+                    env.push((name.clone(), val));
+                }
+
+                let next = self.body(env, body);
+                env.truncate(mark);
+                return next;
+            }
+
+            Cond { pred, then, alt } => {
+                return if self.eval(env, pred)?.truthy() {
+                    self.tail(env, then)
+                } else {
+                    match alt {
+                        Some(alt) => self.tail(env, alt),
+                        None => Ok(Next::Value(Value::Nil)),
+                    }
+                }
+            }
+
+            List(list) => match list.as_slice() {
+                [Identifier(name), args @ ..] => return self.call(env, name, args),
+                _ => return Err(Unwind::Unsupported(format!("`{}`", expr))),
+            },
+
+            e => return Err(Unwind::Unsupported(format!("`{}`", e))),
+        };
+
+        Ok(Next::Value(val))
+    }
+
+    /// Evaluate a sequence of expressions, the last one in tail position
+    fn body(&mut self, env: &mut Env, body: &[Core]) -> Eval<Next> {
+        match body.split_last() {
+            Some((last, init)) => {
+                for expr in init {
+                    self.eval(env, expr)?;
+                }
+                self.tail(env, last)
+            }
+            None => Err(Unwind::Unsupported(String::from("an empty body"))),
+        }
+    }
+
+    fn literal(&mut self, l: &Literal) -> Eval<Value> {
+        Ok(match l {
+            Nil => Value::Nil,
+            Boolean(b) => Value::Boolean(*b),
+            Char(c) => Value::Char(*c),
+            // The compiler truncates integers too large for a fixnum
+            Number(n) => match Big::from(*n).fixnum() {
+                Some(n) => Value::fixnum(n),
+                None => return Err(Unwind::Unsupported(format!("the literal {}", n))),
+            },
+            Bignum(n) => Value::Number(Exact(n.clone())),
+            Float(f) => Value::Number(Inexact(*f)),
+            // The assembler reads escapes in literals, which the interpreter doesn't
+            Str(s) if s.contains('\\') => {
+                return Err(Unwind::Unsupported(format!("the literal {:?}", s)))
+            }
+            Str(s) => {
+                let literal = self.literals.entry(s.clone()).or_insert_with(|| {
+                    Rc::new(Str { bytes: RefCell::new(s.as_bytes().to_vec()), literal: true })
+                });
+                Value::Str(Rc::clone(literal))
+            }
+            Symbol(s) => Value::Symbol(s.clone()),
+        })
+    }
+
+    fn call(&mut self, env: &mut Env, name: &Ident, args: &[Core]) -> Eval<Next> {
+        // Local variables can only be called as continuations, like in the compiler
+        if let Ok(k) = lookup(env, name) {
+            let val = match args {
+                [] => Value::Nil,
+                [val] => self.eval(env, val)?,
+                _ => unreachable!("continuation called with {} arguments", args.len()),
+            };
+
+            return Err(self.throw(k, val));
+        }
+
+        // Names bound by the program shadow the primitives
+        let short = name.free().unwrap_or_default();
+        let val = match (short, args) {
+            ("%guard", [Identifier(var), body, handler]) => {
+                let mark = env.len();
+
+                self.guards += 1;
+                let val = self.eval(env, body);
+                self.guards -= 1;
+
+                match val {
+                    Err(Unwind::Raise(obj)) => {
+                        env.truncate(mark);
+                        assign(env, var, obj)?;
+                        return self.tail(env, handler);
+                    }
+                    val => val?,
+                }
+            }
+
+            ("call/cc" | "call-with-current-continuation", [Identifier(f)]) => {
+                let id = self.next;
+                self.next += 1;
+
+                let k = Value::Vector(Rc::new(RefCell::new(vec![
+                    Value::Symbol(String::from("continuation")),
+                    Value::fixnum(id),
+                ])));
+
+                self.escapes.push(id);
+                let val = self.apply(f.clone(), vec![k]);
+                self.escapes.retain(|e| *e != id);
+
+                match val {
+                    Err(Unwind::Throw(to, val)) if to == id => val,
+                    val => val?,
+                }
+            }
+
+            ("dynamic-wind", [Identifier(before), Identifier(thunk), Identifier(after)]) => {
+                self.apply(before.clone(), vec![])?;
+                let val = self.apply(thunk.clone(), vec![]);
+
+                // The after thunk runs on the way out to a guard or a
+                // continuation too, but not when the program ends
+                match &val {
+                    Ok(_) | Err(Unwind::Throw(..)) => self.apply(after.clone(), vec![])?,
+                    Err(Unwind::Raise(_)) if self.guards > 0 => {
+                        self.apply(after.clone(), vec![])?
+                    }
+                    Err(_) => Value::Nil,
+                };
+
+                val?
+            }
+
+            ("map", [Identifier(f), list]) => {
+                let mut list = self.eval(env, list)?;
+                let mut vals = vec![];
+
+                while let Value::Pair(pair) = list {
+                    let (car, cdr) = pair.borrow().clone();
+                    vals.push(self.apply(f.clone(), vec![car])?);
+                    list = cdr;
+                }
+
+                if !matches!(list, Value::Nil) {
+                    return Err(type_error("map", tags::PAIR, &list));
+                }
+
+                vals.into_iter().rev().fold(Value::Nil, |rest, val| Value::pair(val, rest))
+            }
+
+            ("apply", [Identifier(f), rest @ ..]) => {
+                let mut args = self.args(env, rest)?;
+                let mut list = args.pop().unwrap_or(Value::Nil);
+
+                while let Value::Pair(pair) = list {
+                    let (car, cdr) = pair.borrow().clone();
+                    args.push(car);
+                    list = cdr;
+                }
+
+                if !matches!(list, Value::Nil) {
+                    return Err(Unwind::Unsupported(String::from("apply with an improper list")));
+                }
+
+                match primitives::variadic(f) {
+                    Some(identity) => variadic(&f.short(), Value::fixnum(identity), &args)?,
+                    None => return Ok(Next::Call(f.clone(), args)),
+                }
+            }
+
+            // The functions other primitives take are names, not values
+            _ if (0..args.len()).any(|i| takes_function(short, i)) => {
+                return Err(Unwind::Unsupported(format!("`{}`", short)))
+            }
+
+            _ if primitives::defined(name, args) => {
+                let args = self.args(env, args)?;
+                match self.primitive(short, &args) {
+                    Some(val) => val?,
+                    None => return Err(Unwind::Unsupported(format!("`{}`", short))),
+                }
+            }
+
+            _ if rt::defined(name) => {
+                let args = self.args(env, args)?;
+                self.runtime(short, &args)?
+            }
+
+            _ => return Ok(Next::Call(name.clone(), self.args(env, args)?)),
+        };
+
+        Ok(Next::Value(val))
+    }
+
+    /// Evaluate the arguments of a call in order
+    fn args(&mut self, env: &mut Env, args: &[Core]) -> Eval<Vec<Value>> {
+        args.iter().map(|arg| self.eval(env, arg)).collect()
+    }
+
+    /// Call a function and the functions it calls in tail position in turn
+    fn apply(&mut self, mut name: Ident, mut args: Vec<Value>) -> Eval<Value> {
+        if self.depth == MAX_DEPTH {
+            return Err(Unwind::Unsupported(format!("calls nested over {} deep", MAX_DEPTH)));
+        }
+
+        self.depth += 1;
+
+        let val = loop {
+            let code = match self.functions.get(&name) {
+                Some(code) => Rc::clone(code),
+                None => break Err(Unwind::Unsupported(format!("the function {}", name))),
+            };
+
+            // The generated code reads whatever is in the stack
+            if code.formals.len() != args.len() {
+                break Err(Unwind::Unsupported(format!(
+                    "{} called with {} arguments instead of {}",
+                    name,
+                    args.len(),
+                    code.formals.len()
+                )));
+            }
+
+            let mut env: Env = code.formals.iter().cloned().zip(args).collect();
+
+            match self.body(&mut env, &code.body) {
+                Ok(Next::Value(val)) => break Ok(val),
+                Ok(Next::Call(f, a)) => {
+                    name = f;
+                    args = a;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.depth -= 1;
+        val
+    }
+
+    /// Invoke a continuation, see [continuations](crate::continuations)
+    fn throw(&self, k: Value, val: Value) -> Unwind {
+        let id = match &k {
+            Value::Vector(v) => match v.borrow().as_slice() {
+                [Value::Symbol(tag), Value::Number(Exact(id))] if tag == "continuation" => {
+                    id.fixnum()
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match id {
+            Some(id) if self.escapes.contains(&id) => Unwind::Throw(id, val),
+            Some(_) => error("continuation invoked outside of its dynamic extent"),
+            None => match k.written() {
+                Ok(k) => error(&format!("attempt to apply non-procedure {}", k)),
+                Err(e) => e,
+            },
+        }
+    }
+
+    /// Apply a primitive to its arguments, `None` if the interpreter can't
+    fn primitive(&mut self, name: &str, args: &[Value]) -> Option<Eval<Value>> {
+        use Value::*;
+
+        let bool = |b| Ok(Boolean(b));
+
+        Some(match (name, args) {
+            ("+" | "-" | "*" | "/" | "%", [a, b]) => arithmetic(name, a, b),
+            ("+" | "*", args) => {
+                let identity = primitives::variadic(&Ident::new(name)).unwrap_or_default();
+                variadic(name, Value::fixnum(identity), args)
+            }
+            ("inc", [a]) => arithmetic(name, a, &Value::fixnum(1)),
+            ("dec", [a]) => arithmetic(name, a, &Value::fixnum(1)),
+            ("<" | "<=" | "=" | ">" | ">=", [a, b]) => compare(name, a, b),
+            ("zero?", [a]) => bool(matches!(a, Number(Exact(n)) if *n == Big::from(0))),
+            ("exact->inexact", [a]) => number(name, a).map(|n| Number(Inexact(n.inexact()))),
+            ("inexact->exact", [a]) => match number(name, a) {
+                Ok(Inexact(f)) => match Big::from_f64(f) {
+                    Some(n) => Ok(Number(Exact(n))),
+                    None => Err(Unwind::Fatal(format!(
+                        "Exception: {}: no exact representation for {}",
+                        name,
+                        crate::numbers::format(f)
+                    ))),
+                },
+                n => n.map(Number),
+            },
+
+            ("boolean?", [a]) => bool(matches!(a, Boolean(_))),
+            ("char?", [a]) => bool(matches!(a, Char(_))),
+            ("fixnum?", [a]) => bool(matches!(a, Number(Exact(n)) if n.fixnum().is_some())),
+            ("flonum?", [a]) => bool(matches!(a, Number(Inexact(_)))),
+            ("not", [a]) => bool(!a.truthy()),
+            ("null?", [a]) => bool(matches!(a, Nil)),
+            ("number?", [a]) => bool(matches!(a, Number(_))),
+            ("pair?", [a]) => bool(matches!(a, Pair(_))),
+            ("string?", [a]) => bool(matches!(a, Str(_))),
+            ("symbol?", [a]) => bool(matches!(a, Symbol(_))),
+            ("vector?", [a]) => bool(matches!(a, Vector(_))),
+
+            ("char->integer", [c]) => char(name, c).map(|c| Value::fixnum(i64::from(c))),
+            ("integer->char", [n]) => match fixnum(name, n) {
+                Ok(n) if (0..256).contains(&n) => Ok(Char(n as u8)),
+                Ok(n) => Err(error(&format!("{}: invalid character code {}", name, n))),
+                Err(e) => Err(e),
+            },
+            ("char<?" | "char<=?" | "char=?" | "char>?" | "char>=?", [a, b]) => {
+                let (a, b) = match (char(name, a), char(name, b)) {
+                    (Ok(a), Ok(b)) => (a, b),
+                    (Err(e), _) | (_, Err(e)) => return Some(Err(e)),
+                };
+
+                bool(match name {
+                    "char<?" => a < b,
+                    "char<=?" => a <= b,
+                    "char=?" => a == b,
+                    "char>?" => a > b,
+                    _ => a >= b,
+                })
+            }
+
+            ("cons", [a, b]) => Ok(Value::pair(a.clone(), b.clone())),
+            ("car" | "cdr", [p]) => match p {
+                Pair(p) if name == "car" => Ok(p.borrow().0.clone()),
+                Pair(p) => Ok(p.borrow().1.clone()),
+                p => Err(type_error(name, tags::PAIR, p)),
+            },
+            ("set-car!" | "set-cdr!", [p, val]) => match p {
+                Pair(p) => {
+                    let mut p = p.borrow_mut();
+                    if name == "set-car!" {
+                        p.0 = val.clone()
+                    } else {
+                        p.1 = val.clone()
+                    }
+                    Ok(Nil)
+                }
+                p => Err(type_error(name, tags::PAIR, p)),
+            },
+
+            ("list", args) => {
+                Ok(args.iter().rev().fold(Nil, |rest, val| Value::pair(val.clone(), rest)))
+            }
+            ("vector", args) => Ok(Vector(Rc::new(RefCell::new(args.to_vec())))),
+            ("make-vector", [n]) => make_vector(name, n, &Value::fixnum(0)),
+            ("make-vector", [n, fill]) => make_vector(name, n, fill),
+            ("vector-length", [v]) => match v {
+                Vector(v) => Ok(Value::fixnum(v.borrow().len() as i64)),
+                v => Err(type_error(name, tags::VEC, v)),
+            },
+            ("vector-ref", [v, i]) => match v {
+                Vector(items) => {
+                    index(name, v, i, items.borrow().len()).map(|i| items.borrow()[i].clone())
+                }
+                v => Err(type_error(name, tags::VEC, v)),
+            },
+            ("vector-set!", [v, i, val]) => match v {
+                Vector(items) => {
+                    let len = items.borrow().len();
+                    index(name, v, i, len).map(|i| {
+                        items.borrow_mut()[i] = val.clone();
+                        Nil
+                    })
+                }
+                v => Err(type_error(name, tags::VEC, v)),
+            },
+
+            ("make-string", [n]) => make_string(name, n, 0),
+            ("make-string", [n, c]) => match (length(name, n), char(name, c)) {
+                (Ok(_), Ok(c)) => make_string(name, n, c),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+            ("string-length", [s]) => {
+                string(name, s).map(|s| Value::fixnum(s.bytes.borrow().len() as i64))
+            }
+            ("string-ref", [s, i]) => string(name, s).and_then(|bytes| {
+                let len = bytes.bytes.borrow().len();
+                index(name, s, i, len).map(|i| Char(bytes.bytes.borrow()[i]))
+            }),
+            ("string-set!", [s, i, c]) => string(name, s).and_then(|bytes| {
+                if bytes.literal {
+                    return Err(error(&format!("{}: {} is immutable", name, s.written()?)));
+                }
+
+                let len = bytes.bytes.borrow().len();
+                let i = index(name, s, i, len)?;
+                bytes.bytes.borrow_mut()[i] = char(name, c)?;
+                Ok(Nil)
+            }),
+            ("string->symbol", [s]) => string(name, s).map(|s| {
+                Symbol(String::from_utf8_lossy(&s.bytes.borrow()).into_owned())
+            }),
+            ("symbol->string", [s]) => match s {
+                Symbol(s) => Ok(Value::string(s)),
+                s => Err(type_error(name, tags::SYM, s)),
+            },
+
+            ("display", [val]) => val.core().map(|val| {
+                display(&mut self.output, &val);
+                Nil
+            }),
+            ("write", [val]) => val.core().map(|val| {
+                datum(&mut self.output, &val);
+                Nil
+            }),
+            ("newline", []) => {
+                self.output.push('\n');
+                Ok(Nil)
+            }
+            ("write-char", [Char(c)]) => {
+                self.output.push(*c as char);
+                Ok(Nil)
+            }
+            ("write-string", [Str(s)]) => {
+                self.output.push_str(&String::from_utf8_lossy(&s.bytes.borrow()));
+                Ok(Nil)
+            }
+
+            ("raise", [obj]) => Err(Unwind::Raise(obj.clone())),
+
+            _ => return None,
+        })
+    }
+
+    /// Call a function of the runtime, see [defined](rt::defined)
+    fn runtime(&mut self, name: &str, args: &[Value]) -> Eval<Value> {
+        match (name, args) {
+            ("exit", [Value::Number(Exact(n))]) => match n.fixnum() {
+                // The status is the tagged fixnum, truncated by the system
+                Some(n) => Err(Unwind::Exit((n << tags::SHIFT) & 0xff)),
+                None => Err(Unwind::Unsupported(String::from("exit with a bignum"))),
+            },
+            // Boxed numbers have an identity the interpreter doesn't keep track of
+            ("rt-eq", [Value::Number(x), Value::Number(y)]) => match (unboxed(x), unboxed(y)) {
+                (Some(x), Some(y)) => Ok(Value::Boolean(x == y)),
+                (None, None) => Err(Unwind::Unsupported(String::from("eq? on boxed numbers"))),
+                _ => Ok(Value::Boolean(false)),
+            },
+            ("rt-eq", [a, b]) => Ok(Value::Boolean(equal(a, b, false))),
+            ("rt-equal", [a, b, deep]) => Ok(Value::Boolean(equal(a, b, deep.truthy()))),
+            ("string=?", [Value::Str(a), Value::Str(b)]) => {
+                Ok(Value::Boolean(a.bytes.borrow()[..] == b.bytes.borrow()[..]))
+            }
+            ("string=?", [_, _]) => Ok(Value::Boolean(false)),
+            ("symbol=?", [a, b]) => Ok(Value::Boolean(match (a, b) {
+                (Value::Symbol(a), Value::Symbol(b)) => a == b,
+                _ => false,
+            })),
+            _ => Err(Unwind::Unsupported(format!("`{}`", name))),
+        }
+    }
+}
+
+fn lookup(env: &[(Ident, Value)], name: &Ident) -> Eval<Value> {
+    match env.iter().rev().find(|(n, _)| n == name) {
+        Some((_, val)) => Ok(val.clone()),
+        None => Err(Unwind::Unsupported(format!("the variable {}", name))),
+    }
+}
+
+/// Replace the value of the innermost variable bound to a name
+fn assign(env: &mut Env, name: &Ident, val: Value) -> Eval<()> {
+    match env.iter_mut().rev().find(|(n, _)| n == name) {
+        Some((_, slot)) => {
+            *slot = val;
+            Ok(())
+        }
+        None => Err(Unwind::Unsupported(format!("the variable {}", name))),
+    }
+}
+
+/// Raise a string, like the errors of the runtime
+fn error(message: &str) -> Unwind {
+    Unwind::Raise(Value::string(message))
+}
+
+fn type_error(primitive: &str, expected: i64, val: &Value) -> Unwind {
+    match val.written() {
+        Ok(val) => error(&format!("{}: expected {}, got {}", primitive, tags::name(expected), val)),
+        Err(e) => e,
+    }
+}
+
+/// An argument of a primitive that the generated code takes for another type
+fn mistaken(primitive: &str, val: &Value) -> Unwind {
+    Unwind::Unsupported(format!("{} with {}", primitive, val.written().unwrap_or_default()))
+}
+
+fn number(primitive: &str, val: &Value) -> Eval<Number> {
+    match val {
+        Value::Number(n) => Ok(n.clone()),
+        val => Err(type_error(primitive, tags::NUM, val)),
+    }
+}
+
+fn fixnum(primitive: &str, val: &Value) -> Eval<i64> {
+    match val {
+        Value::Number(Exact(n)) if n.fixnum().is_some() => Ok(n.fixnum().unwrap()),
+        // Flonums and bignums look like strings to the generated code
+        Value::Number(_) => Err(mistaken(primitive, val)),
+        val => Err(type_error(primitive, tags::NUM, val)),
+    }
+}
+
+fn char(primitive: &str, val: &Value) -> Eval<u8> {
+    match val {
+        Value::Char(c) => Ok(*c),
+        val => Err(type_error(primitive, tags::CHAR, val)),
+    }
+}
+
+fn string(primitive: &str, val: &Value) -> Eval<Rc<Str>> {
+    match val {
+        Value::Str(s) => Ok(Rc::clone(s)),
+        Value::Number(_) => Err(mistaken(primitive, val)),
+        val => Err(type_error(primitive, tags::STR, val)),
+    }
+}
+
+/// An index into an object of some length, which must be in range
+fn index(primitive: &str, val: &Value, i: &Value, len: usize) -> Eval<usize> {
+    let i = fixnum(primitive, i)?;
+
+    if i >= 0 && (i as usize) < len {
+        Ok(i as usize)
+    } else {
+        Err(error(&format!("{}: index {} is out of range for {}", primitive, i, val.written()?)))
+    }
+}
+
+/// The length of a new vector or string, which can't be too large
+fn length(primitive: &str, n: &Value) -> Eval<usize> {
+    match fixnum(primitive, n)? {
+        n if (0..1 << 27).contains(&n) => Ok(n as usize),
+        n => Err(error(&format!("{}: invalid length {}", primitive, n))),
+    }
+}
+
+fn make_vector(primitive: &str, n: &Value, fill: &Value) -> Eval<Value> {
+    let n = length(primitive, n)?;
+    Ok(Value::Vector(Rc::new(RefCell::new(vec![fill.clone(); n]))))
+}
+
+fn make_string(primitive: &str, n: &Value, fill: u8) -> Eval<Value> {
+    let n = length(primitive, n)?;
+    Ok(Value::Str(Rc::new(Str { bytes: RefCell::new(vec![fill; n]), literal: false })))
+}
+
+/// Apply a [variadic](primitives::variadic) primitive to the arguments two at a
+/// time, like the compiler does
+fn variadic(name: &str, identity: Value, args: &[Value]) -> Eval<Value> {
+    let (first, rest) = match args {
+        [first, rest @ ..] if !rest.is_empty() => (first.clone(), rest),
+        _ => (identity, args),
+    };
+
+    rest.iter().try_fold(first, |acc, x| arithmetic(name, &acc, x))
+}
+
+/// Apply an arithmetic primitive like [rt_arithmetic](crate::numbers::rt_arithmetic)
+fn arithmetic(primitive: &str, a: &Value, b: &Value) -> Eval<Value> {
+    let (a, b) = (number(primitive, a)?, number(primitive, b)?);
+
+    let n = match (a, b) {
+        (Exact(a), Exact(b)) => {
+            if matches!(primitive, "/" | "%") && b == Big::from(0) {
+                return Err(Unwind::Fatal(format!("{}: division by zero", primitive)));
+            }
+            Exact(Big::apply(primitive, &a, &b))
+        }
+        (a, b) => {
+            let (x, y) = (a.inexact(), b.inexact());
+            Inexact(match primitive {
+                "+" | "inc" => x + y,
+                "-" | "dec" => x - y,
+                "*" => x * y,
+                "/" => x / y,
+                _ => x % y,
+            })
+        }
+    };
+
+    Ok(Value::Number(n))
+}
+
+/// Compare numbers like [rt_compare](crate::numbers::rt_compare)
+fn compare(primitive: &str, a: &Value, b: &Value) -> Eval<Value> {
+    let order = match (number(primitive, a)?, number(primitive, b)?) {
+        (Exact(a), Exact(b)) => Some(a.cmp(&b)),
+        (a, b) => a.inexact().partial_cmp(&b.inexact()),
+    };
+
+    Ok(Value::Boolean(match (primitive, order) {
+        (_, None) => false,
+        ("=", Some(o)) => o.is_eq(),
+        ("<", Some(o)) => o.is_lt(),
+        ("<=", Some(o)) => o.is_le(),
+        (">", Some(o)) => o.is_gt(),
+        (_, Some(o)) => o.is_ge(),
+    }))
+}
+
+/// The value of a number if it is a fixnum, which unlike others isn't boxed
+fn unboxed(n: &Number) -> Option<i64> {
+    match n {
+        Exact(n) => n.fixnum(),
+        Inexact(_) => None,
+    }
+}
+
+/// Are two values the same, or structurally equal if `deep`? See [rt::equal]
+fn equal(a: &Value, b: &Value, deep: bool) -> bool {
+    use Value::*;
+
+    match (a, b) {
+        (Nil, Nil) => true,
+        (Boolean(a), Boolean(b)) => a == b,
+        (Char(a), Char(b)) => a == b,
+        (Number(Inexact(x)), Number(Inexact(y))) => x.to_bits() == y.to_bits(),
+        (Number(x), Number(y)) => x == y,
+        (Symbol(a), Symbol(b)) => a == b,
+        (Str(a), Str(b)) => Rc::ptr_eq(a, b) || deep && a.bytes == b.bytes,
+        (Pair(a), Pair(b)) => {
+            Rc::ptr_eq(a, b) || deep && {
+                let (a, b) = (a.borrow(), b.borrow());
+                equal(&a.0, &b.0, deep) && equal(&a.1, &b.1, deep)
+            }
+        }
+        (Vector(a), Vector(b)) => {
+            Rc::ptr_eq(a, b) || deep && {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| equal(x, y, deep))
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler, parser::parse};
+    use pretty_assertions::assert_eq;
+
+    fn run(program: &str) -> Result<String, Error<'static>> {
+        let prog = compiler::prelude().into_iter().chain(parse(program).unwrap()).collect();
+        super::run(prog, Passes::default())
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(run("(cons 1 (cons 2 3))").unwrap(), "(1 2 . 3)");
+        assert_eq!(run("(vector (cons 1 2) \"a\" #\\b 'c)").unwrap(), "[(1 2) \"a\" #\\b 'c]");
+        assert_eq!(run("(display (vector (cons 1 2) \"a\" #\\b))").unwrap(), "[(1 . 2) a b]()");
+        assert_eq!(run("(* 1152921504606846975 4)").unwrap(), "4611686018427387900");
+        assert_eq!(run("(+ 1 2.5)").unwrap(), "3.5");
+        assert_eq!(run("(make-string 2)").unwrap(), "\"\"");
+        assert_eq!(run("(eq? \"a\" \"a\")").unwrap(), "#t");
+        assert_eq!(run("(list 1 (+ 1 1) 'c)").unwrap(), "(1 2 'c)");
+        assert_eq!(run("(define (f x) (* x x)) (map f (list 1 2 3))").unwrap(), "(1 4 9)");
+        assert_eq!(run("(apply + 1 (list 2 3))").unwrap(), "6");
+        assert_eq!(run("(* 1 2 3 4)").unwrap(), "24");
+        assert_eq!(run("(eq? 2 2)").unwrap(), "#t");
+        assert!(matches!(run("(eq? 1.5 1.5)"), Err(Error::Internal { .. })));
+    }
+
+    #[test]
+    fn control() {
+        let prog = "(define (loop n acc) (if (zero? n) acc (loop (dec n) (+ acc 1))))
+                    (loop 100000 0)";
+        assert_eq!(run(prog).unwrap(), "100000");
+
+        let prog = "(let ((f (lambda (k) (+ 1 (k 41))))) (+ 1 (call/cc f)))";
+        assert_eq!(run(prog).unwrap(), "42");
+
+        let prog = "(guard (e ((string? e) e)) (car 1))";
+        assert_eq!(run(prog).unwrap(), "\"car: expected pair, got 1\"");
+
+        let prog = "(let ((car (lambda (x) 42))) (cons (car (cons 1 2)) (cdr (cons 1 2))))";
+        assert_eq!(run(prog).unwrap(), "(42 . 2)");
+
+        assert!(matches!(run("(display 1) (car 1)"), Err(Error::Runtime(_))));
+        assert!(matches!(run("(/ 1 0)"), Err(Error::Runtime(_))));
+        assert_eq!(run("(display 1) (exit 0) 2").unwrap(), "1");
+    }
+
+    #[test]
+    fn wind() {
+        let prog = "(dynamic-wind (lambda () (display 1)) (lambda () 42) (lambda () (display 2)))";
+        assert_eq!(run(prog).unwrap(), "1242");
+
+        // After thunks run on the way out to a guard
+        let prog = "(guard (e (#t e))
+                      (dynamic-wind (lambda () (display 1))
+                                    (lambda () (raise 'x))
+                                    (lambda () (display 2))))";
+        assert_eq!(run(prog).unwrap(), "12'x");
+
+        // But not when nothing handles the object, which would exit here
+        let prog = "(dynamic-wind (lambda () #t) (lambda () (raise 'x)) (lambda () (exit 0)))";
+        assert!(matches!(run(prog), Err(Error::Runtime(_))));
+
+        // Other primitives taking functions are named, not the functions
+        let prog = "(with-exception-handler (lambda (e) 1) (lambda () 2))";
+        match run(prog) {
+            Err(Error::Internal { message, .. }) => {
+                assert_eq!(message, "The interpreter doesn't support `with-exception-handler`")
+            }
+            e => panic!("Expected an unsupported primitive, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(run("(define (f x) (g x)) (f 1)"), Err(Error::Compilation(_))));
+        assert!(matches!(run("(let ((x 1)) y)"), Err(Error::Compilation(_))));
+        assert!(matches!(run("(- 1 2 3)"), Err(Error::Internal { .. })));
+        assert!(matches!(run("(define (f x) x) (f 1 2)"), Err(Error::Internal { .. })));
+        assert!(matches!(run("(open-output-string)"), Err(Error::Internal { .. })));
+    }
+
+    #[test]
+    fn folding() {
+        let analyze = |program, fold| {
+            let mut s = State::new();
+            s.passes.fold = fold;
+            lang::analyze(&mut s, parse(program).unwrap())
+        };
+
+        assert_eq!(analyze("(- (* 6 7) (/ (% 10 4) 2))", true), analyze("41", false));
+        assert_eq!(analyze("(if (< 1 2) (inc 1) 3)", true), analyze("2", false));
+        assert_eq!(analyze("(char->integer #\\a)", true), analyze("97", false));
+
+        // Errors are left for run time and so are results that aren't literals
+        for program in &["(/ 1 0)", "(car 1)", "(* 4611686018 4611686018)"] {
+            assert_eq!(analyze(program, true), analyze(program, false));
+        }
+    }
+}
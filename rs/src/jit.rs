@@ -0,0 +1,335 @@
+//! Run generated code in memory
+//!
+//! Instead of writing the generated assembly to disk and building an
+//! executable with gcc, the JIT encodes the program with [asm](crate::asm),
+//! copies the machine code into a fresh executable mapping and calls it like
+//! any other function.
+//!
+//! Calls to the runtime are the only references outside the generated code.
+//! Their addresses are known only at run time and could be more than 2GB away
+//! from the mapping, which is out of reach of a 32 bit relative `call`. Every
+//! such call goes through a small trampoline placed right after the code
+//!
+//! ```asm
+//! print:
+//!     jmp qword ptr [rip + 0]
+//!     .quad <absolute address of print>
+//! ```
+//!
+//! The generated code assumes full control of R12 to manage the heap, while
+//! the calling convention expects it to be preserved. A small entry stub saves
+//! all the callee saved registers before calling `init`.
+use crate::{
+    asm, callbacks,
+    compiler::Fault,
+    continuations,
+    core::{Error, Ident},
+    eval, exceptions, ffi, gc, numbers, process,
+    rt::{self, Object},
+    symbols, threads,
+    x86::{self, Register::*, ASM},
+};
+use std::{collections::HashMap, ffi::CString, io, mem, ptr};
+
+/// Label of the entry stub
+const ENTRY: &str = "jit_entry";
+
+/// Executable image of a program, unmapped when dropped
+pub struct Image {
+    mem: *mut u8,
+    len: usize,
+    entry: usize,
+    symbols: HashMap<String, usize>,
+}
+
+/// Encode and load a program into executable memory
+pub fn load(asm: &ASM) -> Result<Image, Error<'static>> {
+    link(&(asm.clone() + entry()), ENTRY, |_| None)
+}
+
+/// Load the entry stub alone, calling an `init` at `address`
+///
+/// Used by executables to call the program linked into them, see
+/// [start](crate::start).
+pub fn stub(address: usize) -> Result<Image, Error<'static>> {
+    link(&entry(), ENTRY, |symbol| if symbol == x86::init() { Some(address) } else { None })
+}
+
+/// Load code that is called at the label `entry`, like [load]
+///
+/// References outside the code are looked up with `lookup` before the runtime,
+/// which lets separately loaded code call each other; see [eval](crate::eval).
+pub fn link(
+    asm: &ASM,
+    entry: &str,
+    lookup: impl Fn(&str) -> Option<usize>,
+) -> Result<Image, Error<'static>> {
+    let obj = asm::encode(asm)?;
+    let mut code = obj.code;
+    let mut trampolines: HashMap<&str, usize> = HashMap::new();
+    let mut undefined = vec![];
+
+    for r in &obj.relocations {
+        if undefined.contains(&r.symbol) {
+            continue;
+        }
+
+        if !trampolines.contains_key(r.symbol.as_str()) {
+            let address = match lookup(&r.symbol).or_else(|| resolve(&r.symbol)) {
+                Some(address) => address,
+                None => {
+                    undefined.push(r.symbol.clone());
+                    continue;
+                }
+            };
+
+            trampolines.insert(&r.symbol, code.len());
+            code.extend(&[0xFF, 0x25, 0, 0, 0, 0]);
+            code.extend(&(address as u64).to_le_bytes());
+        }
+
+        let value = trampolines[r.symbol.as_str()] as i64 + r.addend - r.offset as i64;
+        code[r.offset..r.offset + 4].copy_from_slice(&(value as i32).to_le_bytes());
+    }
+
+    // Every undefined reference is reported, not just the first one
+    if !undefined.is_empty() {
+        let undefined = undefined
+            .iter()
+            .map(|s| format!("Undefined reference to `{}`", Ident::unmangle(s).unwrap_or(s)));
+        return Err(Error::compilation(undefined.map(Fault::from).collect()));
+    }
+
+    let entry = obj.symbols[entry];
+    let symbols = obj.symbols;
+    let len = code.len();
+
+    unsafe {
+        let mem = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        );
+
+        if mem == libc::MAP_FAILED {
+            return Err(Error::Internal {
+                message: String::from("Failed to map memory for the JIT"),
+                e: Some(io::Error::last_os_error()),
+            });
+        }
+
+        ptr::copy_nonoverlapping(code.as_ptr(), mem as *mut u8, len);
+
+        let image = Image { mem: mem as *mut u8, len, entry, symbols };
+
+        if libc::mprotect(mem, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            return Err(Error::Internal {
+                message: String::from("Failed to make JIT memory executable"),
+                e: Some(io::Error::last_os_error()),
+            });
+        }
+
+        Ok(image)
+    }
+}
+
+impl Image {
+    /// Run the program with a fresh heap and inspect the result with `f`
+    ///
+    /// The result could point into the heap or the image itself, so it is
+    /// valid only within `f`. Each thread has its own heap, which is reused by
+    /// the next run on the same thread.
+    pub fn run<T>(&self, f: impl FnOnce(Object) -> T) -> T {
+        self.run_with(gc::gc_init(0, 0, 0), f)
+    }
+
+    /// Run the program with a heap created by [gc::gc_init], like [Image::run]
+    pub fn run_with<T>(&self, heap: gc::Space, f: impl FnOnce(Object) -> T) -> T {
+        exceptions::reset();
+        eval::reset();
+        threads::reset();
+
+        let exit = unsafe {
+            let init: extern "C" fn(*mut i64, *mut i64, *const u8) -> Exit =
+                mem::transmute(self.mem.add(self.entry));
+            init(heap.pointer, heap.limit, rt::rt_stack_limit())
+        };
+
+        // The heap counters are exact only with the final heap pointer
+        unsafe { gc::sync(exit.pointer) };
+
+        f(Object::new(exit.val))
+    }
+
+    /// Address of the entry point
+    pub fn entry(&self) -> usize {
+        self.mem as usize + self.entry
+    }
+
+    /// Address of a label defined in the image
+    pub fn address(&self, label: &str) -> Option<usize> {
+        self.symbols.get(label).map(|offset| self.mem as usize + offset)
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.mem as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// The value of a program and the heap pointer after it, in RAX & RDX
+#[repr(C)]
+struct Exit {
+    val: i64,
+    pointer: *mut i64,
+}
+
+/// Save callee saved registers, call `init` and restore them
+///
+/// The heap pointer in R12 is returned in RDX along with the value, see [Exit].
+fn entry() -> ASM {
+    let saved = [RBX, RBP, R12, R13, R14, R15];
+
+    let mut asm = ASM::from(vec![x86::label(ENTRY)]);
+
+    for r in &saved {
+        asm += x86::push((*r).into());
+    }
+
+    // 6 registers and the return address; realign the stack to 16 bytes
+    asm += x86::sub(RSP.into(), 8.into());
+    asm += x86::call(&x86::init());
+    asm += x86::add(RSP.into(), 8.into());
+    asm += x86::mov(RDX.into(), R12.into());
+
+    for r in saved.iter().rev() {
+        asm += x86::pop((*r).into());
+    }
+
+    asm + x86::ret()
+}
+
+/// Find the address of a runtime function, falling back to the C library
+fn resolve(symbol: &str) -> Option<usize> {
+    // On macos, C functions are prefixed with an underscore
+    let name = if cfg!(target_os = "macos") { symbol.trim_start_matches('_') } else { symbol };
+
+    // Functions defined in this crate aren't necessarily exported from the
+    // executable, so `dlsym` can't find them.
+    let runtime: &[(&str, *const ())] = &[
+        ("car", rt::car as *const ()),
+        ("current_jiffy", process::current_jiffy as *const ()),
+        ("gc_collect", gc::gc_collect as *const ()),
+        ("gc_collected", gc::gc_collected as *const ()),
+        ("gc_epoch", gc::gc_epoch as *const ()),
+        ("gc_finalizer", gc::gc_finalizer as *const ()),
+        ("gc_guard", gc::gc_guard as *const ()),
+        ("gc_guardian", gc::gc_guardian as *const ()),
+        ("gc_register_finalizer", gc::gc_register_finalizer as *const ()),
+        ("gc_force", gc::gc_force as *const ()),
+        ("gc_space", gc::gc_space as *const ()),
+        ("gc_stat", gc::gc_stat as *const ()),
+        ("gc_sync", gc::gc_sync as *const ()),
+        ("gc_remember", gc::gc_remember as *const ()),
+        ("heap_limit", gc::heap_limit as *const ()),
+        ("jiffies_per_second", process::jiffies_per_second as *const ()),
+        ("cdr", rt::cdr as *const ()),
+        ("print", rt::print as *const ()),
+        ("rt_get_output_string", rt::io::rt_get_output_string as *const ()),
+        ("rt_newline", rt::io::rt_newline as *const ()),
+        ("rt_new_key", threads::rt_new_key as *const ()),
+        ("rt_open_input_string", rt::io::rt_open_input_string as *const ()),
+        ("rt_open_output_string", rt::io::rt_open_output_string as *const ()),
+        ("rt_open_read", rt::io::rt_open_read as *const ()),
+        ("rt_open_write", rt::io::rt_open_write as *const ()),
+        ("rt_arithmetic", numbers::rt_arithmetic as *const ()),
+        ("rt_arithmetic_size", numbers::rt_arithmetic_size as *const ()),
+        ("rt_compare", numbers::rt_compare as *const ()),
+        ("rt_callback", callbacks::rt_callback as *const ()),
+        ("rt_callback_error", callbacks::rt_callback_error as *const ()),
+        ("rt_callback_result", callbacks::rt_callback_result as *const ()),
+        ("rt_close_port", rt::io::rt_close_port as *const ()),
+        ("rt_command_line", process::rt_command_line as *const ()),
+        ("rt_command_line_size", process::rt_command_line_size as *const ()),
+        ("rt_condition", exceptions::rt_condition as *const ()),
+        ("rt_deadlock", threads::rt_deadlock as *const ()),
+        ("rt_display", rt::io::rt_display as *const ()),
+        ("rt_eof_object", rt::io::rt_eof_object as *const ()),
+        ("rt_eq", rt::rt_eq as *const ()),
+        ("rt_equal", rt::rt_equal as *const ()),
+        ("rt_environment_size", process::rt_environment_size as *const ()),
+        ("rt_eval", eval::rt_eval as *const ()),
+        ("rt_eval_error", eval::rt_eval_error as *const ()),
+        ("rt_exit", threads::rt_exit as *const ()),
+        ("rt_get_environment_variable", process::rt_get_environment_variable as *const ()),
+        ("rt_handler_returned", exceptions::rt_handler_returned as *const ()),
+        ("rt_peek_char", rt::io::rt_peek_char as *const ()),
+        ("rt_pop_escape", continuations::rt_pop_escape as *const ()),
+        ("rt_pop_handler", exceptions::rt_pop_handler as *const ()),
+        ("rt_pop_wind", continuations::rt_pop_wind as *const ()),
+        ("rt_process_error", process::rt_process_error as *const ()),
+        ("rt_process_output", process::rt_process_output as *const ()),
+        ("rt_process_run", process::rt_process_run as *const ()),
+        ("rt_time", process::rt_time as *const ()),
+        ("rt_register_symbols", symbols::rt_register_symbols as *const ()),
+        ("rt_push_escape", continuations::rt_push_escape as *const ()),
+        ("rt_push_guard", exceptions::rt_push_guard as *const ()),
+        ("rt_push_handler", exceptions::rt_push_handler as *const ()),
+        ("rt_push_wind", continuations::rt_push_wind as *const ()),
+        ("rt_raise", exceptions::rt_raise as *const ()),
+        ("rt_range_error", rt::rt_range_error as *const ()),
+        ("rt_division_error", rt::rt_division_error as *const ()),
+        ("rt_foreign_call", ffi::rt_foreign_call as *const ()),
+        ("rt_hash", rt::rt_hash as *const ()),
+        ("rt_immutable_error", rt::rt_immutable_error as *const ()),
+        ("rt_load_shared_object", ffi::rt_load_shared_object as *const ()),
+        ("rt_string_to_symbol", symbols::rt_string_to_symbol as *const ()),
+        ("rt_symbol_to_string", symbols::rt_symbol_to_string as *const ()),
+        ("rt_read", rt::io::rt_read as *const ()),
+        ("rt_read_char", rt::io::rt_read_char as *const ()),
+        ("rt_read_datum", rt::io::rt_read_datum as *const ()),
+        ("rt_io_error", rt::io::rt_io_error as *const ()),
+        ("rt_resume", exceptions::rt_resume as *const ()),
+        ("rt_spawn", threads::rt_spawn as *const ()),
+        ("rt_stack_limit", rt::rt_stack_limit as *const ()),
+        ("rt_stack_overflow", rt::rt_stack_overflow as *const ()),
+        ("rt_switch", threads::rt_switch as *const ()),
+        ("rt_throw", continuations::rt_throw as *const ()),
+        ("rt_string_fill", rt::rt_string_fill as *const ()),
+        ("rt_string_ref", rt::rt_string_ref as *const ()),
+        ("rt_string_set", rt::rt_string_set as *const ()),
+        ("rt_standard_error_port", rt::io::rt_standard_error_port as *const ()),
+        ("rt_standard_input_port", rt::io::rt_standard_input_port as *const ()),
+        ("rt_standard_output_port", rt::io::rt_standard_output_port as *const ()),
+        ("rt_type_error", rt::rt_type_error as *const ()),
+        ("rt_unwind", exceptions::rt_unwind as *const ()),
+        ("rt_wake", threads::rt_wake as *const ()),
+        ("rt_write", rt::io::rt_write as *const ()),
+        ("rt_write_char", rt::io::rt_write_char as *const ()),
+        ("rt_write_datum", rt::io::rt_write_datum as *const ()),
+        ("rt_write_string", rt::io::rt_write_string as *const ()),
+        ("string_eq", rt::string_eq as *const ()),
+        ("string_length", rt::string_length as *const ()),
+        ("symbol_eq", rt::symbol_eq as *const ()),
+    ];
+
+    match runtime.iter().find(|(n, _)| *n == name) {
+        Some((_, address)) => Some(*address as usize),
+        None => {
+            let name = CString::new(name).ok()?;
+            let address = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+
+            if address.is_null() {
+                None
+            } else {
+                Some(address as usize)
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! A small JSON reader and writer
+//!
+//! Editors and notebooks talk to the tools with JSON messages, see
+//! [lsp](crate::lsp). This is just enough of [JSON] for that: values are read
+//! into a [Json] tree, looked into with [get](Json::get) and the `as_`
+//! accessors, and written back compactly with `Display`.
+//!
+//! ```
+//! use inc::json::Json;
+//!
+//! let message = Json::parse(r#"{"id": 1, "params": {"text": "(+ 1 2)"}}"#).unwrap();
+//! let text = message.get("params").and_then(|params| params.get("text"));
+//! assert_eq!(text.and_then(Json::as_str), Some("(+ 1 2)"));
+//!
+//! let reply = Json::object(vec![("id", 1.into()), ("result", Json::Null)]);
+//! assert_eq!(reply.to_string(), r#"{"id":1,"result":null}"#);
+//! ```
+//!
+//! [JSON]: https://www.json.org
+
+use std::fmt;
+
+/// A JSON value
+///
+/// Objects keep their keys in order, which is the order they are written in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parse a single value, surrounded by nothing but whitespace
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser { source, at: 0 };
+        let value = parser.value()?;
+
+        parser.space();
+        if parser.at < source.len() {
+            return Err(parser.error("Unexpected text after the value"));
+        }
+
+        Ok(value)
+    }
+
+    /// An object with the pairs in order
+    pub fn object(pairs: Vec<(&str, Json)>) -> Self {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// The value of a key of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The number, if it is an integer
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub const fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// Compact JSON, without any whitespace
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::Str(s) => write!(f, "{}", string(s)),
+            Json::Array(values) => {
+                let values: Vec<String> = values.iter().map(Json::to_string).collect();
+                write!(f, "[{}]", values.join(","))
+            }
+            Json::Object(pairs) => {
+                let pairs: Vec<String> =
+                    pairs.iter().map(|(k, v)| format!("{}:{}", string(k), v)).collect();
+                write!(f, "{{{}}}", pairs.join(","))
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Number(n.into())
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Json::Array(values)
+    }
+}
+
+/// A JSON string literal
+pub fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// A recursive descent parser over the source, from a byte offset
+struct Parser<'a> {
+    source: &'a str,
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at offset {}", message, self.at)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.at..]
+    }
+
+    fn space(&mut self) {
+        let rest = self.rest();
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skip `s` if the source continues with it
+    fn eat(&mut self, s: &str) -> bool {
+        self.space();
+        let found = self.rest().starts_with(s);
+        if found {
+            self.at += s.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.space();
+
+        match self.rest().chars().next() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::Str),
+            Some('-' | '0'..='9') => self.number(),
+            _ if self.eat("null") => Ok(Json::Null),
+            _ if self.eat("true") => Ok(Json::Bool(true)),
+            _ if self.eat("false") => Ok(Json::Bool(false)),
+            Some(_) => Err(self.error("Expected a value")),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.eat("{");
+        let mut pairs = vec![];
+
+        if self.eat("}") {
+            return Ok(Json::Object(pairs));
+        }
+
+        loop {
+            self.space();
+            if !self.rest().starts_with('"') {
+                return Err(self.error("Expected a key"));
+            }
+            let key = self.string()?;
+
+            if !self.eat(":") {
+                return Err(self.error("Expected `:` after a key"));
+            }
+            pairs.push((key, self.value()?));
+
+            if self.eat("}") {
+                return Ok(Json::Object(pairs));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected `,` or `}` in an object"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.eat("[");
+        let mut values = vec![];
+
+        if self.eat("]") {
+            return Ok(Json::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+
+            if self.eat("]") {
+                return Ok(Json::Array(values));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected `,` or `]` in an array"));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+
+        match rest[..end].parse() {
+            Ok(n) => {
+                self.at += end;
+                Ok(Json::Number(n))
+            }
+            Err(_) => Err(self.error("Invalid number")),
+        }
+    }
+
+    /// A string literal, starting at its opening quote
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut out = String::new();
+
+        loop {
+            let mut chars = self.rest().chars();
+
+            match chars.next() {
+                None => return Err(self.error("Unterminated string")),
+                Some('"') => {
+                    self.at += 1;
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    let c = match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            self.at += 2;
+                            let c = self.escape()?;
+                            out.push(c);
+                            continue;
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        _ => return Err(self.error("Invalid escape")),
+                    };
+                    out.push(c);
+                    self.at += 2;
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.at += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    /// The character of a `\u` escape after the `\u`, which takes two of them
+    /// for characters outside the basic plane
+    fn escape(&mut self) -> Result<char, String> {
+        let unit = self.hex()?;
+
+        if (0xD800..0xDC00).contains(&unit) && self.rest().starts_with("\\u") {
+            self.at += 2;
+            let low = self.hex()?;
+            let c = 0x10000 + ((unit - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+            return Ok(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+
+        Ok(char::from_u32(unit).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.rest().get(..4).ok_or_else(|| self.error("Invalid escape"))?;
+        let unit = u32::from_str_radix(digits, 16).map_err(|_| self.error("Invalid escape"))?;
+        self.at += 4;
+        Ok(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let json = Json::parse(r#" {"a": [1, -2.5, true, null], "b": {"c": "d\né😀"}} "#);
+        let json = json.unwrap();
+
+        assert_eq!(json.get("a").and_then(Json::as_array).map(<[Json]>::len), Some(4));
+        assert_eq!(json.get("a").unwrap().as_array().unwrap()[1], Json::Number(-2.5));
+        let c = json.get("b").and_then(|b| b.get("c"));
+        assert_eq!(c.and_then(Json::as_str), Some("d\né😀"));
+        assert_eq!(json.get("z"), None);
+        assert_eq!(Json::parse("[]"), Ok(Json::Array(vec![])));
+        assert_eq!(Json::parse("{}"), Ok(Json::Object(vec![])));
+
+        assert!(Json::parse("{\"a\" 1}").unwrap_err().starts_with("Expected `:` after a key"));
+        assert!(Json::parse("[1 2]").unwrap_err().starts_with("Expected `,` or `]`"));
+        assert!(Json::parse("\"abc").unwrap_err().starts_with("Unterminated string"));
+        assert!(Json::parse("1 2").unwrap_err().starts_with("Unexpected text"));
+        assert!(Json::parse("").is_err());
+    }
+
+    #[test]
+    fn print() {
+        let json = Json::object(vec![
+            ("id", 42.into()),
+            ("ok", true.into()),
+            ("text", "a \"b\"\n".into()),
+            ("values", vec![Json::Number(1.5), Json::Null].into()),
+        ]);
+        let text = r#"{"id":42,"ok":true,"text":"a \"b\"\n","values":[1.5,null]}"#;
+
+        assert_eq!(json.to_string(), text);
+        assert_eq!(Json::parse(text), Ok(json));
+    }
+}
@@ -0,0 +1,965 @@
+//! A kernel for Jupyter notebooks
+//!
+//! `inc jupyter FILE` runs a kernel for [Jupyter] notebooks and consoles, with
+//! the connection file the notebook starts it with. Cells are evaluated in a
+//! [Session], so functions defined in a cell are there for the cells run after
+//! it, and a cell may be a [Command] like `:expand` or `:asm`, just like the
+//! input of `inc repl`. The value of a cell is shown as its result and what it
+//! prints as its output, while errors and exceptions are reported as errors.
+//!
+//! Jupyter finds kernels by their specs, which `inc jupyter` without a file
+//! prints for the `inc` being run:
+//!
+//! ```text
+//! $ mkdir -p ~/.local/share/jupyter/kernels/inc
+//! $ inc jupyter > ~/.local/share/jupyter/kernels/inc/kernel.json
+//! ```
+//!
+//! Notebooks talk to kernels with [messages] over [ZeroMQ] sockets. There is no
+//! library for them here, so the kernel speaks just enough of [ZMTP] 3.0, the
+//! protocol of the sockets over TCP, to be a [Peer] of the notebook, with the
+//! NULL mechanism and no encryption. Messages are signed with HMAC-SHA256 and
+//! the key in the connection file, like the notebook expects.
+//!
+//! A cell runs to the end before anything is sent back, so its output shows
+//! up all at once rather than as it is printed.
+//!
+//! [Jupyter]: https://jupyter.org
+//! [messages]: https://jupyter-client.readthedocs.io/en/latest/messaging.html
+//! [ZeroMQ]: https://zeromq.org
+//! [ZMTP]: https://rfc.zeromq.org/spec/23
+
+use crate::{
+    hash::{hex, sha256},
+    json::Json,
+    repl::{self, Command, Session},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::io::AsRawFd,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the messaging protocol
+const PROTOCOL: &str = "5.3";
+
+/// Separates the identities of a message from the rest of it
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// Flags of a ZMTP frame
+const MORE: u8 = 1;
+const LONG: u8 = 2;
+const COMMAND: u8 = 4;
+
+/// The ports and the key from a connection file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub ip: String,
+    pub shell: u16,
+    pub control: u16,
+    pub stdin: u16,
+    pub iopub: u16,
+    pub heartbeat: u16,
+    pub key: String,
+}
+
+impl Connection {
+    pub fn parse(json: &Json) -> Result<Self, String> {
+        let text = |key| json.get(key).and_then(Json::as_str);
+        let port = |key| match json.get(key).and_then(Json::as_i64) {
+            Some(port @ 0..=65535) => Ok(port as u16),
+            _ => Err(format!("Expected a port for `{}` in the connection file", key)),
+        };
+
+        if text("transport").unwrap_or("tcp") != "tcp" {
+            return Err(String::from("Only the tcp transport is supported"));
+        }
+
+        match text("signature_scheme").unwrap_or("hmac-sha256") {
+            "hmac-sha256" => {}
+            scheme => return Err(format!("Unsupported signature scheme `{}`", scheme)),
+        }
+
+        Ok(Connection {
+            ip: text("ip").unwrap_or("127.0.0.1").to_string(),
+            shell: port("shell_port")?,
+            control: port("control_port")?,
+            stdin: port("stdin_port")?,
+            iopub: port("iopub_port")?,
+            heartbeat: port("hb_port")?,
+            key: text("key").unwrap_or_default().to_string(),
+        })
+    }
+}
+
+/// A message of the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The frames before the delimiter, the topic of a broadcast
+    pub identities: Vec<Vec<u8>>,
+    pub header: Json,
+    pub parent: Json,
+    pub metadata: Json,
+    pub content: Json,
+}
+
+impl Message {
+    /// The type of the message, like `execute_request`
+    pub fn kind(&self) -> &str {
+        self.header.get("msg_type").and_then(Json::as_str).unwrap_or_default()
+    }
+
+    /// The frames of the message, signed with `key`
+    pub fn encode(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let parts: Vec<Vec<u8>> = [&self.header, &self.parent, &self.metadata, &self.content]
+            .iter()
+            .map(|part| part.to_string().into_bytes())
+            .collect();
+
+        let mut frames = self.identities.clone();
+        frames.push(DELIMITER.to_vec());
+        frames.push(sign(key, &parts).into_bytes());
+        frames.extend(parts);
+        frames
+    }
+
+    /// Read a message from its frames, if it was signed with `key`
+    pub fn decode(frames: &[Vec<u8>], key: &[u8]) -> Result<Self, String> {
+        let at = frames
+            .iter()
+            .position(|frame| frame == DELIMITER)
+            .ok_or_else(|| String::from("Message without a delimiter"))?;
+
+        let rest = &frames[at + 1..];
+        if rest.len() < 5 {
+            return Err(String::from("Message with too few frames"));
+        }
+
+        let parts = &rest[1..5];
+        if !verify(key, parts, &rest[0]) {
+            return Err(String::from("Message with an invalid signature"));
+        }
+
+        let json = |part: &[u8]| Json::parse(&String::from_utf8_lossy(part));
+
+        Ok(Message {
+            identities: frames[..at].to_vec(),
+            header: json(&parts[0])?,
+            parent: json(&parts[1])?,
+            metadata: json(&parts[2])?,
+            content: json(&parts[3])?,
+        })
+    }
+}
+
+/// Where a message from the kernel goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    /// Back to the socket the request came from
+    Reply,
+    /// To every client, over the iopub socket
+    Broadcast,
+}
+
+/// The state of the kernel, the session the cells are evaluated in
+pub struct Kernel {
+    key: Vec<u8>,
+    /// Identifies the kernel in the header of every message
+    session: String,
+    /// Number of cells executed so far
+    count: usize,
+    repl: Session,
+}
+
+impl Kernel {
+    pub fn new(key: &[u8]) -> Self {
+        Kernel { key: key.to_vec(), session: id(), count: 0, repl: Session::new() }
+    }
+
+    /// The messages to send for a request, surrounded by the busy and idle
+    /// statuses of the kernel
+    ///
+    /// Requests the kernel knows nothing about get no reply, which the
+    /// protocol allows.
+    pub fn handle(&mut self, request: &Message) -> Vec<(Channel, Message)> {
+        let mut out = vec![self.status(request, "busy")];
+
+        match request.kind() {
+            "kernel_info_request" => out.push(self.reply(request, "kernel_info_reply", info())),
+            "execute_request" => out.extend(self.execute(request)),
+            "is_complete_request" => {
+                let code = request.content.get("code").and_then(Json::as_str);
+                let content = if repl::depth(code.unwrap_or_default()) > 0 {
+                    Json::object(vec![("status", "incomplete".into()), ("indent", "  ".into())])
+                } else {
+                    Json::object(vec![("status", "complete".into())])
+                };
+                out.push(self.reply(request, "is_complete_reply", content))
+            }
+            "comm_info_request" => {
+                let content = Json::object(vec![("status", "ok".into()), ("comms", object())]);
+                out.push(self.reply(request, "comm_info_reply", content))
+            }
+            "history_request" => {
+                let content = Json::object(vec![("status", "ok".into()), ("history", array())]);
+                out.push(self.reply(request, "history_reply", content))
+            }
+            "interrupt_request" => {
+                let content = Json::object(vec![("status", "ok".into())]);
+                out.push(self.reply(request, "interrupt_reply", content))
+            }
+            "shutdown_request" => {
+                let restart = request.content.get("restart").and_then(Json::as_bool);
+                let content = Json::object(vec![
+                    ("status", "ok".into()),
+                    ("restart", restart.unwrap_or(false).into()),
+                ]);
+                out.push(self.reply(request, "shutdown_reply", content))
+            }
+            _ => {}
+        }
+
+        out.push(self.status(request, "idle"));
+        out
+    }
+
+    /// Evaluate a cell, broadcasting its output and its value or error
+    fn execute(&mut self, request: &Message) -> Vec<(Channel, Message)> {
+        let code = request.content.get("code").and_then(Json::as_str).unwrap_or_default();
+        let silent = request.content.get("silent").and_then(Json::as_bool).unwrap_or(false);
+
+        if !silent {
+            self.count += 1;
+        }
+        let count = self.count;
+
+        let input = Json::object(vec![("code", code.into()), ("execution_count", count.into())]);
+        let mut out = vec![self.broadcast(request, "execute_input", input)];
+
+        let repl = &mut self.repl;
+        let (result, printed) = match capture(|| run(repl, code)) {
+            Ok((result, printed)) => (result, printed),
+            Err(e) => (Err(format!("Failed to capture the output: {}", e)), vec![]),
+        };
+
+        for (name, text) in ["stdout", "stderr"].iter().zip(printed) {
+            if !text.is_empty() && !silent {
+                let content = Json::object(vec![("name", (*name).into()), ("text", text.into())]);
+                out.push(self.broadcast(request, "stream", content));
+            }
+        }
+
+        let reply = match result {
+            Ok(value) => {
+                if !value.is_empty() && !silent {
+                    let data = Json::object(vec![("text/plain", value.into())]);
+                    let content = Json::object(vec![
+                        ("execution_count", count.into()),
+                        ("data", data),
+                        ("metadata", object()),
+                    ]);
+                    out.push(self.broadcast(request, "execute_result", content));
+                }
+
+                Json::object(vec![
+                    ("status", "ok".into()),
+                    ("execution_count", count.into()),
+                    ("payload", array()),
+                    ("user_expressions", object()),
+                ])
+            }
+            Err(e) => {
+                let (name, value) = match e.strip_prefix("Exception: ") {
+                    Some(message) => ("Exception", message.to_string()),
+                    None => ("Error", e.clone()),
+                };
+                let error = vec![
+                    ("ename", name.into()),
+                    ("evalue", value.into()),
+                    ("traceback", vec![Json::from(e)].into()),
+                ];
+
+                out.push(self.broadcast(request, "error", Json::object(error.clone())));
+
+                let mut reply = vec![("status", "error".into()), ("execution_count", count.into())];
+                reply.extend(error);
+                Json::object(reply)
+            }
+        };
+
+        out.push(self.reply(request, "execute_reply", reply));
+        out
+    }
+
+    fn reply(&self, request: &Message, kind: &str, content: Json) -> (Channel, Message) {
+        (Channel::Reply, self.message(request.identities.clone(), request, kind, content))
+    }
+
+    fn broadcast(&self, request: &Message, kind: &str, content: Json) -> (Channel, Message) {
+        let topic = format!("kernel.{}.{}", self.session, kind).into_bytes();
+        (Channel::Broadcast, self.message(vec![topic], request, kind, content))
+    }
+
+    fn status(&self, request: &Message, state: &str) -> (Channel, Message) {
+        self.broadcast(request, "status", Json::object(vec![("execution_state", state.into())]))
+    }
+
+    fn message(
+        &self,
+        identities: Vec<Vec<u8>>,
+        parent: &Message,
+        kind: &str,
+        content: Json,
+    ) -> Message {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let header = Json::object(vec![
+            ("msg_id", id().into()),
+            ("session", self.session.as_str().into()),
+            ("username", "inc".into()),
+            ("date", date(now).into()),
+            ("msg_type", kind.into()),
+            ("version", PROTOCOL.into()),
+        ]);
+
+        Message { identities, header, parent: parent.header.clone(), metadata: object(), content }
+    }
+}
+
+/// Run a kernel with the connection file at `path` till it is shut down
+pub fn serve(path: &str) -> i32 {
+    match start(path) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("inc jupyter: {}", e);
+            1
+        }
+    }
+}
+
+/// The kernel spec telling Jupyter how to start this kernel
+pub fn spec() -> Json {
+    let bin = env::current_exe().map_or_else(|_| String::from("inc"), |p| p.display().to_string());
+
+    Json::object(vec![
+        ("argv", vec![bin.into(), "jupyter".into(), "{connection_file}".into()].into()),
+        ("display_name", "Scheme (inc)".into()),
+        ("language", "scheme".into()),
+    ])
+}
+
+fn start(path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let connection = Connection::parse(&Json::parse(&text)?)?;
+
+    let listen = |port| {
+        TcpListener::bind((connection.ip.as_str(), port))
+            .map_err(|e| format!("Failed to listen on {}:{}: {}", connection.ip, port, e))
+    };
+
+    let shell = listen(connection.shell)?;
+    let control = listen(connection.control)?;
+    let stdin = listen(connection.stdin)?;
+    let iopub = listen(connection.iopub)?;
+    let heartbeat = listen(connection.heartbeat)?;
+
+    let kernel = Arc::new(Mutex::new(Kernel::new(connection.key.as_bytes())));
+    let subscribers: Arc<Mutex<Vec<Peer<TcpStream>>>> = Arc::default();
+
+    accept(heartbeat, "REP", |mut peer| {
+        while let Ok(frames) = peer.receive() {
+            if peer.send(&frames).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Nothing is ever asked of the user, but the notebook connects anyway
+    accept(stdin, "ROUTER", |mut peer| while peer.receive().is_ok() {});
+
+    // Subscriptions are read and ignored, everything goes to everyone
+    let all = Arc::clone(&subscribers);
+    accept(iopub, "PUB", move |mut peer| {
+        if let Ok(clone) = peer.try_clone() {
+            all.lock().unwrap().push(clone);
+            while peer.receive().is_ok() {}
+        }
+    });
+
+    for listener in [shell, control] {
+        let kernel = Arc::clone(&kernel);
+        let subscribers = Arc::clone(&subscribers);
+        accept(listener, "ROUTER", move |peer| answer(peer, &kernel, &subscribers));
+    }
+
+    // The kernel ends with a shutdown request
+    loop {
+        thread::park();
+    }
+}
+
+/// Accept connections to a socket, greeting each as a `kind` of socket and
+/// handing it to `f` on a thread of its own
+fn accept<F>(listener: TcpListener, kind: &'static str, f: F)
+where
+    F: Fn(Peer<TcpStream>) + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let f = Arc::clone(&f);
+            thread::spawn(move || match Peer::connect(stream, kind) {
+                Ok(peer) => f(peer),
+                Err(e) => eprintln!("inc jupyter: {}", e),
+            });
+        }
+    });
+}
+
+/// Answer the requests from a shell or control socket
+fn answer(
+    mut peer: Peer<TcpStream>,
+    kernel: &Mutex<Kernel>,
+    subscribers: &Mutex<Vec<Peer<TcpStream>>>,
+) {
+    while let Ok(frames) = peer.receive() {
+        let mut kernel = kernel.lock().unwrap();
+
+        let request = match Message::decode(&frames, &kernel.key) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("inc jupyter: {}", e);
+                continue;
+            }
+        };
+
+        for (channel, message) in kernel.handle(&request) {
+            let frames = message.encode(&kernel.key);
+
+            match channel {
+                Channel::Reply => {
+                    if peer.send(&frames).is_err() {
+                        return;
+                    }
+                }
+                Channel::Broadcast => {
+                    subscribers.lock().unwrap().retain_mut(|peer| peer.send(&frames).is_ok())
+                }
+            }
+        }
+
+        if request.kind() == "shutdown_request" {
+            process::exit(0)
+        }
+    }
+}
+
+/// Evaluate a cell, with errors and exceptions apart from its value
+fn run(repl: &mut Session, code: &str) -> Result<String, String> {
+    if code.trim_start().starts_with(':') {
+        match Command::parse(code)? {
+            Command::Quit => Ok(String::new()),
+            command => Ok(repl.command(command)),
+        }
+    } else {
+        repl.try_eval(code)
+    }
+}
+
+/// Run `f` with stdout and stderr going into files, returning its result and
+/// the text written to each
+///
+/// Compiled programs write to the file descriptors of the process, so they
+/// are moved out of the way for a while rather than just the streams of Rust.
+fn capture<T>(f: impl FnOnce() -> T) -> io::Result<(T, Vec<String>)> {
+    let fds = [libc::STDOUT_FILENO, libc::STDERR_FILENO];
+    let mut files = vec![];
+
+    for fd in fds.iter() {
+        let path = env::temp_dir().join(format!("inc-jupyter-{}-{}", process::id(), fd));
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        fs::remove_file(&path)?;
+        files.push(file);
+    }
+
+    io::stdout().flush()?;
+    let saved: Vec<i32> = fds.iter().map(|fd| unsafe { libc::dup(*fd) }).collect();
+    if saved.iter().any(|fd| *fd < 0) {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (fd, file) in fds.iter().zip(&files) {
+        unsafe { libc::dup2(file.as_raw_fd(), *fd) };
+    }
+
+    let val = f();
+
+    io::stdout().flush().ok();
+    io::stderr().flush().ok();
+
+    for (fd, old) in fds.iter().zip(saved) {
+        unsafe {
+            libc::dup2(old, *fd);
+            libc::close(old);
+        }
+    }
+
+    let mut printed = vec![];
+    for mut file in files {
+        let mut bytes = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        printed.push(String::from_utf8_lossy(&bytes).to_string());
+    }
+
+    Ok((val, printed))
+}
+
+/// What the kernel tells about itself
+fn info() -> Json {
+    let language = Json::object(vec![
+        ("name", "scheme".into()),
+        ("version", "".into()),
+        ("mimetype", "text/x-scheme".into()),
+        ("file_extension", ".ss".into()),
+        ("codemirror_mode", "scheme".into()),
+    ]);
+
+    Json::object(vec![
+        ("status", "ok".into()),
+        ("protocol_version", PROTOCOL.into()),
+        ("implementation", "inc".into()),
+        ("implementation_version", env!("CARGO_PKG_VERSION").into()),
+        ("language_info", language),
+        ("banner", "inc, an incremental compiler for Scheme".into()),
+        ("help_links", array()),
+    ])
+}
+
+const fn object() -> Json {
+    Json::Object(Vec::new())
+}
+
+const fn array() -> Json {
+    Json::Array(Vec::new())
+}
+
+/// A unique enough id for messages and sessions
+fn id() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let next = NEXT.fetch_add(1, Ordering::Relaxed);
+    let seed = format!("{:?} {} {}", SystemTime::now(), process::id(), next);
+    hex(&sha256(seed.as_bytes())[..16])
+}
+
+/// An ISO 8601 date in UTC for some time since the epoch
+fn date(since: Duration) -> String {
+    let secs = since.as_secs() as i64;
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        since.subsec_micros()
+    )
+}
+
+/// A connection to a socket of the notebook, after the handshake
+pub struct Peer<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> Peer<S> {
+    /// Greet the other end as a socket of `kind`, like `ROUTER`
+    pub fn connect(stream: S, kind: &str) -> io::Result<Self> {
+        let mut peer = Peer { stream };
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        peer.stream.write_all(&greeting())?;
+        let mut theirs = [0; 64];
+        peer.stream.read_exact(&mut theirs)?;
+
+        if theirs[0] != 0xFF || theirs[9] & 1 == 0 || theirs[10] < 3 {
+            return Err(invalid("Expected a peer speaking ZMTP 3"));
+        }
+        if &theirs[12..16] != b"NULL" {
+            return Err(invalid("Expected a peer with the NULL security mechanism"));
+        }
+
+        // Nothing in the properties of the other end matters here
+        let mut ready = vec![5];
+        ready.extend(b"READY");
+        ready.push(11);
+        ready.extend(b"Socket-Type");
+        ready.extend(&(kind.len() as u32).to_be_bytes());
+        ready.extend(kind.as_bytes());
+        peer.stream.write_all(&frame(COMMAND, &ready))?;
+
+        match peer.frame()? {
+            (flags, body) if flags & COMMAND != 0 && body.starts_with(b"\x05READY") => Ok(peer),
+            _ => Err(invalid("Expected a READY command from the peer")),
+        }
+    }
+
+    /// Receive the frames of a message, skipping any commands before it
+    pub fn receive(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = vec![];
+
+        loop {
+            let (flags, body) = self.frame()?;
+            if flags & COMMAND != 0 {
+                continue;
+            }
+
+            frames.push(body);
+            if flags & MORE == 0 {
+                return Ok(frames);
+            }
+        }
+    }
+
+    /// Send the frames of a message, in a single write
+    pub fn send(&mut self, frames: &[Vec<u8>]) -> io::Result<()> {
+        let mut bytes = vec![];
+
+        for (i, body) in frames.iter().enumerate() {
+            bytes.extend(frame(if i + 1 < frames.len() { MORE } else { 0 }, body));
+        }
+
+        self.stream.write_all(&bytes)
+    }
+
+    fn frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut flags = [0; 1];
+        self.stream.read_exact(&mut flags)?;
+
+        let size = if flags[0] & LONG != 0 {
+            let mut size = [0; 8];
+            self.stream.read_exact(&mut size)?;
+            u64::from_be_bytes(size) as usize
+        } else {
+            let mut size = [0; 1];
+            self.stream.read_exact(&mut size)?;
+            size[0] as usize
+        };
+
+        let mut body = vec![0; size];
+        self.stream.read_exact(&mut body)?;
+        Ok((flags[0], body))
+    }
+}
+
+impl Peer<TcpStream> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Peer { stream: self.stream.try_clone()? })
+    }
+}
+
+/// The greeting of ZMTP 3.0 with the NULL mechanism, as a client
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0; 64];
+    greeting[0] = 0xFF;
+    greeting[9] = 0x7F;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+/// A frame with its flags and size before the body
+fn frame(flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    if body.len() > 255 {
+        bytes.push(flags | LONG);
+        bytes.extend(&(body.len() as u64).to_be_bytes());
+    } else {
+        bytes.push(flags);
+        bytes.push(body.len() as u8);
+    }
+
+    bytes.extend(body);
+    bytes
+}
+
+/// The signature of the parts of a message, nothing without a key
+pub fn sign(key: &[u8], parts: &[Vec<u8>]) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+
+    hex(&hmac(key, parts).finalize().into_bytes())
+}
+
+/// Is `signature` the one of the parts of a message?
+///
+/// The digests are compared in constant time, so that the time it takes
+/// doesn't tell how much of a forged signature is right.
+pub fn verify(key: &[u8], parts: &[Vec<u8>], signature: &[u8]) -> bool {
+    if key.is_empty() {
+        return signature.is_empty();
+    }
+
+    let digest: Option<Vec<u8>> = signature
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect();
+
+    digest.map_or(false, |digest| hmac(key, parts).verify_slice(&digest).is_ok())
+}
+
+/// HMAC with SHA-256 of the parts of a message, see RFC 2104
+fn hmac(key: &[u8], parts: &[Vec<u8>]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+
+    for part in parts {
+        mac.update(part);
+    }
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reads from a buffer and writes into another
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request(kind: &str, content: Json) -> Message {
+        let header = Json::object(vec![("msg_id", "1".into()), ("msg_type", kind.into())]);
+        Message { identities: vec![], header, parent: object(), metadata: object(), content }
+    }
+
+    fn execute(kernel: &mut Kernel, code: &str) -> Vec<(Channel, Message)> {
+        let content = Json::object(vec![("code", code.into()), ("silent", false.into())]);
+        kernel.handle(&request("execute_request", content))
+    }
+
+    fn find<'a>(out: &'a [(Channel, Message)], kind: &str) -> Option<&'a Json> {
+        out.iter().find(|(_, m)| m.kind() == kind).map(|(_, m)| &m.content)
+    }
+
+    #[test]
+    fn signatures() {
+        // RFC 4231, the test cases but the fifth, which truncates the digest
+        let tests: [(Vec<u8>, Vec<u8>, &str); 6] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                (1..=25).collect(),
+                vec![0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size \
+                  data. The key needs to be hashed before being used by the HMAC algorithm."
+                    .to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+
+        for (key, data, digest) in tests.iter() {
+            let parts = std::slice::from_ref(data);
+            assert_eq!(sign(key, parts), *digest);
+            assert!(verify(key, parts, digest.as_bytes()));
+        }
+
+        // The parts are signed as one
+        let parts = [b"what do ya want ".to_vec(), b"for nothing?".to_vec()];
+        let signature = sign(b"Jefe", &parts);
+        assert_eq!(signature, tests[1].2);
+        assert!(verify(b"Jefe", &parts, signature.as_bytes()));
+
+        // Any other signature is wrong, whether or not it is a digest at all
+        assert!(!verify(b"Jefe", &parts, tests[0].2.as_bytes()));
+        assert!(!verify(b"Jefe", &parts, &signature.as_bytes()[..62]));
+        assert!(!verify(b"Jefe", &parts, b"not a digest"));
+        assert!(!verify(b"Jefe", &parts, b""));
+
+        assert_eq!(sign(b"", &parts), "");
+        assert!(verify(b"", &parts, b""));
+        assert!(!verify(b"", &parts, signature.as_bytes()));
+    }
+
+    #[test]
+    fn frames() {
+        let mut theirs = greeting().to_vec();
+        theirs.extend(frame(COMMAND, b"\x05READY"));
+        theirs.extend(frame(COMMAND, b"\x09SUBSCRIBE"));
+        theirs.extend(frame(MORE, b""));
+        theirs.extend(frame(0, &[7; 300]));
+
+        let pipe = Pipe { input: Cursor::new(theirs), output: vec![] };
+        let mut peer = Peer::connect(pipe, "REP").unwrap();
+
+        assert_eq!(&peer.stream.output[..64], &greeting()[..]);
+        assert!(peer.stream.output[64..].ends_with(b"Socket-Type\x00\x00\x00\x03REP"));
+        assert_eq!(peer.receive().unwrap(), vec![vec![], vec![7; 300]]);
+        assert!(peer.receive().is_err());
+
+        peer.stream.output.clear();
+        peer.send(&[b"a".to_vec(), vec![1; 256]]).unwrap();
+        assert_eq!(&peer.stream.output[..4], &[MORE, 1, b'a', LONG]);
+        assert_eq!(peer.stream.output.len(), 3 + 9 + 256);
+
+        let pipe = Pipe { input: Cursor::new(vec![0; 64]), output: vec![] };
+        assert!(Peer::connect(pipe, "REP").is_err());
+    }
+
+    #[test]
+    fn messages() {
+        let mut message = request("kernel_info_request", Json::object(vec![("a", 1.into())]));
+        message.identities = vec![b"client".to_vec()];
+
+        let frames = message.encode(b"key");
+        assert_eq!(frames[1], DELIMITER);
+        assert_eq!(Message::decode(&frames, b"key"), Ok(message.clone()));
+        assert_eq!(message.kind(), "kernel_info_request");
+
+        let e = Message::decode(&frames, b"other").unwrap_err();
+        assert_eq!(e, "Message with an invalid signature");
+        assert!(Message::decode(&frames[..4], b"key").is_err());
+
+        let json = Json::parse(
+            r#"{"shell_port": 1, "iopub_port": 2, "stdin_port": 3, "control_port": 4,
+                "hb_port": 5, "ip": "127.0.0.1", "key": "k", "transport": "tcp",
+                "signature_scheme": "hmac-sha256"}"#,
+        );
+        let connection = Connection::parse(&json.unwrap()).unwrap();
+        assert_eq!((connection.shell, connection.heartbeat), (1, 5));
+        assert_eq!(connection.key, "k");
+        assert!(Connection::parse(&Json::parse(r#"{"shell_port": "1"}"#).unwrap()).is_err());
+
+        assert_eq!(date(Duration::from_secs(0)), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(date(Duration::from_millis(1_709_210_096_500)), "2024-02-29T12:34:56.500000Z");
+    }
+
+    #[test]
+    fn kernel() {
+        let mut kernel = Kernel::new(b"");
+
+        let out = kernel.handle(&request("kernel_info_request", object()));
+        let kinds: Vec<(Channel, &str)> = out.iter().map(|(c, m)| (*c, m.kind())).collect();
+        assert_eq!(
+            kinds,
+            [
+                (Channel::Broadcast, "status"),
+                (Channel::Reply, "kernel_info_reply"),
+                (Channel::Broadcast, "status")
+            ]
+        );
+        let info = find(&out, "kernel_info_reply").unwrap();
+        let language = info.get("language_info").and_then(|l| l.get("name"));
+        assert_eq!(language.and_then(Json::as_str), Some("scheme"));
+        assert_eq!(out[1].1.parent.get("msg_id").and_then(Json::as_str), Some("1"));
+
+        let out = execute(&mut kernel, "(define (twice x) (* x 2))");
+        assert_eq!(find(&out, "execute_result"), None);
+
+        let out = execute(&mut kernel, "(twice 21)");
+        let result = find(&out, "execute_result").unwrap();
+        let value = result.get("data").and_then(|data| data.get("text/plain"));
+        assert_eq!(value.and_then(Json::as_str), Some("42"));
+        assert_eq!(result.get("execution_count").and_then(Json::as_i64), Some(2));
+        let reply = find(&out, "execute_reply").unwrap();
+        assert_eq!(reply.get("status").and_then(Json::as_str), Some("ok"));
+
+        let out = execute(&mut kernel, "(car 1)");
+        let error = find(&out, "error").unwrap();
+        assert_eq!(error.get("ename").and_then(Json::as_str), Some("Exception"));
+        assert_eq!(
+            error.get("evalue").and_then(Json::as_str),
+            Some("car: expected pair, got 1")
+        );
+        let reply = find(&out, "execute_reply").unwrap();
+        assert_eq!(reply.get("status").and_then(Json::as_str), Some("error"));
+
+        let out = execute(&mut kernel, ":expand (twice 1)");
+        let result = find(&out, "execute_result").unwrap();
+        let value = result.get("data").and_then(|data| data.get("text/plain"));
+        assert_eq!(value.and_then(Json::as_str), Some("(twice 1)"));
+
+        let out = execute(&mut kernel, "(twice y)");
+        let error = find(&out, "error").unwrap();
+        assert_eq!(error.get("ename").and_then(Json::as_str), Some("Error"));
+
+        let mut complete = |code: &str| {
+            let content = Json::object(vec![("code", code.into())]);
+            let out = kernel.handle(&request("is_complete_request", content));
+            find(&out, "is_complete_reply").and_then(|c| c.get("status")).cloned()
+        };
+        assert_eq!(complete("(define (f x)"), Some("incomplete".into()));
+        assert_eq!(complete("(f 1)"), Some("complete".into()));
+    }
+}
@@ -29,20 +29,49 @@
 //! ⚠ This module implements the stack version for now, but must be migrated to
 //! SysV at some point.
 use crate::{
-    compiler::{emit::eval, state::State},
-    core::{Closure, Core, Expr, Ident},
+    compiler::{self, emit::eval, state::State},
+    core::{Closure, Core, Expr, Ident, Literal::*},
+    exceptions, ffi, host, immediate, primitives, profile, tags,
     x86::{self, Reference, Register::*, Relative, ASM, WORDSIZE},
 };
+use std::panic;
+
+/// Code generated for a function, along with the labels its fork of the state
+/// used and the errors it found
+type Function = (u64, ASM, Vec<compiler::Fault>);
 
 /// Emit machine code for all top level functions
+///
+/// Lifted functions are independent of each other, so they are generated on
+/// the [pool](host::parallel) of threads, each with its own fork of the state.
+/// The forks are [joined](State::join) in the original order to keep the
+/// output deterministic. A function that fails to compile doesn't stop the
+/// others, the errors of all of them are kept in the state together.
 pub fn emit(s: &mut State, exprs: &[Core]) -> ASM {
-    let mut asm = ASM(vec![]);
+    let jobs: Vec<Box<dyn FnOnce() -> Function + Send>> = exprs
+        .iter()
+        .filter_map(|expr| expr.function())
+        .map(|(name, code)| {
+            let (mut s, name, code) = (s.fork(), name.clone(), code.clone());
+
+            Box::new(move || {
+                let asm = emit1(&mut s, &name, &code);
+                (s.labels(), asm, s.errors)
+            }) as Box<dyn FnOnce() -> Function + Send>
+        })
+        .collect();
+
+    let functions = host::parallel(jobs);
+
+    // Keep the errors of every function together, in the order of the program
+    let mut asm = ASM::default();
 
-    for expr in exprs.iter() {
-        if let Expr::Define { name, val: box Expr::Lambda(c) } = expr {
-            asm += emit1(s, &name, c)
-        }
+    for function in functions {
+        let (labels, f, errors) = function.unwrap_or_else(|e| panic::resume_unwind(e));
+        asm += s.join(labels, f);
+        s.errors.extend(errors);
     }
+
     asm
 }
 
@@ -61,9 +90,9 @@ pub fn emit(s: &mut State, exprs: &[Core]) -> ASM {
 /// such that the such that the first argument can be accessed at `RBP - 8`, the
 /// next one at `RBP - 16` etc.
 fn emit1(s: &mut State, name: &Ident, code: &Closure<Ident>) -> ASM {
-    let mut asm = ASM(vec![]);
+    let mut asm = ASM::default();
 
-    asm += x86::func(&name.to_string());
+    asm += x86::func(&name.mangle());
 
     // Start a new lexical environment for the function, add the formal
     // arguments and leave when it is evaluated. The first argument is available
@@ -77,17 +106,36 @@ fn emit1(s: &mut State, name: &Ident, code: &Closure<Ident>) -> ASM {
         s.set(arg.clone(), Relative { register: RBP, offset: -(i as i64 + 1) * WORDSIZE }.into());
     }
 
+    // The body is evaluated in order within a single frame and the value of
+    // the last expression is left in RAX as the result.
+    asm += x86::enter();
+    asm += guard(s);
+    asm += profile::call(s, &name.to_string());
     for b in &code.body {
-        asm += x86::enter();
-        asm += eval(s, &b);
-        asm += x86::leave()
+        asm += eval(s, b);
     }
+    asm += x86::leave();
 
     s.leave();
 
     asm
 }
 
+/// Check for stack overflow on entry to a function
+///
+/// R15 holds the lowest address the stack may grow to, leaving enough room for
+/// the frame of any single function and to report the error from the runtime.
+/// See [rt_stack_limit](crate::rt::rt_stack_limit).
+pub fn guard(s: &mut State) -> ASM {
+    let ok = s.gen_label("stack");
+
+    x86::cmp(R15.into(), RSP.into())
+        + x86::jle(&ok)
+        + ffi::runtime(s, "rt_stack_overflow")
+        + exceptions::fail(s)
+        + x86::label(&ok)
+}
+
 /// Emit code for a function application. See `code` for details.
 pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
     // Evaluate and push the arguments into stack; 2 words below SI. See
@@ -97,13 +145,21 @@ pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
     //     816 -> ...
     //     808 -> arg 1
     //     800 -> arg 2
-    let mut asm = ASM(vec![]);
+    let mut asm = ASM::default();
 
     // The original stack index - used for remembering index after recursive
     // calls. This wouldn't be required if we could pass an immutable copy into
     // recursive calls of eval.
     let si = s.si;
 
+    // The slots for the return address and the base pointer of the callee are
+    // part of the live frame while the arguments are evaluated. Clear them so
+    // that the garbage collector never sees stale values, see `gc`.
+    if !args.is_empty() {
+        asm += x86::save(Reference::Const(0), si);
+        asm += x86::save(Reference::Const(0), si - WORDSIZE);
+    }
+
     // Lack of persistent state makes this code fairly difficult to understand
     // and this is a whole lot more complex than it looks like. The recursive
     // definition in scheme with persistent `s` is significantly cleaner.
@@ -116,6 +172,216 @@ pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
     // Set stack index back to where it used to be after evaluating all args
     s.si = si;
 
+    asm + invoke(s, name)
+}
+
+/// Emit code for `(apply f args... list)`
+///
+/// The arguments before the list are evaluated into the argument area just
+/// like [call]; the elements of the list are copied into the slots after them
+/// at run time, one at a time, before calling `f`. The list must be a proper
+/// list.
+pub fn apply(s: &mut State, f: &Ident, args: &[Core], list: &Core) -> ASM {
+    function(s, "apply", f);
+
+    let (next, error, overflow, done) =
+        (s.gen_label("next"), s.gen_label("error"), s.gen_label("overflow"), s.gen_label("call"));
+
+    let si = s.si;
+    let mut asm =
+        x86::save(Reference::Const(0), si) + x86::save(Reference::Const(0), si - WORDSIZE);
+
+    for (i, arg) in args.iter().enumerate() {
+        s.si = si - ((i as i64 + 2) * WORDSIZE);
+        asm += eval(s, arg);
+        asm += x86::save(RAX.into(), s.si);
+    }
+
+    // The slot of the first element stays free while the list is evaluated
+    s.si = si - ((args.len() as i64 + 2) * WORDSIZE);
+    asm += eval(s, list);
+    asm += x86::mov(RDI.into(), RBP.into());
+    asm += x86::add(RDI.into(), s.si.into());
+    s.si = si;
+
+    // Nothing is allocated in the loop, so the list can stay in RAX and the
+    // address of the next slot in RDI
+    asm += x86::label(&next);
+    asm += x86::cmp(RAX.into(), immediate::NIL.into());
+    asm += x86::je(&done);
+    asm += tags::load_tag(R11, RAX);
+    asm += x86::cmp(R11.into(), immediate::PAIR.into());
+    asm += x86::jne(&error);
+    asm += x86::cmp(RDI.into(), R15.into());
+    asm += x86::jb(&overflow);
+    asm += x86::mov(R11.into(), Reference::from(RAX - immediate::PAIR));
+    asm += x86::mov(Reference::from(RDI + 0), R11.into());
+    asm += x86::mov(RAX.into(), Reference::from(RAX + (WORDSIZE - immediate::PAIR)));
+    asm += x86::sub(RDI.into(), WORDSIZE.into());
+    asm += x86::jmp(&next);
+
+    asm += x86::label(&error);
+    asm += primitives::type_error(s, "apply", immediate::PAIR);
+    asm += x86::label(&overflow);
+    asm += ffi::runtime(s, "rt_stack_overflow");
+    asm += exceptions::fail(s);
+
+    asm + x86::label(&done) + invoke(s, f)
+}
+
+/// Emit code for `(apply f args... list)` where `f` is a [variadic] primitive
+///
+/// The primitive is folded over the arguments and then over the elements of
+/// the list in a loop, since it can't be called with all of them at once.
+///
+/// [variadic]: primitives::variadic
+pub fn reduce(s: &mut State, f: &Ident, args: &[Core], list: &Core) -> ASM {
+    let (next, error, done) = (s.gen_label("next"), s.gen_label("error"), s.gen_label("reduced"));
+
+    let identity = Expr::Literal(Number(primitives::variadic(f).unwrap_or_default()));
+    let name = f.short();
+    let init = args.iter().cloned().fold(identity, |acc, x| form(&name, vec![acc, x]));
+
+    let mut asm = eval(s, &init);
+    s.enter();
+
+    let [acc, rest] = ["%acc", "%rest"].map(|name| {
+        let slot = s.si;
+        s.set(Ident::new(name), Relative { register: RBP, offset: slot }.into());
+        (Expr::Identifier(Ident::new(name)), slot)
+    });
+
+    asm += x86::save(RAX.into(), acc.1);
+    asm += x86::save(immediate::NIL.into(), rest.1);
+    asm += eval(s, list);
+    asm += x86::save(RAX.into(), rest.1);
+
+    asm += x86::label(&next);
+    asm += x86::load(RAX, rest.1);
+    asm += x86::cmp(RAX.into(), immediate::NIL.into());
+    asm += x86::je(&done);
+    asm += tags::load_tag(R11, RAX);
+    asm += x86::cmp(R11.into(), immediate::PAIR.into());
+    asm += x86::jne(&error);
+
+    asm += eval(s, &form(&name, vec![acc.0.clone(), form("car", vec![rest.0.clone()])]));
+    asm += x86::save(RAX.into(), acc.1);
+    asm += eval(s, &form("cdr", vec![rest.0]));
+    asm += x86::save(RAX.into(), rest.1);
+    asm += x86::jmp(&next);
+
+    asm += x86::label(&error);
+    asm += primitives::type_error(s, "apply", immediate::PAIR);
+    asm += x86::label(&done);
+    asm += x86::load(RAX, acc.1);
+
+    s.leave();
+    asm
+}
+
+/// Emit code for `(map f list)`
+///
+/// The results of calling `f` on the elements in order are collected into a
+/// new list, each pair linked to the last one as it is allocated. The list
+/// being mapped and the new one live in local variables so that the garbage
+/// collector sees them across the calls.
+pub fn map(s: &mut State, f: &Ident, list: &Core) -> ASM {
+    function(s, "map", f);
+
+    let (next, link, linked, error, done) = (
+        s.gen_label("next"),
+        s.gen_label("link"),
+        s.gen_label("linked"),
+        s.gen_label("error"),
+        s.gen_label("mapped"),
+    );
+
+    let mut asm = eval(s, list);
+    s.enter();
+
+    let [rest, head, tail, val] = ["%rest", "%head", "%tail", "%value"].map(|name| {
+        let slot = s.si;
+        s.set(Ident::new(name), Relative { register: RBP, offset: slot }.into());
+        (Expr::Identifier(Ident::new(name)), slot)
+    });
+
+    asm += x86::save(RAX.into(), rest.1);
+    for (_, slot) in [&head, &tail, &val] {
+        asm += x86::save(immediate::NIL.into(), *slot);
+    }
+
+    asm += x86::label(&next);
+    asm += x86::load(RAX, rest.1);
+    asm += x86::cmp(RAX.into(), immediate::NIL.into());
+    asm += x86::je(&done);
+    asm += tags::load_tag(R11, RAX);
+    asm += x86::cmp(R11.into(), immediate::PAIR.into());
+    asm += x86::jne(&error);
+
+    asm += call(s, f, &[form("car", vec![rest.0.clone()])]);
+    asm += x86::save(RAX.into(), val.1);
+    asm += eval(s, &form("cons", vec![val.0.clone(), Expr::Literal(Nil)]));
+    asm += x86::save(RAX.into(), val.1);
+
+    // The first pair is the head of the new list, the others follow the last
+    asm += x86::load(R11, tail.1);
+    asm += x86::cmp(R11.into(), immediate::NIL.into());
+    asm += x86::jne(&link);
+    asm += x86::save(RAX.into(), head.1);
+    asm += x86::jmp(&linked);
+    asm += x86::label(&link);
+    asm += eval(s, &form("set-cdr!", vec![tail.0.clone(), val.0.clone()]));
+    asm += x86::label(&linked);
+    asm += x86::load(RAX, val.1);
+    asm += x86::save(RAX.into(), tail.1);
+
+    asm += eval(s, &form("cdr", vec![rest.0]));
+    asm += x86::save(RAX.into(), rest.1);
+    asm += x86::jmp(&next);
+
+    asm += x86::label(&error);
+    asm += primitives::type_error(s, "map", immediate::PAIR);
+    asm += x86::label(&done);
+    asm += x86::load(RAX, head.1);
+
+    s.leave();
+    asm
+}
+
+/// Keep an error unless `f` names a function, for a primitive that takes one
+///
+/// Functions aren't values yet; they are passed to primitives like `call/cc`
+/// by name, anonymous lambdas included once they are [lifted]. A local
+/// variable can't refer to one.
+///
+/// [lifted]: crate::lang::lifted
+pub fn function(s: &mut State, primitive: &str, f: &Ident) {
+    if s.get(f).is_some() {
+        s.fail(expected(primitive, f))
+    }
+}
+
+/// Error for a primitive given a local variable where it takes a function
+///
+/// Arguments that aren't as simple as a name are bound to temporaries like
+/// `_0` in [A-normal form](crate::lang), which aren't worth naming.
+pub fn expected(primitive: &str, f: &Ident) -> String {
+    let name = f.short();
+
+    if f.free().is_some() && name.starts_with('_') && name[1..].parse::<usize>().is_ok() {
+        format!("{} expects a function, got an expression that isn't one", primitive)
+    } else {
+        format!("{} expects a function, got the variable `{}`", primitive, name)
+    }
+}
+
+/// The call of a primitive, for the code emitted by a primitive in turn
+fn form(name: &str, args: Vec<Core>) -> Core {
+    Expr::List(std::iter::once(Expr::Identifier(Ident::new(name))).chain(args).collect())
+}
+
+/// Call `name` with the arguments in place right below the stack index
+fn invoke(s: &State, name: &Ident) -> ASM {
     // Extend the current stack frame to hold the local variables before
     // creating a new one. The called function might clobber the stack
     // corrupting local variables in previous scopes.
@@ -134,13 +400,13 @@ pub fn call(s: &mut State, name: &Ident, args: &[Core]) -> ASM {
     // debuggers - both GDB and valgrind. Allocating at least 16bytes seems to
     // work on both targets.
     let locals = -(s.si + WORDSIZE);
-    if locals != 0 {
-        asm += x86::sub(RSP.into(), Reference::Const(locals));
-        asm += x86::call(&name.to_string());
-        asm += x86::add(RSP.into(), Reference::Const(locals));
+    let asm = if locals != 0 {
+        x86::sub(RSP.into(), Reference::Const(locals))
+            + x86::call(&name.mangle())
+            + x86::add(RSP.into(), Reference::Const(locals))
     } else {
-        asm += x86::call(&name.to_string())
-    }
+        x86::call(&name.mangle()).into()
+    };
 
     // NOTE: This is one of those big aha moments.
     //
@@ -1,25 +1,659 @@
 //! High level language analysis and transformations.
 use {
     crate::{
-        compiler::state::State,
+        compiler::{self, state::State, Fault},
         core::{Expr::*, Literal::*, *},
+        callbacks, ffi, hash,
+        host::{self, Clock},
+        interp,
+        library::Libraries,
+        parser, primitives, rt, validate,
+    },
+    std::{
+        borrow::Cow, clone::Clone, collections::HashSet, fmt, panic, sync::Arc, time::Duration,
     },
-    std::{clone::Clone, collections::HashMap},
 };
 
 /// Perform all language transformations and analysis on the syntax tree
 ///
-/// A syntax tree is renamed into unique references, lambdas lifted to top level
-/// and then program broken down into simpler ANF expressions and then tail
-/// calls are annotated with a marker.
-pub fn analyze(s: &mut State, prog: Vec<Syntax>) -> Vec<Core> {
-    prog.into_iter()
-        .map(|e| rename(&HashMap::new(), &Ident::empty(), 0, e))
-        .flat_map(lift)
-        .map(|e| inline(s, e))
-        .map(anf)
-        .map(tco)
-        .collect()
+/// Derived syntax is expanded into simpler forms, the syntax tree is renamed
+/// into unique references, lambdas lifted to top level, constants folded and
+/// then program broken down into simpler ANF expressions and then tail calls
+/// are annotated with a marker. The last three are optional, see [Passes].
+pub fn analyze(s: &mut State, prog: Vec<Syntax>) -> Result<Vec<Core>, Vec<Fault>> {
+    let prog = traced(s, prog, &mut |_| {});
+    s.check()?;
+    Ok(prog)
+}
+
+/// A pass of the compiler that just ran, as shown by [traced]
+pub struct Pass<'a> {
+    pub name: &'a str,
+    /// Wall time the pass took
+    pub time: Duration,
+    /// The program the pass produced
+    pub program: &'a dyn Program,
+}
+
+/// A program as it is between two passes
+pub trait Program {
+    /// The program one expression, or instruction, a line
+    fn show(&self) -> String;
+    /// Size of the program, in [nodes](Expr::nodes) or instructions
+    fn nodes(&self) -> usize;
+}
+
+impl<T: Clone + fmt::Display> Program for Vec<Expr<T>> {
+    fn show(&self) -> String {
+        self.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")
+    }
+
+    fn nodes(&self) -> usize {
+        self.iter().map(Expr::nodes).sum()
+    }
+}
+
+/// [Analyze](analyze) a program and show it to `trace` after every pass
+///
+/// `trace` is given every [Pass] as it finishes, along with the time it took.
+/// Optional passes that don't run aren't traced.
+///
+/// Top level expressions with invalid syntax are dropped and the errors kept
+/// in the state, so that the rest of the program is still checked. A program
+/// with files that can't be loaded or names bound twice is dropped whole.
+pub fn traced(s: &mut State, prog: Vec<Syntax>, trace: &mut dyn FnMut(&Pass)) -> Vec<Core> {
+    let passes = s.passes;
+    let unit = s.unit.as_ref().map_or_else(Ident::empty, Ident::new);
+
+    // Time spent tracing isn't counted towards the next pass
+    let mut clock = Clock::start();
+    let mut done = |name: &str, program: &dyn Program| {
+        trace(&Pass { name, time: clock.elapsed(), program });
+        clock = Clock::start();
+    };
+
+    let mut libraries = Libraries::new();
+    let prog: Vec<Syntax> = s
+        .attempt(|_| libraries.resolve(load(prog)?))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|e| {
+            s.attempt(|_| {
+                validate::check(&e)?;
+                expand(e)
+            })
+        })
+        .collect();
+    Arc::make_mut(&mut s.exports).extend(libraries.exports());
+    done("expanded", &prog);
+
+    let prog = s.attempt(|_| renames(&unit, prog)).unwrap_or_default();
+    done("renamed", &prog);
+
+    let prog = lifted(&unit, prog);
+    done("lifted", &prog);
+
+    let inlined = parallel(prog, move |e| {
+        let mut literals = Literals::default();
+        let e = inline(&mut literals, e);
+        (e, literals)
+    });
+
+    // Make room for every literal up front, even the ones repeated
+    let (strings, symbols) = inlined.iter().fold((0, 0), |(strings, symbols), (_, literals)| {
+        (strings + literals.strings.len(), symbols + literals.symbols.len())
+    });
+    Arc::make_mut(&mut s.strings).reserve(strings);
+    Arc::make_mut(&mut s.symbols).reserve(symbols);
+
+    let mut prog: Vec<Core> = inlined
+        .into_iter()
+        .map(|(e, literals)| {
+            literals.merge(s);
+            e
+        })
+        .collect();
+    done("inlined", &prog);
+
+    if passes.fold {
+        prog = prog.into_iter().map(interp::fold).collect();
+        done("folded", &prog);
+    }
+
+    if passes.anf {
+        prog = prog.into_iter().map(anf).collect();
+        done("anf", &prog);
+    }
+
+    if passes.tco {
+        prog = prog.into_iter().map(tco).collect();
+        done("tco", &prog);
+    }
+
+    prog
+}
+
+/// The program with derived syntax expanded and every name made unique
+pub fn renamed(prog: Vec<Syntax>) -> Result<Vec<Core>, Vec<Fault>> {
+    namespaced(&Ident::empty(), prog)
+}
+
+/// Rename a program compiled as part of a unit
+///
+/// Names bound in top level expressions are namespaced by the unit, so that the
+/// functions lifted out of them don't clash with the ones of other units. Top
+/// level definitions keep their names since they are visible to all units.
+fn namespaced(unit: &Ident, prog: Vec<Syntax>) -> Result<Vec<Core>, Vec<Fault>> {
+    renames(unit, expanded(prog)?)
+}
+
+/// The program with files loaded, libraries resolved and syntax expanded
+fn expanded(prog: Vec<Syntax>) -> Result<Vec<Syntax>, Vec<Fault>> {
+    compiler::all(Libraries::new().resolve(load(prog)?)?.into_iter().map(expand))
+}
+
+/// Rename every top level expression, with the errors of all of them
+fn renames(unit: &Ident, prog: Vec<Syntax>) -> Result<Vec<Core>, Vec<Fault>> {
+    let unit = unit.clone();
+
+    // Definitions at the top level are all global, so they can't share a name
+    let defined = prog.iter().filter_map(|e| match e {
+        Define { name, .. } => Some(name),
+        _ => None,
+    });
+    unique(defined.clone(), |name| format!("Duplicate definition `{}`", name))?;
+
+    // References to definitions named like a primitive refer to them instead
+    let shadowing: Arc<Vec<(String, Ident)>> = Arc::new(
+        defined
+            .filter(|name| shadows(name))
+            .map(|name| (name.to_string(), Ident::shadowing(name)))
+            .collect(),
+    );
+
+    let renamed = parallel(prog, move |e| {
+        let names: Vec<(&str, Ident)> =
+            shadowing.iter().map(|(name, ident)| (name.as_str(), ident.clone())).collect();
+        let mut env = Env::default();
+        env.bind(&names);
+
+        match e {
+            Define { .. } => rename(&mut env, &Ident::empty(), 0, e),
+            _ => rename(&mut env, &unit, 0, e),
+        }
+    });
+
+    compiler::all(renamed)
+}
+
+/// Is a definition named like a primitive, a function of the runtime or a
+/// callback? It shadows them, see [Ident::shadowing].
+fn shadows(name: &str) -> bool {
+    primitives::names().contains(&name)
+        || rt::defined(&Ident::new(name))
+        || callbacks::find(name).is_some()
+}
+
+/// A renamed program with all lambdas lifted to the top level
+///
+/// Anonymous lambdas are named after the definition they are in, or after the
+/// position of their top level expression in the `unit` otherwise.
+pub fn lifted(unit: &Ident, prog: Vec<Core>) -> Vec<Core> {
+    let unit = unit.clone();
+    let prog: Vec<(usize, Core)> = prog.into_iter().enumerate().collect();
+
+    parallel(prog, move |(i, e)| {
+        let base = match &e {
+            Define { name, .. } => name.clone(),
+            _ => unit.extend(format!("{{expr {}}}", i)),
+        };
+        lift(&base, e)
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Run a pass over every top level expression of a program on a few threads
+///
+/// Once derived syntax is expanded, top level expressions are renamed, lifted
+/// and inlined independently of each other. The program is split into a run of
+/// expressions for every thread of the [pool](host::parallel) and the results
+/// are put back together in the same order, so the output doesn't depend on
+/// which thread finishes first.
+fn parallel<A, B, F>(mut prog: Vec<A>, f: F) -> Vec<B>
+where
+    A: Send + 'static,
+    B: Send + 'static,
+    F: Fn(A) -> B + Send + Sync + 'static,
+{
+    if prog.len() < 2 {
+        return prog.into_iter().map(f).collect();
+    }
+
+    let f = Arc::new(f);
+    let size = (prog.len() + host::threads() - 1) / host::threads();
+    let mut runs: Vec<Box<dyn FnOnce() -> Vec<B> + Send>> = vec![];
+
+    while !prog.is_empty() {
+        let run: Vec<A> = prog.drain(..size.min(prog.len())).collect();
+        let f = Arc::clone(&f);
+
+        runs.push(Box::new(move || run.into_iter().map(|e| f(e)).collect()));
+    }
+
+    let mut results = vec![];
+
+    for run in host::parallel(runs) {
+        match run {
+            Ok(run) => results.extend(run),
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+
+    results
+}
+
+/// Replace every `(load "file")` at the top level with the forms in the file
+///
+/// Files are read at compile time relative to the working directory and can
+/// load other files in turn, but not themselves.
+pub fn load(prog: Vec<Syntax>) -> Result<Vec<Syntax>, Vec<Fault>> {
+    loading(prog, &mut vec![])
+}
+
+/// Load files like [load], with the files being loaded to catch cycles
+fn loading(prog: Vec<Syntax>, stack: &mut Vec<String>) -> Result<Vec<Syntax>, Vec<Fault>> {
+    let path = |form: &Syntax| match form {
+        List(list) => match list.as_slice() {
+            [Identifier(head), Literal(Str(path))] if head == "load" => Some(path.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let mut forms = vec![];
+
+    for form in prog {
+        match path(&form) {
+            Some(path) if stack.contains(&path) => {
+                return compiler::fail(format!("{} loads itself", path))
+            }
+            Some(path) => {
+                stack.push(path.clone());
+                forms.extend(loading(read(&path)?, stack)?);
+                stack.pop();
+            }
+            None => forms.push(form),
+        }
+    }
+
+    Ok(forms)
+}
+
+/// Read and parse a file at compile time
+pub fn read(path: &str) -> Result<Vec<Syntax>, Vec<Fault>> {
+    let source =
+        host::read(path).or_else(|e| compiler::fail(format!("Failed to read {}: {}", path, e)))?;
+
+    parser::parse(&source).or_else(|e| compiler::fail(format!("Failed to parse {}: {}", path, e)))
+}
+
+/// Files read at compile time by a program, and the ones they read in turn
+///
+/// These are the files of top level `load`s and of `include`s in libraries.
+/// Files that can't be read or parsed are listed but not followed.
+pub fn dependencies(prog: &[Syntax]) -> Vec<String> {
+    let strings = |forms: &[Syntax]| -> Vec<String> {
+        forms
+            .iter()
+            .filter_map(|form| match form {
+                Literal(Str(path)) => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let direct = |prog: &[Syntax]| -> Vec<String> {
+        let mut files = vec![];
+
+        for form in prog {
+            if let List(list) = form {
+                match list.as_slice() {
+                    [Identifier(head), paths @ ..] if head == "load" => {
+                        files.extend(strings(paths))
+                    }
+                    [Identifier(head), _, decls @ ..] if head == "define-library" => {
+                        files.extend(decls.iter().flat_map(|decl| match decl {
+                            List(decl) => match decl.as_slice() {
+                                [Identifier(head), paths @ ..] if head == "include" => {
+                                    strings(paths)
+                                }
+                                _ => vec![],
+                            },
+                            _ => vec![],
+                        }))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        files
+    };
+
+    let mut files: Vec<String> = vec![];
+    let mut pending = direct(prog);
+
+    while let Some(path) = pending.pop() {
+        if files.contains(&path) {
+            continue;
+        }
+
+        if let Some(prog) = host::read(&path).ok().and_then(|s| parser::parse(&s).ok()) {
+            pending.extend(direct(&prog));
+        }
+
+        files.push(path);
+    }
+
+    files
+}
+
+/// The optional passes of [analyze] that run
+///
+/// The rest of the passes are required to generate any code at all. Turning
+/// the others off one at a time helps narrow down a miscompiled program to a
+/// pass. All of them run by default, like at the highest optimization level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Passes {
+    pub fold: bool,
+    pub anf: bool,
+    pub tco: bool,
+}
+
+impl Passes {
+    /// Names of the optional passes, in the order they run
+    pub const NAMES: [&'static str; 3] = ["fold", "anf", "tco"];
+
+    /// Passes for an optimization level from 0 to 2
+    ///
+    /// Level 0 runs only the required passes, 1 adds tail calls and 2 runs
+    /// everything.
+    pub fn level(level: &str) -> Result<Self, String> {
+        match level {
+            "0" => Ok(Passes { fold: false, anf: false, tco: false }),
+            "1" => Ok(Passes { fold: false, anf: false, tco: true }),
+            "2" => Ok(Passes::default()),
+            _ => Err(format!("Unknown optimization level `{}`, expected 0, 1 or 2", level)),
+        }
+    }
+
+    /// Turn a pass on or off by name
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match name {
+            "fold" => self.fold = enabled,
+            "anf" => self.anf = enabled,
+            "tco" => self.tco = enabled,
+            _ => {
+                return Err(format!(
+                    "Unknown pass `{}`, expected one of {}",
+                    name,
+                    Self::NAMES.join(", ")
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Passes { fold: true, anf: true, tco: true }
+    }
+}
+
+/// The passes that run like `fold, anf, tco`, or `none`
+impl fmt::Display for Passes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled: Vec<&str> = Self::NAMES
+            .iter()
+            .zip(&[self.fold, self.anf, self.tco])
+            .filter(|(_, enabled)| **enabled)
+            .map(|(name, _)| *name)
+            .collect();
+
+        if enabled.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", enabled.join(", "))
+        }
+    }
+}
+
+/// Expand derived syntax into the forms understood by the rest of the compiler
+///
+/// `(guard (e clause ...) body ...)` binds `e` to a fresh local variable and
+/// turns into the primitive `(%guard e body handler)`, where the handler is a
+/// chain of conditionals testing each clause in order. The object is raised
+/// again if no clause matches. See [exceptions](crate::exceptions).
+///
+/// `(foreign-procedure "name" (type ...) type)` turns into a lambda applying
+/// the primitive `%foreign-call` to its arguments. See [ffi](crate::ffi).
+///
+/// `(hash-table-ref table key thunk)` calls `thunk` when `table` has no value
+/// for `key`, instead of raising an error like `(hash-table-ref table key)`.
+/// Since functions aren't values yet, the call is spelled out in place.
+///
+/// `(time expr)` evaluates `expr` between two readings of the clock and the
+/// allocation counter and reports the difference. See
+/// [process](crate::process).
+pub fn expand(prog: Syntax) -> Result<Syntax, Vec<Fault>> {
+    let all = |list: Vec<Syntax>| list.into_iter().map(expand).collect::<Result<Vec<_>, _>>();
+
+    let e = match prog {
+        List(list) => match list.as_slice() {
+            [Identifier(f), Literal(Str(name)), args, Identifier(result)]
+                if f == "foreign-procedure" =>
+            {
+                let types: Option<Vec<String>> = match args {
+                    Literal(Nil) => Some(vec![]),
+                    List(types) => types
+                        .iter()
+                        .map(|t| match t {
+                            Identifier(t) => Some(t.to_string()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => None,
+                };
+                let types = match types {
+                    Some(types) => types,
+                    None => {
+                        let message =
+                            format!("Invalid foreign procedure: `{}`", List(list.clone()));
+                        return invalid(f, message);
+                    }
+                };
+
+                let formals: Vec<Name> =
+                    (0..types.len()).map(|i| format!("arg{}", i).into()).collect();
+
+                let signature = ffi::signature(&types, result).or_else(compiler::fail)?;
+                let call = [
+                    Identifier("%foreign-call".into()),
+                    Literal(Str(name.clone())),
+                    Literal(Number(signature)),
+                ];
+
+                let body = call.iter().cloned().chain(formals.iter().cloned().map(Identifier));
+                let body = vec![List(body.collect())];
+
+                Lambda(Closure { formals, free: vec![], body, tail: false })
+            }
+            [Identifier(guard), List(spec), body @ ..] if guard == "guard" && !body.is_empty() => {
+                let (var, clauses) = match spec.as_slice() {
+                    [Identifier(var), clauses @ ..] => (var.clone(), clauses),
+                    _ => return invalid(guard, format!("Invalid guard: `{}`", List(list.clone()))),
+                };
+
+                let reraise = List(vec![Identifier("raise".into()), Identifier(var.clone())]);
+
+                let handler =
+                    clauses.iter().rev().try_fold(reraise, |alt, clause| match clause {
+                        List(c) => match c.as_slice() {
+                            [Identifier(e), exprs @ ..] if e == "else" => sequence(exprs),
+                            [pred, exprs @ ..] if !exprs.is_empty() => Ok(Cond {
+                                pred: Box::new(expand(pred.clone())?),
+                                then: Box::new(sequence(exprs)?),
+                                alt: Some(Box::new(alt)),
+                            }),
+                            _ => invalid(guard, format!("Invalid guard clause: `{}`", clause)),
+                        },
+                        _ => invalid(guard, format!("Invalid guard clause: `{}`", clause)),
+                    })?;
+
+                let guard = List(vec![
+                    Identifier("%guard".into()),
+                    Identifier(var.clone()),
+                    sequence(body)?,
+                    handler,
+                ]);
+
+                Let { bindings: vec![(var, Literal(Boolean(false)))], body: vec![guard] }
+            }
+            [Identifier(guard), ..] if guard == "guard" => {
+                return invalid(guard, format!("Invalid guard: `{}`", List(list.clone())))
+            }
+            [Identifier(f), table, key, thunk] if f == "hash-table-ref" => {
+                let call = |f: &str, args: Vec<Syntax>| {
+                    List(vec![Identifier(f.into())].into_iter().chain(args).collect())
+                };
+                let entry = || Identifier("%hash-table-found".into());
+
+                let table = call("%hash-table-check", vec![expand(table.clone())?]);
+                let found = call("%hash-table-entry", vec![table, expand(key.clone())?]);
+
+                Let {
+                    bindings: vec![("%hash-table-found".into(), found)],
+                    body: vec![Cond {
+                        pred: Box::new(entry()),
+                        then: Box::new(call("cdr", vec![entry()])),
+                        alt: Some(Box::new(List(vec![expand(thunk.clone())?]))),
+                    }],
+                }
+            }
+            [Identifier(time), expr] if time == "time" => {
+                let call = |f: &str, args: Vec<Syntax>| {
+                    List(vec![Identifier(f.into())].into_iter().chain(args).collect())
+                };
+                let allocated = || call("%gc-stat", vec![Literal(Number(3))]);
+                let since = |now: Syntax, var: &str| call("-", vec![now, Identifier(var.into())]);
+
+                let report = call(
+                    "rt-time",
+                    vec![
+                        Literal(Str(List(list.clone()).to_string())),
+                        since(call("current-jiffy", vec![]), "%time-start"),
+                        since(allocated(), "%time-allocated"),
+                    ],
+                );
+
+                let result = Let {
+                    bindings: vec![("%time-result".into(), expand(expr.clone())?)],
+                    body: vec![report, Identifier("%time-result".into())],
+                };
+
+                Let {
+                    bindings: vec![
+                        ("%time-allocated".into(), allocated()),
+                        ("%time-start".into(), call("current-jiffy", vec![])),
+                    ],
+                    body: vec![result],
+                }
+            }
+            // Quoted data isn't code
+            [Identifier(quote), _] if quote == "quote" => List(list),
+            _ => List(all(list)?),
+        },
+
+        Let { bindings, body } => Let {
+            bindings: bindings
+                .into_iter()
+                .map(|(name, val)| Ok((name, expand(val)?)))
+                .collect::<Result<_, Vec<Fault>>>()?,
+            body: all(body)?,
+        },
+
+        Cond { pred, then, alt } => Cond {
+            pred: Box::new(expand(*pred)?),
+            then: Box::new(expand(*then)?),
+            alt: match alt {
+                Some(e) => Some(Box::new(expand(*e)?)),
+                None => None,
+            },
+        },
+
+        Lambda(code) => Lambda(Closure { body: all(code.body)?, ..code }),
+
+        Define { name, val } => Define { name, val: Box::new(expand(*val)?) },
+
+        Vector(list) => Vector(all(list)?),
+
+        e => e,
+    };
+
+    Ok(e)
+}
+
+/// Fail with an error pointing at a name, like the keyword a form starts with
+pub(crate) fn invalid<T>(name: &Name, message: String) -> Result<T, Vec<Fault>> {
+    Err(vec![Fault { message, name: Some(name.clone()) }])
+}
+
+/// Expand a sequence of expressions into a single one
+fn sequence(exprs: &[Syntax]) -> Result<Syntax, Vec<Fault>> {
+    match exprs {
+        [e] => expand(e.clone()),
+        _ => Ok(Let {
+            bindings: vec![],
+            body: exprs.iter().cloned().map(expand).collect::<Result<_, _>>()?,
+        }),
+    }
+}
+
+/// Names bound around an expression being renamed
+///
+/// Every name maps to all its bindings in scope, innermost last, so that
+/// looking one up doesn't get slower the deeper they are nested. Scopes are
+/// bound before renaming what they are around and unbound right after.
+#[derive(Default)]
+struct Env {
+    names: hash::Map<String, Vec<Ident>>,
+}
+
+impl Env {
+    fn bind(&mut self, names: &[(&str, Ident)]) {
+        for (name, ident) in names {
+            self.names.entry(name.to_string()).or_default().push(ident.clone());
+        }
+    }
+
+    fn unbind(&mut self, names: &[(&str, Ident)]) {
+        for (name, _) in names {
+            if let Some(idents) = self.names.get_mut(*name) {
+                idents.pop();
+                if idents.is_empty() {
+                    self.names.remove(*name);
+                }
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Ident> {
+        self.names.get(name).and_then(|idents| idents.last())
+    }
 }
 
 /** Rename all references to unique names.
@@ -42,172 +676,314 @@ existing implementation would be great. See [RFC 2603], its [discussion] and
 [discussion]: https://github.com/rust-lang/rfcs/pull/2603
 [tracking issue]: https://github.com/rust-lang/rust/issues/60705
  **/
-fn rename(env: &HashMap<&str, Ident>, base: &Ident, index: u8, prog: Syntax) -> Core {
-    match prog {
+fn rename(env: &mut Env, base: &Ident, index: usize, prog: Syntax) -> Result<Core, Vec<Fault>> {
+    let e = match prog {
         // If an identifier is defined already, refer to it, otherwise create a
         // new one in the top level environment since its unbound.
         Identifier(s) => {
             env.get(s.as_str()).map_or(Ident::expr(s), |n| Expr::Identifier(n.clone()))
         }
         Let { bindings, body } => {
-            let base = base.extend(format!("{{let {}}}", index));
+            let scope = base.extend(scope(index));
 
             // Collect all the names about to be bound for evaluating body
-            let mut all = env.clone();
-            for (name, _val) in bindings.iter() {
-                all.insert(name.as_str(), base.extend(name));
-            }
+            let (names, values): (Vec<Name>, Vec<Syntax>) = bindings.into_iter().unzip();
+            unique(&names, |name| {
+                format!("Duplicate binding `{}` in let", name)
+            })?;
+            let idents: Vec<(&str, Ident)> =
+                names.iter().map(|name| (name.as_str(), scope.extend(name))).collect();
+            env.bind(&idents);
 
             // A sub expression in let binding is evaluated with the complete
             // environment including the one being defined only if the subexpresison
             // captures the closure with another let or lambda, otherwise evaluate with
             // only the rest of the bindings.
-            Let {
-                bindings: bindings
-                    .iter()
-                    .map(|(current, value)| {
-                        // Collect all the names excluding the one being defined now
-                        let mut rest = env.clone();
-                        for (name, _) in bindings.iter() {
-                            if name != current {
-                                rest.insert(name.as_str(), base.extend(name));
-                            }
-                        }
-
-                        let value = match value {
-                            Let { .. } => rename(&all, &base, index + 1, value.clone()),
-                            Lambda(c) => {
-                                let base = base.extend(current);
-                                rename(&all, &base, index + 1, Lambda(c.clone()))
-                            }
-                            _ => rename(&rest, &base, index + 1, value.clone()),
-                        };
-
-                        let ident = all.get(current.as_str()).unwrap().clone();
+            let values: Vec<Core> = idents
+                .iter()
+                .zip(values)
+                .map(|(current, value)| match value {
+                    Let { .. } => rename(env, base, index + 1, value),
+                    Lambda(code) => closure(env, &current.1, code),
+                    _ => {
+                        // All the names excluding the one being defined now
+                        let current = std::slice::from_ref(current);
+                        env.unbind(current);
+                        let value = rename(env, base, index + 1, value);
+                        env.bind(current);
+                        value
+                    }
+                })
+                .collect::<Result<_, _>>()?;
 
-                        (ident, value)
-                    })
-                    .collect(),
+            let body: Result<_, _> =
+                body.into_iter().map(|b| rename(env, base, index + 1, b)).collect();
+            env.unbind(&idents);
 
-                body: body.into_iter().map(|b| rename(&all, &base, index + 1, b)).collect(),
+            Let {
+                bindings: idents.into_iter().map(|(_, ident)| ident).zip(values).collect(),
+                body: body?,
             }
         }
 
-        List(list) => List(list.into_iter().map(|l| rename(env, base, index, l)).collect()),
+        List(list) if matches!(list.as_slice(), [Identifier(q), _] if q == "quote") => {
+            quoted(&list[1])?
+        }
+
+        List(list) => {
+            List(list.into_iter().map(|l| rename(env, base, index, l)).collect::<Result<_, _>>()?)
+        }
 
         Cond { pred, then, alt } => Cond {
-            pred: box rename(env, base, index, *pred),
-            then: box rename(env, base, index, *then),
-            alt: alt.map(|u| box rename(env, base, index, *u)),
+            pred: Box::new(rename(env, base, index, *pred)?),
+            then: Box::new(rename(env, base, index, *then)?),
+            alt: match alt {
+                Some(u) => Some(Box::new(rename(env, base, index, *u)?)),
+                None => None,
+            },
         },
 
-        Lambda(Closure { formals, free, body, tail }) => {
-            let mut env = env.clone();
-            for arg in formals.iter() {
-                env.insert(arg, base.extend(arg));
-            }
-
-            Lambda(Closure {
-                formals: formals.iter().map(|arg| base.extend(arg)).collect(),
-                free: free.into_iter().map(|arg| base.extend(arg)).collect(),
-                body: body.into_iter().map(|b| rename(&env, base, 0, b)).collect(),
-                tail,
-            })
-        }
+        // A λ nobody named gets a scope of its own, or its formals would be
+        // named like the variables of the enclosing let they shadow
+        Lambda(code) => closure(env, &within(base, index).extend("{closure}"), code)?,
 
         Define { name, val } => {
-            Define { name: base.extend(&name), val: box rename(env, &base.extend(&name), 0, *val) }
+            let name = match env.get(&name) {
+                Some(ident) if *base == Ident::empty() => ident.clone(),
+                _ => within(base, index).extend(&name),
+            };
+            let val = match *val {
+                Lambda(code) => closure(env, &name, code)?,
+                val => rename(env, &name, 0, val)?,
+            };
+            Define { name, val: Box::new(val) }
         }
 
-        Vector(list) => Vector(list.into_iter().map(|l| rename(env, base, index, l)).collect()),
+        Vector(list) => {
+            Vector(list.into_iter().map(|l| rename(env, base, index, l)).collect::<Result<_, _>>()?)
+        }
 
         // All literals and constants evaluate to itself
         Literal(v) => Literal(v),
+    };
+
+    Ok(e)
+}
+
+/// The expression building a quoted datum
+///
+/// Names are symbols and lists are built out of pairs, improper ones ending
+/// with the datum after the `.`. The primitives building them can't be
+/// shadowed, since their names are free.
+fn quoted(datum: &Syntax) -> Result<Core, Vec<Fault>> {
+    let call =
+        |f: &str, args: Vec<Core>| List(std::iter::once(Ident::expr(f)).chain(args).collect());
+
+    match datum {
+        Identifier(name) => Ok(Literal(Symbol(name.to_string()))),
+        Literal(l) => Ok(Literal(l.clone())),
+        List(list) => {
+            let (elems, tail) = match list.as_slice() {
+                [elems @ .., Identifier(dot), tail] if dot == "." => (elems, quoted(tail)?),
+                elems => (elems, Literal(Nil)),
+            };
+
+            elems.iter().rev().try_fold(tail, |rest, e| Ok(call("cons", vec![quoted(e)?, rest])))
+        }
+        Vector(list) => Ok(call("vector", list.iter().map(quoted).collect::<Result<_, _>>()?)),
+        e => compiler::fail(format!("Invalid quote: `{}` isn't a datum", e)),
+    }
+}
+
+/// Rename a λ, with its formals bound in the scope `base` that is its own
+///
+/// A λ bound by a let or a definition takes the name it is bound to as its
+/// scope, since that is unique already.
+fn closure(env: &mut Env, base: &Ident, code: Closure<Name>) -> Result<Core, Vec<Fault>> {
+    let Closure { formals, free, body, tail } = code;
+
+    unique(&formals, |name| format!("Duplicate formal `{}` in λ", name))?;
+
+    let names: Vec<(&str, Ident)> =
+        formals.iter().map(|arg| (arg.as_str(), base.extend(arg))).collect();
+    env.bind(&names);
+    let body: Result<_, _> = body.into_iter().map(|b| rename(env, base, 0, b)).collect();
+    env.unbind(&names);
+
+    Ok(Lambda(Closure {
+        formals: names.into_iter().map(|(_, ident)| ident).collect(),
+        free: free.into_iter().map(|arg| base.extend(arg)).collect(),
+        body: body?,
+        tail,
+    }))
+}
+
+/// The scope of whatever is `index` lets deep in the function `base`
+fn within(base: &Ident, index: usize) -> Cow<'_, Ident> {
+    match index {
+        0 => Cow::Borrowed(base),
+        _ => Cow::Owned(base.extend(scope(index - 1))),
+    }
+}
+
+/// Fail with an error for every name bound more than once at the same time
+///
+/// A `let`, the formals of a `λ` and the definitions at the top level all bind
+/// their names together, so which one a reference means would be arbitrary.
+/// Each error points at the second binding of the name.
+fn unique<'a>(
+    names: impl IntoIterator<Item = &'a Name>,
+    error: impl Fn(&str) -> String,
+) -> Result<(), Vec<Fault>> {
+    let mut seen: HashSet<&str, hash::Fast> = HashSet::default();
+    let mut duplicates: Vec<&Name> = vec![];
+
+    for name in names {
+        if !seen.insert(name) && !duplicates.contains(&name) {
+            duplicates.push(name);
+        }
+    }
+
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    Err(duplicates
+        .into_iter()
+        .map(|name| Fault { message: error(name), name: Some(name.clone()) })
+        .collect())
+}
+
+/// Name of the scope of a let nested `index` deep in a function
+///
+/// Every let gets one, so the first few are formatted once for all of them.
+/// The scope is named after the function alone rather than the lets around it,
+/// or names would get longer the deeper they are nested. Lets nested as deep
+/// in the same function can't see each other's names anyway.
+fn scope(index: usize) -> Cow<'static, str> {
+    const SCOPES: [&str; 8] =
+        ["{let 0}", "{let 1}", "{let 2}", "{let 3}", "{let 4}", "{let 5}", "{let 6}", "{let 7}"];
+
+    match SCOPES.get(index) {
+        Some(scope) => Cow::Borrowed(scope),
+        None => Cow::Owned(format!("{{let {}}}", index)),
     }
 }
 
 /// Lift all lambdas to top level
 ///
+/// The functions lifted out of an expression come before what is left of it,
+/// innermost first. Lambdas bound by a let or a definition keep the name they
+/// are bound to and anonymous ones are named after `base` and numbered in
+/// order, `(call/cc (lambda (k) ...))` calls `base::{closure 0}` for example.
+///
 /// See http://matt.might.net/articles/closure-conversion
-fn lift(prog: Core) -> Vec<Core> {
-    match prog {
-        Let { bindings, body } => {
-            // Rest is all the name bindings that are not functions
-            let rest: Vec<(Ident, Core)> = bindings
-                .iter()
-                .filter_map(|(ident, expr)| match expr {
-                    Lambda(_) => None,
-                    _ => Some((ident.clone(), shrink(lift(expr.clone())))),
-                })
-                .collect();
+fn lift(base: &Ident, prog: Core) -> Vec<Core> {
+    let mut lifted = Lifted { base, anonymous: 0, functions: vec![] };
 
-            let mut export: Vec<Core> = bindings
-                .into_iter()
-                .filter_map(|(name, expr)| match expr {
-                    Lambda(code) => {
-                        let code = Closure {
-                            body: code.body.into_iter().flat_map(lift).collect(),
-                            ..code
-                        };
-                        Some(Define { name, val: box Lambda(code) })
+    let prog = match prog {
+        Define { name, val } => match *val {
+            Lambda(code) => lifted.function(name, code),
+            val => Define { name, val: Box::new(lifted.expr(val)) },
+        },
+        prog => lifted.expr(prog),
+    };
+
+    lifted.functions.push(prog);
+    lifted.functions
+}
+
+/// Functions lifted out of a top level expression so far, see [lift]
+struct Lifted<'a> {
+    base: &'a Ident,
+    anonymous: usize,
+    functions: Vec<Core>,
+}
+
+impl Lifted<'_> {
+    /// Lift the functions out of an expression and return what is left of it
+    fn expr(&mut self, prog: Core) -> Core {
+        match prog {
+            Let { bindings, body } => {
+                // Rest is all the name bindings that are not functions
+                let mut rest = Vec::with_capacity(bindings.len());
+
+                for (name, expr) in bindings {
+                    match expr {
+                        Lambda(code) => {
+                            let function = self.function(name, code);
+                            self.functions.push(function)
+                        }
+                        expr => rest.push((name, self.expr(expr))),
                     }
-                    _ => None,
-                })
-                .collect();
+                }
 
-            export.push(Let {
-                bindings: rest,
-                body: body.into_iter().map(|b| shrink(lift(b))).collect(),
-            });
+                Let { bindings: rest, body: body.into_iter().map(|b| self.expr(b)).collect() }
+            }
 
-            export
-        }
+            List(list) => List(list.into_iter().map(|l| self.expr(l)).collect()),
+
+            Vector(list) => Vector(list.into_iter().map(|l| self.expr(l)).collect()),
+
+            Cond { pred, then, alt } => Cond {
+                pred: Box::new(self.expr(*pred)),
+                then: Box::new(self.expr(*then)),
+                alt: alt.map(|e| Box::new(self.expr(*e))),
+            },
 
-        List(list) => vec![List(list.into_iter().map(|l| shrink(lift(l))).collect())],
+            // An anonymous lambda is referred to by the name it is lifted as
+            Lambda(code) => {
+                let name = self.base.extend(format!("{{closure {}}}", self.anonymous));
+                self.anonymous += 1;
 
-        Cond { pred, then, alt } => vec![Cond {
-            pred: box shrink(lift(*pred)),
-            then: box shrink(lift(*then)),
-            alt: alt.map(|e| box shrink(lift(*e))),
-        }],
+                let function = self.function(name.clone(), code);
+                self.functions.push(function);
+                Identifier(name)
+            }
 
-        // Lift named code blocks to top level immediately, since names are manged by now.
-        Define { name, val: box Lambda(code) } => {
-            let body = (code).body.into_iter().flat_map(lift).collect();
-            vec![Define { name, val: box Lambda(Closure { body, ..code }) }]
+            e => e,
         }
+    }
 
-        // Am unnamed literal lambda must be in an inline calling position
-        // Lambda(Closure { .. }) => unimplemented!("inline λ"),
-        e => vec![e],
+    /// Definition of a function, with the ones in its body lifted out
+    fn function(&mut self, name: Ident, code: Closure<Ident>) -> Core {
+        let body = code.body.into_iter().map(|e| self.expr(e)).collect();
+        Define { name, val: Box::new(Lambda(Closure { body, ..code })) }
     }
 }
-// Shrink a vector of expressions into a single expression
-//
-// TODO: Replace with `(begin ...)`, list really isn't the same thing
-fn shrink<T: Clone>(es: Vec<Expr<T>>) -> Expr<T> {
-    match es.len() {
-        0 => Literal(Nil),
-        1 => es[0].clone(),
-        _ => List(es),
+
+/// Strings and symbols referred to by an expression, in the order they appear
+#[derive(Default)]
+struct Literals {
+    strings: Vec<String>,
+    symbols: Vec<String>,
+}
+
+impl Literals {
+    /// Number the literals of an expression after the ones of the expressions
+    /// before it, just like inlining the whole program in order would
+    fn merge(self, s: &mut State) {
+        let strings = Arc::make_mut(&mut s.strings);
+        for string in self.strings {
+            let index = strings.len();
+            strings.entry(string).or_insert(index);
+        }
+
+        let symbols = Arc::make_mut(&mut s.symbols);
+        for symbol in self.symbols {
+            let index = symbols.len();
+            symbols.entry(symbol).or_insert(index);
+        }
     }
 }
 
 /// Inline all references to strings and symbols
-fn inline(s: &mut State, prog: Core) -> Core {
+fn inline(s: &mut Literals, prog: Core) -> Core {
     match prog {
         Literal(l) => {
             match &l {
-                Str(reference) => {
-                    let index = s.strings.len();
-                    s.strings.entry(reference.clone()).or_insert(index);
-                }
-
-                Symbol(reference) => {
-                    let index = s.symbols.len();
-                    s.symbols.entry(reference.clone()).or_insert(index);
-                }
-
+                Str(reference) => s.strings.push(reference.clone()),
+                Symbol(reference) => s.symbols.push(reference.clone()),
                 _ => {}
             };
 
@@ -224,17 +1000,20 @@ fn inline(s: &mut State, prog: Core) -> Core {
         Vector(list) => Vector(list.into_iter().map(|e| inline(s, e)).collect()),
 
         Cond { pred, then, alt } => Cond {
-            pred: box inline(s, *pred),
-            then: box inline(s, *then),
-            alt: alt.map(|e| box inline(s, *e)),
+            pred: Box::new(inline(s, *pred)),
+            then: Box::new(inline(s, *then)),
+            alt: alt.map(|e| Box::new(inline(s, *e))),
         },
 
-        Define { name, val: box Lambda(code) } => Define {
-            name,
-            val: box Lambda(Closure {
-                body: code.body.into_iter().map(|e| inline(s, e)).collect(),
-                ..code
-            }),
+        Define { name, val } => match *val {
+            Lambda(code) => Define {
+                name,
+                val: Box::new(Lambda(Closure {
+                    body: code.body.into_iter().map(|e| inline(s, e)).collect(),
+                    ..code
+                })),
+            },
+            val => Define { name, val: Box::new(val) },
         },
 
         e => e,
@@ -250,37 +1029,29 @@ fn inline(s: &mut State, prog: Core) -> Core {
 fn anf(prog: Core) -> Core {
     match prog {
         List(list) => {
-            let (car, cdr) = list.split_at(1);
-
             // IF all arguments are already in normal form, return as is it
-            if cdr.iter().all(|e| e.anf()) {
-                List(list)
-            } else {
-                // Collect variables that will be bound to a new let block
-                let bindings = cdr
-                    .iter()
-                    .enumerate()
-                    .map(|(i, e)| (Ident::new(format!("_{}", i)), e.clone()))
-                    .filter(|(_, e)| !e.anf());
-
-                // Collect arguments for the function call where complex
-                // expressions are replaced with a variable name
-                let args: Vec<Core> = cdr
-                    .iter()
-                    .enumerate()
-                    .map(|(i, e)| {
-                        if e.anf() {
-                            e.clone()
-                        } else {
-                            Identifier(Ident::new(format!("_{}", i)))
-                        }
-                    })
-                    .collect();
+            if list.iter().skip(1).all(|e| e.anf()) {
+                return List(list);
+            }
 
-                let body: Core = List(car.iter().chain(args.iter()).cloned().collect());
+            // Complex arguments are bound to variables in a new let block and
+            // replaced with their names in the function call
+            let mut bindings = vec![];
+            let list = list
+                .into_iter()
+                .enumerate()
+                .map(|(i, e)| {
+                    if i == 0 || e.anf() {
+                        e
+                    } else {
+                        let name = Ident::new(format!("_{}", i - 1));
+                        bindings.push((name.clone(), e));
+                        Identifier(name)
+                    }
+                })
+                .collect();
 
-                Let { bindings: bindings.collect(), body: vec![body] }
-            }
+            Let { bindings, body: vec![List(list)] }
         }
         e => e,
     }
@@ -303,16 +1074,20 @@ fn tco(expr: Core) -> Core {
     }
 
     match expr {
-        Define { name, val: box Lambda(code) } => Define {
-            name: name.clone(),
-            val: box Lambda(Closure { tail: is_tail(&name, &code), ..code }),
+        Define { name, val } => match *val {
+            Lambda(code) => {
+                let tail = is_tail(&name, &code);
+                Define { name, val: Box::new(Lambda(Closure { tail, ..code })) }
+            }
+            val => Define { name, val: Box::new(val) },
         },
         Let { bindings, body } => {
             let bindings = bindings
                 .into_iter()
                 .map(|(name, value)| match value {
                     Lambda(code) => {
-                        (name.clone(), Lambda(Closure { tail: is_tail(&name, &code), ..code }))
+                        let tail = is_tail(&name, &code);
+                        (name, Lambda(Closure { tail, ..code }))
                     }
 
                     _ => (name, value),
@@ -342,7 +1117,7 @@ fn tail<T: std::clone::Clone>(e: &Expr<T>) -> Option<&Expr<T>> {
         Let { body, .. } => body.last().and_then(tail),
         Cond { alt, .. } => {
             // What do I do with 2?
-            alt.as_deref().and_then(|e| tail(&e))
+            alt.as_deref().and_then(|e| tail(e))
         }
         e => Some(e),
     }
@@ -353,13 +1128,47 @@ mod tests {
     use super::*;
     use crate::parser::{parse, parse1};
     use pretty_assertions::assert_eq;
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        fs,
+    };
+
+    /// Counts the allocations of every thread, for tests to check that passes
+    /// move the program they are given rather than copy it
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Allocations made by `f`, not counting the ones of the thread before
+    fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (result, ALLOCATIONS.with(Cell::get) - before)
+    }
 
     fn rename(prog: Syntax) -> Core {
-        super::rename(&HashMap::new(), &Ident::empty(), 0, prog)
+        super::rename(&mut Env::default(), &Ident::empty(), 0, prog).unwrap()
     }
 
     fn analyze(prog: Vec<Syntax>) -> Vec<Core> {
-        super::analyze(&mut State::new(), prog)
+        super::analyze(&mut State::new(), prog).unwrap()
     }
 
     /// Mock rename, which blindly converts Strings to Identifiers
@@ -379,9 +1188,9 @@ mod tests {
             List(list) => List(list.into_iter().map(mock).collect()),
 
             Cond { pred, then, alt } => Cond {
-                pred: box mock(*pred),
-                then: box mock(*then),
-                alt: alt.map(|u| box mock(*u)),
+                pred: Box::new(mock(*pred)),
+                then: Box::new(mock(*then)),
+                alt: alt.map(|u| Box::new(mock(*u))),
             },
 
             Lambda(Closure { formals, free, body, tail }) => Lambda(Closure {
@@ -391,7 +1200,7 @@ mod tests {
                 tail,
             }),
 
-            Define { name, val } => Define { name: Ident::new(name), val: box mock(*val) },
+            Define { name, val } => Define { name: Ident::new(name), val: Box::new(mock(*val)) },
 
             Vector(list) => Vector(list.into_iter().map(mock).collect()),
 
@@ -412,8 +1221,8 @@ mod tests {
         let y = mock(parse1(
             "(let (({let 0}::x 1)
                   ({let 0}::y 2))
-               (let (({let 0}::{let 1}::z 3))
-                 (+ {let 0}::x {let 0}::y {let 0}::{let 1}::z))))",
+               (let (({let 1}::z 3))
+                 (+ {let 0}::x {let 0}::y {let 1}::z))))",
         ));
         assert_eq!(x, y);
     }
@@ -479,6 +1288,81 @@ mod tests {
         assert_eq!(x, y)
     }
 
+    #[test]
+    fn shadow() {
+        let x = rename(parse1(
+            "(let ((x 1))
+               (let ((x (+ x 1))
+                     (f (lambda (y) (+ x y))))
+                 (f x)))",
+        ));
+
+        let y = mock(parse1(
+            "(let (({let 0}::x 1))
+               (let (({let 1}::x (+ {let 0}::x 1))
+                     ({let 1}::f (lambda ({let 1}::f::y)
+                                   (+ {let 1}::x {let 1}::f::y))))
+                 ({let 1}::f {let 1}::x)))",
+        ));
+
+        assert_eq!(x, y)
+    }
+
+    #[test]
+    fn formals() {
+        let x = rename(parse1(
+            "(let ((x 1))
+               (let ((f (lambda (x) (let ((x (+ x 1))) x))))
+                 (list (f x) ((lambda (x y) (+ x y)) x 2))))",
+        ));
+
+        let y = mock(parse1(
+            "(let (({let 0}::x 1))
+               (let (({let 1}::f
+                       (lambda ({let 1}::f::x)
+                         (let (({let 1}::f::{let 0}::x (+ {let 1}::f::x 1)))
+                           {let 1}::f::{let 0}::x))))
+                 (list ({let 1}::f {let 0}::x)
+                       ((lambda ({let 1}::{closure}::x {let 1}::{closure}::y)
+                          (+ {let 1}::{closure}::x {let 1}::{closure}::y))
+                        {let 0}::x 2))))",
+        ));
+
+        assert_eq!(x, y)
+    }
+
+    #[test]
+    fn duplicates() {
+        // Every error points at the second binding of its name in the source
+        let errors = |source: &str| {
+            let prog = parse(source).unwrap();
+            let faults = renamed(prog).unwrap_err();
+
+            faults
+                .into_iter()
+                .map(|f| (f.message, f.name.and_then(|name| name.span(source)).map(|s| s.start)))
+                .collect::<Vec<_>>()
+        };
+        let error = |message: &str, at| (message.to_string(), Some(at));
+
+        assert_eq!(
+            errors("(let ((x 1) (x 2)) x)"),
+            vec![error("Duplicate binding `x` in let", 13)]
+        );
+        assert_eq!(
+            errors("(lambda (x y x y x) x)"),
+            vec![error("Duplicate formal `x` in λ", 13), error("Duplicate formal `y` in λ", 15)]
+        );
+        assert_eq!(
+            errors("(define (f) 1) (define (g x) (let ((y 1) (y 2)) y)) (define (f) 2)"),
+            vec![error("Duplicate definition `f`", 61)]
+        );
+        assert_eq!(errors("(define (f x x) x)"), vec![error("Duplicate formal `x` in λ", 13)]);
+
+        // Shadowing a name from an enclosing scope is still fine
+        renamed(parse("(let ((x 1)) (let ((x 2)) ((lambda (x) x) x)))").unwrap()).unwrap();
+    }
+
     #[test]
     fn a_normal_form() {
         let x = parse1("(f (+ 1 2) 7)");
@@ -509,7 +1393,7 @@ mod tests {
                            (odd  (lambda (x) (if (zero? x) #f (even (dec x))))))
                        (even 25)))";
 
-        let expr = lift(rename(parse1(prog)));
+        let expr = lift(&Ident::empty(), rename(parse1(prog)));
 
         assert_eq!(
             expr[0],
@@ -530,6 +1414,24 @@ mod tests {
         assert_eq!(expr[2], mock(parse1("(let () ({let 0}::even 25))")));
     }
 
+    #[test]
+    fn lift_anonymous() {
+        // Named after the definition they are in or the position of the expression
+        let prog = "(define (f) (call/cc (lambda (k) (k 1)))) ((lambda (x) x) 2)";
+        let expr: Vec<String> =
+            analyze(parse(prog).unwrap()).iter().map(|e| e.to_string()).collect();
+
+        assert_eq!(
+            expr,
+            vec![
+                "(define f {closure 0} (λ (f {closure} k) (f {closure} k 1)))",
+                "(define f (λ () (call/cc f {closure 0})))",
+                "(define {expr 1} {closure 0} (λ ({closure} x) {closure} x))",
+                "({expr 1} {closure 0} 2)",
+            ]
+        );
+    }
+
     #[test]
     fn tails() {
         let prog = "(let ((factorial (lambda (x acc)
@@ -538,16 +1440,175 @@ mod tests {
                                   (factorial (dec x) (* x acc))))))
              (factorial 42 1))";
 
-        let exprs = lift(rename(parse1(prog)));
+        let exprs = lift(&Ident::empty(), rename(parse1(prog)));
 
-        match &exprs[0] {
-            Define { name: _, val: box Lambda(code) } => assert_eq!(code.tail, false),
+        match exprs[0].function() {
+            Some((_, code)) => assert_eq!(code.tail, false),
             _ => panic!(),
         };
 
-        match tco(exprs[0].clone()) {
-            Define { name: _, val: box Lambda(code) } => assert_eq!(code.tail, true),
+        match tco(exprs[0].clone()).function() {
+            Some((_, code)) => assert_eq!(code.tail, true),
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn moves() {
+        let args = (0..100).map(|i| format!("x{}", i)).collect::<Vec<_>>().join(" ");
+        let prog = rename(parse1(&format!("(let ((f (lambda (x) x)) (l (list {}))) (f l))", args)));
+
+        // Every identifier takes two allocations to copy, and lifting one takes
+        // one for the expressions lifted out of it
+        let (_, copy) = allocations(|| prog.clone());
+        let (lifted, moved) = allocations(|| lift(&Ident::empty(), prog));
+
+        assert!(moved < copy, "lifting took {} allocations, copying {}", moved, copy);
+        assert_eq!(lifted.len(), 2);
+
+        // Expressions in normal form are returned as is
+        let prog = List(vec![Ident::expr("f"), Ident::expr("x"), Literal(Number(1))]);
+        let copy = prog.clone();
+        assert_eq!(allocations(|| anf(copy)), (prog, 0));
+    }
+
+    #[test]
+    fn names() {
+        // Renamed identifiers share the parts of their names, so copying one
+        // takes a single allocation and extending one two
+        let base = rename(parse1("(lambda (x) (let ((y x)) y))"));
+        let ident = match &base {
+            Lambda(Closure { body, .. }) => match &body[0] {
+                Let { bindings, .. } => bindings[0].0.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+
+        assert_eq!(ident, Ident::new("{closure}::{let 0}::y"));
+        assert_eq!(allocations(|| ident.clone()).1, 1);
+        assert_eq!(allocations(|| ident.extend("z")).1, 2);
+        assert_eq!(allocations(|| scope(3)).1, 0);
+        assert_eq!(scope(12), "{let 12}");
+    }
+
+    #[test]
+    fn parallel() {
+        let doubled = super::parallel((0..100).collect(), |n: usize| n * 2);
+        assert_eq!(doubled, (0..100).map(|n| n * 2).collect::<Vec<_>>());
+
+        // Literals are numbered in the order they appear in the program, no
+        // matter which thread finds them first
+        let mut s = State::new();
+        let prog = parse(r#"(define (f) "b") "a" 'x "b" (define (g) (list "c" 'y 'x))"#).unwrap();
+        super::analyze(&mut s, prog).unwrap();
+
+        assert_eq!((s.strings["b"], s.strings["a"], s.strings["c"]), (0, 1, 2));
+        assert_eq!((s.symbols["x"], s.symbols["y"]), (0, 1));
+    }
+
+    #[test]
+    fn depth() {
+        // Programs nested tens of thousands of levels deep, like the ones
+        // generated by other programs, don't run the compiler out of stack,
+        // and long chains of lets aren't slower than nested calls
+        let depth = 50_000;
+        let nested = format!("(define (f x) {}x{})", "(+ x ".repeat(depth), ")".repeat(depth));
+
+        let chain: String =
+            (0..depth).map(|i| format!("(let ((x{} (+ x{} 1))) ", i + 1, i)).collect();
+        let chain = format!("(let ((x0 1)) {}x{}{}", chain, depth, ")".repeat(depth + 1));
+
+        host::deep(|| {
+            let prog = analyze(parse(&nested).unwrap());
+            assert_eq!(prog.nodes(), 3 * depth + 3);
+
+            let prog = analyze(parse(&chain).unwrap());
+            assert_eq!(prog.nodes(), 5 * depth + 3);
+        });
+    }
+
+    #[test]
+    fn guard() {
+        let x =
+            expand(parse1("(guard (e ((symbol? e) 1) (else (display e) 2)) (raise 'x))")).unwrap();
+
+        let y = parse1(
+            "(let ((e #f))
+               (%guard e (raise 'x) (if (symbol? e) 1 (let () (display e) 2))))",
+        );
+        assert_eq!(x, y);
+
+        // Raise again if no clause matches
+        let x = expand(parse1("(guard (e ((string? e) e)) (car 1) (car 2))")).unwrap();
+
+        let y = parse1(
+            "(let ((e #f))
+               (%guard e (let () (car 1) (car 2)) (if (string? e) e (raise e))))",
+        );
+        assert_eq!(x, y);
+    }
+
+    #[test]
+    fn hash_table_ref() {
+        let x = expand(parse1("(hash-table-ref t 'k (lambda () 0))")).unwrap();
+
+        let y = parse1(
+            "(let ((%hash-table-found (%hash-table-entry (%hash-table-check t) 'k)))
+               (if %hash-table-found (cdr %hash-table-found) ((lambda () 0))))",
+        );
+        assert_eq!(x, y);
+
+        // Without a thunk it is a call of the function in the prelude
+        let x = expand(parse1("(hash-table-ref t 'k)")).unwrap();
+        assert_eq!(x, parse1("(hash-table-ref t 'k)"));
+    }
+
+    #[test]
+    fn passes() {
+        let mut s = State::new();
+        s.passes = Passes::level("0").unwrap();
+
+        let prog = parse("(define (f x) (if (zero? x) 0 (f (dec x)))) (+ (f 1) (f 2))").unwrap();
+        let exprs = super::analyze(&mut s, prog).unwrap();
+
+        match exprs[0].function() {
+            Some((_, code)) => assert_eq!(code.tail, false),
+            _ => panic!(),
+        };
+        assert!(matches!(exprs[1], List(_)));
+
+        let mut passes = Passes::level("1").unwrap();
+        passes.set("anf", true).unwrap();
+        passes.set("fold", true).unwrap();
+        assert_eq!(passes, Passes::default());
+
+        assert!(passes.set("inline", false).is_err());
+        assert!(Passes::level("3").is_err());
+    }
+
+    #[test]
+    fn dependencies() {
+        let dir = std::env::temp_dir().join(format!("inc-deps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let a = format!("(load \"{}\") (load \"{}\")", path("b.scm"), path("a.scm"));
+        fs::write(path("a.scm"), a).unwrap();
+        fs::write(path("b.scm"), "(+ 1 2)").unwrap();
+
+        let prog = parse(&format!(
+            "(load \"{}\") (define-library (l) (include \"{}\")) (f \"{}\")",
+            path("a.scm"),
+            path("missing.scm"),
+            path("c.scm")
+        ))
+        .unwrap();
+
+        let mut files = super::dependencies(&prog);
+        files.sort();
+        assert_eq!(files, vec![path("a.scm"), path("b.scm"), path("missing.scm")]);
+
+        fs::remove_dir_all(&dir).unwrap_or_default();
+    }
 }
@@ -1,9 +1,7 @@
-#![feature(bindings_after_at)] // For Lambda(code @ Closure { name: Some(n), .. }) => {
-#![feature(box_syntax, box_patterns)]
-#![feature(inner_deref)] // For Option::as_deref
-#![feature(llvm_asm)]
-#![feature(const_extern_fn)]
 #![deny(clippy::missing_const_for_fn)]
+// Without the native feature much of the runtime is compiled only for the
+// compiler to refer to, and never called
+#![cfg_attr(not(feature = "native"), allow(dead_code, unused_imports))]
 
 /*!
 # An Incremental scheme compiler
@@ -35,22 +33,82 @@ functions and variables.
 
 See [docs](docs) for some additional notes and comments.
 
+To compile programs from Rust instead of the command line, see the
+[Compiler](builder::Compiler) builder, or [eval](builder::eval) to embed Scheme
+as an extension language.
+
 [Scheme]: https://www.scheme.com
 [book]:   https://doc.rust-lang.org/book/#the-rust-programming-language
 [paper]:  https://github.com/jaseemabid/inc/blob/master/docs/paper.pdf
 */
 
+pub mod asm;
+#[cfg(feature = "native")]
+pub mod bench;
+pub mod bignum;
+pub mod callbacks;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod builder;
+pub mod cache;
 pub mod cli;
 pub mod compiler;
+pub mod complete;
+pub mod continuations;
 pub mod core;
+pub mod coverage;
+#[cfg(feature = "native")]
+pub mod dap;
+pub mod diagnostic;
+pub mod disasm;
+pub mod docgen;
 pub mod docs;
+pub mod eval;
+pub mod exceptions;
 pub mod ffi;
+pub mod fmt;
+pub mod gc;
+pub mod hash;
+pub mod header;
+pub mod host;
 pub mod immediate;
+pub mod interp;
+#[cfg(feature = "native")]
+pub mod jit;
+pub mod json;
+#[cfg(feature = "native")]
+pub mod jupyter;
 pub mod lambda;
 pub mod lang;
+pub mod library;
+pub mod lsp;
+pub mod metrics;
+pub mod numbers;
 pub mod parser;
 pub mod primitives;
+pub mod profile;
+pub mod process;
+pub mod project;
+pub mod refs;
+#[cfg(feature = "native")]
+pub mod repl;
+pub mod resolve;
 pub mod rt;
+pub mod semantic;
+pub mod sourcemap;
+#[cfg(feature = "native")]
+pub mod start;
 pub mod strings;
 pub mod symbols;
+pub mod tags;
+#[cfg(feature = "native")]
+pub mod testing;
+pub mod threads;
+pub mod validate;
+pub mod value;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod x86;
+
+pub use builder::{eval, Artifact, Compiler};
+pub use value::Value;
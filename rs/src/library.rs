@@ -0,0 +1,644 @@
+//! R7RS libraries
+//!
+//! A library is a group of definitions with a name and an interface. Only the
+//! exported names are visible to the programs and libraries importing it, and
+//! they can be renamed, prefixed or filtered on the way in.
+//!
+//! ```scheme
+//! (define-library (math arith)
+//!   (export double (rename triple thrice))
+//!   (import (scheme base))
+//!   (begin
+//!     (define (double x) (* x 2))
+//!     (define (triple x) (+ (double x) x))))
+//!
+//! (import (prefix (math arith) m:))
+//! (m:thrice 2)
+//! ```
+//!
+//! Libraries are resolved at compile time before anything else and leave
+//! nothing behind. Every definition in a library is renamed after the library,
+//! `double` above becomes `%math.arith/double`, and references to imported
+//! names are replaced with the renamed definitions. Two libraries can then
+//! define the same names and still be compiled separately and linked together,
+//! see [units](crate::cli::Driver::units).
+//!
+//! `(include "file" ...)` in a library reads the files at compile time, as if
+//! they were in a `begin`. Libraries must be defined before they are imported.
+//! The standard libraries like `(scheme base)` can be imported, but everything
+//! in them is always available anyway.
+//!
+//! Importing two different definitions with the same name is an error, and so
+//! is a reference to a name an imported library defines without exporting it.
+
+use crate::{
+    callbacks,
+    compiler::{self, Fault},
+    core::{Closure, Expr::*, Literal::*, Name, Syntax},
+    lang, resolve, semantic,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Names of imported bindings mapped to the definitions they refer to
+type Env = HashMap<String, String>;
+
+/// Interfaces of all the libraries defined so far, by name
+#[derive(Default)]
+pub struct Libraries(HashMap<String, Library>);
+
+/// A library defined in the program
+#[derive(Default)]
+struct Library {
+    /// Exported names mapped to the definitions they refer to
+    interface: Env,
+    /// Names of the definitions it doesn't export as such
+    internal: Vec<String>,
+}
+
+/// A name exported by a library, see [Libraries::exports]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Export {
+    /// Name of the library, like `(math arith)`
+    pub library: String,
+    /// Name it is exported as, like `thrice`
+    pub name: String,
+    /// The definition it refers to, like `%math.arith/triple`
+    pub target: String,
+}
+
+impl Libraries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve all the libraries and imports in a program
+    ///
+    /// Libraries are replaced by their renamed definitions and expressions,
+    /// which stay in the same place in the program. Imports apply to the rest
+    /// of the program, where a definition of the same name shadows an import.
+    pub fn resolve(&mut self, prog: Vec<Syntax>) -> Result<Vec<Syntax>, Vec<Fault>> {
+        let mut env = Env::new();
+        let mut hidden = Env::new();
+        let mut resolved = vec![];
+
+        // Names the program defines itself, outside of its libraries
+        let mut globals = resolve::Globals::default();
+        prog.iter().filter(|e| !is_library(e)).for_each(|e| resolve::defined(e, &mut globals));
+        let defined: HashSet<String> = globals.into_iter().map(String::from).collect();
+
+        for form in prog {
+            match form {
+                List(list) => match list.as_slice() {
+                    [Identifier(head), lib, decls @ ..] if head == "define-library" => {
+                        resolved.extend(self.library(&name(lib)?, decls)?)
+                    }
+                    [Identifier(head), sets @ ..] if head == "import" => {
+                        for set in sets {
+                            self.extend(&mut env, &mut hidden, set)?
+                        }
+                    }
+                    _ => {
+                        let form = List(list);
+                        exported(&form, &env, &hidden, &defined)?;
+                        resolved.push(substitute(&env, form))
+                    }
+                },
+                Define { name, val } => {
+                    env.remove(name.as_str());
+                    exported(&val, &env, &hidden, &defined)?;
+                    resolved.push(Define { name, val: Box::new(substitute(&env, *val)) })
+                }
+                form => {
+                    exported(&form, &env, &hidden, &defined)?;
+                    resolved.push(substitute(&env, form))
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Everything exported by the libraries defined so far, ordered by library
+    /// and name
+    pub fn exports(&self) -> Vec<Export> {
+        let mut exports: Vec<Export> = self
+            .0
+            .iter()
+            .flat_map(|(library, Library { interface, .. })| {
+                interface.iter().map(move |(name, target)| Export {
+                    library: library.clone(),
+                    name: name.clone(),
+                    target: target.clone(),
+                })
+            })
+            .collect();
+
+        exports.sort();
+        exports
+    }
+
+    /// Rename all the definitions of a library and remember its interface
+    fn library(&mut self, lib: &str, decls: &[Syntax]) -> Result<Vec<Syntax>, Vec<Fault>> {
+        let mut env = Env::new();
+        let mut hidden = Env::new();
+        let mut exports = vec![];
+        let mut body = vec![];
+
+        for decl in decls {
+            match decl {
+                List(list) => match list.as_slice() {
+                    [Identifier(head), specs @ ..] if head == "export" => {
+                        for spec in specs {
+                            exports.push(export(spec)?)
+                        }
+                    }
+                    [Identifier(head), sets @ ..] if head == "import" => {
+                        for set in sets {
+                            self.extend(&mut env, &mut hidden, set)?
+                        }
+                    }
+                    [Identifier(head), forms @ ..] if head == "begin" => {
+                        body.extend(forms.iter().cloned())
+                    }
+                    [Identifier(head), files @ ..] if head == "include" => {
+                        for file in files {
+                            body.extend(include(file)?)
+                        }
+                    }
+                    _ => {
+                        let message = format!("Invalid library declaration `{}` in {}", decl, lib);
+                        return compiler::fail(message);
+                    }
+                },
+                _ => {
+                    let message = format!("Invalid library declaration `{}` in {}", decl, lib);
+                    return compiler::fail(message);
+                }
+            }
+        }
+
+        // Definitions of the library take precedence over the imports
+        let prefix = lib.trim_start_matches('(').trim_end_matches(')').replace(' ', ".");
+
+        for form in &body {
+            if let Define { name, .. } = form {
+                env.insert(name.to_string(), format!("%{}/{}", prefix, name));
+            }
+        }
+
+        let interface: Env = exports
+            .into_iter()
+            .map(|(internal, external)| match env.get(&internal) {
+                Some(target) => Ok((external, target.clone())),
+                None => compiler::fail(format!("Library {} exports undefined `{}`", lib, internal)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let internal = body
+            .iter()
+            .filter_map(|form| match form {
+                Define { name, .. } if !interface.contains_key(name.as_str()) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })
+            .collect();
+
+        for form in &body {
+            exported(form, &env, &hidden, &HashSet::new())?;
+        }
+
+        self.0.insert(lib.to_string(), Library { interface, internal });
+
+        Ok(body
+            .into_iter()
+            .map(|form| match form {
+                Define { name, val } => {
+                    let name = name.rename(&env[name.as_str()]);
+                    Define { name, val: Box::new(substitute(&env, *val)) }
+                }
+                form => substitute(&env, form),
+            })
+            .collect())
+    }
+
+    /// Add the bindings of an import set to `env`
+    ///
+    /// Importing the same definition twice is fine, two different ones with
+    /// the same name is not. The names the library keeps to itself are added
+    /// to `hidden`, mapped to the name of the library.
+    fn extend(&self, env: &mut Env, hidden: &mut Env, set: &Syntax) -> Result<(), Vec<Fault>> {
+        let lib = source(set);
+        let imported = self.import(set)?;
+
+        for (id, target) in imported {
+            if let Some(other) = env.get(&id).filter(|other| **other != target) {
+                let owner = self.owner(other).unwrap_or("another library");
+                return lang::invalid(
+                    library_name(lib)?,
+                    format!("`{}` is imported from both {} and {}", id, owner, name(lib)?),
+                );
+            }
+            env.insert(id, target);
+        }
+
+        let lib = name(lib)?;
+        if let Some(library) = self.0.get(&lib) {
+            hidden.extend(library.internal.iter().map(|id| (id.clone(), lib.clone())));
+        }
+
+        Ok(())
+    }
+
+    /// Name of the library a definition belongs to
+    fn owner(&self, target: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(_, library)| library.interface.values().any(|t| t == target))
+            .map(|(lib, _)| lib.as_str())
+    }
+
+    /// The bindings introduced by an import set
+    fn import(&self, set: &Syntax) -> Result<Env, Vec<Fault>> {
+        let list = match set {
+            List(list) => list,
+            _ => return compiler::fail(format!("Invalid import set `{}`", set)),
+        };
+
+        let env = match list.as_slice() {
+            [Identifier(head), set, ids @ ..] if head == "only" => {
+                let ids: Vec<String> = ids.iter().map(identifier).collect::<Result<_, _>>()?;
+                self.import(set)?.into_iter().filter(|(name, _)| ids.contains(name)).collect()
+            }
+            [Identifier(head), set, ids @ ..] if head == "except" => {
+                let ids: Vec<String> = ids.iter().map(identifier).collect::<Result<_, _>>()?;
+                self.import(set)?.into_iter().filter(|(name, _)| !ids.contains(name)).collect()
+            }
+            [Identifier(head), set, Identifier(prefix)] if head == "prefix" => self
+                .import(set)?
+                .into_iter()
+                .map(|(name, target)| (format!("{}{}", prefix, name), target))
+                .collect(),
+            [Identifier(head), set, renames @ ..] if head == "rename" => {
+                let mut env = self.import(set)?;
+
+                for rename in renames {
+                    let (from, to) = export(rename)?;
+
+                    match env.remove(&from) {
+                        Some(target) => env.insert(to, target),
+                        None => {
+                            return compiler::fail(format!(
+                                "Can't rename `{}`, it isn't imported by `{}`",
+                                from, set
+                            ))
+                        }
+                    };
+                }
+
+                env
+            }
+            _ => {
+                let lib = name(set)?;
+
+                match self.0.get(&lib) {
+                    Some(library) => library.interface.clone(),
+                    None if builtin(&lib) => Env::new(),
+                    None => return compiler::fail(format!("Unknown library {}", lib)),
+                }
+            }
+        };
+
+        Ok(env)
+    }
+}
+
+/// Names of a library, like `(math arith)`
+fn name(name: &Syntax) -> Result<String, Vec<Fault>> {
+    match name {
+        List(parts) if !parts.is_empty() => {
+            let parts: Vec<String> = parts
+                .iter()
+                .map(|part| match part {
+                    Identifier(s) => Ok(s.to_string()),
+                    Literal(Number(n)) => Ok(n.to_string()),
+                    _ => compiler::fail(format!("Invalid library name `{}`", name)),
+                })
+                .collect::<Result<_, _>>()?;
+
+            Ok(format!("({})", parts.join(" ")))
+        }
+        _ => compiler::fail(format!("Invalid library name `{}`", name)),
+    }
+}
+
+/// Name of the library an import set imports from, like `(math arith)` in
+/// `(prefix (math arith) m:)`
+fn source(set: &Syntax) -> &Syntax {
+    match set {
+        List(list) => match list.as_slice() {
+            [Identifier(head), set, ..]
+                if ["only", "except", "prefix", "rename"].contains(&head.as_str()) =>
+            {
+                source(set)
+            }
+            _ => set,
+        },
+        _ => set,
+    }
+}
+
+/// The first part of the name of a library, to point errors at
+fn library_name(lib: &Syntax) -> Result<&Name, Vec<Fault>> {
+    match lib {
+        List(parts) => match parts.first() {
+            Some(Identifier(part)) => Ok(part),
+            _ => compiler::fail(format!("Invalid library name `{}`", lib)),
+        },
+        _ => compiler::fail(format!("Invalid library name `{}`", lib)),
+    }
+}
+
+/// Is the form a `define-library`?
+fn is_library(form: &Syntax) -> bool {
+    match form {
+        List(list) => matches!(list.first(), Some(Identifier(head)) if head == "define-library"),
+        _ => false,
+    }
+}
+
+/// Libraries implemented by the compiler itself
+fn builtin(lib: &str) -> bool {
+    ["(scheme ", "(srfi ", "(inc "].iter().any(|prefix| lib.starts_with(prefix))
+}
+
+/// An export spec as a pair of the internal and the external name
+fn export(spec: &Syntax) -> Result<(String, String), Vec<Fault>> {
+    match spec {
+        Identifier(name) => Ok((name.to_string(), name.to_string())),
+        List(list) => match list.as_slice() {
+            [Identifier(head), Identifier(from), Identifier(to)] if head == "rename" => {
+                Ok((from.to_string(), to.to_string()))
+            }
+            [Identifier(from), Identifier(to)] => Ok((from.to_string(), to.to_string())),
+            _ => compiler::fail(format!("Invalid export spec `{}`", spec)),
+        },
+        _ => compiler::fail(format!("Invalid export spec `{}`", spec)),
+    }
+}
+
+fn identifier(id: &Syntax) -> Result<String, Vec<Fault>> {
+    match id {
+        Identifier(name) => Ok(name.to_string()),
+        _ => compiler::fail(format!("Expected an identifier, found `{}`", id)),
+    }
+}
+
+/// Read the forms of an included file
+fn include(file: &Syntax) -> Result<Vec<Syntax>, Vec<Fault>> {
+    let path = match file {
+        Literal(Str(path)) => path,
+        _ => return compiler::fail(format!("Invalid include `{}`", file)),
+    };
+
+    lang::read(path)
+}
+
+/// Fail on a reference to a name an imported library doesn't export
+///
+/// `hidden` maps the names the imported libraries define without exporting to
+/// the libraries. A name that is imported, defined by the program or built in
+/// is fine even when a library keeps a definition of the same name to itself.
+fn exported(
+    prog: &Syntax,
+    env: &Env,
+    hidden: &Env,
+    defined: &HashSet<String>,
+) -> Result<(), Vec<Fault>> {
+    if hidden.is_empty() {
+        return Ok(());
+    }
+
+    let mut free = vec![];
+    references(prog, &mut vec![], &mut free);
+
+    for id in free {
+        let lib = match hidden.get(id.as_str()) {
+            Some(lib) => lib,
+            None => continue,
+        };
+
+        let known = env.contains_key(id.as_str())
+            || defined.contains(id.as_str())
+            || semantic::builtin(id)
+            || semantic::KEYWORDS.contains(&id.as_str())
+            || callbacks::find(id).is_some();
+
+        if !known {
+            return lang::invalid(id, format!("`{}` isn't exported by {}", id, lib));
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect the free references of an expression evaluated with `bound` bound
+fn references<'a>(prog: &'a Syntax, bound: &mut Vec<&'a str>, free: &mut Vec<&'a Name>) {
+    match prog {
+        Identifier(name) => {
+            if !bound.contains(&name.as_str()) {
+                free.push(name)
+            }
+        }
+
+        // Quoted data isn't code
+        List(list) if matches!(list.first(), Some(Identifier(quote)) if quote == "quote") => {}
+
+        List(list) | Vector(list) => list.iter().for_each(|e| references(e, bound, free)),
+
+        Cond { pred, then, alt } => {
+            references(pred, bound, free);
+            references(then, bound, free);
+            alt.iter().for_each(|e| references(e, bound, free));
+        }
+
+        Let { bindings, body } => {
+            bindings.iter().for_each(|(_, val)| references(val, bound, free));
+            let depth = bound.len();
+            bound.extend(bindings.iter().map(|(name, _)| name.as_str()));
+            body.iter().for_each(|e| references(e, bound, free));
+            bound.truncate(depth);
+        }
+
+        Lambda(Closure { formals, body, .. }) => {
+            let depth = bound.len();
+            bound.extend(formals.iter().map(Name::as_str));
+            body.iter().for_each(|e| references(e, bound, free));
+            bound.truncate(depth);
+        }
+
+        Define { val, .. } => references(val, bound, free),
+
+        Literal(_) => {}
+    }
+}
+
+/// Replace free references to the names in `env`
+///
+/// Local variables shadow the names bound by the environment.
+fn substitute(env: &Env, prog: Syntax) -> Syntax {
+    let without = |names: &mut dyn Iterator<Item = &Name>| {
+        let mut env = env.clone();
+        for name in names {
+            env.remove(name.as_str());
+        }
+        env
+    };
+
+    match prog {
+        Identifier(name) => match env.get(name.as_str()) {
+            Some(target) => Identifier(name.rename(target)),
+            None => Identifier(name),
+        },
+
+        List(list) => List(list.into_iter().map(|e| substitute(env, e)).collect()),
+
+        Vector(list) => Vector(list.into_iter().map(|e| substitute(env, e)).collect()),
+
+        Cond { pred, then, alt } => Cond {
+            pred: Box::new(substitute(env, *pred)),
+            then: Box::new(substitute(env, *then)),
+            alt: alt.map(|e| Box::new(substitute(env, *e))),
+        },
+
+        Let { bindings, body } => {
+            let env = without(&mut bindings.iter().map(|(name, _)| name));
+
+            Let {
+                bindings: bindings
+                    .into_iter()
+                    .map(|(name, val)| (name, substitute(&env, val)))
+                    .collect(),
+                body: body.into_iter().map(|e| substitute(&env, e)).collect(),
+            }
+        }
+
+        Lambda(code) => {
+            let env = without(&mut code.formals.iter());
+
+            Lambda(Closure {
+                body: code.body.into_iter().map(|e| substitute(&env, e)).collect(),
+                ..code
+            })
+        }
+
+        Define { name, val } => Define { name, val: Box::new(substitute(env, *val)) },
+
+        Literal(_) => prog,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn resolve(prog: &str) -> String {
+        let prog = Libraries::new().resolve(parser::parse(prog).unwrap()).unwrap();
+        prog.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")
+    }
+
+    const LIB: &str = "(define-library (math arith)
+                         (export double (rename triple thrice))
+                         (import (scheme base))
+                         (begin
+                           (define (double x) (* x 2))
+                           (define (triple x) (+ (double x) x))))";
+
+    #[test]
+    fn rename() {
+        let prog = resolve(&format!("{} (import (math arith)) (thrice (double 1))", LIB));
+
+        assert!(prog.contains("(define %math.arith/triple"));
+        assert!(prog.contains("(%math.arith/double x)"));
+        assert!(prog.ends_with("(%math.arith/triple (%math.arith/double 1))"));
+    }
+
+    #[test]
+    fn sets() {
+        let imports = |set: &str| {
+            let mut libs = Libraries::new();
+            libs.resolve(parser::parse(LIB).unwrap()).unwrap();
+
+            let mut names: Vec<String> =
+                libs.import(&parser::parse(set).unwrap()[0]).unwrap().into_keys().collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(imports("(math arith)"), vec!["double", "thrice"]);
+        assert_eq!(imports("(only (math arith) double)"), vec!["double"]);
+        assert_eq!(imports("(except (math arith) double)"), vec!["thrice"]);
+        assert_eq!(imports("(prefix (math arith) m:)"), vec!["m:double", "m:thrice"]);
+        assert_eq!(imports("(rename (math arith) (double twice))"), vec!["thrice", "twice"]);
+        assert!(imports("(scheme base)").is_empty());
+    }
+
+    #[test]
+    fn exports() {
+        let mut libs = Libraries::new();
+        libs.resolve(parser::parse(LIB).unwrap()).unwrap();
+
+        let exports: Vec<(String, String)> =
+            libs.exports().into_iter().map(|e| (e.name, e.target)).collect();
+
+        assert_eq!(
+            exports,
+            vec![
+                ("double".into(), "%math.arith/double".into()),
+                ("thrice".into(), "%math.arith/triple".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let result = |prog: String| {
+            let prog = parser::parse(&prog).unwrap();
+            Libraries::new().resolve(prog).map_err(|faults| faults[0].message.clone())
+        };
+
+        let other = "(define-library (other) (export double) (begin (define (double x) x)))";
+
+        assert!(result(format!("{} (import (math arith) (only (math arith) double))", LIB)).is_ok());
+        assert!(result(format!("{} (import (math arith)) (define (triple x) x) (triple 1)", LIB))
+            .is_ok());
+        assert!(result(format!("{} (import (math arith)) (let ((triple 1)) triple)", LIB)).is_ok());
+        assert!(result(format!("{} (import (math arith)) 'triple", LIB)).is_ok());
+
+        assert_eq!(
+            result(format!("{} {} (import (math arith) (other))", LIB, other)).unwrap_err(),
+            "`double` is imported from both (math arith) and (other)"
+        );
+        assert_eq!(
+            result(format!("{} (import (math arith)) (triple 1)", LIB)).unwrap_err(),
+            "`triple` isn't exported by (math arith)"
+        );
+        assert_eq!(
+            result(format!("{} {} (import (prefix (math arith) m:)) (triple 1)", LIB, other))
+                .unwrap_err(),
+            "`triple` isn't exported by (math arith)"
+        );
+    }
+
+    #[test]
+    fn shadow() {
+        let prog = resolve(&format!(
+            "{} (import (math arith)) (let ((double 1)) double) (lambda (thrice) thrice)",
+            LIB
+        ));
+
+        assert!(prog.contains("(let ((double 1)) double)"));
+        assert!(prog.ends_with("(λ (thrice) thrice)"));
+    }
+}
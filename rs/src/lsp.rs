@@ -0,0 +1,940 @@
+//! A language server for editors
+//!
+//! `inc lsp` speaks the [Language Server Protocol] on stdin and stdout, so any
+//! editor with a client for it gets a few conveniences for Scheme files:
+//!
+//! - Errors in a file as it changes, found by compiling it like `inc check`
+//!   does and shown as [Diagnostic]s.
+//! - Going to the definition of a function or a variable, in the same file or
+//!   in any other open file, and finding all the references to it. Both are
+//!   answered from a [Database] of the open files.
+//! - Hover showing the arguments a function takes, or what derived syntax like
+//!   `guard` [expands](lang::expand) to.
+//! - An outline of the functions and variables defined at the top level.
+//! - [Semantic tokens](semantic), telling keywords, local variables, globals,
+//!   built in functions, literals and macros apart.
+//! - [Completion](complete) of the name being typed, with the names defined
+//!   in the other open files as well.
+//!
+//! The [parser](crate::parser) doesn't keep track of where forms came from, so
+//! the server reads files once more into [Form]s that do, leaving out the
+//! details the compiler cares about. Reading never fails, an unclosed list
+//! just ends with the file, since the file being edited is rarely valid.
+//!
+//! Documents are synced whole on every change. Positions are lines and UTF-16
+//! code units, as the protocol says.
+//!
+//! [Language Server Protocol]: https://microsoft.github.io/language-server-protocol
+
+use crate::{
+    cli::Driver,
+    complete::{self, Candidate},
+    core::{Config, Expr},
+    diagnostic::Diagnostic,
+    host,
+    json::Json,
+    lang, parser,
+    refs::Database,
+    semantic::{self, Kind},
+};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+/// Error code for a request with an unknown method
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Error code for a message that isn't valid JSON
+const PARSE_ERROR: i64 = -32700;
+
+/// Kinds of document symbols in the protocol
+const FUNCTION: i64 = 12;
+const VARIABLE: i64 = 13;
+
+/// Kinds of completion items in the protocol
+const COMPLETE_FUNCTION: i64 = 3;
+const COMPLETE_VARIABLE: i64 = 6;
+const COMPLETE_KEYWORD: i64 = 14;
+
+/// Types of semantic tokens in the protocol, in the order of [Kind::ALL]
+const TOKEN_TYPES: [&str; 6] = ["keyword", "parameter", "variable", "function", "string", "macro"];
+
+/// A form of the source and where it is
+#[derive(Debug, Clone, PartialEq)]
+pub enum Form {
+    /// A symbol, number, string or any other token
+    Atom(String, Range<usize>),
+    /// A list in parentheses or brackets
+    List(Vec<Form>, Range<usize>),
+}
+
+/// A definition found in a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    /// The whole `define` form
+    pub span: Range<usize>,
+    /// Just the name in it
+    pub name_span: Range<usize>,
+    /// Names of the arguments of a function, nothing for a variable
+    pub formals: Option<Vec<String>>,
+    /// Whether the function takes any number of arguments after the formals
+    pub rest: bool,
+}
+
+/// The state of the server, the open documents by URI
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    database: Database,
+    shutdown: bool,
+}
+
+/// Serve an editor on stdin and stdout until it says to exit
+///
+/// Returns the status to exit with, which is 0 only if the editor asked the
+/// server to shut down first.
+pub fn serve() -> i32 {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+
+    Server::default().run(stdin.lock(), stdout.lock()).unwrap_or_else(|e| {
+        eprintln!("inc lsp: {}", e);
+        1
+    })
+}
+
+impl Server {
+    /// Answer the messages from `input` on `output` till the `exit`
+    /// notification or the end of the input
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<i32> {
+        while let Some(message) = read_message(&mut input)? {
+            let replies = match Json::parse(&message) {
+                Ok(message) if method(&message) == Some("exit") => break,
+                Ok(message) => self.handle(&message),
+                Err(e) => vec![error(Json::Null, PARSE_ERROR, &e)],
+            };
+
+            for reply in replies {
+                write_message(&mut output, &reply)?;
+            }
+        }
+
+        Ok(if self.shutdown { 0 } else { 1 })
+    }
+
+    /// The messages to send back for one from the editor
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|doc| doc.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        let id = message.get("id").cloned().unwrap_or(Json::Null);
+
+        let result = match method(message).unwrap_or_default() {
+            "initialize" => Json::object(vec![
+                (
+                    "capabilities",
+                    Json::object(vec![
+                        ("textDocumentSync", 1.into()),
+                        ("definitionProvider", true.into()),
+                        ("referencesProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                        ("documentSymbolProvider", true.into()),
+                        ("completionProvider", Json::object(vec![])),
+                        (
+                            "semanticTokensProvider",
+                            Json::object(vec![("legend", legend()), ("full", true.into())]),
+                        ),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object(vec![
+                        ("name", "inc".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ]),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|doc| doc.get("text"));
+                return self.update(uri, text.and_then(Json::as_str).map(String::from));
+            }
+            "textDocument/didChange" => {
+                // Changes are always the whole text, see the capabilities
+                let changes = params.get("contentChanges").and_then(Json::as_array);
+                let text = changes.and_then(|changes| changes.last()?.get("text")?.as_str());
+                return self.update(uri, text.map(String::from));
+            }
+            "textDocument/didClose" => return self.update(uri, None),
+            "textDocument/definition" => match self.offset(&uri, params) {
+                Some(offset) => self.definition(&uri, offset).map_or(Json::Null, |(uri, span)| {
+                    let range = range(&self.documents[&uri], &span);
+                    Json::object(vec![("uri", uri.into()), ("range", range)])
+                }),
+                None => Json::Null,
+            },
+            "textDocument/references" => match self.offset(&uri, params) {
+                Some(offset) => {
+                    let context = params.get("context").and_then(|c| c.get("includeDeclaration"));
+                    let declaration = context != Some(&Json::Bool(false));
+                    let definition = self.database.definition_of(&uri, offset);
+
+                    let locations = self.database.references_at(&uri, offset).into_iter();
+                    let locations = locations
+                        .filter(|l| declaration || Some(l) != definition.as_ref())
+                        .map(|l| {
+                            let range = range(&self.documents[&l.file], &l.span);
+                            Json::object(vec![("uri", l.file.into()), ("range", range)])
+                        });
+                    Json::from(locations.collect::<Vec<Json>>())
+                }
+                None => Json::Null,
+            },
+            "textDocument/hover" => match self.offset(&uri, params) {
+                Some(offset) => self.hover(&uri, offset).map_or(Json::Null, |(text, span)| {
+                    let contents =
+                        Json::object(vec![("kind", "markdown".into()), ("value", text.into())]);
+                    let range = range(&self.documents[&uri], &span);
+                    Json::object(vec![("contents", contents), ("range", range)])
+                }),
+                None => Json::Null,
+            },
+            "textDocument/completion" => match self.offset(&uri, params) {
+                Some(offset) => {
+                    let items = self.complete(&uri, offset).into_iter().map(|c| {
+                        let kind = match c.kind {
+                            Kind::Keyword | Kind::Macro => COMPLETE_KEYWORD,
+                            Kind::Bound => COMPLETE_VARIABLE,
+                            _ => COMPLETE_FUNCTION,
+                        };
+                        Json::object(vec![
+                            ("label", c.name.into()),
+                            ("kind", kind.into()),
+                            ("detail", c.kind.name().into()),
+                        ])
+                    });
+                    Json::from(items.collect::<Vec<Json>>())
+                }
+                None => Json::Null,
+            },
+            "textDocument/documentSymbol" => match self.documents.get(&uri) {
+                Some(source) => symbols(source).into(),
+                None => Json::Null,
+            },
+            "textDocument/semanticTokens/full" => match self.documents.get(&uri) {
+                Some(source) => Json::object(vec![("data", tokens(source).into())]),
+                None => Json::Null,
+            },
+            // Notifications need no answer, even unknown ones
+            _ if message.get("id").is_none() => return vec![],
+            method => {
+                let e = format!("Unknown method `{}`", method);
+                return vec![error(id, METHOD_NOT_FOUND, &e)];
+            }
+        };
+
+        vec![Json::object(vec![("jsonrpc", "2.0".into()), ("id", id), ("result", result)])]
+    }
+
+    /// Open, change or close a document and publish its diagnostics
+    fn update(&mut self, uri: String, text: Option<String>) -> Vec<Json> {
+        let diagnostics = match &text {
+            Some(text) => diagnostics(text),
+            None => vec![],
+        };
+
+        match text {
+            Some(text) => {
+                self.database.insert(&uri, &text);
+                self.documents.insert(uri.clone(), text);
+            }
+            None => {
+                self.database.remove(&uri);
+                self.documents.remove(&uri);
+            }
+        }
+
+        let params = Json::object(vec![("uri", uri.into()), ("diagnostics", diagnostics.into())]);
+        vec![notification("textDocument/publishDiagnostics", params)]
+    }
+
+    /// Offset of the position in the params of a request, in an open document
+    fn offset(&self, uri: &str, params: &Json) -> Option<usize> {
+        let source = self.documents.get(uri)?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_i64()?;
+        let character = position.get("character")?.as_i64()?;
+
+        Some(offset(source, line as usize, character as usize))
+    }
+
+    /// The document and span a name at an offset is defined at
+    ///
+    /// Names bound around the offset come first, then functions and variables
+    /// defined at the top level of the document and then of the other open
+    /// documents, see [Database::definition_of].
+    pub fn definition(&self, uri: &str, offset: usize) -> Option<(String, Range<usize>)> {
+        let found = self.database.definition_of(uri, offset)?;
+        Some((found.file, found.span))
+    }
+
+    /// The names completing the one being typed at an offset, see
+    /// [complete](complete::complete)
+    ///
+    /// Names defined at the top level of the other open documents complete
+    /// too, since a program can be built from several files.
+    pub fn complete(&self, uri: &str, offset: usize) -> Vec<Candidate> {
+        let source = match self.documents.get(uri) {
+            Some(source) => source,
+            None => return vec![],
+        };
+
+        let globals: Vec<String> = self
+            .documents
+            .iter()
+            .filter(|(other, _)| *other != uri)
+            .flat_map(|(_, text)| definitions(&read(text)))
+            .map(|d| d.name)
+            .collect();
+
+        complete::complete(source, offset, &globals).1
+    }
+
+    /// Markdown describing the name at an offset, and its span
+    pub fn hover(&self, uri: &str, offset: usize) -> Option<(String, Range<usize>)> {
+        let source = self.documents.get(uri)?;
+        let forms = read(source);
+        let (name, span, path) = at(&forms, offset)?;
+
+        // Derived syntax shows what it expands to from its keyword
+        if let Some(Form::List(items, list)) = path.last() {
+            if items.first().map(Form::span) == Some(span.clone()) {
+                if let Some(expansion) = expansion(&source[list.clone()]) {
+                    return Some((format!("```scheme\n{}\n```", expansion), span));
+                }
+            }
+        }
+
+        if self.database.token_at(uri, offset).map_or(false, |t| t.binding.is_some()) {
+            return Some((format!("```scheme\n{}\n```\nlocal variable", name), span));
+        }
+
+        if let Some((uri, _)) = self.definition(uri, offset) {
+            let definitions = definitions(&read(&self.documents[&uri]));
+            let found = definitions.into_iter().find(|d| d.name == name)?;
+            return Some((describe(&found), span));
+        }
+
+        if semantic::builtin(&name) {
+            return Some((format!("```scheme\n{}\n```\nbuilt in", name), span));
+        }
+
+        None
+    }
+}
+
+/// Read the forms of a source with their spans
+///
+/// Quotes are skipped and strings, characters and comments are read like the
+/// parser does. A list left open ends with the source and a stray closing
+/// parenthesis is ignored.
+pub fn read(source: &str) -> Vec<Form> {
+    let bytes = source.as_bytes();
+    let delimiter = |b: u8| b.is_ascii_whitespace() || b"()[]\";".contains(&b);
+
+    // The items of each list still open, with where it starts
+    let mut open: Vec<(Vec<Form>, usize)> = vec![(vec![], 0)];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+
+        match bytes[i] {
+            b if b.is_ascii_whitespace() || b == b'\'' || b == b'`' || b == b',' => i += 1,
+            b';' => i = source[i..].find('\n').map_or(bytes.len(), |n| i + n),
+            b'(' | b'[' => {
+                open.push((vec![], i));
+                i += 1;
+            }
+            b')' | b']' => {
+                i += 1;
+                if open.len() > 1 {
+                    let (items, start) = open.pop().unwrap();
+                    open.last_mut().unwrap().0.push(Form::List(items, start..i));
+                }
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                open.last_mut().unwrap().0.push(Form::Atom(source[start..i].to_string(), start..i));
+            }
+            _ => {
+                // A character like #\( is a single token, delimiter included
+                if source[i..].starts_with("#\\") && i + 2 < bytes.len() {
+                    i += 3;
+                }
+                while i < bytes.len() && !delimiter(bytes[i]) {
+                    i += 1;
+                }
+                while !source.is_char_boundary(i) {
+                    i += 1;
+                }
+                open.last_mut().unwrap().0.push(Form::Atom(source[start..i].to_string(), start..i));
+            }
+        }
+    }
+
+    while open.len() > 1 {
+        let (items, start) = open.pop().unwrap();
+        open.last_mut().unwrap().0.push(Form::List(items, start..bytes.len()));
+    }
+
+    open.pop().unwrap().0
+}
+
+impl Form {
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Form::Atom(_, span) | Form::List(_, span) => span.clone(),
+        }
+    }
+
+    /// The name of an atom
+    fn name(&self) -> Option<&str> {
+        match self {
+            Form::Atom(name, _) => Some(name),
+            Form::List(..) => None,
+        }
+    }
+}
+
+/// The functions and variables defined at the top level, including the ones
+/// in a `begin`
+pub fn definitions(forms: &[Form]) -> Vec<Definition> {
+    forms
+        .iter()
+        .flat_map(|form| match form {
+            Form::List(items, _) if items.first().and_then(Form::name) == Some("begin") => {
+                definitions(&items[1..])
+            }
+            form => define(form).into_iter().collect(),
+        })
+        .collect()
+}
+
+/// The definition of a `define` form
+fn define(form: &Form) -> Option<Definition> {
+    let (items, span) = match form {
+        Form::List(items, span) if items.first()?.name()? == "define" => (items, span.clone()),
+        _ => return None,
+    };
+
+    let definition = |name: &Form, formals: Option<&Form>| {
+        let (formals, rest) = match formals {
+            Some(Form::List(formals, _)) => {
+                let names: Vec<String> =
+                    formals.iter().filter_map(Form::name).map(String::from).collect();
+                match names.iter().position(|name| name == ".") {
+                    Some(dot) => (Some(names[..dot].to_vec()), true),
+                    None => (Some(names), false),
+                }
+            }
+            Some(Form::Atom(..)) => (Some(vec![]), true),
+            None => (None, false),
+        };
+
+        Some(Definition {
+            name: name.name()?.to_string(),
+            span: span.clone(),
+            name_span: name.span(),
+            formals,
+            rest,
+        })
+    };
+
+    match items.get(1)? {
+        // (define (f x ...) body ...), where the formals are the rest of the list
+        Form::List(head, list) => {
+            let name = head.first()?;
+            let formals = Form::List(head[1..].to_vec(), list.clone());
+            definition(name, Some(&formals))
+        }
+        name => match items.get(2) {
+            Some(Form::List(lambda, _))
+                if matches!(lambda.first().and_then(Form::name), Some("lambda" | "λ")) =>
+            {
+                definition(name, lambda.get(1))
+            }
+            _ => definition(name, None),
+        },
+    }
+}
+
+/// The atom at an offset, its span and the lists around it, outermost first
+fn at(forms: &[Form], offset: usize) -> Option<(String, Range<usize>, Vec<&Form>)> {
+    let mut path = vec![];
+    let mut forms = forms;
+
+    loop {
+        // An offset right after a name is still on it, like at the end of a line
+        let form = forms.iter().find(|form| {
+            let span = form.span();
+            span.start <= offset && offset <= span.end
+        })?;
+
+        match form {
+            Form::Atom(name, span) => return Some((name.clone(), span.clone(), path)),
+            Form::List(items, _) => {
+                path.push(form);
+                forms = items;
+            }
+        }
+    }
+}
+
+/// What derived syntax expands to, if the source is a form that does
+///
+/// Only forms that turn into something else are expanded; a call stays a call
+/// of the same function even if some of its arguments expand.
+fn expansion(source: &str) -> Option<String> {
+    let form = parser::parse(source).ok()?.into_iter().next()?;
+    let expanded = lang::expand(form.clone()).ok()?;
+
+    match (&form, &expanded) {
+        (Expr::List(a), Expr::List(b)) if a.first() == b.first() => None,
+        _ if form == expanded => None,
+        _ => Some(expanded.pretty(80)),
+    }
+}
+
+/// Markdown describing a definition
+fn describe(d: &Definition) -> String {
+    let formals = match &d.formals {
+        Some(formals) => formals,
+        None => return format!("```scheme\n{}\n```\nvariable", d.name),
+    };
+
+    let mut signature = std::iter::once(&d.name).chain(formals).cloned().collect::<Vec<_>>();
+    if d.rest {
+        signature.push(String::from(". rest"));
+    }
+
+    let plural = if formals.len() == 1 { "" } else { "s" };
+    let least = if d.rest { "at least " } else { "" };
+
+    format!(
+        "```scheme\n({})\n```\ntakes {}{} argument{}",
+        signature.join(" "),
+        least,
+        formals.len(),
+        plural
+    )
+}
+
+/// The errors in a source, as `inc check` finds them
+///
+/// The file is [checked](Driver::check) on a [deep](host::deep) stack like
+/// the command line does, without ever running it. Errors that don't know
+/// where they are in the source are shown at its start.
+fn diagnostics(source: &str) -> Vec<Json> {
+    // An empty file isn't a program yet, but not wrong either
+    if source.trim().is_empty() {
+        return vec![];
+    }
+
+    let config = Config { program: source.to_string(), ..Config::default() };
+    let checked = host::deep(|| {
+        let driver = Driver::new(&config);
+
+        #[cfg(feature = "native")]
+        let checked = driver.check();
+        #[cfg(not(feature = "native"))]
+        let checked = driver.compile().map(|_| ());
+
+        checked.map_err(|e| Diagnostic::all(&e, &[("<input>", config.program.as_str())]))
+    });
+
+    let errors = match checked {
+        Ok(()) => return vec![],
+        Err(errors) => errors,
+    };
+
+    errors
+        .iter()
+        .map(|d: &Diagnostic| {
+            let span = d.span.clone().unwrap_or(0..0);
+            let message: Vec<&str> =
+                std::iter::once(&d.message).chain(&d.notes).map(String::as_str).collect();
+
+            Json::object(vec![
+                ("range", range(source, &span)),
+                ("severity", 1.into()),
+                ("code", d.code.into()),
+                ("source", "inc".into()),
+                ("message", message.join("\n").into()),
+            ])
+        })
+        .collect()
+}
+
+/// The outline of a source, its top level definitions
+fn symbols(source: &str) -> Vec<Json> {
+    definitions(&read(source))
+        .iter()
+        .map(|d| {
+            let kind = if d.formals.is_some() { FUNCTION } else { VARIABLE };
+
+            Json::object(vec![
+                ("name", d.name.as_str().into()),
+                ("kind", kind.into()),
+                ("range", range(source, &d.span)),
+                ("selectionRange", range(source, &d.name_span)),
+            ])
+        })
+        .collect()
+}
+
+/// The types of semantic tokens, in the order [tokens] numbers them
+fn legend() -> Json {
+    let types: Vec<Json> = TOKEN_TYPES.iter().map(|&t| t.into()).collect();
+    Json::object(vec![("tokenTypes", types.into()), ("tokenModifiers", Json::from(vec![]))])
+}
+
+/// The semantic tokens of a source, as the protocol encodes them
+///
+/// Every token is five numbers: its line and start relative to the previous
+/// one, its length, its type and no modifiers. A token over several lines,
+/// like a string with newlines in it, is split into one for each line.
+fn tokens(source: &str) -> Vec<Json> {
+    let mut data = vec![];
+    let (mut last_line, mut last_start) = (0, 0);
+
+    for token in semantic::tokens(source) {
+        let kind = Kind::ALL.iter().position(|&k| k == token.kind).unwrap_or_default();
+        let before = &source[..token.span.start];
+        let first = before.matches('\n').count();
+        let column = before[before.rfind('\n').map_or(0, |i| i + 1)..].encode_utf16().count();
+
+        for (n, part) in source[token.span.clone()].split('\n').enumerate() {
+            let (line, start) = (first + n, if n == 0 { column } else { 0 });
+            let length = part.encode_utf16().count();
+            if length > 0 {
+                let delta = if line == last_line { start - last_start } else { start };
+                data.extend(vec![line - last_line, delta, length, kind, 0]);
+                last_line = line;
+                last_start = start;
+            }
+        }
+    }
+
+    data.into_iter().map(Json::from).collect()
+}
+
+/// Line and UTF-16 character of an offset
+fn position(source: &str, offset: usize) -> Json {
+    let before = &source[..offset.min(source.len())];
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count();
+    let character = before[start..].encode_utf16().count();
+
+    Json::object(vec![("line", line.into()), ("character", character.into())])
+}
+
+/// A range of the protocol for a span
+fn range(source: &str, span: &Range<usize>) -> Json {
+    Json::object(vec![("start", position(source, span.start)), ("end", position(source, span.end))])
+}
+
+/// Offset of a line and UTF-16 character, clamped to the line
+fn offset(source: &str, line: usize, character: usize) -> usize {
+    let start: usize = source.split_inclusive('\n').take(line).map(str::len).sum();
+    let text = source[start..].split('\n').next().unwrap_or_default();
+
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if units >= character {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+
+    start + text.len()
+}
+
+fn method(message: &Json) -> Option<&str> {
+    message.get("method").and_then(Json::as_str)
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])
+}
+
+fn error(id: Json, code: i64, message: &str) -> Json {
+    let error = Json::object(vec![("code", code.into()), ("message", message.into())]);
+    Json::object(vec![("jsonrpc", "2.0".into()), ("id", id), ("error", error)])
+}
+
+/// Read the content of the next message, nothing at the end of the input
+///
+/// Messages start with headers, of which only `Content-Length` matters.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Expected a Content-Length header")
+    })?;
+
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+
+    String::from_utf8(content)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write a message with its header
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let content = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "; Twice
+(define (twice x) (* x 2))
+(define (sum . xs) (fold + 0 xs))
+(define answer (twice 21))
+(define (f a) (let loop ((i a)) (if (zero? i) (loop (- i 1)) (twice \"é\"))))
+(guard (e (#t 1)) (f answer))";
+
+    fn server() -> Server {
+        let mut server = Server::default();
+        for (uri, source) in &[("file:///a.ss", SOURCE), ("file:///b.ss", "(define (g) (h))")] {
+            server.documents.insert(uri.to_string(), source.to_string());
+            server.database.insert(uri, source);
+        }
+        server
+    }
+
+    #[test]
+    fn forms() {
+        let forms = read("(a [b \"c)\"] #\\( 'd) ; e\n(f");
+
+        assert_eq!(
+            forms,
+            vec![
+                Form::List(
+                    vec![
+                        Form::Atom(String::from("a"), 1..2),
+                        Form::List(
+                            vec![
+                                Form::Atom(String::from("b"), 4..5),
+                                Form::Atom(String::from("\"c)\""), 6..10),
+                            ],
+                            3..11
+                        ),
+                        Form::Atom(String::from("#\\("), 12..15),
+                        Form::Atom(String::from("d"), 17..18),
+                    ],
+                    0..19
+                ),
+                Form::List(vec![Form::Atom(String::from("f"), 25..26)], 24..26),
+            ]
+        );
+
+        let names: Vec<(String, Option<usize>, bool)> = definitions(&read(SOURCE))
+            .into_iter()
+            .map(|d| (d.name, d.formals.map(|f| f.len()), d.rest))
+            .collect();
+        let expected = [("twice", Some(1), false), ("sum", Some(0), true), ("answer", None, false)];
+        assert_eq!(names[..3].len(), expected.len());
+        for ((name, formals, rest), expected) in names.iter().zip(&expected) {
+            assert_eq!((name.as_str(), *formals, *rest), *expected);
+        }
+        assert_eq!(names[3].0, "f");
+    }
+
+    #[test]
+    fn positions() {
+        let source = "ab\n\"é😀\" c";
+
+        assert_eq!(offset(source, 1, 0), 3);
+        assert_eq!(offset(source, 1, 4), 10);
+        assert_eq!(offset(source, 1, 99), source.len());
+        assert_eq!(position(source, 10).to_string(), r#"{"line":1,"character":4}"#);
+
+        // A string over two lines is a token on each
+        let data = Json::from(tokens("(car \"é\nb\" x)")).to_string();
+        assert_eq!(data, "[0,1,3,3,0,0,4,2,4,0,1,0,2,4,0,0,3,1,2,0]");
+    }
+
+    #[test]
+    fn navigate() {
+        let server = server();
+        let uri = "file:///a.ss";
+        let at = |needle: &str, n: usize| SOURCE.match_indices(needle).nth(n).unwrap().0 + 1;
+
+        // A function, a variable and the loop of a named let
+        let twice = SOURCE.find("twice").unwrap();
+        assert_eq!(server.definition(uri, at("twice", 1)), Some((uri.into(), twice..twice + 5)));
+        let answer = SOURCE.find("answer").unwrap();
+        assert_eq!(server.definition(uri, at("answer", 1)), Some((uri.into(), answer..answer + 6)));
+        let lp = SOURCE.find("loop").unwrap();
+        assert_eq!(server.definition(uri, at("loop", 1)), Some((uri.into(), lp..lp + 4)));
+        let a = SOURCE.find("(f a)").unwrap() + 3;
+        assert_eq!(server.definition(uri, at("(i a)", 0) + 3), Some((uri.into(), a..a + 1)));
+
+        // Other open documents are looked into, but not for built in functions
+        let g = server.definition("file:///b.ss", 9).map(|(_, span)| span);
+        assert_eq!(g, Some(9..10));
+        assert_eq!(server.definition("file:///b.ss", 13), None);
+        assert_eq!(server.definition(uri, at("zero?", 0)), None);
+
+        // References to a local stay in its scope, the ones to a global don't
+        let refs = |offset| server.database.references_at(uri, offset).len();
+        assert_eq!(refs(at("(i a)", 0) + 1), 3);
+        assert_eq!(refs(at("twice", 1)), 3);
+
+        let hover = |offset| server.hover(uri, offset).unwrap().0;
+        assert_eq!(hover(at("twice", 2)), "```scheme\n(twice x)\n```\ntakes 1 argument");
+        assert_eq!(hover(at("sum", 0)), "```scheme\n(sum . rest)\n```\ntakes at least 0 arguments");
+        assert_eq!(hover(at("answer", 1)), "```scheme\nanswer\n```\nvariable");
+        assert_eq!(hover(at("i a", 0)), "```scheme\ni\n```\nlocal variable");
+        assert_eq!(hover(at("zero?", 0)), "```scheme\nzero?\n```\nbuilt in");
+        assert!(hover(at("guard", 0)).contains("(%guard e"));
+        assert_eq!(server.hover(uri, at("f answer", 0) - 1).map(|(_, span)| span.len()), Some(1));
+    }
+
+    #[test]
+    fn completion() {
+        let server = server();
+        let uri = "file:///a.ss";
+        let names = |offset| -> Vec<(String, Kind)> {
+            server.complete(uri, offset).into_iter().map(|c| (c.name, c.kind)).collect()
+        };
+
+        // Right after the `t` of `(twice "é")`, inside the named let
+        let offset = SOURCE.find("twice \"é").unwrap() + 1;
+        let found = names(offset);
+        assert_eq!(found[0], (String::from("twice"), Kind::Free));
+        assert!(found.iter().all(|(name, _)| name.starts_with('t')));
+
+        // Locals come first, and other documents are looked into
+        let offset = SOURCE.find("(loop (- i 1))").unwrap() + 2;
+        assert_eq!(names(offset)[0], (String::from("loop"), Kind::Bound));
+        assert!(server.complete("file:///b.ss", 13).iter().any(|c| c.name == "f"));
+        assert!(server.complete("file:///c.ss", 0).is_empty());
+    }
+
+    #[test]
+    fn checks() {
+        let errors = diagnostics("(define (f x) (g x))\n(car)");
+        let found: Vec<(String, &str)> = errors
+            .iter()
+            .map(|e| {
+                let start = e.get("range").unwrap().get("start").unwrap().to_string();
+                (start, e.get("message").and_then(Json::as_str).unwrap())
+            })
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                (r#"{"line":0,"character":15}"#.to_string(), "Undefined reference to `g`"),
+                (
+                    r#"{"line":1,"character":1}"#.to_string(),
+                    "Primitive `car` doesn't take 0 arguments"
+                ),
+            ]
+        );
+        assert!(diagnostics("(define (f x) (* x 2)) (f 21)").is_empty());
+    }
+
+    #[test]
+    fn session() {
+        let messages = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":
+                {"uri":"file:///a.ss","languageId":"scheme","version":1,"text":"(car 1"}}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":
+                {"uri":"file:///a.ss","version":2},"contentChanges":[{"text":"(define (f) 1)"}]}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/documentSymbol","params":
+                {"textDocument":{"uri":"file:///a.ss"}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/formatting","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ];
+
+        let mut input = vec![];
+        for message in &messages {
+            write_message(&mut input, &Json::parse(message).unwrap()).unwrap();
+        }
+
+        let mut output = vec![];
+        let status = Server::default().run(input.as_slice(), &mut output).unwrap();
+        assert_eq!(status, 0);
+
+        let mut output = output.as_slice();
+        let mut replies = vec![];
+        while let Some(reply) = read_message(&mut output).unwrap() {
+            replies.push(Json::parse(&reply).unwrap());
+        }
+        assert_eq!(replies.len(), 6);
+
+        let capabilities = replies[0].get("result").and_then(|r| r.get("capabilities")).unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+
+        let diagnostics = |reply: &Json| {
+            assert_eq!(method(reply), Some("textDocument/publishDiagnostics"));
+            reply.get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap().to_vec()
+        };
+        let errors = diagnostics(&replies[1]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get("code").and_then(Json::as_str), Some("E0001"));
+        let start = errors[0].get("range").unwrap().get("start").unwrap().to_string();
+        assert_eq!(start, r#"{"line":0,"character":6}"#);
+        assert!(diagnostics(&replies[2]).is_empty());
+
+        let symbols = replies[3].get("result").and_then(Json::as_array).unwrap();
+        assert_eq!(symbols[0].get("name").and_then(Json::as_str), Some("f"));
+        assert_eq!(symbols[0].get("kind"), Some(&Json::from(FUNCTION)));
+
+        let error = replies[4].get("error").and_then(|e| e.get("code"));
+        assert_eq!(error, Some(&Json::from(METHOD_NOT_FOUND)));
+        assert_eq!(replies[5].get("id"), Some(&Json::from(4)));
+    }
+}
@@ -1,65 +1,941 @@
 extern crate getopts;
 extern crate inc;
 
-use getopts::Options;
+use colored::Colorize;
+use getopts::{Matches, Options};
 use inc::{
-    cli::{run, Action::*},
-    core::Config,
+    cli::{self, Action, Action::*, Driver, Stage},
+    bench, dap,
+    core::{Config, Error, Target, Timings, Trace, Unit},
+    coverage::Coverage,
+    diagnostic::Diagnostic,
+    disasm, docgen, fmt, host,
+    lang::Passes,
+    jupyter, lsp,
+    profile::Profile,
+    project::Project,
+    refs::Database,
+    sourcemap::SourceMap,
+    testing,
 };
 use std::{
-    env,
+    env, fs,
     io::{self, Read},
-    process::exit,
+    path::Path,
+    process::{self, exit},
 };
 
+const NAMES: [&str; 16] = [
+    "build", "run", "repl", "check", "expand", "test", "bench", "disasm", "cov", "prof", "fmt",
+    "refs", "doc", "lsp", "dap", "jupyter",
+];
+
+const COMMANDS: &str = "
+Commands:
+    build       Build an executable, or only the asm with -S
+    run         Build and run a program, the default
+    repl        Evaluate expressions as they are typed
+    check       Report errors in a program without building it
+    expand      Print a program with derived syntax expanded
+    test        Run the Scheme tests in the files or directories, tests/ by default
+    bench       Build a program and time a few runs of it
+    disasm      Disassemble a built program, with its source and source map if given
+    cov report  Show the source of a program with the coverage written by a run
+    prof report List the functions of a program called the most in a profiled run
+    fmt         Format the files in place, or stdin to stdout
+    refs        List where a name is defined and used in the files
+    doc         Print the doc comments of the definitions in the files
+    lsp         Serve an editor with the Language Server Protocol on stdio
+    dap         Debug programs for an editor with the Debug Adapter Protocol on stdio
+    jupyter     Run a Jupyter kernel with a connection file, or print its spec
+
+The program is read from FILE, or from stdin without one. build and run take
+several files, the last one being the program and the rest compiled separately
+and linked with it. With --watch, build, run and check start over every time
+one of the files changes.
+
+In a directory with an inc.toml, build and run without any files build the
+project it declares, with its files and options.
+
+The optional passes are fold, anf and tco; -O0 runs none, -O1 only tco and
+-O2 all of them. run --interp evaluates the program without compiling it.
+
+fmt --check changes nothing, and fails listing the files that aren't
+formatted.
+
+doc prints markdown, or a page of HTML with --html, with a section for every
+library and the top level of every file. Definitions are documented by the
+comments starting with ;;; or ;;> right above them.
+
+build --coverage counts how many times every expression of the program is
+evaluated. The program writes the counts out as it exits, to FILE.cov next to
+it or to $INC_COVERAGE, and cov report takes them along with the source.
+build --profile counts the calls to every function instead, written to
+FILE.prof or $INC_PROFILE for prof report.
+
+bench runs the program 10 times unless --runs says otherwise, and --compare
+builds it a second time with other optimization flags, like --compare=-O0.
+
+The stages for --emit are tokens, tokens-semantic, ast, renamed, lifted, ir,
+asm, header, map, obj and bin; obj and bin are written to -o. header declares
+the procedures exported by libraries for C, which calls them in the obj. map
+is the source map of the asm as JSON, which disasm takes as a .json file.
+
+A FILE given first, before any command or option, is built and run as a
+script with the rest of the arguments, which it gets from (command-line).
+The first line is skipped if it starts with #!, so that a file starting with
+#!/usr/bin/env inc can be run by itself.";
+
+/// How errors are reported, see [Diagnostic]
+#[derive(Clone, Copy)]
+enum Format {
+    Human { color: bool },
+    Json,
+}
+
+/// Everything runs on a stack big enough for programs nested deeply
 fn main() {
+    host::deep(start)
+}
+
+fn start() {
     let args: Vec<String> = env::args().collect();
     let bin = args[0].clone();
 
+    // A script takes any arguments of its own, which aren't options for inc
+    if let Some(path) = args.get(1) {
+        if !path.starts_with('-') && !NAMES.contains(&path.as_str()) {
+            exit(self::script(path, &args[2..]))
+        }
+    }
+
     let mut opts = Options::new();
     opts.optopt("o", "", "Output file name", "FILE");
     opts.optflag("S", "", "Print generated asm");
     opts.optflag("p", "", "Print parse tree");
+    opts.optflag("", "jit", "Run in memory without building an executable");
+    opts.optflag("", "interp", "Evaluate the program with the interpreter");
+    opts.optflag("", "watch", "Build again every time a file of the program changes");
+    opts.optflag("", "no-prelude", "Compile the program without the prelude");
+    opts.optflag("", "no-cache", "Compile every file, even if it didn't change");
+    opts.optmulti("", "load", "Load a file before the program or session", "FILE");
+    opts.optopt("", "emit", "Stop after a stage and print it", "STAGE");
+    opts.optflagopt("", "trace-passes", "Show the program after every pass", "DIR");
+    opts.optflagopt("", "time-passes", "Report the time taken by every pass", "table|json");
+    opts.optflag("v", "verbose", "Report the size of the program and the memory compiling took");
+    opts.optflag("", "coverage", "Count the expressions a built program evaluates");
+    opts.optflag("", "profile", "Count the calls to the functions of a built program");
+    opts.optopt("", "target", "Platform to compile for, the host by default", "TRIPLE");
+    opts.optopt("O", "", "Optimization level, 2 by default", "0|1|2");
+    opts.optopt("", "runs", "Times to run a benchmark", "N");
+    opts.optopt("", "compare", "Benchmark again with other optimization flags", "FLAGS");
+    opts.optflag("", "check", "Report files that aren't formatted instead of formatting them");
+    opts.optflag("", "html", "Print docs as HTML instead of markdown");
+    opts.optmulti("", "enable-pass", "Run an optional pass, see below", "PASS");
+    opts.optmulti("", "disable-pass", "Skip an optional pass", "PASS");
+    opts.optopt("", "color", "Color the output, auto by default", "auto|always|never");
+    opts.optopt("", "message-format", "Format of errors, human by default", "human|json");
     opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => usage(&opts, &bin, &f.to_string()),
     };
 
     let help = matches.opt_present("h");
     let parse = matches.opt_present("p");
     let asm = matches.opt_present("S");
+    let jit = matches.opt_present("jit");
+    let interp = matches.opt_present("interp");
+    let watch = matches.opt_present("watch");
+
+    let color = match matches.opt_str("color").as_deref() {
+        None | Some("auto") => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+        Some("always") => true,
+        Some("never") => false,
+        Some(other) => usage(&opts, &bin, &format!("Unknown color choice `{}`", other)),
+    };
+    colored::control::set_override(color);
+
+    let format = match matches.opt_str("message-format").as_deref() {
+        None | Some("human") => Format::Human { color },
+        Some("json") => Format::Json,
+        Some(other) => usage(&opts, &bin, &format!("Unknown message format `{}`", other)),
+    };
 
     if help {
-        print!("{}", opts.usage(&format!("Usage: {} [options]", bin)));
+        print!("{}", opts.usage(&brief(&bin)));
+        return;
+    }
+
+    // Without a command, the options alone pick what to do
+    let (command, files) = match matches.free.split_first() {
+        Some((command, files)) if NAMES.contains(&command.as_str()) => (command.as_str(), files),
+        _ => ("run", &matches.free[..]),
+    };
+
+    if command == "lsp" || command == "dap" {
+        if !files.is_empty() {
+            usage(&opts, &bin, &format!("{} doesn't take any files", command))
+        }
+        exit(if command == "lsp" { lsp::serve() } else { dap::serve() })
+    }
+
+    if command == "fmt" {
+        exit(self::fmt(files, matches.opt_present("check")))
+    }
+
+    if command == "refs" {
+        match files.split_first() {
+            Some((name, files)) if !files.is_empty() => exit(self::refs(name, files)),
+            _ => usage(&opts, &bin, "refs takes a name and the files to look in"),
+        }
+    }
+
+    if command == "cov" {
+        match files {
+            [report, counts, source] if report == "report" => exit(self::cov(counts, source)),
+            _ => usage(&opts, &bin, "cov report takes the coverage of a run and its source"),
+        }
+    }
+
+    if command == "prof" {
+        match files {
+            [report, counts, source] if report == "report" => exit(self::prof(counts, source)),
+            _ => usage(&opts, &bin, "prof report takes the profile of a run and its source"),
+        }
+    }
+
+    if matches.opt_present("check") {
+        usage(&opts, &bin, "--check works only with fmt")
+    }
+
+    if command == "doc" {
+        exit(self::doc(files, matches.opt_present("html")))
+    }
+
+    if matches.opt_present("html") {
+        usage(&opts, &bin, "--html works only with doc")
+    }
+
+    if command == "jupyter" {
+        match files {
+            [] => println!("{}", jupyter::spec()),
+            [file] => exit(jupyter::serve(file)),
+            _ => usage(&opts, &bin, "jupyter takes a single connection file"),
+        }
         return;
     }
 
-    let output = matches
-        .opt_str("o")
-        .unwrap_or_else(|| String::from(if asm { "/dev/stdout" } else { "inc" }));
+    // A project is built when build or run aren't given any files
+    let project = match command {
+        "build" | "run" if matches.free.len() == 1 => {
+            Project::find(Path::new("")).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1)
+            })
+        }
+        _ => None,
+    };
+    let files = project.as_ref().map_or(files, |project| &project.files[..]);
+
+    let batch = command == "test" || command == "bench" || command == "disasm";
+
+    if batch && (watch || parse || asm || jit || interp || matches.opt_present("emit")) {
+        let e = format!("{} doesn't take --watch, --emit, --jit, --interp, -S or -p", command);
+        usage(&opts, &bin, &e)
+    }
+
+    let coverage = matches.opt_present("coverage");
 
-    let mut program = String::new();
-    io::stdin().read_to_string(&mut program).expect("Expected a program in stdin");
+    if coverage && (jit || interp || !(command == "build" || command == "run")) {
+        usage(&opts, &bin, "--coverage works only with build and run, without --jit or --interp")
+    }
 
-    let config = Config { program, output };
+    let profile = matches.opt_present("profile");
 
-    let action = if parse {
-        Parse
-    } else if asm {
-        GenASM
+    if profile && (jit || interp || !(command == "build" || command == "run")) {
+        usage(&opts, &bin, "--profile works only with build and run, without --jit or --interp")
+    }
+
+    if command != "bench" && (matches.opt_present("runs") || matches.opt_present("compare")) {
+        usage(&opts, &bin, "--runs and --compare work only with bench")
+    }
+
+    if command == "disasm" {
+        // The source map is told apart from the source by its extension
+        let (maps, sources): (Vec<&String>, Vec<&String>) =
+            files.iter().skip(1).partition(|file| file.ends_with(".json"));
+
+        match (files.first(), &sources[..], &maps[..]) {
+            (Some(path), [], []) => exit(self::disasm(path, None, None)),
+            (Some(path), [source], []) => exit(self::disasm(path, Some(source), None)),
+            (Some(path), [], [map]) => exit(self::disasm(path, None, Some(map))),
+            (Some(path), [source], [map]) => exit(self::disasm(path, Some(source), Some(map))),
+            _ => usage(
+                &opts,
+                &bin,
+                "disasm takes a built program and optionally its source and a source map",
+            ),
+        }
+    }
+
+    let emit = matches.opt_str("emit").map(|stage| {
+        Stage::parse(&stage).unwrap_or_else(|e| usage(&opts, &bin, &e))
+    });
+
+    let action = match command {
+        "repl" if emit.is_some() => usage(&opts, &bin, "repl doesn't emit stages"),
+        _ if emit.is_some() => Emit(emit.unwrap()),
+        _ if parse => Parse,
+        "build" | "run" if asm => GenASM,
+        "build" => Build,
+        "run" if jit => Jit,
+        "run" if interp => Interp,
+        "run" => Run,
+        "repl" => Repl,
+        "check" => Check,
+        "expand" => Expand,
+        // Each test file is built and run, see [test]
+        "test" => Run,
+        "bench" => Build,
+        _ => unreachable!(),
+    };
+
+    match action {
+        Build | Run | Jit | Interp | Check if watch && files.is_empty() => {
+            usage(&opts, &bin, "--watch needs a file to watch")
+        }
+        Build | Run | Jit | Interp | Check => {}
+        _ if watch => usage(&opts, &bin, "--watch works only with build, run and check"),
+        _ => {}
+    }
+
+    let (program, units) = match (action, files) {
+        _ if command == "test" => (String::new(), vec![]),
+        (Repl, []) => (String::new(), vec![]),
+        (_, []) => {
+            let mut program = String::new();
+            io::stdin().read_to_string(&mut program).expect("Expected a program in stdin");
+            (program, vec![])
+        }
+        (Repl, _) => usage(&opts, &bin, "repl doesn't take a file"),
+        (_, [_]) | (Build | Run, _) => sources(files).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        }),
+        _ => usage(&opts, &bin, "Expected a single file"),
+    };
+
+    let mut names: Vec<&str> = units.iter().map(|unit| unit.name.as_str()).collect();
+    names.sort_unstable();
+    if names.windows(2).any(|pair| pair[0] == pair[1]) {
+        usage(&opts, &bin, "Files must have different names")
+    }
+
+    let output = matches.opt_str("o").or_else(|| project.as_ref()?.output.clone());
+    let output = output.unwrap_or_else(|| {
+        String::from(match emit {
+            Some(Stage::Obj) => "inc.o",
+            Some(Stage::Bin) => "inc",
+            Some(_) => "/dev/stdout",
+            None if asm => "/dev/stdout",
+            None => "inc",
+        })
+    });
+
+    // Test and benchmark executables are thrown away after they run
+    let output = match matches.opt_str("o") {
+        None if batch => {
+            let name = format!("inc-{}-{}", command, process::id());
+            env::temp_dir().join(name).display().to_string()
+        }
+        _ => output,
+    };
+
+    let base = project.as_ref().and_then(|project| project.passes).unwrap_or_default();
+    let passes = passes(&matches, base).unwrap_or_else(|e| usage(&opts, &bin, &e));
+
+    // Files loaded by the project come before the ones on the command line
+    let mut load = project.as_ref().map_or(vec![], |project| project.load.clone());
+    load.extend(matches.opt_strs("load"));
+
+    let prelude = !matches.opt_present("no-prelude")
+        && project.as_ref().and_then(|project| project.prelude).unwrap_or(true);
+    let cache = !matches.opt_present("no-cache");
+    let trace = if matches.opt_present("trace-passes") {
+        Some(matches.opt_str("trace-passes").map_or(Trace::Stdout, Trace::Dir))
     } else {
-        Run
+        None
+    };
+
+    let timings = match matches.opt_str("time-passes").as_deref() {
+        _ if !matches.opt_present("time-passes") => None,
+        None | Some("table") => Some(Timings::Table),
+        Some("json") => Some(Timings::Json),
+        Some(other) => usage(&opts, &bin, &format!("Unknown report format `{}`", other)),
+    };
+
+    let target = match matches.opt_str("target") {
+        Some(triple) => Target::parse(&triple).unwrap_or_else(|e| usage(&opts, &bin, &e)),
+        None => {
+            let target = project.as_ref().and_then(|project| project.target.clone());
+            target.unwrap_or_else(Target::host)
+        }
     };
 
-    // Run the entire CLI with config
-    match run(&config, action) {
+    let config = Config {
+        program,
+        output,
+        passes,
+        units,
+        load,
+        prelude,
+        cache,
+        trace,
+        timings,
+        verbose: matches.opt_present("verbose"),
+        coverage,
+        profile,
+        target,
+    };
+
+    if command == "test" {
+        exit(test(&config, files))
+    }
+
+    if command == "bench" {
+        let runs = match matches.opt_str("runs").map(|n| n.parse()) {
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => usage(&opts, &bin, "--runs expects a positive number"),
+            None => 10,
+        };
+
+        // The flags to compare with are parsed like the ones for the compiler
+        let compare = matches.opt_str("compare").map(|flags| {
+            opts.parse(flags.split_whitespace())
+                .map_err(|e| e.to_string())
+                .and_then(|m| self::passes(&m, Passes::default()))
+                .unwrap_or_else(|e| usage(&opts, &bin, &format!("Invalid --compare: {}", e)))
+        });
+
+        exit(self::bench(&config, runs, compare, files, format))
+    }
+
+    if watch {
+        self::watch(config, files, action, format)
+    }
+
+    // Run the entire CLI with config, exiting like a program that failed
+    let result = Driver::new(&config).run(action);
+    let status = match &result {
+        Err(Error::Exit { status, .. }) => *status,
+        _ => 1,
+    };
+
+    if !report(result, &config, files, format) {
+        exit(status)
+    }
+}
+
+/// Print the result of an action, and whether it succeeded
+///
+/// Errors are shown as [Diagnostic]s pointing into the files they came from.
+fn report(
+    result: Result<Option<String>, Error>,
+    config: &Config,
+    files: &[String],
+    format: Format,
+) -> bool {
+    match result {
         Err(e) => {
-            println!("{}", e);
-            exit(1)
+            let sources: Vec<(&str, &str)> = match files.split_last() {
+                Some((file, units)) => units
+                    .iter()
+                    .zip(&config.units)
+                    .map(|(name, unit)| (name.as_str(), unit.program.as_str()))
+                    .chain(Some((file.as_str(), config.program.as_str())))
+                    .collect(),
+                None => vec![("<stdin>", config.program.as_str())],
+            };
+
+            let diagnostics = Diagnostic::all(&e, &sources);
+
+            for diagnostic in &diagnostics {
+                let source = sources
+                    .iter()
+                    .find(|(name, _)| Some(*name) == diagnostic.file.as_deref())
+                    .map(|(_, source)| *source);
+
+                match format {
+                    Format::Human { color } => println!("{}", diagnostic.render(source, color)),
+                    Format::Json => println!("{}", diagnostic.json(source)),
+                }
+            }
+
+            match format {
+                Format::Human { .. } if diagnostics.len() > 1 => {
+                    println!("{}", format!("{} errors", diagnostics.len()).red().bold())
+                }
+                _ => {}
+            }
+
+            false
+        }
+        Ok(Some(out)) => {
+            println!("{}", out);
+            true
+        }
+        Ok(None) => true,
+    }
+}
+
+/// Build and run a script with some arguments, and return its exit code
+fn script(path: &str, args: &[String]) -> i32 {
+    let color = unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 };
+    colored::control::set_override(color);
+
+    let files = [path.to_string()];
+    let (program, units) = match sources(&files) {
+        Ok(sources) => sources,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let name = format!("inc-script-{}", process::id());
+    let output = env::temp_dir().join(name).display().to_string();
+    let config = Config { program, output, units, ..Config::default() };
+
+    match Driver::new(&config).script(path, args) {
+        Ok(code) => code,
+        Err(e) => {
+            report(Err(e), &config, &files, Format::Human { color });
+            1
+        }
+    }
+}
+
+/// Print the disassembly of a built program, and return the exit code
+fn disasm(path: &str, source: Option<&str>, map: Option<&str>) -> i32 {
+    let source = match source.map(fs::read_to_string).transpose() {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to read the source: {}", e);
+            return 1;
+        }
+    };
+
+    let map = match map.map(|path| {
+        fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|map| SourceMap::parse(&map))
+    }) {
+        Some(Ok(map)) => Some(map),
+        Some(Err(e)) => {
+            eprintln!("Failed to read the source map: {}", e);
+            return 1;
+        }
+        None => None,
+    };
+
+    match disasm::disassemble(path, source.as_deref(), map.as_ref()) {
+        Ok(out) => {
+            println!("{}", out);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Print the source of a program annotated with the coverage written by a
+/// run, and return the exit code
+fn cov(counts: &str, source: &str) -> i32 {
+    let coverage = fs::read_to_string(counts)
+        .map_err(|e| e.to_string())
+        .and_then(|counts| Coverage::parse(&counts));
+
+    let coverage = match coverage {
+        Ok(coverage) => coverage,
+        Err(e) => {
+            eprintln!("Failed to read the coverage in {}: {}", counts, e);
+            return 1;
+        }
+    };
+
+    match fs::read_to_string(source) {
+        Ok(source) => {
+            println!("{}", coverage.report(&source));
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", source, e);
+            1
+        }
+    }
+}
+
+/// Print the functions of a program called the most in a profiled run, and
+/// return the exit code
+fn prof(counts: &str, source: &str) -> i32 {
+    let profile = fs::read_to_string(counts)
+        .map_err(|e| e.to_string())
+        .and_then(|counts| Profile::parse(&counts));
+
+    let profile = match profile {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("Failed to read the profile in {}: {}", counts, e);
+            return 1;
+        }
+    };
+
+    match fs::read_to_string(source) {
+        Ok(source) => {
+            println!("{}", profile.report(&source));
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", source, e);
+            1
+        }
+    }
+}
+
+/// Format the files in place, or stdin to stdout, and return the exit code
+///
+/// With `check`, nothing is written and the files that would change are listed
+/// instead, failing the run if there are any.
+fn fmt(files: &[String], check: bool) -> i32 {
+    if files.is_empty() {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).expect("Expected a program in stdin");
+
+        return match fmt::format(&source) {
+            Ok(formatted) if check => i32::from(formatted != source),
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(e) => {
+                eprintln!("<stdin>: {}", e);
+                1
+            }
+        };
+    }
+
+    let mut status = 0;
+
+    for file in files {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                status = 1;
+                continue;
+            }
+        };
+
+        match fmt::format(&source) {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                println!("{}", file);
+                status = 1;
+            }
+            Ok(formatted) => {
+                if let Err(e) = fs::write(file, formatted) {
+                    eprintln!("Failed to write {}: {}", file, e);
+                    status = 1;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                status = 1;
+            }
+        }
+    }
+
+    status
+}
+
+/// Print every place a global name is defined or used in the files, and
+/// return the exit code
+///
+/// Each reference is a line like `file:line:column: text`, as grep would print
+/// it. Finding nothing fails like grep does too.
+fn refs(name: &str, files: &[String]) -> i32 {
+    let mut db = Database::default();
+
+    for file in files {
+        match fs::read_to_string(file) {
+            Ok(source) => db.insert(file, &source),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                return 1;
+            }
+        }
+    }
+
+    let references = db.references_to(name);
+
+    for reference in &references {
+        let source = db.source(&reference.file).unwrap_or_default();
+        let before = &source[..reference.span.start];
+        let start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line = source[start..].lines().next().unwrap_or_default();
+
+        println!(
+            "{}:{}:{}: {}",
+            reference.file,
+            before.matches('\n').count() + 1,
+            before[start..].chars().count() + 1,
+            line.trim()
+        );
+    }
+
+    if references.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Print the docs of the files, or of stdin, and return the exit code
+fn doc(files: &[String], html: bool) -> i32 {
+    let mut libraries = vec![];
+
+    let sources = if files.is_empty() {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).expect("Expected a program in stdin");
+        vec![(String::from("<stdin>"), Ok(source))]
+    } else {
+        files.iter().map(|file| (file.clone(), fs::read_to_string(file))).collect()
+    };
+
+    for (file, source) in sources {
+        let source = match source {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                return 1;
+            }
+        };
+
+        match docgen::extract(&file, &source) {
+            Ok(found) => libraries.extend(found),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                return 1;
+            }
         }
-        Ok(Some(out)) => println!("{}", out),
-        Ok(None) => {}
     }
+
+    if html {
+        println!("{}", docgen::html(&libraries));
+    } else {
+        println!("{}", docgen::markdown(&libraries));
+    }
+
+    0
+}
+
+/// Run the tests in the files and directories, and return the exit code
+///
+/// Each file is reported as it finishes, followed by a summary of all of them.
+/// A file that fails to compile or crashes is broken, which fails the run like
+/// a failed test.
+fn test(config: &Config, paths: &[String]) -> i32 {
+    let default = [String::from("tests")];
+    let paths = if paths.is_empty() { &default[..] } else { paths };
+
+    let files = match testing::files(paths) {
+        Ok(files) if files.is_empty() => {
+            eprintln!("No tests found in {}", paths.join(", "));
+            return 1;
+        }
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Failed to find tests: {}", e);
+            return 1;
+        }
+    };
+
+    let (mut passed, mut failed, mut broken) = (0, 0, 0);
+
+    for (n, file) in files.iter().enumerate() {
+        let report = testing::run(config, file, n);
+        let status = if report.ok() { "ok".green() } else { "FAILED".red() };
+
+        println!(
+            "{} {} ({} passed, {} failed)",
+            status,
+            file,
+            report.passed.len(),
+            report.failed.len()
+        );
+
+        for failure in &report.failed {
+            println!("    {} {}", "fail".red(), failure);
+        }
+
+        if let Some(e) = &report.error {
+            for line in e.trim().lines() {
+                println!("    {}", line);
+            }
+            broken += 1;
+        }
+
+        passed += report.passed.len();
+        failed += report.failed.len();
+    }
+
+    let summary =
+        format!("{} passed, {} failed, {} of {} files broken", passed, failed, broken, files.len());
+
+    if failed == 0 && broken == 0 {
+        println!("\n{}", summary.green().bold());
+        0
+    } else {
+        println!("\n{}", summary.red().bold());
+        1
+    }
+}
+
+/// Benchmark the program, and another build of it with other passes
+fn bench(
+    config: &Config,
+    runs: usize,
+    compare: Option<Passes>,
+    files: &[String],
+    format: Format,
+) -> i32 {
+    let other = compare.map(|passes| Config {
+        program: config.program.clone(),
+        output: format!("{}-compare", config.output),
+        passes,
+        units: config.units.clone(),
+        load: config.load.clone(),
+        prelude: config.prelude,
+        cache: config.cache,
+        trace: None,
+        timings: None,
+        verbose: false,
+        coverage: config.coverage,
+        profile: config.profile,
+        target: config.target.clone(),
+    });
+
+    let mut summaries = vec![];
+
+    for config in Some(config).into_iter().chain(other.as_ref()) {
+        match bench::run(config, runs) {
+            Ok(summary) => summaries.push((config.passes, summary)),
+            Err(e) => {
+                report(Err(e), config, files, format);
+                return 1;
+            }
+        }
+    }
+
+    let width = summaries.iter().map(|(passes, _)| passes.to_string().len()).max().unwrap_or(0);
+    let ms = |d: std::time::Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+
+    for (passes, s) in &summaries {
+        println!(
+            "{:width$}  min {}  median {}  stddev {}  {} bytes  {} collections",
+            passes.to_string(),
+            ms(s.min),
+            ms(s.median),
+            ms(s.stddev),
+            s.allocated,
+            s.collections,
+            width = width
+        );
+    }
+
+    if let [(a, x), (b, y)] = &summaries[..] {
+        let ratio = y.median.as_secs_f64() / x.median.as_secs_f64();
+        println!("\n{} is {:.2}x as fast as {} by the median", a, ratio, b);
+    }
+
+    0
+}
+
+/// Run an action again every time one of the files it reads changes
+///
+/// The files are read again for each run, along with the ones they load or
+/// include. Errors are reported and the watch goes on.
+fn watch(mut config: Config, files: &[String], action: Action, format: Format) -> ! {
+    loop {
+        let mut watched = files.to_vec();
+
+        match sources(files) {
+            Ok((program, units)) => {
+                config.program = program;
+                config.units = units;
+                watched.extend(Driver::new(&config).dependencies());
+
+                report(Driver::new(&config).run(action), &config, files, format);
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        eprintln!("{}", "Watching for changes…".dimmed());
+        cli::wait(&watched);
+        eprintln!("{}", "Change detected, building again".dimmed());
+    }
+}
+
+fn brief(bin: &str) -> String {
+    format!(
+        "Usage: {0} [command] [options] [FILE...]\n       {0} FILE [ARG...]\n{1}",
+        bin, COMMANDS
+    )
+}
+
+/// The optimization passes picked with -O, --enable-pass and --disable-pass
+///
+/// Without a level, the individual passes refine `base` instead.
+fn passes(matches: &Matches, base: Passes) -> Result<Passes, String> {
+    let mut passes = match matches.opt_str("O") {
+        Some(level) => Passes::level(&level)?,
+        None => base,
+    };
+
+    // Individual passes refine the level
+    for (option, enabled) in &[("enable-pass", true), ("disable-pass", false)] {
+        for pass in matches.opt_strs(option) {
+            passes.set(&pass, *enabled)?;
+        }
+    }
+
+    Ok(passes)
+}
+
+/// Read the program in the last file, and the units in the rest
+fn sources(files: &[String]) -> Result<(String, Vec<Unit>), String> {
+    let read = |file: &String| {
+        fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {}", file, e))
+    };
+
+    match files.split_last() {
+        Some((file, units)) => {
+            let units = units
+                .iter()
+                .map(|unit| read(unit).map(|program| cli::unit(unit, program)))
+                .collect::<Result<_, _>>()?;
+
+            Ok((read(file)?, units))
+        }
+        None => Ok((String::new(), vec![])),
+    }
+}
+
+/// Report a mistake in the command line and exit
+fn usage(opts: &Options, bin: &str, message: &str) -> ! {
+    eprintln!("{}\n", message);
+    eprint!("{}", opts.usage(&brief(bin)));
+    exit(2)
 }
@@ -0,0 +1,151 @@
+//! How much work the compiler did for a program, and the memory it took
+//!
+//! `--verbose` and `--time-passes` report these after compiling, so that a
+//! change making the compiler slower or hungrier shows up without reaching for
+//! an external profiler:
+//!
+//! ```text
+//! nodes              1532
+//! instructions       9044
+//! tree-bytes        98048
+//! strings               3
+//! symbols               7
+//! peak-rss       14680064
+//! ```
+//!
+//! The size of the program is gathered from every [Pass] as it runs. Nodes of
+//! the syntax tree aren't allocated from an arena of their own, so the bytes
+//! of the tree are estimated from the size of a node. The strings and symbols
+//! are the ones the compiler numbers for the data section, and the peak memory
+//! is whatever the system reports for the whole process.
+use crate::{core::Core, lang::Pass};
+use std::{cell::Cell, fmt, mem};
+
+/// Measurements of a compilation, see the [module](self) docs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    /// Nodes of the largest program between two passes
+    pub nodes: usize,
+    /// Instructions of the generated code
+    pub instructions: usize,
+    /// Estimated bytes of the largest program between two passes
+    pub tree: usize,
+    /// Distinct strings in the data section
+    pub strings: usize,
+    /// Distinct symbols in the data section
+    pub symbols: usize,
+    /// Most memory the process ever had resident, in bytes
+    pub rss: Option<usize>,
+}
+
+thread_local! {
+    /// Strings and symbols of the last program compiled on this thread
+    static INTERNED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+impl Metrics {
+    /// Account for the program a pass produced
+    pub fn pass(&mut self, pass: &Pass) {
+        match pass.name {
+            "codegen" => self.instructions = pass.program.nodes(),
+            _ => {
+                self.nodes = self.nodes.max(pass.program.nodes());
+                self.tree = self.nodes * mem::size_of::<Core>();
+            }
+        }
+    }
+
+    /// Done compiling, read what the compiler and the system kept track of
+    pub fn finish(self) -> Self {
+        let (strings, symbols) = INTERNED.with(Cell::get);
+        Metrics { strings, symbols, rss: rss(), ..self }
+    }
+
+    /// The metrics as a JSON object
+    pub fn json(&self) -> String {
+        format!(
+            r#"{{"nodes":{},"instructions":{},"tree":{},"strings":{},"symbols":{},"rss":{}}}"#,
+            self.nodes,
+            self.instructions,
+            self.tree,
+            self.strings,
+            self.symbols,
+            self.rss.map_or(String::from("null"), |rss| rss.to_string())
+        )
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:12}  {:>10}", "nodes", self.nodes)?;
+        writeln!(f, "{:12}  {:>10}", "instructions", self.instructions)?;
+        writeln!(f, "{:12}  {:>10}", "tree-bytes", self.tree)?;
+        writeln!(f, "{:12}  {:>10}", "strings", self.strings)?;
+        write!(f, "{:12}  {:>10}", "symbols", self.symbols)?;
+
+        match self.rss {
+            Some(rss) => write!(f, "\n{:12}  {:>10}", "peak-rss", rss),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Record the strings and symbols numbered for a program, see [Metrics::finish]
+pub fn interned(strings: usize, symbols: usize) {
+    INTERNED.with(|interned| interned.set((strings, symbols)))
+}
+
+/// Peak resident memory of the process in bytes, if the system says
+#[cfg(feature = "native")]
+pub fn rss() -> Option<usize> {
+    let mut usage = mem::MaybeUninit::<libc::rusage>::uninit();
+
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+
+    let max = unsafe { usage.assume_init() }.ru_maxrss as usize;
+
+    // Linux counts in kilobytes and macOS in bytes
+    if cfg!(target_os = "macos") {
+        Some(max)
+    } else {
+        Some(max * 1024)
+    }
+}
+
+/// Peak resident memory of the process in bytes, if the system says
+#[cfg(not(feature = "native"))]
+pub const fn rss() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::emit, parser};
+
+    #[test]
+    fn compile() {
+        let prog = parser::parse(r#"(define (f x) (cons x 'a)) (f "s") (f "s") (f 'b)"#).unwrap();
+        let mut metrics = Metrics::default();
+        let asm = emit::traced(prog, Default::default(), &mut |pass| metrics.pass(pass)).unwrap();
+        let metrics = metrics.finish();
+
+        assert_eq!(metrics.instructions, asm.0.len());
+        assert!(metrics.nodes >= 15);
+        assert_eq!(metrics.tree, metrics.nodes * mem::size_of::<Core>());
+        assert_eq!((metrics.strings, metrics.symbols), (1, 2));
+
+        #[cfg(feature = "native")]
+        assert!(metrics.rss.unwrap() > 0);
+
+        let json = metrics.json();
+        assert!(json.starts_with(&format!(r#"{{"nodes":{},"#, metrics.nodes)));
+        assert!(json.contains(r#""strings":1,"symbols":2,"rss":"#));
+
+        let table = metrics.to_string();
+        let rows: Vec<&str> = table.lines().map(|l| l.split_whitespace().next().unwrap()).collect();
+        assert!(rows.starts_with(&["nodes", "instructions", "tree-bytes", "strings", "symbols"]));
+    }
+}
@@ -0,0 +1,242 @@
+//! The numeric tower
+//!
+//! Numbers are either exact integers, fixnums or [bignums](crate::bignum), or
+//! inexact flonums, which are IEEE 754 doubles boxed on the heap. Generated
+//! code handles fixnums inline and falls back to the runtime functions here
+//! for everything else. An exact operand is converted to the nearest double
+//! when the other operand is inexact and the result is inexact as well, so
+//! `(+ 1 2.5)` is `3.5`. `/` is true division for flonums but truncates
+//! integers, and `exact->inexact` and `inexact->exact` convert between the two.
+//!
+//! A flonum is a string object as far as the collector is concerned, with the
+//! [FLONUM] bit set in the length prefix followed by the bits of the double.
+//!
+//! ```txt
+//!  ----------------------------
+//! | FLONUM or 8 | 0x3ff8000... |  => 1.5
+//!  ----------------------------
+//! ```
+//!
+//! Runtime functions can't trigger a collection, see
+//! [allocate](crate::rt::allocate), so generated code first asks for the size
+//! of the result with [rt_arithmetic_size] and makes room for it before
+//! calling [rt_arithmetic].
+use crate::{
+    bignum::{self, Big},
+    compiler::state::State,
+    exceptions::Status,
+    gc,
+    immediate::*,
+    primitives,
+    rt::{self, Object},
+    tags::tag,
+    x86::{self, Reference, Register::*, ASM, WORDSIZE},
+};
+
+/// Marks the length of a string object that holds a flonum
+pub const FLONUM: i64 = 1 << 60;
+
+/// Bits of the length prefix that mark a string object as a number
+pub const BOXED: i64 = bignum::BIGNUM | FLONUM;
+
+/// Bytes taken by a flonum, the prefix, the double and a null byte
+const SIZE: usize = 3 * WORDSIZE as usize;
+
+/// A number of any kind
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Exact(Big),
+    Inexact(f64),
+}
+
+use Number::*;
+
+impl Number {
+    /// The value of a number object, `None` for anything else
+    pub fn decode(val: Object) -> Option<Self> {
+        if is(val.0) {
+            Some(Inexact(f64::from_bits(unsafe { *((val.0 - STR + WORDSIZE) as *const u64) })))
+        } else {
+            Big::decode(val).map(Exact)
+        }
+    }
+
+    /// A new number object, see [Big::encode] for exact numbers
+    ///
+    /// There must be room for [Number::size] bytes at the heap pointer.
+    pub fn encode(&self) -> Object {
+        match self {
+            Exact(n) => n.encode(),
+            Inexact(f) => {
+                let r12 = rt::heap();
+                rt::allocate(SIZE);
+
+                unsafe {
+                    std::ptr::write(r12 as *mut i64, WORDSIZE | FLONUM);
+                    std::ptr::write((r12 + 8) as *mut u64, f.to_bits());
+                    std::ptr::write((r12 + 16) as *mut i64, 0);
+                }
+
+                Object::new(r12 as i64 | STR)
+            }
+        }
+    }
+
+    /// Bytes taken by the heap object of the number, including the prefix
+    pub fn size(&self) -> usize {
+        match self {
+            Exact(n) => n.size(),
+            Inexact(_) => SIZE,
+        }
+    }
+
+    /// The number as the nearest double
+    pub fn inexact(&self) -> f64 {
+        match self {
+            Exact(n) => n.to_f64(),
+            Inexact(f) => *f,
+        }
+    }
+}
+
+/// Is the object a flonum?
+pub const fn is(val: i64) -> bool {
+    tag(val) == STR && unsafe { *((val - STR) as *const i64) } & BOXED == FLONUM
+}
+
+/// Is the object a bignum or a flonum?
+pub const fn boxed(val: i64) -> bool {
+    tag(val) == STR && unsafe { *((val - STR) as *const i64) } & BOXED != 0
+}
+
+/// Scheme representation of a double, like `2.0`, `-inf.0` or `+nan.0`
+pub fn format(f: f64) -> String {
+    if f.is_nan() {
+        String::from("+nan.0")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "+inf.0" } else { "-inf.0" })
+    } else if f.fract() == 0.0 {
+        format!("{}.0", f)
+    } else {
+        format!("{}", f)
+    }
+}
+
+/// Allocate a flonum on the heap and move the reference to RAX
+pub fn eval(s: &mut State, f: f64) -> ASM {
+    gc::alloc(s, Reference::Const(SIZE as i64))
+        + x86::mov(R11.into(), (WORDSIZE | FLONUM).into())
+        + x86::mov(Reference::from(R12 + 0), R11.into())
+        + x86::mov(R11.into(), (f.to_bits() as i64).into())
+        + x86::mov(Reference::from(R12 + 8), R11.into())
+        + x86::mov(Reference::from(R12 + 16), 0.into())
+        + x86::mov(RAX.into(), R12.into())
+        + x86::add(R12.into(), Reference::from(SIZE as i64))
+        + x86::or(RAX.into(), STR.into())
+}
+
+/// Operands of a primitive, known to be numbers by the caller
+fn operands(a: Object, b: Object) -> (Number, Number) {
+    let decode =
+        |val| Number::decode(val).unwrap_or_else(|| panic!("Expected a number, got {}", val));
+    (decode(a), decode(b))
+}
+
+/// Report an error in a primitive and exit
+// Foreign functions can't transfer control to a handler, so these errors
+// can't be caught
+fn error(primitive: &str, message: &str) -> ! {
+    eprintln!("Exception: {}: {}", primitive, message);
+    Status::Raised.exit()
+}
+
+/// Upper bound on the bytes needed for the result of an arithmetic primitive
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+#[no_mangle]
+pub extern "C" fn rt_arithmetic_size(primitive: i64, a: Object, b: Object) -> i64 {
+    let size = match (primitives::CHECKED[primitive as usize], operands(a, b)) {
+        ("exact->inexact", _) => SIZE,
+        ("inexact->exact", (Inexact(f), _)) => Big::from_f64(f).map_or(0, |n| n.size()),
+        ("inexact->exact", (n, _)) => n.size(),
+        (p, (Exact(a), Exact(b))) => Big::bound(p, &a, &b),
+        _ => SIZE,
+    };
+
+    size as i64
+}
+
+/// Apply an arithmetic primitive or a conversion to numbers
+///
+/// There must be room for [rt_arithmetic_size] bytes at the heap pointer.
+/// Conversions ignore the second operand.
+#[no_mangle]
+pub extern "C" fn rt_arithmetic(primitive: i64, a: Object, b: Object) -> Object {
+    let primitive = primitives::CHECKED[primitive as usize];
+
+    let result = match (primitive, operands(a, b)) {
+        ("exact->inexact", (a, _)) => Inexact(a.inexact()),
+        ("inexact->exact", (Inexact(f), _)) => match Big::from_f64(f) {
+            Some(n) => Exact(n),
+            None => error(primitive, &format!("no exact representation for {}", format(f))),
+        },
+        ("inexact->exact", (n, _)) => n,
+        (p, (Exact(a), Exact(b))) => {
+            if matches!(p, "/" | "%") && b == Big::from(0) {
+                error(p, "division by zero")
+            }
+            Exact(Big::apply(p, &a, &b))
+        }
+        (p, (a, b)) => {
+            let (x, y) = (a.inexact(), b.inexact());
+            Inexact(match p {
+                "+" | "inc" => x + y,
+                "-" | "dec" => x - y,
+                "*" => x * y,
+                "/" => x / y,
+                "%" => x % y,
+                p => unreachable!("`{}` is not an arithmetic primitive", p),
+            })
+        }
+    };
+
+    result.encode()
+}
+
+/// Compare numbers with a comparison primitive
+///
+/// Every comparison with NaN is false.
+#[no_mangle]
+pub extern "C" fn rt_compare(primitive: i64, a: Object, b: Object) -> Object {
+    let order = match operands(a, b) {
+        (Exact(a), Exact(b)) => Some(a.cmp(&b)),
+        (a, b) => a.inexact().partial_cmp(&b.inexact()),
+    };
+
+    let result = match (primitives::CHECKED[primitive as usize], order) {
+        (_, None) => false,
+        ("=", Some(o)) => o.is_eq(),
+        ("<", Some(o)) => o.is_lt(),
+        ("<=", Some(o)) => o.is_le(),
+        (">", Some(o)) => o.is_gt(),
+        (">=", Some(o)) => o.is_ge(),
+        (p, _) => unreachable!("`{}` is not a comparison primitive", p),
+    };
+
+    Object::new(if result { TRUE } else { FALSE })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format(2.0), "2.0");
+        assert_eq!(format(-0.5), "-0.5");
+        assert_eq!(format(2.25), "2.25");
+        assert_eq!(format(1e21), "1000000000000000000000.0");
+        assert_eq!(format(f64::NAN), "+nan.0");
+        assert_eq!(format(f64::NEG_INFINITY), "-inf.0");
+    }
+}
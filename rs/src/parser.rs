@@ -11,17 +11,38 @@
 //!
 //! [grammar]: http://www.scheme.com/tspl2d/grammar.html
 //! [lisper]: https://github.com/jaseemabid/lisper/blob/master/src/Lisper/Parser.hs
-use super::core::{Literal::*, *};
+use super::{
+    bignum::Big,
+    core::{Literal::*, *},
+    host, tags,
+};
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag},
+    bytes::complete::{escaped, is_not, tag, take},
     character::complete::{multispace0 as space0, multispace1 as space1, *},
-    combinator::{map, opt, value},
+    combinator::{all_consuming, map, opt, recognize, value},
+    error::ErrorKind,
     multi::*,
     sequence::*,
     IResult,
 };
-use std::str;
+use std::{cell::Cell, str};
+
+/// Forms can't be nested deeper than this on a [stack](host::STACK)
+///
+/// Every part of the compiler walks the program recursively, and a program
+/// nested deep enough would run it out of stack, which can't be recovered from.
+/// The parser fails with [ErrorKind::TooLarge] instead, see [max_depth]. The
+/// compiler runs [deep](host::deep) on a stack big enough for this.
+pub const MAX_DEPTH: usize = 100_000;
+
+/// Forms can't be nested deeper than this on any other stack
+pub const SHALLOW_DEPTH: usize = 128;
+
+thread_local! {
+    /// Forms the parser is in the middle of, see [nested]
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
 
 /// A program consists of a sequence of definitions and expressions.
 ///
@@ -66,7 +87,38 @@ fn form(i: &str) -> IResult<&str, Syntax> {
 /// <syntax binding>    → (<keyword> <transformer expression>)
 /// ```
 fn definition(i: &str) -> IResult<&str, Syntax> {
-    define_syntax(i) // | begin_syntax
+    alt((define_syntax, library))(i) // | begin_syntax
+}
+
+/// R7RS libraries, see [library](crate::library)
+///
+/// The declarations are left as lists, except for the forms in `begin` which
+/// are parsed like the top level of a program.
+///
+/// ```BNF
+/// <library>             → (define-library <library name> <library declaration>*)
+/// <library declaration> → (export <export spec>*)
+///                       | (import <import set>*)
+///                       | (begin <form>*)
+///                       | (include <string>+)
+/// ```
+fn library(i: &str) -> IResult<&str, Syntax> {
+    let (i, _) = tuple((open, tag("define-library"), space1))(i)?;
+    let (i, name) = expression(i)?;
+    let (i, decls) = many0(preceded(space0, alt((begin, expression))))(i)?;
+    let (i, _) = close(i)?;
+
+    let head = vec![Expr::name("define-library"), name];
+    Ok((i, Expr::List(head.into_iter().chain(decls).collect())))
+}
+
+fn begin(i: &str) -> IResult<&str, Syntax> {
+    let (i, _) = tuple((open, tag("begin")))(i)?;
+    let (i, forms) = many0(preceded(space1, form))(i)?;
+    let (i, _) = close(i)?;
+
+    let head = Expr::name("begin");
+    Ok((i, Expr::List(std::iter::once(head).chain(forms).collect())))
 }
 
 /// Expressions defined with a `define` keyword
@@ -82,36 +134,35 @@ fn define_variable(i: &str) -> IResult<&str, Syntax> {
     let (i, (_, _, _, name, _, body, _)) =
         tuple((open, tag("define"), space1, identifier, space1, expression, close))(i)?;
 
-    Ok((i, Expr::Define { name, val: box body }))
+    Ok((i, Expr::Define { name, val: Box::new(body) }))
 }
 
 fn define_lambda(i: &str) -> IResult<&str, Syntax> {
     let (i, _) = tuple((open, tag("define"), space1))(i)?;
-    let (i, mut params) = delimited(open, identifiers, close)(i)?;
+    let (i, params) = delimited(open, names, close)(i)?;
     let (i, body) = delimited(space0, many1(terminated(expression, space0)), space0)(i)?;
     let (i, _) = close(i)?;
 
-    let name = params[0].to_string();
-    let formals = params.split_off(1);
+    let name = Name::read(params[0]);
+    let formals = params[1..].iter().map(|p| Name::read(p)).collect();
     let body = Syntax::Lambda(Closure { tail: false, formals, body, free: vec![] });
 
-    Ok((i, Expr::Define { name, val: box body }))
+    Ok((i, Expr::Define { name, val: Box::new(body) }))
 }
 
 fn define_variadic_fn(i: &str) -> IResult<&str, Syntax> {
     let (i, _) = tuple((open, tag("define"), space1))(i)?;
-    let (i, mut params) = delimited(open, identifiers, tag("."))(i)?;
-    let (i, rest_param) = delimited(space1, identifier, close)(i)?;
+    let (i, params) = delimited(open, names, tag("."))(i)?;
+    let (i, rest_param) = delimited(space1, name, close)(i)?;
     let (i, body) = delimited(space0, many1(terminated(expression, space0)), space0)(i)?;
     let (i, _) = close(i)?;
 
-    let name = params[0].to_string();
-    let mut formals = params.split_off(1);
-    formals.push(rest_param);
+    let name = Name::read(params[0]);
+    let formals = params[1..].iter().chain(&[rest_param]).map(|p| Name::read(p)).collect();
 
     let body = Expr::Lambda(Closure { tail: false, formals, body, free: vec![] });
 
-    Ok((i, Expr::Define { name, val: box body }))
+    Ok((i, Expr::Define { name, val: Box::new(body) }))
 }
 
 /// Core expressions
@@ -142,15 +193,17 @@ fn define_variadic_fn(i: &str) -> IResult<&str, Syntax> {
 /// <application> → (<expression> <expression>*)
 /// ```
 fn expression(i: &str) -> IResult<&str, Syntax> {
-    alt((
-        (map(constant, Expr::Literal)),
-        variable,
-        quote,
-        lambda_syntax,
-        if_syntax,
-        let_syntax,
-        application,
-    ))(i)
+    nested(i, |i| {
+        alt((
+            (map(constant, Expr::Literal)),
+            variable,
+            quote,
+            lambda_syntax,
+            if_syntax,
+            let_syntax,
+            application,
+        ))(i)
+    })
 }
 
 /// `(let-syntax (<syntax binding>*) <expression>+)`
@@ -164,7 +217,7 @@ fn let_syntax(i: &str) -> IResult<&str, Syntax> {
 }
 
 /// `named → (name value)`
-fn binding(i: &str) -> IResult<&str, (String, Syntax)> {
+fn binding(i: &str) -> IResult<&str, (Name, Syntax)> {
     let (i, (_, name, _, value, _, _)) =
         tuple((open, identifier, space1, expression, close, space0))(i)?;
 
@@ -193,7 +246,14 @@ fn if_syntax(i: &str) -> IResult<&str, Syntax> {
         close,
     ))(i)?;
 
-    Ok((i, Expr::Cond { pred: box pred, then: box then, alt: alt.map(|(_, a)| box a) }))
+    Ok((
+        i,
+        Expr::Cond {
+            pred: Box::new(pred),
+            then: Box::new(then),
+            alt: alt.map(|(_, a)| Box::new(a)),
+        },
+    ))
 }
 
 /// variable is an identifier
@@ -202,7 +262,7 @@ fn variable(i: &str) -> IResult<&str, Syntax> {
 }
 
 /// `<formals>     → <variable> | (<variable>*) | (<variable>+ . <variable>)`
-fn formals(i: &str) -> IResult<&str, Vec<String>> {
+fn formals(i: &str) -> IResult<&str, Vec<Name>> {
     alt((
         map(identifier, |s| vec![s]),
         delimited(open, many0(terminated(identifier, space0)), close),
@@ -218,10 +278,22 @@ fn body(i: &str) -> IResult<&str, Vec<Syntax>> {
     Ok((i, v))
 }
 
-/// (quote <datum>) | '<datum>
-// Note: This parser only handles simple quoted symbols for now
+/// `(quote <datum>) | '<datum>`
+///
+/// A quoted name is read as a symbol right away, any other datum is left
+/// quoted for [rename](crate::lang) to build.
 fn quote(i: &str) -> IResult<&str, Syntax> {
-    map(tuple((tag("\'"), identifier)), |(_, i)| Expr::symbol(i))(i)
+    fn quoted(d: Syntax) -> Syntax {
+        Expr::List(vec![Expr::name("quote"), d])
+    }
+
+    alt((
+        map(preceded(tag("\'"), name), Expr::symbol),
+        map(preceded(tag("\'"), datum), quoted),
+        map(tuple((open, tag("quote"), space1, datum, space0, close)), |(_, _, _, d, _, _)| {
+            quoted(d)
+        }),
+    ))(i)
 }
 
 /// `<constant> → <boolean> | <number> | <character> | <string>`
@@ -230,8 +302,9 @@ fn constant(i: &str) -> IResult<&str, Literal> {
         (map(tag("()"), |_| Nil)),
         (map(ascii, Char)),
         (map(boolean, Boolean)),
-        (map(number, Number)),
-        (map(string, Str)),
+        (map(decimal, Float)),
+        number,
+        (map(string, |s| Str(unescape(s)))),
     ))(i)
 }
 
@@ -265,26 +338,29 @@ fn application(i: &str) -> IResult<&str, Syntax> {
 /// ```
 ///
 /// The parser extends the grammar for identifiers like `fn::{let 1}::x`
-fn identifier(i: &str) -> IResult<&str, String> {
+fn identifier(i: &str) -> IResult<&str, Name> {
+    map(name, Name::read)(i)
+}
+
+/// An [identifier] as a slice of the input
+///
+/// Nothing is copied while parsing, the parser backtracks a lot and most of
+/// what it reads is thrown away. Names are copied once they end up in the
+/// program.
+fn name(i: &str) -> IResult<&str, &str> {
     let subsequent_with_space = |i| alt((initial, digit, symbol, one_of(".+- ")))(i);
 
     alt((
-        value(String::from("+"), tag("+")),
-        value(String::from("-"), tag("-")),
-        value(String::from("..."), tag("...")),
-        map(tuple((initial, many0(subsequent), opt(identifier))), |(i, s, rest)| match rest {
-            Some(more) => format!("{}{}{}", i, s.iter().collect::<String>(), more),
-            None => format!("{}{}", i, s.iter().collect::<String>()),
-        }),
-        map(
-            tuple((tag("{"), tuple((initial, many0(subsequent_with_space))), tag("}"), identifier)),
-            |(_, (i, s), _, again)| format!("{{{}{}}}{}", i, s.iter().collect::<String>(), again),
-        ),
+        tag("+"),
+        tag("-"),
+        tag("..."),
+        recognize(tuple((initial, many0_count(subsequent), opt(name)))),
+        recognize(tuple((tag("{"), initial, many0_count(subsequent_with_space), tag("}"), name))),
     ))(i)
 }
 
-fn identifiers(i: &str) -> IResult<&str, Vec<String>> {
-    many1(terminated(identifier, space0))(i)
+fn names(i: &str) -> IResult<&str, Vec<&str>> {
+    many1(terminated(name, space0))(i)
 }
 
 fn initial(i: &str) -> IResult<&str, char> {
@@ -329,17 +405,29 @@ fn digit(i: &str) -> IResult<&str, char> {
 /// <abbreviation>     →  ' <datum> | ` <datum> | , <datum> | ,@ <datum>
 /// <vector>           → #(<datum>*)
 /// ```
-#[cfg(test)]
+///
+/// Data is read by the runtime with [read], so symbols are identifiers here.
+/// Only the `'` abbreviation is supported for now.
 fn datum(i: &str) -> IResult<&str, Syntax> {
-    alt((
-        (map(tag("()"), |_| Expr::Literal(Nil))),
-        (map(boolean, Expr::from)),
-        (map(ascii, |c| Expr::from(c as char))),
-        (map(number, Expr::from)),
-        (map(identifier, Expr::Identifier)),
-        (map(string, Expr::string)),
-        list,
-    ))(i)
+    nested(i, |i| {
+        alt((
+            (map(tag("()"), |_| Expr::Literal(Nil))),
+            (map(boolean, Expr::from)),
+            (map(ascii, |c| Expr::from(c as char))),
+            (map(decimal, |f| Expr::Literal(Float(f)))),
+            (map(number, Expr::Literal)),
+            (map(name, Expr::name)),
+            (map(string, |s| Expr::string(unescape(s)))),
+            list,
+            vector,
+            abbreviation,
+        ))(i)
+    })
+}
+
+/// Read a single datum from the start of the input and return the rest
+pub fn read(i: &str) -> IResult<&str, Syntax> {
+    preceded(space0, datum)(i)
 }
 
 fn boolean(i: &str) -> IResult<&str, bool> {
@@ -350,48 +438,168 @@ fn sign(i: &str) -> IResult<&str, i64> {
     alt((value(-1, tag("-")), value(1, tag("+"))))(i)
 }
 
-fn number(i: &str) -> IResult<&str, i64> {
-    let (i, s) = opt(sign)(i)?;
-    let (i, n) = digit1(i)?;
+/// Integers are fixnums if they fit in one and bignums otherwise
+fn number(i: &str) -> IResult<&str, Literal> {
+    let (rest, n) = recognize(pair(opt(sign), digit1))(i)?;
+
+    match n.parse::<i64>() {
+        Ok(n) if tags::untag(tags::immediate(n, tags::NUM)) == n => Ok((rest, Number(n))),
+        _ => Ok((rest, Bignum(Big::parse(n)))),
+    }
+}
 
-    // TODO: Propagate this error up rather than panic
-    let n = n.parse::<i64>().expect(&format!("Failed to parse digits into i64: `{:?}`\n", n)[..]);
+/// Numbers with a decimal point like `3.14` or `-0.5` are flonums
+fn decimal(i: &str) -> IResult<&str, f64> {
+    let (i, n) = recognize(tuple((opt(sign), digit1, char('.'), digit1)))(i)?;
 
-    Ok((i, s.unwrap_or(1) * n))
+    Ok((i, n.parse::<f64>().unwrap()))
 }
 
 /// ASCII Characters for now
 fn ascii(i: &str) -> IResult<&str, u8> {
     // $ man ascii
     alt((
-        value(9 as u8, tag(r"#\tab")),
-        value(10 as u8, tag(r"#\newline")),
-        value(13 as u8, tag(r"#\return")),
-        value(32 as u8, tag(r"#\space")),
+        value(9_u8, tag(r"#\tab")),
+        value(10_u8, tag(r"#\newline")),
+        value(13_u8, tag(r"#\return")),
+        value(32_u8, tag(r"#\space")),
         // Picking the first byte is quite unsafe, fix for UTF8
         preceded(tag(r"#\"), map(anychar, |c: char| c as u8)),
     ))(i)
 }
 
-fn string(i: &str) -> IResult<&str, String> {
+/// The contents of a string, without the quotes
+fn string(i: &str) -> IResult<&str, &str> {
     let q = "\"";
-    let (i, s) = delimited(tag(q), opt(is_not(q)), tag(q))(i)?;
+    let (i, s) = delimited(tag(q), opt(escaped(is_not("\"\\"), '\\', anychar)), tag(q))(i)?;
 
-    Ok((i, s.map_or(String::from(""), |s| s.to_string())))
+    Ok((i, s.unwrap_or_default()))
+}
+
+/// The contents of a string literal with its escapes replaced
+///
+/// Like R7RS, `\a`, `\b`, `\t`, `\n`, `\r` and `\x<hex>;` stand for the
+/// character with that code. Any other character after a `\` is itself, which
+/// covers `\"` and `\\`.
+fn unescape(s: &str) -> String {
+    if !s.contains('\\') {
+        return s.to_string();
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('a') => out.push('\u{7}'),
+            Some('b') => out.push('\u{8}'),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('x') => {
+                let rest = chars.as_str();
+                let code = rest.find(';').and_then(|end| {
+                    let code = u32::from_str_radix(&rest[..end], 16).ok()?;
+                    Some((end, char::from_u32(code)?))
+                });
+
+                match code {
+                    Some((end, c)) => {
+                        out.push(c);
+                        chars = rest[end + 1..].chars();
+                    }
+                    None => out.push('x'),
+                }
+            }
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+
+    out
 }
 
 /// `<list> → (<datum>*) | (<datum>+ . <datum>) | <abbreviation>`
-#[cfg(test)]
+///
+/// The tail of an improper list follows a `.` in the list, which can't be an
+/// identifier otherwise.
 fn list(i: &str) -> IResult<&str, Syntax> {
     let (i, _) = tuple((char('('), space0))(i)?;
-    let (i, elems) = separated_list1(space1, datum)(i)?;
+    let (i, mut elems) = separated_list1(space1, datum)(i)?;
+    let (i, tail) = opt(preceded(tuple((space1, char('.'), space1)), datum))(i)?;
     let (i, _) = tuple((space0, char(')')))(i)?;
 
-    if elems.is_empty() {
-        Ok((i, Expr::Literal(Nil)))
+    if let Some(tail) = tail {
+        elems.push(Expr::name("."));
+        elems.push(tail);
+    }
+
+    Ok((i, Expr::List(elems)))
+}
+
+/// `<vector> → #(<datum>*)`
+fn vector(i: &str) -> IResult<&str, Syntax> {
+    let (i, _) = tuple((tag("#("), space0))(i)?;
+    let (i, elems) = separated_list0(space1, datum)(i)?;
+    let (i, _) = tuple((space0, char(')')))(i)?;
+
+    Ok((i, Expr::Vector(elems)))
+}
+
+/// `<abbreviation> → ' <datum>`
+fn abbreviation(i: &str) -> IResult<&str, Syntax> {
+    let (i, d) = preceded(char('\''), datum)(i)?;
+
+    Ok((i, Expr::List(vec![Expr::name("quote"), d])))
+}
+
+/// `<token> → <string> | <character> | #( | ( | ) | ' | <atom>`
+///
+/// The parser doesn't need tokens, this is only a view of the source as it
+/// is read. An atom is anything up to the next delimiter, like an identifier
+/// or a number.
+fn token(i: &str) -> IResult<&str, &str> {
+    alt((
+        recognize(string),
+        recognize(pair(tag("#\\"), alt((alpha1, take(1usize))))),
+        tag("#("),
+        tag("("),
+        tag(")"),
+        tag("'"),
+        is_not(" \t\r\n()'\""),
+    ))(i)
+}
+
+/// How deep forms can be nested on the stack of this thread
+pub fn max_depth() -> usize {
+    if host::is_deep() {
+        MAX_DEPTH
     } else {
-        Ok((i, Expr::List(elems)))
+        SHALLOW_DEPTH
+    }
+}
+
+/// Parse a form inside another, unless they are nested too deep already
+fn nested<'a, T>(
+    i: &'a str,
+    f: impl FnOnce(&'a str) -> IResult<&'a str, T>,
+) -> IResult<&'a str, T> {
+    let depth = DEPTH.with(Cell::get);
+
+    if depth >= max_depth() {
+        return Err(nom::Err::Failure((i, ErrorKind::TooLarge)));
     }
+
+    DEPTH.with(|d| d.set(depth + 1));
+    let result = f(i);
+    DEPTH.with(|d| d.set(depth));
+
+    result
 }
 
 fn open(i: &str) -> IResult<&str, ()> {
@@ -404,6 +612,101 @@ fn close(i: &str) -> IResult<&str, ()> {
     Ok((i, ()))
 }
 
+/// Parse a single expression for testing, return or panic
+#[cfg(test)]
+pub fn parse1(i: &str) -> Syntax {
+    match form(i) {
+        Ok((_rest, e)) => e,
+        Err(e) => panic!("Failed to parse `{}`: {:?}", i, e),
+    }
+}
+
+/// Split a program into tokens, see [token]
+pub fn tokens(i: &str) -> Result<Vec<&str>, Error<'_>> {
+    let i = shebang(i);
+
+    match all_consuming(many0(delimited(space0, token, space0)))(i) {
+        Ok((_rest, tokens)) => Ok(tokens),
+        Err(e) => Err(Error::Parser(e)),
+    }
+}
+
+/// The source without a first line like `#!/usr/bin/env inc`
+///
+/// The line makes a file executable as a script on Unix, see
+/// [Driver::script](crate::cli::Driver::script). What is left is a suffix of
+/// the source, so positions in errors are counted from the end as usual.
+pub fn shebang(i: &str) -> &str {
+    match i.strip_prefix("#!") {
+        Some(rest) => rest.find('\n').map_or("", |n| &rest[n..]),
+        None => i,
+    }
+}
+
+/// Parse the whole program along with the text of every form
+///
+/// Unlike [parse], this stops at the first invalid form. The text is used to
+/// show a form next to the code it compiled to, see [disasm](crate::disasm).
+pub fn forms(i: &str) -> Result<Vec<(Syntax, &str)>, Error<'_>> {
+    let mut forms = vec![];
+    let mut rest = shebang(i);
+
+    while !rest.trim_start().is_empty() {
+        let start = rest.trim_start();
+        let (next, expr) = terminated(form, space0)(start).map_err(Error::Parser)?;
+
+        forms.push((expr, start[..start.len() - next.len()].trim_end()));
+        rest = next;
+    }
+
+    Ok(forms)
+}
+
+/// Parse the whole program
+///
+/// Input left over after the last form is an error, which points at where the
+/// parser gave up. The parser carries on after an invalid form from the next
+/// line starting with a `(`, so that all the forms in error are reported
+/// together. A first line starting with `#!` is skipped, see [shebang].
+///
+/// Forms are parsed on the stack of the caller, as deep as [max_depth] allows
+/// on it, so that the program is dropped on a stack as deep as well.
+pub fn parse<'a>(i: &'a str) -> Result<Vec<Syntax>, Error<'a>> {
+    let i = shebang(i);
+
+    if i.trim().is_empty() {
+        return program(i).map(|(_, prog)| prog).map_err(Error::Parser);
+    }
+
+    let mut expressions = vec![];
+    let mut errors = vec![];
+    let mut rest = i;
+
+    while !rest.trim_start().is_empty() {
+        match delimited(space0, form, space0)(rest) {
+            Ok((next, expr)) => {
+                expressions.push(expr);
+                rest = next;
+            }
+            Err(e) => {
+                errors.push(Error::Parser(e));
+
+                let start = rest.trim_start();
+                match start.find("\n(") {
+                    Some(n) => rest = &start[n + 1..],
+                    None => break,
+                }
+            }
+        }
+    }
+
+    match errors.len() {
+        0 => Ok(expressions),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Errors(errors)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -433,8 +736,11 @@ mod tests {
 
         assert_eq!(ok('?'), symbol("?"));
 
-        assert_eq!(ok(42), number("42"));
-        assert_eq!(ok(-42), number("-42"));
+        assert_eq!(ok(Number(42)), number("42"));
+        assert_eq!(ok(Number(-42)), number("-42"));
+
+        assert_eq!(ok(3.25), decimal("3.25"));
+        assert_eq!(ok(-0.5), decimal("-0.5"));
 
         assert_eq!(ok(b'j'), ascii("#\\j"));
         assert_eq!(ok(b'^'), ascii("#\\^"));
@@ -446,33 +752,51 @@ mod tests {
 
     #[test]
     fn identifiers() {
-        assert_eq!(ok(String::from("x")), identifier("x"));
-        assert_eq!(ok(String::from("one")), identifier("one"));
-        assert_eq!(ok(String::from("!bang")), identifier("!bang"));
-        assert_eq!(ok(String::from("a->b")), identifier("a->b"));
-        assert_eq!(ok(String::from("+")), identifier("+"));
-        assert_eq!(ok(String::from("-")), identifier("-"));
-        assert_eq!(ok(String::from("i64")), identifier("i64"));
+        assert_eq!(ok(Name::from("x")), identifier("x"));
+        assert_eq!(ok(Name::from("one")), identifier("one"));
+        assert_eq!(ok(Name::from("!bang")), identifier("!bang"));
+        assert_eq!(ok(Name::from("a->b")), identifier("a->b"));
+        assert_eq!(ok(Name::from("+")), identifier("+"));
+        assert_eq!(ok(Name::from("-")), identifier("-"));
+        assert_eq!(ok(Name::from("i64")), identifier("i64"));
 
         // -> is not an identifier, consume the - as an id and return the >
-        assert_eq!(partial(">", String::from("-")), identifier("->"));
+        assert_eq!(partial(">", Name::from("-")), identifier("->"));
 
         // Identifiers must split at space and not consume anything
         // afterwards
-        assert_eq!(partial(" b", String::from("a")), identifier("a b"));
+        assert_eq!(partial(" b", Name::from("a")), identifier("a b"));
 
         // Quoted symbols are not identifiers
-        assert_eq!(partial("'woo", String::from("a")), identifier("a'woo"));
+        assert_eq!(partial("'woo", Name::from("a")), identifier("a'woo"));
 
         // Allow namespaced identifiers for tests
-        assert_eq!(ok(String::from("x::y")), identifier("x::y"));
-        assert_eq!(ok(String::from("{let 0}::y")), identifier("{let 0}::y"));
+        assert_eq!(ok(Name::from("x::y")), identifier("x::y"));
+        assert_eq!(ok(Name::from("{let 0}::y")), identifier("{let 0}::y"));
         assert_eq!(
-            ok(String::from("fn::{let 0}::{closure}::x")),
+            ok(Name::from("fn::{let 0}::{closure}::x")),
             identifier("fn::{let 0}::{closure}::x")
         );
     }
 
+    #[test]
+    fn slices() {
+        // Names and strings are slices of the input until they are part of
+        // the program
+        let source = "fn::{let 0}::x \"hello\"";
+
+        let (rest, n) = name(source).unwrap();
+        assert_eq!(n, "fn::{let 0}::x");
+        assert!(std::ptr::eq(n.as_ptr(), source.as_ptr()));
+
+        let (_, s) = string(rest.trim_start()).unwrap();
+        assert_eq!(s, "hello");
+        assert!(std::ptr::eq(s.as_ptr(), source[16..].as_ptr()));
+
+        assert_eq!(ok(""), string("\"\""));
+        assert_eq!(ok(-1.5), decimal("-1.5"));
+    }
+
     // #[test]
     // fn unicode() {
     //     assert_eq!(fail(("അ")), identifier(("അ")))
@@ -481,16 +805,33 @@ mod tests {
     #[test]
     fn data() {
         assert_eq!(ok(Expr::Literal(Nil)), datum("()"));
-        assert_eq!(ok(Expr::Identifier(String::from("one"))), datum("one"));
+        assert_eq!(ok(Expr::Identifier(Name::from("one"))), datum("one"));
         assert_eq!(ok(42.into()), datum("42"))
     }
 
+    #[test]
+    fn reader() {
+        assert_eq!(
+            ok(List(vec![1.into(), Expr::name("."), 2.into()])),
+            datum("(1 . 2)")
+        );
+        assert_eq!(ok(Expr::Vector(vec![1.into(), Expr::name("x")])), datum("#(1 x)"));
+        assert_eq!(ok(Expr::Vector(vec![])), datum("#()"));
+        assert_eq!(ok(List(vec![Expr::name("quote"), Expr::name("a")])), datum("'a"));
+        assert_eq!(Ok((" 2", 1.into())), read("  1 2"));
+    }
+
     #[test]
     fn strings() {
         assert_eq!(ok(Expr::string("hello world")), datum("\"hello world\""));
         assert_eq!(ok(Expr::string("മലയാളം")), datum("\"മലയാളം\""));
         assert_eq!(ok(Expr::string("Unicode 😱 ⌘")), datum("\"Unicode 😱 ⌘\""));
-        assert_eq!(ok(Expr::string("")), datum("\"\""))
+        assert_eq!(ok(Expr::string("")), datum("\"\""));
+
+        assert_eq!(ok(Expr::string("a \"b\" \\")), datum(r#""a \"b\" \\""#));
+        assert_eq!(ok(Expr::string("\t\n\0λ;")), datum(r#""\t\n\x0;\x3bb;;""#));
+        assert_eq!(ok(Expr::string("xy")), datum(r#""\x\y""#));
+        assert_eq!(ok(r#"a\"b"#), string(r#""a\"b""#));
     }
 
     #[test]
@@ -547,6 +888,45 @@ mod tests {
 
         assert_eq!(ok(vec!['j'.into()]), program("#\\j"));
         assert_eq!(ok(vec!['^'.into()]), program("#\\^"));
+
+        assert!(parse("(+ 1 2) ").is_ok());
+        assert!(parse("(+ 1 2) )").is_err());
+
+        // Parsing carries on from the next form after an error
+        match parse("(+ 1\n(car (quote ()))\n(- 2 #z)\n(* 3 4)") {
+            Err(Error::Errors(errors)) => assert_eq!(errors.len(), 2),
+            e => panic!("Expected two errors, found {:?}", e),
+        }
+
+        assert_eq!(parse("#!/usr/bin/env inc\n(+ 1 2)").unwrap(), parse("(+ 1 2)").unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let nest = |depth| "(car ".repeat(depth) + "1" + &")".repeat(depth);
+
+        fn too_large(r: Result<Vec<Syntax>, Error>) -> bool {
+            matches!(r, Err(Error::Parser(nom::Err::Failure((_, ErrorKind::TooLarge)))))
+        }
+
+        assert!(parse(&nest(SHALLOW_DEPTH - 1)).is_ok());
+        assert!(too_large(parse(&nest(SHALLOW_DEPTH))));
+
+        host::deep(|| {
+            assert!(parse(&nest(MAX_DEPTH - 1)).is_ok());
+            assert!(too_large(parse(&nest(MAX_DEPTH))));
+        });
+
+        // Integers too large for a fixnum are bignums, no matter how large
+        let big = |n| Expr::Literal(Bignum(Big::parse(n)));
+
+        assert_eq!(ok(vec![Expr::from((1 << 60) - 1)]), program("1152921504606846975"));
+        assert_eq!(ok(vec![big("1152921504606846976")]), program("1152921504606846976"));
+        assert_eq!(ok(vec![big("-9223372036854775808")]), program("-9223372036854775808"));
+        assert_eq!(
+            parse("(+ 1 99999999999999999999)").unwrap(),
+            vec![Expr::List(vec![Expr::name("+"), Expr::from(1), big("99999999999999999999")])]
+        );
     }
 
     #[test]
@@ -555,14 +935,14 @@ mod tests {
         let p2 = "(let ((x 1)) (let ((x 2)) #t) x)";
 
         let e1 = Let {
-            bindings: vec![(String::from("x"), Expr::from(1)), (String::from("y"), Expr::from(2))],
+            bindings: vec![(Name::from("x"), Expr::from(1)), (Name::from("y"), Expr::from(2))],
             body: vec![List(vec![Expr::name("+"), (Expr::name("x")), (Expr::name("y"))])],
         };
 
         let e2 = Let {
-            bindings: vec![(String::from("x"), Expr::from(1))],
+            bindings: vec![(Name::from("x"), Expr::from(1))],
             body: vec![
-                Let { bindings: vec![(String::from("x"), Expr::from(2))], body: vec![true.into()] },
+                Let { bindings: vec![(Name::from("x"), Expr::from(2))], body: vec![true.into()] },
                 Expr::name("x"),
             ],
         };
@@ -578,24 +958,28 @@ mod tests {
     #[test]
     fn if_syntax() {
         let prog = "(if #t 12 13)";
-        let exp = Cond { pred: box true.into(), then: box 12.into(), alt: Some(box 13.into()) };
+        let exp = Cond {
+            pred: Box::new(true.into()),
+            then: Box::new(12.into()),
+            alt: Some(Box::new(13.into())),
+        };
 
         assert_eq!(ok(vec![exp]), program(prog));
 
         let prog = "(if #t 14)";
-        let exp = Cond { pred: box true.into(), then: box 14.into(), alt: None };
+        let exp = Cond { pred: Box::new(true.into()), then: Box::new(14.into()), alt: None };
 
         assert_eq!(ok(vec![exp]), program(prog));
 
         let prog = "(if (zero? x) 1 (* x (f (dec x))))";
         let exp = Cond {
-            pred: box List(vec![Expr::name("zero?"), Expr::name("x")]),
-            then: box 1.into(),
-            alt: Some(box List(vec![
+            pred: Box::new(List(vec![Expr::name("zero?"), Expr::name("x")])),
+            then: Box::new(1.into()),
+            alt: Some(Box::new(List(vec![
                 Expr::name("*"),
                 Expr::name("x"),
                 List(vec![Expr::name("f"), List(vec![Expr::name("dec"), Expr::name("x")])]),
-            ])),
+            ]))),
         };
 
         assert_eq!(ok(vec![exp]), program(prog));
@@ -613,6 +997,12 @@ mod tests {
         let e = vec![List(vec![Expr::name("symbol=?"), Expr::symbol("one"), Expr::symbol("two")])];
 
         assert_eq!(ok(e), p);
+
+        let quoted = |d| List(vec![Expr::name("quote"), d]);
+        assert_eq!(ok(quoted(Literal(Nil))), super::quote("'()"));
+        assert_eq!(ok(quoted(Literal(Nil))), super::quote("(quote ())"));
+        assert_eq!(ok(quoted(List(vec![1.into(), Expr::name("a")]))), super::quote("'(1 a)"));
+        assert_eq!(ok(quoted(Expr::name("a"))), super::quote("(quote a)"));
     }
 
     #[test]
@@ -621,33 +1011,36 @@ mod tests {
             (
                 "(define (id x) x)",
                 Define {
-                    name: (String::from("id")),
-                    val: box Lambda(Closure {
+                    name: (Name::from("id")),
+                    val: Box::new(Lambda(Closure {
                         tail: false,
                         formals: vec!["x".into()],
                         body: vec![(Expr::name("x"))],
                         free: vec![],
-                    }),
+                    })),
                 },
             ),
             (
                 "(define (pi) 42)",
                 Define {
-                    name: (String::from("pi")),
-                    val: box Lambda(Closure {
+                    name: (Name::from("pi")),
+                    val: Box::new(Lambda(Closure {
                         tail: false,
                         formals: vec![],
                         body: vec![42.into()],
                         free: vec![],
-                    }),
+                    })),
                 },
             ),
-            ("(define pi 42)", Define { name: (String::from("pi")), val: box Expr::from(42) }),
+            (
+                "(define pi 42)",
+                Define { name: (Name::from("pi")), val: Box::new(Expr::from(42)) },
+            ),
             (
                 "(define (add a b) (+ a b))",
                 Define {
-                    name: (String::from("add")),
-                    val: box Lambda(Closure {
+                    name: (Name::from("add")),
+                    val: Box::new(Lambda(Closure {
                         tail: false,
                         formals: vec!["a".into(), "b".into()],
                         body: vec![Expr::List(vec![
@@ -656,14 +1049,14 @@ mod tests {
                             Expr::name("b"),
                         ])],
                         free: vec![],
-                    }),
+                    })),
                 },
             ),
             (
                 "(define (add x y . args) (reduce + 0 args))",
                 Define {
-                    name: (String::from("add")),
-                    val: box Lambda(Closure {
+                    name: (Name::from("add")),
+                    val: Box::new(Lambda(Closure {
                         tail: false,
                         formals: vec!["x".into(), "y".into(), "args".into()],
                         body: vec![Expr::List(vec![
@@ -673,7 +1066,7 @@ mod tests {
                             Expr::name("args"),
                         ])],
                         free: vec![],
-                    }),
+                    })),
                 },
             ),
         ];
@@ -687,6 +1080,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn library() {
+        let prog = "(define-library (math 1)\n  (export pi)\n  (begin (define pi 3) (pi)))";
+        let exp = Expr::List(vec![
+            Expr::name("define-library"),
+            Expr::List(vec![Expr::name("math"), Expr::from(1)]),
+            Expr::List(vec![Expr::name("export"), Expr::name("pi")]),
+            Expr::List(vec![
+                Expr::name("begin"),
+                Define { name: Name::from("pi"), val: Box::new(Expr::from(3)) },
+                Expr::List(vec![Expr::name("pi")]),
+            ]),
+        ]);
+
+        assert_eq!(ok(vec![exp]), program(prog));
+    }
+
     #[test]
     fn lambda_syntax() {
         let prog = "(lambda () 1)";
@@ -735,7 +1145,11 @@ mod tests {
             tail: false,
             formals: vec!["x".into()],
             free: vec![],
-            body: vec![Cond { pred: box true.into(), then: box 1.into(), alt: Some(box 2.into()) }],
+            body: vec![Cond {
+                pred: Box::new(true.into()),
+                then: Box::new(1.into()),
+                alt: Some(Box::new(2.into())),
+            }],
         });
 
         assert_eq!(ok(vec![exp]), program(prog));
@@ -746,33 +1160,16 @@ mod tests {
             formals: vec!["x".into()],
             free: vec![],
             body: vec![Cond {
-                pred: box List(vec![Expr::name("zero?"), Expr::name("x")]),
-                then: box 1.into(),
-                alt: Some(box List(vec![
+                pred: Box::new(List(vec![Expr::name("zero?"), Expr::name("x")])),
+                then: Box::new(1.into()),
+                alt: Some(Box::new(List(vec![
                     Expr::name("*"),
                     Expr::name("x"),
                     List(vec![Expr::name("f"), List(vec![Expr::name("dec"), Expr::name("x")])]),
-                ])),
+                ]))),
             }],
         });
 
         assert_eq!(ok(vec![exp]), program(prog));
     }
 }
-
-/// Parse a single expression for testing, return or panic
-#[cfg(test)]
-pub fn parse1(i: &str) -> Syntax {
-    match form(i) {
-        Ok((_rest, e)) => e,
-        Err(e) => panic!("Failed to parse `{}`: {:?}", i, e),
-    }
-}
-
-/// Parse the whole program
-pub fn parse<'a>(i: &'a str) -> Result<Vec<Syntax>, Error<'a>> {
-    match program(i) {
-        Ok((_rest, expressions)) => Ok(expressions),
-        Err(e) => Err(Error::Parser(e)),
-    }
-}
@@ -16,15 +16,20 @@ use crate::{
         emit::{eval, mask},
         state::State,
     },
+    continuations,
     core::{Ident, Literal::*, *},
-    immediate, strings,
+    coverage, exceptions, ffi, gc, immediate, lambda, numbers, process, rt, strings, symbols, tags,
+    threads,
     x86::{self, Reference::*, Register::*, *},
 };
 
-/// Call compiler primitive by name
+/// Call compiler primitive by name, unless the program shadows it
 pub fn call(s: &mut State, fname: &Ident, args: &[Core]) -> Option<ASM> {
-    match (fname.short().as_str(), args) {
+    match (fname.free()?, args) {
         ("%", [x, y]) => Some(remainder(s, x, y)),
+        ("%foreign-call", [Expr::Literal(Str(name)), Expr::Literal(Number(sig)), args @ ..]) => {
+            Some(ffi::foreign(s, name, *sig, args))
+        }
         ("*", [x, y]) => Some(mul(s, x, y)),
         ("+", [x, y]) => Some(plus(s, x, y)),
         ("-", [x, y]) => Some(minus(s, x, y)),
@@ -34,36 +39,451 @@ pub fn call(s: &mut State, fname: &Ident, args: &[Core]) -> Option<ASM> {
         ("=", [x, y]) => Some(eq(s, x, y)),
         (">", [x, y]) => Some(gt(s, x, y)),
         (">=", [x, y]) => Some(gte(s, x, y)),
+        ("+" | "*", args) => Some(fold(s, fname, args)),
+        ("apply", [Expr::Identifier(f), args @ .., list]) => match variadic(f) {
+            Some(_) => Some(lambda::reduce(s, f, args, list)),
+            None => Some(lambda::apply(s, f, args, list)),
+        },
         ("boolean?", [arg]) => Some(booleanp(s, arg)),
+        ("call/cc", [Expr::Identifier(f)]) => Some(continuations::call(s, f)),
+        ("call-with-current-continuation", [Expr::Identifier(f)]) => {
+            Some(continuations::call(s, f))
+        }
         ("car", [arg]) => Some(car(s, arg)),
         ("cdr", [arg]) => Some(cdr(s, arg)),
         ("char?", [arg]) => Some(charp(s, arg)),
+        ("char->integer", [c]) => Some(char_integer(s, c)),
+        ("char<?", [x, y]) => Some(char_comparison(s, "char<?", x, y, Condition::L)),
+        ("char<=?", [x, y]) => Some(char_comparison(s, "char<=?", x, y, Condition::LE)),
+        ("char=?", [x, y]) => Some(char_comparison(s, "char=?", x, y, Condition::E)),
+        ("char>?", [x, y]) => Some(char_comparison(s, "char>?", x, y, Condition::G)),
+        ("char>=?", [x, y]) => Some(char_comparison(s, "char>=?", x, y, Condition::GE)),
+        ("close-input-port", [port]) | ("close-output-port", [port]) | ("close-port", [port]) => {
+            Some(io(s, "rt-close-port", std::slice::from_ref(port)))
+        }
+        ("command-line", []) => Some(process::command_line(s)),
         ("cons", [x, y]) => Some(cons(s, x, y)),
+        (coverage::HIT, [Expr::Literal(Number(index)), ..]) => Some(coverage::hit(*index)),
         ("dec", [arg]) => Some(dec(s, arg)),
+        ("display", [val]) => Some(io(s, "rt-display", &[val.clone(), Expr::Literal(Nil)])),
+        ("display", [val, port]) => Some(io(s, "rt-display", &[val.clone(), port.clone()])),
+        ("eval", [expr, env]) => Some(crate::eval::call(s, expr, env)),
+        ("exact->inexact", [x]) => Some(convert(s, "exact->inexact", x)),
+        (
+            "dynamic-wind",
+            [Expr::Identifier(before), Expr::Identifier(thunk), Expr::Identifier(after)],
+        ) => Some(continuations::wind(s, before, thunk, after)),
         ("fixnum?", [arg]) => Some(fixnump(s, arg)),
+        ("gc", []) => Some(gc::collect(s)),
+        ("%gc-stat", [k]) => Some(gc::stat(s, k)),
+        ("flonum?", [arg]) => Some(flonump(s, arg)),
+        ("get-environment-variable", [name]) => Some(process::environment(s, name)),
+        ("get-output-string", [port]) => {
+            Some(io(s, "rt-get-output-string", std::slice::from_ref(port)))
+        }
         ("inc", [arg]) => Some(inc(s, arg)),
-        ("make-string", [Expr::Literal(Number(n))]) => Some(strings::make(s, *n)),
+        ("inexact->exact", [x]) => Some(convert(s, "inexact->exact", x)),
+        ("integer->char", [n]) => Some(integer_char(s, n)),
+        ("make-string", [n]) => Some(make_string(s, n, None)),
+        ("make-string", [n, fill]) => Some(make_string(s, n, Some(fill))),
+        ("make-vector", [n]) => Some(make_vector(s, n, &Expr::Literal(Number(0)))),
+        ("make-vector", [n, fill]) => Some(make_vector(s, n, fill)),
+        ("newline", []) => Some(io(s, "rt-newline", &[Expr::Literal(Nil)])),
+        ("newline", [port]) => Some(io(s, "rt-newline", std::slice::from_ref(port))),
         ("not", [arg]) => Some(not(s, arg)),
         ("null?", [arg]) => Some(nullp(s, arg)),
+        ("number?", [arg]) => Some(numberp(s, arg)),
         ("pair?", [arg]) => Some(pairp(s, arg)),
+        ("peek-char", []) => Some(io(s, "rt-peek-char", &[Expr::Literal(Nil)])),
+        ("peek-char", [port]) => Some(io(s, "rt-peek-char", std::slice::from_ref(port))),
+        ("%wait", [key]) => Some(threads::switch(s, key)),
+        (symbols::REGISTER, []) => Some(symbols::register(s)),
+        ("process-output", [prog, args]) => Some(process::run(s, "process-output", prog, args)),
+        ("process-run", [prog]) => Some(process::run(s, "process-run", prog, &Expr::Literal(Nil))),
+        ("process-run", [prog, args]) => Some(process::run(s, "process-run", prog, args)),
+        ("register-finalizer", [obj, Expr::Identifier(f)]) => Some(gc::register(s, obj, f)),
+        ("rt-open-read", [path]) => Some(io(s, "rt-open-read", std::slice::from_ref(path))),
+        ("rt-open-write", [path]) => Some(io(s, "rt-open-write", std::slice::from_ref(path))),
+        ("raise", [obj]) => Some(exceptions::raise(s, obj)),
+        ("raise-continuable", [obj]) => Some(exceptions::raise_continuable(s, obj)),
+        ("set-car!", [pair, val]) => Some(set(s, "set-car!", pair, val, 0)),
+        ("set-cdr!", [pair, val]) => Some(set(s, "set-cdr!", pair, val, WORDSIZE)),
+        ("spawn", [Expr::Identifier(f), args @ ..]) => Some(threads::spawn(s, f, args)),
         ("string?", [arg]) => Some(stringp(s, arg)),
+        ("string->symbol", [x]) => Some(symbols::from_string(s, x)),
+        ("string-length", [arg]) => Some(string_length(s, arg)),
+        ("string-ref", [x, k]) => Some(string_ref(s, x, k)),
+        ("string-set!", [x, k, c]) => Some(string_set(s, x, k, c)),
+        ("symbol->string", [x]) => Some(symbols::to_string(s, x)),
         ("symbol?", [arg]) => Some(symbolp(s, arg)),
+        ("system", [cmd]) => Some(process::run(s, "system", cmd, &Expr::Literal(Nil))),
+        ("yield", []) => Some(threads::switch(s, &Expr::Literal(Nil))),
         ("zero?", [arg]) => Some(zerop(s, arg)),
+        ("list", args) => Some(list(s, args)),
+        ("map", [Expr::Identifier(f), list]) => Some(lambda::map(s, f, list)),
         ("vector", args) => Some(vector(s, args)),
+        ("vector?", [arg]) => Some(vectorp(s, arg)),
+        ("vector-length", [v]) => Some(vector_length(s, v)),
+        ("vector-ref", [v, i]) => Some(vector_ref(s, v, i)),
+        ("vector-set!", [v, i, val]) => Some(vector_set(s, v, i, val)),
+        ("read", []) => Some(io(s, "rt-read-datum", &[Expr::Literal(Nil)])),
+        ("read", [port]) => Some(io(s, "rt-read-datum", std::slice::from_ref(port))),
+        ("read-char", []) => Some(io(s, "rt-read-char", &[Expr::Literal(Nil)])),
+        ("read-char", [port]) => Some(io(s, "rt-read-char", std::slice::from_ref(port))),
+        ("write", [val]) => Some(io(s, "rt-write-datum", &[val.clone(), Expr::Literal(Nil)])),
+        ("write", [val, port]) => Some(io(s, "rt-write-datum", &[val.clone(), port.clone()])),
+        ("write-char", [c]) => Some(io(s, "rt-write-char", &[c.clone(), Expr::Literal(Nil)])),
+        ("write-char", [c, port]) => Some(io(s, "rt-write-char", &[c.clone(), port.clone()])),
+        ("write-string", [val]) => {
+            Some(io(s, "rt-write-string", &[val.clone(), Expr::Literal(Nil)]))
+        }
+        ("write-string", [val, port]) => {
+            Some(io(s, "rt-write-string", &[val.clone(), port.clone()]))
+        }
+        ("with-exception-handler", [Expr::Identifier(handler), Expr::Identifier(thunk)]) => {
+            Some(exceptions::with(s, handler, thunk))
+        }
+        ("%guard", [Expr::Identifier(var), body, handler]) => {
+            Some(exceptions::guard(s, var, body, handler))
+        }
         _ => None,
     }
 }
 
+/// Whether [call] implements a primitive applied to these arguments
+///
+/// A call it doesn't implement is compiled as a call to a function of the same
+/// name, which fails to link unless the program defines one. Names bound by the
+/// program aren't primitives, see [Ident::free].
+pub fn defined(fname: &Ident, args: &[Core]) -> bool {
+    use Expr::Identifier as Id;
+
+    let name = match fname.free() {
+        Some(name) => name,
+        None => return false,
+    };
+
+    match (name, args) {
+        ("%foreign-call", [Expr::Literal(Str(_)), Expr::Literal(Number(_)), ..]) => true,
+        ("%guard", [Id(_), _, _]) => true,
+        (coverage::HIT, [Expr::Literal(Number(_)), ..]) => true,
+        ("+" | "*", _) => true,
+        ("apply", [Id(_), .., _]) => true,
+        ("call/cc", [Id(_)]) | ("call-with-current-continuation", [Id(_)]) => true,
+        ("dynamic-wind", [Id(_), Id(_), Id(_)]) => true,
+        ("register-finalizer", [_, Id(_)]) => true,
+        ("rt-open-read" | "rt-open-write", [_]) => true,
+        ("list", _) => true,
+        ("map", [Id(_), _]) => true,
+        ("spawn", [Id(_), ..]) => true,
+        ("vector", _) => true,
+        ("with-exception-handler", [Id(_), Id(_)]) => true,
+        (name, args) => ARITIES.contains(&(name, args.len())),
+    }
+}
+
+/// The identity of a primitive taking any number of arguments, like `+`
+///
+/// Applied to more or less than two, the primitive is folded over them
+/// starting from the identity; see [fold]. It can be passed to `apply` too,
+/// which folds it over the list at run time.
+pub fn variadic(f: &Ident) -> Option<i64> {
+    match f.free()? {
+        "+" => Some(0),
+        "*" => Some(1),
+        _ => None,
+    }
+}
+
+/// Names of the primitives, in order
+///
+/// Names starting with `%` are left out, they are for the compiler and not for
+/// programs to call.
+pub fn names() -> Vec<&'static str> {
+    let special = [
+        "apply",
+        "call/cc",
+        "call-with-current-continuation",
+        "dynamic-wind",
+        "list",
+        "map",
+        "register-finalizer",
+        "spawn",
+        "vector",
+        "with-exception-handler",
+    ];
+
+    let mut names: Vec<&str> = ARITIES
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(special.iter().copied())
+        .filter(|name| *name == "%" || !name.starts_with('%'))
+        .collect();
+
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+/// Primitives taking a fixed number of arguments of any kind, see [defined]
+const ARITIES: &[(&str, usize)] = &[
+    ("%", 2),
+    ("%gc-stat", 1),
+    ("%wait", 1),
+    ("*", 2),
+    ("+", 2),
+    ("-", 2),
+    ("/", 2),
+    ("<", 2),
+    ("<=", 2),
+    ("=", 2),
+    (">", 2),
+    (">=", 2),
+    ("boolean?", 1),
+    ("car", 1),
+    ("cdr", 1),
+    ("char->integer", 1),
+    ("char<=?", 2),
+    ("char<?", 2),
+    ("char=?", 2),
+    ("char>=?", 2),
+    ("char>?", 2),
+    ("char?", 1),
+    ("close-input-port", 1),
+    ("close-output-port", 1),
+    ("close-port", 1),
+    ("command-line", 0),
+    ("cons", 2),
+    ("dec", 1),
+    ("display", 1),
+    ("display", 2),
+    ("eval", 2),
+    ("exact->inexact", 1),
+    ("fixnum?", 1),
+    ("flonum?", 1),
+    ("gc", 0),
+    ("get-environment-variable", 1),
+    ("get-output-string", 1),
+    ("inc", 1),
+    ("inexact->exact", 1),
+    ("integer->char", 1),
+    ("make-string", 1),
+    ("make-string", 2),
+    ("make-vector", 1),
+    ("make-vector", 2),
+    ("newline", 0),
+    ("newline", 1),
+    ("not", 1),
+    ("null?", 1),
+    ("number?", 1),
+    ("pair?", 1),
+    ("peek-char", 0),
+    ("peek-char", 1),
+    ("process-output", 2),
+    ("process-run", 1),
+    ("process-run", 2),
+    ("raise", 1),
+    ("raise-continuable", 1),
+    ("read", 0),
+    ("read", 1),
+    ("read-char", 0),
+    ("read-char", 1),
+    ("set-car!", 2),
+    ("set-cdr!", 2),
+    ("string->symbol", 1),
+    ("string-length", 1),
+    ("string-ref", 2),
+    ("string-set!", 3),
+    ("string?", 1),
+    ("symbol->string", 1),
+    ("symbol?", 1),
+    ("system", 1),
+    ("vector-length", 1),
+    ("vector-ref", 2),
+    ("vector-set!", 3),
+    ("vector?", 1),
+    ("write", 1),
+    ("write", 2),
+    ("write-char", 1),
+    ("write-char", 2),
+    ("write-string", 1),
+    ("write-string", 2),
+    ("yield", 0),
+    ("zero?", 1),
+];
+
+/// Primitives that check the types of their arguments
+///
+/// Generated code identifies the primitive with its index here when reporting
+/// a type error, see [rt_type_error](crate::rt::rt_type_error).
+pub const CHECKED: &[&str] = &[
+    "%",
+    "*",
+    "+",
+    "-",
+    "/",
+    "<",
+    "<=",
+    "=",
+    ">",
+    ">=",
+    "apply",
+    "car",
+    "cdr",
+    "char->integer",
+    "char<=?",
+    "char<?",
+    "char=?",
+    "char>=?",
+    "char>?",
+    "dec",
+    "exact->inexact",
+    "get-environment-variable",
+    "inc",
+    "inexact->exact",
+    "integer->char",
+    "make-string",
+    "make-vector",
+    "map",
+    "process-output",
+    "process-run",
+    "set-car!",
+    "set-cdr!",
+    "string->symbol",
+    "string-length",
+    "string-ref",
+    "string-set!",
+    "symbol->string",
+    "system",
+    "vector-length",
+    "vector-ref",
+    "vector-set!",
+];
+
+/// Read or write an optional port with a function from the runtime, see
+/// [rt::io](crate::rt::io)
+///
+/// The port defaults to the standard input or output if it is `()`. A port the
+/// runtime [failed](rt::FAILED) to use is raised as an error.
+fn io(s: &mut State, name: &str, args: &[Core]) -> ASM {
+    let ok = s.gen_label("io");
+
+    ffi::call(s, &Ident::new(name), args)
+        + x86::cmp(RAX.into(), rt::FAILED.into())
+        + x86::jne(&ok)
+        + ffi::runtime(s, "rt_io_error")
+        + exceptions::fail(s)
+        + x86::label(&ok)
+}
+
+/// Index of a primitive in [CHECKED]
+pub fn checked(primitive: &str) -> i64 {
+    CHECKED
+        .iter()
+        .position(|p| *p == primitive)
+        .unwrap_or_else(|| panic!("`{}` is not a checked primitive", primitive)) as i64
+}
+
+/// Ensure the value in RAX has the type `tag` expected by `primitive`
+pub fn check(s: &mut State, primitive: &str, tag: i64) -> ASM {
+    let ok = s.gen_label("check");
+
+    let mut asm = tags::load_tag(R11, RAX)
+        + x86::cmp(R11.into(), tag.into());
+
+    // Boxed numbers are string objects as well
+    if tag == immediate::STR {
+        let error = s.gen_label("error");
+        asm += x86::jne(&error);
+        asm += flags();
+        asm += x86::cmp(R11.into(), Const(0));
+        asm += x86::je(&ok);
+        asm += x86::label(&error);
+    } else {
+        asm += x86::je(&ok);
+    }
+
+    asm + type_error(s, primitive, tag) + x86::label(&ok)
+}
+
+/// Ensure the value at stack index `slot` is a number
+fn number(s: &mut State, primitive: &str, slot: i64) -> ASM {
+    let (ok, error) = (s.gen_label("number"), s.gen_label("error"));
+
+    x86::mov(RAX.into(), Reference::from(RBP + slot))
+        + tags::load_tag(R11, RAX)
+        + x86::cmp(R11.into(), immediate::NUM.into())
+        + x86::je(&ok)
+        + x86::cmp(R11.into(), immediate::STR.into())
+        + x86::jne(&error)
+        + flags()
+        + x86::cmp(R11.into(), Const(0))
+        + x86::jne(&ok)
+        + x86::label(&error)
+        + type_error(s, primitive, immediate::NUM)
+        + x86::label(&ok)
+}
+
+/// Load the kind of the string object in RAX into R11
+///
+/// The result is 1 for a flonum, 2 for a bignum and 0 for any string; see
+/// [numbers](crate::numbers).
+fn flags() -> ASM {
+    x86::mov(R11.into(), Reference::from(RAX - immediate::STR))
+        + x86::sar(R11.into(), Const(numbers::FLONUM.trailing_zeros().into()))
+        + x86::and(R11.into(), Const(3))
+}
+
+/// Raise the value in RAX as the wrong type for `primitive`
+pub fn type_error(s: &mut State, primitive: &str, tag: i64) -> ASM {
+    x86::mov(RDI.into(), checked(primitive).into())
+        + x86::mov(RSI.into(), tag.into())
+        + x86::mov(RDX.into(), RAX.into())
+        + ffi::runtime(s, "rt_type_error")
+        + exceptions::fail(s)
+}
+
+/// Raise an `index` out of the range of `val`, see
+/// [rt_range_error](crate::rt::rt_range_error)
+fn range_error(s: &mut State, primitive: &str, index: Reference, val: Reference) -> ASM {
+    x86::mov(RSI.into(), index)
+        + x86::mov(RDX.into(), val)
+        + x86::mov(RDI.into(), checked(primitive).into())
+        + ffi::runtime(s, "rt_range_error")
+        + exceptions::fail(s)
+}
+
 // Unary Primitives
 
 /// Increment number by 1
 fn inc(s: &mut State, x: &Core) -> ASM {
-    eval(s, x) + x86::add(RAX.into(), immediate::n(1).into())
+    arithmetic(s, "inc", x, &Expr::Literal(Number(1)), |a, b, slow| {
+        x86::mov(RAX.into(), a) + x86::add(RAX.into(), b) + x86::jo(slow)
+    })
 }
 
 /// Decrement by 1
 fn dec(s: &mut State, x: &Core) -> ASM {
-    eval(s, x) + x86::sub(RAX.into(), immediate::n(1).into())
+    arithmetic(s, "dec", x, &Expr::Literal(Number(1)), |a, b, slow| {
+        x86::mov(RAX.into(), a) + x86::sub(RAX.into(), b) + x86::jo(slow)
+    })
+}
+
+/// Convert a number between exact and inexact with the runtime
+///
+/// Exact integers become the nearest flonum and integral flonums become exact
+/// integers, see [numbers](crate::numbers).
+fn convert(s: &mut State, name: &str, x: &Core) -> ASM {
+    let bp = s.si;
+    let mut asm = eval(s, x);
+    let a = s.alloc();
+    asm += x86::save(RAX.into(), a);
+    asm += number(s, name, a);
+    asm += boxed(s, name, a, a);
+
+    s.dealloc(1);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
 }
 
 /// Is the expression a fixnum?
@@ -75,93 +495,213 @@ fn dec(s: &mut State, x: &Core) -> ASM {
 /// (fixnum? "hello") => #f
 /// ```
 fn fixnump(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::NUM.into(), "sete")
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::NUM.into(), Condition::E)
 }
 
 /// Is the expression a boolean?
 fn booleanp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::BOOL.into(), "sete")
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::BOOL.into(), Condition::E)
 }
 
 /// Is the expression a char?
 fn charp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::CHAR.into(), "sete")
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::CHAR.into(), Condition::E)
 }
 
 /// Is the expression null?
 fn nullp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + compare(RAX.into(), immediate::NIL.into(), "sete")
+    eval(s, expr) + compare(RAX.into(), immediate::NIL.into(), Condition::E)
 }
 
 /// Is the expression a pair?
 fn pairp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::PAIR.into(), "sete")
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::PAIR.into(), Condition::E)
 }
 
 /// Is the expression a string?
 fn stringp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::STR.into(), "sete")
+    let (other, done) = (s.gen_label("other"), s.gen_label("done"));
+
+    eval(s, expr)
+        + tags::load_tag(R11, RAX)
+        + x86::cmp(R11.into(), immediate::STR.into())
+        + x86::jne(&other)
+        + flags()
+        + compare(R11.into(), Const(0), Condition::E)
+        + x86::jmp(&done)
+        + x86::label(&other)
+        + x86::mov(RAX.into(), immediate::FALSE.into())
+        + x86::label(&done)
+}
+
+/// Is the expression a flonum?
+fn flonump(s: &mut State, expr: &Core) -> ASM {
+    let (other, done) = (s.gen_label("other"), s.gen_label("done"));
+
+    eval(s, expr)
+        + tags::load_tag(R11, RAX)
+        + x86::cmp(R11.into(), immediate::STR.into())
+        + x86::jne(&other)
+        + flags()
+        + compare(R11.into(), Const(1), Condition::E)
+        + x86::jmp(&done)
+        + x86::label(&other)
+        + x86::mov(RAX.into(), immediate::FALSE.into())
+        + x86::label(&done)
+}
+
+/// Is the expression a number of any kind?
+fn numberp(s: &mut State, expr: &Core) -> ASM {
+    let (fixnum, other, done) = (s.gen_label("fixnum"), s.gen_label("other"), s.gen_label("done"));
+
+    eval(s, expr)
+        + tags::load_tag(R11, RAX)
+        + x86::cmp(R11.into(), immediate::NUM.into())
+        + x86::je(&fixnum)
+        + x86::cmp(R11.into(), immediate::STR.into())
+        + x86::jne(&other)
+        + flags()
+        + compare(R11.into(), Const(0), Condition::NE)
+        + x86::jmp(&done)
+        + x86::label(&fixnum)
+        + x86::mov(RAX.into(), immediate::TRUE.into())
+        + x86::jmp(&done)
+        + x86::label(&other)
+        + x86::mov(RAX.into(), immediate::FALSE.into())
+        + x86::label(&done)
+}
+
+/// Is the expression a vector?
+fn vectorp(s: &mut State, expr: &Core) -> ASM {
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::VEC.into(), Condition::E)
 }
 
 /// Is the expression a symbol?
 fn symbolp(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + mask() + compare(RAX.into(), immediate::SYM.into(), "sete")
+    eval(s, expr) + mask() + compare(RAX.into(), immediate::SYM.into(), Condition::E)
 }
 
 /// Is the expression zero?
 fn zerop(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + compare(RAX.into(), immediate::NUM.into(), "sete")
+    eval(s, expr) + compare(RAX.into(), immediate::NUM.into(), Condition::E)
 }
 
 /// Logical not
 fn not(s: &mut State, expr: &Core) -> ASM {
-    eval(s, expr) + compare(RAX.into(), immediate::FALSE.into(), "sete")
+    eval(s, expr) + compare(RAX.into(), immediate::FALSE.into(), Condition::E)
 }
 
 // Binary Primitives
 
-/// Evaluate arguments and store the first argument in stack and second in `RAX`
-fn binop(s: &mut State, x: &Core, y: &Core) -> ASM {
-    let t = s.alloc();
-    let ctx = eval(s, x) + x86::save(RAX.into(), t) + eval(s, y);
+/// Evaluate numeric arguments into two new stack slots and jump to `slow`
+/// unless both are fixnums
+///
+/// The caller deallocates the slots.
+fn binop(s: &mut State, x: &Core, y: &Core, slow: &str) -> (ASM, i64, i64) {
+    let mut asm = eval(s, x);
+    let a = s.alloc();
+    asm += x86::save(RAX.into(), a);
+    asm += eval(s, y);
+    let b = s.alloc();
+    asm += x86::save(RAX.into(), b);
+
+    // The tag of `x | y` is 0 only if both are fixnums
+    asm += x86::mov(R11.into(), Reference::from(RBP + a));
+    asm += x86::or(R11.into(), RAX.into());
+    asm += tags::load_tag(R11, R11);
+    asm += x86::cmp(R11.into(), immediate::NUM.into());
+    asm += x86::jne(slow);
+
+    (asm, a, b)
+}
+
+/// Apply an arithmetic primitive to `x` and `y` and move the result to RAX
+///
+/// `op` computes the result of fixnums at `a` and `b` in RAX and jumps to the
+/// `slow` label if it doesn't fit. Bignums, flonums and overflows are handled
+/// by the runtime, see [numbers](crate::numbers).
+fn arithmetic<F>(s: &mut State, name: &str, x: &Core, y: &Core, op: F) -> ASM
+where
+    F: Fn(Reference, Reference, &str) -> ASM,
+{
+    let bp = s.si;
+    let (slow, done) = (s.gen_label("slow"), s.gen_label("done"));
+    let (mut asm, a, b) = binop(s, x, y, &slow);
+
+    asm += op(Reference::from(RBP + a), Reference::from(RBP + b), &slow);
+    asm += x86::jmp(&done);
+
+    asm += x86::label(&slow);
+    asm += number(s, name, a);
+    asm += number(s, name, b);
+    asm += boxed(s, name, a, b);
+    asm += x86::label(&done);
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Apply `name` to the numbers at `a` and `b` with the runtime
+fn boxed(s: &mut State, name: &str, a: i64, b: i64) -> ASM {
+    // The size is a multiple of 8 and looks like a fixnum to the collector
+    let size = s.alloc();
+    let asm = numeric(s, "rt_arithmetic_size", name, a, b)
+        + x86::save(RAX.into(), size)
+        + gc::alloc(s, Reference::from(RBP + size))
+        + numeric(s, "rt_arithmetic", name, a, b);
+
     s.dealloc(1);
-    ctx
+    asm
+}
+
+/// Call a function from the runtime with the primitive and numeric arguments
+fn numeric(s: &mut State, f: &str, name: &str, a: i64, b: i64) -> ASM {
+    x86::mov(RDI.into(), checked(name).into())
+        + x86::mov(RSI.into(), Reference::from(RBP + a))
+        + x86::mov(RDX.into(), Reference::from(RBP + b))
+        + ffi::runtime(s, f)
+}
+
+/// Apply a [variadic] primitive to the arguments two at a time, left to right
+fn fold(s: &mut State, f: &Ident, args: &[Core]) -> ASM {
+    let identity = Expr::Literal(Number(variadic(f).unwrap_or_default()));
+
+    let mut args = args.iter().cloned();
+    let first = if args.len() == 1 { identity } else { args.next().unwrap_or(identity) };
+    let expr = args.fold(first, |acc, x| Expr::List(vec![Expr::Identifier(f.clone()), acc, x]));
+
+    eval(s, &expr)
 }
 
 /// Add `x` and `y` and move result to register RAX
 fn plus(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, &x, &y) + x86::add(RAX.into(), Reference::from(RBP + s.si))
+    arithmetic(s, "+", x, y, |a, b, slow| {
+        x86::mov(RAX.into(), a) + x86::add(RAX.into(), b) + x86::jo(slow)
+    })
 }
 
-/// Subtract `x` from `y` and move result to register RAX
-// `sub` subtracts the 2nd op from the first and stores the result in the 1st.
-//
-// Since binop evaluates x first and then y, this is a little clumsy. A
-// temporary register RDI is used to swap the arguments before subtracting in
-// right order.
-//
-//     x: [RBP - 8], y: RAX
-//     y: RAX -> RDI
-//     x: [RBP - 8] -> RAX
-//     RAX  = RAX (x) - RDI (y)
+/// Subtract `y` from `x` and move result to register RAX
 fn minus(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, &x, &y)
-        + x86::mov(RDI.into(), RAX.into())
-        + x86::mov(RAX.into(), Reference::from(RBP + s.si))
-        + x86::sub(RAX.into(), RDI.into())
+    arithmetic(s, "-", x, y, |a, b, slow| {
+        x86::mov(RAX.into(), a) + x86::sub(RAX.into(), b) + x86::jo(slow)
+    })
 }
 
 /// Multiply `x` and `y` and move result to register RAX
-// The destination operand is of `mul` is an implied operand located in register
-// AX. GCC throws `Error: ambiguous operand size for `mul'` without size
-// quantifier
+// Only one of the operands is shifted, which keeps the tag of the product 0.
+// `imul` sets the overflow flag if the 128 bit product in RDX:RAX doesn't fit
+// in RAX, which is exactly when the product doesn't fit in a fixnum.
 fn mul(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, &x, &y)
-        + x86::sar(RAX.into(), immediate::SHIFT.into())
-        + x86::mul(Reference::from(RBP + s.si))
+    arithmetic(s, "*", x, y, |a, b, slow| {
+        x86::mov(RAX.into(), a)
+            + tags::to_int(RAX)
+            + x86::imul(b)
+            + x86::jo(slow)
+    })
 }
 
-/// Divide `x` by `y` and move result to register RAX
+/// Divide `x` by `y` and move the quotient or the remainder in `result` to RAX
 // Division turned out to be much more trickier than I expected it to be.
 // Unlike @namin's code, I'm using a shift arithmetic right (SAR) instead of
 // shift logical right (SHR) and I don't know how the original examples worked
@@ -171,25 +711,66 @@ fn mul(s: &mut State, x: &Core, y: &Core) -> ASM {
 //
 // Dividend is passed in RDX:RAX and IDIV instruction takes the divisor as the
 // argument. the quotient is stored in RAX and the remainder in RDX.
-fn div(s: &mut State, x: &Core, y: &Core) -> ASM {
-    eval(s, y)
-        + x86::sar(RAX.into(), immediate::SHIFT.into())
-        + x86::mov(RCX.into(), RAX.into())
-        + eval(s, x)
-        + x86::sar(RAX.into(), immediate::SHIFT.into())
-        + x86::mov(RDX.into(), 0.into())
-        + Ins::from("cqo")
-        + Ins::from("idiv rcx")
+//
+// IDIV traps on a zero divisor, which is raised as an error before getting
+// there, and before dividing a bignum by it as well. The smallest fixnum
+// divided by -1 is the only quotient that doesn't fit in a fixnum and is left
+// to the runtime.
+fn div(s: &mut State, name: &str, x: &Core, y: &Core, result: x86::Register) -> ASM {
+    let bp = s.si;
+    let (slow, zero, done) = (s.gen_label("slow"), s.gen_label("zero"), s.gen_label("done"));
+    let (mut asm, a, b) = binop(s, x, y, &slow);
+
+    // The divisor is still in RAX
+    asm += x86::cmp(RAX.into(), immediate::NUM.into());
+    asm += x86::je(&zero);
+    asm += x86::mov(RAX.into(), Reference::from(RBP + a));
+    asm += tags::to_int(RAX);
+    asm += x86::mov(RCX.into(), Reference::from(RBP + b));
+    asm += tags::to_int(RCX);
+    asm += x86::cqo();
+    asm += x86::idiv(RCX);
+
+    if result != RAX {
+        asm += x86::mov(RAX.into(), result.into());
+    }
+
+    // The result is a fixnum only if tagging it loses none of its bits
+    asm += x86::mov(R11.into(), RAX.into());
+    asm += tags::to_fixnum(R11);
+    asm += tags::to_int(R11);
+    asm += x86::cmp(R11.into(), RAX.into());
+    asm += x86::jne(&slow);
+    asm += tags::to_fixnum(RAX);
+    asm += x86::jmp(&done);
+
+    asm += x86::label(&slow);
+    asm += number(s, name, a);
+    asm += number(s, name, b);
+    asm += x86::cmp(RAX.into(), immediate::NUM.into());
+    asm += x86::je(&zero);
+    asm += boxed(s, name, a, b);
+    asm += x86::jmp(&done);
+
+    asm += x86::label(&zero);
+    asm += x86::mov(RDI.into(), checked(name).into());
+    asm += ffi::runtime(s, "rt_division_error");
+    asm += exceptions::fail(s);
+    asm += x86::label(&done);
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
 }
 
 /// Quotient after dividing `x` by `y`
 fn quotient(s: &mut State, x: &Core, y: &Core) -> ASM {
-    div(s, x, y) + x86::sal(RAX.into(), immediate::SHIFT.into())
+    div(s, "/", x, y, RAX)
 }
 
 /// Remainder after dividing `x` by `y`
 fn remainder(s: &mut State, x: &Core, y: &Core) -> ASM {
-    div(s, x, y) + x86::mov(RAX.into(), RDX.into()) + x86::sal(RAX.into(), immediate::SHIFT.into())
+    div(s, "%", x, y, RDX)
 }
 
 /// Compares the first operand with the second with `SETcc`
@@ -200,37 +781,98 @@ fn remainder(s: &mut State, x: &Core, y: &Core) -> ASM {
 //
 // `MOVZX` copies the contents of the source operand (register or memory
 // location) to the destination operand (register) and zero extends the value.
-fn compare(a: Reference, b: Reference, setcc: &str) -> ASM {
+fn compare(a: Reference, b: Reference, setcc: Condition) -> ASM {
     x86::cmp(a, b)
-        + Ins(format!("{} al", setcc))
-        + Ins::from("movzx rax, al")
-        + Ins(format!("sal al, {}", immediate::SHIFT))
-        + Ins(format!("or al, {}", immediate::BOOL))
+        + x86::set(setcc, AL)
+        + x86::movzx(RAX, AL)
+        + x86::sal(AL.into(), immediate::SHIFT.into())
+        + x86::or(AL.into(), immediate::BOOL.into())
+}
+
+/// Compare `x` and `y` with a comparison primitive
+///
+/// Fixnums are compared inline and anything else by the runtime.
+fn comparison(s: &mut State, name: &str, x: &Core, y: &Core, setcc: Condition) -> ASM {
+    let bp = s.si;
+    let (slow, done) = (s.gen_label("slow"), s.gen_label("done"));
+    let (mut asm, a, b) = binop(s, x, y, &slow);
+
+    asm += compare(Reference::from(RBP + a), RAX.into(), setcc);
+    asm += x86::jmp(&done);
+
+    asm += x86::label(&slow);
+    asm += number(s, name, a);
+    asm += number(s, name, b);
+    asm += numeric(s, "rt_compare", name, a, b);
+    asm += x86::label(&done);
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
 }
 
 /// Logical eq
 fn eq(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, x, y) + compare(Reference::from(RBP + s.si), RAX.into(), "sete")
+    comparison(s, "=", x, y, Condition::E)
 }
 
 /// Logical <
 fn lt(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, x, y) + compare(Reference::from(RBP + s.si), RAX.into(), "setl")
+    comparison(s, "<", x, y, Condition::L)
 }
 
 /// Logical >
 fn gt(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, x, y) + compare(Reference::from(RBP + s.si), RAX.into(), "setg")
+    comparison(s, ">", x, y, Condition::G)
 }
 
 /// Logical <=
 fn lte(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, x, y) + compare(Reference::from(RBP + s.si), RAX.into(), "setle")
+    comparison(s, "<=", x, y, Condition::LE)
 }
 
 /// Logical >=
 fn gte(s: &mut State, x: &Core, y: &Core) -> ASM {
-    binop(s, x, y) + compare(Reference::from(RBP + s.si), RAX.into(), "setge")
+    comparison(s, ">=", x, y, Condition::GE)
+}
+
+// Characters
+
+/// The code of a character as a fixnum
+// A character is its code shifted left past the tag, so dropping the tag
+// leaves the same code as a fixnum.
+fn char_integer(s: &mut State, c: &Core) -> ASM {
+    eval(s, c)
+        + check(s, "char->integer", immediate::CHAR)
+        + x86::sub(RAX.into(), immediate::CHAR.into())
+}
+
+/// The character with the code `n`, which must be between 0 and 255
+fn integer_char(s: &mut State, n: &Core) -> ASM {
+    let ok = s.gen_label("char");
+
+    eval(s, n)
+        + check(s, "integer->char", immediate::NUM)
+        + x86::cmp(RAX.into(), Const(256 << immediate::SHIFT))
+        + x86::jb(&ok)
+        + range_error(s, "integer->char", RAX.into(), immediate::NIL.into())
+        + x86::label(&ok)
+        + x86::add(RAX.into(), immediate::CHAR.into())
+}
+
+/// Compare two characters by their codes with `SETcc`
+fn char_comparison(s: &mut State, name: &str, x: &Core, y: &Core, setcc: Condition) -> ASM {
+    let bp = s.si;
+    let mut asm = eval(s, x) + check(s, name, immediate::CHAR);
+    let a = s.alloc();
+    asm += x86::save(RAX.into(), a);
+    asm += eval(s, y);
+    asm += check(s, name, immediate::CHAR);
+    asm += compare(Reference::from(RBP + a), RAX.into(), setcc);
+
+    s.dealloc(1);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
 }
 
 // Allocation primitives
@@ -239,25 +881,33 @@ fn gte(s: &mut State, x: &Core, y: &Core) -> ASM {
 #[allow(clippy::identity_op)]
 fn cons(s: &mut State, x: &Core, y: &Core) -> ASM {
     // 1. Evaluate the first argument and push to stack
-    // 2. Evaluate second argument
-    // 3. Write second arg to [heap + 8]
-    // 4. Fetch first argument back to RAX
-    // 5. Write first arg from RAX to [heap + 0]
-    // 6. Deallocate a word used for first arg
+    // 2. Evaluate second argument and push to stack
+    // 3. Make space for a pair, which could trigger a collection
+    // 4. Fetch both arguments back from stack and write to [heap + 0] and
+    //    [heap + 8]
+    // 5. Deallocate the words used for the arguments
+    //
+    // Values must be in the stack and not registers when allocating, see
+    // `gc` for details.
     let bp = s.si;
-    let scratch = s.alloc();
-    let ctx = Ins(format!("# (cons {} {})", x, y))
-        + eval(s, x)
-        + x86::save(RAX.into(), scratch)
-        + eval(s, y)
-        + x86::mov(Reference::from(R12 + 8), RAX.into())
-        + x86::mov(RAX.into(), Reference::from(RBP + scratch))
+    let mut ctx = x86::comment(&format!("(cons {} {})", x, y)) + eval(s, x);
+    let car = s.alloc();
+    ctx += x86::save(RAX.into(), car);
+    ctx += eval(s, y);
+    let cdr = s.alloc();
+    ctx += x86::save(RAX.into(), cdr);
+
+    let ctx = ctx
+        + gc::alloc(s, Const(WORDSIZE * 2))
+        + x86::mov(RAX.into(), Reference::from(RBP + car))
         + x86::mov(Reference::from(R12 + 0), RAX.into())
+        + x86::mov(RAX.into(), Reference::from(RBP + cdr))
+        + x86::mov(Reference::from(R12 + 8), RAX.into())
         + x86::mov(RAX.into(), R12.into())
         + x86::add(R12.into(), Reference::from(WORDSIZE * 2))
         + x86::or(RAX.into(), immediate::PAIR.into());
 
-    s.dealloc(1);
+    s.dealloc(2);
     assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
     ctx
 }
@@ -265,37 +915,349 @@ fn cons(s: &mut State, x: &Core, y: &Core) -> ASM {
 /// First half of a pair
 // Subtracting the tag from the heap pointer gets us back the real address.
 fn car(s: &mut State, pair: &Core) -> ASM {
-    // Assert destination is really a pair ?
-    eval(s, pair) + Ins(format!("mov rax, [rax - {}]    # (car ..)", immediate::PAIR))
+    eval(s, pair)
+        + x86::comment("(car ..)")
+        + check(s, "car", immediate::PAIR)
+        + x86::mov(RAX.into(), Reference::from(RAX - immediate::PAIR))
 }
 
 /// Second half of a pair
 // Offset for cdr is (address - tag + 8) = 5
 fn cdr(s: &mut State, pair: &Core) -> ASM {
-    // Assert destination is really a pair ?
-    eval(s, pair) + Ins(format!("mov rax, [rax + {}]    # (cdr ...)", 5))
+    eval(s, pair)
+        + x86::comment("(cdr ...)")
+        + check(s, "cdr", immediate::PAIR)
+        + x86::mov(RAX.into(), Reference::from(RAX + 5))
+}
+
+/// Replace one half of a pair, at `offset` from the start
+fn set(s: &mut State, name: &str, pair: &Core, val: &Core, offset: i64) -> ASM {
+    let bp = s.si;
+    let mut ctx = x86::comment(&format!("({} {} {})", name, pair, val)) + eval(s, pair);
+    ctx += check(s, name, immediate::PAIR);
+    let p = s.alloc();
+    ctx += x86::save(RAX.into(), p);
+    ctx += eval(s, val);
+
+    // The updated slot could point from the old generation into the nursery,
+    // the write barrier takes care of it. See `gc` for details.
+    let ctx = ctx
+        + x86::mov(RDI.into(), Reference::from(RBP + p))
+        + x86::add(RDI.into(), Const(offset - immediate::PAIR))
+        + x86::mov(Reference::from(RDI + 0), RAX.into())
+        + gc::barrier(s)
+        + x86::mov(RAX.into(), immediate::NIL.into());
+
+    s.dealloc(1);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    ctx
+}
+
+/// Build a list of the values of the expressions out of pairs
+fn list(s: &mut State, exprs: &[Core]) -> ASM {
+    let list = exprs.iter().rev().fold(Expr::Literal(Nil), |rest, expr| {
+        Expr::List(vec![Expr::Identifier(Ident::new("cons")), expr.clone(), rest])
+    });
+
+    eval(s, &list)
 }
 
 /// Allocate a vector on heap
 // Allows `R12 + 0`, its not ineffective
 #[allow(clippy::identity_op)]
 fn vector(s: &mut State, exprs: &[Core]) -> ASM {
+    let mut asm = ASM::default();
+    let mut slots = vec![];
+
+    // Evaluate all the elements before allocating the vector; evaluating an
+    // element could allocate and trigger a collection.
+    for expr in exprs {
+        match immediate::to(expr) {
+            Some(c) => slots.push(Const(c)),
+            None => {
+                asm += eval(s, expr);
+                let slot = s.alloc();
+                asm += x86::save(RAX.into(), slot);
+                slots.push(Relative(RBP + slot));
+            }
+        }
+    }
+
     // Vectors are length prefixed like strings
-    let mut asm: ASM = x86::mov(Relative(R12 + 0), Const(exprs.len() as i64)).into();
+    let size = WORDSIZE * (exprs.len() + 1) as i64;
+    asm += gc::alloc(s, Const(size));
+    asm += x86::mov(Relative(R12 + 0), Const(exprs.len() as i64));
 
-    for (index, expr) in exprs.iter().enumerate() {
+    for (index, slot) in slots.iter().enumerate() {
         let dest = Relative(R12 + (WORDSIZE * (index + 1) as i64));
 
-        match immediate::to(expr) {
-            Some(c) => asm += x86::mov(dest, Reference::Const(c)),
-            None => asm += eval(s, expr) + x86::mov(dest, RAX.into()),
+        match slot {
+            Const(c) => asm += x86::mov(dest, Const(*c)),
+            _ => asm += x86::mov(RAX.into(), slot.clone()) + x86::mov(dest, RAX.into()),
         }
     }
 
+    s.dealloc(slots.iter().filter(|slot| matches!(slot, Relative(_))).count() as i64);
+
     asm = asm
         + x86::mov(RAX.into(), R12.into())
-        + x86::add(R12.into(), Const(WORDSIZE * exprs.len() as i64))
+        + x86::add(R12.into(), Const(size))
         + x86::or(RAX.into(), immediate::VEC.into());
 
     asm
 }
+
+/// Longest vector `make-vector` can allocate, a fixnum fits in a 32 bit
+/// immediate up to here
+const MAX_LENGTH: i64 = 1 << 27;
+
+/// Allocate a vector of `n` elements all set to `fill`
+fn make_vector(s: &mut State, n: &Core, fill: &Core) -> ASM {
+    let bp = s.si;
+    let fits = s.gen_label("fits");
+    let top = s.gen_label("fill");
+    let done = s.gen_label("filled");
+
+    let mut asm = x86::comment(&format!("(make-vector {} {})", n, fill)) + eval(s, n);
+    asm += check(s, "make-vector", immediate::NUM);
+
+    // Negative lengths are huge unsigned numbers and fail the same check
+    asm += x86::cmp(RAX.into(), immediate::n(MAX_LENGTH).into());
+    asm += x86::jb(&fits);
+    asm += range_error(s, "make-vector", RAX.into(), immediate::NIL.into());
+    asm += x86::label(&fits);
+
+    // A fixnum is the number times the word size, so adding a word for the
+    // length prefix makes the size of the vector in bytes. The size looks like
+    // a fixnum to the collector.
+    let size = s.alloc();
+    asm += x86::add(RAX.into(), WORDSIZE.into());
+    asm += x86::save(RAX.into(), size);
+    asm += eval(s, fill);
+    let value = s.alloc();
+    asm += x86::save(RAX.into(), value);
+
+    let asm = asm
+        + gc::alloc(s, Reference::from(RBP + size))
+        + x86::mov(RCX.into(), Reference::from(RBP + size))
+        + tags::to_int(RCX)
+        + x86::sub(RCX.into(), 1.into())
+        + x86::mov(Relative(R12 + 0), RCX.into())
+        + x86::mov(RDI.into(), R12.into())
+        + x86::mov(RAX.into(), Reference::from(RBP + value))
+        + x86::label(&top)
+        + x86::cmp(RCX.into(), 0.into())
+        + x86::je(&done)
+        + x86::add(RDI.into(), WORDSIZE.into())
+        + x86::mov(Relative(RDI + 0), RAX.into())
+        + x86::sub(RCX.into(), 1.into())
+        + x86::jmp(&top)
+        + x86::label(&done)
+        + x86::mov(RAX.into(), R12.into())
+        + x86::add(R12.into(), Reference::from(RBP + size))
+        + x86::or(RAX.into(), immediate::VEC.into());
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Number of elements in a vector
+fn vector_length(s: &mut State, v: &Core) -> ASM {
+    eval(s, v)
+        + check(s, "vector-length", immediate::VEC)
+        + x86::mov(RAX.into(), Reference::from(RAX - immediate::VEC))
+        + tags::to_fixnum(RAX)
+}
+
+/// Ensure the fixnum index in RAX is within the bounds of the vector or the
+/// string with `tag` at `slot`
+//
+// Negative indices are huge unsigned numbers, so a single unsigned comparison
+// with the length takes care of both ends.
+fn bounds(s: &mut State, primitive: &str, slot: i64, tag: i64) -> ASM {
+    let ok = s.gen_label("bounds");
+
+    x86::mov(R11.into(), Reference::from(RBP + slot))
+        + x86::mov(R11.into(), Reference::from(R11 - tag))
+        + tags::to_fixnum(R11)
+        + x86::cmp(RAX.into(), R11.into())
+        + x86::jb(&ok)
+        + range_error(s, primitive, RAX.into(), Reference::from(RBP + slot))
+        + x86::label(&ok)
+}
+
+/// The element of a vector at index `i`
+// A fixnum index is already scaled by the word size, the element is right
+// after the length at (address - tag + 8 + index).
+fn vector_ref(s: &mut State, v: &Core, i: &Core) -> ASM {
+    let bp = s.si;
+    let mut asm = x86::comment(&format!("(vector-ref {} {})", v, i)) + eval(s, v);
+    asm += check(s, "vector-ref", immediate::VEC);
+    let vec = s.alloc();
+    asm += x86::save(RAX.into(), vec);
+    asm += eval(s, i);
+    asm += check(s, "vector-ref", immediate::NUM);
+    asm += bounds(s, "vector-ref", vec, immediate::VEC);
+
+    let asm = asm
+        + x86::mov(R11.into(), Reference::from(RBP + vec))
+        + x86::add(R11.into(), RAX.into())
+        + x86::mov(RAX.into(), Reference::from(R11 + (WORDSIZE - immediate::VEC)));
+
+    s.dealloc(1);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Replace the element of a vector at index `i`
+fn vector_set(s: &mut State, v: &Core, i: &Core, val: &Core) -> ASM {
+    let bp = s.si;
+    let mut asm = x86::comment(&format!("(vector-set! {} {} {})", v, i, val)) + eval(s, v);
+    asm += check(s, "vector-set!", immediate::VEC);
+    let vec = s.alloc();
+    asm += x86::save(RAX.into(), vec);
+    asm += eval(s, i);
+    asm += check(s, "vector-set!", immediate::NUM);
+    asm += bounds(s, "vector-set!", vec, immediate::VEC);
+    let index = s.alloc();
+    asm += x86::save(RAX.into(), index);
+    asm += eval(s, val);
+
+    // Same as `set`, the write barrier takes care of old to young pointers
+    let asm = asm
+        + x86::mov(RDI.into(), Reference::from(RBP + vec))
+        + x86::add(RDI.into(), Reference::from(RBP + index))
+        + x86::add(RDI.into(), Const(WORDSIZE - immediate::VEC))
+        + x86::mov(Relative(RDI + 0), RAX.into())
+        + gc::barrier(s)
+        + x86::mov(RAX.into(), immediate::NIL.into());
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Allocate a string of `n` characters, all set to `fill` or NUL
+///
+/// Strings are length prefixed and NUL terminated and the heap is always
+/// zeroed, so a runtime call is required only to fill the string.
+fn make_string(s: &mut State, n: &Core, fill: Option<&Core>) -> ASM {
+    let bp = s.si;
+    let fits = s.gen_label("fits");
+
+    let mut asm = x86::comment("(make-string ..)") + eval(s, n);
+    asm += check(s, "make-string", immediate::NUM);
+    asm += x86::cmp(RAX.into(), immediate::n(MAX_LENGTH).into());
+    asm += x86::jb(&fits);
+    asm += range_error(s, "make-string", RAX.into(), immediate::NIL.into());
+    asm += x86::label(&fits);
+
+    let length = s.alloc();
+    asm += x86::save(RAX.into(), length);
+
+    // The size in bytes is `8 + (n + 1)` rounded up to a word, which is
+    // `(n + 16) & -8`. The size is a multiple of 8 and looks like a fixnum to
+    // the collector.
+    let size = s.alloc();
+    asm += tags::to_int(RAX);
+    asm += x86::add(RAX.into(), Const(16));
+    asm += x86::and(RAX.into(), Const(-8));
+    asm += x86::save(RAX.into(), size);
+
+    let value = fill.map(|fill| {
+        asm += eval(s, fill);
+        asm += check(s, "make-string", immediate::CHAR);
+        let value = s.alloc();
+        asm += x86::save(RAX.into(), value);
+        value
+    });
+
+    asm = asm
+        + gc::alloc(s, Reference::from(RBP + size))
+        + x86::mov(RAX.into(), Reference::from(RBP + length))
+        + tags::to_int(RAX)
+        + x86::mov(Relative(R12 + 0), RAX.into())
+        + x86::mov(RAX.into(), R12.into())
+        + x86::or(RAX.into(), immediate::STR.into())
+        + x86::add(R12.into(), Reference::from(RBP + size));
+
+    if let Some(value) = value {
+        asm += x86::mov(RDI.into(), RAX.into());
+        asm += x86::mov(RSI.into(), Reference::from(RBP + value));
+        asm += ffi::runtime(s, "rt_string_fill");
+        s.dealloc(1);
+    }
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Number of characters in a string
+// Shifting the length into a fixnum drops the immutable bit of literals
+fn string_length(s: &mut State, x: &Core) -> ASM {
+    eval(s, x)
+        + check(s, "string-length", immediate::STR)
+        + x86::mov(RAX.into(), Reference::from(RAX - immediate::STR))
+        + tags::to_fixnum(RAX)
+}
+
+/// The character of a string at index `k`
+fn string_ref(s: &mut State, x: &Core, k: &Core) -> ASM {
+    let bp = s.si;
+    let mut asm = x86::comment(&format!("(string-ref {} {})", x, k)) + eval(s, x);
+    asm += check(s, "string-ref", immediate::STR);
+    let string = s.alloc();
+    asm += x86::save(RAX.into(), string);
+    asm += eval(s, k);
+    asm += check(s, "string-ref", immediate::NUM);
+    asm += bounds(s, "string-ref", string, immediate::STR);
+
+    let asm = asm
+        + x86::mov(RSI.into(), RAX.into())
+        + x86::mov(RDI.into(), Reference::from(RBP + string))
+        + ffi::runtime(s, "rt_string_ref");
+
+    s.dealloc(1);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
+
+/// Replace the character of a string at index `k`, unless it is a literal
+fn string_set(s: &mut State, x: &Core, k: &Core, c: &Core) -> ASM {
+    let bp = s.si;
+    let mutable = s.gen_label("mutable");
+    let mut asm = x86::comment(&format!("(string-set! {} {} {})", x, k, c)) + eval(s, x);
+    asm += check(s, "string-set!", immediate::STR);
+    let string = s.alloc();
+    asm += x86::save(RAX.into(), string);
+
+    asm += x86::mov(R11.into(), Reference::from(RAX - immediate::STR));
+    asm += x86::sar(R11.into(), Const(strings::IMMUTABLE.trailing_zeros().into()));
+    asm += x86::cmp(R11.into(), Const(0));
+    asm += x86::je(&mutable);
+    asm += x86::mov(RSI.into(), RAX.into());
+    asm += x86::mov(RDI.into(), checked("string-set!").into());
+    asm += ffi::runtime(s, "rt_immutable_error");
+    asm += exceptions::fail(s);
+    asm += x86::label(&mutable);
+
+    asm += eval(s, k);
+    asm += check(s, "string-set!", immediate::NUM);
+    asm += bounds(s, "string-set!", string, immediate::STR);
+    let index = s.alloc();
+    asm += x86::save(RAX.into(), index);
+    asm += eval(s, c);
+    asm += check(s, "string-set!", immediate::CHAR);
+
+    let asm = asm
+        + x86::mov(RDX.into(), RAX.into())
+        + x86::mov(RSI.into(), Reference::from(RBP + index))
+        + x86::mov(RDI.into(), Reference::from(RBP + string))
+        + ffi::runtime(s, "rt_string_set");
+
+    s.dealloc(2);
+    assert!(s.si == bp, "Stack deallocated; expected {}, found {} ", bp, s.si);
+    asm
+}
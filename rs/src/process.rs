@@ -0,0 +1,328 @@
+//! The process running a program
+//!
+//! `(command-line)` evaluates to the arguments of the program as a list of
+//! strings, starting with the name of the program itself, and
+//! `(get-environment-variable name)` to the value of an environment variable
+//! as a string, or `#f` if it isn't set.
+//!
+//! ```scheme
+//! (let ((args (command-line)))
+//!   (if (null? (cdr args))
+//!       (get-environment-variable "HOME")
+//!       (car (cdr args))))
+//! ```
+//!
+//! `(system cmd)` runs a shell command and `(process-run prog args)` a program
+//! with a list of string arguments, both with the standard ports of the
+//! program and evaluating to the exit status once the command is done. A
+//! command killed by a signal exits with 128 plus the number of the signal,
+//! like in the shell. `(process-output prog args)` evaluates to everything the
+//! program wrote to its standard output as a string instead. A program that
+//! can't be started at all raises an error.
+//!
+//! ```scheme
+//! (let ((status (system "make")))
+//!   (if (zero? status)
+//!       (process-output "git" (cons "rev-parse" (cons "HEAD" ())))
+//!       status))
+//! ```
+//!
+//! `(current-jiffy)` evaluates to the microseconds elapsed since an arbitrary
+//! point that stays put while the program runs, and `(jiffies-per-second)` to
+//! the number of those in a second. `(time expr)` evaluates to the value of
+//! `expr` after reporting the wall time it took and the bytes it allocated on
+//! the standard error, which is all a quick benchmark needs.
+//!
+//! ```scheme
+//! (time (fib 30))
+//! ;; (time (fib 30)): 12.345ms elapsed, 0 bytes allocated
+//! ```
+//!
+//! The [entry point](crate::start) of an executable records the arguments
+//! before running the program, leaving out the options for the runtime itself
+//! like `--heap-size`. Programs run with the [jit](crate::jit) see the
+//! arguments of the current process unless they are set explicitly with
+//! [set_command_line].
+//!
+//! The results are allocated by the runtime, so the generated code asks for
+//! their size first and makes room for them, just like for the arithmetic
+//! primitives. See [gc](crate::gc). The output of a process is kept in the
+//! runtime till then.
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::Core,
+    exceptions::{self, Target},
+    ffi, gc,
+    immediate::*,
+    primitives,
+    rt::{self, car, cdr, Object},
+    tags::{self, tag, untag},
+    x86::{self, Reference, Register::*, ASM, WORDSIZE},
+};
+use std::{
+    cell::RefCell,
+    env,
+    ffi::{OsStr, OsString},
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
+    process::{Command, ExitStatus, Stdio},
+};
+
+/// Jiffies in a second
+const JIFFIES: i64 = 1_000_000;
+
+thread_local! {
+    /// Arguments of the program, if different from the current process
+    static COMMAND_LINE: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+
+    /// Output of the last `process-output`, see [rt_process_output]
+    static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+
+    /// The error of the last process that failed to start, see
+    /// [rt_process_error]
+    static ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Set the arguments seen by programs run on the current thread
+pub fn set_command_line(args: Vec<String>) {
+    COMMAND_LINE.with(|command| *command.borrow_mut() = Some(args))
+}
+
+/// Emit code for `(command-line)`
+pub fn command_line(s: &mut State) -> ASM {
+    let size = s.alloc();
+
+    let asm = ffi::runtime(s, "rt_command_line_size")
+        + x86::save(RAX.into(), size)
+        + gc::alloc(s, Reference::from(RBP + size))
+        + ffi::runtime(s, "rt_command_line");
+
+    s.dealloc(1);
+    asm
+}
+
+/// Emit code for `(get-environment-variable name)`
+pub fn environment(s: &mut State, name: &Core) -> ASM {
+    let mut asm = eval(s, name) + primitives::check(s, "get-environment-variable", STR);
+
+    let (name, size) = (s.alloc(), s.alloc());
+
+    asm += x86::save(RAX.into(), name);
+    asm += x86::mov(RDI.into(), Reference::from(RBP + name));
+    asm += ffi::runtime(s, "rt_environment_size");
+    asm += x86::save(RAX.into(), size);
+    asm += gc::alloc(s, Reference::from(RBP + size));
+    asm += x86::mov(RDI.into(), Reference::from(RBP + name));
+    asm += ffi::runtime(s, "rt_get_environment_variable");
+
+    s.dealloc(2);
+    asm
+}
+
+/// Emit code for `(system cmd)`, `(process-run prog args)` or
+/// `(process-output prog args)`
+///
+/// The arguments of `system` are always `()`.
+pub fn run(s: &mut State, primitive: &str, prog: &Core, args: &Core) -> ASM {
+    let ok = s.gen_label("process");
+
+    let mut asm = eval(s, prog) + primitives::check(s, primitive, STR);
+    let slot = s.alloc();
+    asm += x86::save(RAX.into(), slot);
+
+    asm += eval(s, args);
+    asm += x86::mov(RDX.into(), RAX.into());
+    asm += x86::mov(RSI.into(), Reference::from(RBP + slot));
+    asm += x86::mov(RDI.into(), primitives::checked(primitive).into());
+    asm += ffi::runtime(s, "rt_process_run");
+    asm += x86::cmp(RAX.into(), Reference::Const(-1));
+    asm += x86::jne(&ok);
+    asm += ffi::runtime(s, "rt_process_error");
+    asm += exceptions::fail(s);
+    asm += x86::label(&ok);
+
+    // The output is waiting in the runtime, RAX is its size
+    if primitive == "process-output" {
+        asm += x86::save(RAX.into(), slot);
+        asm += gc::alloc(s, Reference::from(RBP + slot));
+        asm += ffi::runtime(s, "rt_process_output");
+    } else {
+        asm += tags::to_fixnum(RAX);
+    }
+
+    s.dealloc(1);
+    asm
+}
+
+/// Bytes needed for the list returned by [rt_command_line]
+#[no_mangle]
+pub extern "C" fn rt_command_line_size() -> i64 {
+    arguments().iter().map(|arg| size(arg.len()) + 2 * WORDSIZE).sum()
+}
+
+/// The arguments of the program as a list of strings
+///
+/// There must be room for [rt_command_line_size] bytes at the heap pointer.
+#[no_mangle]
+pub extern "C" fn rt_command_line() -> Object {
+    arguments()
+        .iter()
+        .rev()
+        .fold(Object(NIL), |rest, arg| rt::cons(rt::string(arg.as_bytes()), rest))
+}
+
+/// Bytes needed for the value of an environment variable, see
+/// [rt_get_environment_variable]
+#[no_mangle]
+pub extern "C" fn rt_environment_size(name: Object) -> i64 {
+    variable(name).map_or(0, |value| size(value.len()))
+}
+
+/// The value of an environment variable as a string, or `#f` if it isn't set
+///
+/// There must be room for [rt_environment_size] bytes at the heap pointer.
+#[no_mangle]
+pub extern "C" fn rt_get_environment_variable(name: Object) -> Object {
+    match variable(name) {
+        Some(value) => rt::string(&value),
+        None => Object(FALSE),
+    }
+}
+
+/// Run a process for a primitive and wait for it to finish
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED). Returns
+/// the exit status or the size of the output for `process-output`, or -1 if
+/// the process couldn't be started, see [rt_process_error].
+#[no_mangle]
+pub extern "C" fn rt_process_run(primitive: i64, prog: Object, args: Object) -> i64 {
+    let primitive = primitives::CHECKED[primitive as usize];
+
+    match execute(primitive, prog, args) {
+        Ok(result) => result,
+        Err(e) => {
+            ERROR.with(|error| *error.borrow_mut() = format!("{}: {}", primitive, e));
+            -1
+        }
+    }
+}
+
+/// The output of the last `process-output` as a string
+///
+/// There must be room for the size returned by [rt_process_run] at the heap
+/// pointer.
+#[no_mangle]
+pub extern "C" fn rt_process_output() -> Object {
+    OUTPUT.with(|output| rt::string(&output.replace(vec![])))
+}
+
+/// Raise the error of the last process that failed to start
+#[no_mangle]
+pub extern "C" fn rt_process_error() -> Target {
+    ERROR.with(|error| exceptions::error(&error.borrow()))
+}
+
+/// Microseconds since an arbitrary point fixed for the life of the process
+#[cfg(feature = "native")]
+#[no_mangle]
+pub extern "C" fn current_jiffy() -> Object {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    Object::immediate(now.tv_sec * JIFFIES + now.tv_nsec / 1000)
+}
+
+/// Number of jiffies in a second, see [current_jiffy]
+#[no_mangle]
+pub const extern "C" fn jiffies_per_second() -> Object {
+    Object::immediate(JIFFIES)
+}
+
+/// Report the time and allocation of an expression, see `(time expr)`
+#[no_mangle]
+pub extern "C" fn rt_time(expr: Object, elapsed: Object, allocated: Object) -> Object {
+    let ms = untag(elapsed.0) as f64 / 1000.0;
+    let expr = String::from_utf8_lossy(rt::str_bytes(expr.0));
+
+    eprintln!("{}: {:.3}ms elapsed, {} bytes allocated", expr, ms, untag(allocated.0));
+    Object(NIL)
+}
+
+fn execute(primitive: &str, prog: Object, args: Object) -> Result<i64, String> {
+    let prog = OsStr::from_bytes(rt::str_bytes(prog.0));
+
+    let mut command = if primitive == "system" {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(prog);
+        command
+    } else {
+        let mut command = Command::new(prog);
+        command.args(strings(args)?);
+        command
+    };
+
+    // The child writes to the same files, after everything written so far
+    io::stdout().flush().map_err(|e| e.to_string())?;
+
+    let started = |e: io::Error| format!("{}: {}", prog.to_string_lossy(), e);
+
+    if primitive == "process-output" {
+        let output = command.stderr(Stdio::inherit()).output().map_err(started)?;
+        let len = output.stdout.len();
+
+        OUTPUT.with(|o| *o.borrow_mut() = output.stdout);
+        Ok(size(len))
+    } else {
+        command.status().map(code).map_err(started)
+    }
+}
+
+/// The strings in a list of arguments
+fn strings(args: Object) -> Result<Vec<OsString>, String> {
+    let mut strings = vec![];
+    let mut rest = args;
+
+    while tag(rest.0) == PAIR && rt::is_string(car(rest).0) {
+        strings.push(OsStr::from_bytes(rt::str_bytes(car(rest).0)).to_os_string());
+        rest = cdr(rest);
+    }
+
+    if rest.0 == NIL {
+        Ok(strings)
+    } else {
+        Err(format!("expected a list of strings, got {}", args))
+    }
+}
+
+/// Exit code of a process, or 128 plus the signal that killed it
+fn code(status: ExitStatus) -> i64 {
+    status.code().or_else(|| status.signal().map(|signal| 128 + signal)).unwrap_or(-1) as i64
+}
+
+fn arguments() -> Vec<String> {
+    COMMAND_LINE.with(|command| command.borrow().clone()).unwrap_or_else(|| env::args().collect())
+}
+
+fn variable(name: Object) -> Option<Vec<u8>> {
+    env::var_os(OsStr::from_bytes(rt::str_bytes(name.0))).map(|value| value.as_bytes().to_vec())
+}
+
+/// Size of a string object with `len` bytes, which looks like a fixnum
+const fn size(len: usize) -> i64 {
+    ((WORDSIZE as usize + len + 1 + 7) / 8 * 8) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size(0), 16);
+        assert_eq!(size(7), 16);
+        assert_eq!(size(8), 24);
+
+        set_command_line(vec![String::from("inc"), String::from("--jit")]);
+        assert_eq!(rt_command_line_size(), 2 * 16 + 2 * 16);
+    }
+}
@@ -0,0 +1,306 @@
+//! How often the functions of a program are called
+//!
+//! `inc build --profile` bumps a counter in the prologue of every function
+//! compiled with the program, including the prelude and the lambdas lifted out
+//! of other functions. The counters are words in the data section of the
+//! executable, followed by the names of the functions.
+//!
+//! An executable with counters writes them out when it exits, as JSON:
+//!
+//! ```json
+//! {"version":1,"functions":[["fib",177],["twice",2],...]}
+//! ```
+//!
+//! They are written to the path in `INC_PROFILE`, or next to the executable
+//! with a `.prof` extension. `inc prof report` lists the functions called the
+//! most along with where they are defined in the source, see
+//! [Profile::report].
+//!
+//! Functions of the units linked with the program aren't counted. Like
+//! [coverage](crate::coverage), the counters live in memory the
+//! [jit](crate::jit) can't write to, so programs with them have to be built.
+use crate::{
+    compiler::state::State,
+    core::Core,
+    ffi,
+    hash::{self, Map},
+    json::Json,
+    sourcemap,
+    x86::{self, Directive, Ins, Register::*, Relative, ASM, WORDSIZE},
+};
+use std::fmt;
+
+/// Label of the counters in the data section, see [table]
+pub const LABEL: &str = "inc_profile";
+
+/// Version of the counters written by a program, bumped whenever they change
+pub const VERSION: i64 = 1;
+
+/// The counters written by a run of a program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub functions: Vec<Calls>,
+}
+
+/// A function of the program and the times it was called
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calls {
+    pub name: String,
+    pub calls: u64,
+}
+
+/// Number the functions of a program for their counters, in the order they
+/// are defined
+pub fn functions(prog: &[Core]) -> Map<String, usize> {
+    let names = prog.iter().filter_map(|expr| expr.function()).map(|(name, _)| name.to_string());
+
+    // Every function is defined at the top level by now
+    let mut functions = hash::map(prog.len());
+    functions.extend(names.enumerate().map(|(i, name)| (name, i)));
+    functions
+}
+
+/// Bump the counter of a function from its prologue, if it has one
+///
+/// R11 is free to use on entry, the arguments are all in the frame.
+pub fn call(s: &State, name: &str) -> ASM {
+    match s.profile.get(name) {
+        Some(index) => {
+            let offset = WORDSIZE * (1 + *index as i64);
+
+            x86::lea(R11, &ffi::symbol(LABEL), offset)
+                + x86::add(Relative { register: R11, offset: 0 }.into(), 1.into())
+        }
+        None => ASM::default(),
+    }
+}
+
+/// The counters of a program in the data section
+///
+/// The table is the number of counters followed by a word for each, and then
+/// the names of the functions as C strings in the same order. It is exported
+/// for the runtime to find, see [install].
+pub fn table(s: &State) -> ASM {
+    if s.profile.is_empty() {
+        return ASM::default();
+    }
+
+    let mut names: Vec<(&String, &usize)> = s.profile.iter().collect();
+    names.sort_by_key(|(_, index)| **index);
+
+    let label = ffi::symbol(LABEL);
+    let mut asm = Ins::Blank
+        + Ins::Directive(Directive::Data)
+        + Ins::Directive(Directive::Align(3))
+        + Ins::Directive(Directive::Global(label.clone()))
+        + x86::label(&label)
+        + Ins::Directive(Directive::Quad(names.len() as i64));
+
+    for _ in &names {
+        asm += Ins::Directive(Directive::Quad(0));
+    }
+
+    for (name, _) in names {
+        asm += Ins::Directive(Directive::Asciz(name.clone()));
+    }
+
+    asm + Ins::Directive(Directive::Text)
+}
+
+/// Write the counters of the program out when it exits, if it has any
+///
+/// # Safety
+///
+/// Must be called once, from the [start](crate::start) of a program.
+#[cfg(feature = "native")]
+pub unsafe fn install() {
+    let name = std::ffi::CString::new(LABEL).unwrap();
+    let table = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+
+    if !table.is_null() {
+        TABLE.store(table as usize, std::sync::atomic::Ordering::SeqCst);
+        libc::atexit(dump);
+    }
+}
+
+/// Address of the counters of the running program
+#[cfg(feature = "native")]
+static TABLE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(feature = "native")]
+extern "C" fn dump() {
+    let table = TABLE.load(std::sync::atomic::Ordering::SeqCst) as *const i64;
+
+    let profile = unsafe {
+        let count = *table as usize;
+        let counters = std::slice::from_raw_parts(table.add(1), count);
+        let mut name = table.add(1 + count) as *const std::os::raw::c_char;
+
+        let functions = counters.iter().map(|calls| {
+            let s = std::ffi::CStr::from_ptr(name);
+            name = name.add(s.to_bytes().len() + 1);
+            Calls { name: s.to_string_lossy().into_owned(), calls: *calls as u64 }
+        });
+
+        Profile { functions: functions.collect() }
+    };
+
+    let path = std::env::var("INC_PROFILE").ok().or_else(|| {
+        let exe = std::env::current_exe().ok()?;
+        Some(format!("{}.prof", exe.display()))
+    });
+
+    if let Some(path) = path {
+        if let Err(e) = std::fs::write(&path, profile.to_string()) {
+            eprintln!("Failed to write the profile to {}: {}", path, e);
+        }
+    }
+}
+
+impl Profile {
+    /// Read the counters written by a program
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json = Json::parse(text)?;
+
+        match json.get("version").and_then(Json::as_i64) {
+            Some(VERSION) => {}
+            _ => return Err(format!("Expected a profile of version {}", VERSION)),
+        }
+
+        let functions = json.get("functions").and_then(Json::as_array).unwrap_or_default();
+
+        functions
+            .iter()
+            .map(|f| match f.as_array() {
+                Some([name, calls]) => match (name.as_str(), calls.as_i64()) {
+                    (Some(name), Some(calls)) => {
+                        Ok(Calls { name: name.to_string(), calls: calls as u64 })
+                    }
+                    _ => Err(format!("Invalid function in profile: {}", f)),
+                },
+                _ => Err(format!("Invalid function in profile: {}", f)),
+            })
+            .collect::<Result<_, _>>()
+            .map(|functions| Profile { functions })
+    }
+
+    /// The functions that were called, the most called first, with where they
+    /// are defined in `source`
+    ///
+    /// Lambdas are lifted into functions named after the function they are
+    /// defined in and point at its definition, like in a
+    /// [source map](crate::sourcemap). Functions that didn't come from the
+    /// source, like the ones in the prelude, are shown without a place.
+    pub fn report(&self, source: &str) -> String {
+        let definitions = sourcemap::definitions(source);
+
+        let mut called: Vec<&Calls> = self.functions.iter().filter(|f| f.calls > 0).collect();
+        called.sort_by(|a, b| b.calls.cmp(&a.calls).then_with(|| a.name.cmp(&b.name)));
+
+        let total: u64 = called.iter().map(|f| f.calls).sum();
+        let mut out = format!("{:>10} {:>6}  {:<24} {}\n", "calls", "%", "function", "defined");
+
+        for f in &called {
+            let function = f.name.split(' ').next().unwrap_or_default();
+            let place = definitions.get(function).map_or(String::from("-"), |span| {
+                let before = &source[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |n| n + 1) + 1;
+                format!("{}:{}", line, column)
+            });
+            let percent = f.calls as f64 * 100.0 / total as f64;
+
+            out += &format!("{:>10} {:>6.1}  {:<24} {}\n", f.calls, percent, f.name, place);
+        }
+
+        out + &format!("\n{} calls to {} functions", total, called.len())
+    }
+}
+
+/// Compact JSON, see the module docs
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let functions = self
+            .functions
+            .iter()
+            .map(|c| Json::from(vec![c.name.as_str().into(), (c.calls as i64).into()]))
+            .collect::<Vec<_>>();
+
+        let json = Json::object(vec![("version", VERSION.into()), ("functions", functions.into())]);
+        write!(f, "{}", json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lang, parser};
+    use std::sync::Arc;
+
+    const SOURCE: &str = "(define (fib n)
+  (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+
+(fib 10)";
+
+    fn state(source: &str) -> (State, Vec<Core>) {
+        let mut s = State::new();
+        let prog = lang::analyze(&mut s, parser::parse(source).unwrap()).unwrap();
+        s.profile = Arc::new(functions(&prog));
+        (s, prog)
+    }
+
+    #[test]
+    fn counters() {
+        let (s, _) = state("(define (f x) x) (define (g x) (f x)) (g 1)");
+
+        assert_eq!(s.profile.get("f"), Some(&0));
+        assert_eq!(s.profile.get("g"), Some(&1));
+
+        let asm = call(&s, "g").to_string();
+        assert!(asm.contains(&format!("lea r11, [rip + 16 + \"{}\"]", ffi::symbol(LABEL))));
+        assert!(asm.contains("add qword ptr [r11], 1"));
+        assert!(call(&s, "h").to_string().is_empty());
+
+        let table = table(&s).to_string();
+        assert!(table.contains(".quad  2\n"));
+        assert!(table.find(".asciz \"f\"").unwrap() < table.find(".asciz \"g\"").unwrap());
+
+        // Nothing is emitted without profiling
+        assert!(super::table(&State::new()).to_string().is_empty());
+    }
+
+    #[test]
+    fn report() {
+        let profile = Profile {
+            functions: vec![
+                Calls { name: String::from("fib"), calls: 177 },
+                Calls { name: String::from("length"), calls: 0 },
+                Calls { name: String::from("fib h"), calls: 3 },
+                Calls { name: String::from("append"), calls: 20 },
+            ],
+        };
+
+        let report = profile.report(SOURCE);
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(lines[1], "       177   88.5  fib                      1:1");
+        assert_eq!(lines[2], "        20   10.0  append                   -");
+        assert_eq!(lines[3], "         3    1.5  fib h                    1:1");
+        assert_eq!(lines.last(), Some(&"200 calls to 3 functions"));
+        assert!(!report.contains("length"));
+    }
+
+    #[test]
+    fn json() {
+        let profile = Profile {
+            functions: vec![
+                Calls { name: String::from("fib"), calls: 177 },
+                Calls { name: String::from("%math.arith/double"), calls: 0 },
+            ],
+        };
+
+        assert_eq!(Profile::parse(&profile.to_string()), Ok(profile));
+        assert!(Profile::parse(r#"{"version":0,"functions":[]}"#).is_err());
+        assert!(Profile::parse(r#"{"version":1,"functions":[["f"]]}"#).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Project configuration, read from `inc.toml`
+//!
+//! A directory with an `inc.toml` in it is a project, and `inc build` or `inc
+//! run` without any files build the project instead of reading the program
+//! from stdin. The file lists the sources in the order they would be given on
+//! the command line, the program last, and the options to build them with:
+//!
+//! ```toml
+//! # Build ./app from two files
+//! files = ["lib.ss", "main.ss"]
+//! output = "app"
+//! opt-level = 1
+//! prelude = true
+//! load = ["macros.ss"]
+//! target = "x86_64-unknown-linux-gnu"
+//! ```
+//!
+//! Only `files` is required. Paths are relative to the directory of the file
+//! and options given on the command line take precedence over the project.
+//!
+//! The file is a small subset of [TOML]: one `key = value` a line, where the
+//! value is a string, an integer, a boolean or an array of them on a single
+//! line, and comments starting with `#`. Tables aren't supported.
+//!
+//! [TOML]: https://toml.io
+
+use crate::{core::Target, lang::Passes};
+use std::{fs, io, path::Path};
+
+/// Name of the project file
+pub const NAME: &str = "inc.toml";
+
+/// A project, as declared in its [NAME] file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Project {
+    /// Source files, the units followed by the program
+    pub files: Vec<String>,
+    /// Name of the executable
+    pub output: Option<String>,
+    /// Passes picked with `opt-level`
+    pub passes: Option<Passes>,
+    /// Whether the prelude is compiled with the program
+    pub prelude: Option<bool>,
+    /// Files loaded before the program
+    pub load: Vec<String>,
+    /// Platform the project is compiled for
+    pub target: Option<Target>,
+}
+
+/// A value in the project file
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Project {
+    /// The project in a directory, if there is one
+    pub fn find(dir: &Path) -> Result<Option<Self>, String> {
+        let path = dir.join(NAME);
+
+        match fs::read_to_string(&path) {
+            Ok(source) => Self::parse(&source, dir)
+                .map(Some)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Parse a project file, with paths relative to `dir`
+    ///
+    /// Errors in a line of the file start with its number.
+    pub fn parse(source: &str, dir: &Path) -> Result<Self, String> {
+        let mut project = Project::default();
+        let mut files: Option<Vec<String>> = None;
+
+        for (n, line) in source.lines().enumerate() {
+            let error = |e: String| format!("line {}: {}", n + 1, e);
+            let (key, value) = match Self::line(line).map_err(error)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let expected = |kind: &str| error(format!("Expected {} for `{}`", kind, key));
+            let path = |value: &Value| match value {
+                Value::Str(s) => Ok(Self::path(dir, s)),
+                _ => Err(expected("an array of strings")),
+            };
+
+            match (key, &value) {
+                ("files", Value::Array(values)) => {
+                    files = Some(values.iter().map(path).collect::<Result<_, _>>()?)
+                }
+                ("load", Value::Array(values)) => {
+                    project.load = values.iter().map(path).collect::<Result<_, _>>()?
+                }
+                ("files", _) | ("load", _) => return Err(expected("an array of strings")),
+                ("output", Value::Str(s)) => project.output = Some(Self::path(dir, s)),
+                ("output", _) => return Err(expected("a string")),
+                ("opt-level", Value::Int(level)) => {
+                    project.passes = Some(Passes::level(&level.to_string()).map_err(error)?)
+                }
+                ("opt-level", _) => return Err(expected("an integer")),
+                ("prelude", Value::Bool(b)) => project.prelude = Some(*b),
+                ("prelude", _) => return Err(expected("a boolean")),
+                ("target", Value::Str(s)) => {
+                    project.target = Some(Target::parse(s).map_err(error)?)
+                }
+                ("target", _) => return Err(expected("a string")),
+                _ => return Err(error(format!("Unknown key `{}`", key))),
+            }
+        }
+
+        match files {
+            Some(files) if !files.is_empty() => Ok(Project { files, ..project }),
+            _ => Err(String::from("Expected `files` with the sources of the project")),
+        }
+    }
+
+    /// A key and its value, or nothing for an empty line or a comment
+    fn line(line: &str) -> Result<Option<(&str, Value)>, String> {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        if line.starts_with('[') {
+            return Err(String::from("Tables aren't supported, expected `key = value`"));
+        }
+
+        let (key, rest) = match line.find('=') {
+            Some(i) => (line[..i].trim(), &line[i + 1..]),
+            None => return Err(String::from("Expected `key = value`")),
+        };
+
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("Invalid key `{}`", key));
+        }
+
+        let (value, rest) = Self::value(rest.trim_start())?;
+        let rest = rest.trim();
+
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(format!("Unexpected `{}` after the value of `{}`", rest, key));
+        }
+
+        Ok(Some((key, value)))
+    }
+
+    /// Parse a value at the start of the input, and return the rest
+    fn value(i: &str) -> Result<(Value, &str), String> {
+        if let Some(i) = i.strip_prefix('"') {
+            let end = i.find('"').ok_or_else(|| String::from("Unterminated string"))?;
+            return Ok((Value::Str(i[..end].to_string()), &i[end + 1..]));
+        }
+
+        if let Some(mut i) = i.strip_prefix('[') {
+            let mut values = vec![];
+
+            loop {
+                i = i.trim_start();
+
+                if let Some(rest) = i.strip_prefix(']') {
+                    return Ok((Value::Array(values), rest));
+                }
+
+                let (value, rest) = Self::value(i)?;
+                values.push(value);
+                i = rest.trim_start();
+
+                match i.chars().next() {
+                    Some(',') => i = &i[1..],
+                    Some(']') => {}
+                    _ => return Err(String::from("Expected `,` or `]` in an array")),
+                }
+            }
+        }
+
+        let end = i.find(|c: char| c.is_whitespace() || c == ',' || c == ']').unwrap_or(i.len());
+        let (word, rest) = i.split_at(end);
+
+        match word {
+            "true" => Ok((Value::Bool(true), rest)),
+            "false" => Ok((Value::Bool(false), rest)),
+            _ => match word.parse() {
+                Ok(n) => Ok((Value::Int(n), rest)),
+                Err(_) if word.is_empty() => Err(String::from("Expected a value")),
+                Err(_) => Err(format!("Invalid value `{}`", word)),
+            },
+        }
+    }
+
+    /// A path in the project file, relative to its directory
+    fn path(dir: &Path, path: &str) -> String {
+        dir.join(path).display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let source = r#"
+# Two files and a few options
+files = ["lib.ss", "main.ss"]   # the program is last
+output = "app"
+opt-level = 1
+prelude = false
+load = [ "macros.ss", ]
+"#;
+        let project = Project::parse(source, Path::new("")).unwrap();
+
+        assert_eq!(project.files, ["lib.ss", "main.ss"]);
+        assert_eq!(project.output.as_deref(), Some("app"));
+        assert_eq!(project.passes, Some(Passes::level("1").unwrap()));
+        assert_eq!(project.prelude, Some(false));
+        assert_eq!(project.load, ["macros.ss"]);
+        assert_eq!(project.target, None);
+
+        let project = Project::parse("files = [\"main.ss\"]", Path::new("app")).unwrap();
+        assert_eq!(project.files, ["app/main.ss"]);
+        assert_eq!(project.output, None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| Project::parse(source, Path::new("")).unwrap_err();
+
+        assert_eq!(error(""), "Expected `files` with the sources of the project");
+        assert_eq!(error("files = []"), "Expected `files` with the sources of the project");
+        let files = "line 1: Expected an array of strings for `files`";
+        assert_eq!(error("files = \"main.ss\""), files);
+        assert_eq!(error("files = [1]"), files);
+        assert_eq!(error("\nopt-level = \"2\""), "line 2: Expected an integer for `opt-level`");
+        assert!(error("opt-level = 3").starts_with("line 1: Unknown optimization level `3`"));
+        assert_eq!(error("name = \"app\""), "line 1: Unknown key `name`");
+        assert_eq!(error("[project]"), "line 1: Tables aren't supported, expected `key = value`");
+        assert_eq!(error("files"), "line 1: Expected `key = value`");
+        assert_eq!(error("files = [\"a\" \"b\"]"), "line 1: Expected `,` or `]` in an array");
+        assert_eq!(error("output = \"app"), "line 1: Unterminated string");
+        assert_eq!(error("prelude = yes"), "line 1: Invalid value `yes`");
+        assert_eq!(
+            error("prelude = true false"),
+            "line 1: Unexpected `false` after the value of `prelude`"
+        );
+    }
+
+    #[test]
+    fn find() {
+        let dir = std::env::temp_dir().join(format!("inc-project-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(Project::find(&dir), Ok(None));
+
+        fs::write(dir.join(NAME), "files = [\"main.ss\"]\nprelude = 1\n").unwrap();
+        let error = Project::find(&dir).unwrap_err();
+        assert!(error.ends_with("inc.toml: line 2: Expected a boolean for `prelude`"), "{}", error);
+
+        fs::write(dir.join(NAME), "files = [\"main.ss\"]\n").unwrap();
+        let project = Project::find(&dir).unwrap().unwrap();
+        assert_eq!(project.files, [dir.join("main.ss").display().to_string()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
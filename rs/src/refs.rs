@@ -0,0 +1,187 @@
+//! Definitions and references across files
+//!
+//! A [Database] keeps the files of a program along with their [semantic]
+//! tokens, so that questions about names don't read anything again:
+//!
+//! - [definition_of](Database::definition_of) a name at some offset, which
+//!   is where a local variable is bound or a global one is defined at the top
+//!   level of one of the files.
+//! - [references_to](Database::references_to) a global name, every place it
+//!   is defined or used in any of the files.
+//! - [references_at](Database::references_at) an offset, the same for a local
+//!   variable or a global one alike.
+//!
+//! `inc lsp` answers definition and reference requests with one, and
+//! `inc refs NAME FILE...` lists the references to a name on a terminal.
+//!
+//! ```
+//! use inc::refs::Database;
+//!
+//! let mut db = Database::default();
+//! db.insert("lib.ss", "(define (twice x) (* x 2))");
+//! db.insert("main.ss", "(twice (twice 2))");
+//!
+//! let found = db.definition_of("main.ss", 2).unwrap();
+//! assert_eq!((found.file.as_str(), found.span), ("lib.ss", 9..14));
+//! assert_eq!(db.references_to("twice").len(), 3);
+//! ```
+
+use crate::{
+    lsp,
+    semantic::{self, Kind, Token},
+};
+use std::{collections::BTreeMap, ops::Range};
+
+/// A span in one of the files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub span: Range<usize>,
+}
+
+/// The files of a program and what their names refer to
+#[derive(Debug, Default)]
+pub struct Database {
+    files: BTreeMap<String, File>,
+}
+
+/// A file with its tokens and its top level definitions
+#[derive(Debug)]
+struct File {
+    source: String,
+    tokens: Vec<Token>,
+    definitions: Vec<lsp::Definition>,
+}
+
+impl Database {
+    /// Add a file, or replace it with a new version
+    pub fn insert(&mut self, file: &str, source: &str) {
+        let entry = File {
+            source: source.to_string(),
+            tokens: semantic::tokens(source),
+            definitions: lsp::definitions(&lsp::read(source)),
+        };
+        self.files.insert(file.to_string(), entry);
+    }
+
+    /// Forget a file
+    pub fn remove(&mut self, file: &str) {
+        self.files.remove(file);
+    }
+
+    /// The source of a file
+    pub fn source(&self, file: &str) -> Option<&str> {
+        self.files.get(file).map(|f| f.source.as_str())
+    }
+
+    /// The names in a file
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    /// The token of a name at an offset, which may be right after it
+    pub fn token_at(&self, file: &str, offset: usize) -> Option<&Token> {
+        self.files.get(file)?.tokens.iter().find(|token| {
+            matches!(token.kind, Kind::Bound | Kind::Free | Kind::Primitive)
+                && token.span.start <= offset
+                && offset <= token.span.end
+        })
+    }
+
+    /// Where the name at an offset is bound or defined
+    ///
+    /// Local variables are bound in the same file. Global ones are looked for
+    /// in the same file first and then in the others in order of their names,
+    /// and functions built into the compiler have no definition.
+    pub fn definition_of(&self, file: &str, offset: usize) -> Option<Location> {
+        let token = self.token_at(file, offset)?;
+
+        if let Some(binding) = &token.binding {
+            return Some(Location { file: file.to_string(), span: binding.clone() });
+        }
+
+        let name = &self.files[file].source[token.span.clone()];
+        let others = self.files.keys().map(String::as_str).filter(|f| *f != file);
+
+        std::iter::once(file).chain(others).find_map(|file| {
+            let found = self.files[file].definitions.iter().find(|d| d.name == name)?;
+            Some(Location { file: file.to_string(), span: found.name_span.clone() })
+        })
+    }
+
+    /// Every place a global name is defined or used, in order
+    pub fn references_to(&self, name: &str) -> Vec<Location> {
+        self.files
+            .iter()
+            .flat_map(|(file, f)| {
+                f.tokens
+                    .iter()
+                    .filter(move |t| t.binding.is_none() && &f.source[t.span.clone()] == name)
+                    .filter(|t| matches!(t.kind, Kind::Free | Kind::Primitive))
+                    .map(move |t| Location { file: file.clone(), span: t.span.clone() })
+            })
+            .collect()
+    }
+
+    /// Every place the name at an offset is bound or used, in order
+    pub fn references_at(&self, file: &str, offset: usize) -> Vec<Location> {
+        let token = match self.token_at(file, offset) {
+            Some(token) => token,
+            None => return vec![],
+        };
+
+        match &token.binding {
+            Some(binding) => self.files[file]
+                .tokens
+                .iter()
+                .filter(|t| t.binding.as_ref() == Some(binding))
+                .map(|t| Location { file: file.to_string(), span: t.span.clone() })
+                .collect(),
+            None => self.references_to(&self.files[file].source[token.span.clone()]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries() {
+        let mut db = Database::default();
+        let a = "(define (f x) (let ((y x)) (g y x)))\n(f 1)";
+        db.insert("a.ss", a);
+        db.insert("b.ss", "(define (g . xs) xs)\n(define (f) 2)");
+
+        let at = |needle: &str, n: usize| a.match_indices(needle).nth(n).unwrap().0;
+        let location = |file: &str, span| Some(Location { file: file.to_string(), span });
+        let call = at("(f 1)", 0) + 1;
+
+        // Locals are bound in the file, globals defined in it or another one
+        assert_eq!(
+            db.definition_of("a.ss", at("y", 1)),
+            location("a.ss", at("y", 0)..at("y", 0) + 1)
+        );
+        assert_eq!(db.definition_of("a.ss", at("g", 0)), location("b.ss", 9..10));
+        assert_eq!(db.definition_of("a.ss", call), location("a.ss", 9..10));
+        assert_eq!(db.definition_of("a.ss", at("let", 0)), None);
+
+        let spans = |locations: Vec<Location>| {
+            locations.into_iter().map(|l| (l.file, l.span.start)).collect::<Vec<_>>()
+        };
+        let x = |n| (String::from("a.ss"), at("x", n));
+        assert_eq!(spans(db.references_at("a.ss", at("x", 2))), vec![x(0), x(1), x(2)]);
+
+        let f = vec![
+            (String::from("a.ss"), 9),
+            (String::from("a.ss"), call),
+            (String::from("b.ss"), 30),
+        ];
+        assert_eq!(spans(db.references_to("f")), f);
+        assert_eq!(spans(db.references_at("a.ss", call)), f);
+
+        db.remove("b.ss");
+        assert_eq!(db.definition_of("a.ss", at("g", 0)), None);
+        assert_eq!(db.files().collect::<Vec<_>>(), vec!["a.ss"]);
+    }
+}
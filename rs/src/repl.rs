@@ -0,0 +1,882 @@
+//! An interactive session in the terminal
+//!
+//! `inc repl` reads expressions a line at a time and prints the value of each
+//! with `write`, like the result of a program. An expression spanning several
+//! lines is evaluated once all its parentheses are closed, and the prompt
+//! changes in the meantime to show that more is expected.
+//!
+//! ```text
+//! inc> (define (twice x)
+//! ...    (* x 2))
+//! inc> (twice 21)
+//! 42
+//! inc> (car 1)
+//! Exception: car: expected pair, got 1
+//! ```
+//!
+//! Functions defined at the top level stay around for the rest of the
+//! [Session], and defining one again replaces it. Each input is evaluated along
+//! with them by the [interpreter](crate::interp), or compiled and run with the
+//! [jit](crate::jit) if it does something the interpreter doesn't. Exceptions
+//! are caught and reported instead of ending the session. Like in a program,
+//! only functions can be defined at the top level.
+//!
+//! `(load "file")` evaluates the forms in a file as if they were typed in, and
+//! `inc repl --load file` does the same before the first prompt.
+//!
+//! Input starting with a colon is a [Command] to the session instead, to see
+//! what the compiler makes of an expression at each stage along the way:
+//!
+//! ```text
+//! :load FILE    Evaluate the forms in a file, like `load`
+//! :ast EXPR     The expression as the parser reads it
+//! :expand EXPR  The expression with derived syntax expanded, like `inc expand`
+//! :asm EXPR     Generated code for the expression and the functions defined
+//! :type EXPR    The type of the value of the expression
+//! :quit         End the session
+//! ```
+//!
+//! Lines are read with a small editor of our own, which puts the terminal in
+//! raw mode while reading. The arrow keys move around the line and through the
+//! history, which is kept in `~/.inc_history` across sessions, along with the
+//! usual Emacs style keys like `C-a`, `C-e`, `C-k` and `C-u`. `C-c` discards
+//! the expression read so far and `C-d` on an empty line ends the session.
+//! Tab [completes](crate::complete) the name before the cursor with the names
+//! bound there, the functions defined in the session and the ones built in,
+//! listing them if there is more than one. Input that isn't a terminal is read
+//! line by line as is.
+
+use crate::{
+    cli,
+    compiler::{self, emit},
+    complete::{self, Candidate},
+    core::{Core, Error, Expr, Ident, Literal::*, Syntax},
+    exceptions, interp,
+    jit::{self, Image},
+    lang::{self, Passes},
+    parser, tags,
+    x86::{Directive, Ins, ASM},
+};
+use std::{
+    env, fs,
+    io::{self, BufRead, Read, Write},
+    mem,
+    ops::Range,
+    path::PathBuf,
+};
+
+/// Prompt for a new expression
+const PROMPT: &str = "inc> ";
+
+/// Prompt for the rest of an expression
+const MORE: &str = "...  ";
+
+/// Marks a value raised by the expression instead of returned
+const RAISED: &str = "%repl-raised";
+
+/// Run a session on the terminal till the end of the input, after loading
+/// some files
+pub fn run(load: &[String]) -> io::Result<()> {
+    let mut editor = Editor::new(history());
+    let mut session = Session::new();
+    let mut expr = String::new();
+
+    for path in load {
+        match session.load(path) {
+            out if out.is_empty() => {}
+            out => println!("{}", out),
+        }
+    }
+
+    loop {
+        // Names are completed in the whole expression read so far
+        let complete = |line: &str, cursor: usize| {
+            let input = format!("{}{}", expr, line);
+            let cursor = line.char_indices().nth(cursor).map_or(line.len(), |(i, _)| i);
+            let (span, candidates) = session.complete(&input, expr.len() + cursor);
+            let start = input[expr.len()..span.start].chars().count();
+
+            (start, candidates.into_iter().map(|c| c.name).collect())
+        };
+
+        let prompt = if expr.is_empty() { PROMPT } else { MORE };
+        let line = match editor.read(prompt, &complete)? {
+            Line::Text(line) => line,
+            Line::Interrupted => {
+                expr.clear();
+                continue;
+            }
+            Line::Eof => break,
+        };
+
+        expr.push_str(&line);
+        expr.push('\n');
+
+        if depth(&expr) > 0 {
+            continue;
+        }
+
+        if !expr.trim().is_empty() {
+            editor.remember(expr.trim());
+
+            let out = if expr.trim_start().starts_with(':') {
+                match Command::parse(&expr) {
+                    Ok(Command::Quit) => break,
+                    Ok(command) => session.command(command),
+                    Err(e) => e,
+                }
+            } else {
+                session.eval(&expr)
+            };
+
+            match out {
+                out if out.is_empty() => {}
+                out => println!("{}", out),
+            }
+        }
+
+        expr.clear();
+    }
+
+    editor.save()
+}
+
+/// A command to the session rather than an expression to evaluate
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Load(String),
+    Ast(String),
+    Expand(String),
+    Asm(String),
+    Type(String),
+    Quit,
+}
+
+impl Command {
+    pub const NAMES: [&'static str; 6] = [":load", ":ast", ":expand", ":asm", ":type", ":quit"];
+
+    /// Parse a command like `:expand (when a b)`, with everything after the
+    /// name as its argument
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let (name, arg) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        let arg = arg.trim().to_string();
+
+        match name {
+            ":load" if arg.is_empty() => Err(String::from("`:load` expects a file")),
+            ":ast" | ":expand" | ":asm" | ":type" if arg.is_empty() => {
+                Err(format!("`{}` expects an expression", name))
+            }
+            ":load" => Ok(Command::Load(arg.trim_matches('"').to_string())),
+            ":ast" => Ok(Command::Ast(arg)),
+            ":expand" => Ok(Command::Expand(arg)),
+            ":asm" => Ok(Command::Asm(arg)),
+            ":type" => Ok(Command::Type(arg)),
+            ":quit" => Ok(Command::Quit),
+            _ => Err(format!(
+                "Unknown command `{}`, expected one of {}",
+                name,
+                Self::NAMES.join("|")
+            )),
+        }
+    }
+}
+
+/// Definitions entered so far, which the rest of the session builds on
+///
+/// Every evaluation compiles the definitions again along with the new
+/// expressions, so that a redefinition replaces the old function everywhere,
+/// even in the functions calling it.
+#[derive(Default)]
+pub struct Session {
+    definitions: Vec<Syntax>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluate some input and describe the outcome like a program would
+    ///
+    /// Definitions have no value to show and describe only errors. They are
+    /// kept for later once they compile, even if the rest of the input raises
+    /// an exception.
+    pub fn eval(&mut self, input: &str) -> String {
+        self.try_eval(input).unwrap_or_else(|e| e)
+    }
+
+    /// Evaluate some input like [eval](Self::eval), but with errors and
+    /// exceptions apart from the values
+    pub fn try_eval(&mut self, input: &str) -> Result<String, String> {
+        match parser::parse(input) {
+            Ok(prog) => self.evaluate(prog, |val| val.to_string()),
+            Err(e) => Err(e.to_string().trim_end().to_string()),
+        }
+    }
+
+    /// Evaluate the forms in a file, see [load](lang::load)
+    pub fn load(&mut self, path: &str) -> String {
+        let prog = vec![Expr::List(vec![Expr::name("load"), Expr::string(path)])];
+        self.evaluate(prog, |val| val.to_string()).unwrap_or_else(|e| e)
+    }
+
+    /// Run a command and describe the outcome, `:quit` is left to the caller
+    ///
+    /// Only `:load` and `:type` evaluate anything, and like [eval](Self::eval)
+    /// keep the definitions they come across. The rest leave the session as it
+    /// was.
+    pub fn command(&mut self, command: Command) -> String {
+        let parse = |input: &str| parser::parse(input).map_err(|e| e.to_string());
+
+        let out = match command {
+            Command::Load(path) => Ok(self.load(&path)),
+            Command::Ast(input) => parse(&input).map(|prog| {
+                prog.iter().map(|e| format!("{:?}", e)).collect::<Vec<_>>().join("\n")
+            }),
+            Command::Expand(input) => parse(&input).and_then(expand),
+            Command::Asm(input) => parse(&input).and_then(|prog| self.asm(prog)),
+            Command::Type(input) => parse(&input)
+                .map(|prog| self.evaluate(prog, type_of).unwrap_or_else(|e| e)),
+            Command::Quit => Ok(String::new()),
+        };
+
+        match out {
+            Ok(out) => out,
+            Err(e) => e.trim_end().to_string(),
+        }
+    }
+
+    /// The names completing the one being typed at an offset of some input,
+    /// see [complete](complete::complete)
+    ///
+    /// The functions defined in the session so far complete along with the
+    /// ones defined in the input.
+    pub fn complete(&self, input: &str, offset: usize) -> (Range<usize>, Vec<Candidate>) {
+        let globals: Vec<String> = self
+            .definitions
+            .iter()
+            .filter_map(|d| match d {
+                Expr::Define { name, .. } => Some(String::from(name)),
+                _ => None,
+            })
+            .collect();
+
+        complete::complete(input, offset, &globals)
+    }
+
+    /// The definitions of the session updated with the ones in a program,
+    /// along with the rest of it
+    fn split(&self, prog: Vec<Syntax>) -> (Vec<Syntax>, Vec<Syntax>) {
+        let mut definitions = self.definitions.clone();
+        let mut exprs = vec![];
+
+        for e in prog {
+            match &e {
+                Expr::Define { name, .. } => {
+                    match definitions.iter().position(|d| defines(d, name)) {
+                        Some(i) => definitions[i] = e,
+                        None => definitions.push(e),
+                    }
+                }
+                _ => exprs.push(e),
+            }
+        }
+
+        (definitions, exprs)
+    }
+
+    /// Generated code for a program along with the definitions of the session
+    ///
+    /// The prelude is compiled too, as it would be to run the program, but its
+    /// functions are left out along with the routines every program ends with.
+    fn asm(&self, prog: Vec<Syntax>) -> Result<String, String> {
+        let describe = |e: Error| e.to_string();
+
+        let prog = lang::load(prog)
+            .map_err(|e| describe(Error::Compilation(compiler::messages(e))))?;
+        let (definitions, exprs) = self.split(prog);
+
+        let prelude: Vec<String> = compiler::prelude()
+            .into_iter()
+            .filter_map(|e| match e {
+                Expr::Define { name, .. } => Some(String::from(name)),
+                _ => None,
+            })
+            .collect();
+
+        let prog = compiler::prelude().into_iter().chain(definitions).chain(exprs).collect();
+        let asm = emit::compile(prog).map_err(|e| describe(Error::compilation(e)))?;
+
+        // Lifted lambdas are named after the function they are defined in
+        let mut skip = false;
+        let code = asm
+            .0
+            .into_iter()
+            .filter(|ins| {
+                match ins {
+                    Ins::Directive(Directive::Global(name)) => {
+                        let name = Ident::unmangle(name).unwrap_or(name);
+                        let function = name.split(' ').next().unwrap_or_default();
+                        skip = prelude.iter().any(|name| name == function);
+                    }
+                    Ins::Label(label) if label == exceptions::DISPATCH => skip = true,
+                    _ => {}
+                }
+                !skip
+            })
+            .collect();
+
+        Ok(ASM(code).to_string().trim().to_string())
+    }
+
+    /// Evaluate a program and describe its value with `show`
+    fn evaluate(
+        &mut self,
+        prog: Vec<Syntax>,
+        show: impl Fn(Core) -> String,
+    ) -> Result<String, String> {
+        let prog = match lang::load(prog) {
+            Ok(prog) => prog,
+            Err(e) => {
+                let e = Error::Compilation(compiler::messages(e));
+                return Err(e.to_string().trim_end().to_string());
+            }
+        };
+
+        let (definitions, exprs) = self.split(prog);
+
+        // Definitions must stay at the top level, everything else is guarded
+        let value = !exprs.is_empty();
+        let mut prog = definitions.clone();
+        if value {
+            prog.push(guard(exprs));
+        }
+
+        let describe = |val: Core| match raised(&val) {
+            Some(message) => Err(format!("Exception: {}", message)),
+            None if !value => Ok(String::new()),
+            None => Ok(show(val)),
+        };
+
+        // Anything the interpreter can't do is left to the jit, which runs the
+        // input again from the start; nothing has been printed yet
+        let all = compiler::prelude().into_iter().chain(prog.clone()).collect();
+        let outcome = interp::eval(all, Passes::default());
+
+        if let Ok(interp::Outcome { output, value: Some(val) }) = outcome {
+            self.definitions = definitions;
+            print!("{}", output);
+            return describe(val);
+        }
+
+        let image = compile(prog)?;
+
+        self.definitions = definitions;
+
+        image.run(|val| describe(val.deref()))
+    }
+}
+
+/// Does a definition define `name`?
+fn defines(definition: &Syntax, name: &str) -> bool {
+    matches!(definition, Expr::Define { name: n, .. } if n == name)
+}
+
+/// Name of the type of a value, as used in error messages
+fn type_of(val: Core) -> String {
+    let tag = match val {
+        Expr::Literal(Nil) => tags::NIL,
+        Expr::Literal(Boolean(_)) => tags::BOOL,
+        Expr::Literal(Char(_)) => tags::CHAR,
+        Expr::Literal(Str(_)) => tags::STR,
+        Expr::Literal(Symbol(_)) => tags::SYM,
+        Expr::Literal(_) => tags::NUM,
+        Expr::List(_) => tags::PAIR,
+        Expr::Vector(_) => tags::VEC,
+        _ => return String::from("unknown"),
+    };
+
+    String::from(tags::name(tag))
+}
+
+/// The program with derived syntax expanded, pretty printed like `inc expand`
+fn expand(prog: Vec<Syntax>) -> Result<String, String> {
+    let expanded = lang::load(prog)
+        .and_then(|prog| prog.into_iter().map(lang::expand).collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Compilation(compiler::messages(e)).to_string())?;
+
+    Ok(expanded.iter().map(|e| e.pretty(cli::WIDTH)).collect::<Vec<_>>().join("\n"))
+}
+
+/// Wrap expressions in a guard that returns raised objects marked as such
+pub(crate) fn guard(exprs: Vec<Syntax>) -> Syntax {
+    let template = format!("(guard (e (#t (cons '{} e))))", RAISED);
+
+    match parser::parse(&template).unwrap().remove(0) {
+        Expr::List(list) => Expr::List(list.into_iter().chain(exprs).collect()),
+        _ => unreachable!(),
+    }
+}
+
+/// The message of the object raised in a [guard], if the value is one
+pub(crate) fn raised(val: &Core) -> Option<String> {
+    match val {
+        Expr::List(list) if list.len() == 2 && list[0] == Expr::symbol(RAISED) => match &list[1] {
+            Expr::Literal(Str(message)) => Some(message.clone()),
+            obj => Some(obj.to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// Compile a program along with the prelude, describing any errors
+fn compile(prog: Vec<Syntax>) -> Result<Image, String> {
+    let describe = |e: Error| e.to_string().trim_end().to_string();
+
+    let prog = compiler::prelude().into_iter().chain(prog).collect();
+    let asm = emit::compile(prog).map_err(|e| describe(Error::compilation(e)))?;
+
+    jit::load(&asm).map_err(describe)
+}
+/// Number of parentheses left open in some source code
+pub fn depth(source: &str) -> i64 {
+    let (mut depth, mut string, mut comment) = (0, false, false);
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if string => {
+                chars.next();
+            }
+            '"' if !comment => string = !string,
+            '\n' if comment => comment = false,
+            _ if string || comment => {}
+            ';' => comment = true,
+            // Characters like #\( don't count
+            '#' if chars.peek() == Some(&'\\') => {
+                chars.nth(1);
+            }
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth
+}
+
+/// Location of the history file, if there is a home directory
+fn history() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".inc_history"))
+}
+
+/// Outcome of reading a line
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Text(String),
+    Interrupted,
+    Eof,
+}
+
+/// Keys understood by the editor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillStart,
+    KillEnd,
+    Complete,
+    Interrupt,
+    Eof,
+    Ignore,
+}
+
+/// A line being edited, with the cursor somewhere in it
+#[derive(Debug, Default)]
+pub struct Buffer {
+    pub chars: Vec<char>,
+    pub cursor: usize,
+}
+
+impl Buffer {
+    /// Apply a key that edits the line or moves the cursor
+    pub fn key(&mut self, key: Key) {
+        match key {
+            Key::Char(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            Key::Delete | Key::Eof if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.chars.len(),
+            Key::KillStart => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::KillEnd => self.chars.truncate(self.cursor),
+            _ => {}
+        }
+    }
+
+    /// Complete the name from `start` to the cursor with the longest prefix
+    /// all the names share
+    ///
+    /// Returns whether the names are worth listing, which they are when there
+    /// are several of them and none of them completes the name any further.
+    pub fn complete(&mut self, start: usize, names: &[String]) -> bool {
+        let first: Vec<char> = match names.first() {
+            Some(name) => name.chars().collect(),
+            None => return false,
+        };
+
+        let common = names.iter().skip(1).fold(first.len(), |len, name| {
+            first.iter().zip(name.chars()).take(len).take_while(|(a, b)| *a == b).count()
+        });
+
+        let typed = self.cursor.saturating_sub(start);
+        if common > typed {
+            self.chars.splice(start..self.cursor, first[..common].iter().copied());
+            self.cursor = start + common;
+            return false;
+        }
+
+        names.len() > 1
+    }
+
+    /// Replace the whole line, with the cursor at the end
+    pub fn set(&mut self, line: &str) {
+        self.chars = line.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+}
+
+/// Completes the name at the cursor of a line, see [Editor::read]
+pub type Completer<'a> = dyn Fn(&str, usize) -> (usize, Vec<String>) + 'a;
+
+/// A line editor with history
+pub struct Editor {
+    history: Vec<String>,
+    file: Option<PathBuf>,
+    terminal: bool,
+}
+
+impl Editor {
+    /// An editor with the history in `file`, if any
+    pub fn new(file: Option<PathBuf>) -> Self {
+        let history = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map(|h| h.lines().map(String::from).collect())
+            .unwrap_or_default();
+
+        Editor { history, file, terminal: unsafe { libc::isatty(libc::STDIN_FILENO) == 1 } }
+    }
+
+    /// Add an entry to the history, on a single line
+    pub fn remember(&mut self, entry: &str) {
+        let entry = entry.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if self.history.last() != Some(&entry) {
+            self.history.push(entry);
+        }
+    }
+
+    /// Write the history back to its file, after a session on a terminal
+    pub fn save(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) if self.terminal => fs::write(file, self.history.join("\n") + "\n"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a line after showing `prompt`
+    ///
+    /// `complete` takes the line and the cursor on Tab, and returns where the
+    /// name being completed starts and the names completing it, see
+    /// [Buffer::complete].
+    pub fn read(&mut self, prompt: &str, complete: &Completer) -> io::Result<Line> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+
+        if !self.terminal {
+            let mut line = String::new();
+
+            return match io::stdin().lock().read_line(&mut line)? {
+                0 => Ok(Line::Eof),
+                _ => Ok(Line::Text(line.trim_end_matches('\n').to_string())),
+            };
+        }
+
+        let saved = raw()?;
+        let line = self.edit(prompt, complete);
+        restore(&saved)?;
+
+        println!();
+        line
+    }
+
+    /// Edit a line in raw mode till it is done
+    fn edit(&mut self, prompt: &str, complete: &Completer) -> io::Result<Line> {
+        let mut buffer = Buffer::default();
+        // Position in the history, the line being edited is at the end
+        let mut index = self.history.len();
+        let mut current = String::new();
+
+        loop {
+            let key = read_key()?;
+
+            match key {
+                Key::Enter => return Ok(Line::Text(buffer.text())),
+                Key::Interrupt => return Ok(Line::Interrupted),
+                Key::Eof if buffer.chars.is_empty() => return Ok(Line::Eof),
+                Key::Up if index > 0 => {
+                    if index == self.history.len() {
+                        current = buffer.text();
+                    }
+                    index -= 1;
+                    buffer.set(&self.history[index]);
+                }
+                Key::Down if index < self.history.len() => {
+                    index += 1;
+                    buffer.set(self.history.get(index).unwrap_or(&current));
+                }
+                Key::Complete => {
+                    let (start, names) = complete(&buffer.text(), buffer.cursor);
+                    if buffer.complete(start, &names) {
+                        print!("\r\n{}\r\n", names.join("  "));
+                    }
+                }
+                key => buffer.key(key),
+            }
+
+            // Redraw the line and put the cursor back where it belongs
+            let column = prompt.chars().count() + buffer.cursor;
+            print!("\r{}{}\x1b[K\r\x1b[{}C", prompt, buffer.text(), column);
+            io::stdout().flush()?;
+        }
+    }
+}
+
+/// Read a key press from the terminal in raw mode
+fn read_key() -> io::Result<Key> {
+    let mut stdin = io::stdin();
+    let mut byte = || -> io::Result<u8> {
+        let mut b = [0];
+        match stdin.read(&mut b)? {
+            0 => Ok(4),
+            _ => Ok(b[0]),
+        }
+    };
+
+    let key = match byte()? {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        6 => Key::Right,
+        9 => Key::Complete,
+        11 => Key::KillEnd,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillStart,
+        // Escape sequences for the arrows and friends
+        27 => match (byte()?, byte()?) {
+            (b'[', b'A') => Key::Up,
+            (b'[', b'B') => Key::Down,
+            (b'[', b'C') => Key::Right,
+            (b'[', b'D') => Key::Left,
+            (b'[', b'H') | (b'O', b'H') => Key::Home,
+            (b'[', b'F') | (b'O', b'F') => Key::End,
+            (b'[', b'3') if byte()? == b'~' => Key::Delete,
+            _ => Key::Ignore,
+        },
+        b if b < 32 => Key::Ignore,
+        b if b < 128 => Key::Char(b as char),
+        // The rest of a UTF-8 encoded character
+        b => {
+            let len = b.leading_ones() as usize;
+            let mut bytes = vec![b];
+            for _ in 1..len {
+                bytes.push(byte()?);
+            }
+
+            match String::from_utf8(bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Ignore,
+            }
+        }
+    };
+
+    Ok(key)
+}
+
+/// Put the terminal in raw mode, returning the settings to restore
+fn raw() -> io::Result<libc::termios> {
+    unsafe {
+        let mut saved = mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = saved;
+        raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(saved)
+    }
+}
+
+fn restore(saved: &libc::termios) -> io::Result<()> {
+    match unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, saved) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth() {
+        assert_eq!(super::depth("(+ 1 2)"), 0);
+        assert_eq!(super::depth("(let ((x 1))\n"), 1);
+        assert_eq!(super::depth(r#"(display ")(" ; (("#), 1);
+        assert_eq!(super::depth("#\\( (f)"), 0);
+    }
+
+    #[test]
+    fn buffer() {
+        let mut b = Buffer::default();
+        "(car x)".chars().for_each(|c| b.key(Key::Char(c)));
+
+        b.key(Key::Left);
+        b.key(Key::Backspace);
+        b.key(Key::Char('y'));
+        assert_eq!(b.text(), "(car y)");
+
+        b.key(Key::Home);
+        b.key(Key::Delete);
+        b.key(Key::KillEnd);
+        assert_eq!((b.text(), b.cursor), (String::new(), 0));
+    }
+
+    #[test]
+    fn complete() {
+        let mut s = Session::new();
+        s.eval("(define (twice x) (* x 2)) (define (thrice x) (* x 3))");
+
+        let (span, candidates) = s.complete("(let ((total 0)) (t", 19);
+        let names: Vec<&str> = candidates.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(span, 18..19);
+        assert_eq!(&names[..3], ["total", "thrice", "twice"]);
+
+        let mut b = Buffer::default();
+        b.set("(tw");
+        assert!(!b.complete(1, &[String::from("twice")]));
+        assert_eq!((b.text(), b.cursor), (String::from("(twice"), 6));
+
+        // Names sharing nothing more are listed instead
+        b.set("(t");
+        let names = [String::from("thrice"), String::from("twice")];
+        assert!(b.complete(1, &names));
+        assert_eq!(b.text(), "(t");
+        assert!(!b.complete(1, &[]));
+    }
+
+    #[test]
+    fn eval() {
+        let mut s = Session::new();
+
+        assert_eq!(s.eval("(+ 1 2)"), "3");
+        assert_eq!(s.eval("(car 1)"), "Exception: car: expected pair, got 1");
+        assert_eq!(s.eval("(raise 'oops)"), "Exception: 'oops");
+        assert!(s.eval("(f 1)").contains("Undefined reference to `f`"));
+        assert!(s.eval("(car y)").contains("Undefined variable y"));
+    }
+
+    #[test]
+    fn definitions() {
+        let mut s = Session::new();
+
+        assert_eq!(s.eval("(define (twice x) (* x 2))"), "");
+        assert_eq!(s.eval("(define (quad x) (twice (twice x)))"), "");
+        assert_eq!(s.eval("(quad 3)"), "12");
+
+        // Redefinitions replace the old function for its callers as well
+        assert_eq!(s.eval("(define (twice x) (+ (* x 2) 1)) (quad 3)"), "15");
+
+        // Definitions that fail to compile are forgotten
+        assert!(s.eval("(define (twice x) (f x))").contains("Undefined reference"));
+        assert_eq!(s.eval("(twice 1)"), "3");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse(":load \"lib.scm\""), Ok(Command::Load(String::from("lib.scm"))));
+        assert_eq!(Command::parse(":quit\n"), Ok(Command::Quit));
+        assert!(Command::parse(":help").unwrap_err().contains("Unknown command `:help`"));
+        assert_eq!(Command::parse(":asm "), Err(String::from("`:asm` expects an expression")));
+
+        let mut s = Session::new();
+        let mut run = |input: &str| s.command(Command::parse(input).unwrap());
+
+        assert_eq!(run(":ast (f 1)"), "List([Identifier(\"f\"), Literal(Number(1))])");
+        assert!(run(":expand (guard (e (#t 1)) 2)").contains("(%guard"));
+        assert_eq!(run(":type (cons 1 2)"), "pair");
+        assert_eq!(run(":type (car 1)"), "Exception: car: expected pair, got 1");
+
+        assert_eq!(run(":type (define (twice x) (* x 2))"), "");
+        assert_eq!(run(":type (twice 2.5)"), "number");
+
+        let asm = run(":asm (twice 1)");
+        assert!(asm.contains("\"scm:twice\":"));
+        assert!(!asm.contains("\"eq?\":"));
+        assert!(!asm.contains(&format!("\"{}\":", exceptions::DISPATCH)));
+    }
+
+    #[test]
+    fn load() {
+        let path = env::temp_dir().join(format!("inc-repl-{}.scm", std::process::id()));
+        fs::write(&path, "(define (twice x) (* x 2)) (twice 1)").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut s = Session::new();
+        assert_eq!(s.load(&path), "2");
+        assert_eq!(s.eval("(twice 21)"), "42");
+        assert_eq!(s.eval(&format!("(load \"{}\") (twice 2)", path)), "4");
+        assert!(s.load("/does/not/exist.scm").contains("Failed to read /does/not/exist.scm"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
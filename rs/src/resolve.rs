@@ -0,0 +1,237 @@
+//! References to names nothing defines
+//!
+//! A call to a function the program doesn't define is compiled like any other
+//! call, and the linker or the [jit](crate::jit) is the first to notice that
+//! the function is missing, without knowing where it was called from. A
+//! variable nothing binds fails in code generation just the same. Programs are
+//! checked for both before they are compiled instead, so that every error
+//! points at the name:
+//!
+//! ```
+//! use inc::{parser, resolve};
+//!
+//! let source = "(define (twice x) (* x 2)) (twice (thrice 1)) (car)";
+//! let prog = parser::parse(source).unwrap();
+//! let errors = resolve::check(&prog, &[]).unwrap_err();
+//!
+//! assert_eq!(errors[0].message, "Undefined reference to `thrice`");
+//! assert_eq!(errors[0].name.as_ref().unwrap().span(source), Some(35..41));
+//! assert_eq!(errors[1].message, "Primitive `car` doesn't take 0 arguments");
+//! ```
+//!
+//! A name is defined if a lambda or a `let` around it binds it, if it is
+//! defined anywhere in the program, or if it is a primitive, a function of the
+//! runtime or a [callback](crate::callbacks). Definitions in a body count as
+//! global, which may let a few wrong references through but never rejects a
+//! program that compiles. Forms that don't [validate](crate::validate) or
+//! [expand](crate::lang::expand) are reported along with the references, and
+//! nothing inside them is checked.
+use crate::{
+    callbacks,
+    compiler::Fault,
+    core::{Closure, Core, Expr::*, Ident, Name, Syntax},
+    hash, lang, primitives, rt, semantic, validate,
+};
+use std::collections::HashSet;
+
+/// Names defined at the top level of the program or elsewhere
+pub(crate) type Globals<'a> = HashSet<&'a str, hash::Fast>;
+
+/// Fail with an error for every reference to a name nothing defines
+///
+/// `prog` is a whole program with its libraries and loads resolved, and
+/// `external` the names it can use from elsewhere, like the functions defined
+/// in units linked with it.
+pub fn check(prog: &[Syntax], external: &[String]) -> Result<(), Vec<Fault>> {
+    let mut faults = vec![];
+
+    // Forms are validated and expanded first, so that a malformed form is
+    // reported as one error rather than as references to its keyword and
+    // binders
+    let expanded: Vec<Syntax> = prog
+        .iter()
+        .filter_map(|e| {
+            validate::check(e)
+                .and_then(|_| lang::expand(e.clone()))
+                .map_err(|errors| faults.extend(errors))
+                .ok()
+        })
+        .collect();
+
+    // Definitions in forms that don't expand still count, so that their own
+    // error isn't followed by one for every reference to them
+    let mut globals: Globals = external.iter().map(String::as_str).collect();
+    prog.iter().chain(&expanded).for_each(|e| defined(e, &mut globals));
+
+    for e in &expanded {
+        visit(e, &mut vec![], &globals, &mut faults);
+    }
+
+    if faults.is_empty() {
+        Ok(())
+    } else {
+        Err(faults)
+    }
+}
+
+/// Collect the names defined anywhere in an expression
+pub(crate) fn defined<'a>(prog: &'a Syntax, globals: &mut Globals<'a>) {
+    match prog {
+        Define { name, val } => {
+            globals.insert(name.as_str());
+            defined(val, globals)
+        }
+        Let { bindings, body } => {
+            bindings.iter().for_each(|(_, value)| defined(value, globals));
+            body.iter().for_each(|e| defined(e, globals));
+        }
+        Lambda(Closure { body, .. }) => body.iter().for_each(|e| defined(e, globals)),
+        Cond { pred, then, alt } => {
+            defined(pred, globals);
+            defined(then, globals);
+            alt.iter().for_each(|e| defined(e, globals));
+        }
+        List(list) | Vector(list) => list.iter().for_each(|e| defined(e, globals)),
+        Identifier(_) | Literal(_) => {}
+    }
+}
+
+/// Check the references of an expression evaluated with `scope` bound
+fn visit<'a>(
+    prog: &'a Syntax,
+    scope: &mut Vec<&'a str>,
+    globals: &Globals,
+    faults: &mut Vec<Fault>,
+) {
+    let known = |name: &str, scope: &[&str]| {
+        scope.contains(&name) || globals.contains(name) || callbacks::find(name).is_some()
+    };
+
+    match prog {
+        Identifier(name) => {
+            if !known(name.as_str(), scope.as_slice()) && !semantic::builtin(name) {
+                faults.push(fault(name, format!("Undefined variable `{}`", name)))
+            }
+        }
+
+        Let { bindings, body } => {
+            let depth = scope.len();
+            scope.extend(bindings.iter().map(|(name, _)| name.as_str()));
+            bindings.iter().for_each(|(_, value)| visit(value, scope, globals, faults));
+            body.iter().for_each(|e| visit(e, scope, globals, faults));
+            scope.truncate(depth);
+        }
+
+        Lambda(Closure { formals, body, .. }) => {
+            let depth = scope.len();
+            scope.extend(formals.iter().map(Name::as_str));
+            body.iter().for_each(|e| visit(e, scope, globals, faults));
+            scope.truncate(depth);
+        }
+
+        Define { val, .. } => visit(val, scope, globals, faults),
+
+        Cond { pred, then, alt } => {
+            visit(pred, scope, globals, faults);
+            visit(then, scope, globals, faults);
+            alt.iter().for_each(|e| visit(e, scope, globals, faults));
+        }
+
+        List(list) => match list.as_slice() {
+            // Quoted data isn't code
+            [Identifier(quote), _] if quote == "quote" => {}
+            [Identifier(head), args @ ..] => {
+                if !known(head.as_str(), scope.as_slice()) && !callable(head, args) {
+                    let message = if primitives::names().contains(&head.as_str()) {
+                        let plural = if args.len() == 1 { "" } else { "s" };
+                        format!(
+                            "Primitive `{}` doesn't take {} argument{}",
+                            head,
+                            args.len(),
+                            plural
+                        )
+                    } else {
+                        format!("Undefined reference to `{}`", head)
+                    };
+                    faults.push(fault(head, message));
+                }
+                args.iter().for_each(|e| visit(e, scope, globals, faults));
+            }
+            _ => list.iter().for_each(|e| visit(e, scope, globals, faults)),
+        },
+
+        Vector(list) => list.iter().for_each(|e| visit(e, scope, globals, faults)),
+
+        Literal(_) => {}
+    }
+}
+
+/// Can a name nothing in the program binds be applied to the arguments?
+///
+/// Primitives are picky about the shape of some of their arguments, like the
+/// function `map` applies being a name. Lambdas are lifted out into names
+/// before any primitive sees them, so anything but a literal is taken to be a
+/// name here.
+fn callable(name: &str, args: &[Syntax]) -> bool {
+    let ident = Ident::new(name);
+    let args: Vec<Core> = args
+        .iter()
+        .map(|arg| match arg {
+            Literal(l) => Literal(l.clone()),
+            _ => Identifier(Ident::new("_")),
+        })
+        .collect();
+
+    primitives::defined(&ident, &args) || rt::defined(&ident)
+}
+
+fn fault(name: &Name, message: String) -> Fault {
+    Fault { message, name: Some(name.clone()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn errors(source: &str) -> Vec<String> {
+        let prog = parser::parse(source).unwrap();
+        check(&prog, &[String::from("elsewhere")])
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|fault| fault.message)
+            .collect()
+    }
+
+    #[test]
+    fn references() {
+        assert!(errors("(define (f x) (g x)) (define (g y) (+ y 1)) (f 1)").is_empty());
+        assert!(errors("(let ((x 1)) (lambda (y) (+ x y)))").is_empty());
+        assert!(errors("(elsewhere 1) (display (string-length \"a\"))").is_empty());
+        assert!(errors("'(f x) (quote (g y))").is_empty());
+        assert!(errors("(call/cc (lambda (k) (k 1)))").is_empty());
+        assert!(errors("(map (lambda (x) x) (list 1 2))").is_empty());
+        assert!(errors("(%coverage 0 1 5)").is_empty());
+
+        assert_eq!(errors("(f 1)"), ["Undefined reference to `f`"]);
+        assert_eq!(errors("(let ((x 1)) y)"), ["Undefined variable `y`"]);
+        assert_eq!(errors("(lambda (x) x) x"), ["Undefined variable `x`"]);
+        assert_eq!(errors("(car)"), ["Primitive `car` doesn't take 0 arguments"]);
+        assert_eq!(
+            errors("(f (g 1))"),
+            ["Undefined reference to `f`", "Undefined reference to `g`"]
+        );
+
+        // Malformed forms are one error, not references to their keyword
+        assert_eq!(
+            errors("(guard (e (1))) y"),
+            ["Invalid guard: `(guard (e (1)))`", "Undefined variable `y`"]
+        );
+    }
+
+    #[test]
+    fn prelude() {
+        assert!(check(&crate::compiler::prelude(), &[]).is_ok());
+    }
+}
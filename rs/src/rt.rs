@@ -5,17 +5,35 @@
 //! called from scheme functions.
 
 use crate::{
+    bignum::{self, Big},
     core::{
         Core,
         Expr::{self, *},
         Ident,
         Literal::*,
+        Syntax,
     },
+    exceptions::{self, Status, Target},
+    gc,
     immediate::{self, *},
+    numbers::{self, Number},
+    parser, primitives, strings, symbols,
+    tags::{self, tag, untag},
     x86::WORDSIZE,
 };
 
-use std::{convert::TryFrom, ffi::CStr, io::Write, os::raw::c_char};
+use std::{
+    arch::asm,
+    cell::{Cell, RefCell},
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap, HashSet,
+    },
+    convert::TryFrom,
+    fmt,
+    hash::{Hash, Hasher},
+    io::Write,
+};
 
 /// A scheme object
 #[repr(C)]
@@ -32,23 +50,19 @@ impl Object {
     }
 
     pub fn deref(&self) -> Core {
-        match (self.0) & MASK {
+        match tag(self.0) {
             NIL => Literal(Nil),
-            NUM => Literal(Number(self.0 >> SHIFT)),
+            NUM => Literal(Number(untag(self.0))),
             BOOL => Literal(Boolean(self.0 == TRUE)),
-            CHAR => Literal(Char((self.0 >> SHIFT) as u8)),
+            CHAR => Literal(Char(untag(self.0) as u8)),
             PAIR => List(vec![car(*self).deref(), cdr(*self).deref()]),
-            STR => {
-                // TODO: Read required bytes instead of looking for NUL
-                let s = unsafe { CStr::from_ptr((self.0 - STR + 8) as *const c_char) };
-                Expr::string(s.to_string_lossy())
-            }
-            SYM => {
-                let s = unsafe { CStr::from_ptr((self.0 - SYM + 16) as *const c_char) };
-                Expr::symbol(s.to_string_lossy())
-            }
+            STR if bignum::is(self.0) => Literal(Bignum(Big::decode(*self).unwrap())),
+            STR if numbers::is(self.0) => Literal(Float(Number::decode(*self).unwrap().inexact())),
+            // Strings may have NULs in them, so the length tells where they end
+            STR => Expr::string(String::from_utf8_lossy(str_bytes(self.0))),
+            SYM => Expr::symbol(String::from_utf8_lossy(sym_bytes(self.0))),
             VEC => Expr::Vector(
-                (0..vec_len(self.0)).map(|i| (Self::new(vec_nth(self.0, i)).deref())).collect(),
+                (0..vec_len(self.0)).map(|i| Self::new(vec_nth(self.0, i)).deref()).collect(),
             ),
 
             _ => unreachable!("Tried to decode object from address {} and failed", self.0),
@@ -56,119 +70,521 @@ impl Object {
     }
 }
 
+/// Returned by the functions of the runtime for ports when they fail, for the
+/// generated code to raise the error with [rt_io_error](io::rt_io_error)
+///
+/// This is never an object; it would be a vector right below address 0.
+pub const FAILED: i64 = -1;
+
+/// Functions defined in the built in runtime
+pub const FUNCTIONS: [&str; 28] = [
+    "current-jiffy",
+    "exit",
+    "gc-collected",
+    "gc-epoch",
+    "gc-guard",
+    "gc-guardian",
+    "heap-limit",
+    "jiffies-per-second",
+    "rt-eof-object",
+    "rt-eq",
+    "rt-equal",
+    "rt-hash",
+    "rt-load-shared-object",
+    "rt-new-key",
+    "rt-open-input-string",
+    "rt-open-output-string",
+    "rt-standard-error-port",
+    "rt-standard-input-port",
+    "rt-standard-output-port",
+    "rt-wake",
+    "rt-open-read",
+    "rt-open-write",
+    "rt-read",
+    "rt-time",
+    "rt-write",
+    "string=?",
+    "symbol=?",
+    "type",
+];
+
 /// Checks if a function is defined in the built in runtime
 pub fn defined(name: &Ident) -> bool {
-    [
-        "exit",
-        "rt-standard-error-port",
-        "rt-standard-input-port",
-        "rt-standard-output-port",
-        "rt-open-read",
-        "rt-open-write",
-        "rt-read",
-        "rt-write",
-        "string-length",
-        "symbol=?",
-        "type",
-    ]
-    .contains(&name.short().as_str())
+    name.free().map_or(false, |name| FUNCTIONS.contains(&name))
 }
 
 #[no_mangle]
 pub extern "C" fn print(val: Object, nested: bool) {
-    match val.0 & MASK {
+    let mut out = String::new();
+    write(&mut out, val, nested).unwrap();
+    print!("{}", out);
+
+    std::io::stdout().flush().unwrap();
+}
+
+/// Write the external representation of an object, just like `print`
+fn write(f: &mut dyn fmt::Write, val: Object, nested: bool) -> fmt::Result {
+    match tag(val.0) {
         PAIR => {
             let pcar = car(val);
             let pcdr = cdr(val);
 
             if !nested {
-                print!("(")
+                write!(f, "(")?
             };
 
-            print(pcar, false);
+            write(f, pcar, false)?;
 
             if pcdr.0 != NIL {
-                if (pcdr.0 & MASK) != PAIR {
-                    print!(" . ");
-                    print(pcdr, false);
+                if tag(pcdr.0) != PAIR {
+                    write!(f, " . ")?;
+                    write(f, pcdr, false)?;
                 } else {
-                    print!(" ");
-                    print(pcdr, true);
+                    write!(f, " ")?;
+                    write(f, pcdr, true)?;
                 }
             }
             if !nested {
-                print!(")")
+                write!(f, ")")?
             };
+
+            Ok(())
         }
-        _ => print!("{}", val.deref()),
+        _ => write!(f, "{}", val.deref()),
     }
+}
 
-    std::io::stdout().flush().unwrap();
+/// Write the human readable representation of an object, like `display`
+///
+/// Unlike `write`, strings and chars are written as is and symbols without the
+/// quote.
+fn display(f: &mut dyn fmt::Write, val: Object) -> fmt::Result {
+    match tag(val.0) {
+        STR | SYM if !numbers::boxed(val.0) => match val.deref() {
+            Literal(Str(s)) | Literal(Symbol(s)) => write!(f, "{}", s),
+            e => unreachable!("Expected a string or a symbol, got {}", e),
+        },
+
+        CHAR => write!(f, "{}", untag(val.0) as u8 as char),
+
+        PAIR => {
+            write!(f, "(")?;
+            display(f, car(val))?;
+
+            let mut rest = cdr(val);
+            while tag(rest.0) == PAIR {
+                write!(f, " ")?;
+                display(f, car(rest))?;
+                rest = cdr(rest);
+            }
+
+            if rest.0 != NIL {
+                write!(f, " . ")?;
+                display(f, rest)?;
+            }
+
+            write!(f, ")")
+        }
+
+        VEC => {
+            write!(f, "[")?;
+
+            for i in 0..vec_len(val.0) {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                display(f, Object::new(vec_nth(val.0, i)))?;
+            }
+
+            write!(f, "]")
+        }
+
+        _ => write(f, val, false),
+    }
+}
+
+/// Write the external representation of an object, like `write`
+///
+/// Unlike [Display](Object), which prints values for the REPL, the result
+/// reads back as the same datum: symbols are bare and vectors are `#(...)`.
+/// Strings and chars are escaped just the same.
+fn datum(f: &mut dyn fmt::Write, val: Object) -> fmt::Result {
+    match tag(val.0) {
+        SYM => display(f, val),
+
+        PAIR => {
+            write!(f, "(")?;
+            datum(f, car(val))?;
+
+            let mut rest = cdr(val);
+            while tag(rest.0) == PAIR {
+                write!(f, " ")?;
+                datum(f, car(rest))?;
+                rest = cdr(rest);
+            }
+
+            if rest.0 != NIL {
+                write!(f, " . ")?;
+                datum(f, rest)?;
+            }
+
+            write!(f, ")")
+        }
+
+        VEC => {
+            write!(f, "#(")?;
+
+            for i in 0..vec_len(val.0) {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                datum(f, Object::new(vec_nth(val.0, i)))?;
+            }
+
+            write!(f, ")")
+        }
+
+        _ => write(f, val, false),
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write(f, *self, false)
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn car(val: Object) -> Object {
-    assert!(((val.0) & MASK) == PAIR);
+    assert!(tag(val.0) == PAIR);
 
     Object::new(unsafe { *((val.0 - PAIR) as *mut i64) })
 }
 
 #[no_mangle]
 pub extern "C" fn cdr(val: Object) -> Object {
-    assert!((val.0 & MASK) == PAIR);
+    assert!(tag(val.0) == PAIR);
     Object::new(unsafe { *((val.0 - PAIR + 8) as *mut i64) })
 }
 
 #[no_mangle]
 pub extern "C" fn string_length(val: i64) -> Object {
-    assert!((val & MASK) == STR);
+    // Foreign functions can't transfer control to a handler, so this error
+    // can't be caught
+    if !is_string(val) {
+        eprintln!("Exception: {}", type_error("string-length", STR, Object::new(val)));
+        Status::Type.exit()
+    }
 
-    let len = unsafe { *((val - STR) as *mut usize) };
-    Object::immediate(i64::try_from(len).unwrap())
+    Object::immediate(i64::try_from(str_len(val)).unwrap())
 }
 
+/// Are both the objects strings with the same characters?
 #[no_mangle]
-pub extern "C" fn symbol_eq(a: i64, b: i64) -> i64 {
-    if (a == b) && ((a & MASK) == SYM) {
+pub extern "C" fn string_eq(a: i64, b: i64) -> i64 {
+    let same = is_string(a) && is_string(b) && str_bytes(a) == str_bytes(b);
+    if same {
         TRUE
     } else {
         FALSE
     }
 }
 
+/// Are two objects the same, or structurally equal if `deep`?
+///
+/// Symbols with the same name are always the same, see `symbol_eq`, and so are
+/// bignums with the same value and flonums with the same bits.
+///
+/// Deep comparison terminates on cyclic structures too. A pair of objects
+/// already being compared is assumed to be equal when seen again, since any
+/// difference between them is found along the first path anyway.
+pub fn equal(a: Object, b: Object, deep: bool) -> bool {
+    compare(a, b, deep, &mut HashSet::new())
+}
+
+/// See [equal], `seen` has the pairs and vectors compared so far
+fn compare(mut a: Object, mut b: Object, deep: bool, seen: &mut HashSet<(i64, i64)>) -> bool {
+    // Loop down the spine of lists, recursion would overflow on long ones
+    while deep && a.0 != b.0 && tag(a.0) == PAIR && tag(b.0) == PAIR {
+        if !seen.insert((a.0, b.0)) {
+            return true;
+        }
+
+        if !compare(car(a), car(b), deep, seen) {
+            return false;
+        }
+
+        a = cdr(a);
+        b = cdr(b);
+    }
+
+    if a.0 == b.0 {
+        return true;
+    }
+
+    if numbers::boxed(a.0) || numbers::boxed(b.0) {
+        return match (Number::decode(a), Number::decode(b)) {
+            (Some(Number::Inexact(x)), Some(Number::Inexact(y))) => x.to_bits() == y.to_bits(),
+            (x, y) => x == y,
+        };
+    }
+
+    match (tag(a.0), tag(b.0)) {
+        (STR, STR) if deep => str_bytes(a.0) == str_bytes(b.0),
+        (VEC, VEC) if deep && !seen.insert((a.0, b.0)) => true,
+        (VEC, VEC) if deep => {
+            vec_len(a.0) == vec_len(b.0)
+                && (0..vec_len(a.0)).all(|i| {
+                    let (x, y) = (Object::new(vec_nth(a.0, i)), Object::new(vec_nth(b.0, i)));
+                    compare(x, y, deep, seen)
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Hash an object consistently with [equal]
+///
+/// Identity hashes of heap objects are derived from their addresses, which
+/// change when the collector moves them; see [gc_epoch](crate::gc::gc_epoch).
+pub fn hash(val: Object, deep: bool, state: &mut impl Hasher) {
+    match tag(val.0) {
+        STR if bignum::is(val.0) => Big::decode(val).hash(state),
+        STR if numbers::is(val.0) => Number::decode(val).map(|n| n.inexact().to_bits()).hash(state),
+        SYM => sym_bytes(val.0).hash(state),
+        STR if deep => str_bytes(val.0).hash(state),
+        PAIR if deep => {
+            hash(car(val), deep, state);
+            hash(cdr(val), deep, state);
+        }
+        VEC if deep => {
+            vec_len(val.0).hash(state);
+            for i in 0..vec_len(val.0) {
+                hash(Object::new(vec_nth(val.0, i)), deep, state)
+            }
+        }
+        _ => val.0.hash(state),
+    }
+}
+
+/// Compare two objects by identity, boxed numbers included
+#[no_mangle]
+pub const extern "C" fn rt_eq(a: i64, b: i64) -> i64 {
+    if a == b {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+/// Compare two objects with [equal], like `eqv?` unless `deep` is true
+#[no_mangle]
+pub extern "C" fn rt_equal(a: Object, b: Object, deep: Object) -> Object {
+    if equal(a, b, deep.0 != FALSE) {
+        Object::new(TRUE)
+    } else {
+        Object::new(FALSE)
+    }
+}
+
+/// A non negative fixnum hash of an object, see [hash]
+#[no_mangle]
+pub extern "C" fn rt_hash(val: Object, deep: Object) -> Object {
+    let mut state = DefaultHasher::new();
+    hash(val, deep.0 != FALSE, &mut state);
+
+    Object::immediate((state.finish() >> 4) as i64)
+}
+
+/// The character at index `k` of a string; the caller checks the bounds
+#[no_mangle]
+pub extern "C" fn rt_string_ref(val: Object, k: Object) -> Object {
+    let c = str_bytes(val.0)[untag(k.0) as usize];
+    Object::new(tags::immediate(i64::from(c), CHAR))
+}
+
+/// Replace the character at index `k` of a mutable string; the caller checks
+/// the bounds
+#[no_mangle]
+pub extern "C" fn rt_string_set(val: Object, k: Object, c: Object) -> Object {
+    str_bytes_mut(val.0)[untag(k.0) as usize] = untag(c.0) as u8;
+    Object::new(NIL)
+}
+
+/// Set every character of a new string to `c` and return the string
+#[no_mangle]
+pub extern "C" fn rt_string_fill(val: Object, c: Object) -> Object {
+    for b in str_bytes_mut(val.0) {
+        *b = untag(c.0) as u8;
+    }
+
+    val
+}
+
+/// Raise an attempt to modify a string literal
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+#[no_mangle]
+pub extern "C" fn rt_immutable_error(primitive: i64, val: Object) -> Target {
+    let primitive = primitives::CHECKED[primitive as usize];
+    exceptions::error(&format!("{}: {} is immutable", primitive, val))
+}
+
+#[no_mangle]
+pub const extern "C" fn symbol_eq(a: i64, b: i64) -> i64 {
+    // Symbols are interned, see `symbols`
+    if tag(a) == SYM && a == b {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+/// Is the object a string? Boxed numbers are string objects too, see [numbers]
+pub const fn is_string(val: i64) -> bool {
+    tag(val) == STR && !numbers::boxed(val)
+}
+
+/// Number of bytes in a string object, ignoring the immutable bit of literals
+fn str_len(val: i64) -> usize {
+    assert!(is_string(val));
+
+    let len = unsafe { *((val - STR) as *const i64) };
+    (len & !strings::IMMUTABLE) as usize
+}
+
+pub(crate) fn str_bytes<'a>(val: i64) -> &'a [u8] {
+    unsafe { std::slice::from_raw_parts((val - STR + 8) as *const u8, str_len(val)) }
+}
+
+// Only strings allocated at runtime may be modified, see `strings`
+fn str_bytes_mut<'a>(val: i64) -> &'a mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut((val - STR + 8) as *mut u8, str_len(val)) }
+}
+
+pub(crate) fn sym_bytes<'a>(val: i64) -> &'a [u8] {
+    assert!(tag(val) == SYM);
+
+    unsafe {
+        let len = *((val - SYM + 8) as *const usize);
+        std::slice::from_raw_parts((val - SYM + 16) as *const u8, len)
+    }
+}
+
 // Get a string pointer from a string object
 fn str_str(val: i64) -> String {
-    assert!((val & MASK) == STR);
+    assert!(tag(val) == STR);
 
-    let s = unsafe { CStr::from_ptr((val - STR + 8) as *const c_char) };
-    s.to_string_lossy().into_owned()
+    String::from_utf8_lossy(str_bytes(val)).into_owned()
 }
 
 fn vec_len(val: i64) -> i64 {
-    assert!((val & MASK) == VEC);
+    assert!(tag(val) == VEC);
 
     unsafe { *((val - VEC) as *const i64) }
 }
 
 fn vec_nth(val: i64, n: i64) -> i64 {
-    assert!((val & MASK) == VEC);
+    assert!(tag(val) == VEC);
 
     unsafe { *((val - VEC + WORDSIZE + (n * WORDSIZE)) as *const i64) }
 }
 
+/// Allocate a length prefixed and NUL terminated string on the heap
+pub(crate) fn string(data: &[u8]) -> Object {
+    let r12 = heap();
+
+    let plen = r12 as *mut usize;
+    let pstr = (r12 + 8) as *mut u8;
+
+    // The heap is always zeroed, so the NUL is already there
+    allocate(8 + data.len() + 1);
+
+    unsafe {
+        std::ptr::write(plen, data.len());
+        std::ptr::copy(data.as_ptr(), pstr, data.len());
+    }
+
+    Object::new(plen as i64 | STR)
+}
+
+/// Allocate a pair on the heap
+pub(crate) fn cons(car: Object, cdr: Object) -> Object {
+    let r12 = heap();
+    allocate(2 * WORDSIZE as usize);
+
+    unsafe {
+        std::ptr::write(r12 as *mut i64, car.0);
+        std::ptr::write((r12 + 8) as *mut i64, cdr.0);
+    }
+
+    Object::new(r12 as i64 | PAIR)
+}
+
+/// Allocate a length prefixed vector on the heap
+pub(crate) fn vector(items: &[Object]) -> Object {
+    let r12 = heap();
+    allocate((items.len() + 1) * WORDSIZE as usize);
+
+    unsafe {
+        std::ptr::write(r12 as *mut i64, items.len() as i64);
+        for (i, item) in items.iter().enumerate() {
+            std::ptr::write((r12 + 8 * (i + 1)) as *mut i64, item.0);
+        }
+    }
+
+    Object::new(r12 as i64 | VEC)
+}
+
+/// Allocate the object for a datum read at runtime, the inverse of `deref`
+///
+/// See [parser::read](crate::parser::read) for the syntax of improper lists.
+fn build(datum: &Syntax) -> Object {
+    match datum {
+        Literal(Str(s)) => string(s.as_bytes()),
+        Literal(Symbol(s)) => symbols::intern(s),
+        Identifier(s) => symbols::intern(s),
+        Literal(Float(f)) => Number::Inexact(*f).encode(),
+        Literal(Bignum(n)) => n.encode(),
+        Literal(l) => {
+            let core: Core = Literal(l.clone());
+            Object::new(immediate::to(&core).unwrap())
+        }
+        List(items) => {
+            let (items, tail) = match items.as_slice() {
+                [init @ .., Identifier(dot), tail] if dot == "." => (init, build(tail)),
+                _ => (items.as_slice(), Object::new(NIL)),
+            };
+
+            items.iter().rev().fold(tail, |rest, item| cons(build(item), rest))
+        }
+        Vector(items) => vector(&items.iter().map(build).collect::<Vec<_>>()),
+        _ => unreachable!("Unexpected datum {}", datum),
+    }
+}
+
 /// Read current heap pointer from r12
 ///
 /// See [Exploring ARM inline assembly in
 /// Rust](http://embed.rs/articles/2016/arm-inline-assembly-rust) for an intro
 /// into inline asm.
+///
+/// There is no scheme heap without the `native` feature, since nothing runs
+/// generated code then.
+#[cfg(feature = "native")]
 pub fn heap() -> usize {
     let r12: usize;
     unsafe {
-        llvm_asm!("nop" : "={r12}"(r12) ::: "intel");
+        asm!("nop", out("r12") r12);
     }
     r12
 }
 
+#[cfg(not(feature = "native"))]
+pub fn heap() -> usize {
+    unreachable!("The scheme heap exists only with the native feature")
+}
+
 /// Allocate space on the scheme heap
 ///
 /// In terms of lines of machine code vs time taken to write, this function tops
@@ -179,22 +595,19 @@ pub fn heap() -> usize {
 ///
 /// ```asm
 /// inc::rt::allocate:
-///     push    rax
 ///     add     rdi, 7
 ///     and     rdi, -8
-///     mov     qword, ptr, [rsp], rdi
-///     add     r12, qword, ptr, [rsp]
-///     pop     rax
+///     add     r12, rdi
 ///     ret
 /// ```
 ///
 /// The *correct* way to write this function is to mark r12 as clobbered - tell
 /// the compiler explicitly that register r12 will be modified. This is done
-/// with the 4th argument to
-/// [std::asm!](https://doc.rust-lang.org/std/macro.asm.html) macro.
+/// with an `out` operand discarding the register in the
+/// [std::arch::asm!](https://doc.rust-lang.org/std/arch/macro.asm.html) macro.
 ///
 /// ```rs
-/// asm!("add r12, $0" :: "m"(aligned) : "r12" : "intel");
+/// asm!("add r12, {0}", in(reg) aligned, out("r12") _);
 /// ```
 ///
 /// As per [System V Calling Convention](crate::x86), `r12` is a callee saved
@@ -205,12 +618,9 @@ pub fn heap() -> usize {
 /// ```asm
 /// inc::rt::allocate:
 ///     push    r12                     <- Save r12
-///     push    rax
 ///     add     rdi, 7
 ///     and     rdi, -8
-///     mov     qword, ptr, [rsp], rdi
-///     add     r12, qword, ptr, [rsp]
-///     add     rsp, 8
+///     add     r12, rdi
 ///     pop     r12                     <- Restore r12
 ///     ret
 /// ```
@@ -228,12 +638,111 @@ pub fn heap() -> usize {
 /// is a [minimal reproduction example](https://godbolt.org/z/MM6ezC).
 ///
 /// Know better? Please let me know!
+///
+/// Allocations from the runtime can't trigger a garbage collection since the
+/// collector can't find the scheme stack frames from here; the program aborts
+/// if the heap is full instead.
 pub fn allocate(size: usize) {
     let aligned = ((size + 7) / 8) * 8;
 
+    if let Some(limit) = gc::limit() {
+        if heap() + aligned > limit {
+            eprintln!("Out of memory");
+            Status::Memory.exit()
+        }
+    }
+
+    #[cfg(feature = "native")]
     unsafe {
         // Increment r12 to allocate space
-        llvm_asm!("add r12, $0" :: "m"(aligned) :: "intel");
+        asm!("add r12, {0}", in(reg) aligned);
+    }
+}
+
+/// Room left at the end of the stack to report an overflow, in bytes
+pub const STACK_MARGIN: usize = 64 * 1024;
+
+/// Lowest address the scheme stack may grow to on the current thread
+///
+/// A small margin is left at the end of the stack to report the overflow.
+#[cfg(feature = "native")]
+#[no_mangle]
+pub extern "C" fn rt_stack_limit() -> *const u8 {
+    stack().wrapping_add(STACK_MARGIN)
+}
+
+/// Raise a stack overflow
+#[no_mangle]
+pub extern "C" fn rt_stack_overflow() -> Target {
+    exceptions::failure(Status::Stack, "stack overflow")
+}
+
+/// Raise a primitive applied to a value of the wrong type
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED) and
+/// `expected` is the tag of the expected type.
+#[no_mangle]
+pub extern "C" fn rt_type_error(primitive: i64, expected: i64, val: Object) -> Target {
+    let primitive = primitives::CHECKED[primitive as usize];
+    exceptions::failure(Status::Type, &type_error(primitive, expected, val))
+}
+
+/// Raise an index out of the range of a vector
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED) and
+/// `val` is the object being indexed, or `()` for an invalid length or
+/// character code.
+#[no_mangle]
+pub extern "C" fn rt_range_error(primitive: i64, index: Object, val: Object) -> Target {
+    let primitive = primitives::CHECKED[primitive as usize];
+
+    if primitive == "integer->char" {
+        exceptions::error(&format!("{}: invalid character code {}", primitive, index))
+    } else if val.0 == NIL {
+        exceptions::error(&format!("{}: invalid length {}", primitive, index))
+    } else {
+        exceptions::error(&format!("{}: index {} is out of range for {}", primitive, index, val))
+    }
+}
+
+/// Raise a division by zero in `primitive`
+///
+/// `primitive` is an index into [CHECKED](crate::primitives::CHECKED).
+#[no_mangle]
+pub extern "C" fn rt_division_error(primitive: i64) -> Target {
+    let primitive = primitives::CHECKED[primitive as usize];
+    exceptions::error(&format!("{}: division by zero", primitive))
+}
+
+fn type_error(primitive: &str, expected: i64, val: Object) -> String {
+    format!("{}: expected {}, got {}", primitive, immediate::name(expected), val)
+}
+
+/// Lowest address of the stack of the current thread
+#[cfg(all(feature = "native", target_os = "linux"))]
+fn stack() -> *const u8 {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        let mut start = std::ptr::null_mut();
+        let mut size = 0;
+
+        assert_eq!(libc::pthread_getattr_np(libc::pthread_self(), &mut attr), 0);
+        assert_eq!(libc::pthread_attr_getstack(&attr, &mut start, &mut size), 0);
+        libc::pthread_attr_destroy(&mut attr);
+
+        start as *const u8
+    }
+}
+
+// The stack grows down from the address returned on macos
+#[cfg(all(feature = "native", target_os = "macos"))]
+fn stack() -> *const u8 {
+    unsafe {
+        let thread = libc::pthread_self();
+        let size = libc::pthread_get_stacksize_np(thread);
+        let top = libc::pthread_get_stackaddr_np(thread) as *const u8;
+
+        top.wrapping_sub(size)
     }
 }
 
@@ -243,17 +752,60 @@ pub fn allocate(size: usize) {
 /// bits from the spec to make some toy programs work.
 ///
 /// See: https://www.scheme.com/tspl4/io.html
+#[cfg(feature = "native")]
 pub mod io {
     use super::*;
     use std::{
         fs::{self, File},
-        os::unix::io::AsRawFd,
+        io::Read,
+        mem::ManuallyDrop,
+        os::unix::io::{FromRawFd, IntoRawFd},
     };
 
     const STDIN: i64 = 0;
     const STDOUT: i64 = 1;
     const STDERR: i64 = 2;
 
+    thread_local! {
+        /// Contents of the ports being read along with the position of the
+        /// next byte, by file descriptor
+        static INPUT: RefCell<HashMap<i64, (Vec<u8>, usize)>> = RefCell::new(HashMap::new());
+
+        /// Text written to the string output ports so far
+        static OUTPUT: RefCell<HashMap<i64, String>> = RefCell::new(HashMap::new());
+
+        /// Last descriptor of a string port, they count down from -1
+        static STRINGS: Cell<i64> = const { Cell::new(0) };
+
+        /// Why the last operation on a port failed, see [rt_io_error]
+        static ERROR: RefCell<Option<Failure>> = const { RefCell::new(None) };
+    }
+
+    /// An error of an operation on a port along with the status of the process
+    /// if it isn't handled
+    type Failure = (Status, String);
+
+    /// The result of an operation on a port, or [FAILED] after keeping the
+    /// error around for [rt_io_error]
+    ///
+    /// Foreign functions can't transfer control to a handler, the generated
+    /// code raises the error right after the call instead.
+    fn raising(result: Result<Object, Failure>) -> Object {
+        result.unwrap_or_else(|failure| {
+            ERROR.with(|error| *error.borrow_mut() = Some(failure));
+            Object::new(FAILED)
+        })
+    }
+
+    /// Raise the error of the last operation on a port that failed
+    #[no_mangle]
+    pub extern "C" fn rt_io_error() -> Target {
+        let (status, message) =
+            ERROR.with(|error| error.borrow_mut().take()).expect("No operation on a port failed");
+
+        exceptions::failure(status, &message)
+    }
+
     #[no_mangle]
     pub const extern "C" fn rt_standard_input_port() -> Object {
         Object::immediate(STDIN)
@@ -271,27 +823,310 @@ pub mod io {
 
     /// Open a file for writing and return the immediate encoded file descriptor
     /// Creates file if it doesn't exist already
+    ///
+    /// The file stays open till the port is closed with `rt_close_port`.
     #[no_mangle]
     pub extern "C" fn rt_open_write(fname: Object) -> Object {
-        match fname.deref() {
-            Literal(Str(path)) => {
-                let f = File::create(path).unwrap().as_raw_fd();
-                Object::immediate(f as i64)
-            }
-            e => panic!("Expected fname: String, got `{}` instead", e),
-        }
+        raising(open("open-output-file", fname, |p| File::create(p)))
     }
 
     /// Open a file for reading return the immediate encoded file descriptor
-    /// Fails if file doesn't exist already
+    /// Raises an error if the file can't be opened, like when it doesn't exist
+    ///
+    /// The file stays open till the port is closed with `rt_close_port`.
     #[no_mangle]
     pub extern "C" fn rt_open_read(fname: Object) -> Object {
-        match fname.deref() {
-            Literal(Str(path)) => {
-                let f = File::open(path).unwrap().as_raw_fd();
-                Object::immediate(f as i64)
+        raising(open("open-input-file", fname, |p| File::open(p)))
+    }
+
+    /// Open the file named by `fname` with `f` for `primitive`
+    fn open(
+        primitive: &str,
+        fname: Object,
+        f: impl FnOnce(&str) -> std::io::Result<File>,
+    ) -> Result<Object, Failure> {
+        let path = match fname.deref() {
+            Literal(Str(path)) => path,
+            _ => return Err((Status::Type, type_error(primitive, STR, fname))),
+        };
+
+        match f(&path) {
+            Ok(file) => Ok(Object::immediate(file.into_raw_fd() as i64)),
+            Err(e) => {
+                Err((Status::Raised, format!("{}: failed to open {}: {}", primitive, path, e)))
+            }
+        }
+    }
+
+    /// Open a port to read the characters of a string
+    ///
+    /// String ports have negative descriptors that don't refer to any file;
+    /// the string is copied into the input buffer of the port right away.
+    #[no_mangle]
+    pub extern "C" fn rt_open_input_string(data: Object) -> Object {
+        let fd = string_port();
+        INPUT.with(|input| input.borrow_mut().insert(fd, (str_str(data.0).into_bytes(), 0)));
+
+        Object::immediate(fd)
+    }
+
+    /// Open a port that accumulates the text written to it in a string
+    #[no_mangle]
+    pub extern "C" fn rt_open_output_string() -> Object {
+        let fd = string_port();
+        OUTPUT.with(|output| output.borrow_mut().insert(fd, String::new()));
+
+        Object::immediate(fd)
+    }
+
+    /// The text written to a string output port so far, as a new string
+    #[no_mangle]
+    pub extern "C" fn rt_get_output_string(port: Object) -> Object {
+        let primitive = "get-output-string";
+
+        raising(descriptor(primitive, port, STDOUT).and_then(|fd| {
+            match OUTPUT.with(|output| output.borrow().get(&fd).cloned()) {
+                Some(text) => Ok(string(text.as_bytes())),
+                None => {
+                    let message =
+                        format!("{}: expected string output port, got {}", primitive, port);
+                    Err((Status::Type, message))
+                }
+            }
+        }))
+    }
+
+    fn string_port() -> i64 {
+        STRINGS.with(|last| {
+            last.set(last.get() - 1);
+            last.get()
+        })
+    }
+
+    /// Close the file of a port
+    ///
+    /// Closing a standard port does nothing, the process owns them.
+    #[no_mangle]
+    pub extern "C" fn rt_close_port(port: Object) -> Object {
+        let fd = match descriptor("close-port", port, STDIN) {
+            Ok(fd) => fd,
+            Err(failure) => return raising(Err(failure)),
+        };
+
+        if !(STDIN..=STDERR).contains(&fd) {
+            INPUT.with(|input| input.borrow_mut().remove(&fd));
+            OUTPUT.with(|output| output.borrow_mut().remove(&fd));
+        }
+
+        if fd > STDERR {
+            unsafe { libc::close(fd as i32) };
+        }
+
+        Object::new(NIL)
+    }
+
+    /// File descriptor of a port, `default` if the port is `()`
+    ///
+    /// Ports are vectors built by the prelude, see `open-input-file`; anything
+    /// else is the wrong type for `primitive`.
+    fn descriptor(primitive: &str, port: Object, default: i64) -> Result<i64, Failure> {
+        if port.0 == NIL {
+            return Ok(default);
+        }
+
+        let port = port.0;
+        let valid = tag(port) == VEC
+            && vec_len(port) == 3
+            && tag(vec_nth(port, 0)) == SYM
+            && sym_bytes(vec_nth(port, 0)) == b"port"
+            && tag(vec_nth(port, 1)) == STR
+            && tag(vec_nth(port, 2)) == NUM;
+
+        if valid {
+            Ok(untag(vec_nth(port, 2)))
+        } else {
+            let message = format!("{}: expected port, got {}", primitive, Object::new(port));
+            Err((Status::Type, message))
+        }
+    }
+
+    /// Name of a port for error messages
+    fn name(port: Object) -> String {
+        if port.0 == NIL {
+            String::from("stdin")
+        } else {
+            str_str(vec_nth(port.0, 1))
+        }
+    }
+
+    /// Borrow the file of a port without closing it afterwards
+    fn file(fd: i64) -> ManuallyDrop<File> {
+        ManuallyDrop::new(unsafe { File::from_raw_fd(fd as i32) })
+    }
+
+    /// Consume the unread input of a port with `f`, defaults to stdin if the
+    /// port is `()`
+    ///
+    /// `f` returns the number of bytes it consumed along with the result. The
+    /// whole input is read at once on first use, so reading from the terminal
+    /// waits for the end of the input.
+    fn input<T>(
+        primitive: &str,
+        port: Object,
+        f: impl FnOnce(&[u8]) -> (usize, T),
+    ) -> Result<T, Failure> {
+        let fd = descriptor(primitive, port, STDIN)?;
+
+        INPUT.with(|input| {
+            let mut input = input.borrow_mut();
+
+            if fd < STDIN && !input.contains_key(&fd) {
+                return Err((
+                    Status::Type,
+                    format!("{}: expected input port, got {}", primitive, port),
+                ));
+            }
+
+            if let Entry::Vacant(e) = input.entry(fd) {
+                let mut data = vec![];
+                file(fd).read_to_end(&mut data).map_err(|e| {
+                    (Status::Raised, format!("{}: failed to read {}: {}", primitive, name(port), e))
+                })?;
+                e.insert((data, 0));
+            }
+
+            let (data, pos) = input.get_mut(&fd).expect("The input of the port was just read");
+            let (n, val) = f(&data[*pos..]);
+            *pos += n;
+            Ok(val)
+        })
+    }
+
+    /// Write text to a port for `primitive`, defaults to stdout if the port is
+    /// `()`
+    fn put(primitive: &str, port: Object, text: &str) -> Object {
+        raising(output(primitive, port, text))
+    }
+
+    fn output(primitive: &str, port: Object, text: &str) -> Result<Object, Failure> {
+        let written = match descriptor(primitive, port, STDOUT)? {
+            STDOUT => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush())
+            }
+            STDERR => std::io::stderr().write_all(text.as_bytes()),
+            fd if fd < STDIN => {
+                let buffered = OUTPUT.with(|output| {
+                    output.borrow_mut().get_mut(&fd).map(|buffer| buffer.push_str(text))
+                });
+
+                if buffered.is_none() {
+                    let message = format!("{}: expected output port, got {}", primitive, port);
+                    return Err((Status::Type, message));
+                }
+                Ok(())
+            }
+            fd => file(fd).write_all(text.as_bytes()),
+        };
+
+        written.map(|_| Object::new(NIL)).map_err(|e| {
+            (Status::Raised, format!("{}: failed to write to {}: {}", primitive, name(port), e))
+        })
+    }
+
+    /// Write the human readable representation of an object to a port
+    #[no_mangle]
+    pub extern "C" fn rt_display(val: Object, port: Object) -> Object {
+        let mut out = String::new();
+        display(&mut out, val).unwrap();
+        put("display", port, &out)
+    }
+
+    /// Write the external representation of an object to a port
+    #[no_mangle]
+    pub extern "C" fn rt_write_datum(val: Object, port: Object) -> Object {
+        let mut out = String::new();
+        datum(&mut out, val).unwrap();
+        put("write", port, &out)
+    }
+
+    /// Write a newline to a port
+    #[no_mangle]
+    pub extern "C" fn rt_newline(port: Object) -> Object {
+        put("newline", port, "\n")
+    }
+
+    /// Write the characters of a string to a port
+    #[no_mangle]
+    pub extern "C" fn rt_write_string(data: Object, port: Object) -> Object {
+        if !is_string(data.0) {
+            return raising(Err((Status::Type, type_error("write-string", STR, data))));
+        }
+
+        put("write-string", port, &str_str(data.0))
+    }
+
+    /// Write a character to a port
+    #[no_mangle]
+    pub extern "C" fn rt_write_char(c: Object, port: Object) -> Object {
+        put("write-char", port, &(untag(c.0) as u8 as char).to_string())
+    }
+
+    /// Read the next character from a port, defaults to stdin if the port is
+    /// `()`
+    #[no_mangle]
+    pub extern "C" fn rt_read_char(port: Object) -> Object {
+        raising(input("read-char", port, |data| match data.first() {
+            Some(c) => (1, character(*c)),
+            None => (0, rt_eof_object()),
+        }))
+    }
+
+    /// Return the next character from a port without consuming it
+    #[no_mangle]
+    pub extern "C" fn rt_peek_char(port: Object) -> Object {
+        raising(input("peek-char", port, |data| match data.first() {
+            Some(c) => (0, character(*c)),
+            None => (0, rt_eof_object()),
+        }))
+    }
+
+    fn character(c: u8) -> Object {
+        Object::new(tags::immediate(i64::from(c), CHAR))
+    }
+
+    /// The end of file object returned by `read`
+    #[no_mangle]
+    pub extern "C" fn rt_eof_object() -> Object {
+        crate::symbols::intern("#<eof>")
+    }
+
+    /// Read the next datum from a port, defaults to stdin if the port is `()`
+    #[no_mangle]
+    pub extern "C" fn rt_read_datum(port: Object) -> Object {
+        let datum = input("read", port, |data| match std::str::from_utf8(data) {
+            Ok(text) if text.trim().is_empty() => (data.len(), Ok(None)),
+            Ok(text) => match parser::read(text) {
+                Ok((rest, datum)) => (text.len() - rest.len(), Ok(Some(datum))),
+                Err(_) => (0, Err(())),
+            },
+            Err(_) => (0, Err(())),
+        });
+
+        let datum = match datum {
+            Ok(datum) => datum,
+            Err(failure) => return raising(Err(failure)),
+        };
+
+        match datum {
+            Ok(Some(datum)) => build(&datum),
+            Ok(None) => rt_eof_object(),
+            // Foreign functions can't transfer control to a handler, so this
+            // error can't be caught
+            Err(_) => {
+                eprintln!("Exception: read: invalid datum in {}", name(port));
+                Status::Raised.exit()
             }
-            e => panic!("Expected fname: String, got `{}` instead", e),
         }
     }
 
@@ -299,7 +1134,7 @@ pub mod io {
     #[no_mangle]
     pub extern "C" fn rt_write(data: Object, port: Object) -> Object {
         let path = str_str(vec_nth(port.0, 1));
-        let fd = (vec_nth(port.0, 2) >> SHIFT) as i32;
+        let fd = untag(vec_nth(port.0, 2)) as i32;
 
         if fd == STDOUT as i32 {
             print(data, false)
@@ -318,7 +1153,7 @@ pub mod io {
     //
     // This is honestly making me wonder WTH I'm really doing. There is no need
     // to really do this in assembly, what I need is a custom allocator in Rust.
-    // See `strings::make` as well.
+    // See `primitives::make_string` as well.
     //
     // This is legit cursed!
     #[no_mangle]
@@ -327,20 +1162,6 @@ pub mod io {
         let path = str_str(vec_nth(port.0, 1));
         let data = fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {:?}", &path, e));
 
-        let r12 = heap();
-
-        let plen = r12 as *mut usize;
-        let pstr = (r12 + 8) as *mut u8;
-
-        allocate(8 + data.len());
-
-        unsafe {
-            // Write prefix length and then null terminated data
-            std::ptr::write(plen, data.len());
-            std::ptr::copy(data.as_ptr(), pstr, data.len());
-        }
-
-        // Return immediate encoded string object
-        Object::new(plen as i64 | STR)
+        string(&data)
     }
 }
@@ -0,0 +1,489 @@
+//! Semantic tokens for editors
+//!
+//! [tokens] classifies every name and literal of a source by what it means
+//! rather than how it looks, so that an editor can tell a local variable from
+//! a global one or from a function that is built in. `inc lsp` answers
+//! `textDocument/semanticTokens/full` with these, and `--emit tokens-semantic`
+//! prints them a line each.
+//!
+//! Names are resolved like [rename](crate::lang::renamed) does: formals of a
+//! lambda and names bound by a `let` are [Bound](Kind::Bound) in its body,
+//! and anything else is looked up globally, which makes it a
+//! [Primitive](Kind::Primitive) if the compiler or the runtime has it and
+//! [Free](Kind::Free) otherwise. Definitions in a body are bound in all of it.
+//!
+//! The source is read into [Form](lsp::Form)s like the language server does,
+//! so a file being edited still has tokens up to where it stops making sense.
+
+use crate::{
+    core::{Expr, Ident, Literal::Nil},
+    lsp::{self, Form},
+    primitives, rt,
+};
+use std::{fmt, ops::Range};
+
+/// What a token of the source means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A special form the compiler knows, like `define` or `lambda`
+    Keyword,
+    /// A name bound by a lambda, a `let` or a definition around it
+    Bound,
+    /// A name that isn't bound locally, like the functions at the top level
+    Free,
+    /// A function built into the compiler or the runtime
+    Primitive,
+    /// A number, string, character or boolean, or anything quoted
+    Literal,
+    /// Derived syntax that [expands](crate::lang::expand) to other forms
+    Macro,
+}
+
+/// A classified span of the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub span: Range<usize>,
+    pub kind: Kind,
+    /// Where a [Bound](Kind::Bound) name is bound, its own span where it is
+    pub binding: Option<Range<usize>>,
+}
+
+/// The names bound around a form, innermost last, and where
+type Scope<'a> = Vec<(&'a str, Range<usize>)>;
+
+/// The tokens found so far, and the names bound at an offset once the walk
+/// gets to it, see [bound_at]
+#[derive(Default)]
+struct Out {
+    tokens: Vec<Token>,
+    at: Option<usize>,
+    scope: Option<Vec<String>>,
+}
+
+/// Special forms, which are keywords only at the head of a list
+pub const KEYWORDS: [&str; 15] = [
+    "define",
+    "lambda",
+    "λ",
+    "if",
+    "let",
+    "let*",
+    "letrec",
+    "letrec*",
+    "quote",
+    "begin",
+    "load",
+    "define-library",
+    "export",
+    "import",
+    "include",
+];
+
+/// Forms expanded by [expand](crate::lang::expand)
+pub const MACROS: [&str; 3] = ["guard", "time", "foreign-procedure"];
+
+impl Kind {
+    pub const ALL: [Kind; 6] =
+        [Kind::Keyword, Kind::Bound, Kind::Free, Kind::Primitive, Kind::Literal, Kind::Macro];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Kind::Keyword => "keyword",
+            Kind::Bound => "bound",
+            Kind::Free => "free",
+            Kind::Primitive => "primitive",
+            Kind::Literal => "literal",
+            Kind::Macro => "macro",
+        }
+    }
+}
+
+impl Token {
+    fn new(span: &Range<usize>, kind: Kind) -> Self {
+        Token { span: span.clone(), kind, binding: None }
+    }
+}
+
+impl Out {
+    fn push(&mut self, token: Token) {
+        self.tokens.push(token)
+    }
+
+    /// Keep the names bound in a form, if it is the innermost one around the
+    /// offset
+    ///
+    /// Forms are done with from the inside out, so the first one around the
+    /// offset is the innermost. A list that isn't closed goes on till the end
+    /// of the source, and so does the offset at its end.
+    fn visit(&mut self, source: &str, form: &Form, scope: &Scope) {
+        let at = match self.at {
+            Some(at) if self.scope.is_none() => at,
+            _ => return,
+        };
+
+        let span = form.span();
+        let around = match form {
+            Form::Atom(..) => span.start < at && at <= span.end,
+            Form::List(..) => {
+                let closed = source[..span.end].ends_with([')', ']']);
+                span.start < at && (at < span.end || (at == span.end && !closed))
+            }
+        };
+
+        if around {
+            self.scope = Some(scope.iter().map(|(name, _)| name.to_string()).collect());
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The tokens of a source, in the order they appear
+pub fn tokens(source: &str) -> Vec<Token> {
+    let forms = lsp::read(source);
+    let mut out = Out::default();
+
+    for form in &forms {
+        expression(source, form, &mut vec![], &mut out);
+    }
+
+    // Names are bound before the forms that come first in some lets
+    out.tokens.sort_by_key(|token| token.span.start);
+    out.tokens
+}
+
+/// The names bound around an offset of a source, innermost last
+///
+/// Names defined at the top level aren't bound, they are looked up globally
+/// like the functions of the prelude.
+pub fn bound_at(source: &str, offset: usize) -> Vec<String> {
+    let forms = lsp::read(source);
+    let mut out = Out { at: Some(offset), ..Out::default() };
+
+    for form in &forms {
+        expression(source, form, &mut vec![], &mut out);
+    }
+
+    out.scope.unwrap_or_default()
+}
+
+/// The tokens a line each, with the line and column they start at
+pub fn render(source: &str) -> String {
+    tokens(source)
+        .iter()
+        .map(|token| {
+            let before = &source[..token.span.start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+            format!("{}:{} {} {}", line, column, token.kind, &source[token.span.clone()])
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether a name is a function of the compiler or the runtime
+pub fn builtin(name: &str) -> bool {
+    let ident = Ident::new(name);
+    let args = |n| vec![Expr::Literal(Nil); n];
+    (0..=4).any(|n| primitives::defined(&ident, &args(n))) || rt::defined(&ident)
+}
+
+/// Classify a form evaluated with the names in `scope` bound
+fn expression<'a>(source: &str, form: &'a Form, scope: &mut Scope<'a>, out: &mut Out) {
+    if prefixed(source, form, &["'"]) {
+        return datum(source, form, false, scope, out);
+    }
+    if prefixed(source, form, &["`"]) {
+        return datum(source, form, true, scope, out);
+    }
+
+    let items = match form {
+        Form::Atom(name, span) => {
+            out.visit(source, form, scope);
+            return out.push(atom(name, span, scope));
+        }
+        Form::List(items, _) => items,
+    };
+
+    // A keyword bound as a variable is just a variable
+    let head = match items.first() {
+        Some(Form::Atom(name, span)) if !scope.iter().any(|(n, _)| n == name) => {
+            if KEYWORDS.contains(&name.as_str()) {
+                out.push(Token::new(span, Kind::Keyword));
+            } else if MACROS.contains(&name.as_str()) {
+                out.push(Token::new(span, Kind::Macro));
+            }
+            name.as_str()
+        }
+        _ => "",
+    };
+
+    let rest = items.get(1..).unwrap_or_default();
+    let depth = scope.len();
+
+    match head {
+        "quote" | "export" | "import" | "include" | "load" | "foreign-procedure" => {
+            rest.iter().for_each(|form| datum(source, form, false, scope, out))
+        }
+        "define-library" => {
+            if let Some((name, declarations)) = rest.split_first() {
+                datum(source, name, false, scope, out);
+                declarations.iter().for_each(|form| expression(source, form, scope, out));
+            }
+        }
+        "define" => match rest.split_first() {
+            // (define (f x ...) body ...) binds the formals in the body
+            Some((Form::List(head, _), forms)) => {
+                if let Some((name, formals)) = head.split_first() {
+                    expression(source, name, scope, out);
+                    bind(formals, scope, out);
+                }
+                body(source, forms, scope, out);
+            }
+            _ => rest.iter().for_each(|form| expression(source, form, scope, out)),
+        },
+        "lambda" | "λ" => match rest.split_first() {
+            Some((Form::List(formals, _), forms)) => {
+                bind(formals, scope, out);
+                body(source, forms, scope, out);
+            }
+            Some((formal, forms)) => {
+                bind(std::slice::from_ref(formal), scope, out);
+                body(source, forms, scope, out);
+            }
+            None => {}
+        },
+        "let" | "let*" | "letrec" | "letrec*" => {
+            let (name, rest) = match rest.split_first() {
+                Some((name @ Form::Atom(..), rest)) => (Some(name), rest),
+                _ => (None, rest),
+            };
+            let (bindings, forms) = match rest.split_first() {
+                Some((Form::List(bindings, _), forms)) => (&bindings[..], forms),
+                _ => (&[][..], rest),
+            };
+            let pairs = bindings.iter().filter_map(|binding| match binding {
+                Form::List(pair, _) => pair.split_first(),
+                Form::Atom(..) => None,
+            });
+
+            // Values see all the names of a letrec, the ones before them in a
+            // let* and none of them in a let
+            let recursive = head.starts_with("letrec");
+            if recursive {
+                for (name, _) in pairs.clone() {
+                    bind(std::slice::from_ref(name), scope, out);
+                }
+            }
+            for (name, values) in pairs {
+                let mut outer = scope[..depth].to_vec();
+                let values_scope = if head == "let" { &mut outer } else { &mut *scope };
+                values.iter().for_each(|value| expression(source, value, values_scope, out));
+                if !recursive {
+                    bind(std::slice::from_ref(name), scope, out);
+                }
+            }
+
+            // A named let binds the loop in the body only
+            if let Some(name) = name {
+                bind(std::slice::from_ref(name), scope, out);
+            }
+            body(source, forms, scope, out);
+        }
+        "guard" => match rest.split_first() {
+            // (guard (e clause ...) body ...) binds e in the clauses only
+            Some((Form::List(spec, _), forms)) => {
+                body(source, forms, scope, out);
+                if let Some((var, clauses)) = spec.split_first() {
+                    bind(std::slice::from_ref(var), scope, out);
+                    for clause in clauses {
+                        match clause {
+                            Form::List(exprs, _) => match exprs.split_first() {
+                                Some((Form::Atom(name, span), exprs)) if name == "else" => {
+                                    out.push(Token::new(span, Kind::Keyword));
+                                    exprs.iter().for_each(|e| expression(source, e, scope, out));
+                                }
+                                _ => exprs.iter().for_each(|e| expression(source, e, scope, out)),
+                            },
+                            form => expression(source, form, scope, out),
+                        }
+                    }
+                }
+            }
+            _ => rest.iter().for_each(|form| expression(source, form, scope, out)),
+        },
+        _ if KEYWORDS.contains(&head) || MACROS.contains(&head) => {
+            rest.iter().for_each(|form| expression(source, form, scope, out))
+        }
+        _ => items.iter().for_each(|form| expression(source, form, scope, out)),
+    }
+
+    out.visit(source, form, scope);
+    scope.truncate(depth);
+}
+
+/// Classify the forms of a body, with the names it defines bound in all of it
+fn body<'a>(source: &str, forms: &'a [Form], scope: &mut Scope<'a>, out: &mut Out) {
+    for form in forms {
+        if let Form::List(items, _) = form {
+            let name = match items.as_slice() {
+                [Form::Atom(define, _), Form::List(head, _), ..] if define == "define" => {
+                    head.first()
+                }
+                [Form::Atom(define, _), name, ..] if define == "define" => Some(name),
+                _ => None,
+            };
+            if let Some(Form::Atom(name, span)) = name {
+                scope.push((name, span.clone()));
+            }
+        }
+    }
+
+    forms.iter().for_each(|form| expression(source, form, scope, out));
+}
+
+/// Bind the names of some formals, skipping the dot before a rest argument
+fn bind<'a>(formals: &'a [Form], scope: &mut Scope<'a>, out: &mut Out) {
+    for formal in formals {
+        if let Form::Atom(name, span) = formal {
+            if name != "." {
+                scope.push((name, span.clone()));
+                out.push(Token {
+                    span: span.clone(),
+                    kind: Kind::Bound,
+                    binding: Some(span.clone()),
+                });
+            }
+        }
+    }
+}
+
+/// Classify quoted data, where only what is unquoted in a quasiquote is
+/// evaluated
+fn datum<'a>(source: &str, form: &'a Form, quasi: bool, scope: &mut Scope<'a>, out: &mut Out) {
+    if quasi && prefixed(source, form, &[",", ",@"]) {
+        return expression(source, form, scope, out);
+    }
+
+    match form {
+        Form::Atom(_, span) => out.push(Token::new(span, Kind::Literal)),
+        Form::List(items, _) => {
+            items.iter().for_each(|form| datum(source, form, quasi, scope, out))
+        }
+    }
+}
+
+/// The token of a name or a constant
+fn atom(name: &str, span: &Range<usize>, scope: &Scope) -> Token {
+    let mut chars = name.chars();
+    let number = match chars.next() {
+        Some('+' | '-' | '.') => chars.next().map_or(false, |c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    };
+
+    if number || name.starts_with('"') || name.starts_with('#') {
+        return Token::new(span, Kind::Literal);
+    }
+
+    match scope.iter().rev().find(|(n, _)| *n == name) {
+        Some((_, binding)) => {
+            Token { span: span.clone(), kind: Kind::Bound, binding: Some(binding.clone()) }
+        }
+        None if builtin(name) => Token::new(span, Kind::Primitive),
+        None => Token::new(span, Kind::Free),
+    }
+}
+
+/// Whether a form comes right after one of the prefixes, like a quote
+fn prefixed(source: &str, form: &Form, prefixes: &[&str]) -> bool {
+    let before = &source[..form.span().start];
+    prefixes.iter().any(|prefix| before.ends_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn kinds(source: &str) -> Vec<(&str, Kind)> {
+        tokens(source).into_iter().map(|t| (&source[t.span], t.kind)).collect()
+    }
+
+    #[test]
+    fn scopes() {
+        use Kind::*;
+
+        let source = "(define (f x) (let ((y (car x)) (z y)) (g y '(a ,x) \"s\" 1)))";
+        assert_eq!(
+            kinds(source),
+            vec![
+                ("define", Keyword),
+                ("f", Free),
+                ("x", Bound),
+                ("let", Keyword),
+                ("y", Bound),
+                ("car", Primitive),
+                ("x", Bound),
+                ("z", Bound),
+                ("y", Free),
+                ("g", Free),
+                ("y", Bound),
+                ("a", Literal),
+                ("x", Literal),
+                ("\"s\"", Literal),
+                ("1", Literal),
+            ]
+        );
+
+        // Unquoted data is evaluated, and definitions are bound in all of a body
+        let source = "(lambda (x) (define (h) (k)) (define (k) x) `(a ,x ,@(h)))";
+        let bound: Vec<&str> =
+            kinds(source).into_iter().filter(|(_, k)| *k == Bound).map(|(n, _)| n).collect();
+        assert_eq!(bound, vec!["x", "h", "k", "k", "x", "x", "h"]);
+
+        // A keyword is only one at the head of a list and if it isn't bound
+        assert_eq!(kinds("(let ((if 1)) (if))")[3], ("if", Bound));
+        assert_eq!(kinds("(f if)")[1], ("if", Free));
+    }
+
+    #[test]
+    fn forms() {
+        use Kind::*;
+
+        let source = "(let loop ((i 0)) (guard (e (else e)) (loop (- i 1))))";
+        assert_eq!(
+            kinds(source),
+            vec![
+                ("let", Keyword),
+                ("loop", Bound),
+                ("i", Bound),
+                ("0", Literal),
+                ("guard", Macro),
+                ("e", Bound),
+                ("else", Keyword),
+                ("e", Bound),
+                ("loop", Bound),
+                ("-", Primitive),
+                ("i", Bound),
+                ("1", Literal),
+            ]
+        );
+
+        let letrec = kinds("(letrec ((even? (lambda (n) (odd? n))) (odd? even?)) 1)");
+        assert_eq!(letrec.iter().filter(|(_, k)| *k == Bound).count(), 6);
+
+        let source = "(define-library (lib a) (export f) (begin (define (f) #t)))";
+        let kinds: Vec<Kind> = kinds(source).into_iter().map(|(_, k)| k).collect();
+        assert_eq!(
+            kinds,
+            vec![Keyword, Literal, Literal, Keyword, Literal, Keyword, Keyword, Free, Literal]
+        );
+
+        assert_eq!(render("(car\n  \"é\")"), "1:2 primitive car\n2:3 literal \"é\"");
+    }
+}
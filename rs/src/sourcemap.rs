@@ -0,0 +1,383 @@
+//! Source maps from generated code back to the program
+//!
+//! `inc --emit map` prints where the code of every function generated for a
+//! program came from, as JSON that doesn't need DWARF or any other debug info
+//! in the object:
+//!
+//! ```json
+//! {"version":1,"functions":[
+//!   {"name":"twice","lines":[18335,18507],"span":[0,26],"line":1,"column":1},
+//!   ...
+//! ]}
+//! ```
+//!
+//! `lines` are the lines of the asm generated by `inc --emit asm` for the same
+//! program and options, counting from 1 and excluding the end. Every function
+//! is a global symbol named like the Scheme function after a `scm:` prefix,
+//! see [mangle](crate::core::Ident::mangle), so tools working on an object or
+//! executable find the address of one in its symbol table and the definition
+//! it came from here, see [disasm](crate::disasm).
+//!
+//! `span` is the definition in bytes from the start of the program, along with
+//! its `line` and `column`, or null for the code that didn't come from a
+//! definition in the program, like `init` and the prelude. Lambdas are lifted
+//! into functions named after the function they are defined in and map to its
+//! definition, and functions of a [library](crate::library) to their
+//! definitions in it.
+//!
+//! ```
+//! use inc::sourcemap::SourceMap;
+//!
+//! let asm = "    .globl \"scm:twice\"\n\"scm:twice\":\n    ret\n";
+//! let map = SourceMap::new(asm, "(define (twice x) (* x 2))");
+//!
+//! assert_eq!(map.function("twice").unwrap().span, Some(0..26));
+//! assert_eq!(map.at(3).unwrap().name, "twice");
+//! assert_eq!(SourceMap::parse(&map.to_string()), Ok(map));
+//! ```
+use crate::{
+    core::Ident,
+    coverage, exceptions, gc,
+    json::Json,
+    lsp::{self, Form},
+    profile, strings, symbols,
+};
+use std::{collections::HashMap, fmt, io, ops::Range};
+
+/// Version of the format, bumped whenever it changes
+pub const VERSION: i64 = 1;
+
+/// The code generated for the functions of a program and where it came from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    pub functions: Vec<Function>,
+}
+
+/// Builds a [SourceMap] from asm written to it as it is generated
+///
+/// The code of a program doesn't have to be kept in memory to be mapped, see
+/// [Writer](crate::x86::Writer).
+pub struct Mapper<'a> {
+    source: &'a str,
+    definitions: HashMap<String, Range<usize>>,
+    functions: Vec<Function>,
+    open: bool,
+    lines: usize,
+    partial: Vec<u8>,
+}
+
+impl<'a> Mapper<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Mapper {
+            source,
+            definitions: definitions(source),
+            functions: vec![],
+            open: false,
+            lines: 0,
+            partial: vec![],
+        }
+    }
+
+    /// The map of every line written so far
+    pub fn finish(mut self) -> SourceMap {
+        if !self.partial.is_empty() {
+            let partial = std::mem::take(&mut self.partial);
+            self.line(&String::from_utf8_lossy(&partial));
+        }
+
+        SourceMap { functions: self.functions }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.lines += 1;
+        let n = self.lines;
+
+        let global = global(line);
+
+        if let Some(label) = global.as_deref().filter(|label| !routine(label)) {
+            let name = Ident::unmangle(label).unwrap_or(label).to_string();
+
+            // Lambdas are lifted into functions named after the one they are
+            // defined in
+            let function = name.split(' ').next().unwrap_or_default();
+            let span = self.definitions.get(function).cloned();
+            let (line, column) = match &span {
+                Some(span) => {
+                    let (line, column) = position(self.source, span.start);
+                    (Some(line), Some(column))
+                }
+                None => (None, None),
+            };
+
+            self.functions.push(Function { name, lines: n..n + 1, span, line, column });
+            self.open = true;
+        } else if global.is_some() || label(line).map_or(false, routine) {
+            self.open = false;
+        } else if self.open && !line.trim().is_empty() {
+            if let Some(f) = self.functions.last_mut() {
+                f.lines.end = n + 1
+            }
+        }
+    }
+}
+
+impl io::Write for Mapper<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            self.partial.extend_from_slice(&rest[..end]);
+            let line = std::mem::take(&mut self.partial);
+            self.line(&String::from_utf8_lossy(&line));
+            rest = &rest[end + 1..];
+        }
+
+        self.partial.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Line and column of a byte offset in the source, counting from 1
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let column = before.len() - before.rfind('\n').map_or(0, |n| n + 1) + 1;
+    (before.matches('\n').count() + 1, column)
+}
+
+/// A function in the generated code, see the module docs for its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub lines: Range<usize>,
+    pub span: Option<Range<usize>>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl SourceMap {
+    /// Map the functions in the asm generated for a program to its source
+    ///
+    /// A function starts with the `.globl` directive declaring it and ends
+    /// with the last line before the next one, or before the first label of
+    /// the literals, the routines and the data emitted after the code.
+    pub fn new(asm: &str, source: &str) -> Self {
+        let mut mapper = Mapper::new(source);
+        asm.lines().for_each(|line| mapper.line(line));
+        mapper.finish()
+    }
+
+    /// Read a map printed before
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json = Json::parse(text)?;
+
+        match json.get("version").and_then(Json::as_i64) {
+            Some(VERSION) => {}
+            _ => return Err(format!("Expected a source map of version {}", VERSION)),
+        }
+
+        let functions = json.get("functions").and_then(Json::as_array).unwrap_or_default();
+
+        functions
+            .iter()
+            .map(|f| {
+                let name = f.get("name").and_then(Json::as_str);
+                let lines = f.get("lines").and_then(range);
+
+                match (name, lines) {
+                    (Some(name), Some(lines)) => Ok(Function {
+                        name: name.to_string(),
+                        lines,
+                        span: f.get("span").and_then(range),
+                        line: f.get("line").and_then(Json::as_i64).map(|n| n as usize),
+                        column: f.get("column").and_then(Json::as_i64).map(|n| n as usize),
+                    }),
+                    _ => Err(format!("Invalid function in source map: {}", f)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(|functions| SourceMap { functions })
+    }
+
+    /// The function with a name
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// The function a line of the asm belongs to
+    pub fn at(&self, line: usize) -> Option<&Function> {
+        self.functions.iter().find(|f| f.lines.contains(&line))
+    }
+}
+
+/// Compact JSON, see the module docs
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pair = |r: &Range<usize>| Json::from(vec![r.start.into(), r.end.into()]);
+        let or_null = |n: Option<Json>| n.unwrap_or(Json::Null);
+
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                Json::object(vec![
+                    ("name", function.name.as_str().into()),
+                    ("lines", pair(&function.lines)),
+                    ("span", or_null(function.span.as_ref().map(pair))),
+                    ("line", or_null(function.line.map(Json::from))),
+                    ("column", or_null(function.column.map(Json::from))),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let map = Json::object(vec![("version", VERSION.into()), ("functions", functions.into())]);
+        write!(f, "{}", map)
+    }
+}
+
+/// The name of the function declared by a `.globl` directive
+fn global(line: &str) -> Option<String> {
+    let name = line.trim().strip_prefix(".globl")?.trim();
+    let name = name.strip_prefix('"').and_then(|n| n.strip_suffix('"')).unwrap_or(name);
+
+    Some(name.to_string())
+}
+
+/// The label defined by a line
+fn label(line: &str) -> Option<&str> {
+    line.strip_suffix(':').map(|label| label.trim_matches('"'))
+}
+
+/// Is the label one of a literal, a routine or the data emitted after the code?
+fn routine(label: &str) -> bool {
+    let labels = [
+        strings::LABEL,
+        symbols::LABEL,
+        exceptions::DISPATCH,
+        gc::FINALIZE,
+        coverage::LABEL,
+        profile::LABEL,
+    ];
+
+    labels.iter().any(|prefix| label.starts_with(prefix))
+}
+
+/// A pair of numbers as a range
+fn range(json: &Json) -> Option<Range<usize>> {
+    match json.as_array()? {
+        [start, end] => Some(start.as_i64()? as usize..end.as_i64()? as usize),
+        _ => None,
+    }
+}
+
+/// Spans of the functions defined in a source by the labels they get
+///
+/// Definitions in a library are renamed after it, like `%math.arith/double`.
+pub(crate) fn definitions(source: &str) -> HashMap<String, Range<usize>> {
+    let forms = lsp::read(source);
+    let mut spans: HashMap<String, Range<usize>> =
+        lsp::definitions(&forms).into_iter().map(|d| (d.name, d.span)).collect();
+
+    for form in &forms {
+        let items = match form {
+            Form::List(items, _) => items,
+            _ => continue,
+        };
+
+        let parts = match items.as_slice() {
+            [Form::Atom(head, _), Form::List(parts, _), ..] if head == "define-library" => parts,
+            _ => continue,
+        };
+
+        let parts: Vec<&str> = parts
+            .iter()
+            .filter_map(|part| match part {
+                Form::Atom(part, _) => Some(part.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        for decl in &items[2..] {
+            if let Form::List(body, _) = decl {
+                if let [Form::Atom(head, _), forms @ ..] = body.as_slice() {
+                    if head == "begin" {
+                        for d in lsp::definitions(forms) {
+                            spans.insert(format!("%{}/{}", parts.join("."), d.name), d.span);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::emit, parser};
+
+    const SOURCE: &str = "(define (twice x) (* x 2))
+
+(define-library (math arith)
+  (export double)
+  (begin
+    (define (double x) (* x 2))))
+
+(let ((h (lambda (x) (twice x)))) (h 21))";
+
+    #[test]
+    fn functions() {
+        let asm = emit::compile(parser::parse(SOURCE).unwrap()).unwrap().to_string();
+        let map = SourceMap::new(&asm, SOURCE);
+        let lines: Vec<&str> = asm.lines().collect();
+
+        let twice = map.function("twice").unwrap();
+        assert_eq!((twice.span.clone(), twice.line, twice.column), (Some(0..26), Some(1), Some(1)));
+        assert_eq!(lines[twice.lines.start - 1].trim(), ".globl \"scm:twice\"");
+        assert_eq!(lines[twice.lines.end - 2].trim(), "ret");
+
+        let double = map.function("%math.arith/double").unwrap();
+        assert_eq!((double.line, double.column), (Some(6), Some(5)));
+
+        // Lambdas lifted out of top level expressions aren't definitions
+        let lifted = map.functions.iter().find(|f| f.name.ends_with(" h")).unwrap();
+        assert_eq!(lifted.span, None);
+
+        let init = map.function(&crate::x86::init()).unwrap();
+        assert_eq!(init.span, None);
+        assert!(!lines[init.lines.end - 2].contains(strings::LABEL));
+
+        assert_eq!(map.at(twice.lines.start + 2), Some(twice));
+        assert_eq!(map.at(lines.len() + 1), None);
+    }
+
+    #[test]
+    fn streaming() {
+        use std::io::Write;
+
+        let asm = emit::compile(parser::parse(SOURCE).unwrap()).unwrap().to_string();
+        let mut mapper = Mapper::new(SOURCE);
+
+        // Writes split lines anywhere
+        for chunk in asm.as_bytes().chunks(7) {
+            mapper.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(mapper.finish(), SourceMap::new(&asm, SOURCE));
+    }
+
+    #[test]
+    fn json() {
+        let asm = emit::compile(parser::parse(SOURCE).unwrap()).unwrap().to_string();
+        let map = SourceMap::new(&asm, SOURCE);
+
+        assert_eq!(SourceMap::parse(&map.to_string()), Ok(map));
+        assert!(SourceMap::parse(r#"{"version":0,"functions":[]}"#).is_err());
+        assert!(SourceMap::parse(r#"{"version":1,"functions":[{"name":"f"}]}"#).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Entry point of compiled programs
+//!
+//! An executable built by [cli](crate::cli) is the generated code linked with
+//! this crate as a static library; nothing else is required at run time. The
+//! library provides `inc_main`, which the linker is told to use as `main`. It
+//! installs a handler for segfaults, creates the heap with the options from
+//! the command line, passes the rest of the arguments on to the program, calls
+//! `init` and prints the result, followed by the [heap counters](gc::report) if
+//! asked for. A program with [coverage] or [profile] counters writes them out
+//! at exit.
+//!
+//! The generated code assumes full control of the callee saved registers, so
+//! `init` is called through the same stub the [jit] uses, loaded into memory
+//! at start up. The stub finds `init` with `dlsym`, which requires the
+//! executable to export its symbols.
+use crate::{coverage, gc, jit, process, profile, rt};
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+};
+
+/// Size of the stack used by the segfault handler
+#[cfg(target_os = "linux")]
+const SIGNAL_STACK: usize = 64 * 1024;
+
+/// The `main` of a compiled program
+///
+/// # Safety
+///
+/// Must be called only as the entry point of an executable, with valid
+/// arguments.
+#[no_mangle]
+pub unsafe extern "C" fn inc_main(argc: c_int, argv: *const *const c_char) -> c_int {
+    let args: Vec<String> = (0..argc as usize)
+        .map(|i| CStr::from_ptr(*argv.add(i)).to_string_lossy().into_owned())
+        .collect();
+
+    let debug = std::env::var_os("DEBUG").is_some();
+
+    if debug {
+        eprintln!("The glorious incremental compiler\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    handle_segfaults();
+
+    // `dlsym` expects the C name, without the leading underscore on macos
+    let name = CString::new(crate::x86::init().trim_start_matches('_')).unwrap();
+    let address = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+
+    if address.is_null() {
+        eprintln!("Failed to find the entry point `init` of the program");
+        return 1;
+    }
+
+    let image = match jit::stub(address as usize) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    // A program built with `--coverage` or `--profile` writes its counters out
+    // as it exits
+    coverage::install();
+    profile::install();
+
+    // Heap settings missing here are read from the environment, see `gc::Policy`
+    let heap = gc::gc_init(
+        option(&args, "--heap-size"),
+        option(&args, "--heap-growth"),
+        option(&args, "--heap-limit"),
+    );
+
+    if debug {
+        eprintln!("Heap segment    : {:p}", heap.pointer);
+    }
+
+    process::set_command_line(arguments(&args));
+
+    image.run_with(heap, |val| {
+        if debug {
+            eprintln!("Value in rax    : {} ({:#x})\n", val.0, val.0);
+        }
+
+        rt::print(val, false);
+        println!();
+    });
+
+    gc::report();
+
+    0
+}
+
+/// Options for the runtime itself, each followed by a value
+const OPTIONS: [&str; 3] = ["--heap-size", "--heap-growth", "--heap-limit"];
+
+/// Arguments for the program, leaving out the [OPTIONS] for the runtime
+fn arguments(args: &[String]) -> Vec<String> {
+    let mut rest = vec![];
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if OPTIONS.contains(&arg.as_str()) {
+            iter.next();
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+
+    rest
+}
+
+/// Value of a numeric command line option like `--heap-size 4096` or 0
+fn option(args: &[String], name: &str) -> usize {
+    args.iter()
+        .skip(1)
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 2))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Report segfaults before aborting
+///
+/// The handler runs on a stack of its own, since the segfault could be due to
+/// a stack overflow. See the [rethinkdb blog][blog] for details.
+///
+/// [blog]: https://rethinkdb.com/blog/handling-stack-overflow-on-custom-stacks/
+#[cfg(target_os = "linux")]
+unsafe fn handle_segfaults() {
+    extern "C" fn handler(signo: c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        println!("Segmentation fault due to invalid memory access ");
+        println!("Signal number        : {} ", signo);
+        println!("SIGSEGV at address   : {:p} ", unsafe { (*info).si_addr() });
+        std::process::abort()
+    }
+
+    let stack = libc::stack_t {
+        ss_sp: libc::malloc(SIGNAL_STACK),
+        ss_flags: 0,
+        ss_size: SIGNAL_STACK,
+    };
+    libc::sigaltstack(&stack, std::ptr::null_mut());
+
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    action.sa_sigaction = handler as *const () as libc::sighandler_t;
+
+    if libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut()) == -1 {
+        eprintln!("sigsegv: sigaction: {}", std::io::Error::last_os_error());
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options() {
+        let args: Vec<String> =
+            ["inc", "--heap-size", "4096", "--heap-limit"].iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(option(&args, "--heap-size"), 4096);
+        assert_eq!(option(&args, "--heap-limit"), 0);
+        assert_eq!(option(&args, "--heap-growth"), 0);
+        assert_eq!(arguments(&args), vec![String::from("inc")]);
+    }
+}
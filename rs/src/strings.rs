@@ -26,18 +26,22 @@
 //! addresses `(*p)` and the data at `(*p + 1)`. fwrite can safely print the
 //! exact number of bytes using the length and pointer.
 //!
+//! Strings allocated at runtime with `make-string` are mutable, but literals
+//! are part of the program and can't be modified. Literals are marked with the
+//! [IMMUTABLE] bit in the length prefix, which is shifted out when the length
+//! is converted to a fixnum.
+//!
 //! TODO: Consider switching to SDS. https://github.com/antirez/sds
 
 use crate::{
     compiler::state::State,
     immediate,
-    x86::{
-        self, Ins, Reference,
-        Register::{R12, RAX},
-        ASM,
-    },
+    x86::{self, Directive, Ins, Register::RAX, ASM},
 };
 
+/// Marks the length of a string that can't be modified
+pub const IMMUTABLE: i64 = 1 << 62;
+
 /// Evaluate a string object
 pub fn eval(s: &State, data: &str) -> ASM {
     let index = s
@@ -50,36 +54,32 @@ pub fn eval(s: &State, data: &str) -> ASM {
 
 /// Inline static strings in source directly into the binary
 pub fn inline(s: &State) -> ASM {
-    let mut asm = ASM(vec![]);
+    let mut asm = ASM::default();
 
-    for (symbol, index) in &s.strings {
+    // Emit in index order so that the generated code is deterministic
+    let mut all: Vec<(&String, &usize)> = s.strings.iter().collect();
+    all.sort_by_key(|(_, index)| **index);
+
+    for (symbol, index) in all {
         // `.p2align 3` aligns the address of the following target to 8
         // bytes by setting the 3 low order bits to 0. This is necessary for
         // the immediate tagging scheme to work correctly.
         //
         // https://sourceware.org/binutils/docs-2.32/as/P2align.html
-        asm += Ins::from("");
-        asm += Ins::from(".p2align 3");
+        asm += Ins::Blank;
+        asm += Ins::Directive(Directive::Align(3));
         asm += x86::label(&label(*index));
-        asm += Ins(format!(".quad  {}", symbol.len()));
-        asm += Ins(format!(".asciz \"{}\"", symbol));
+        asm += Ins::Directive(Directive::Quad(symbol.len() as i64 | IMMUTABLE));
+        asm += Ins::Directive(Directive::Asciz(symbol.clone()));
     }
 
     asm
 }
 
+/// Prefix of the labels of string literals
+pub const LABEL: &str = "inc_str_";
+
 /// Label for inlining symbol
 fn label(index: usize) -> String {
-    format!("inc_str_{}", index)
-}
-
-/// Allocate a string object in heap with a specific size
-#[allow(clippy::identity_op)]
-pub fn make(_: &State, size: i64) -> ASM {
-    let aligned = ((size as i64 + 7) / 8) * 8;
-
-    x86::mov(Reference::from(R12 + 0), size.into())
-        + x86::mov(RAX.into(), R12.into())
-        + x86::or(RAX.into(), immediate::STR.into())
-        + x86::add(R12.into(), aligned.into())
+    format!("{}{}", LABEL, index)
 }
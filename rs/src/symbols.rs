@@ -21,40 +21,247 @@
 //! | 8000    | 4005  |
 //!  -----------------
 //! ```
+//!
+//! # Interning
+//!
+//! Symbols are interned, two symbols with the same name are always the same
+//! object and `eq?` compares the addresses alone. The runtime keeps a table of
+//! all the symbols by name, which starts out with the symbols in the binary;
+//! `init` passes them to [rt_register_symbols] before anything else. Symbols
+//! created later by `string->symbol`, `read` or `eval` are looked up in the
+//! table and new ones are allocated outside the heap with the next free ID,
+//! right after the IDs used by the compiler.
+//!
+//! Code compiled by `eval` while the program is running refers to the symbols
+//! in the table directly instead of allocating its own.
+//!
+//! Units compiled separately from the program have symbols of their own in the
+//! binary, which may be in the program or another unit too. A unit refers to
+//! its symbols through a slot each in the data section instead, and the first
+//! thing its initializer does is to pass them to [rt_register_unit_symbols],
+//! which sets every slot to the symbol of that name in the table. The program
+//! runs the initializers right after `init` registers its own symbols, so the
+//! same symbol from two units or a unit and the program is one object.
+//!
+//! `(symbol->string sym)` copies the name into a fresh string on the heap.
 
 use crate::{
-    compiler::state::State,
-    immediate,
-    x86::{self, Ins, Register::RAX, ASM},
+    compiler::{emit, state::State},
+    core::Core,
+    ffi, gc,
+    hash::{self, Map},
+    immediate::{self, *},
+    primitives,
+    rt::{self, Object},
+    x86::{self, Directive, Ins, Reference, Register::*, ASM, WORDSIZE},
 };
+use std::cell::RefCell;
+
+thread_local! {
+    /// Every symbol of the running program by name
+    static SYMBOLS: RefCell<Map<String, Object>> = RefCell::new(hash::map(0));
+}
 
 /// Evaluate a symbols object
 pub fn eval(s: &State, data: &str) -> ASM {
+    if s.runtime {
+        return x86::mov(RAX.into(), intern(data).0.into()).into();
+    }
+
     let index = s
         .symbols
         .get(data)
         .unwrap_or_else(|| panic!("Symbol `{}` not found in symbol table", data));
 
+    if s.unit.is_some() {
+        return x86::lea(RAX, SLOTS, *index as i64 * WORDSIZE)
+            + x86::mov(RAX.into(), Reference::from(RAX + 0));
+    }
+
     x86::lea(RAX, &label(*index), immediate::SYM).into()
 }
 
+/// Emit code to add the symbols in the binary to the table in the runtime
+///
+/// The symbols are laid out one after the other in order by [inline]. The
+/// symbols of a unit are interned instead, for its slots to be filled in.
+pub fn register(s: &State) -> ASM {
+    if s.symbols.is_empty() {
+        return ASM::default();
+    }
+
+    let asm = x86::lea(RDI, &label(0), immediate::SYM)
+        + x86::mov(RSI.into(), (s.symbols.len() as i64).into());
+
+    match s.unit {
+        Some(_) => asm + x86::lea(RDX, SLOTS, 0) + ffi::runtime(s, "rt_register_unit_symbols"),
+        None => asm + ffi::runtime(s, "rt_register_symbols"),
+    }
+}
+
+/// The call a unit makes to [register] its symbols, see [emit::unit]
+pub const REGISTER: &str = "%register-symbols";
+
+/// Emit code for `(string->symbol str)`
+pub fn from_string(s: &mut State, val: &Core) -> ASM {
+    emit::eval(s, val)
+        + primitives::check(s, "string->symbol", STR)
+        + x86::mov(RDI.into(), RAX.into())
+        + ffi::runtime(s, "rt_string_to_symbol")
+}
+
+/// Emit code for `(symbol->string sym)`
+pub fn to_string(s: &mut State, val: &Core) -> ASM {
+    let mut asm = emit::eval(s, val) + primitives::check(s, "symbol->string", SYM);
+    let (sym, size) = (s.alloc(), s.alloc());
+
+    // Length prefixed and NUL terminated, rounded up to a word
+    asm += x86::save(RAX.into(), sym);
+    asm += x86::mov(RAX.into(), Reference::from(RAX + (WORDSIZE - SYM)));
+    asm += x86::add(RAX.into(), (WORDSIZE + 8).into());
+    asm += x86::and(RAX.into(), (-8).into());
+    asm += x86::save(RAX.into(), size);
+    asm += gc::alloc(s, Reference::from(RBP + size));
+    asm += x86::mov(RDI.into(), Reference::from(RBP + sym));
+    asm += ffi::runtime(s, "rt_symbol_to_string");
+
+    s.dealloc(2);
+    asm
+}
+
+/// Find or create a symbol by name
+///
+/// New symbols have the same layout as the ones in the binary and live outside
+/// the heap forever.
+pub fn intern(name: &str) -> Object {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        let id = symbols.len() as i64;
+
+        *symbols.entry(name.to_string()).or_insert_with(|| {
+            let mut words = vec![0i64; 2 + (name.len() + 8) / 8];
+            words[0] = id;
+            words[1] = name.len() as i64;
+
+            let data = Box::leak(words.into_boxed_slice());
+            let pstr = data[2..].as_mut_ptr() as *mut u8;
+            unsafe { std::ptr::copy(name.as_ptr(), pstr, name.len()) };
+
+            Object::new(data.as_ptr() as i64 | SYM)
+        })
+    })
+}
+
+/// Start the symbol table of a program with the `count` symbols in the binary
+/// starting at `first`
+///
+/// Symbols of a previous program on the same thread are forgotten.
+///
+/// # Safety
+///
+/// Must be called only from `init`, with the symbols emitted by [inline].
+#[no_mangle]
+pub unsafe extern "C" fn rt_register_symbols(first: Object, count: i64) {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        let mut sym = first.0;
+
+        symbols.clear();
+        symbols.reserve(count as usize);
+
+        for _ in 0..count {
+            let name = String::from_utf8_lossy(rt::sym_bytes(sym)).into_owned();
+
+            symbols.insert(name, Object::new(sym));
+            sym = next(sym);
+        }
+    })
+}
+
+/// Set each of the `slots` of a unit to the symbol in the table named like the
+/// one of the `count` symbols in the binary starting at `first`
+///
+/// # Safety
+///
+/// Must be called only by the initializer of a unit, with the symbols emitted
+/// by [inline] and a slot for each of them.
+#[no_mangle]
+pub unsafe extern "C" fn rt_register_unit_symbols(first: Object, count: i64, slots: *mut Object) {
+    let mut sym = first.0;
+
+    for i in 0..count as usize {
+        *slots.add(i) = intern(&String::from_utf8_lossy(rt::sym_bytes(sym)));
+        sym = next(sym);
+    }
+}
+
+/// The symbol laid out right after `sym` by [inline]
+fn next(sym: i64) -> i64 {
+    let words = 2 + (rt::sym_bytes(sym).len() as i64 + 8) / 8;
+    sym + words * WORDSIZE
+}
+
+/// The symbol named by a string
+#[no_mangle]
+pub extern "C" fn rt_string_to_symbol(name: Object) -> Object {
+    intern(&String::from_utf8_lossy(rt::str_bytes(name.0)))
+}
+
+/// A copy of the name of a symbol as a string
+///
+/// There must be room for the string at the heap pointer.
+#[no_mangle]
+pub extern "C" fn rt_symbol_to_string(sym: Object) -> Object {
+    rt::string(rt::sym_bytes(sym.0))
+}
+
 /// Inline static symbols in source directly into the binary
+///
+/// Code compiled at run time uses the symbols in the runtime instead.
 pub fn inline(s: &State) -> ASM {
-    let mut asm = ASM(vec![]);
+    let mut asm = ASM::default();
+
+    if s.runtime {
+        return asm;
+    }
+
+    // Emit in index order so that the generated code is deterministic
+    let mut all: Vec<(&String, &usize)> = s.symbols.iter().collect();
+    all.sort_by_key(|(_, index)| **index);
 
-    for (symbol, index) in &s.symbols {
-        asm += Ins::from("");
-        asm += Ins::from(".p2align 3");
+    for (symbol, index) in all {
+        asm += Ins::Blank;
+        asm += Ins::Directive(Directive::Align(3));
         asm += x86::label(&label(*index));
-        asm += Ins(format!(".quad  {}", index));
-        asm += Ins(format!(".quad  {}", symbol.len()));
-        asm += Ins(format!(".asciz \"{}\"", symbol))
+        asm += Ins::Directive(Directive::Quad(*index as i64));
+        asm += Ins::Directive(Directive::Quad(symbol.len() as i64));
+        asm += Ins::Directive(Directive::Asciz(symbol.clone()))
+    }
+
+    // A word for every symbol of a unit, see [rt_register_unit_symbols]
+    if s.unit.is_some() && !s.symbols.is_empty() {
+        asm += Ins::Blank;
+        asm += Ins::Directive(Directive::Data);
+        asm += Ins::Directive(Directive::Align(3));
+        asm += x86::label(SLOTS);
+
+        for _ in 0..s.symbols.len() {
+            asm += Ins::Directive(Directive::Quad(0));
+        }
+
+        asm += Ins::Directive(Directive::Text);
     }
 
     asm
 }
 
+/// Prefix of the labels of symbols
+pub const LABEL: &str = "inc_sym_";
+
+/// Label of the slots of the symbols of a unit
+const SLOTS: &str = "inc_sym_slots";
+
 /// Label for inlining symbol
 fn label(index: usize) -> String {
-    format!("inc_sym_{}", index)
+    format!("{}{}", LABEL, index)
 }
@@ -0,0 +1,110 @@
+//! Encoding of values in a machine word
+//!
+//! Every value is a 64 bit word. The lowest 3 bits are the type tag, the rest
+//! is either the payload of an immediate value like a fixnum or a character,
+//! or the address of an object aligned to 8 bytes. See
+//! [immediate](crate::immediate) for the representation of literals.
+//!
+//! ```text
+//!  63                                      3   0
+//! +------------------------------------------+---+
+//! | payload or address                       |tag|
+//! +------------------------------------------+---+
+//! ```
+//!
+//! Flonums don't fit in the payload and are boxed in the heap, see
+//! [numbers](crate::numbers). Code that looks at the bits of a value, in the
+//! runtime or in the code generator, should use the functions here rather
+//! than mask and shift by hand.
+
+use crate::x86::{self, Register, ASM};
+
+pub const NUM: i64 = 0;
+pub const BOOL: i64 = 1;
+pub const CHAR: i64 = 2;
+pub const PAIR: i64 = 3;
+pub const NIL: i64 = 4;
+pub const STR: i64 = 5;
+pub const SYM: i64 = 6;
+pub const VEC: i64 = 7;
+
+pub const SHIFT: i64 = 3;
+pub const MASK: i64 = 0b0000_0111;
+
+pub const FALSE: i64 = immediate(0, BOOL);
+pub const TRUE: i64 = immediate(1, BOOL);
+
+/// Type tag of a value
+pub const fn tag(val: i64) -> i64 {
+    val & MASK
+}
+
+/// Payload of an immediate value like a fixnum or a character
+pub const fn untag(val: i64) -> i64 {
+    val >> SHIFT
+}
+
+/// Immediate value with `tag` holding `payload`
+pub const fn immediate(payload: i64, tag: i64) -> i64 {
+    (payload << SHIFT) | tag
+}
+
+/// Address of the object referenced by a value
+pub const fn address(val: i64) -> *mut i64 {
+    (val & !MASK) as *mut i64
+}
+
+/// Can the value be a reference to an object in the heap?
+///
+/// Symbols are never allocated in the heap.
+pub const fn is_heap(val: i64) -> bool {
+    matches!(tag(val), PAIR | VEC | STR)
+}
+
+/// Name of the type with `tag`, as used in error messages
+pub const fn name(tag: i64) -> &'static str {
+    match tag {
+        NUM => "number",
+        BOOL => "boolean",
+        CHAR => "char",
+        PAIR => "pair",
+        NIL => "null",
+        STR => "string",
+        SYM => "symbol",
+        VEC => "vector",
+        _ => "unknown",
+    }
+}
+
+/// Emit code to load the tag of the value in `src` into `dst`
+pub fn load_tag(dst: Register, src: Register) -> ASM {
+    if dst == src {
+        x86::and(dst.into(), MASK.into()).into()
+    } else {
+        x86::mov(dst.into(), src.into()) + x86::and(dst.into(), MASK.into())
+    }
+}
+
+/// Emit code to turn the fixnum in `r` into a machine integer
+pub fn to_int(r: Register) -> ASM {
+    x86::sar(r.into(), SHIFT.into()).into()
+}
+
+/// Emit code to turn the machine integer in `r` into a fixnum
+pub fn to_fixnum(r: Register) -> ASM {
+    x86::sal(r.into(), SHIFT.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        assert_eq!(immediate(42, NUM), 336);
+        assert_eq!(untag(immediate(-7, NUM)), -7);
+        assert_eq!(tag(immediate(65, CHAR)), CHAR);
+        assert_eq!(address(0x1000 | PAIR), 0x1000 as *mut i64);
+        assert!(is_heap(0x1000 | STR) && !is_heap(0x1000 | SYM));
+    }
+}
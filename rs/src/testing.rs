@@ -0,0 +1,179 @@
+//! Run tests written in Scheme
+//!
+//! `inc test tests/` compiles and runs every `.scm` and `.ss` file under the
+//! directory. Tests use the [SRFI-64] subset defined in the prelude:
+//!
+//! ```scheme
+//! (test-begin "lists")
+//! (test-equal "length" 3 (length (cons 1 (cons 2 (cons 3 ())))))
+//! (test-assert "pair" (pair? (cons 1 2)))
+//! (test-end "lists")
+//! ```
+//!
+//! `test-eqv` and `test-eq` compare like `test-equal` with `eqv?` and `eq?`.
+//! There is no mutable state to count the results in, so each test prints a
+//! line starting with `%test` instead, and the runner reads them back from the
+//! output of the program. Anything else the program prints is left alone.
+//!
+//! [SRFI-64]: https://srfi.schemers.org/srfi-64/srfi-64.html
+
+use crate::{
+    cli,
+    core::{Config, Error},
+    diagnostic::Diagnostic,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Prefix of the lines printed by the test procedures in the prelude
+const MARKER: &str = "%test ";
+
+/// Results of running one test file
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub file: String,
+    /// Names of the tests that passed
+    pub passed: Vec<String>,
+    /// Tests that failed, with what was expected and what they got
+    pub failed: Vec<String>,
+    /// The file didn't compile or crashed, which fails it on its own
+    pub error: Option<String>,
+}
+
+impl Report {
+    /// Whether every test in the file passed
+    pub fn ok(&self) -> bool {
+        self.failed.is_empty() && self.error.is_none()
+    }
+
+    /// Collect the results from the output of a test program
+    pub fn parse(file: &str, output: &str) -> Self {
+        let mut report = Report { file: file.to_string(), ..Report::default() };
+
+        for line in output.lines() {
+            let line = match line.strip_prefix(MARKER) {
+                Some(line) => line,
+                None => continue,
+            };
+
+            if let Some(name) = line.strip_prefix("pass ") {
+                report.passed.push(name.to_string());
+            } else if let Some(failure) = line.strip_prefix("fail ") {
+                report.failed.push(failure.to_string());
+            }
+        }
+
+        report
+    }
+}
+
+/// The test files in each of the paths, in order
+///
+/// Directories are searched recursively, files are taken as they are.
+pub fn files(paths: &[String]) -> io::Result<Vec<String>> {
+    fn walk(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+        let mut entries =
+            fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for path in entries {
+            if path.is_dir() {
+                walk(&path, out)?;
+            } else if matches!(path.extension().and_then(|e| e.to_str()), Some("scm" | "ss")) {
+                out.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut out = vec![];
+
+    for path in paths {
+        let path = Path::new(path);
+
+        if path.is_dir() {
+            walk(path, &mut out)?;
+        } else {
+            out.push(path.to_path_buf());
+        }
+    }
+
+    Ok(out.into_iter().map(|p| p.to_string_lossy().into_owned()).collect())
+}
+
+/// Compile and run a test file
+///
+/// `config` is used for everything but the program, and each file is built
+/// in its own executable next to `config.output`.
+pub fn run(config: &Config, file: &str, n: usize) -> Report {
+    let program = match fs::read_to_string(file) {
+        Ok(program) => program,
+        Err(e) => {
+            return Report {
+                file: file.to_string(),
+                error: Some(format!("Failed to read {}: {}", file, e)),
+                ..Report::default()
+            }
+        }
+    };
+
+    let config = Config {
+        program,
+        output: format!("{}-{}", config.output, n),
+        passes: config.passes,
+        load: config.load.clone(),
+        prelude: config.prelude,
+        cache: config.cache,
+        target: config.target.clone(),
+        ..Config::default()
+    };
+
+    let result = cli::run(&config, cli::Action::Run);
+
+    for path in &[config.output.clone(), config.asm()] {
+        fs::remove_file(path).ok();
+    }
+
+    match result {
+        Ok(output) => Report::parse(file, output.as_deref().unwrap_or("")),
+        // A crash still reports the tests that ran before it
+        Err(Error::Runtime(e) | Error::Exit { message: e, .. }) => {
+            Report { error: Some(e.clone()), ..Report::parse(file, &e) }
+        }
+        Err(e) => {
+            let source = config.program.as_str();
+            let error = Diagnostic::all(&e, &[(file, source)])
+                .iter()
+                .map(|d| d.render(Some(source), false))
+                .collect();
+
+            Report { file: file.to_string(), error: Some(error), ..Report::default() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let output = "%test begin lists\n\
+                      %test pass length\n\
+                      hello\n\
+                      %test fail wrong: expected \"ab\", got \"ac\"\n\
+                      %test end lists\n\
+                      #t";
+
+        let report = Report::parse("lists.scm", output);
+
+        assert_eq!(report.passed, vec!["length"]);
+        assert_eq!(report.failed, vec!["wrong: expected \"ab\", got \"ac\""]);
+        assert!(!report.ok());
+
+        assert!(Report::parse("empty.scm", "()").ok());
+    }
+}
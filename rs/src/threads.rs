@@ -0,0 +1,495 @@
+//! Green threads
+//!
+//! `(spawn f args...)` creates a lightweight thread applying the function `f`
+//! to the arguments and returns a thread object right away, without running
+//! it. Threads are
+//! scheduled cooperatively; a thread runs until it calls `(yield)`, waits for
+//! a message or another thread, or returns. `(thread-join t)` waits for a
+//! thread to finish and evaluates to the value returned by its function.
+//!
+//! ```scheme
+//! (define (producer ch)
+//!   (channel-send ch 42))
+//!
+//! (let ((ch (make-channel)))
+//!   (let ((t (spawn producer ch)))
+//!     (channel-recv ch)))
+//! ```
+//!
+//! Channels created with `(make-channel)` are unbounded queues; `channel-send`
+//! never blocks, while `channel-recv` waits for a message if the channel is
+//! empty. Waiting when every other thread is waiting too is a deadlock, which
+//! raises an error in the thread that was about to wait.
+//!
+//! Every thread gets its own stack and dynamic environment (exception handlers,
+//! escape points and after thunks), while the heap is shared. The main
+//! program runs on the native stack as the first thread; returning from it
+//! ends the program, no matter what the other threads are doing.
+//!
+//! Threads switch only at calls, which are safe points for the collector as
+//! well; every live value is in a stack slot of a frame in the chain from the
+//! current RBP up to the base of the stack. A suspended thread is exactly that,
+//! a frame, the address to resume at and the base and the limit of its stack,
+//! and the collector walks it like the stack of the current thread. Switching
+//! is a jump to the resume address of the next thread with its frame, just
+//! like a raise to a guard. See [exceptions](crate::exceptions).
+//!
+//! The thread and channel objects are vectors, `#(thread key done value args)`
+//! and `#(channel key head tail)`, managed by the
+//! [prelude](crate::compiler::parse). The runtime knows only the keys threads
+//! wait on.
+use crate::{
+    compiler::{emit::eval, state::State},
+    core::{Core, Expr, Ident, Literal::*},
+    exceptions::{self, Dynamic, Target},
+    ffi,
+    immediate::*,
+    lambda,
+    rt::{self, Object},
+    tags::untag,
+    x86::{self, Reference, Register::*, Relative, ASM, WORDSIZE},
+};
+use std::{cell::RefCell, collections::VecDeque, mem, ptr};
+
+/// Size of the stack of a thread, including the margin to report an overflow
+pub const STACK_SIZE: usize = 1 << 20;
+
+/// Where a suspended thread continues
+///
+/// The generated code switches to a thread by loading the base and the limit
+/// of its stack into R14 and R15 and jumping to `address` with `frame` as
+/// both RBP and RSP.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Context {
+    pub frame: i64,
+    pub address: i64,
+    pub base: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ready,
+    /// Waiting on a key, until it is passed to [rt_wake]
+    Waiting(i64),
+}
+
+/// A stack mapped for a thread, unmapped when dropped
+///
+/// Without the operating system of the `native` feature, the stack is
+/// allocated on the heap instead.
+struct Stack {
+    mem: *mut u8,
+    len: usize,
+}
+
+struct Thread {
+    key: i64,
+    status: Status,
+    context: Context,
+    // Last live slot of the suspended frame
+    top: i64,
+    dynamic: Dynamic,
+    // The main thread runs on the native stack
+    stack: Option<Stack>,
+}
+
+/// The threads of a program
+///
+/// `target` is the context of the thread switched to most recently, which the
+/// generated code reads right after the switch.
+struct Scheduler {
+    current: Thread,
+    suspended: VecDeque<Thread>,
+    keys: i64,
+    // Stacks of finished threads, unmapped once they are no longer in use
+    dead: Vec<Stack>,
+    target: Context,
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<Scheduler> = RefCell::new(Scheduler::new());
+}
+
+/// Emit code for `(spawn f args...)`
+///
+/// The arguments are evaluated into a list kept in the thread object. The new
+/// thread starts at a small routine emitted inline, which applies `f` to them
+/// on a fresh stack with the thread object in the only slot of the first
+/// frame and then finishes the thread with the value.
+pub fn spawn(s: &mut State, f: &Ident, args: &[Core]) -> ASM {
+    lambda::function(s, "spawn", f);
+
+    let start = s.gen_label("thread");
+    let done = s.gen_label("spawned");
+
+    let list = args.iter().rev().fold(Expr::Literal(Nil), |rest, arg| {
+        Expr::List(vec![Expr::Identifier(Ident::new("cons")), arg.clone(), rest])
+    });
+
+    let mut asm = lambda::call(s, &Ident::new("%make-thread"), &[list])
+        + x86::mov(RDI.into(), RAX.into())
+        + x86::lea(RSI, &start, 0)
+        + ffi::runtime(s, "rt_spawn")
+        + x86::jmp(&done)
+        + x86::label(&start);
+
+    let si = s.si;
+    s.si = -WORDSIZE;
+    s.enter();
+
+    let thread = Ident::new("%thread");
+    s.set(thread.clone(), Relative { register: RBP, offset: -WORDSIZE }.into());
+
+    let args = Expr::List(vec![
+        Expr::Identifier(Ident::new("vector-ref")),
+        Expr::Identifier(thread.clone()),
+        Expr::Literal(Number(4)),
+    ]);
+    asm += lambda::apply(s, f, &[], &args);
+
+    let value = Ident::new("%value");
+    asm += x86::save(RAX.into(), s.si);
+    s.set(value.clone(), Relative { register: RBP, offset: s.si }.into());
+
+    let args = [Expr::Identifier(thread), Expr::Identifier(value)];
+    asm += lambda::call(s, &Ident::new("%thread-exit"), &args);
+    asm += ffi::runtime(s, "rt_exit");
+    asm += transfer(s);
+
+    s.leave();
+    s.si = si;
+
+    asm + x86::label(&done)
+}
+
+/// Emit code to switch to the next thread, suspending the current one
+///
+/// The current thread waits on the key `wait` or is ready to run again right
+/// away if it is `()`, like `(yield)`. Evaluates to `()` when the thread is
+/// resumed.
+pub fn switch(s: &mut State, wait: &Core) -> ASM {
+    let resume = s.gen_label("resume");
+
+    eval(s, wait)
+        + x86::mov(R9.into(), RAX.into())
+        + x86::mov(RDI.into(), RBP.into())
+        + x86::mov(RSI.into(), RBP.into())
+        + x86::add(RSI.into(), (s.si + WORDSIZE).into())
+        + x86::lea(RDX, &resume, 0)
+        + x86::mov(RCX.into(), R14.into())
+        + x86::mov(R8.into(), R15.into())
+        + ffi::runtime(s, "rt_switch")
+        + transfer(s)
+        + x86::label(&resume)
+        + x86::mov(RAX.into(), NIL.into())
+}
+
+/// Jump to the [Context] in RAX, or raise a deadlock if it is null
+fn transfer(s: &mut State) -> ASM {
+    let ok = s.gen_label("switch");
+
+    x86::cmp(RAX.into(), Reference::Const(0))
+        + x86::jne(&ok)
+        + ffi::runtime(s, "rt_deadlock")
+        + exceptions::fail(s)
+        + x86::label(&ok)
+        + x86::mov(R14.into(), Reference::from(RAX + 2 * WORDSIZE))
+        + x86::mov(R15.into(), Reference::from(RAX + 3 * WORDSIZE))
+        + x86::mov(RDX.into(), Reference::from(RAX + WORDSIZE))
+        + x86::mov(RAX.into(), Reference::from(RAX + 0))
+        + x86::mov(RBP.into(), RAX.into())
+        + x86::mov(RSP.into(), RAX.into())
+        + x86::jmp_indirect(RDX)
+}
+
+/// Create a thread starting at `address` for a thread object
+///
+/// The thread is ready to run, but the current thread continues for now.
+#[no_mangle]
+pub extern "C" fn rt_spawn(thread: Object, address: i64) -> Object {
+    let key = match thread.deref() {
+        Expr::Vector(v) => match v.get(1) {
+            Some(Expr::Literal(Number(key))) => *key,
+            _ => unreachable!("invalid thread {}", thread),
+        },
+        _ => unreachable!("invalid thread {}", thread),
+    };
+
+    let stack = Stack::new();
+    let base = stack.base();
+
+    // The thread object is the only live slot of the first frame
+    unsafe { *(base as *mut i64).sub(1) = thread.0 };
+
+    let context =
+        Context { frame: base, address, base, limit: stack.mem as i64 + rt::STACK_MARGIN as i64 };
+
+    let t = Thread {
+        key,
+        status: Status::Ready,
+        context,
+        top: base - WORDSIZE,
+        dynamic: Dynamic::new(),
+        stack: Some(stack),
+    };
+
+    SCHEDULER.with(|s| s.borrow_mut().suspended.push_back(t));
+    thread
+}
+
+/// Suspend the current thread and return the context of the next one
+///
+/// The current thread continues at `address` with the frame `frame`, whose
+/// last live slot is `top`. It waits on the key `wait` unless it is `()`.
+/// A thread that yields continues right away if there is no other thread to
+/// run, while a waiting one gets null, see [rt_deadlock].
+#[no_mangle]
+pub extern "C" fn rt_switch(
+    frame: i64,
+    top: i64,
+    address: i64,
+    base: i64,
+    limit: i64,
+    wait: Object,
+) -> *const Context {
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+
+        s.dead.clear();
+        s.current.status =
+            if wait.0 == NIL { Status::Ready } else { Status::Waiting(untag(wait.0)) };
+        s.current.context = Context { frame, address, base, limit };
+        s.current.top = top;
+
+        match s.next() {
+            Some(next) => {
+                let previous = mem::replace(&mut s.current, next);
+                s.suspend(previous);
+                s.resume()
+            }
+            None if wait.0 == NIL => {
+                s.target = s.current.context;
+                &s.target
+            }
+            None => ptr::null(),
+        }
+    })
+}
+
+/// Finish the current thread and return the context of the next one
+///
+/// Wakes every thread waiting on the thread. Returns null if there is no
+/// thread to run.
+#[no_mangle]
+pub extern "C" fn rt_exit() -> *const Context {
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        let key = s.current.key;
+        s.wake(key);
+
+        // The stack of this thread is still in use until the switch, unlike
+        // the stacks of the threads that finished before
+        s.dead.clear();
+
+        match s.next() {
+            Some(next) => {
+                let previous = mem::replace(&mut s.current, next);
+                s.dead.extend(previous.stack);
+                s.resume()
+            }
+            None => ptr::null(),
+        }
+    })
+}
+
+/// Raise a deadlock when no thread can run
+#[no_mangle]
+pub extern "C" fn rt_deadlock() -> Target {
+    exceptions::error("deadlock: every thread is waiting")
+}
+
+/// A fresh key for a thread or a channel
+#[no_mangle]
+pub extern "C" fn rt_new_key() -> Object {
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        s.keys += 1;
+        Object::immediate(s.keys)
+    })
+}
+
+/// Make every thread waiting on `key` ready to run
+#[no_mangle]
+pub extern "C" fn rt_wake(key: Object) -> Object {
+    SCHEDULER.with(|s| s.borrow_mut().wake(untag(key.0)));
+    Object(NIL)
+}
+
+/// The live part of the stack of every suspended thread, as the top, the
+/// frame and the base; see [gc](crate::gc)
+pub fn stacks() -> Vec<(*mut i64, *mut i64, *mut i64)> {
+    SCHEDULER.with(|s| {
+        s.borrow()
+            .suspended
+            .iter()
+            .map(|t| (t.top as *mut i64, t.context.frame as *mut i64, t.context.base as *mut i64))
+            .collect()
+    })
+}
+
+/// Forget all the threads of the current thread, before running a program
+pub fn reset() {
+    SCHEDULER.with(|s| *s.borrow_mut() = Scheduler::new())
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let main = Thread {
+            key: 0,
+            status: Status::Ready,
+            context: Context::default(),
+            top: 0,
+            dynamic: Dynamic::new(),
+            stack: None,
+        };
+
+        Scheduler {
+            current: main,
+            suspended: VecDeque::new(),
+            keys: 0,
+            dead: vec![],
+            target: Context::default(),
+        }
+    }
+
+    /// Take the first suspended thread that is ready to run, round robin
+    fn next(&mut self) -> Option<Thread> {
+        let i = self.suspended.iter().position(|t| t.status == Status::Ready)?;
+        self.suspended.remove(i)
+    }
+
+    /// Put a thread to the end of the queue along with its dynamic environment
+    fn suspend(&mut self, mut thread: Thread) {
+        exceptions::dynamic(|d| mem::swap(d, &mut thread.dynamic));
+        self.suspended.push_back(thread);
+    }
+
+    /// Switch to the current thread, which was suspended before
+    fn resume(&mut self) -> *const Context {
+        let dynamic = mem::replace(&mut self.current.dynamic, Dynamic::new());
+        exceptions::dynamic(|d| *d = dynamic);
+
+        self.target = self.current.context;
+        &self.target
+    }
+
+    fn wake(&mut self, key: i64) {
+        for t in self.suspended.iter_mut() {
+            if t.status == Status::Waiting(key) {
+                t.status = Status::Ready;
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    // The program could exit from a thread other than the main one, with the
+    // scheduler dropped along with the other thread locals; the stack still in
+    // use must stay mapped till the very end.
+    fn drop(&mut self) {
+        mem::forget(self.current.stack.take())
+    }
+}
+
+impl Stack {
+    #[cfg(feature = "native")]
+    fn new() -> Self {
+        let mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                STACK_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+
+        if mem == libc::MAP_FAILED {
+            eprintln!("Out of memory");
+            exceptions::Status::Memory.exit()
+        }
+
+        Stack { mem: mem as *mut u8, len: STACK_SIZE }
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn new() -> Self {
+        let mem = unsafe { std::alloc::alloc_zeroed(Self::layout()) };
+
+        if mem.is_null() {
+            eprintln!("Out of memory");
+            exceptions::Status::Memory.exit()
+        }
+
+        Stack { mem, len: STACK_SIZE }
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn layout() -> std::alloc::Layout {
+        std::alloc::Layout::from_size_align(STACK_SIZE, 16).unwrap()
+    }
+
+    /// The highest address of the stack, aligned to 16 bytes
+    fn base(&self) -> i64 {
+        (self.mem as i64 + self.len as i64) & -16
+    }
+}
+
+impl Drop for Stack {
+    #[cfg(feature = "native")]
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.mem as *mut libc::c_void, self.len);
+        }
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.mem, Self::layout()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suspend(s: &mut Scheduler, key: i64, status: Status) {
+        let thread = Thread {
+            key,
+            status,
+            context: Context { frame: key, ..Context::default() },
+            top: 0,
+            dynamic: Dynamic::new(),
+            stack: None,
+        };
+        s.suspended.push_back(thread);
+    }
+
+    #[test]
+    fn schedule() {
+        let mut s = Scheduler::new();
+
+        suspend(&mut s, 1, Status::Waiting(3));
+        suspend(&mut s, 2, Status::Ready);
+
+        // Waiting threads are skipped until woken up
+        assert_eq!(s.next().map(|t| t.key), Some(2));
+        assert!(s.next().is_none());
+
+        s.wake(3);
+        assert_eq!(s.next().map(|t| t.context.frame), Some(1));
+    }
+}
@@ -0,0 +1,245 @@
+//! Special forms written wrong and keywords used as variables
+//!
+//! The parser reads a special form only when it is well formed, anything else
+//! starting with a keyword like `(let (x 1) x)`, `(lambda)` or `(if)` is read
+//! as an application of the keyword. Left alone these end up as an undefined
+//! variable or a call to a function the linker can't find, far from what went
+//! wrong, so every top level form is checked right after it is read:
+//!
+//! ```
+//! use inc::{parser, validate};
+//!
+//! let prog = parser::parse("(let (x 1) x)").unwrap().remove(0);
+//! let errors = validate::check(&prog).unwrap_err();
+//!
+//! assert_eq!(
+//!     errors[0].message,
+//!     "Malformed `let` in `(let (x 1) x)`: binding `x` isn't a name and a value"
+//! );
+//! ```
+//!
+//! Keywords can't be bound or referred to as variables either, `(let ((if 1))
+//! if)` is rejected rather than making `if` mean two things in one program.
+use crate::{
+    compiler::Fault,
+    core::{Closure, Expr::*, Literal::Nil, Name, Syntax},
+    semantic::KEYWORDS,
+};
+
+/// Keywords of the special forms the parser reads, see [malformed]
+const FORMS: [&str; 5] = ["define", "if", "lambda", "let", "quote"];
+
+/// Fail with an error for every malformed form and keyword used as a variable
+///
+/// Each error points at the keyword it is about.
+pub fn check(prog: &Syntax) -> Result<(), Vec<Fault>> {
+    let mut errors = vec![];
+    visit(prog, &mut errors);
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn visit(prog: &Syntax, errors: &mut Vec<Fault>) {
+    fn variable(name: &Name, errors: &mut Vec<Fault>) {
+        if KEYWORDS.contains(&name.as_str()) {
+            let message = format!("Keyword `{}` can't be used as a variable", name);
+            errors.push(Fault { message, name: Some(name.clone()) })
+        }
+    }
+
+    match prog {
+        Identifier(name) => variable(name, errors),
+
+        Let { bindings, body } => {
+            for (name, value) in bindings {
+                variable(name, errors);
+                visit(value, errors);
+            }
+            body.iter().for_each(|e| visit(e, errors));
+        }
+
+        Lambda(Closure { formals, body, .. }) => {
+            formals.iter().for_each(|name| variable(name, errors));
+            body.iter().for_each(|e| visit(e, errors));
+        }
+
+        Define { name, val } => {
+            variable(name, errors);
+            visit(val, errors);
+        }
+
+        Cond { pred, then, alt } => {
+            visit(pred, errors);
+            visit(then, errors);
+            alt.iter().for_each(|e| visit(e, errors));
+        }
+
+        List(list) => match list.as_slice() {
+            // Quoted data can have keywords anywhere
+            [Identifier(head), _] if head == "quote" => {}
+            [Identifier(head), args @ ..] if FORMS.contains(&head.as_str()) => {
+                let message =
+                    format!("Malformed `{}` in `{}`: {}", head, prog, malformed(head, args));
+                errors.push(Fault { message, name: Some(head.clone()) })
+            }
+            // Other keywords are resolved or expanded by name, as forms of their own
+            [Identifier(head), args @ ..] if KEYWORDS.contains(&head.as_str()) => {
+                args.iter().for_each(|e| visit(e, errors))
+            }
+            _ => list.iter().for_each(|e| visit(e, errors)),
+        },
+
+        Vector(list) => list.iter().for_each(|e| visit(e, errors)),
+
+        Literal(_) => {}
+    }
+}
+
+/// What is wrong with the arguments of a special form the parser didn't read
+fn malformed(keyword: &str, args: &[Syntax]) -> String {
+    let names = |list: &[Syntax]| list.iter().find(|e| !matches!(e, Identifier(_))).cloned();
+
+    match (keyword, args) {
+        ("if", _) => {
+            format!("expected a condition and one or two branches, got {} forms", args.len())
+        }
+
+        ("let", []) => String::from("expected bindings and a body"),
+        ("let", [Identifier(name), ..]) => format!("named let `{}` isn't supported", name),
+        ("let", [Literal(Nil), body @ ..]) | ("let", [List(_), body @ ..]) => {
+            let bindings = match &args[0] {
+                List(bindings) => bindings.as_slice(),
+                _ => &[],
+            };
+            let binding = bindings.iter().find(|binding| match binding {
+                List(pair) => !matches!(pair.as_slice(), [Identifier(_), _]),
+                _ => true,
+            });
+
+            match binding {
+                Some(binding) => format!("binding `{}` isn't a name and a value", binding),
+                None if body.is_empty() => String::from("expected a body after the bindings"),
+                None => String::from("expected a list of bindings and a body"),
+            }
+        }
+        ("let", [bindings, ..]) => format!("expected a list of bindings, got `{}`", bindings),
+
+        ("lambda", []) => String::from("expected formals and a body"),
+        ("lambda", [List(formals), ..]) if names(formals).is_some() => {
+            format!("formal `{}` isn't a name", names(formals).unwrap())
+        }
+        ("lambda", [_]) => String::from("expected a body after the formals"),
+        ("lambda", [formals, ..]) => format!("expected formals, got `{}`", formals),
+
+        ("define", []) => String::from("expected a name and a value"),
+        ("define", [Identifier(_)]) => String::from("expected a value after the name"),
+        ("define", [List(head), ..]) if names(head).is_some() => {
+            format!("`{}` isn't a name", names(head).unwrap())
+        }
+        ("define", [List(_)]) => String::from("expected a body after the formals"),
+        ("define", [name, ..]) => format!("expected a name, got `{}`", name),
+
+        ("quote", _) => format!("expected a single datum, got {} forms", args.len()),
+
+        _ => String::from("it couldn't be read"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn errors(source: &str) -> Vec<String> {
+        parser::parse(source)
+            .unwrap()
+            .iter()
+            .flat_map(|e| check(e).err().unwrap_or_default())
+            .map(|fault| fault.message)
+            .collect()
+    }
+
+    #[test]
+    fn forms() {
+        let error = |source: &str| errors(source).remove(0);
+
+        assert_eq!(
+            error("(if)"),
+            "Malformed `if` in `(if)`: expected a condition and one or two branches, got 0 forms"
+        );
+        assert_eq!(
+            error("(if 1 2 3 4)").split(": ").nth(1),
+            Some("expected a condition and one or two branches, got 4 forms")
+        );
+        assert_eq!(
+            error("(let ((x 1)))"),
+            "Malformed `let` in `(let ((x 1)))`: expected a body after the bindings"
+        );
+        assert_eq!(error("(let)"), "Malformed `let` in `(let)`: expected bindings and a body");
+        assert_eq!(
+            error("(quote a b)"),
+            "Malformed `quote` in `(quote a b)`: expected a single datum, got 2 forms"
+        );
+        assert!(errors("(quote (if define))").is_empty());
+        assert_eq!(
+            error("(let ((x 1) (y)) y)"),
+            "Malformed `let` in `(let ((x 1) (y)) y)`: binding `(y)` isn't a name and a value"
+        );
+        assert_eq!(
+            error("(let loop ((i 0)) i)"),
+            "Malformed `let` in `(let loop ((i 0)) i)`: named let `loop` isn't supported"
+        );
+        assert_eq!(
+            error("(lambda)"),
+            "Malformed `lambda` in `(lambda)`: expected formals and a body"
+        );
+        assert_eq!(
+            error("(lambda (x 1) x)"),
+            "Malformed `lambda` in `(lambda (x 1) x)`: formal `1` isn't a name"
+        );
+        assert_eq!(
+            error("(lambda (x))"),
+            "Malformed `lambda` in `(lambda (x))`: expected a body after the formals"
+        );
+        assert_eq!(
+            error("(define)"),
+            "Malformed `define` in `(define)`: expected a name and a value"
+        );
+        assert_eq!(
+            error("(define x)"),
+            "Malformed `define` in `(define x)`: expected a value after the name"
+        );
+        assert_eq!(
+            error("(define (f 1) 1)"),
+            "Malformed `define` in `(define (f 1) 1)`: `1` isn't a name"
+        );
+
+        // Nested forms are found too, all of them
+        assert_eq!(errors("(define (f x) (cons (if) (lambda)))").len(), 2);
+        assert!(errors("(define (f x) (let ((y x)) (if y (lambda (z) z) 2))) (f 1)").is_empty());
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            errors("(let ((if 1)) if)"),
+            vec![
+                "Keyword `if` can't be used as a variable",
+                "Keyword `if` can't be used as a variable"
+            ]
+        );
+        assert_eq!(errors("(define (f let) 1)"), vec!["Keyword `let` can't be used as a variable"]);
+        assert_eq!(
+            errors("(define lambda 1)"),
+            vec!["Keyword `lambda` can't be used as a variable"]
+        );
+        assert_eq!(errors("(f define)"), vec!["Keyword `define` can't be used as a variable"]);
+
+        // Primitives are names like any other
+        assert!(errors("(let ((car 1) (list 2)) (+ car list))").is_empty());
+    }
+}
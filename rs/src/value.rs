@@ -0,0 +1,320 @@
+//! Scheme values on the Rust side
+//!
+//! A [Value] is what Rust code embedding inc gets back from [eval](crate::eval)
+//! and hands to Scheme. Values are built from Rust types with `From`, or with
+//! the helpers for lists and vectors, and taken apart with `TryFrom`, which
+//! fails with a [runtime error](Error::Runtime) like a primitive given the
+//! wrong type would:
+//!
+//! ```
+//! use inc::Value;
+//! use std::convert::TryFrom;
+//!
+//! let list = Value::list(vec![1, 2, 3]);
+//! assert_eq!(list.to_string(), "(1 2 3)");
+//!
+//! let sum: i64 = list.as_list().unwrap().into_iter().map(|n| i64::try_from(n).unwrap()).sum();
+//! assert_eq!(sum, 6);
+//! assert!(bool::try_from(&list).is_err());
+//! ```
+//!
+//! Values are copied out of the heap of the program, so they stay valid after
+//! it finishes and can't be used to change anything in it.
+
+use crate::{
+    bignum::Big,
+    core::{Core, Error, Expr, Ident, Literal::*},
+    interp,
+};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
+
+/// A Scheme value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// The empty list `()`
+    Nil,
+    Int(i64),
+    /// An integer too large for a fixnum
+    Big(Big),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Symbol(String),
+    Pair(Box<Value>, Box<Value>),
+    Vector(Vec<Value>),
+    /// A top level function, by name
+    ///
+    /// Functions aren't values of their own in the language, so a procedure
+    /// is only a handle naming one for the host to call.
+    Procedure(String),
+}
+
+impl Value {
+    /// A value from the [Core] both the interpreter and the runtime decode
+    /// values to, where a pair is a list of its car and cdr
+    pub(crate) fn new(val: Core) -> Self {
+        match val {
+            Expr::Literal(Nil) => Value::Nil,
+            Expr::Literal(Number(n)) => Value::Int(n),
+            Expr::Literal(Bignum(n)) => Value::Big(n),
+            Expr::Literal(Float(f)) => Value::Float(f),
+            Expr::Literal(Boolean(b)) => Value::Bool(b),
+            Expr::Literal(Char(c)) => Value::Char(c as char),
+            Expr::Literal(Str(s)) => Value::Str(s),
+            Expr::Literal(Symbol(s)) => Value::Symbol(s),
+            Expr::List(pair) => {
+                let mut pair = pair.into_iter().map(Self::new);
+                let car = pair.next().unwrap_or(Value::Nil);
+                let cdr = pair.next().unwrap_or(Value::Nil);
+                Value::cons(car, cdr)
+            }
+            Expr::Vector(vec) => Value::Vector(vec.into_iter().map(Self::new).collect()),
+            val => unreachable!("{} isn't a value", val),
+        }
+    }
+
+    /// The value as [Core] again, to print it like the compiler does
+    fn core(&self) -> Core {
+        match self {
+            Value::Nil => Expr::Literal(Nil),
+            Value::Int(n) => Expr::Literal(Number(*n)),
+            Value::Big(n) => Expr::Literal(Bignum(n.clone())),
+            Value::Float(f) => Expr::Literal(Float(*f)),
+            Value::Bool(b) => Expr::Literal(Boolean(*b)),
+            Value::Char(c) => Expr::Literal(Char(*c as u8)),
+            Value::Str(s) => Expr::Literal(Str(s.clone())),
+            Value::Symbol(s) => Expr::Literal(Symbol(s.clone())),
+            Value::Pair(car, cdr) => Expr::List(vec![car.core(), cdr.core()]),
+            Value::Vector(vec) => Expr::Vector(vec.iter().map(Self::core).collect()),
+            Value::Procedure(name) => {
+                Expr::Identifier(Ident::new(format!("#<procedure {}>", name)))
+            }
+        }
+    }
+
+    /// A pair of two values
+    pub fn cons<A: Into<Value>, B: Into<Value>>(car: A, cdr: B) -> Self {
+        Value::Pair(Box::new(car.into()), Box::new(cdr.into()))
+    }
+
+    /// A proper list of the values
+    pub fn list<T: Into<Value>, I: IntoIterator<Item = T>>(values: I) -> Self {
+        let values: Vec<Value> = values.into_iter().map(Into::into).collect();
+        values.into_iter().rev().fold(Value::Nil, |cdr, car| Value::cons(car, cdr))
+    }
+
+    /// A vector of the values
+    pub fn vector<T: Into<Value>, I: IntoIterator<Item = T>>(values: I) -> Self {
+        Value::Vector(values.into_iter().map(Into::into).collect())
+    }
+
+    /// A symbol with a name
+    pub fn symbol<S: Into<String>>(name: S) -> Self {
+        Value::Symbol(name.into())
+    }
+
+    /// The elements of a proper list
+    pub fn as_list(&self) -> Option<Vec<&Value>> {
+        let mut list = vec![];
+        let mut rest = self;
+
+        loop {
+            match rest {
+                Value::Nil => return Some(list),
+                Value::Pair(car, cdr) => {
+                    list.push(car.as_ref());
+                    rest = cdr;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// The error for a value of the wrong type
+    fn expected(&self, kind: &str) -> Error<'static> {
+        Error::Runtime(format!("Exception: expected {}, got {}", kind, self))
+    }
+}
+
+/// Values are shown like the program would `write` them
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        interp::write(&mut out, &self.core(), false);
+        write!(f, "{}", out)
+    }
+}
+
+/// A fixnum if the integer fits in one, a bignum otherwise
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        let big = Big::from(n);
+
+        match big.fixnum() {
+            Some(n) => Value::Int(n),
+            None => Value::Big(big),
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<char> for Value {
+    fn from(c: char) -> Self {
+        Value::Char(c)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+/// A vector, see [Value::list] for a list
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::vector(values)
+    }
+}
+
+impl TryFrom<&Value> for i64 {
+    type Error = Error<'static>;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Int(n) => Ok(*n),
+            _ => Err(val.expected("fixnum")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = Error<'static>;
+
+    /// Integers are converted to flonums, like `exact->inexact`
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Float(f) => Ok(*f),
+            Value::Int(n) => Ok(*n as f64),
+            Value::Big(n) => Ok(n.to_f64()),
+            _ => Err(val.expected("number")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = Error<'static>;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(val.expected("boolean")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for char {
+    type Error = Error<'static>;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Char(c) => Ok(*c),
+            _ => Err(val.expected("char")),
+        }
+    }
+}
+
+/// The characters of a string or the name of a symbol
+impl TryFrom<&Value> for String {
+    type Error = Error<'static>;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Str(s) | Value::Symbol(s) => Ok(s.clone()),
+            _ => Err(val.expected("string")),
+        }
+    }
+}
+
+/// The elements of a vector or a proper list
+impl<T> TryFrom<&Value> for Vec<T>
+where
+    for<'a> T: TryFrom<&'a Value, Error = Error<'static>>,
+{
+    type Error = Error<'static>;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Vector(values) => values.iter().map(TryInto::try_into).collect(),
+            _ => match val.as_list() {
+                Some(values) => values.into_iter().map(TryInto::try_into).collect(),
+                None => Err(val.expected("vector or list")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build() {
+        assert_eq!(Value::list(Vec::<Value>::new()), Value::Nil);
+        assert_eq!(Value::list(vec![1, 2]), Value::cons(1, Value::cons(2, Value::Nil)));
+        assert_eq!(Value::list(vec!["a"]).to_string(), r#"("a")"#);
+        assert_eq!(Value::cons(1, 2).to_string(), "(1 . 2)");
+        assert_eq!(Value::from(vec![1.5, 2.0]).to_string(), "[1.5 2.0]");
+        assert_eq!(Value::list(vec![Value::symbol("a"), 'b'.into()]).to_string(), r"('a #\b)");
+        assert_eq!(Value::Procedure(String::from("f")).to_string(), "#<procedure f>");
+
+        assert_eq!(Value::from(1_i64 << 60), Value::Big(Big::from(1 << 60)));
+        assert_eq!(Value::from(-1), Value::Int(-1));
+    }
+
+    #[test]
+    fn convert() {
+        assert_eq!(i64::try_from(&Value::Int(42)).unwrap(), 42);
+        assert_eq!(f64::try_from(&Value::Int(2)).unwrap(), 2.0);
+        assert!(!bool::try_from(&Value::Bool(false)).unwrap());
+        assert_eq!(char::try_from(&Value::Char('x')).unwrap(), 'x');
+        assert_eq!(String::try_from(&Value::symbol("s")).unwrap(), "s");
+
+        assert_eq!(Vec::<i64>::try_from(&Value::list(vec![1, 2])).unwrap(), [1, 2]);
+        assert_eq!(Vec::<String>::try_from(&Value::from(vec!["a"])).unwrap(), ["a"]);
+
+        let e = i64::try_from(&Value::from("1")).unwrap_err();
+        assert!(matches!(e, Error::Runtime(e) if e == r#"Exception: expected fixnum, got "1""#));
+
+        let e = Vec::<i64>::try_from(&Value::cons(1, 2)).unwrap_err();
+        assert!(matches!(e, Error::Runtime(e) if e.ends_with("vector or list, got (1 . 2)")));
+        assert!(Vec::<i64>::try_from(&Value::list(vec!["a"])).is_err());
+    }
+}
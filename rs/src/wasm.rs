@@ -0,0 +1,77 @@
+//! Bindings for JavaScript, to run the compiler in a browser
+//!
+//! Built for wasm32-unknown-unknown with the `wasm` feature and without the
+//! default `native` one, this is all a playground needs to show the code
+//! generated for a program as it is typed:
+//!
+//! ```sh
+//! cargo build --lib --release --target wasm32-unknown-unknown \
+//!     --no-default-features --features wasm
+//! wasm-bindgen --target web --out-dir www \
+//!     target/wasm32-unknown-unknown/release/inc.wasm
+//! ```
+//!
+//! ```js
+//! import init, { compile_to_asm, eval } from "./www/inc.js";
+//!
+//! await init();
+//! compile_to_asm("(+ 1 2)"); // The asm, just like `inc --emit asm`
+//! eval("(+ 1 2)");           // "3"
+//! ```
+//!
+//! Nothing runs the generated code in a browser, so programs are evaluated by
+//! the [interpreter](crate::interp). Files to `load` are served from memory,
+//! see [add_file]. Errors are thrown as a JSON array of
+//! [diagnostics](Diagnostic::json).
+//!
+//! Every pass returns its errors as values, so they are thrown even though
+//! panics abort on wasm32-unknown-unknown; only a bug in the compiler traps.
+
+use crate::{
+    cli, compiler,
+    diagnostic::Diagnostic,
+    host::{self, Memory},
+    interp,
+    lang::Passes,
+};
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+
+/// Name of the program in diagnostics
+const INPUT: &str = "<input>";
+
+thread_local! {
+    /// Files added so far, see [add_file]
+    static FILES: RefCell<Memory> = RefCell::new(Memory::default());
+}
+
+/// Compile a program along with the prelude to asm
+#[wasm_bindgen]
+pub fn compile_to_asm(source: &str) -> Result<String, JsValue> {
+    cli::compile_to_asm(source).map_err(|diagnostics| throw(&diagnostics, source))
+}
+
+/// Evaluate a program with the interpreter, returning everything it printed
+/// followed by its value
+#[wasm_bindgen]
+pub fn eval(source: &str) -> Result<String, JsValue> {
+    compiler::parse(source)
+        .and_then(|prog| interp::run(prog, Passes::default()))
+        .map_err(|e| throw(&Diagnostic::all(&e, &[(INPUT, source)]), source))
+}
+
+/// Add a file for programs to `load`, replacing any at the same path
+#[wasm_bindgen]
+pub fn add_file(path: &str, source: &str) {
+    FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        files.insert(path, source);
+        host::set(files.clone());
+    })
+}
+
+/// Diagnostics as an error for JavaScript
+fn throw(diagnostics: &[Diagnostic], source: &str) -> JsValue {
+    let json: Vec<String> = diagnostics.iter().map(|d| d.json(Some(source))).collect();
+    JsValue::from_str(&format!("[{}]", json.join(",")))
+}
@@ -11,7 +11,7 @@
 //! 1. [x86 Assembly Guide 1](https://www.cs.virginia.edu/~evans/cs216/guides/x86.html)
 //! 2. [x86 Assembly Guide 2](http://flint.cs.yale.edu/cs421/papers/x86-asm/asm.html)
 //! 3. Ops like `.p2align` are not x86 instructions but GNU assembly directives.
-//! See [GNU assembler docs](https://sourceware.org/binutils/docs-2.32/as/).
+//!    See [GNU assembler docs](https://sourceware.org/binutils/docs-2.32/as/).
 //!
 //! # Syntax
 //!
@@ -79,7 +79,10 @@
 //!
 //! [cdecl]: https://en.wikipedia.org/wiki/X86_calling_conventions#cdecl
 //! [history]: https://devblogs.microsoft.com/oldnewthing/?p=41213
+use crate::{core::Target, lang::Program};
+use std::collections::VecDeque;
 use std::fmt;
+use std::io::{self, Write};
 use std::ops::{Add, AddAssign, Sub};
 
 /// Word size of the architecture
@@ -87,14 +90,113 @@ pub const WORDSIZE: i64 = 8;
 
 /// An x86 instruction
 ///
-/// This is a simple `newtype` wrapper over string, with a bunch of helpers to
-/// make the caller's API clean.
+/// Instructions are plain data with a bunch of helpers below to make the
+/// caller's API clean. The `Display` implementation renders them as Intel
+/// syntax for an external assembler while [asm](crate::asm) encodes the very
+/// same values into machine code directly.
+///
+/// Only the tiny subset of x86 the compiler actually emits is represented here;
+/// grow it as required.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Ins {
+    Add(Reference, Reference),
+    And(Reference, Reference),
+    /// Call a function by name
+    Call(String),
+    /// Call the function at the address in a register
+    CallIndirect(Register),
+    Cmp(Reference, Reference),
+    /// Sign extend RAX into RDX:RAX
+    Cqo,
+    /// Signed divide RDX:RAX by the register
+    Idiv(Register),
+    /// Signed multiply RAX by the operand into RDX:RAX
+    Imul(Reference),
+    /// Jump if below, an unsigned less than
+    Jb(String),
+    Je(String),
+    Jle(String),
+    Jmp(String),
+    Jne(String),
+    /// Jump if the last arithmetic operation overflowed
+    Jo(String),
+    /// Jump to the address in a register
+    JmpIndirect(Register),
+    Label(String),
+    /// Load the address of a label plus a constant offset, RIP relative
+    Lea(Register, String, i64),
+    Mov(Reference, Reference),
+    /// Move a byte register into a 64 bit register with zero extension
+    Movzx(Register, Register),
+
+    Or(Reference, Reference),
+    Pop(Reference),
+    Push(Reference),
+    Ret,
+    Sal(Reference, Reference),
+    Sar(Reference, Reference),
+    /// Set a byte register to 0 or 1 based on a condition code
+    Set(Condition, Register),
+    Sub(Reference, Reference),
+    /// Assembler directives; data and metadata rather than code
+    Directive(Directive),
+    /// Comments are printed along with the next instruction
+    Comment(String),
+    /// An empty line, purely cosmetic
+    Blank,
+}
+
+/// Condition codes for `SETcc` and friends
+///
+/// See [Intel x86 JUMP quick reference](http://unixwiz.net/techtips/x86-jumps.html)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// Equal
+    E,
+    /// Not equal
+    NE,
+    /// Signed less than
+    L,
+    /// Signed less than or equal
+    LE,
+    /// Signed greater than
+    G,
+    /// Signed greater than or equal
+    GE,
+}
+
+/// GNU assembler directives used by the compiler
+///
+/// See [GNU assembler docs](https://sourceware.org/binutils/docs-2.32/as/).
 #[derive(Debug, PartialEq, Clone)]
-pub struct Ins(pub String);
+pub enum Directive {
+    /// Switch to the text section; `.text` or `.section __TEXT,__text`
+    Text,
+    /// Switch to the writable data section; `.data` or `.section __DATA,__data`
+    Data,
+    /// Use intel syntax everywhere, `.intel_syntax noprefix`
+    IntelSyntax,
+    /// Export a symbol, `.globl`
+    Global(String),
+    /// Mark a symbol as a function; `.type name, @function`. ELF only.
+    Function(String),
+    /// Align the next address to `2^n` bytes, `.p2align n`
+    Align(u8),
+    /// A 64 bit constant, `.quad`
+    Quad(i64),
+    /// A NUL terminated string, `.asciz`
+    Asciz(String),
+    /// A note on what produced the code, `.ident`. ELF keeps it in `.comment`.
+    Ident(String),
+}
 
 /// ASM represents a list of instructions
-#[derive(Default, Clone)]
-pub struct ASM(pub Vec<Ins>);
+///
+/// Code is concatenated by copying the shorter of two lists into the other one
+/// from either end, so that generating code for a program nested `n` deep
+/// doesn't copy the code of every level `n` times.
+#[derive(Debug, Default, Clone)]
+pub struct ASM(pub VecDeque<Ins>);
 
 /// A Reference is a valid address to an x86 instruction.
 ///
@@ -116,19 +218,19 @@ pub struct ASM(pub Vec<Ins>);
 ///
 /// ```
 /// # use inc::x86::{self, Register::*, *};
-/// assert_eq!(Ins::from("add rax, rax"), add(RAX.into(), RAX.into()))
+/// assert_eq!("add rax, rax", add(RAX.into(), RAX.into()).to_string())
 /// ```
 ///
 /// Numeric literals work as constants.
 /// ```
 /// # use inc::x86::{self, Register::*, *};
-/// assert_eq!(Ins::from("add rdx, 7"), add(RDX.into(), 7.into()))
+/// assert_eq!("add rdx, 7", add(RDX.into(), 7.into()).to_string())
 /// ```
 ///
 /// Arithmetic on registers can be used for relative addresses.
 /// ```
 /// # use inc::x86::{self, Register::*, *};
-/// assert_eq!(Ins::from("add rax, [rsi - 16]"), add(RAX.into(), Reference::from(RSI - 16)))
+/// assert_eq!("add rax, [rsi - 16]", add(RAX.into(), Reference::from(RSI - 16)).to_string())
 /// ```
 #[derive(PartialEq, Debug, Clone)]
 pub enum Reference {
@@ -170,6 +272,8 @@ pub enum Register {
     R13,
     R14,
     R15,
+    /// Lowest byte of RAX, used with `SETcc`
+    AL,
 }
 
 /// Registers for argument passing
@@ -196,18 +300,23 @@ pub struct Relative {
 // ¶ Codegen functions
 
 /// Add `v` to register `r`
-pub fn add(r: Reference, v: Reference) -> Ins {
-    Ins(format!("add {}, {}", r, v))
+pub const fn add(r: Reference, v: Reference) -> Ins {
+    Ins::Add(r, v)
 }
 
 /// Logical and of `v` to register `r`
-pub fn and(r: Reference, v: Reference) -> Ins {
-    Ins(format!("and {}, {}", r, v))
+pub const fn and(r: Reference, v: Reference) -> Ins {
+    Ins::And(r, v)
 }
 
 /// Unconditional function call
 pub fn call(f: &str) -> Ins {
-    Ins(format!("call \"{}\"", f))
+    Ins::Call(f.to_string())
+}
+
+/// Call the function at the address in register `r`
+pub const fn call_indirect(r: Register) -> Ins {
+    Ins::CallIndirect(r)
 }
 
 /// Compares the first source operand with the second source operand and sets
@@ -218,80 +327,122 @@ pub fn call(f: &str) -> Ins {
 // sign-extended to the length of the first operand. The condition codes used by
 // the Jcc, CMOVcc, and SETcc instructions are based on the results of a CMP
 // instruction.
-pub fn cmp(a: Reference, b: Reference) -> Ins {
-    Ins(format!("cmp {}, {}", a, b))
+pub const fn cmp(a: Reference, b: Reference) -> Ins {
+    Ins::Cmp(a, b)
+}
+
+/// A comment that gets printed along with the next instruction
+pub fn comment(c: &str) -> Ins {
+    Ins::Comment(c.to_string())
+}
+
+/// Sign extend RAX into RDX:RAX, usually before a division
+pub const fn cqo() -> Ins {
+    Ins::Cqo
 }
 
 /// x86 function preamble
 pub fn enter() -> ASM {
-    Ins::from("push rbp") + Ins::from("mov rbp, rsp")
+    push(Register::RBP.into()) + mov(Register::RBP.into(), Register::RSP.into())
+}
+
+/// Signed divide RDX:RAX by register `r`, quotient in RAX & remainder in RDX
+pub const fn idiv(r: Register) -> Ins {
+    Ins::Idiv(r)
+}
+
+/// Signed multiply register RAX with value `v` into RDX:RAX
+// The destination operand of `imul` is an implied operand located in register
+// RAX. GCC throws `Error: ambiguous operand size for `imul'` without size
+// quantifier. The overflow flag is set if the product doesn't fit in RAX.
+pub const fn imul(v: Reference) -> Ins {
+    Ins::Imul(v)
+}
+
+/// Jump to the specified label if the last comparison was unsigned less than
+pub fn jb(l: &str) -> Ins {
+    Ins::Jb(l.to_string())
 }
 
 /// Jump to the specified label if last comparison resulted in equality
 pub fn je(l: &str) -> Ins {
-    Ins(format!("je {}", l))
+    Ins::Je(l.to_string())
+}
+
+/// Jump to the specified label if the last comparison was less or equal
+pub fn jle(l: &str) -> Ins {
+    Ins::Jle(l.to_string())
 }
 
 /// Unconditionally jump to the specified label
 pub fn jmp(l: &str) -> Ins {
-    Ins(format!("jmp {}", l))
+    Ins::Jmp(l.to_string())
+}
+
+/// Jump to the specified label if the last comparison was not equal
+pub fn jne(l: &str) -> Ins {
+    Ins::Jne(l.to_string())
+}
+
+/// Jump to the specified label if the last arithmetic operation overflowed
+pub fn jo(l: &str) -> Ins {
+    Ins::Jo(l.to_string())
+}
+
+/// Jump to the address in register `r`
+pub const fn jmp_indirect(r: Register) -> Ins {
+    Ins::JmpIndirect(r)
 }
 
 /// A label is a target to jump to
 pub fn label(l: &str) -> Ins {
-    Ins(format!("\"{}\":", l))
+    Ins::Label(l.to_string())
 }
 
 /// Exit a function and clean up. See `Enter`
 pub fn leave() -> ASM {
-    Ins::from("pop rbp") + Ins::from("ret")
+    pop(Register::RBP.into()) + ret()
 }
 
 /// Load effective address `of` a label into register `r` with an `offset`
 pub fn lea(r: Register, of: &str, offset: i64) -> Ins {
-    Ins(format!("lea {}, [rip + {} + {}]", r, offset, of))
+    Ins::Lea(r, of.to_string(), offset)
 }
 
 /// Load a value at stack index `si` to register `r`
 pub fn load(r: Register, si: i64) -> Ins {
-    Ins(format!("mov {}, {}", r, Register::RBP + si))
+    mov(r.into(), (Register::RBP + si).into())
 }
 
 /// Mov! At least one of the operands must be a register, moving from
 /// RAM to RAM isn't a valid op.
-pub fn mov(to: Reference, from: Reference) -> Ins {
-    match (&to, &from) {
-        (Reference::Register(_), _) => Ins(format!("mov {}, {}", to, from)),
-        _ => Ins(format!("mov qword ptr {}, {}", to, from)),
-    }
+pub const fn mov(to: Reference, from: Reference) -> Ins {
+    Ins::Mov(to, from)
 }
 
-/// Multiply register AX with value `v` and move result to register RAX
-// The destination operand is of `mul` is an implied operand located in register
-// AX. GCC throws `Error: ambiguous operand size for `mul'` without size
-// quantifier
-pub fn mul(v: Reference) -> Ins {
-    Ins(format!("mul qword ptr {}", v))
+/// Move byte register `from` into `to` with zero extension
+pub const fn movzx(to: Register, from: Register) -> Ins {
+    Ins::Movzx(to, from)
 }
 
 /// Logical or of `v` to register `r`
-pub fn or(r: Reference, v: Reference) -> Ins {
-    Ins(format!("or {}, {}", r, v))
+pub const fn or(r: Reference, v: Reference) -> Ins {
+    Ins::Or(r, v)
 }
 
 /// Pop a register `r` from stack
-pub fn pop(r: Reference) -> Ins {
-    Ins(format!("pop {}", r))
+pub const fn pop(r: Reference) -> Ins {
+    Ins::Pop(r)
 }
 
 /// Push a register `r` to stack
-pub fn push(r: Reference) -> Ins {
-    Ins(format!("push {}", r))
+pub const fn push(r: Reference) -> Ins {
+    Ins::Push(r)
 }
 
 /// Return from the calling function
-pub fn ret() -> Ins {
-    Ins::from("ret")
+pub const fn ret() -> Ins {
+    Ins::Ret
 }
 
 /// Save a reference `r` to stack at index `si`.
@@ -310,26 +461,42 @@ pub fn save(r: Reference, si: i64) -> Ins {
 // logical right (`SHR`) everywhere.
 
 /// Shift register `r` left by `v` bits; `r = r * 2^v`
-pub fn sal(r: Reference, v: Reference) -> Ins {
-    Ins(format!("sal {}, {}", r, v))
+pub const fn sal(r: Reference, v: Reference) -> Ins {
+    Ins::Sal(r, v)
 }
 
 /// Shift register `r` right by `v` bits; `r = r / 2^v`
-pub fn sar(r: Reference, v: Reference) -> Ins {
-    Ins(format!("sar {}, {}", r, v))
+pub const fn sar(r: Reference, v: Reference) -> Ins {
+    Ins::Sar(r, v)
+}
+
+/// Set byte register `r` to 1 if condition `c` holds, 0 otherwise
+pub const fn set(c: Condition, r: Register) -> Ins {
+    Ins::Set(c, r)
 }
 
 /// Sub `k` from register `r`
-pub fn sub(r: Reference, v: Reference) -> Ins {
-    Ins(format!("sub {}, {}", r, v))
+pub const fn sub(r: Reference, v: Reference) -> Ins {
+    Ins::Sub(r, v)
 }
 
 /// The base address of the heap is passed in RDI and we reserve reg R12 for it.
+///
+/// The end of the heap is passed in RSI and kept in R13 so that allocations can
+/// check for available space without calling into the runtime. R14 remembers
+/// the base of the scheme stack for the garbage collector, see [gc](crate::gc).
+///
+/// The lowest address the stack may grow to is passed in RDX and kept in R15 to
+/// detect stack overflows.
 pub fn init_heap() -> ASM {
-    Ins::from("# Store heap index to R12") + Ins::from("mov r12, rdi")
+    comment("Store heap index to R12")
+        + mov(Register::R12.into(), Register::RDI.into())
+        + mov(Register::R13.into(), Register::RSI.into())
+        + mov(Register::R14.into(), Register::RBP.into())
+        + mov(Register::R15.into(), Register::RDX.into())
 }
 
-/// Init is the target called from C.
+/// Init is the target called from the runtime, see [start](crate::start).
 #[cfg(target_os = "macos")]
 pub fn init() -> String {
     String::from("_init")
@@ -343,26 +510,32 @@ pub fn init() -> String {
 /// Emit code for a function header
 #[cfg(target_os = "macos")]
 pub fn func(name: &str) -> ASM {
-    Ins::from("") + Ins(format!(".globl \"{}\"", &name)) + label(name)
+    Ins::Blank + Ins::Directive(Directive::Global(name.to_string())) + label(name)
 }
 
 #[cfg(target_os = "linux")]
 pub fn func(name: &str) -> ASM {
-    Ins::from("")
-        + Ins(format!(".globl \"{}\"", &name))
-        + Ins(format!(".type \"{}\", @function", &name))
+    Ins::Blank
+        + Ins::Directive(Directive::Global(name.to_string()))
+        + Ins::Directive(Directive::Function(name.to_string()))
         + label(name)
 }
 
-/// Prelude at the start of generated ASM
+/// Record the compiler and the target in the object, see [Target]
+#[cfg(target_os = "linux")]
+pub fn ident(target: &Target) -> ASM {
+    Ins::Directive(Directive::Ident(format!("inc {} {}", env!("CARGO_PKG_VERSION"), target))).into()
+}
+
+/// Mach-O has no place for `.ident`, so the target is only checked
 #[cfg(target_os = "macos")]
-pub fn prelude() -> ASM {
-    Ins::from(".section __TEXT,__text") + Ins::from(".intel_syntax noprefix")
+pub fn ident(_target: &Target) -> ASM {
+    ASM::default()
 }
 
-#[cfg(target_os = "linux")]
+/// Prelude at the start of generated ASM
 pub fn prelude() -> ASM {
-    Ins::from(".text") + Ins::from(".intel_syntax noprefix")
+    Ins::Directive(Directive::Text) + Ins::Directive(Directive::IntelSyntax)
 }
 
 // ¶ Trait implementations
@@ -383,18 +556,12 @@ impl Sub<i64> for Register {
     }
 }
 
-impl From<&str> for Ins {
-    fn from(s: &str) -> Self {
-        Ins(s.to_string())
-    }
-}
-
 /// Concat Ins to get ASM; `asm = op + op`
 impl Add<Ins> for Ins {
     type Output = ASM;
 
     fn add(self, op: Ins) -> ASM {
-        ASM { 0: vec![self, op] }
+        ASM::from(vec![self, op])
     }
 }
 
@@ -402,9 +569,28 @@ impl Add<ASM> for Ins {
     type Output = ASM;
 
     fn add(self, mut asm: ASM) -> ASM {
-        let mut v = vec![self];
-        v.append(&mut asm.0);
-        ASM { 0: v }
+        asm.0.push_front(self);
+        asm
+    }
+}
+
+impl ASM {
+    /// Rename every label the code jumps to, defines or loads the address of
+    pub fn relabel(self, f: impl Fn(String) -> String) -> Self {
+        let relabel = |ins| match ins {
+            Ins::Call(l) => Ins::Call(f(l)),
+            Ins::Jb(l) => Ins::Jb(f(l)),
+            Ins::Je(l) => Ins::Je(f(l)),
+            Ins::Jle(l) => Ins::Jle(f(l)),
+            Ins::Jmp(l) => Ins::Jmp(f(l)),
+            Ins::Jne(l) => Ins::Jne(f(l)),
+            Ins::Jo(l) => Ins::Jo(f(l)),
+            Ins::Label(l) => Ins::Label(f(l)),
+            Ins::Lea(r, l, offset) => Ins::Lea(r, f(l), offset),
+            ins => ins,
+        };
+
+        ASM(self.0.into_iter().map(relabel).collect())
     }
 }
 
@@ -413,45 +599,56 @@ impl Add<ASM> for Ins {
 /// This is pretty efficient at the cost of owning the value.
 impl AddAssign<Ins> for ASM {
     fn add_assign(&mut self, op: Ins) {
-        self.0.push(op)
+        self.0.push_back(op)
     }
 }
 
 /// Syntax sugar for concatenating two ASM objects Ex: `asm += asm`
 impl AddAssign<ASM> for ASM {
     fn add_assign(&mut self, asm: ASM) {
-        self.0.extend(asm.0)
+        *self = std::mem::take(self) + asm;
     }
 }
 
 /// Add operations to ASM with overloaded `asm' = asm + op`.
-///
-/// NOTE: This is pretty inefficient due to copying of self.
 impl Add<Ins> for ASM {
     type Output = Self;
 
     fn add(mut self, op: Ins) -> Self {
-        self.0.push(op);
+        self.0.push_back(op);
         self
     }
 }
 
 /// Concat ASM; `asm + asm`
 ///
-/// NOTE: This is pretty inefficient due to copying both arguments.
+/// The shorter of the two is copied into the longer one.
 impl Add<ASM> for ASM {
     type Output = Self;
 
     fn add(mut self, mut asm: ASM) -> Self {
-        self.0.append(&mut asm.0);
-        self
+        if self.0.len() >= asm.0.len() {
+            self.0.append(&mut asm.0);
+            self
+        } else {
+            for op in self.0.into_iter().rev() {
+                asm.0.push_front(op);
+            }
+            asm
+        }
     }
 }
 
 /// Convert a single operation to ASM
 impl From<Ins> for ASM {
     fn from(op: Ins) -> Self {
-        ASM { 0: vec![op] }
+        ASM::from(vec![op])
+    }
+}
+
+impl From<Vec<Ins>> for ASM {
+    fn from(ops: Vec<Ins>) -> Self {
+        ASM(ops.into())
     }
 }
 
@@ -483,11 +680,107 @@ impl fmt::Display for Relative {
     #[allow(clippy::comparison_chain)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.offset < 0 {
-            write!(f, "{}", format!("[{} - {}]", self.register, -self.offset))
+            write!(f, "[{} - {}]", self.register, -self.offset)
         } else if self.offset > 0 {
-            write!(f, "{}", format!("[{} + {}]", self.register, self.offset))
+            write!(f, "[{} + {}]", self.register, self.offset)
         } else {
-            write!(f, "{}", format!("[{}]", self.register))
+            write!(f, "[{}]", self.register)
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(target_os = "macos")]
+            Directive::Text => write!(f, ".section __TEXT,__text"),
+            #[cfg(not(target_os = "macos"))]
+            Directive::Text => write!(f, ".text"),
+            #[cfg(target_os = "macos")]
+            Directive::Data => write!(f, ".section __DATA,__data"),
+            #[cfg(not(target_os = "macos"))]
+            Directive::Data => write!(f, ".data"),
+            Directive::IntelSyntax => write!(f, ".intel_syntax noprefix"),
+            Directive::Global(name) => write!(f, ".globl \"{}\"", name),
+            Directive::Function(name) => write!(f, ".type \"{}\", @function", name),
+            Directive::Align(n) => write!(f, ".p2align {}", n),
+            Directive::Quad(n) => write!(f, ".quad  {}", n),
+            Directive::Asciz(s) => write!(f, ".asciz \"{}\"", quoted(s)),
+            Directive::Ident(s) => write!(f, ".ident \"{}\"", quoted(s)),
+        }
+    }
+}
+
+/// A string escaped to go between the quotes of a directive
+///
+/// The assembler would end the string at a quote or a newline and read its own
+/// escapes after a backslash, so anything but printable ASCII is written as an
+/// octal escape of each byte. The bytes assembled are then exactly the UTF-8
+/// of the string, NULs included, which the length before a literal counts.
+fn quoted(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => out += &format!("\\{:03o}", byte),
+        }
+    }
+
+    out
+}
+
+/// Intel syntax for a single instruction
+///
+/// Memory operands need an explicit size when the other operand is not a
+/// register; GCC throws `Error: ambiguous operand size` otherwise.
+impl fmt::Display for Ins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ins::Add(r @ Reference::Relative(_), v @ Reference::Const(_)) => {
+                write!(f, "add qword ptr {}, {}", r, v)
+            }
+            Ins::Add(r, v) => write!(f, "add {}, {}", r, v),
+            Ins::And(r, v) => write!(f, "and {}, {}", r, v),
+            Ins::Call(l) => write!(f, "call \"{}\"", l),
+            Ins::CallIndirect(r) => write!(f, "call {}", r),
+            Ins::Cmp(a, b) => write!(f, "cmp {}, {}", a, b),
+            Ins::Cqo => write!(f, "cqo"),
+            Ins::Idiv(r) => write!(f, "idiv {}", r),
+            Ins::Imul(v) => write!(f, "imul qword ptr {}", v),
+            Ins::Jb(l) => write!(f, "jb {}", l),
+            Ins::Je(l) => write!(f, "je {}", l),
+            Ins::Jle(l) => write!(f, "jle {}", l),
+            Ins::Jmp(l) => write!(f, "jmp {}", l),
+            Ins::Jne(l) => write!(f, "jne {}", l),
+            Ins::Jo(l) => write!(f, "jo {}", l),
+            Ins::JmpIndirect(r) => write!(f, "jmp {}", r),
+            Ins::Label(l) => write!(f, "\"{}\":", l),
+            Ins::Lea(r, of, offset) => write!(f, "lea {}, [rip + {} + \"{}\"]", r, offset, of),
+            Ins::Mov(to @ Reference::Register(_), from) => write!(f, "mov {}, {}", to, from),
+            Ins::Mov(to, from) => write!(f, "mov qword ptr {}, {}", to, from),
+            Ins::Movzx(to, from) => write!(f, "movzx {}, {}", to, from),
+            Ins::Or(r, v) => write!(f, "or {}, {}", r, v),
+            Ins::Pop(r) => write!(f, "pop {}", r),
+            Ins::Push(r) => write!(f, "push {}", r),
+            Ins::Ret => write!(f, "ret"),
+            Ins::Sal(r, v) => write!(f, "sal {}, {}", r, v),
+            Ins::Sar(r, v) => write!(f, "sar {}, {}", r, v),
+            Ins::Set(c, r) => write!(f, "set{} {}", c, r),
+            Ins::Sub(r, v) => write!(f, "sub {}, {}", r, v),
+            Ins::Directive(d) => write!(f, "{}", d),
+            Ins::Comment(c) => write!(f, "# {}", c),
+            Ins::Blank => Ok(()),
         }
     }
 }
@@ -510,44 +803,196 @@ impl fmt::Display for Reference {
 /// together.
 impl fmt::Display for ASM {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut ctx = String::new();
         let mut comment: Option<&Ins> = None;
 
         for op in &self.0 {
-            // Comments must be appended to the next instruction
-            if op.0.starts_with('#') {
-                comment = Some(op);
-                continue;
+            match op {
+                // Comments must be appended to the next instruction
+                Ins::Comment(_) => comment = Some(op),
+                Ins::Label(_) | Ins::Blank => write!(f, "{}", Line(op, None))?,
+                _ => write!(f, "{}", Line(op, comment.take()))?,
             }
+        }
+
+        Ok(())
+    }
+}
+
+/// A line of asm for an instruction, along with a comment for it
+struct Line<'a>(&'a Ins, Option<&'a Ins>);
+
+/// Indent every line except labels by 4 spaces
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line(op @ Ins::Label(_), _) => writeln!(f, "{}", op),
+            Line(Ins::Blank, _) => writeln!(f),
+            Line(op, Some(comment)) => writeln!(f, "    {:32}{}", op.to_string(), comment),
+            Line(op, None) => writeln!(f, "    {}", op),
+        }
+    }
+}
+
+/// Where generated code goes, see [Writer]
+pub trait Output: Program {
+    fn emit(&mut self, asm: ASM);
+}
+
+/// All of the code is kept, to be shown or written out later
+impl Output for ASM {
+    fn emit(&mut self, asm: ASM) {
+        *self += asm
+    }
+}
+
+/// Writes asm out as it is generated, rather than all of it at once
+///
+/// Lines of the text section go through a buffer straight to the output, like
+/// a file or the stdin of the assembler. Lines of the data section are kept
+/// aside and written after all the text, which is fine since they are only
+/// referred to by labels. Errors are kept till [finish](Writer::finish), so
+/// that code can be emitted in pieces without checking each one.
+pub struct Writer<W: Write> {
+    text: io::BufWriter<W>,
+    data: Vec<u8>,
+    in_data: bool,
+    comment: Option<Ins>,
+    written: usize,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Self {
+        Writer {
+            text: io::BufWriter::new(out),
+            data: vec![],
+            in_data: false,
+            comment: None,
+            written: 0,
+            error: None,
+        }
+    }
+
+    /// Write the data section after the text and return the output, or the
+    /// first error writing to it
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.data.is_empty() {
+            let data = std::mem::take(&mut self.data);
+            self.line(&Ins::Directive(Directive::Data), None);
+            self.result(|text| text.write_all(&data));
+        }
 
-            // Indent every line except labels by 4 spaces
-            if op.0.ends_with(':') {
-                ctx.push_str(&format!("{}\n", &op.0));
-            } else {
-                match comment {
-                    Some(s) => {
-                        ctx.push_str(&format!("    {:32}{}\n", &op.0, s.0));
-                        comment = None
-                    }
-                    None => ctx.push_str(&format!("    {}\n", &op.0)),
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        self.text.flush()?;
+        self.text.into_inner().map_err(|e| io::Error::new(e.error().kind(), e.to_string()))
+    }
+
+    fn line(&mut self, op: &Ins, comment: Option<&Ins>) {
+        if self.in_data {
+            self.data.write_fmt(format_args!("{}", Line(op, comment))).ok();
+        } else {
+            self.result(|text| write!(text, "{}", Line(op, comment)));
+        }
+    }
+
+    fn result(&mut self, f: impl FnOnce(&mut io::BufWriter<W>) -> io::Result<()>) {
+        if self.error.is_none() {
+            self.error = f(&mut self.text).err();
+        }
+    }
+}
+
+impl<W: Write> Output for Writer<W> {
+    fn emit(&mut self, asm: ASM) {
+        self.written += asm.0.len();
+
+        for op in asm.0 {
+            match op {
+                Ins::Directive(Directive::Data) => self.in_data = true,
+                Ins::Directive(Directive::Text) if self.in_data => self.in_data = false,
+                Ins::Comment(_) => self.comment = Some(op),
+                Ins::Label(_) | Ins::Blank => self.line(&op, None),
+                _ => {
+                    let comment = self.comment.take();
+                    self.line(&op, comment.as_ref())
                 }
             }
         }
-        write!(f, "{}", ctx)
+    }
+}
+
+/// The code is gone once it is written, only its size is known
+impl<W: Write> Program for Writer<W> {
+    fn show(&self) -> String {
+        String::new()
+    }
+
+    fn nodes(&self) -> usize {
+        self.written
+    }
+}
+
+impl Program for ASM {
+    fn show(&self) -> String {
+        self.to_string()
+    }
+
+    fn nodes(&self) -> usize {
+        self.0.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Ins, Reference, Register::*};
+    use super::{Condition, Reference, Register::*};
     use pretty_assertions::assert_eq;
 
     #[test]
     fn mov() {
-        assert_eq!(Ins::from("mov rax, 16"), super::mov(RAX.into(), 16.into()));
+        assert_eq!("mov rax, 16", super::mov(RAX.into(), 16.into()).to_string());
         assert_eq!(
-            Ins::from("mov qword ptr [rbp + 8], 16"),
-            super::mov(Reference::from(RBP + 8), 16.into())
+            "mov qword ptr [rbp + 8], 16",
+            super::mov(Reference::from(RBP + 8), 16.into()).to_string()
         )
     }
+
+    #[test]
+    fn bytes() {
+        assert_eq!("sete al", super::set(Condition::E, AL).to_string());
+        assert_eq!("setge al", super::set(Condition::GE, AL).to_string());
+        assert_eq!("movzx rax, al", super::movzx(RAX, AL).to_string());
+    }
+
+    #[test]
+    fn strings() {
+        let asciz = |s: &str| super::Directive::Asciz(s.into()).to_string();
+
+        assert_eq!(asciz("hello world"), r#".asciz "hello world""#);
+        assert_eq!(asciz("a \"b\" \\"), r#".asciz "a \"b\" \\""#);
+        assert_eq!(asciz("x\ny\0"), r#".asciz "x\012y\000""#);
+        assert_eq!(asciz("λ"), r#".asciz "\316\273""#);
+    }
+
+    #[test]
+    fn writer() {
+        use super::{Directive::*, Ins, Output, Program, Writer};
+
+        let code = super::prelude() + super::label("f") + super::comment("done") + Ins::Ret;
+        let data = Ins::Directive(Data) + Ins::Directive(Quad(42)) + Ins::Directive(Text);
+
+        let mut writer = Writer::new(vec![]);
+        writer.emit(code.clone());
+        writer.emit(data);
+        writer.emit(code.clone());
+        assert_eq!(writer.nodes(), 13);
+
+        // The text is written as it is shown, with the data after all of it
+        let out = String::from_utf8(writer.finish().unwrap()).unwrap();
+        let text = code.clone() + code + Ins::Directive(Data) + Ins::Directive(Quad(42));
+        assert_eq!(out, text.to_string());
+        assert!(out.contains("    ret                             # done\n"));
+    }
 }
@@ -0,0 +1,165 @@
+// Differential tests for the compiler
+//
+// Every `.scm` fixture in `tests/differential` is run by several engines, which
+// must all agree on the outcome: what the program printed along with its value,
+// or the class of error it failed with. Error messages are free to differ.
+//
+// The engines are the interpreter and the full compiler with each set of
+// optional passes, built into an executable and run. A pass that changes what a
+// program means shows up here even when its own unit tests pass, and nothing is
+// checked in for a fixture; drop a program in the directory and it is tested.
+// Programs the interpreter doesn't support are compared among the rest.
+//
+//     $ cargo test --test differential -- [FILTER]
+//
+// This test uses a custom harness (see `Cargo.toml`) like the golden tests.
+extern crate inc;
+
+use inc::{
+    cli::{self, Action},
+    core::{Config, Error},
+    lang::Passes,
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, exit},
+    thread,
+};
+
+const FIXTURES: &str = "tests/differential";
+
+/// What running a program came to, as far as the engines must agree
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    /// Everything printed by the program, followed by its value
+    Output(String),
+    CompileError,
+    RuntimeError,
+    /// The engine can't run the program at all
+    Unsupported,
+}
+
+/// Engines by name; the first one that runs a program is the reference the
+/// others are compared to
+const ENGINES: [&str; 4] = ["interp", "O2", "O1", "O0"];
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // Any free argument is a test name filter, just like the default harness
+    let filters: Vec<&String> = args.iter().filter(|a| !a.starts_with('-')).collect();
+
+    let fixtures: Vec<PathBuf> = fixtures()
+        .into_iter()
+        .filter(|fixture| {
+            filters.is_empty() || filters.iter().any(|f| name(fixture).contains(f.as_str()))
+        })
+        .collect();
+
+    let dir = env::temp_dir().join(format!("inc-differential-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    println!("\nrunning {} tests", fixtures.len());
+
+    // Fixtures are independent and spend most of their time in gcc
+    let results: Vec<(String, Vec<(&str, Outcome)>)> = thread::scope(|scope| {
+        let handles: Vec<_> = fixtures
+            .iter()
+            .map(|fixture| {
+                let dir = &dir;
+                scope.spawn(move || (name(fixture), run(fixture, dir)))
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    fs::remove_dir_all(&dir).unwrap_or_default();
+
+    let mut failed = vec![];
+
+    for (name, outcomes) in &results {
+        let supported: Vec<&Outcome> = outcomes
+            .iter()
+            .map(|(_, outcome)| outcome)
+            .filter(|outcome| **outcome != Outcome::Unsupported)
+            .collect();
+
+        if supported.iter().all(|outcome| *outcome == supported[0]) {
+            println!("test differential::{} ... ok", name);
+        } else {
+            println!("test differential::{} ... FAILED", name);
+            failed.push((name, outcomes));
+        }
+    }
+
+    if !failed.is_empty() {
+        println!("\nfailures:\n");
+
+        for (name, outcomes) in &failed {
+            println!("---- differential::{} ----", name);
+
+            for (engine, outcome) in outcomes.iter() {
+                println!("{:>6}: {:?}", engine, outcome);
+            }
+
+            println!();
+        }
+
+        println!("test result: FAILED. {} failed\n", failed.len());
+        exit(1)
+    }
+
+    println!("\ntest result: ok\n");
+}
+
+/// All fixtures in a stable order
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(FIXTURES);
+
+    let mut all: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Failed to read fixtures from {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "scm"))
+        .collect();
+
+    all.sort();
+    all
+}
+
+fn name(fixture: &Path) -> String {
+    fixture.file_stem().unwrap().to_string_lossy().to_string()
+}
+
+/// Run a fixture with every engine
+fn run(fixture: &Path, dir: &Path) -> Vec<(&'static str, Outcome)> {
+    let program = fs::read_to_string(fixture).unwrap();
+
+    ENGINES.iter().map(|engine| (*engine, build(fixture, &program, dir, engine))).collect()
+}
+
+/// Interpret a program, or build an executable with the passes of an engine and
+/// run it
+fn build(fixture: &Path, program: &str, dir: &Path, engine: &str) -> Outcome {
+    let output = dir.join(format!("{}-{}", name(fixture), engine));
+
+    let (action, passes) = match engine {
+        "interp" => (Action::Interp, Passes::default()),
+        _ => (Action::Run, Passes::level(&engine[1..]).unwrap()),
+    };
+
+    let config = Config {
+        program: program.to_string(),
+        output: output.to_string_lossy().to_string(),
+        passes,
+        ..Default::default()
+    };
+
+    match cli::run(&config, action) {
+        Ok(output) => Outcome::Output(output.unwrap_or_default()),
+        Err(Error::Runtime(_) | Error::Exit { .. }) => Outcome::RuntimeError,
+        Err(Error::Internal { .. }) if engine == "interp" => Outcome::Unsupported,
+        Err(_) => Outcome::CompileError,
+    }
+}
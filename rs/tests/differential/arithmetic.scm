@@ -0,0 +1,10 @@
+(define (fact n) (if (zero? n) 1 (* n (fact (- n 1)))))
+(display (fact 10))
+(newline)
+(display (* 1152921504606846975 4))
+(newline)
+(display (- 0 (/ 17 5)))
+(newline)
+(display (% 17 5))
+(newline)
+(* 1.5 2)
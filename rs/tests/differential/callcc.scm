@@ -0,0 +1,2 @@
+(let ((f (lambda (k) (+ 1 (k 41)))))
+  (+ 1 (call/cc f)))
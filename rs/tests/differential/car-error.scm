@@ -0,0 +1,3 @@
+(display "before")
+(newline)
+(car 1)
@@ -0,0 +1,4 @@
+(let ((e (lambda (x) (if (zero? x) #t (o (dec x)))))
+      (o (lambda (x) (if (zero? x) #f (e (dec x)))))
+      (f (lambda (x y) (cons y x))))
+  (f (e 25) (o 25)))
@@ -0,0 +1,3 @@
+(display (guard (e (#t (+ e 1))) (raise 41)))
+(newline)
+(guard (e ((string? e) e) (#t 'other)) (raise 'boom))
@@ -0,0 +1,9 @@
+(define (build n acc) (if (zero? n) acc (build (- n 1) (cons n acc))))
+(display (length (build 10 ())))
+(newline)
+(display (reverse (build 5 ())))
+(newline)
+(define (square x) (* x x))
+(display (map square (list 1 2 3)))
+(newline)
+(assq 'b (cons (cons 'a 1) (cons (cons 'b 2) ())))
@@ -0,0 +1,6 @@
+(define (even? n) (if (zero? n) #t (odd? (- n 1))))
+(define (odd? n) (if (zero? n) #f (even? (- n 1))))
+(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+(display (even? 100))
+(newline)
+(fib 20)
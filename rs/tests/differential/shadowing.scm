@@ -0,0 +1,4 @@
+(let ((x 1))
+  (let ((x (+ x 1)) (y x))
+    (let ((x (* x 10)))
+      (cons x y))))
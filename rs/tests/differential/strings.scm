@@ -0,0 +1,7 @@
+(let ((s (make-string 3 #\a)))
+  (string-set! s 1 #\b)
+  (display s)
+  (newline)
+  (write (string-append "foo" "bar"))
+  (newline)
+  (string-length (string-append s s)))
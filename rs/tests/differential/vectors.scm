@@ -0,0 +1,4 @@
+(let ((v (make-vector 5 0)))
+  (vector-set! v 0 'x)
+  (vector-set! v 4 "y")
+  v)
@@ -0,0 +1,10 @@
+(display (dynamic-wind
+           (lambda () (display "in "))
+           (lambda () 42)
+           (lambda () (display "out "))))
+(newline)
+(guard (e (#t e))
+  (dynamic-wind
+    (lambda () (display "in "))
+    (lambda () (raise 'x))
+    (lambda () (display "out "))))
@@ -0,0 +1,213 @@
+// Golden file tests for generated assembly
+//
+// Every `.scm` fixture in `tests/golden` is compiled to assembly and compared
+// against the checked in `.s` snapshot next to it. Codegen changes show up here
+// as a readable diff long before they turn into a mysterious runtime failure.
+//
+// The generated code is normalized before comparison so that the snapshots are
+// stable across unrelated changes; labels from `State::gen_label` are numbered
+// in the order they are first seen and insignificant whitespace is dropped.
+// Constants aren't folded, so that the fixtures exercise the code generated
+// for the primitives they apply to them.
+//
+// After an intentional codegen change, update the snapshots with
+//
+//     $ cargo test --test golden -- --bless
+//
+// or by setting `INC_BLESS=1` in the environment and review the diff.
+//
+// This test uses a custom harness (see `Cargo.toml`) so that it can accept the
+// extra flag.
+extern crate inc;
+
+use inc::{compiler::emit, lang::Passes, parser::parse};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+const FIXTURES: &str = "tests/golden";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let bless = args.iter().any(|a| a == "--bless") || env::var("INC_BLESS").is_ok();
+
+    // Any free argument is a test name filter, just like the default harness
+    let filters: Vec<&String> = args.iter().filter(|a| !a.starts_with('-')).collect();
+
+    // Snapshots are generated on Linux; directives differ on other platforms.
+    if cfg!(not(target_os = "linux")) {
+        println!("\nrunning 0 tests; golden snapshots are only checked on linux\n");
+        return;
+    }
+
+    let mut failed = vec![];
+    let fixtures = fixtures();
+
+    println!("\nrunning {} tests", fixtures.len());
+
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_string_lossy().to_string();
+
+        if !filters.is_empty() && !filters.iter().any(|f| name.contains(f.as_str())) {
+            continue;
+        }
+
+        let snapshot = fixture.with_extension("s");
+        let actual = compile(fixture);
+
+        if bless {
+            fs::write(&snapshot, &actual).unwrap();
+            println!("test golden::{} ... blessed", name);
+            continue;
+        }
+
+        match fs::read_to_string(&snapshot) {
+            Ok(expected) if expected == actual => println!("test golden::{} ... ok", name),
+            Ok(expected) => {
+                println!("test golden::{} ... FAILED", name);
+                failed.push((name, diff(&expected, &actual)));
+            }
+            Err(_) => {
+                println!("test golden::{} ... FAILED", name);
+                failed.push((name, format!("missing snapshot {}", snapshot.display())));
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        println!("\nfailures:\n");
+
+        for (name, diff) in &failed {
+            println!("---- golden::{} ----\n{}", name, diff);
+        }
+
+        println!("Run `cargo test --test golden -- --bless` to accept the new output\n");
+        println!("test result: FAILED. {} failed\n", failed.len());
+        exit(1)
+    }
+
+    println!("\ntest result: ok\n");
+}
+
+/// All fixtures in a stable order
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(FIXTURES);
+
+    let mut all: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Failed to read fixtures from {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "scm"))
+        .collect();
+
+    all.sort();
+    all
+}
+
+/// Compile a fixture into normalized assembly
+fn compile(path: &Path) -> String {
+    let source = fs::read_to_string(path).unwrap();
+    let prog = parse(&source).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    let passes = Passes { fold: false, ..Passes::default() };
+    normalize(&emit::compile_with(prog, passes).unwrap().to_string())
+}
+
+/// Normalize generated assembly for stable comparisons
+///
+/// Blank lines and repeated spaces are removed, indentation is made uniform and
+/// generated labels like `exit_42` are renumbered sequentially in order of
+/// appearance.
+fn normalize(asm: &str) -> String {
+    let lines: Vec<String> = asm
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let indent = if line.starts_with(char::is_whitespace) { "    " } else { "" };
+            format!("{}{}", indent, line.split_whitespace().collect::<Vec<_>>().join(" "))
+        })
+        .collect();
+
+    // Labels defined in this file that look generated
+    let generated: Vec<&str> = lines
+        .iter()
+        .filter_map(|line| line.strip_suffix(':'))
+        .map(|label| label.trim().trim_matches('"'))
+        .filter(|label| is_generated(label))
+        .collect();
+
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut counter = 0;
+    let mut out = String::new();
+
+    for line in &lines {
+        let mut word = String::new();
+
+        for c in line.chars().chain(std::iter::once('\n')) {
+            if c.is_alphanumeric() || c == '_' {
+                word.push(c);
+                continue;
+            }
+
+            if generated.contains(&word.as_str()) {
+                let fresh = names.entry(word.clone()).or_insert_with(|| {
+                    counter += 1;
+                    let (prefix, _) = split(&word);
+                    format!("{}_{}", prefix, counter)
+                });
+                out.push_str(fresh);
+            } else {
+                out.push_str(&word);
+            }
+
+            word.clear();
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+/// Labels from `gen_label` are a prefix, an underscore and a counter. Data
+/// labels for strings and symbols are deterministic and left alone.
+fn is_generated(label: &str) -> bool {
+    let (prefix, n) = split(label);
+    !prefix.is_empty() && !prefix.starts_with("inc_") && n.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Split a label into prefix and counter at the last underscore
+fn split(label: &str) -> (&str, &str) {
+    match label.rfind('_') {
+        Some(i) if i + 1 < label.len() => (&label[..i], &label[i + 1..]),
+        _ => ("", label),
+    }
+}
+
+/// A minimal line diff, enough to spot what changed
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // Skip the common prefix and suffix and show the rest
+    let prefix = expected.iter().zip(actual.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut out = format!("@@ line {} @@\n", prefix + 1);
+
+    for line in &expected[prefix..expected.len() - suffix] {
+        out.push_str(&format!("-{}\n", line));
+    }
+
+    for line in &actual[prefix..actual.len() - suffix] {
+        out.push_str(&format!("+{}\n", line));
+    }
+
+    out
+}
@@ -0,0 +1,690 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov rax, 48
+    mov qword ptr [rbp - 8], rax
+    mov rax, 56
+    mov qword ptr [rbp - 16], rax
+    mov r11, [rbp - 8]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_1
+    mov rax, [rbp - 8]
+    sar rax, 3
+    imul qword ptr [rbp - 16]
+    jo slow_1
+    jmp done_2
+"slow_1":
+    mov rax, [rbp - 8]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_3
+    cmp r11, 5
+    jne error_4
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_3
+"error_4":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_5":
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_5
+"number_3":
+    mov rax, [rbp - 16]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_6
+    cmp r11, 5
+    jne error_7
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_6
+"error_7":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_8":
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_8
+"number_6":
+    mov rdi, 1
+    mov rsi, [rbp - 8]
+    mov rdx, [rbp - 16]
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 24], rax
+"retry_9":
+    mov r11, r12
+    add r11, [rbp - 24]
+    cmp r11, r13
+    jle alloc_10
+    mov rdi, [rbp - 24]
+    mov rsi, rbp
+    add rsi, -24
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_9
+"alloc_10":
+    mov rdi, 1
+    mov rsi, [rbp - 8]
+    mov rdx, [rbp - 16]
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_2":
+    mov qword ptr [rbp - 8], rax
+    mov rax, 80
+    mov qword ptr [rbp - 16], rax
+    mov rax, 32
+    mov qword ptr [rbp - 24], rax
+    mov r11, [rbp - 16]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_11
+    cmp rax, 0
+    je zero_12
+    mov rax, [rbp - 16]
+    sar rax, 3
+    mov rcx, [rbp - 24]
+    sar rcx, 3
+    cqo
+    idiv rcx
+    mov rax, rdx
+    mov r11, rax
+    sal r11, 3
+    sar r11, 3
+    cmp r11, rax
+    jne slow_11
+    sal rax, 3
+    jmp done_13
+"slow_11":
+    mov rax, [rbp - 16]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_14
+    cmp r11, 5
+    jne error_15
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_14
+"error_15":
+    mov rdi, 0
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_16":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_16
+"number_14":
+    mov rax, [rbp - 24]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_17
+    cmp r11, 5
+    jne error_18
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_17
+"error_18":
+    mov rdi, 0
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_19":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_19
+"number_17":
+    cmp rax, 0
+    je zero_12
+    mov rdi, 0
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+"retry_20":
+    mov r11, r12
+    add r11, [rbp - 32]
+    cmp r11, r13
+    jle alloc_21
+    mov rdi, [rbp - 32]
+    mov rsi, rbp
+    add rsi, -32
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_20
+"alloc_21":
+    mov rdi, 0
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+    jmp done_13
+"zero_12":
+    mov rdi, 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_division_error"
+    mov rsp, rbp
+"raise_22":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_22
+"done_13":
+    mov qword ptr [rbp - 16], rax
+    mov rax, 16
+    mov qword ptr [rbp - 24], rax
+    mov r11, [rbp - 16]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_23
+    cmp rax, 0
+    je zero_24
+    mov rax, [rbp - 16]
+    sar rax, 3
+    mov rcx, [rbp - 24]
+    sar rcx, 3
+    cqo
+    idiv rcx
+    mov r11, rax
+    sal r11, 3
+    sar r11, 3
+    cmp r11, rax
+    jne slow_23
+    sal rax, 3
+    jmp done_25
+"slow_23":
+    mov rax, [rbp - 16]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_26
+    cmp r11, 5
+    jne error_27
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_26
+"error_27":
+    mov rdi, 4
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_28":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_28
+"number_26":
+    mov rax, [rbp - 24]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_29
+    cmp r11, 5
+    jne error_30
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_29
+"error_30":
+    mov rdi, 4
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_31":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_31
+"number_29":
+    cmp rax, 0
+    je zero_24
+    mov rdi, 4
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+"retry_32":
+    mov r11, r12
+    add r11, [rbp - 32]
+    cmp r11, r13
+    jle alloc_33
+    mov rdi, [rbp - 32]
+    mov rsi, rbp
+    add rsi, -32
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_32
+"alloc_33":
+    mov rdi, 4
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+    jmp done_25
+"zero_24":
+    mov rdi, 4
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_division_error"
+    mov rsp, rbp
+"raise_34":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_34
+"done_25":
+    mov qword ptr [rbp - 16], rax
+    mov rax, [rbp - 8]
+    mov qword ptr [rbp - 24], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [rbp - 32], rax
+    mov r11, [rbp - 24]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_35
+    mov rax, [rbp - 24]
+    sub rax, [rbp - 32]
+    jo slow_35
+    jmp done_36
+"slow_35":
+    mov rax, [rbp - 24]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_37
+    cmp r11, 5
+    jne error_38
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_37
+"error_38":
+    mov rdi, 3
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_39":
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_39
+"number_37":
+    mov rax, [rbp - 32]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_40
+    cmp r11, 5
+    jne error_41
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_40
+"error_41":
+    mov rdi, 3
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_42":
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_42
+"number_40":
+    mov rdi, 3
+    mov rsi, [rbp - 24]
+    mov rdx, [rbp - 32]
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 40], rax
+"retry_43":
+    mov r11, r12
+    add r11, [rbp - 40]
+    cmp r11, r13
+    jle alloc_44
+    mov rdi, [rbp - 40]
+    mov rsi, rbp
+    add rsi, -40
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 48], 0
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_43
+"alloc_44":
+    mov rdi, 3
+    mov rsi, [rbp - 24]
+    mov rdx, [rbp - 32]
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_36":
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
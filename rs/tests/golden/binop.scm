@@ -0,0 +1 @@
+(- (* 6 7) (/ (% 10 4) 2))
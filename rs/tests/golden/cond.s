@@ -0,0 +1,177 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov rax, 8
+    mov qword ptr [rbp - 8], rax
+    mov rax, 16
+    mov qword ptr [rbp - 16], rax
+    mov r11, [rbp - 8]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_1
+    cmp [rbp - 8], rax
+    setl al
+    movzx rax, al
+    sal al, 3
+    or al, 1
+    jmp done_2
+"slow_1":
+    mov rax, [rbp - 8]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_3
+    cmp r11, 5
+    jne error_4
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_3
+"error_4":
+    mov rdi, 5
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_5":
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_5
+"number_3":
+    mov rax, [rbp - 16]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_6
+    cmp r11, 5
+    jne error_7
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_6
+"error_7":
+    mov rdi, 5
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_8":
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_8
+"number_6":
+    mov rdi, 5
+    mov rsi, [rbp - 8]
+    mov rdx, [rbp - 16]
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_compare"
+    mov rsp, rbp
+"done_2":
+    cmp rax, 1
+    je else_9
+    mov rax, 1
+    cmp rax, 1
+    je else_10
+    mov rax, 24
+    jmp exit_11
+"else_10":
+    mov rax, 32
+"exit_11":
+    jmp exit_12
+"else_9":
+    mov rax, 40
+"exit_12":
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
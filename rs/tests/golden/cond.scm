@@ -0,0 +1 @@
+(if (< 1 2) (if #f 3 4) 5)
@@ -0,0 +1,236 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 16], 0
+    mov rax, 168
+    mov qword ptr [rbp - 24], rax
+    call "scm:twice"
+    pop rbp
+    ret
+    .globl "scm:twice"
+    .type "scm:twice", @function
+"scm:twice":
+    push rbp
+    mov rbp, rsp
+    cmp r15, rsp
+    jle stack_1
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_stack_overflow"
+    mov rsp, rbp
+"raise_2":
+    mov qword ptr [rbp - 16], 0
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_2
+"stack_1":
+    mov rax, [rbp - 8]
+    mov qword ptr [rbp - 16], rax
+    mov rax, 16
+    mov qword ptr [rbp - 24], rax
+    mov r11, [rbp - 16]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_3
+    mov rax, [rbp - 16]
+    sar rax, 3
+    imul qword ptr [rbp - 24]
+    jo slow_3
+    jmp done_4
+"slow_3":
+    mov rax, [rbp - 16]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_5
+    cmp r11, 5
+    jne error_6
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_5
+"error_6":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_7":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_7
+"number_5":
+    mov rax, [rbp - 24]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_8
+    cmp r11, 5
+    jne error_9
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_8
+"error_9":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_10":
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_10
+"number_8":
+    mov rdi, 1
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+"retry_11":
+    mov r11, r12
+    add r11, [rbp - 32]
+    cmp r11, r13
+    jle alloc_12
+    mov rdi, [rbp - 32]
+    mov rsi, rbp
+    add rsi, -32
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_11
+"alloc_12":
+    mov rdi, 1
+    mov rsi, [rbp - 16]
+    mov rdx, [rbp - 24]
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_4":
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
@@ -0,0 +1,2 @@
+(define (twice x) (* x 2))
+(twice 21)
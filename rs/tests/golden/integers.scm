@@ -0,0 +1 @@
+(inc (dec 42))
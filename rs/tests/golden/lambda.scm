@@ -0,0 +1,3 @@
+(let ((f (lambda (x) (g x x)))
+      (g (lambda (x y) (+ x y))))
+  (f 12))
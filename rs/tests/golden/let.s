@@ -0,0 +1,341 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov qword ptr [rbp - 8], 8
+    mov qword ptr [rbp - 16], 16
+    mov rax, [rbp - 8]
+    mov qword ptr [rbp - 24], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [rbp - 32], rax
+    mov r11, [rbp - 24]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_1
+    mov rax, [rbp - 24]
+    add rax, [rbp - 32]
+    jo slow_1
+    jmp done_2
+"slow_1":
+    mov rax, [rbp - 24]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_3
+    cmp r11, 5
+    jne error_4
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_3
+"error_4":
+    mov rdi, 2
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_5":
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_5
+"number_3":
+    mov rax, [rbp - 32]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_6
+    cmp r11, 5
+    jne error_7
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_6
+"error_7":
+    mov rdi, 2
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_8":
+    mov qword ptr [rbp - 40], 0
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -32
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_8
+"number_6":
+    mov rdi, 2
+    mov rsi, [rbp - 24]
+    mov rdx, [rbp - 32]
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 40], rax
+"retry_9":
+    mov r11, r12
+    add r11, [rbp - 40]
+    cmp r11, r13
+    jle alloc_10
+    mov rdi, [rbp - 40]
+    mov rsi, rbp
+    add rsi, -40
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 48], 0
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_9
+"alloc_10":
+    mov rdi, 2
+    mov rsi, [rbp - 24]
+    mov rdx, [rbp - 32]
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_2":
+    mov qword ptr [rbp - 24], rax
+    mov rax, [rbp - 24]
+    mov qword ptr [rbp - 32], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [rbp - 40], rax
+    mov r11, [rbp - 32]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_11
+    mov rax, [rbp - 32]
+    sar rax, 3
+    imul qword ptr [rbp - 40]
+    jo slow_11
+    jmp done_12
+"slow_11":
+    mov rax, [rbp - 32]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_13
+    cmp r11, 5
+    jne error_14
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_13
+"error_14":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_15":
+    mov qword ptr [rbp - 48], 0
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_15
+"number_13":
+    mov rax, [rbp - 40]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_16
+    cmp r11, 5
+    jne error_17
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_16
+"error_17":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_18":
+    mov qword ptr [rbp - 48], 0
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -40
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_18
+"number_16":
+    mov rdi, 1
+    mov rsi, [rbp - 32]
+    mov rdx, [rbp - 40]
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 48], rax
+"retry_19":
+    mov r11, r12
+    add r11, [rbp - 48]
+    cmp r11, r13
+    jle alloc_20
+    mov rdi, [rbp - 48]
+    mov rsi, rbp
+    add rsi, -48
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 56], 0
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_19
+"alloc_20":
+    mov rdi, 1
+    mov rsi, [rbp - 32]
+    mov rdx, [rbp - 40]
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_12":
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
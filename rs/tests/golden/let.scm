@@ -0,0 +1,3 @@
+(let ((x 1) (y 2))
+  (let ((x (+ x y)))
+    (* x y)))
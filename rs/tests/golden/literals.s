@@ -0,0 +1,96 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    lea rdi, [rip + 6 + "inc_sym_0"]
+    mov rsi, 1
+    mov r11, rbp
+    add r11, 0
+    mov rsp, r11
+    and rsp, -16
+    call "rt_register_symbols"
+    mov rsp, rbp
+    lea rax, [rip + 6 + "inc_sym_0"]
+    and rax, 7
+    cmp rax, 6
+    sete al
+    movzx rax, al
+    sal al, 3
+    or al, 1
+    cmp rax, 1
+    je else_1
+    lea rax, [rip + 5 + "inc_str_0"]
+    jmp exit_2
+"else_1":
+    lea rax, [rip + 5 + "inc_str_1"]
+"exit_2":
+    pop rbp
+    ret
+    .p2align 3
+"inc_str_0":
+    .quad 4611686018427387909
+    .asciz "hello"
+    .p2align 3
+"inc_str_1":
+    .quad 4611686018427387909
+    .asciz "world"
+    .p2align 3
+"inc_sym_0":
+    .quad 0
+    .quad 3
+    .asciz "yes"
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
@@ -0,0 +1 @@
+(if (symbol? 'yes) "hello" "world")
@@ -0,0 +1,200 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov rax, 8 # (cons 1 (cons 2 ()))
+    mov qword ptr [rbp - 8], rax
+    mov rax, 16 # (cons 2 ())
+    mov qword ptr [rbp - 16], rax
+    mov rax, 4
+    mov qword ptr [rbp - 24], rax
+"retry_1":
+    mov r11, r12
+    add r11, 16
+    cmp r11, r13
+    jle alloc_2
+    mov rdi, 16
+    mov rsi, rbp
+    add rsi, -24
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 32], 0
+    mov r11, rbp
+    add r11, -24
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_1
+"alloc_2":
+    mov rax, [rbp - 16]
+    mov qword ptr [r12], rax
+    mov rax, [rbp - 24]
+    mov qword ptr [r12 + 8], rax
+    mov rax, r12
+    add r12, 16
+    or rax, 3
+    mov qword ptr [rbp - 16], rax
+"retry_3":
+    mov r11, r12
+    add r11, 16
+    cmp r11, r13
+    jle alloc_4
+    mov rdi, 16
+    mov rsi, rbp
+    add rsi, -16
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_3
+"alloc_4":
+    mov rax, [rbp - 8]
+    mov qword ptr [r12], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [r12 + 8], rax
+    mov rax, r12
+    add r12, 16
+    or rax, 3
+    mov qword ptr [rbp - 8], rax
+    mov rax, [rbp - 8]
+    mov r11, rax # (cdr ...)
+    and r11, 7
+    cmp r11, 3
+    je check_5
+    mov rdi, 12
+    mov rsi, 3
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_6":
+    mov qword ptr [rbp - 16], 0
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_6
+"check_5":
+    mov rax, [rax + 5]
+    mov r11, rax # (car ..)
+    and r11, 7
+    cmp r11, 3
+    je check_7
+    mov rdi, 11
+    mov rsi, 3
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_8":
+    mov qword ptr [rbp - 16], 0
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -8
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_8
+"check_7":
+    mov rax, [rax - 3]
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
@@ -0,0 +1,2 @@
+(let ((p (cons 1 (cons 2 ()))))
+  (car (cdr p)))
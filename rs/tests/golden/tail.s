@@ -0,0 +1,395 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 16], 0
+    mov rax, 80
+    mov qword ptr [rbp - 24], rax
+    mov rax, 8
+    mov qword ptr [rbp - 32], rax
+    call "scm:{let 0} factorial"
+    pop rbp
+    ret
+    .globl "scm:{let 0} factorial"
+    .type "scm:{let 0} factorial", @function
+"scm:{let 0} factorial":
+    push rbp
+    mov rbp, rsp
+    cmp r15, rsp
+    jle stack_1
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_stack_overflow"
+    mov rsp, rbp
+"raise_2":
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_2
+"stack_1":
+    mov rax, [rbp - 8]
+    cmp rax, 0
+    sete al
+    movzx rax, al
+    sal al, 3
+    or al, 1
+    cmp rax, 1
+    je else_3
+    mov rax, [rbp - 16]
+    jmp exit_4
+"else_3":
+    mov qword ptr [rbp - 24], 0
+    mov qword ptr [rbp - 32], 0
+    mov rax, [rbp - 8]
+    mov qword ptr [rbp - 40], rax
+    mov rax, 8
+    mov qword ptr [rbp - 48], rax
+    mov r11, [rbp - 40]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_5
+    mov rax, [rbp - 40]
+    sub rax, [rbp - 48]
+    jo slow_5
+    jmp done_6
+"slow_5":
+    mov rax, [rbp - 40]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_7
+    cmp r11, 5
+    jne error_8
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_7
+"error_8":
+    mov rdi, 19
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_9":
+    mov qword ptr [rbp - 56], 0
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_9
+"number_7":
+    mov rax, [rbp - 48]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_10
+    cmp r11, 5
+    jne error_11
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_10
+"error_11":
+    mov rdi, 19
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_12":
+    mov qword ptr [rbp - 56], 0
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -48
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_12
+"number_10":
+    mov rdi, 19
+    mov rsi, [rbp - 40]
+    mov rdx, [rbp - 48]
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 56], rax
+"retry_13":
+    mov r11, r12
+    add r11, [rbp - 56]
+    cmp r11, r13
+    jle alloc_14
+    mov rdi, [rbp - 56]
+    mov rsi, rbp
+    add rsi, -56
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 64], 0
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_13
+"alloc_14":
+    mov rdi, 19
+    mov rsi, [rbp - 40]
+    mov rdx, [rbp - 48]
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_6":
+    mov qword ptr [rbp - 40], rax
+    mov rax, [rbp - 8]
+    mov qword ptr [rbp - 48], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [rbp - 56], rax
+    mov r11, [rbp - 48]
+    or r11, rax
+    and r11, 7
+    cmp r11, 0
+    jne slow_15
+    mov rax, [rbp - 48]
+    sar rax, 3
+    imul qword ptr [rbp - 56]
+    jo slow_15
+    jmp done_16
+"slow_15":
+    mov rax, [rbp - 48]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_17
+    cmp r11, 5
+    jne error_18
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_17
+"error_18":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_19":
+    mov qword ptr [rbp - 64], 0
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_19
+"number_17":
+    mov rax, [rbp - 56]
+    mov r11, rax
+    and r11, 7
+    cmp r11, 0
+    je number_20
+    cmp r11, 5
+    jne error_21
+    mov r11, [rax - 5]
+    sar r11, 60
+    and r11, 3
+    cmp r11, 0
+    jne number_20
+"error_21":
+    mov rdi, 1
+    mov rsi, 0
+    mov rdx, rax
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_type_error"
+    mov rsp, rbp
+"raise_22":
+    mov qword ptr [rbp - 64], 0
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "inc_dispatch"
+    mov rsp, rbp
+    mov r11, rbp
+    add r11, -56
+    mov rsp, r11
+    and rsp, -16
+    call "rt_handler_returned"
+    mov rsp, rbp
+    jmp raise_22
+"number_20":
+    mov rdi, 1
+    mov rsi, [rbp - 48]
+    mov rdx, [rbp - 56]
+    mov r11, rbp
+    add r11, -64
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic_size"
+    mov rsp, rbp
+    mov qword ptr [rbp - 64], rax
+"retry_23":
+    mov r11, r12
+    add r11, [rbp - 64]
+    cmp r11, r13
+    jle alloc_24
+    mov rdi, [rbp - 64]
+    mov rsi, rbp
+    add rsi, -64
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -64
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 72], 0
+    mov r11, rbp
+    add r11, -64
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_23
+"alloc_24":
+    mov rdi, 1
+    mov rsi, [rbp - 48]
+    mov rdx, [rbp - 56]
+    mov r11, rbp
+    add r11, -64
+    mov rsp, r11
+    and rsp, -16
+    call "rt_arithmetic"
+    mov rsp, rbp
+"done_16":
+    mov qword ptr [rbp - 48], rax
+    sub rsp, 16
+    call "scm:{let 0} factorial"
+    add rsp, 16
+"exit_4":
+    pop rbp
+    ret
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
@@ -0,0 +1,5 @@
+(let ((factorial (lambda (x acc)
+                   (if (zero? x)
+                       acc
+                       (factorial (dec x) (* x acc))))))
+  (factorial 10 1))
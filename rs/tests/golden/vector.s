@@ -0,0 +1,121 @@
+    .text
+    .intel_syntax noprefix
+    .globl "init"
+    .type "init", @function
+"init":
+    push rbp
+    mov rbp, rsp
+    mov r12, rdi # Store heap index to R12
+    mov r13, rsi
+    mov r14, rbp
+    mov r15, rdx
+    lea rdi, [rip + 6 + "inc_sym_0"]
+    mov rsi, 1
+    mov r11, rbp
+    add r11, 0
+    mov rsp, r11
+    and rsp, -16
+    call "rt_register_symbols"
+    mov rsp, rbp
+    lea rax, [rip + 6 + "inc_sym_0"]
+    mov qword ptr [rbp - 8], rax
+    lea rax, [rip + 5 + "inc_str_0"]
+    mov qword ptr [rbp - 16], rax
+"retry_1":
+    mov r11, r12
+    add r11, 48
+    cmp r11, r13
+    jle alloc_2
+    mov rdi, 48
+    mov rsi, rbp
+    add rsi, -16
+    mov rdx, rbp
+    mov rcx, r14
+    mov r8, r12
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "gc_collect"
+    mov rsp, rbp
+    mov r12, rax
+    mov r13, rdx
+    mov qword ptr [rbp - 24], 0
+    mov r11, rbp
+    add r11, -16
+    mov rsp, r11
+    and rsp, -16
+    call "inc_finalize"
+    mov rsp, rbp
+    jmp retry_1
+"alloc_2":
+    mov qword ptr [r12], 5
+    mov qword ptr [r12 + 8], 8
+    mov qword ptr [r12 + 16], 9
+    mov qword ptr [r12 + 24], 778
+    mov rax, [rbp - 8]
+    mov qword ptr [r12 + 32], rax
+    mov rax, [rbp - 16]
+    mov qword ptr [r12 + 40], rax
+    mov rax, r12
+    add r12, 48
+    or rax, 7
+    pop rbp
+    ret
+    .p2align 3
+"inc_str_0":
+    .quad 4611686018427387907
+    .asciz "str"
+    .p2align 3
+"inc_sym_0":
+    .quad 0
+    .quad 3
+    .asciz "sym"
+"inc_dispatch":
+    cmp rax, 0
+    je inc_dispatch_call
+    cmp rax, 1
+    je inc_dispatch_unwind
+    mov rbp, rax
+    mov rsp, rax
+    jmp rdx
+"inc_dispatch_call":
+    push rbp
+    mov rbp, rsp
+    mov qword ptr [rbp - 8], rdx
+    sub rsp, 16
+    call "rt_condition"
+    mov rsp, rbp
+    mov qword ptr [rbp - 32], rax
+    mov rdx, [rbp - 8]
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    pop rbp
+    ret
+"inc_dispatch_unwind":
+    push rbp
+    mov rbp, rsp
+    call rdx
+    mov rsp, rbp
+    call "rt_unwind"
+    mov rsp, rbp
+    pop rbp
+    jmp inc_dispatch
+"inc_finalize":
+    push rbp
+    mov rbp, rsp
+"inc_finalize_next":
+    call "gc_finalizer"
+    mov rsp, rbp
+    cmp rdx, 0
+    je inc_finalize_done
+    mov qword ptr [rbp - 8], 0
+    mov qword ptr [rbp - 32], rax
+    sub rsp, 8
+    call rdx
+    mov rsp, rbp
+    jmp inc_finalize_next
+"inc_finalize_done":
+    pop rbp
+    ret
@@ -0,0 +1 @@
+(vector 1 #t #\a 'sym "str")
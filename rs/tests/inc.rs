@@ -97,7 +97,7 @@ mod unary {
 
     #[test]
     fn inc() {
-        let tests = vec![
+        let tests = [
             ("(inc 0)", "1"),
             ("(inc -1)", "0"),
             ("(inc 41)", "42"),
@@ -144,7 +144,7 @@ mod unary {
 
     #[test]
     fn zero() {
-        let tests = vec![
+        let tests = [
             (r"(zero? 0)", r"#t"),
             (r"(zero? 1)", r"#f"),
             (r"(zero? #t)", r"#f"),
@@ -158,7 +158,7 @@ mod unary {
 
     #[test]
     fn null() {
-        let tests = vec![
+        let tests = [
             (r"(null? ())", r"#t"),
             (r"(null? #\Q)", r"#f"),
             (r"(null? #f)", r"#f"),
@@ -218,6 +218,278 @@ mod binary {
     }
 }
 
+// Integers that don't fit in a fixnum
+mod bignum {
+    use super::*;
+
+    // Largest fixnum
+    const MAX: &str = "1152921504606846975";
+
+    #[test]
+    fn overflow() {
+        test_many(&[
+            (&format!("(+ {} 1)", MAX), "1152921504606846976"),
+            (&format!("(- (- 0 {}) 2)", MAX), "-1152921504606846977"),
+            (&format!("(* {} 16)", MAX), "18446744073709551600"),
+            (&format!("(inc {})", MAX), "1152921504606846976"),
+            (&format!("(* {} (- 0 {}))", MAX, MAX), "-1329227995784915870597964051066650625"),
+            // The smallest fixnum divided by -1 is the only quotient that overflows
+            ("(/ -1152921504606846976 -1)", "1152921504606846976"),
+            ("(% -1152921504606846976 -1)", "0"),
+            ("(/ -1152921504606846976 1)", "-1152921504606846976"),
+        ])
+    }
+
+    // Results that fit in a fixnum are fixnums again
+    #[test]
+    fn demote() {
+        test_many(&[
+            (&format!("(- (+ {} 10) 20)", MAX), "1152921504606846965"),
+            (&format!("(fixnum? (dec (inc {})))", MAX), "#t"),
+            (&format!("(fixnum? (* {} 2))", MAX), "#f"),
+        ])
+    }
+
+    #[test]
+    fn factorial() {
+        let expr = "(define (f n) (if (zero? n) 1 (* n (f (dec n)))))";
+
+        test_many(&[
+            (&format!("{} (f 30)", expr), "265252859812191058636308480000000"),
+            (&format!("{} (= (f 25) (* 25 (f 24)))", expr), "#t"),
+            (&format!("{} (< (f 25) (f 24))", expr), "#f"),
+            (&format!("{} (> 0 (- 0 (f 21)))", expr), "#t"),
+            (&format!("{} (/ (f 30) (f 28))", expr), "870"),
+            (&format!("{} (% (f 25) 7)", expr), "0"),
+            (&format!("{} (/ (- 0 (f 22)) (f 20))", expr), "-462"),
+        ])
+    }
+
+    // Integer literals too large for a fixnum are read as bignums
+    #[test]
+    fn literals() {
+        test_many(&[
+            ("1152921504606846976", "1152921504606846976"),
+            ("-1152921504606846977", "-1152921504606846977"),
+            ("4611686018427387903", "4611686018427387903"),
+            ("(fixnum? 1152921504606846976)", "#f"),
+            ("(- 1152921504606846976 1)", "1152921504606846975"),
+            ("(fixnum? (- 1152921504606846976 1))", "#t"),
+            ("(* 99999999999999999999 -3)", "-299999999999999999997"),
+            ("(= 265252859812191058636308480000000 (* 30 8841761993739701954543616000000))", "#t"),
+            (
+                "(read (open-input-string \"-123456789012345678901234567890\"))",
+                "-123456789012345678901234567890",
+            ),
+        ])
+    }
+
+    // Bignums survive collections
+    #[test]
+    fn gc() {
+        let expr = format!(
+            "(define (sum n acc) (if (zero? n) acc (sum (dec n) (+ acc (* {} n)))))
+             (sum 2000 0)",
+            MAX
+        );
+
+        test1(&expr, "2306995930718300796975000");
+    }
+}
+
+mod flonum {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        test_many(&[
+            ("3.25", "3.25"),
+            ("-0.5", "-0.5"),
+            ("2.0", "2.0"),
+            ("(cons 1.5 ())", "(1.5)"),
+            ("(flonum? 1.5)", "#t"),
+            ("(flonum? 1)", "#f"),
+            ("(number? 1.5)", "#t"),
+            ("(number? \"1.5\")", "#f"),
+            ("(string? 1.5)", "#f"),
+        ])
+    }
+
+    // Exact operands are converted when the other one is inexact
+    #[test]
+    fn coercion() {
+        test_many(&[
+            ("(+ 1 2.5)", "3.5"),
+            ("(- 0.5 1)", "-0.5"),
+            ("(* 1.5 2)", "3.0"),
+            ("(/ 1.0 4)", "0.25"),
+            ("(/ 7 2)", "3"),
+            ("(% 7.5 2)", "1.5"),
+            ("(inc 1.5)", "2.5"),
+            ("(+ 0.5 (* 1152921504606846975 2))", "2305843009213694000.0"),
+            ("(/ 1.0 0)", "+inf.0"),
+            ("(/ -1 0.0)", "-inf.0"),
+        ])
+    }
+
+    #[test]
+    fn comparison() {
+        test_many(&[
+            ("(< 1 1.5)", "#t"),
+            ("(= 2 2.0)", "#t"),
+            ("(>= 2.5 3)", "#f"),
+            ("(> 1.0 (* 1152921504606846975 2))", "#f"),
+            ("(let ((nan (/ 0.0 0.0))) (= nan nan))", "#f"),
+        ])
+    }
+
+    #[test]
+    fn conversion() {
+        test_many(&[
+            ("(exact->inexact 3)", "3.0"),
+            ("(inexact->exact 2.0)", "2"),
+            ("(inexact->exact -7)", "-7"),
+            ("(exact->inexact (* 1152921504606846975 16))", "18446744073709552000.0"),
+            ("(inexact->exact 18446744073709551616.0)", "18446744073709551616"),
+            ("(exact? 1)", "#t"),
+            ("(exact? 1.0)", "#f"),
+            ("(inexact? 2.5)", "#t"),
+        ])
+    }
+
+    // Flonums survive collections
+    #[test]
+    fn gc() {
+        let expr = "(define (sum n acc) (if (zero? n) acc (sum (dec n) (+ acc 0.5))))
+                    (sum 100000 0)";
+
+        test1(expr, "50000.0");
+    }
+}
+
+mod chars {
+    use super::*;
+
+    #[test]
+    fn conversion() {
+        test_many(&[
+            (r"(char->integer #\A)", "65"),
+            (r"(char->integer #\newline)", "10"),
+            ("(integer->char 97)", r"#\a"),
+            (r"(integer->char (inc (char->integer #\a)))", r"#\b"),
+            (r"(digit-value #\7)", "7"),
+            (r"(digit-value #\x)", "#f"),
+        ])
+    }
+
+    #[test]
+    fn comparison() {
+        test_many(&[
+            (r"(char=? #\a #\a)", "#t"),
+            (r"(char=? #\a #\A)", "#f"),
+            (r"(char<? #\a #\b)", "#t"),
+            (r"(char<=? #\b #\a)", "#f"),
+            (r"(char>? #\z #\Z)", "#t"),
+            (r"(char>=? #\0 #\0)", "#t"),
+        ])
+    }
+
+    #[test]
+    fn classes() {
+        test_many(&[
+            (r"(char-alphabetic? #\q)", "#t"),
+            (r"(char-alphabetic? #\[)", "#f"),
+            (r"(char-numeric? #\5)", "#t"),
+            (r"(char-numeric? #\a)", "#f"),
+            (r"(char-whitespace? #\space)", "#t"),
+            (r"(char-whitespace? #\tab)", "#t"),
+            (r"(char-whitespace? #\-)", "#f"),
+            (r"(char-upper-case? #\Q)", "#t"),
+            (r"(char-lower-case? #\Q)", "#f"),
+        ])
+    }
+
+    #[test]
+    fn case() {
+        test_many(&[
+            (r"(char-upcase #\a)", r"#\A"),
+            (r"(char-upcase #\A)", r"#\A"),
+            (r"(char-downcase #\Z)", r"#\z"),
+            (r"(char-downcase #\1)", r"#\1"),
+        ])
+    }
+}
+
+mod equality {
+    use super::*;
+
+    #[test]
+    fn identity() {
+        test_many(&[
+            ("(eq? 'a 'a)", "#t"),
+            ("(eq? (cons 1 2) (cons 1 2))", "#f"),
+            (r#"(eq? "a" (make-string 1 #\a))"#, "#f"),
+            ("(eqv? 1.5 1.5)", "#t"),
+            ("(eqv? 2 2.0)", "#f"),
+            ("(eqv? (* 1152921504606846975 2) (* 1152921504606846975 2))", "#t"),
+            ("(eq? 2 2)", "#t"),
+            ("(eq? (* 1152921504606846975 2) (* 1152921504606846975 2))", "#f"),
+            ("(let ((x (* 1152921504606846975 2))) (eq? x x))", "#t"),
+            ("(eq? (exact->inexact 3) (exact->inexact 3))", "#f"),
+        ])
+    }
+
+    #[test]
+    fn deep() {
+        test_many(&[
+            ("(equal? (cons 1 (cons 2 ())) (cons 1 (cons 2 ())))", "#t"),
+            ("(equal? (cons 1 (cons 2 ())) (cons 1 (cons 3 ())))", "#f"),
+            (r#"(equal? "abc" (make-string 3 #\a))"#, "#f"),
+            (r#"(equal? (vector 1 "b" (vector 'c)) (vector 1 "b" (vector 'c)))"#, "#t"),
+            ("(equal? (vector 1 2) (vector 1 2 3))", "#f"),
+            ("(equal? 1 1.0)", "#f"),
+        ])
+    }
+
+    #[test]
+    fn cycles() {
+        test_many(&[
+            (
+                "(let ((a (cons 1 1)) (b (cons 1 1)) (x (set-cdr! a a)) (y (set-cdr! b b)))
+                   (equal? a b))",
+                "#t",
+            ),
+            (
+                "(let ((a (cons 1 1)) (b (cons 1 (cons 1 1))) (x (set-cdr! a a))
+                       (y (set-cdr! (cdr b) b)))
+                   (equal? a b))",
+                "#t",
+            ),
+            (
+                "(let ((a (cons 1 1)) (b (cons 1 (cons 2 1))) (x (set-cdr! a a))
+                       (y (set-cdr! (cdr b) b)))
+                   (equal? a b))",
+                "#f",
+            ),
+            (
+                "(let ((a (vector 1 2)) (b (vector 1 2)) (x (vector-set! a 1 a))
+                       (y (vector-set! b 1 b)))
+                   (equal? a b))",
+                "#t",
+            ),
+        ])
+    }
+
+    // The spine of a list is compared in a loop
+    #[test]
+    fn long() {
+        let expr = "(define (l n acc) (if (zero? n) acc (l (dec n) (cons n acc))))
+                    (equal? (l 100000 ()) (l 100000 ()))";
+
+        test1(expr, "#t");
+    }
+}
+
 // Step 5: Let bindings
 mod bindings {
     mod unit {
@@ -247,7 +519,12 @@ mod bindings {
 
         #[test]
         fn shadow() {
-            let tests = [("(let ((x 1)) (let ((x 2)) #t) x)", "1")];
+            let tests = [
+                ("(let ((x 1)) (let ((x 2)) #t) x)", "1"),
+                ("(let ((car (lambda (x) 42))) (car (cons 1 2)))", "42"),
+                ("(let ((car (lambda (x) 42))) (car 1)) (car (cons 1 2))", "1"),
+                ("(let ((vector (lambda (x) (* x 2)))) (vector 21))", "42"),
+            ];
 
             for (inp, out) in tests.iter() {
                 test1(inp, out);
@@ -381,6 +658,34 @@ mod heap {
             test1("(string-length \"\")", "0");
             test1("(string-length \"🐈\")", "4")
         }
+
+        #[test]
+        fn escapes() {
+            test_many(&[
+                (r#"(string-length "a\"b\\c")"#, "5"),
+                (r#""a\"b\\c""#, r#""a\"b\\c""#),
+                (r#""tab\there""#, r#""tab\there""#),
+                (r#"(string-length "x\x0;y\x3bb;")"#, "5"),
+                (r#"(string-ref "x\x0;y" 2)"#, "#\\y"),
+                (r#""x\x0;y""#, r#""x\x0;y""#),
+                // Literals are the same object wherever they are in a program
+                (r#"(define (f) "a\"b") (define (g) "a\"b") (eq? (f) (g))"#, "#t"),
+            ])
+        }
+
+        #[test]
+        fn mutation() {
+            test_many(&[
+                ("(make-string 3 #\\a)", "\"aaa\""),
+                ("(let ((n 2)) (string-length (make-string n)))", "2"),
+                ("(let ((s (make-string 3 #\\a))) (string-set! s 1 #\\b) s)", "\"aba\""),
+                ("(string-ref \"hello\" 1)", "#\\e"),
+                ("(string-append \"foo\" \"bar\")", "\"foobar\""),
+                ("(substring \"hello world\" 6 11)", "\"world\""),
+                ("(string=? \"ab\" (string-append \"a\" \"b\"))", "#t"),
+                ("(string=? \"ab\" \"abc\")", "#f"),
+            ])
+        }
     }
 
     mod symbols {
@@ -409,6 +714,44 @@ mod heap {
             test1("(symbol=? 'one 'two)", "#f");
             test1("(symbol=? 'woo 'woo)", "#t")
         }
+
+        #[test]
+        fn quoted() {
+            let tests = [
+                ("(quote ())", "()"),
+                ("'()", "()"),
+                ("(quote one)", "'one"),
+                ("'(1 2)", "(1 2)"),
+                ("'(a . b)", "('a . 'b)"),
+                ("'(1 (a \"b\") #\\c)", "(1 ('a \"b\") #\\c)"),
+                ("'#(1 two)", "[1 'two]"),
+                ("(car ''a)", "'quote"),
+                ("(symbol=? (car '(if x)) 'if)", "#t"),
+            ];
+
+            test_many(&tests)
+        }
+
+        #[test]
+        fn interned() {
+            test_many(&[
+                (r#"(string->symbol "hello")"#, "'hello"),
+                (r#"(eq? (string->symbol "hello") 'hello)"#, "#t"),
+                (r#"(eq? (string->symbol "new") (string->symbol "new"))"#, "#t"),
+                (r#"(eq? (string->symbol "one") (string->symbol "two"))"#, "#f"),
+                (r#"(eq? (read (open-input-string "woo")) 'woo)"#, "#t"),
+                (
+                    r#"(let ((env (interaction-environment)))
+                         (eq? (eval (read (open-input-string "(quote hi)")) env) 'hi))"#,
+                    "#t",
+                ),
+                ("(symbol->string 'world)", r#""world""#),
+                (r#"(symbol->string (string->symbol "new"))"#, r#""new""#),
+            ]);
+
+            let e = fail("(symbol->string 42)");
+            assert!(e.contains("symbol->string: expected symbol, got 42"), "{}", e);
+        }
     }
 
     mod vector {
@@ -418,6 +761,133 @@ mod heap {
         fn simple() {
             test1("(vector 1 5 'one 'two \"DAMN\")", "[1 5 'one 'two \"DAMN\"]");
         }
+
+        #[test]
+        fn make() {
+            test_many(&[
+                ("(make-vector 3)", "[0 0 0]"),
+                ("(make-vector 2 'x)", "['x 'x]"),
+                ("(make-vector 0)", "[]"),
+                ("(vector-length (make-vector 5 #t))", "5"),
+            ])
+        }
+
+        #[test]
+        fn access() {
+            test_many(&[
+                ("(vector-ref (vector 1 2 3) 2)", "3"),
+                ("(let ((v (make-vector 2 0))) (vector-set! v 1 'b) v)", "[0 'b]"),
+                ("(vector->list (vector 1 #\\a \"s\"))", "(1 #\\a \"s\")"),
+                ("(vector->list (vector))", "()"),
+                ("(cons (vector? (vector)) (vector? (cons 1 2)))", "(#t . #f)"),
+            ])
+        }
+    }
+
+    mod tables {
+        use super::*;
+
+        // Evaluate `body` with a fresh table `t` made by `make`
+        fn table(make: &str, tests: &[(&str, &str)]) {
+            for (body, out) in tests.iter() {
+                test1(&format!("(let ((t ({}))) {})", make, body), out);
+            }
+        }
+
+        #[test]
+        fn equal() {
+            table(
+                "make-hash-table",
+                &[
+                    ("(hash-table-count t)", "0"),
+                    ("(hash-table-set! t \"a\" 1) (hash-table-ref t \"a\")", "1"),
+                    ("(hash-table-set! t (cons 1 2) 'p) (hash-table-ref t (cons 1 2))", "'p"),
+                    ("(hash-table-ref/default t 'missing 'none)", "'none"),
+                    (
+                        "(hash-table-set! t 'k 1) (hash-table-set! t 'k 2)
+                         (cons (hash-table-count t) (hash-table-ref/default t 'k 0))",
+                        "(1 . 2)",
+                    ),
+                ],
+            )
+        }
+
+        #[test]
+        fn eq() {
+            table(
+                "make-eq-hash-table",
+                &[
+                    ("(hash-table-set! t 'a 1) (hash-table-contains? t 'a)", "#t"),
+                    ("(let ((k (cons 1 2))) (hash-table-set! t k 3) (hash-table-ref t k))", "3"),
+                    (
+                        "(hash-table-set! t (make-string 1 #\\a) 1)
+                         (hash-table-contains? t (make-string 1 #\\a))",
+                        "#f",
+                    ),
+                ],
+            )
+        }
+
+        #[test]
+        fn delete() {
+            table(
+                "make-hash-table",
+                &[
+                    (
+                        "(hash-table-set! t 1 'a) (hash-table-delete! t 1)
+                         (cons (hash-table-count t) (hash-table-contains? t 1))",
+                        "(0 . #f)",
+                    ),
+                    ("(hash-table-set! t 1 'a) (hash-table-delete! t 2) (hash-table-count t)", "1"),
+                ],
+            )
+        }
+
+        #[test]
+        fn contents() {
+            table(
+                "make-hash-table",
+                &[
+                    ("(hash-table-set! t 1 'a) (hash-table->alist t)", "((1 . 'a))"),
+                    ("(hash-table-set! t 1 'a) (hash-table-keys t)", "(1)"),
+                    ("(hash-table-set! t 1 'a) (hash-table-values t)", "('a)"),
+                ],
+            )
+        }
+
+        // The thunk is called only when the key is missing, which is an error
+        // without one
+        #[test]
+        fn missing() {
+            table(
+                "make-hash-table",
+                &[
+                    ("(hash-table-ref t 'missing (lambda () 'none))", "'none"),
+                    ("(hash-table-set! t 'k 1) (hash-table-ref t 'k (lambda () (car 1)))", "1"),
+                    ("(let ((f (lambda () 2))) (hash-table-ref t 'missing f))", "2"),
+                    ("(guard (e ((string? e) 'raised)) (hash-table-ref t 'missing))", "'raised"),
+                ],
+            );
+
+            test1("(define (f) 0) (hash-table-ref (make-hash-table) 'missing f)", "0");
+
+            let e = fail("(hash-table-ref (make-hash-table) 'missing)");
+            assert!(e.contains("Exception: hash-table-ref: no value for the key"), "{}", e);
+        }
+
+        // Tables grow well beyond their initial number of buckets
+        #[test]
+        fn grow() {
+            let expr = "(define (fill t n)
+                           (if (zero? n)
+                             t
+                             (let ((x (hash-table-set! t n (* n n))))
+                               (fill t (dec n)))))
+                         (let ((t (fill (make-hash-table) 500)))
+                           (cons (hash-table-count t) (hash-table-ref t 321)))";
+
+            test1(expr, "(500 . 103041)");
+        }
     }
 }
 
@@ -425,6 +895,19 @@ mod heap {
 mod functions {
     use super::*;
 
+    #[test]
+    fn shadow() {
+        test_many(&[
+            ("(define (car x) 42) (car (cons 1 2))", "42"),
+            ("(define (car x) 42) (define (f p) (car p)) (f (cons 1 2))", "42"),
+            // Labels of functions don't clash with the runtime, libc or the compiler
+            ("(define (string=? a b) 42) (string=? \"a\" \"a\")", "42"),
+            ("(define (print x) (* x 2)) (print 21)", "42"),
+            ("(define (open x) x) (open 7)", "7"),
+            ("(define (stack_1) 5) (stack_1)", "5"),
+        ])
+    }
+
     #[test]
     fn no_arg() {
         test1("(let ((f (lambda () 5))) 7)", "7");
@@ -496,6 +979,18 @@ mod functions {
         );
     }
 
+    #[test]
+    fn formals() {
+        test_many(&[
+            ("(let ((x 1) (f (lambda (x) (let ((x (* x 10))) x)))) (cons (f 2) x))", "(20 . 1)"),
+            (
+                "(let ((y 3) (f (lambda (x y) (let ((y (- y x))) (cons x y))))) (cons (f 1 y) y))",
+                "((1 . 2) . 3)",
+            ),
+            ("(define (f x) (let ((x (+ x 1))) (let ((y x)) (cons x y)))) (f 1)", "(2 . 2)"),
+        ]);
+    }
+
     // Lambda lifts are not recursive yet, so there is no point testing for this
     // one yet.
     #[test]
@@ -512,6 +1007,40 @@ mod functions {
             "42",
         );
     }
+
+    #[test]
+    fn body() {
+        test_many(&[
+            ("(define (f x) (car (cons 1 2)) (* x 2)) (f 21)", "42"),
+            ("(define (f x) (if x 1 2) (if x 3 4) (cons x x)) (f 5)", "(5 . 5)"),
+        ]);
+    }
+
+    #[test]
+    fn apply() {
+        let add = "(define (add a b c) (+ a (+ b c)))";
+
+        test_many(&[
+            (&format!("{} (apply add (cons 1 (cons 2 (cons 3 ()))))", add), "6"),
+            (&format!("{} (apply add 1 (cons 2 (cons 3 ())))", add), "6"),
+            (&format!("{} (apply add 1 2 3 ())", add), "6"),
+            ("(define (f) 42) (apply f ())", "42"),
+            ("(apply + (list 1 2 3))", "6"),
+            ("(apply * 2 (list 3 4))", "24"),
+            ("(apply + ())", "0"),
+            ("(+ 1 2 3 4)", "10"),
+            ("(+ 4)", "4"),
+            ("(*)", "1"),
+            ("(let ((f (lambda (a b) (cons b a)))) (apply f (cons 1 (cons 2 ()))))", "(2 . 1)"),
+        ]);
+
+        // Calls in tail position
+        test1(
+            "(define (sum a b) (if (zero? a) b (apply sum (dec a) (cons (+ a b) ()))))
+             (sum 100 0)",
+            "5050",
+        );
+    }
 }
 
 // Step 9, TCO
@@ -532,86 +1061,1515 @@ mod tco {
     }
 }
 
-// Step 19, 20 & 21 - IO
-mod io {
+// The optional passes only make the generated code better
+mod passes {
     use super::*;
-    use std::fs::read_to_string;
+    use inc::lang::Passes;
 
     #[test]
-    fn std_ports() {
-        let k = r#"(current-input-port)"#;
-        test1(k, r#"['port "stdin" 0]"#);
+    fn level0() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
 
-        let k = r#"(current-output-port)"#;
-        test1(k, r#"['port "stdout" 1]"#);
+        let program = "(let ((x (+ (* 2 3) (* 4 5)))) (cons (vector (inc (car (cons 2 3)))) x))";
+        let mut config = config(&base_folder, program.to_string());
+        config.passes = Passes::level("0").unwrap();
 
-        let k = r#"(current-error-port)"#;
-        test1(k, r#"['port "stderr" 2]"#);
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, "([3] . 26)"),
+            other => panic!("Unexpected result {:?}", other),
+        }
     }
+}
+
+// Programs built from several files, each compiled to an object of its own
+mod units {
+    use super::*;
 
     #[test]
-    fn fd() {
-        let k = r#"(open-input-file "/etc/hosts")"#;
-        test1(k, r#"['port "/etc/hosts" 4]"#);
+    fn link() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let lib = "(define (twice x) (* x 2))
+                   (define (tag x) (cons 'tagged x))
+                   (let ((f (lambda (s) (display s)))) (f \"lib \"))";
+        let util = "(define (thrice x) (+ (twice x) x))
+                    (let ((f (lambda (s) (display s)))) (f \"util \"))";
+        let program = "(let ((f (lambda (x) (cons (twice x) (thrice x)))))
+                         (cons (f 2) (eq? (car (tag 1)) 'tagged)))";
+
+        let mut config = config(&base_folder, program.to_string());
+        config.units = vec![cli::unit("lib.scm", lib.into()), cli::unit("util.scm", util.into())];
+
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, "lib util ((4 . 6) . #t)"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
     }
 
     #[test]
-    fn write_to_file() {
-        let k = r#"(rt-write "hello world\n" (open-output-file "/tmp/inc/io.txt"))"#;
+    fn symbols() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        // Symbols in a unit only, in a unit and the program, and in two units
+        let lib = "(define (kind x) (if (pair? x) 'pair 'atom))
+                   (define (own) 'lib)
+                   (define (first) (cons 'unit 'pair))";
+        let util = "(define (same? x) (eq? x 'atom))
+                    (define (units) (cons (car (first)) (cons 'unit (own))))";
+        let program = "(cons (eq? (kind (cons 1 2)) 'pair)
+                             (cons (same? (kind 1))
+                                   (cons (eq? (car (units)) (car (cdr (units))))
+                                         (cons (eq? (cdr (cdr (units))) (string->symbol \"lib\"))
+                                               (symbol->string (own))))))";
+
+        let mut config = config(&base_folder, program.to_string());
+        config.units = vec![cli::unit("lib.scm", lib.into()), cli::unit("util.scm", util.into())];
+
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, "(#t #t #t #t . \"lib\")"),
+            other => panic!("Unexpected result {:?}", other),
+        }
 
-        test1(k, "()");
-        assert_eq!("hello world\n", read_to_string("/tmp/inc/io.txt").unwrap())
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
     }
 
     #[test]
-    fn write_to_stdout() {
-        let k = r#"(rt-write "hello world" (current-output-port))"#;
-        test1(k, "\"hello world\"()");
+    fn names() {
+        assert_eq!(cli::unit("src/list-utils.scm", String::new()).name, "list_utils");
     }
 
     #[test]
-    fn read() {
-        fs::create_dir_all(TEST_FOLDER)
-            .unwrap_or_else(|e| panic!("Failed to create test folder {}", e));
+    fn cache() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let mut config = config(&base_folder, String::from("(twice 21)"));
+        let cached = || fs::read_dir(format!("{}/.inc-cache", base_folder)).unwrap().count();
+        let run = |config: &Config, expected: &str| match cli::run(config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, expected),
+            other => panic!("Unexpected result {:?}", other),
+        };
+
+        // The unit and the program
+        config.units = vec![cli::unit("lib.scm", "(define (twice x) (* x 2))".into())];
+        run(&config, "42");
+        assert_eq!(cached(), 2);
+
+        run(&config, "42");
+        assert_eq!(cached(), 2);
+
+        // Only the changed unit is compiled again
+        config.units = vec![cli::unit("lib.scm", "(define (twice x) (+ (* x 2) 1))".into())];
+        run(&config, "43");
+        assert_eq!(cached(), 3);
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+}
 
-        fs::write("/tmp/inc/hello.txt", "hello ").unwrap();
-        fs::write("/tmp/inc/world.txt", "world").unwrap();
+// R7RS libraries resolved at compile time
+mod libraries {
+    use super::*;
 
-        let k = r#"
-            (let ((f1 (open-input-file "/tmp/inc/hello.txt"))
-                  (hello (rt-read f1))
+    const ARITH: &str = "(define-library (math arith)
+                           (export double (rename triple thrice))
+                           (import (scheme base))
+                           (begin
+                             (define (double x) (* x 2))
+                             (define (triple x) (+ (double x) x))))";
 
-                  (f2 (open-input-file "/tmp/inc/world.txt"))
-                  (world (rt-read f2)))
+    #[test]
+    fn import() {
+        test_many(&[
+            (&format!("{} (import (math arith)) (cons (double 21) (thrice 2))", ARITH), "(42 . 6)"),
+            (&format!("{} (import (prefix (math arith) m:)) (m:double 1)", ARITH), "2"),
+            (&format!("{} (import (only (math arith) double)) (double 2)", ARITH), "4"),
+            (&format!("{} (import (math arith)) (define (double x) x) (double 2)", ARITH), "2"),
+        ]);
+    }
 
-              (cons hello world))"#;
+    // Libraries with the same names for different definitions don't clash
+    #[test]
+    fn clash() {
+        let other = "(define-library (other)
+                       (export double)
+                       (import (only (math arith) thrice))
+                       (begin (define (double x) (thrice (* x 100)))))";
 
-        test1(k, r#"("hello " . "world")"#);
+        test1(
+            &format!(
+                "{} {} (import (math arith) (rename (other) (double other-double)))
+                 (cons (double 1) (other-double 1))",
+                ARITH, other
+            ),
+            "(2 . 300)",
+        );
     }
 }
 
-mod rt {
+// Files loaded at compile time
+mod load {
     use super::*;
-    use inc::rt;
 
     #[test]
-    fn alloc() {
-        let old = rt::heap();
-        rt::allocate(15);
-        let new = rt::heap();
+    fn forms() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
 
-        assert_eq!(new, old + 16);
+        let lib = format!("{}/lib.scm", base_folder);
+        fs::write(&lib, "(define (square x) (* x x))").unwrap();
+
+        test1(&format!("(load \"{}\") (square 5)", lib), "25");
+
+        let mut config = config(&base_folder, String::from("(square 6)"));
+        config.load = vec![lib];
+
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, "36"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+
+    #[test]
+    fn cycle() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let a = format!("{}/a.scm", base_folder);
+        let b = format!("{}/b.scm", base_folder);
+        fs::write(&a, format!("(load \"{}\") 1", b)).unwrap();
+        fs::write(&b, format!("(load \"{}\") 2", a)).unwrap();
+
+        let config = config(&base_folder, format!("(load \"{}\")", a));
+
+        match cli::run(&config, cli::Action::Run) {
+            Err(Error::Compilation(e)) => assert_eq!(e, format!("{} loads itself", a)),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
     }
 }
 
-// Get a test config with program as input
-fn config(base_folder: &str, program: String) -> Config {
-    // Time epoch instead of UUID occasionally ran into race conditions which
-    // made multiple tests write to the same file concurrently completely
-    // messing things up.
-    let output = format!("{}/inc", base_folder);
+// Nothing a fuzzer throws at the compiler makes it panic
+mod fuzz {
+    use inc::cli::compile_to_asm;
+
+    #[quickcheck]
+    fn arbitrary(source: String) {
+        compile_to_asm(&source).ok();
+    }
+
+    #[quickcheck]
+    fn tokens(tokens: Vec<u8>) {
+        let words = ["(", ")", "'", "#(", "1", "-2", "#\\a", "\"s\"", "x", "car", "lambda", "let"];
+        let source: Vec<&str> = tokens.iter().map(|t| words[*t as usize % words.len()]).collect();
+
+        // Every error is reported, there is never an empty list of them
+        if let Err(errors) = compile_to_asm(&source.join(" ")) {
+            assert!(!errors.is_empty())
+        }
+    }
+}
+
+mod prelude {
+    use super::*;
+
+    #[test]
+    fn lists() {
+        test_many(&[
+            ("(length (cons 1 (cons 2 (cons 3 ()))))", "3"),
+            ("(length ())", "0"),
+            ("(append (cons 1 ()) (cons 2 ()))", "(1 2)"),
+            ("(reverse (cons 1 (cons 2 (cons 3 ()))))", "(3 2 1)"),
+            ("(list? (cons 1 ()))", "#t"),
+            ("(list? (cons 1 2))", "#f"),
+            ("(list-ref (cons 1 (cons 2 ())) 1)", "2"),
+            ("(last-pair (cons 1 (cons 2 ())))", "(2)"),
+            ("(memq 'b (cons 'a (cons 'b ())))", "('b)"),
+            ("(member 3 (cons 1 (cons 2 ())))", "#f"),
+            ("(assq 'b (cons (cons 'a 1) (cons (cons 'b 2) ())))", "('b . 2)"),
+            ("(assoc \"b\" (cons (cons \"a\" 1) (cons (cons \"b\" 2) ())))", "(\"b\" . 2)"),
+            ("(list 1 (+ 1 1) 3)", "(1 2 3)"),
+            ("(list)", "()"),
+            ("(length (list 'a 'b))", "2"),
+        ]);
+    }
+
+    #[test]
+    fn map() {
+        test_many(&[
+            ("(define (square x) (* x x)) (map square (list 1 2 3))", "(1 4 9)"),
+            ("(define (square x) (* x x)) (map square ())", "()"),
+            ("(map (lambda (x) (cons x x)) (list 1 2))", "((1 . 1) (2 . 2))"),
+            // Enough allocation to collect the lists while they are being built
+            (
+                "(define (f x) (make-vector 64 x))
+                 (length (map f (vector->list (make-vector 4096 1))))",
+                "4096",
+            ),
+        ]);
+
+        fail("(define (f x) x) (map f (cons 1 2))");
+    }
+
+    #[test]
+    fn disabled() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let mut config = config(&base_folder, String::from("(+ 1 2)"));
+        config.prelude = false;
+
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => assert_eq!(result, "3"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        config.program = String::from("(length ())");
+        assert!(cli::run(&config, cli::Action::Run).is_err());
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+
+    #[test]
+    fn testing() {
+        use inc::testing;
+
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        let tests = format!("{}/tests", base_folder);
+        fs::create_dir_all(&tests).unwrap();
+
+        fs::write(
+            format!("{}/lists.scm", tests),
+            "(test-begin \"lists\")
+             (test-equal \"length\" 2 (length (cons 1 (cons 2 ()))))
+             (test-eqv \"car\" 1 (car (cons 1 2)))
+             (test-assert \"pair\" (pair? (cons 1 2)))
+             (test-equal \"append\" \"ab\" (string-append \"a\" \"c\"))
+             (test-end \"lists\")",
+        )
+        .unwrap();
+        fs::write(format!("{}/broken.scm", tests), "(test-equal \"car\" 1 (car 1))").unwrap();
+
+        let files = testing::files(std::slice::from_ref(&tests)).unwrap();
+        assert_eq!(files, vec![format!("{}/broken.scm", tests), format!("{}/lists.scm", tests)]);
+
+        let config = config(&base_folder, String::new());
+
+        let report = testing::run(&config, &files[1], 1);
+        assert_eq!(report.passed, vec!["length", "car", "pair"]);
+        assert_eq!(report.failed, vec!["append: expected \"ab\", got \"ac\""]);
+        assert!(report.error.is_none());
 
-    Config { program, output }
+        let report = testing::run(&config, &files[0], 0);
+        assert!(!report.ok());
+        assert!(report.error.unwrap().contains("car: expected pair"));
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+}
+
+// Deep recursion must fail with an error instead of crashing
+mod bench {
+    use super::*;
+    use inc::bench;
+
+    #[test]
+    fn allocation() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let program = "(define (build n acc) (if (zero? n) acc (build (- n 1) (cons n acc))))
+                       (length (build 100 ()))";
+        let built = config(&base_folder, String::from(program));
+
+        let summary = bench::run(&built, 3).unwrap();
+
+        assert_eq!(summary.runs, 3);
+        assert!(summary.min <= summary.median);
+        // 100 pairs of 2 words each, at the very least
+        assert!(summary.allocated >= 1600, "allocated {}", summary.allocated);
+        assert!(!std::path::Path::new(&built.output).exists());
+
+        let broken = config(&base_folder, String::from("(car 1)"));
+        assert!(bench::run(&broken, 1).is_err());
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+}
+
+mod stack {
+    use super::*;
+
+    const COUNT: &str = "(let ((count (lambda (n) (if (zero? n) 0 (inc (count (dec n)))))))
+                           (count {}))";
+
+    #[test]
+    fn deep() {
+        test1(&COUNT.replace("{}", "10000"), "10000");
+    }
+
+    #[test]
+    fn overflow() {
+        let e = fail(&COUNT.replace("{}", "100000000"));
+        assert!(e.contains("Exception: stack overflow"), "{}", e);
+    }
+}
+
+// Primitives applied to the wrong types report an error
+mod errors {
+    use super::*;
+
+    #[test]
+    fn types() {
+        let tests = [
+            ("(car 42)", "car: expected pair, got 42"),
+            ("(cdr ())", "cdr: expected pair, got ()"),
+            ("(+ 1 #t)", "+: expected number, got #t"),
+            ("(- #\\a 1)", "-: expected number, got #\\a"),
+            ("(/ 10 (cons 1 2))", "/: expected number, got (1 . 2)"),
+            ("(< \"one\" 2)", "<: expected number, got \"one\""),
+            ("(inc 'a)", "inc: expected number, got 'a"),
+            ("(set-car! 1 2)", "set-car!: expected pair, got 1"),
+            ("(string-length 42)", "string-length: expected string, got 42"),
+            ("(vector-ref 1 0)", "vector-ref: expected vector, got 1"),
+            ("(make-vector #t)", "make-vector: expected number, got #t"),
+            ("(make-string 2 1)", "make-string: expected char, got 1"),
+            ("(string-ref 'a 0)", "string-ref: expected string, got 'a"),
+            (
+                "(string-length (* 1152921504606846975 16))",
+                "string-length: expected string, got 18446744073709551600",
+            ),
+            ("(* (+ 1152921504606846975 1) #t)", "*: expected number, got #t"),
+            ("(string-length 1.5)", "string-length: expected string, got 1.5"),
+            ("(char->integer 65)", "char->integer: expected char, got 65"),
+            (r"(char<? #\a 'b)", "char<?: expected char, got 'b"),
+            (r"(integer->char #\a)", r"integer->char: expected number, got #\a"),
+            ("(exact->inexact #\\a)", "exact->inexact: expected number, got #\\a"),
+            ("(let ((f (lambda (x) (car x)))) (f (vector 1)))", "car: expected pair, got [1]"),
+        ];
+
+        for (input, error) in tests.iter() {
+            let e = fail(input);
+            assert!(e.contains(&format!("Exception: {}", error)), "{}: {}", input, e);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let tests = [
+            ("(vector-ref (vector 1 2) 2)", "vector-ref: index 2 is out of range for [1 2]"),
+            ("(vector-ref (vector 1 2) -1)", "vector-ref: index -1 is out of range for [1 2]"),
+            ("(vector-set! (make-vector 0) 0 1)", "vector-set!: index 0 is out of range for []"),
+            ("(make-vector -1)", "make-vector: invalid length -1"),
+            ("(string-ref \"abc\" 3)", "string-ref: index 3 is out of range for \"abc\""),
+            ("(string-set! \"abc\" 0 #\\x)", "string-set!: \"abc\" is immutable"),
+            ("(/ (+ 1152921504606846975 1) 0)", "/: division by zero"),
+            ("(/ 1 0)", "/: division by zero"),
+            ("(% 1 0)", "%: division by zero"),
+            ("(inexact->exact 2.5)", "inexact->exact: no exact representation for 2.5"),
+            ("(integer->char 256)", "integer->char: invalid character code 256"),
+            ("(define (f a b) a) (apply f 1 (cons 2 3))", "apply: expected pair, got 3"),
+            ("(apply + 1 (cons 2 3))", "apply: expected pair, got 3"),
+            ("(apply + (list 1 #t))", "+: expected number, got #t"),
+        ];
+
+        for (input, error) in tests.iter() {
+            let e = fail(input);
+            assert!(e.contains(&format!("Exception: {}", error)), "{}: {}", input, e);
+        }
+
+        test1(
+            "(guard (e (#t e)) (vector-ref (vector) 0))",
+            "\"vector-ref: index 0 is out of range for []\"",
+        );
+        test1("(guard (e (#t e)) (/ 1 0))", "\"/: division by zero\"");
+        test1("(guard (e (#t e)) (% (* 1152921504606846975 2) 0))", "\"%: division by zero\"");
+    }
+}
+
+mod exceptions {
+    use super::*;
+
+    #[test]
+    fn guard() {
+        let tests = [
+            ("(guard (e (#t 42)) (raise 1))", "42"),
+            ("(guard (e ((symbol? e) e)) (raise 'boom))", "'boom"),
+            ("(guard (e ((string? e) e)) (car 1))", "\"car: expected pair, got 1\""),
+            ("(guard (e ((= e 1) 0) (else 42)) (raise 2))", "42"),
+            ("(guard (e (#t 0)) 42)", "42"),
+            ("(let ((x 10)) (+ x (guard (e ((= e 1) (+ x 30))) (+ 2 (raise 1)))))", "50"),
+            ("(guard (e ((= e 2) 42)) (guard (e ((= e 1) 0)) (raise 2)))", "42"),
+        ];
+
+        test_many(&tests);
+    }
+
+    #[test]
+    fn unwind() {
+        let prog = "(let ((f (lambda (n) (if (zero? n) (raise 'deep) (+ 1 (f (dec n)))))))
+                      (guard (e ((symbol? e) 42)) (f 100)))";
+        test1(prog, "42");
+
+        let prog = "(let ((f (lambda (n) (+ 1 (f n)))))
+                      (guard (e ((string? e) e)) (f 1)))";
+        test1(prog, "\"stack overflow\"");
+    }
+
+    #[test]
+    fn handlers() {
+        let prog = "(let ((h (lambda (e) (+ e 40)))
+                          (t (lambda () (+ 1 (raise-continuable 1)))))
+                      (with-exception-handler h t))";
+        test1(prog, "42");
+
+        let prog = "(let ((h (lambda (e) 0))
+                          (t (lambda () (raise 1))))
+                      (guard (e ((string? e) e)) (with-exception-handler h t)))";
+        test1(prog, "\"handler returned from non-continuable raise\"");
+
+        // Literal lambdas work just as well as named ones
+        let prog = "(with-exception-handler
+                      (lambda (e) (+ e 40))
+                      (lambda () (+ 1 (raise-continuable 1))))";
+        test1(prog, "42");
+
+        let prog = "(define (f)
+                      (with-exception-handler (lambda (e) (* e 2)) (lambda () (raise-continuable 21))))
+                    (f)";
+        test1(prog, "42");
+    }
+
+    #[test]
+    fn uncaught() {
+        assert!(fail("(raise 'oops)").contains("Exception: 'oops"));
+        assert!(fail("(guard (e ((string? e) 0)) (raise 1))").contains("Exception: 1"));
+    }
+
+    // The exit status tells what kind of error stopped the program
+    #[test]
+    fn status() {
+        use inc::exceptions::Status;
+
+        let tests = [
+            ("(raise 'oops)", Status::Raised),
+            ("(car 1)", Status::Type),
+            ("(guard (e ((symbol? e) 0)) (+ 1 (car 1)))", Status::Type),
+            ("(heap-limit 1024) (make-vector 100000 0)", Status::Memory),
+            ("(let ((f (lambda (n) (+ 1 (f n))))) (f 1))", Status::Stack),
+        ];
+
+        for (input, expected) in tests.iter() {
+            assert_eq!(super::status(input), *expected as i32, "{}", input);
+        }
+    }
+}
+
+mod continuations {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        let prog = "(let ((f (lambda (k) (+ 1 (k 41))))) (+ 1 (call/cc f)))";
+        test1(prog, "42");
+
+        let prog = "(let ((f (lambda (k) 5))) (+ 1 (call/cc f)))";
+        test1(prog, "6");
+
+        let prog = "(let ((f (lambda (k n) (if (zero? n) (k 42) (+ 1 (f k (dec n))))))
+                          (g (lambda (k) (f k 1000))))
+                      (call-with-current-continuation g))";
+        test1(prog, "42");
+    }
+
+    #[test]
+    fn lambdas() {
+        test1("(+ 1 (call/cc (lambda (k) (+ 1 (k 41)))))", "42");
+        test1("(call-with-current-continuation (lambda (k) (k 42)))", "42");
+        test1("(define (f) (+ 1 (call/cc (lambda (k) (k 41))))) (f)", "42");
+
+        let prog = r#"(dynamic-wind
+                        (lambda () (rt-write "in" (current-output-port)))
+                        (lambda () 42)
+                        (lambda () (rt-write "out" (current-output-port))))"#;
+        test1(prog, r#""in""out"42"#);
+
+        let prog = r#"(guard (e (#t e))
+                        (dynamic-wind
+                          (lambda () #t)
+                          (lambda () (raise 'x))
+                          (lambda () (rt-write "out" (current-output-port)))))"#;
+        test1(prog, r#""out"'x"#);
+
+        // Applied right away too
+        test1("((lambda (x y) (+ x y)) 40 2)", "42");
+    }
+
+    #[test]
+    fn handlers() {
+        // Escaping discards the guards installed after `call/cc`
+        let prog = "(let ((f (lambda (k) (guard (e (#t 'inner)) (k 1)))))
+                      (guard (e (#t 'outer)) (+ 1 (call/cc f)) (raise 'x)))";
+        test1(prog, "'outer");
+    }
+
+    #[test]
+    fn wind() {
+        let winders = r#"(before (lambda () (rt-write "in" (current-output-port))))
+                         (after (lambda () (rt-write "out" (current-output-port))))"#;
+
+        let prog = format!("(let ({} (t (lambda () 42))) (dynamic-wind before t after))", winders);
+        test1(&prog, r#""in""out"42"#);
+
+        // After thunks run on the way out to a guard
+        let prog = format!(
+            "(let ({} (t (lambda () (dynamic-wind before u after))) (u (lambda () (raise 'x))))
+               (guard (e (#t e)) (dynamic-wind before t after)))",
+            winders
+        );
+        test1(&prog, r#""in""in""out""out"'x"#);
+
+        // But not for a procedure handler, which is called before unwinding
+        let prog = format!(
+            "(let ({} (t (lambda () (dynamic-wind before u after)))
+                      (u (lambda () (raise-continuable 1)))
+                      (h (lambda (e) (rt-write \"handler\" (current-output-port)))))
+               (with-exception-handler h t))",
+            winders
+        );
+        test1(&prog, r#""in""handler""out"()"#);
+    }
+
+    #[test]
+    fn invalid() {
+        let prog = "(let ((f (lambda (k) k))) (let ((k (call/cc f))) (k 1)))";
+        let e = fail(prog);
+        assert!(e.contains("continuation invoked outside of its dynamic extent"), "{}", e);
+
+        let e = fail("(let ((x 1)) (x 2))");
+        assert!(e.contains("Exception: attempt to apply non-procedure 1"), "{}", e);
+
+        // Functions are passed by name, a variable can't hold one
+        let e = failure("(define (f k) (call/cc k)) (f 1)", |e| e.to_string());
+        assert!(e.contains("call/cc expects a function, got the variable `k`"), "{}", e);
+
+        let e = failure("(call/cc (cons 1 2))", |e| e.to_string());
+        assert!(e.contains("call/cc expects a function, got an expression"), "{}", e);
+    }
+}
+
+mod threads {
+    use super::*;
+
+    #[test]
+    fn spawn() {
+        let prog = "(define (worker n) (let ((x (yield)) (y (yield))) (* n 2)))
+                    (define (pair a b) (cons a b))
+                    (let ((a (spawn worker 21)) (b (spawn pair 1 2)))
+                      (cons (thread-join a) (thread-join b)))";
+        test1(prog, "(42 1 . 2)");
+
+        // Yielding with no other thread to run continues right away
+        test1("(let ((x (yield))) 1)", "1");
+    }
+
+    #[test]
+    fn channels() {
+        let prog = "(define (produce ch i)
+                      (if (< i 5)
+                          (let ((x (channel-send ch (* i i)))) (produce ch (inc i)))
+                          'done))
+                    (define (consume ch n acc)
+                      (if (zero? n) acc (consume ch (dec n) (cons (channel-recv ch) acc))))
+                    (let ((ch (make-channel)))
+                      (let ((t (spawn produce ch 0)))
+                        (cons (thread-join t) (consume ch 5 ()))))";
+        test1(prog, "('done 16 9 4 1 0)");
+    }
+
+    #[test]
+    fn gc() {
+        // Collections triggered by one thread must update the others too
+        let prog = "(define (build n acc)
+                      (if (zero? n)
+                          acc
+                          (let ((x (if (zero? (% n 10)) (yield) 0)))
+                            (build (dec n) (cons n acc)))))
+                    (define (sum l acc) (if (null? l) acc (sum (cdr l) (+ acc (car l)))))
+                    (define (work n) (sum (build n ()) 0))
+                    (define (deep n) (if (zero? n) (let ((x (yield))) 0) (inc (deep (dec n)))))
+                    (let ((a (spawn work 1000)) (b (spawn deep 5000)) (c (spawn work 2000)))
+                      (cons (thread-join a) (cons (thread-join b) (thread-join c))))";
+        test1(prog, "(500500 5000 . 2001000)");
+    }
+
+    #[test]
+    fn errors() {
+        // Every thread has its own handlers
+        let prog = "(define (inner)
+                      (guard (e (#t (cons 'caught e))) (let ((y (yield))) (raise 'x))))
+                    (let ((a (spawn inner)))
+                      (guard (e (#t (cons 'main e))) (cons (thread-join a) (raise 'y))))";
+        test1(prog, "('main . 'y)");
+
+        let prog = "(define (f n) (inc (f n)))
+                    (define (g) (guard (e (#t e)) (f 0)))
+                    (thread-join (spawn g))";
+        test1(prog, "\"stack overflow\"");
+
+        let prog = "(guard (e (#t e)) (channel-recv (make-channel)))";
+        test1(prog, "\"deadlock: every thread is waiting\"");
+
+        let e = fail("(define (f) (channel-recv (make-channel))) (thread-join (spawn f))");
+        assert!(e.contains("Exception: deadlock: every thread is waiting"), "{}", e);
+    }
+}
+
+mod eval {
+    use super::*;
+
+    const RUN: &str =
+        "(define (run s) (eval (read (open-input-string s)) (interaction-environment)))";
+
+    #[test]
+    fn expressions() {
+        let tests = [
+            ("(eval 42 (interaction-environment))", "42"),
+            ("(eval (cons '+ (cons 1 (cons 2 ()))) (interaction-environment))", "3"),
+            ("(eval (cons 'quote (cons 'x ())) (interaction-environment))", "'x"),
+            (r#"(run "(let ((x 20)) (+ x 22))")"#, "42"),
+            (r#"(run "(equal? (cons 1 2) (cons 1 2))")"#, "#t"),
+        ];
+
+        for (input, output) in &tests {
+            test1(&format!("{} {}", RUN, input), output);
+        }
+    }
+
+    #[test]
+    fn definitions() {
+        let prog = r#"(run "(define (sq x) (* x x))")
+                      (run "(define (f n) (if (zero? n) 0 (+ (sq n) (f (dec n)))))")
+                      (run "(f 10)")"#;
+        test1(&format!("{} {}", RUN, prog), "385");
+    }
+
+    #[test]
+    fn gc() {
+        let prog = r#"(run "(define (build n acc) (if (zero? n) acc (build (dec n) (cons n acc))))")
+                      (run "(define (sum l) (if (null? l) 0 (+ (car l) (sum (cdr l)))))")
+                      (let ((x (cons 1 2)))
+                        (run "(sum (build 100000 ()))")
+                        (cons (run "(sum (build 1000 ()))") x))"#;
+        test1(&format!("{} {}", RUN, prog), "(500500 1 . 2)");
+    }
+
+    #[test]
+    fn errors() {
+        let prog = r#"(guard (e (#t e)) (run "(raise 'boom)"))"#;
+        test1(&format!("{} {}", RUN, prog), "'boom");
+
+        let prog = r#"(guard (e ((string? e) e)) (run "(f 1)"))"#;
+        test1(&format!("{} {}", RUN, prog), "\"eval: Undefined reference to `f`\"");
+
+        let e = fail("(eval 1 2)");
+        assert!(e.contains("Exception: eval: expected an environment, got 2"), "{}", e);
+    }
+}
+
+mod ffi {
+    use super::*;
+
+    #[test]
+    fn libm() {
+        let prog = r#"(load-shared-object "libm.so.6")
+                      (define cos (foreign-procedure "cos" (double) double))
+                      (define pow (foreign-procedure "pow" (double double) double))
+                      (define sqrtf (foreign-procedure "sqrtf" (float) float))
+                      (define ldexp (foreign-procedure "ldexp" (double int) double))
+                      (cons (cos 0.0) (cons (pow 2 10) (cons (sqrtf 2.25) (ldexp 1.5 4))))"#;
+        test1(prog, "(1.0 1024.0 1.5 . 24.0)");
+    }
+
+    #[test]
+    fn types() {
+        test_many(&[
+            (r#"(define c-abs (foreign-procedure "abs" (int) int)) (c-abs -42)"#, "42"),
+            (r#"(define c-labs (foreign-procedure "labs" (long) long)) (c-labs -5)"#, "5"),
+            (r#"(define len (foreign-procedure "strlen" (string) long)) (len "hello")"#, "5"),
+            (r#"(define up (foreign-procedure "toupper" (char) char)) (up #\a)"#, "#\\A"),
+            (r#"(let ((abs (foreign-procedure "abs" (int) int))) (abs -3))"#, "3"),
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        let e = fail(r#"(load-shared-object "libnope.so")"#);
+        assert!(e.contains("Exception: load-shared-object: libnope.so"), "{}", e);
+
+        let e = fail(r#"(define f (foreign-procedure "nope" () int)) (f)"#);
+        assert!(e.contains("Exception: nope: no such foreign procedure"), "{}", e);
+
+        let e = fail(r#"(define c-abs (foreign-procedure "abs" (int) int)) (c-abs "x")"#);
+        assert!(e.contains("Exception: abs: expected int, got \"x\""), "{}", e);
+    }
+}
+
+// Step 19, 20 & 21 - IO
+mod process {
+    use super::*;
+    use inc::process;
+    use std::env;
+
+    #[test]
+    fn command_line() {
+        test1("(let ((args (command-line))) (cons (string? (car args)) (cdr args)))", "(#t)");
+
+        let args = ["prog", "a", "b c"].iter().map(|arg| arg.to_string()).collect();
+        process::set_command_line(args);
+
+        let config = config(TEST_FOLDER, String::from("(cdr (command-line))"));
+        match cli::run(&config, cli::Action::Jit) {
+            Ok(Some(result)) => assert_eq!(result, r#"("a" "b c")"#),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn script() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let program = String::from(
+            "#!/usr/bin/env inc
+             (if (equal? (command-line) (cons \"prog.scm\" (cons \"a\" (cons \"b c\" ()))))
+                 #t
+                 (car 1))",
+        );
+        let config = config(&base_folder, program);
+        let args = [String::from("a"), String::from("b c")];
+
+        assert_eq!(cli::Driver::new(&config).script("prog.scm", &args).unwrap(), 0);
+        assert_ne!(cli::Driver::new(&config).script("prog.scm", &args[1..]).unwrap(), 0);
+        assert!(fs::metadata(&config.output).is_err());
+    }
+
+    #[test]
+    fn environment() {
+        env::set_var("INC_TEST_VARIABLE", "hello");
+
+        test1(r#"(get-environment-variable "INC_TEST_VARIABLE")"#, r#""hello""#);
+        test1(r#"(get-environment-variable "INC_TEST_UNSET")"#, "#f");
+
+        let e = fail("(get-environment-variable 'home)");
+        assert!(e.contains("get-environment-variable: expected string, got 'home"), "{}", e);
+    }
+
+    #[test]
+    fn system() {
+        test1(r#"(system "true")"#, "0");
+        test1(r#"(system "exit 3")"#, "3");
+        test1(r#"(system "kill -9 $$")"#, "137");
+
+        let e = fail("(system 'ls)");
+        assert!(e.contains("system: expected string, got 'ls"), "{}", e);
+    }
+
+    #[test]
+    fn run() {
+        test1(r#"(process-run "true")"#, "0");
+        test1(r#"(process-run "sh" (cons "-c" (cons "exit 7" ())))"#, "7");
+        test1(r#"(process-output "printf" (cons "a b" ()))"#, r#""a b""#);
+        test1(r#"(process-output "true" ())"#, r#""""#);
+
+        let e = fail(r#"(process-run "/nonexistent")"#);
+        assert!(e.contains("process-run: /nonexistent: No such file or directory"), "{}", e);
+
+        let e = fail(r#"(process-output "echo" (cons 1 ()))"#);
+        assert!(e.contains("process-output: expected a list of strings, got (1)"), "{}", e);
+    }
+
+    #[test]
+    fn time() {
+        test1("(jiffies-per-second)", "1000000");
+        test1("(let ((t (current-jiffy))) (<= t (current-jiffy)))", "#t");
+
+        // The report on stderr ends up after the value
+        let folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&folder).unwrap();
+
+        let config = config(&folder, String::from("(let ((x 5)) (time (make-vector x x)))"));
+        match cli::run(&config, cli::Action::Run) {
+            Ok(Some(result)) => {
+                assert!(result.starts_with("[5 5 5 5 5](time (make-vector x x)): "), "{}", result);
+                assert!(result.ends_with("ms elapsed, 48 bytes allocated"), "{}", result);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
+
+mod io {
+    use super::*;
+    use std::fs::read_to_string;
+
+    #[test]
+    fn std_ports() {
+        let k = r#"(current-input-port)"#;
+        test1(k, r#"['port "stdin" 0]"#);
+
+        let k = r#"(current-output-port)"#;
+        test1(k, r#"['port "stdout" 1]"#);
+
+        let k = r#"(current-error-port)"#;
+        test1(k, r#"['port "stderr" 2]"#);
+    }
+
+    #[test]
+    fn fd() {
+        let k = r#"(open-input-file "/etc/hosts")"#;
+        test1(k, r#"['port "/etc/hosts" 3]"#);
+    }
+
+    #[test]
+    fn write_to_file() {
+        let k = r#"(rt-write "hello world\n" (open-output-file "/tmp/inc/io.txt"))"#;
+
+        test1(k, "()");
+        assert_eq!("hello world\n", read_to_string("/tmp/inc/io.txt").unwrap())
+    }
+
+    #[test]
+    fn write_to_stdout() {
+        let k = r#"(rt-write "hello world" (current-output-port))"#;
+        test1(k, "\"hello world\"()");
+    }
+
+    #[test]
+    fn display() {
+        let k = r#"(display "hello") (display #\a) (display 'b) (display (cons "c" (cons 'd 4)))"#;
+        test1(k, "helloab(c d . 4)()");
+
+        let k = r#"(display (vector 1 "x" #\y)) (display (cons 1 (cons #t ())))"#;
+        test1(k, "[1 x y](1 #t)()");
+    }
+
+    #[test]
+    fn write() {
+        let k = r#"(write "hello") (write #\a) (write 'b) (write (cons "c" (cons 'd 4)))"#;
+        test1(k, r##""hello"#\ab("c" d . 4)()"##);
+
+        let k = r#"(write (vector 1 "x" #\y)) (newline) (write 42)"#;
+        test1(k, "#(1 \"x\" #\\y)\n42()");
+
+        // Strings are escaped so that `read` gets the same string back, but
+        // only when written
+        let k = r#"(write "a \"b\" \\ c\n\td\x1;") (display "\"e\"")"#;
+        test1(k, r#""a \"b\" \\ c\n\td\x1;""e"()"#);
+
+        let k = r#"(let ((s "say \"hi\"\\\n\tthere\a")
+                         (out (open-output-string)))
+                     (write s out)
+                     (string=? s (read (open-input-string (get-output-string out)))))"#;
+        test1(k, "#t");
+
+        // Anything written reads back as the same datum
+        let k = r#"(let ((x (list 'a "b \"c\"" #\d #\space 1.5 -12345678901234567890
+                               (vector 'e (list 1 2) "f") (cons 'g 'h) '(quote i)))
+                         (out (open-output-string)))
+                     (write x out)
+                     (equal? x (read (open-input-string (get-output-string out)))))"#;
+        test1(k, "#t");
+    }
+
+    #[test]
+    fn display_to_file() {
+        let k = r#"(let ((port (open-output-file "/tmp/inc/display.txt")))
+                     (display "hello" port)
+                     (newline port)
+                     (write "world" port))"#;
+
+        test1(k, "()");
+        assert_eq!("hello\n\"world\"", read_to_string("/tmp/inc/display.txt").unwrap())
+    }
+
+    #[test]
+    fn read() {
+        fs::create_dir_all(TEST_FOLDER)
+            .unwrap_or_else(|e| panic!("Failed to create test folder {}", e));
+
+        fs::write("/tmp/inc/hello.txt", "hello ").unwrap();
+        fs::write("/tmp/inc/world.txt", "world").unwrap();
+
+        let k = r#"
+            (let ((f1 (open-input-file "/tmp/inc/hello.txt"))
+                  (hello (rt-read f1))
+
+                  (f2 (open-input-file "/tmp/inc/world.txt"))
+                  (world (rt-read f2)))
+
+              (cons hello world))"#;
+
+        test1(k, r#"("hello " . "world")"#);
+    }
+
+    #[test]
+    fn read_datum() {
+        fs::create_dir_all(TEST_FOLDER)
+            .unwrap_or_else(|e| panic!("Failed to create test folder {}", e));
+
+        fs::write("/tmp/inc/data.ss", "(1 #t #\\a \"s\") foo\n #(x y) '(q) (y . z)  ").unwrap();
+
+        let k = r#"
+            (let ((port (open-input-file "/tmp/inc/data.ss"))
+                  (a (read port))
+                  (b (read port))
+                  (c (read port))
+                  (d (read port))
+                  (e (read port)))
+              (write a)
+              (write b)
+              (write c)
+              (write d)
+              (write e)
+              (eof-object? (read port)))"#;
+
+        test1(k, r##"(1 #t #\a "s")foo#(x y)(quote (q))(y . z)#t"##);
+
+        let k = r#"
+            (let ((port (open-input-file "/tmp/inc/data.ss"))
+                  (l (read port))
+                  (foo (read port)))
+              (cons (symbol=? foo 'foo) (eof-object? foo)))"#;
+
+        test1(k, "(#t . #f)");
+    }
+
+    #[test]
+    fn ports() {
+        let k = r#"(let ((port (open-output-file "/tmp/inc/ports.txt")))
+                     (write-string "hi" port)
+                     (write-char #\! port)
+                     (close-port port))"#;
+
+        test1(k, "()");
+        assert_eq!("hi!", read_to_string("/tmp/inc/ports.txt").unwrap());
+
+        let k = r#"(let ((port (open-input-file "/tmp/inc/ports.txt"))
+                         (a (peek-char port))
+                         (b (read-char port))
+                         (c (read-char port))
+                         (d (read-char port))
+                         (e (read-char port)))
+                     (close-input-port port)
+                     (write (cons a (cons b (cons c (cons d (eof-object? e)))))))"#;
+
+        test1(k, r##"(#\h #\h #\i #\! . #t)()"##);
+
+        test1(r#"(write-string "hello") (write-char #\newline)"#, "hello\n()");
+    }
+
+    #[test]
+    fn strings() {
+        let k = r#"(let ((port (open-input-string "(a . b) #\\c"))
+                         (datum (read port))
+                         (space (read-char port)))
+                     (write datum)
+                     (write space)
+                     (write (read port))
+                     (eof-object? (peek-char port)))"#;
+
+        test1(k, r#"(a . b)#\space#\c#t"#);
+
+        let k = r#"(let ((port (open-output-string)))
+                     (write-string "x = " port)
+                     (write 42 port)
+                     (write-char #\! port)
+                     (get-output-string port))"#;
+
+        test1(k, r#""x = 42!""#);
+    }
+
+    // Ports that can't be used are raised as errors rather than crashing
+    #[test]
+    fn errors() {
+        let missing = "open-input-file: failed to open /tmp/inc/missing.txt: \
+                       No such file or directory (os error 2)";
+
+        let k = r#"(guard (e ((string? e) e)) (open-input-file "/tmp/inc/missing.txt"))"#;
+        test1(k, &format!("\"{}\"", missing));
+        test1("(guard (e ((string? e) e)) (newline 42))", r#""newline: expected port, got 42""#);
+
+        let tests = [
+            (r#"(open-input-file "/tmp/inc/missing.txt")"#, missing),
+            (r#"(open-output-file 42)"#, "open-output-file: expected string, got 42"),
+            (
+                r#"(write-string "x" (open-input-file "/etc/hosts"))"#,
+                "write-string: failed to write to /etc/hosts: Bad file descriptor (os error 9)",
+            ),
+            (
+                r#"(write-char #\a (open-input-string "x"))"#,
+                r#"write-char: expected output port, got ['port "string" -1]"#,
+            ),
+            (
+                r#"(read-char (open-output-string))"#,
+                r#"read-char: expected input port, got ['port "string" -1]"#,
+            ),
+            ("(write 1 42)", "write: expected port, got 42"),
+            ("(display 1 (vector 1 2))", "display: expected port, got [1 2]"),
+            ("(read-char 'stdin)", "read-char: expected port, got 'stdin"),
+            ("(close-port (vector 'port))", "close-port: expected port, got ['port]"),
+            (r#"(get-output-string "out")"#, r#"get-output-string: expected port, got "out""#),
+            ("(write-string 1)", "write-string: expected string, got 1"),
+        ];
+
+        for (input, error) in tests.iter() {
+            let e = fail(input);
+            assert!(e.contains(&format!("Exception: {}", error)), "{}: {}", input, e);
+        }
+    }
+}
+
+// Garbage collection
+mod gc {
+    use super::*;
+    use std::process::{Command, Output};
+
+    // Allocate a lot more than the size of the heap, none of it live
+    #[test]
+    fn garbage() {
+        let expr = "(let ((drop (lambda (x) 0))
+                   (churn (lambda (n)
+                            (if (zero? n)
+                              0
+                              (+ (drop (cons n (vector n n))) (churn (dec n)))))))
+             (churn 5000))";
+
+        test1(expr, "0");
+    }
+
+    // Build a list of `n` numbers with plenty of garbage along the way and sum
+    // it up
+    fn build(n: usize) -> String {
+        format!(
+            "(let ((build (lambda (n acc)
+                             (if (zero? n)
+                               acc
+                               (build (dec n) (cons n (cdr (cons (vector 1 2 3 4) acc)))))))
+                   (sum (lambda (l acc)
+                          (if (null? l)
+                            acc
+                            (sum (cdr l) (+ acc (car l)))))))
+             (sum (build {} ()) 0))",
+            n
+        )
+    }
+
+    // Build an executable and run it with custom arguments and environment
+    fn exec(program: &str, args: &[&str], env: &[(&str, &str)]) -> Output {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let config = config(&base_folder, program.to_string());
+        cli::run(&config, cli::Action::GenASM).unwrap();
+        cli::build(&config).unwrap();
+
+        let exe = Command::new(&config.output).args(args).envs(env.to_vec()).output().unwrap();
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+        exe
+    }
+
+    // Live data must survive several collections
+    #[test]
+    fn live() {
+        test1(&build(300), "45150");
+    }
+
+    // Scanning the stack conservatively must find the same roots
+    #[test]
+    fn conservative() {
+        let exe = exec(&build(300), &[], &[("INC_GC", "conservative")]);
+
+        assert!(exe.status.success());
+        assert_eq!(String::from_utf8_lossy(&exe.stdout).trim(), "45150");
+    }
+
+    // The heap grows to fit live data much larger than the initial size
+    #[test]
+    fn grow() {
+        test1(&build(2000), "2001000")
+    }
+
+    // Programs fail cleanly when running over the limit
+    #[test]
+    fn limit() {
+        let program = build(2000)
+            .replace("(sum (build", "(if (heap-limit 1024) (sum (build")
+            .replace("()) 0))", "()) 0) 0))");
+
+        let e = fail(&program);
+        assert!(e.contains("Out of memory"), "{}", e);
+    }
+
+    // Heap settings from the command line take priority over the environment
+    #[test]
+    fn options() {
+        let program = build(2000);
+        let oom = |exe: Output| String::from_utf8_lossy(&exe.stderr).contains("Out of memory");
+
+        assert!(oom(exec(&program, &["--heap-limit", "1024"], &[])));
+        assert!(oom(exec(&program, &[], &[("INC_HEAP_LIMIT", "1024")])));
+
+        let exe = exec(&program, &["--heap-limit", "65536"], &[("INC_HEAP_LIMIT", "1024")]);
+        assert_eq!(String::from_utf8_lossy(&exe.stdout).trim(), "2001000");
+
+        let exe = exec(&program, &["--heap-growth", "4"], &[("INC_HEAP_SIZE", "256")]);
+        assert_eq!(String::from_utf8_lossy(&exe.stdout).trim(), "2001000");
+    }
+
+    // Allocating while building an object must not corrupt it
+    #[test]
+    fn nested() {
+        test_many(&[
+            ("(vector (cons 1 2) 3 (cons 4 5))", "[(1 2) 3 (4 5)]"),
+            ("(cons (cons 1 2) (vector (cons 3 4)))", "((1 . 2) . [(3 4)])"),
+        ])
+    }
+
+    // Young objects referenced only from a mutated old object must survive
+    #[test]
+    fn mutation() {
+        let expr = "(let ((drop (lambda (x) 0))
+                   (churn (lambda (n)
+                            (if (zero? n)
+                              0
+                              (+ (drop (cons n n)) (churn (dec n))))))
+                   (update (lambda (old)
+                             (let ((a (churn 200)))
+                               (let ((b (set-car! old (cons 3 4))))
+                                 (let ((c (churn 200)))
+                                   (car (car old))))))))
+             (update (cons 1 2)))";
+
+        test_many(&[
+            (expr, "3"),
+            ("(let ((p (cons 1 2))) (let ((x (set-cdr! p 5))) p))", "(1 . 5)"),
+        ])
+    }
+
+    // Objects larger than the nursery go straight to the old generation
+    #[test]
+    fn large() {
+        test_many(&[
+            ("(let ((v (make-string 4000))) (string? v))", "#t"),
+            ("(let ((s (make-string 4000))) (cons 1 (cons 2 ())))", "(1 2)"),
+            ("(vector-ref (make-vector 4000 (cons 1 2)) 3999)", "(1 . 2)"),
+        ])
+    }
+
+    // Identity hash tables must find their keys after the keys have moved
+    #[test]
+    fn tables() {
+        let expr = "(define (fill t n keys)
+                       (if (zero? n)
+                         keys
+                         (let ((k (cons n n))
+                               (x (hash-table-set! t k n)))
+                           (fill t (dec n) (cons k keys)))))
+                     (define (total t keys sum)
+                       (if (null? keys)
+                         sum
+                         (total t (cdr keys) (+ sum (hash-table-ref/default t (car keys) 0)))))
+                     (define (drop t keys)
+                       (if (null? keys)
+                         t
+                         (let ((x (hash-table-delete! t (car keys))))
+                           (drop t (cdr (cdr keys))))))
+                     (let ((t (make-eq-hash-table))
+                           (e (gc-epoch))
+                           (keys (fill t 2000 ()))
+                           (s (total t keys 0))
+                           (x (drop t keys)))
+                       (cons (> (gc-epoch) e)
+                             (cons s (cons (hash-table-count t) (total t keys 0)))))";
+
+        test1(expr, "(#t 2001000 1000 . 1001000)");
+    }
+
+    // Weak boxes don't keep their values alive, guardians save theirs
+    #[test]
+    fn weak() {
+        let expr = "(let ((live (cons 1 2))
+                          (a (make-weak-box live))
+                          (b (make-weak-box (cons 3 4)))
+                          (x (gc)))
+                      (cons (weak-box-value a) (weak-box-value b)))";
+
+        let churn = "(define (churn n) (if (zero? n) 0 (let ((x (cons n n))) (churn (dec n)))))
+                     (let ((b (make-weak-box (cons 3 4)))
+                           (x (churn 1000)))
+                       (weak-box-value b))";
+
+        let guardian = "(let ((g (make-guardian))
+                              (live (cons 1 2))
+                              (x (guardian-register! g (cons 5 6)))
+                              (y (guardian-register! g live))
+                              (z (gc))
+                              (a (guardian-next g))
+                              (b (guardian-next g)))
+                          (cons a (cons b live)))";
+
+        test_many(&[(expr, "((1 . 2) . #f)"), (churn, "#f"), (guardian, "((5 . 6) #f 1 . 2)")]);
+    }
+
+    // Finalizers run after a collection, at `(gc)` or the next allocation
+    #[test]
+    fn finalizers() {
+        let expr = "(define (report p) (display (car p)))
+                    (define (churn n) (if (zero? n) 0 (let ((x (cons n n))) (churn (dec n)))))
+                    (let ((live (cons 3 4))
+                          (a (register-finalizer (cons 1 2) report))
+                          (b (register-finalizer live report))
+                          (x (gc))
+                          (c (register-finalizer (cons 5 6) report))
+                          (y (churn 1000)))
+                      live)";
+
+        test1(expr, "15(3 . 4)");
+    }
+
+    // Collections on demand, statistics and the log of every collection
+    #[test]
+    fn stats() {
+        test_many(&[
+            ("(let ((x (gc)) (used (heap-used))) used)", "0"),
+            ("(let ((p (cons 1 2)) (x (gc)) (used (heap-used))) (cons p used))", "((1 . 2) . 16)"),
+            ("(let ((x (gc)) (stats (gc-stats))) (car (car (cdr (cdr stats)))))", "'major"),
+            ("(let ((x (gc)) (stats (gc-stats))) (cdr (car (cdr (cdr stats)))))", "1"),
+        ]);
+
+        let exe = exec(&build(300), &[], &[("GC_LOG", "1")]);
+        let log = String::from_utf8_lossy(&exe.stderr);
+
+        assert_eq!(String::from_utf8_lossy(&exe.stdout).trim(), "45150");
+        assert!(log.starts_with("gc: minor collection 1, "), "{}", log);
+    }
+}
+
+// Count the expressions a program evaluates
+mod coverage {
+    use super::*;
+    use inc::coverage::Coverage;
+    use std::process::Command;
+
+    #[test]
+    fn counts() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let program = "(define (sign n) (if (< n 0) 'negative 'positive)) (sign 1) (sign 2)";
+        let config = Config { coverage: true, ..config(&base_folder, program.to_string()) };
+        cli::run(&config, cli::Action::GenASM).unwrap();
+        cli::build(&config).unwrap();
+
+        let counts = format!("{}/inc.cov", base_folder);
+        let exe = Command::new(&config.output).env("INC_COVERAGE", &counts).output().unwrap();
+        assert!(exe.status.success());
+
+        let coverage = Coverage::parse(&fs::read_to_string(&counts).unwrap()).unwrap();
+        let hits = |text: &str| {
+            let start = program.find(text).unwrap();
+            coverage.counters.iter().find(|c| c.span.start == start).map(|c| c.hits)
+        };
+
+        assert_eq!(hits("(sign 1)"), Some(1));
+        assert_eq!(hits("positive"), Some(2));
+        assert_eq!(hits("negative"), Some(0));
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+}
+
+// Count the calls to the functions of a program
+mod profile {
+    use super::*;
+    use inc::profile::Profile;
+    use std::process::Command;
+
+    #[test]
+    fn calls() {
+        let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+        fs::create_dir_all(&base_folder).unwrap();
+
+        let program = "(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))) (fib 10)";
+        let config = Config { profile: true, ..config(&base_folder, program.to_string()) };
+        cli::run(&config, cli::Action::GenASM).unwrap();
+        cli::build(&config).unwrap();
+
+        let counts = format!("{}/inc.prof", base_folder);
+        let exe = Command::new(&config.output).env("INC_PROFILE", &counts).output().unwrap();
+        assert!(exe.status.success());
+
+        let profile = Profile::parse(&fs::read_to_string(&counts).unwrap()).unwrap();
+        let calls = |name: &str| profile.functions.iter().find(|f| f.name == name).map(|f| f.calls);
+
+        assert_eq!(calls("fib"), Some(177));
+        assert_eq!(calls("length"), Some(0));
+
+        fs::remove_dir_all(&base_folder).unwrap_or_default();
+    }
+}
+
+// Run programs in memory without building an executable
+mod jit {
+    use super::*;
+    use inc::compiler::Compiler;
+
+    #[test]
+    fn values() {
+        let c = Compiler::new();
+
+        assert_eq!(c.run("(+ 1 2)").unwrap(), Expr::Literal(Literal::Number(3)));
+        assert_eq!(c.run("(zero? 0)").unwrap(), Expr::Literal(Literal::Boolean(true)));
+        assert_eq!(c.run(r#""hello""#).unwrap(), Expr::string("hello"));
+        assert_eq!(c.run("'hello").unwrap(), Expr::symbol("hello"));
+    }
+
+    #[test]
+    fn cli() {
+        let tests = [
+            ("(cons 1 (cons 2 ()))", "(1 2)"),
+            ("(vector 1 5 'one)", "[1 5 'one]"),
+            ("(string-length \"hello\")", "5"),
+            ("(define (car x) 42) (car (cons 1 2))", "42"),
+            (
+                "(let ((factorial (lambda (x acc)
+                                    (if (zero? x)
+                                      acc
+                                      (factorial (dec x) (* x acc))))))
+                   (factorial 10 1))",
+                "3628800",
+            ),
+        ];
+
+        for (input, output) in tests.iter() {
+            let config = config(TEST_FOLDER, input.to_string());
+
+            match cli::run(&config, cli::Action::Jit) {
+                Ok(Some(result)) => assert_eq!(&result, output, "Failed: {}", input),
+                Ok(None) => panic!("Test produced no output"),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+}
+
+// Evaluate programs with the interpreter, without compiling them
+mod interp {
+    use super::*;
+
+    #[test]
+    fn cli() {
+        let tests = [
+            ("(cons 1 (cons 2 ()))", "(1 2)"),
+            ("(display (string-append \"a\" \"b\")) (vector 1 5 'one)", "ab[1 5 'one]"),
+            ("(let ((v (make-vector 2 0))) (vector-set! v 1 'x) v)", "[0 'x]"),
+            ("(guard (e ((string? e) e)) (car 1))", "\"car: expected pair, got 1\""),
+            ("(* 1152921504606846975 4)", "4611686018427387900"),
+        ];
+
+        for (input, output) in tests.iter() {
+            let config = config(TEST_FOLDER, input.to_string());
+
+            match cli::run(&config, cli::Action::Interp) {
+                Ok(Some(result)) => assert_eq!(&result, output, "Failed: {}", input),
+                Ok(None) => panic!("Test produced no output"),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let run = |input: &str| {
+            match cli::run(&config(TEST_FOLDER, input.to_string()), cli::Action::Interp) {
+                Err(Error::Runtime(e)) => e,
+                Err(Error::Compilation(_)) => String::from("compilation"),
+                Err(Error::Internal { .. }) => String::from("unsupported"),
+                other => panic!("Expected {} to fail, found {:?}", input, other),
+            }
+        };
+
+        assert_eq!(run("(car 1)"), "Exception: car: expected pair, got 1");
+        assert_eq!(run("(let ((x 1)) y)"), "compilation");
+        assert_eq!(run("(open-output-string)"), "unsupported");
+    }
+}
+
+mod rt {
+    use super::*;
+    use inc::rt;
+
+    #[test]
+    fn alloc() {
+        let old = rt::heap();
+        rt::allocate(15);
+        let new = rt::heap();
+
+        assert_eq!(new, old + 16);
+    }
+}
+
+// Get a test config with program as input
+fn config(base_folder: &str, program: String) -> Config {
+    // Time epoch instead of UUID occasionally ran into race conditions which
+    // made multiple tests write to the same file concurrently completely
+    // messing things up.
+    let output = format!("{}/inc", base_folder);
+
+    Config { program, output, ..Default::default() }
+}
+
+// Run a program expected to fail at runtime and return the error
+fn fail(input: &str) -> String {
+    failure(input, |e| match e {
+        Error::Runtime(e) | Error::Exit { message: e, .. } => e,
+        other => panic!("Expected {} to fail, found {:?}", input, other),
+    })
+}
+
+// Run a program expected to exit with an error and return the exit status
+fn status(input: &str) -> i32 {
+    failure(input, |e| match e {
+        Error::Exit { status, .. } => status,
+        other => panic!("Expected {} to exit with an error, found {:?}", input, other),
+    })
+}
+
+// Run a program expected to fail and look at the error
+fn failure<T>(input: &str, f: impl FnOnce(Error) -> T) -> T {
+    let base_folder = format!("{}/{:x?}", TEST_FOLDER, random::<u32>());
+    fs::create_dir_all(&base_folder).unwrap();
+
+    let config = config(&base_folder, input.to_string());
+    let result = cli::run(&config, cli::Action::Run);
+
+    fs::remove_dir_all(&base_folder).unwrap_or_default();
+
+    match result {
+        Err(e) => f(e),
+        Ok(output) => panic!("Expected {} to fail, found {:?}", input, output),
+    }
 }
 
 fn test_many(tests: &[(&str, &str)]) {
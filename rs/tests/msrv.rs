@@ -0,0 +1,51 @@
+// The crate builds on stable Rust, as old as the `rust-version` in Cargo.toml
+//
+// These only catch the easy mistakes, like an unstable feature creeping back
+// in. Building and testing with the oldest supported toolchain is the real
+// test, which needs rustup to install it
+//
+//     $ make msrv
+use std::{fs, path::Path};
+
+// Every Rust source of the crate, its tests and benchmarks
+fn sources() -> Vec<(String, String)> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    ["src", "tests", "benches"]
+        .iter()
+        .flat_map(|dir| fs::read_dir(root.join(dir)).unwrap())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "rs"))
+        .map(|path| (path.display().to_string(), fs::read_to_string(&path).unwrap()))
+        .collect()
+}
+
+#[test]
+fn stable() {
+    let attribute = ["#!", "[feature("].concat();
+
+    for (path, source) in sources() {
+        for (n, line) in source.lines().enumerate() {
+            assert!(!line.contains(&attribute), "Unstable feature at {}:{}: {}", path, n + 1, line);
+        }
+    }
+}
+
+#[test]
+fn declared() {
+    let manifest = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"));
+    let manifest = manifest.unwrap();
+
+    let version = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("rust-version"))
+        .map(|rest| rest.trim_start_matches([' ', '=']).trim_matches('"'))
+        .expect("Cargo.toml doesn't declare the rust-version the crate supports");
+
+    let parts: Vec<&str> = version.split('.').collect();
+    assert!(
+        parts.len() == 2 && parts.iter().all(|n| n.parse::<u32>().is_ok()),
+        "rust-version {} isn't a major.minor toolchain rustup can install",
+        version
+    );
+}